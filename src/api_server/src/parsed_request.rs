@@ -8,19 +8,22 @@ use super::VmmData;
 use crate::request::actions::parse_put_actions;
 use crate::request::balloon::{parse_get_balloon, parse_patch_balloon, parse_put_balloon};
 use crate::request::boot_source::parse_put_boot_source;
-use crate::request::drive::{parse_patch_drive, parse_put_drive};
+use crate::request::drive::{parse_get_drive, parse_patch_drive, parse_put_drive};
 use crate::request::instance_info::parse_get_instance_info;
 use crate::request::logger::parse_put_logger;
 use crate::request::machine_configuration::{
     parse_get_machine_config, parse_patch_machine_config, parse_put_machine_config,
 };
 use crate::request::metrics::parse_put_metrics;
-use crate::request::mmds::{parse_get_mmds, parse_patch_mmds, parse_put_mmds};
-use crate::request::net::{parse_patch_net, parse_put_net};
+use crate::request::mmds::{
+    parse_delete_mmds_tokens, parse_get_mmds, parse_patch_mmds, parse_put_mmds,
+};
+use crate::request::net::{parse_get_net, parse_patch_net, parse_put_net};
+use crate::request::rate_limiter::{parse_patch_rate_limiter, parse_put_rate_limiter};
 use crate::request::snapshot::parse_patch_vm_state;
 use crate::request::snapshot::parse_put_snapshot;
 use crate::request::version::parse_get_version;
-use crate::request::vsock::parse_put_vsock;
+use crate::request::vsock::{parse_patch_vsock, parse_put_vsock};
 use crate::ApiServer;
 use micro_http::{Body, Method, Request, Response, StatusCode, Version};
 
@@ -96,12 +99,14 @@ impl ParsedRequest {
         match (request.method(), path, request.body.as_ref()) {
             (Method::Get, "", None) => parse_get_instance_info(),
             (Method::Get, "balloon", None) => parse_get_balloon(path_tokens.get(1)),
+            (Method::Get, "drives", None) => parse_get_drive(path_tokens.get(1)),
             (Method::Get, "version", None) => parse_get_version(),
             (Method::Get, "vm", None) if path_tokens.get(1) == Some(&"config") => {
                 Ok(ParsedRequest::new_sync(VmmAction::GetFullVmConfig))
             }
             (Method::Get, "machine-config", None) => parse_get_machine_config(),
-            (Method::Get, "mmds", None) => parse_get_mmds(),
+            (Method::Get, "mmds", None) => parse_get_mmds(path_tokens.get(1)),
+            (Method::Get, "network-interfaces", None) => parse_get_net(path_tokens.get(1)),
             (Method::Get, _, Some(_)) => method_to_error(Method::Get),
             (Method::Put, "actions", Some(body)) => parse_put_actions(body),
             (Method::Put, "balloon", Some(body)) => parse_put_balloon(body),
@@ -114,6 +119,9 @@ impl ParsedRequest {
             (Method::Put, "network-interfaces", Some(body)) => {
                 parse_put_net(body, path_tokens.get(1))
             }
+            (Method::Put, "rate-limiters", Some(body)) => {
+                parse_put_rate_limiter(body, path_tokens.get(1))
+            }
             (Method::Put, "shutdown-internal", None) => {
                 Ok(ParsedRequest::new(RequestAction::ShutdownInternal))
             }
@@ -123,12 +131,20 @@ impl ParsedRequest {
             (Method::Patch, "balloon", Some(body)) => parse_patch_balloon(body, path_tokens.get(1)),
             (Method::Patch, "drives", Some(body)) => parse_patch_drive(body, path_tokens.get(1)),
             (Method::Patch, "machine-config", Some(body)) => parse_patch_machine_config(body),
-            (Method::Patch, "mmds", Some(body)) => parse_patch_mmds(body),
+            (Method::Patch, "mmds", Some(body)) => parse_patch_mmds(body, path_tokens.get(1)),
             (Method::Patch, "network-interfaces", Some(body)) => {
                 parse_patch_net(body, path_tokens.get(1))
             }
+            (Method::Patch, "rate-limiters", Some(body)) => {
+                parse_patch_rate_limiter(body, path_tokens.get(1))
+            }
             (Method::Patch, "vm", Some(body)) => parse_patch_vm_state(body),
+            (Method::Patch, "vsock", Some(body)) => parse_patch_vsock(body),
             (Method::Patch, _, None) => method_to_error(Method::Patch),
+            (Method::Delete, "mmds", None) if path_tokens.get(1) == Some(&"tokens") => {
+                parse_delete_mmds_tokens()
+            }
+            (Method::Delete, _, Some(_)) => method_to_error(Method::Delete),
             (method, unknown_uri, _) => {
                 Err(Error::InvalidPathMethod(unknown_uri.to_string(), method))
             }
@@ -173,11 +189,14 @@ impl ParsedRequest {
                     Self::success_response_with_data(balloon_config)
                 }
                 VmmData::BalloonStats(stats) => Self::success_response_with_data(stats),
+                VmmData::BlockDevice(info) => Self::success_response_with_data(info),
+                VmmData::NetworkInterface(info) => Self::success_response_with_data(info),
                 VmmData::InstanceInformation(info) => Self::success_response_with_data(info),
                 VmmData::VmmVersion(version) => Self::success_response_with_data(
                     &serde_json::json!({ "firecracker_version": version.as_str() }),
                 ),
                 VmmData::FullVmConfig(config) => Self::success_response_with_data(config),
+                VmmData::SnapshotCompatibility(report) => Self::success_response_with_data(report),
             },
             Err(vmm_action_error) => {
                 let mut response = match vmm_action_error {
@@ -222,12 +241,15 @@ fn log_received_api_request(api_description: String) {
 ///
 /// # Arguments
 ///
-/// * `method` - one of `GET`, `PATCH`, `PUT`
+/// * `method` - one of `GET`, `PATCH`, `PUT`, `DELETE`
 /// * `path` - path of the API request
 /// * `body` - body of the API request
 fn describe(method: Method, path: &str, body: Option<&Body>) -> String {
+    // The contents of the MMDS data stores are not logged.
+    let is_mmds_data = path == "/mmds" || (path.starts_with("/mmds/") && path != "/mmds/config");
     match (path, body) {
-        ("/mmds", Some(_)) | (_, None) => format!("{:?} request on {:?}", method, path),
+        (_, Some(_)) if is_mmds_data => format!("{:?} request on {:?}", method, path),
+        (_, None) => format!("{:?} request on {:?}", method, path),
         (_, Some(value)) => format!(
             "{:?} request on {:?} with body {:?}",
             method,
@@ -254,6 +276,10 @@ pub(crate) fn method_to_error(method: Method) -> Result<ParsedRequest, Error> {
             StatusCode::BadRequest,
             "Empty PATCH request.".to_string(),
         )),
+        Method::Delete => Err(Error::Generic(
+            StatusCode::BadRequest,
+            "DELETE request cannot have a body.".to_string(),
+        )),
     }
 }
 
@@ -334,11 +360,15 @@ pub(crate) mod tests {
 
     use micro_http::HttpConnection;
     use vmm::builder::StartMicrovmError;
+    use vmm::persist::CpuCompatibilityReport;
     use vmm::resources::VmmConfig;
     use vmm::rpc_interface::VmmActionError;
     use vmm::vmm_config::balloon::{BalloonDeviceConfig, BalloonStats};
+    use vmm::vmm_config::drive::BlockDeviceInfo;
     use vmm::vmm_config::instance_info::InstanceInfo;
     use vmm::vmm_config::machine_config::VmConfig;
+    use vmm::vmm_config::net::NetworkInterfaceInfo;
+    use vmm::vmm_config::RateLimiterStatus;
 
     impl PartialEq for ParsedRequest {
         fn eq(&self, other: &ParsedRequest) -> bool {
@@ -562,6 +592,10 @@ pub(crate) mod tests {
             describe(Method::Put, "/mmds", None),
             "Put request on \"/mmds\""
         );
+        assert_eq!(
+            describe(Method::Put, "/mmds/sidecar", Some(&Body::new("body"))),
+            "Put request on \"/mmds/sidecar\""
+        );
         assert_eq!(
             describe(Method::Put, "path", Some(&Body::new("body"))),
             "Put request on \"path\" with body \"body\""
@@ -580,6 +614,12 @@ pub(crate) mod tests {
                 VmmData::BalloonStats(stats) => {
                     http_response(&serde_json::to_string(stats).unwrap(), 200)
                 }
+                VmmData::BlockDevice(info) => {
+                    http_response(&serde_json::to_string(info).unwrap(), 200)
+                }
+                VmmData::NetworkInterface(info) => {
+                    http_response(&serde_json::to_string(info).unwrap(), 200)
+                }
                 VmmData::Empty => http_response("", 204),
                 VmmData::FullVmConfig(cfg) => {
                     http_response(&serde_json::to_string(cfg).unwrap(), 200)
@@ -597,6 +637,9 @@ pub(crate) mod tests {
                     &serde_json::json!({ "firecracker_version": version.as_str() }).to_string(),
                     200,
                 ),
+                VmmData::SnapshotCompatibility(report) => {
+                    http_response(&serde_json::to_string(report).unwrap(), 200)
+                }
             };
             let response = ParsedRequest::convert_to_response(&data);
             assert!(response.write_all(&mut buf).is_ok());
@@ -609,12 +652,31 @@ pub(crate) mod tests {
             swap_out: Some(1),
             ..Default::default()
         }));
+        let drive_cfg = r#"{
+                "drive_id": "rootfs",
+                "path_on_host": "/dev/null",
+                "is_root_device": true,
+                "is_read_only": false
+              }"#;
+        verify_ok_response_with(VmmData::BlockDevice(BlockDeviceInfo {
+            config: serde_json::from_str(drive_cfg).unwrap(),
+            rate_limiter_status: RateLimiterStatus::default(),
+        }));
+        verify_ok_response_with(VmmData::NetworkInterface(NetworkInterfaceInfo {
+            config: serde_json::from_str(r#"{"iface_id": "eth0", "host_dev_name": "tap0"}"#)
+                .unwrap(),
+            rx_rate_limiter_status: RateLimiterStatus::default(),
+            tx_rate_limiter_status: RateLimiterStatus::default(),
+        }));
         verify_ok_response_with(VmmData::Empty);
         verify_ok_response_with(VmmData::FullVmConfig(VmmConfig::default()));
         verify_ok_response_with(VmmData::MachineConfiguration(VmConfig::default()));
         verify_ok_response_with(VmmData::MmdsValue(serde_json::from_str("{}").unwrap()));
         verify_ok_response_with(VmmData::InstanceInformation(InstanceInfo::default()));
         verify_ok_response_with(VmmData::VmmVersion(String::default()));
+        verify_ok_response_with(VmmData::SnapshotCompatibility(
+            CpuCompatibilityReport::default(),
+        ));
 
         // Error.
         let error = VmmActionError::StartMicrovm(StartMicrovmError::MissingKernelConfig);
@@ -663,6 +725,30 @@ pub(crate) mod tests {
         assert!(ParsedRequest::try_from_request(&req).is_ok());
     }
 
+    #[test]
+    fn test_try_from_get_drive() {
+        let (mut sender, receiver) = UnixStream::pair().unwrap();
+        let mut connection = HttpConnection::new(receiver);
+        sender
+            .write_all(http_request("GET", "/drives/rootfs", None).as_bytes())
+            .unwrap();
+        assert!(connection.try_read().is_ok());
+        let req = connection.pop_parsed_request().unwrap();
+        assert!(ParsedRequest::try_from_request(&req).is_ok());
+    }
+
+    #[test]
+    fn test_try_from_get_net() {
+        let (mut sender, receiver) = UnixStream::pair().unwrap();
+        let mut connection = HttpConnection::new(receiver);
+        sender
+            .write_all(http_request("GET", "/network-interfaces/eth0", None).as_bytes())
+            .unwrap();
+        assert!(connection.try_read().is_ok());
+        let req = connection.pop_parsed_request().unwrap();
+        assert!(ParsedRequest::try_from_request(&req).is_ok());
+    }
+
     #[test]
     fn test_try_from_get_machine_config() {
         let (mut sender, receiver) = UnixStream::pair().unwrap();
@@ -863,6 +949,34 @@ pub(crate) mod tests {
         assert!(ParsedRequest::try_from_request(&req).is_ok());
     }
 
+    #[test]
+    fn test_try_from_delete_mmds_tokens() {
+        let (mut sender, receiver) = UnixStream::pair().unwrap();
+        let mut connection = HttpConnection::new(receiver);
+
+        sender
+            .write_all(http_request("DELETE", "/mmds/tokens", None).as_bytes())
+            .unwrap();
+        assert!(connection.try_read().is_ok());
+        let req = connection.pop_parsed_request().unwrap();
+        assert!(ParsedRequest::try_from_request(&req).is_ok());
+
+        // Only the session tokens can be deleted.
+        sender
+            .write_all(http_request("DELETE", "/mmds", None).as_bytes())
+            .unwrap();
+        assert!(connection.try_read().is_ok());
+        let req = connection.pop_parsed_request().unwrap();
+        assert!(ParsedRequest::try_from_request(&req).is_err());
+
+        sender
+            .write_all(http_request("DELETE", "/mmds/tokens", Some(&"{}")).as_bytes())
+            .unwrap();
+        assert!(connection.try_read().is_ok());
+        let req = connection.pop_parsed_request().unwrap();
+        assert!(ParsedRequest::try_from_request(&req).is_err());
+    }
+
     #[test]
     fn test_try_from_put_netif() {
         let (mut sender, receiver) = UnixStream::pair().unwrap();
@@ -980,6 +1094,55 @@ pub(crate) mod tests {
         assert!(ParsedRequest::try_from_request(&req).is_ok());
     }
 
+    #[test]
+    fn test_try_from_patch_vsock() {
+        let (mut sender, receiver) = UnixStream::pair().unwrap();
+        let mut connection = HttpConnection::new(receiver);
+        let body = "{ \
+            \"rx_rate_limiter\": { \
+                \"bandwidth\": { \"size\": 1000, \"refill_time\": 100 } \
+            } \
+        }";
+        sender
+            .write_all(http_request("PATCH", "/vsock", Some(&body)).as_bytes())
+            .unwrap();
+        assert!(connection.try_read().is_ok());
+        let req = connection.pop_parsed_request().unwrap();
+        assert!(ParsedRequest::try_from_request(&req).is_ok());
+    }
+
+    #[test]
+    fn test_try_from_put_rate_limiter() {
+        let (mut sender, receiver) = UnixStream::pair().unwrap();
+        let mut connection = HttpConnection::new(receiver);
+        let body = "{ \
+            \"group_id\": \"disks\", \
+            \"bandwidth\": { \"size\": 1000, \"refill_time\": 100 } \
+        }";
+        sender
+            .write_all(http_request("PUT", "/rate-limiters/disks", Some(&body)).as_bytes())
+            .unwrap();
+        assert!(connection.try_read().is_ok());
+        let req = connection.pop_parsed_request().unwrap();
+        assert!(ParsedRequest::try_from_request(&req).is_ok());
+    }
+
+    #[test]
+    fn test_try_from_patch_rate_limiter() {
+        let (mut sender, receiver) = UnixStream::pair().unwrap();
+        let mut connection = HttpConnection::new(receiver);
+        let body = "{ \
+            \"group_id\": \"disks\", \
+            \"ops\": { \"size\": 1000, \"refill_time\": 100 } \
+        }";
+        sender
+            .write_all(http_request("PATCH", "/rate-limiters/disks", Some(&body)).as_bytes())
+            .unwrap();
+        assert!(connection.try_read().is_ok());
+        let req = connection.pop_parsed_request().unwrap();
+        assert!(ParsedRequest::try_from_request(&req).is_ok());
+    }
+
     #[test]
     fn test_try_from_patch_balloon() {
         let (mut sender, receiver) = UnixStream::pair().unwrap();
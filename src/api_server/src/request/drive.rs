@@ -7,6 +7,13 @@ use crate::request::{Body, StatusCode};
 use logger::{IncMetric, METRICS};
 use vmm::vmm_config::drive::{BlockDeviceConfig, BlockDeviceUpdateConfig};
 
+pub(crate) fn parse_get_drive(id_from_path: Option<&&str>) -> Result<ParsedRequest, Error> {
+    let id = id_from_path.ok_or(Error::EmptyID)?;
+    Ok(ParsedRequest::new_sync(VmmAction::GetBlockDevice(
+        checked_id(id)?.to_string(),
+    )))
+}
+
 pub(crate) fn parse_put_drive(
     body: &Body,
     id_from_path: Option<&&str>,
@@ -88,6 +95,17 @@ mod tests {
     use super::*;
     use crate::parsed_request::tests::vmm_action_from_request;
 
+    #[test]
+    fn test_parse_get_drive_request() {
+        assert!(parse_get_drive(None).is_err());
+        assert!(parse_get_drive(Some(&"bad id")).is_err());
+
+        match vmm_action_from_request(parse_get_drive(Some(&"drive")).unwrap()) {
+            VmmAction::GetBlockDevice(id) => assert_eq!(id, "drive"),
+            _ => panic!("Test failed: Invalid parameters"),
+        };
+    }
+
     #[test]
     fn test_parse_patch_drive_request() {
         assert!(parse_patch_drive(&Body::new("invalid_payload"), None).is_err());
@@ -244,6 +262,11 @@ mod tests {
                 "is_read_only": true,
                 "cache_type": "Unsafe",
                 "io_engine": "Sync",
+                "direct_io": true,
+                "num_queues": 4,
+                "io_threads": false,
+                "fixed_buffers": false,
+                "sq_poll": false,
                 "rate_limiter": {
                     "bandwidth": {
                         "size": 0,
@@ -258,5 +281,15 @@ mod tests {
                 }
             }"#;
         assert!(parse_put_drive(&Body::new(body), Some(&"1000")).is_ok());
+
+        // PUT with an NBD server socket as the drive.
+        let body = r#"{
+            "drive_id": "1000",
+            "path_on_host": "/run/nbd.sock",
+            "is_root_device": false,
+            "is_read_only": false,
+            "io_engine": "Nbd"
+        }"#;
+        assert!(parse_put_drive(&Body::new(body), Some(&"1000")).is_ok());
     }
 }
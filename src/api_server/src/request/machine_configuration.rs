@@ -81,6 +81,7 @@ mod tests {
             mem_size_mib: Some(1024),
             smt: Some(false),
             cpu_template: Some(CpuFeaturesTemplate::None),
+            cpu_template_path: None,
             track_dirty_pages: Some(false),
         };
 
@@ -100,6 +101,7 @@ mod tests {
             mem_size_mib: Some(1024),
             smt: Some(false),
             cpu_template: Some(CpuFeaturesTemplate::None),
+            cpu_template_path: None,
             track_dirty_pages: Some(true),
         };
 
@@ -114,6 +116,7 @@ mod tests {
                 "mem_size_mib": 1024,
                 "smt": false,
                 "cpu_template": "T2",
+                "cpu_template_path": "/tmp/template.json",
                 "track_dirty_pages": true
               }"#;
 
@@ -125,6 +128,7 @@ mod tests {
                 mem_size_mib: Some(1024),
                 smt: Some(false),
                 cpu_template: Some(CpuFeaturesTemplate::T2),
+                cpu_template_path: Some("/tmp/template.json".to_string()),
                 track_dirty_pages: Some(true),
             };
 
@@ -154,6 +158,7 @@ mod tests {
                 mem_size_mib: Some(1024),
                 smt: Some(true),
                 cpu_template: Some(CpuFeaturesTemplate::None),
+                cpu_template_path: None,
                 track_dirty_pages: Some(true),
             };
 
@@ -189,6 +194,14 @@ mod tests {
         #[cfg(target_arch = "x86_64")]
         assert!(parse_patch_machine_config(&Body::new(body)).is_ok());
 
+        let body = r#"{
+                "cpu_template_path": "/tmp/template.json"
+              }"#;
+        #[cfg(target_arch = "aarch64")]
+        assert!(parse_patch_machine_config(&Body::new(body)).is_err());
+        #[cfg(target_arch = "x86_64")]
+        assert!(parse_patch_machine_config(&Body::new(body)).is_ok());
+
         let body = r#"{
                 "vcpu_count": 8,
                 "mem_size_mib": 1024
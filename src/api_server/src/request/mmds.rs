@@ -1,15 +1,19 @@
 // Copyright 2019 Amazon.com, Inc. or its affiliates. All Rights Reserved.
 // SPDX-License-Identifier: Apache-2.0
 
-use crate::parsed_request::{Error, ParsedRequest};
+use crate::parsed_request::{checked_id, Error, ParsedRequest};
 use crate::request::Body;
 use logger::{IncMetric, METRICS};
-use micro_http::StatusCode;
 use vmm::rpc_interface::VmmAction;
 
-pub(crate) fn parse_get_mmds() -> Result<ParsedRequest, Error> {
+pub(crate) fn parse_get_mmds(path_second_token: Option<&&str>) -> Result<ParsedRequest, Error> {
     METRICS.get_api_requests.mmds_count.inc();
-    Ok(ParsedRequest::new_sync(VmmAction::GetMMDS))
+    match path_second_token {
+        None => Ok(ParsedRequest::new_sync(VmmAction::GetMMDS)),
+        Some(&data_store) => Ok(ParsedRequest::new_sync(VmmAction::GetMMDSDataStore(
+            checked_id(data_store)?.to_string(),
+        ))),
+    }
 }
 
 pub(crate) fn parse_put_mmds(
@@ -30,33 +34,60 @@ pub(crate) fn parse_put_mmds(
                 Error::SerdeJson(e)
             })?,
         ))),
-        Some(&unrecognized) => {
-            METRICS.put_api_requests.mmds_fails.inc();
-            Err(Error::Generic(
-                StatusCode::BadRequest,
-                format!("Unrecognized PUT request path `{}`.", unrecognized),
-            ))
+        Some(&data_store) => {
+            let data_store = checked_id(data_store).map_err(|e| {
+                METRICS.put_api_requests.mmds_fails.inc();
+                e
+            })?;
+            Ok(ParsedRequest::new_sync(VmmAction::PutMMDSDataStore(
+                data_store.to_string(),
+                serde_json::from_slice(body.raw()).map_err(|e| {
+                    METRICS.put_api_requests.mmds_fails.inc();
+                    Error::SerdeJson(e)
+                })?,
+            )))
         }
     }
 }
 
-pub(crate) fn parse_patch_mmds(body: &Body) -> Result<ParsedRequest, Error> {
+pub(crate) fn parse_delete_mmds_tokens() -> Result<ParsedRequest, Error> {
+    Ok(ParsedRequest::new_sync(VmmAction::RevokeMMDSTokens))
+}
+
+pub(crate) fn parse_patch_mmds(
+    body: &Body,
+    path_second_token: Option<&&str>,
+) -> Result<ParsedRequest, Error> {
     METRICS.patch_api_requests.mmds_count.inc();
-    Ok(ParsedRequest::new_sync(VmmAction::PatchMMDS(
-        serde_json::from_slice(body.raw()).map_err(|e| {
-            METRICS.patch_api_requests.mmds_fails.inc();
-            Error::SerdeJson(e)
-        })?,
-    )))
+    let value = serde_json::from_slice(body.raw()).map_err(|e| {
+        METRICS.patch_api_requests.mmds_fails.inc();
+        Error::SerdeJson(e)
+    })?;
+    match path_second_token {
+        None => Ok(ParsedRequest::new_sync(VmmAction::PatchMMDS(value))),
+        Some(&data_store) => {
+            let data_store = checked_id(data_store).map_err(|e| {
+                METRICS.patch_api_requests.mmds_fails.inc();
+                e
+            })?;
+            Ok(ParsedRequest::new_sync(VmmAction::PatchMMDSDataStore(
+                data_store.to_string(),
+                value,
+            )))
+        }
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::parsed_request::tests::vmm_action_from_request;
 
     #[test]
     fn test_parse_get_mmds_request() {
-        assert!(parse_get_mmds().is_ok());
+        assert!(parse_get_mmds(None).is_ok());
+        assert!(parse_get_mmds(Some(&"sidecar")).is_ok());
+        assert!(parse_get_mmds(Some(&"invalid.store")).is_err());
         assert!(METRICS.get_api_requests.mmds_count.count() > 0);
     }
 
@@ -110,8 +141,32 @@ mod tests {
                 "invalid_config": "invalid_value"
               }"#;
         assert!(parse_put_mmds(&Body::new(invalid_config_body), Some(&config_path)).is_err());
-        assert!(parse_put_mmds(&Body::new(body), Some(&"invalid_path")).is_err());
+        assert!(parse_put_mmds(&Body::new(body), Some(&"invalid.path")).is_err());
         assert!(parse_put_mmds(&Body::new(invalid_body), Some(&config_path)).is_err());
+
+        // Test named data store path.
+        let body = r#"{
+                "foo": "bar"
+              }"#;
+        match vmm_action_from_request(parse_put_mmds(&Body::new(body), Some(&"sidecar")).unwrap()) {
+            VmmAction::PutMMDSDataStore(data_store, value) => {
+                assert_eq!(data_store, "sidecar");
+                assert_eq!(
+                    value,
+                    serde_json::from_str::<serde_json::Value>(body).unwrap()
+                );
+            }
+            _ => panic!("Test failed."),
+        }
+        assert!(parse_put_mmds(&Body::new(invalid_body), Some(&"sidecar")).is_err());
+    }
+
+    #[test]
+    fn test_parse_delete_mmds_tokens_request() {
+        match vmm_action_from_request(parse_delete_mmds_tokens().unwrap()) {
+            VmmAction::RevokeMMDSTokens => (),
+            _ => panic!("Test failed."),
+        }
     }
 
     #[test]
@@ -119,9 +174,11 @@ mod tests {
         let body = r#"{
                 "foo": "bar"
               }"#;
-        assert!(parse_patch_mmds(&Body::new(body)).is_ok());
+        assert!(parse_patch_mmds(&Body::new(body), None).is_ok());
         assert!(METRICS.patch_api_requests.mmds_count.count() > 0);
-        assert!(parse_patch_mmds(&Body::new("invalid_body")).is_err());
+        assert!(parse_patch_mmds(&Body::new("invalid_body"), None).is_err());
+        assert!(parse_patch_mmds(&Body::new(body), Some(&"sidecar")).is_ok());
+        assert!(parse_patch_mmds(&Body::new(body), Some(&"invalid.store")).is_err());
         assert!(METRICS.patch_api_requests.mmds_fails.count() > 0);
     }
 }
@@ -11,6 +11,7 @@ pub mod machine_configuration;
 pub mod metrics;
 pub mod mmds;
 pub mod net;
+pub mod rate_limiter;
 pub mod snapshot;
 pub mod version;
 pub mod vsock;
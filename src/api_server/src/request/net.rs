@@ -7,6 +7,13 @@ use crate::request::{Body, StatusCode};
 use logger::{IncMetric, METRICS};
 use vmm::vmm_config::net::{NetworkInterfaceConfig, NetworkInterfaceUpdateConfig};
 
+pub(crate) fn parse_get_net(id_from_path: Option<&&str>) -> Result<ParsedRequest, Error> {
+    let id = id_from_path.ok_or(Error::EmptyID)?;
+    Ok(ParsedRequest::new_sync(VmmAction::GetNetworkInterface(
+        checked_id(id)?.to_string(),
+    )))
+}
+
 pub(crate) fn parse_put_net(
     body: &Body,
     id_from_path: Option<&&str>,
@@ -69,6 +76,17 @@ mod tests {
     use super::*;
     use crate::parsed_request::tests::vmm_action_from_request;
 
+    #[test]
+    fn test_parse_get_net_request() {
+        assert!(parse_get_net(None).is_err());
+        assert!(parse_get_net(Some(&"bad id")).is_err());
+
+        match vmm_action_from_request(parse_get_net(Some(&"eth0")).unwrap()) {
+            VmmAction::GetNetworkInterface(id) => assert_eq!(id, "eth0"),
+            _ => panic!("Test failed: Invalid parameters"),
+        };
+    }
+
     #[test]
     fn test_parse_put_net_request() {
         let body = r#"{
@@ -0,0 +1,150 @@
+// Copyright 2022 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+use super::super::VmmAction;
+use crate::parsed_request::{checked_id, Error, ParsedRequest};
+use crate::request::{Body, StatusCode};
+use logger::{IncMetric, METRICS};
+use vmm::vmm_config::rate_limiter_group::{RateLimiterGroupConfig, RateLimiterGroupUpdateConfig};
+
+pub(crate) fn parse_put_rate_limiter(
+    body: &Body,
+    id_from_path: Option<&&str>,
+) -> Result<ParsedRequest, Error> {
+    METRICS.put_api_requests.rate_limiters_count.inc();
+    let id = if let Some(id) = id_from_path {
+        checked_id(id)?
+    } else {
+        METRICS.put_api_requests.rate_limiters_fails.inc();
+        return Err(Error::EmptyID);
+    };
+
+    let group_cfg = serde_json::from_slice::<RateLimiterGroupConfig>(body.raw()).map_err(|e| {
+        METRICS.put_api_requests.rate_limiters_fails.inc();
+        Error::SerdeJson(e)
+    })?;
+
+    if id != group_cfg.group_id {
+        METRICS.put_api_requests.rate_limiters_fails.inc();
+        Err(Error::Generic(
+            StatusCode::BadRequest,
+            "The id from the path does not match the id from the body!".to_string(),
+        ))
+    } else {
+        Ok(ParsedRequest::new_sync(VmmAction::SetRateLimiterGroup(
+            group_cfg,
+        )))
+    }
+}
+
+pub(crate) fn parse_patch_rate_limiter(
+    body: &Body,
+    id_from_path: Option<&&str>,
+) -> Result<ParsedRequest, Error> {
+    METRICS.patch_api_requests.rate_limiters_count.inc();
+    let id = if let Some(id) = id_from_path {
+        checked_id(id)?
+    } else {
+        METRICS.patch_api_requests.rate_limiters_fails.inc();
+        return Err(Error::EmptyID);
+    };
+
+    let group_update_cfg = serde_json::from_slice::<RateLimiterGroupUpdateConfig>(body.raw())
+        .map_err(|e| {
+            METRICS.patch_api_requests.rate_limiters_fails.inc();
+            Error::SerdeJson(e)
+        })?;
+
+    if id != group_update_cfg.group_id {
+        METRICS.patch_api_requests.rate_limiters_fails.inc();
+        return Err(Error::Generic(
+            StatusCode::BadRequest,
+            String::from("The id from the path does not match the id from the body!"),
+        ));
+    }
+
+    if group_update_cfg.bandwidth.is_none() && group_update_cfg.ops.is_none() {
+        METRICS.patch_api_requests.rate_limiters_fails.inc();
+        return Err(Error::Generic(
+            StatusCode::BadRequest,
+            String::from("Please specify at least one property to patch: bandwidth, ops."),
+        ));
+    }
+
+    Ok(ParsedRequest::new_sync(VmmAction::UpdateRateLimiterGroup(
+        group_update_cfg,
+    )))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parsed_request::tests::vmm_action_from_request;
+
+    #[test]
+    fn test_parse_put_rate_limiter_request() {
+        assert!(parse_put_rate_limiter(&Body::new("invalid_payload"), None).is_err());
+        assert!(parse_put_rate_limiter(&Body::new("invalid_payload"), Some(&"id")).is_err());
+
+        // PUT with unknown fields.
+        let body = r#"{
+                "group_id": "disks",
+                "size": 1000
+              }"#;
+        assert!(parse_put_rate_limiter(&Body::new(body), Some(&"disks")).is_err());
+
+        let body = r#"{
+                "group_id": "disks",
+                "bandwidth": {
+                    "size": 209715200,
+                    "refill_time": 1000
+                }
+              }"#;
+        // Must fail since the group id differs from id_from_path.
+        assert!(parse_put_rate_limiter(&Body::new(body), Some(&"nets")).is_err());
+
+        match vmm_action_from_request(
+            parse_put_rate_limiter(&Body::new(body), Some(&"disks")).unwrap(),
+        ) {
+            VmmAction::SetRateLimiterGroup(cfg) => {
+                assert_eq!(cfg.group_id, "disks");
+                assert_eq!(cfg.bandwidth.unwrap().size, 209_715_200);
+                assert!(cfg.ops.is_none());
+            }
+            _ => panic!("Test failed: Invalid parameters"),
+        };
+    }
+
+    #[test]
+    fn test_parse_patch_rate_limiter_request() {
+        assert!(parse_patch_rate_limiter(&Body::new("invalid_payload"), None).is_err());
+        assert!(parse_patch_rate_limiter(&Body::new("invalid_payload"), Some(&"id")).is_err());
+
+        // PATCH without any property to update.
+        let body = r#"{
+                "group_id": "disks"
+              }"#;
+        assert!(parse_patch_rate_limiter(&Body::new(body), Some(&"disks")).is_err());
+
+        let body = r#"{
+                "group_id": "disks",
+                "ops": {
+                    "size": 1000,
+                    "refill_time": 100
+                }
+              }"#;
+        // Must fail since the group id differs from id_from_path.
+        assert!(parse_patch_rate_limiter(&Body::new(body), Some(&"nets")).is_err());
+
+        match vmm_action_from_request(
+            parse_patch_rate_limiter(&Body::new(body), Some(&"disks")).unwrap(),
+        ) {
+            VmmAction::UpdateRateLimiterGroup(cfg) => {
+                assert_eq!(cfg.group_id, "disks");
+                assert!(cfg.bandwidth.is_none());
+                assert_eq!(cfg.ops.unwrap().size, 1000);
+            }
+            _ => panic!("Test failed: Invalid parameters"),
+        };
+    }
+}
@@ -110,6 +110,7 @@ mod tests {
             mem_file_path: PathBuf::from("bar"),
             enable_diff_snapshots: false,
             resume_vm: false,
+            dry_run: false,
         };
         match vmm_action_from_request(parse_put_snapshot(&Body::new(body), Some(&"load")).unwrap())
         {
@@ -128,6 +129,7 @@ mod tests {
             mem_file_path: PathBuf::from("bar"),
             enable_diff_snapshots: true,
             resume_vm: false,
+            dry_run: false,
         };
 
         match vmm_action_from_request(parse_put_snapshot(&Body::new(body), Some(&"load")).unwrap())
@@ -147,6 +149,27 @@ mod tests {
             mem_file_path: PathBuf::from("bar"),
             enable_diff_snapshots: false,
             resume_vm: true,
+            dry_run: false,
+        };
+
+        match vmm_action_from_request(parse_put_snapshot(&Body::new(body), Some(&"load")).unwrap())
+        {
+            VmmAction::LoadSnapshot(cfg) => assert_eq!(cfg, expected_cfg),
+            _ => panic!("Test failed."),
+        }
+
+        body = r#"{
+                "snapshot_path": "foo",
+                "mem_file_path": "bar",
+                "dry_run": true
+              }"#;
+
+        expected_cfg = LoadSnapshotParams {
+            snapshot_path: PathBuf::from("foo"),
+            mem_file_path: PathBuf::from("bar"),
+            enable_diff_snapshots: false,
+            resume_vm: false,
+            dry_run: true,
         };
 
         match vmm_action_from_request(parse_put_snapshot(&Body::new(body), Some(&"load")).unwrap())
@@ -5,7 +5,7 @@ use super::super::VmmAction;
 use crate::parsed_request::{Error, ParsedRequest};
 use crate::request::Body;
 use logger::{IncMetric, METRICS};
-use vmm::vmm_config::vsock::VsockDeviceConfig;
+use vmm::vmm_config::vsock::{VsockDeviceConfig, VsockDeviceUpdateConfig};
 
 pub(crate) fn parse_put_vsock(body: &Body) -> Result<ParsedRequest, Error> {
     METRICS.put_api_requests.vsock_count.inc();
@@ -32,10 +32,22 @@ pub(crate) fn parse_put_vsock(body: &Body) -> Result<ParsedRequest, Error> {
     Ok(parsed_req)
 }
 
+pub(crate) fn parse_patch_vsock(body: &Body) -> Result<ParsedRequest, Error> {
+    METRICS.patch_api_requests.vsock_count.inc();
+    let vsock_cfg = serde_json::from_slice::<VsockDeviceUpdateConfig>(body.raw()).map_err(|e| {
+        METRICS.patch_api_requests.vsock_fails.inc();
+        Error::SerdeJson(e)
+    })?;
+
+    Ok(ParsedRequest::new_sync(VmmAction::UpdateVsockDevice(
+        vsock_cfg,
+    )))
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::parsed_request::tests::depr_action_from_req;
+    use crate::parsed_request::tests::{depr_action_from_req, vmm_action_from_request};
 
     #[test]
     fn test_parse_put_vsock_request() {
@@ -45,6 +57,23 @@ mod tests {
               }"#;
         assert!(parse_put_vsock(&Body::new(body)).is_ok());
 
+        let body = r#"{
+                "guest_cid": 42,
+                "tcp": {
+                    "connect": [{"guest_port": 52, "host_addr": "127.0.0.1:8080"}],
+                    "listen": [{"host_addr": "127.0.0.1:9000", "guest_port": 1024}]
+                }
+              }"#;
+        assert!(parse_put_vsock(&Body::new(body)).is_ok());
+
+        let body = r#"{
+                "guest_cid": 42,
+                "tcp": {
+                    "connect": [{"guest_port": 52, "host_addr": "localhost"}]
+                }
+              }"#;
+        assert!(parse_put_vsock(&Body::new(body)).is_err());
+
         let body = r#"{
                 "guest_cid": 42,
                 "invalid_field": false
@@ -52,6 +81,29 @@ mod tests {
         assert!(parse_put_vsock(&Body::new(body)).is_err());
     }
 
+    #[test]
+    fn test_parse_patch_vsock_request() {
+        let body = r#"{
+                "rx_rate_limiter": {
+                    "bandwidth": { "size": 1000, "refill_time": 100 }
+                },
+                "tx_rate_limiter": {
+                    "ops": { "size": 10, "refill_time": 1000 }
+                }
+              }"#;
+        let expected_cfg = serde_json::from_str::<VsockDeviceUpdateConfig>(body).unwrap();
+        match vmm_action_from_request(parse_patch_vsock(&Body::new(body)).unwrap()) {
+            VmmAction::UpdateVsockDevice(cfg) => assert_eq!(cfg, expected_cfg),
+            _ => panic!("Test failed."),
+        }
+
+        // Only the rate limiters can be updated.
+        let body = r#"{
+                "guest_cid": 42
+              }"#;
+        assert!(parse_patch_vsock(&Body::new(body)).is_err());
+    }
+
     #[test]
     fn test_depr_vsock_id() {
         let body = r#"{
@@ -10,13 +10,17 @@ use std::convert::From;
 use std::fs::{File, OpenOptions};
 use std::io::{Seek, SeekFrom, Write};
 use std::os::linux::fs::MetadataExt;
+use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::io::{AsRawFd, RawFd};
 use std::path::PathBuf;
 use std::result;
 use std::sync::atomic::AtomicUsize;
 use std::sync::Arc;
+use std::time::Instant;
 
-use logger::{error, warn, IncMetric, METRICS};
-use rate_limiter::{BucketUpdate, RateLimiter};
+use logger::{error, warn, IncMetric, StoreMetric, METRICS};
+use rate_limiter::{BucketUpdate, IoDirection, RateLimiter};
+use utils::epoll::EventSet;
 use utils::eventfd::EventFd;
 use utils::kernel_version::{min_kernel_version_for_io_uring, KernelVersion};
 use virtio_gen::virtio_blk::*;
@@ -24,10 +28,13 @@ use vm_memory::GuestMemoryMmap;
 
 use super::io as block_io;
 use super::io::async_io;
+use super::io::nbd;
+use super::io_thread::{Command, IoThread};
 use super::{
     super::{ActivateResult, DeviceState, Queue, VirtioDevice, TYPE_BLOCK},
     request::*,
-    Error, CONFIG_SPACE_SIZE, QUEUE_SIZES, SECTOR_SHIFT, SECTOR_SIZE,
+    Error, CONFIG_SPACE_SIZE, DISCARD_CONFIG_OFFSET, MAX_NUM_QUEUES, NUM_QUEUES_CONFIG_OFFSET,
+    QUEUE_SIZE, SECTOR_SHIFT, SECTOR_SIZE, SEG_MAX,
 };
 use crate::virtio::{IrqTrigger, IrqType};
 use block_io::FileEngine;
@@ -52,12 +59,15 @@ impl Default for CacheType {
     }
 }
 
-#[derive(Debug, Deserialize, PartialEq, Serialize)]
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
 pub enum FileEngineType {
     /// Use an Async engine, based on io_uring.
     Async,
     /// Use a Sync engine, based on blocking system calls.
     Sync,
+    /// Forward the requests to the default export of an NBD server, listening on the Unix
+    /// socket found at the path of the backing file.
+    Nbd,
 }
 
 impl Default for FileEngineType {
@@ -76,26 +86,88 @@ impl FileEngineType {
 }
 
 /// Helper object for setting up all `Block` fields derived from its backing file.
+#[derive(Clone)]
 pub(crate) struct DiskProperties {
     cache_type: CacheType,
+    // Whether the backing file is opened with `O_DIRECT`, bypassing the host page cache.
+    direct_io: bool,
+    // Whether the submission queues of the async engines are polled by kernel threads.
+    sq_poll: bool,
+    is_read_only: bool,
     file_path: String,
-    file_engine: FileEngine<PendingRequest>,
+    file_engine_type: FileEngineType,
     nsectors: u64,
     image_id: [u8; VIRTIO_BLK_ID_BYTES as usize],
+    // The transmission flags of the NBD export, which advertise the commands it supports.
+    nbd_flags: Option<u16>,
 }
 
 impl DiskProperties {
-    pub fn new(
+    /// Opens the disk, along with the handlers of `num_queues` queues.
+    ///
+    /// The connections of the queues of an NBD drive share a single deadline, and the first one
+    /// probes the export.
+    pub fn open(
+        disk_image_path: String,
+        is_disk_read_only: bool,
+        cache_type: CacheType,
+        file_engine_type: FileEngineType,
+        direct_io: bool,
+        sq_poll: bool,
+        num_queues: u16,
+    ) -> result::Result<(Self, Vec<QueueHandler>), Error> {
+        let deadline = Instant::now() + nbd::CONNECT_TIMEOUT;
+        let (disk, mut probe) = match file_engine_type {
+            FileEngineType::Nbd => {
+                let engine = block_io::NbdEngine::connect_until(&disk_image_path, deadline)
+                    .map_err(|e| Error::FileEngine(block_io::Error::Nbd(e)))?;
+                let disk = Self::new_nbd(
+                    disk_image_path,
+                    is_disk_read_only,
+                    cache_type,
+                    direct_io,
+                    engine.export(),
+                )?;
+                (disk, Some(FileEngine::Nbd(engine)))
+            }
+            _ => {
+                let disk = Self::new(
+                    disk_image_path,
+                    is_disk_read_only,
+                    cache_type,
+                    file_engine_type,
+                    direct_io,
+                    sq_poll,
+                )?;
+                (disk, None)
+            }
+        };
+        if num_queues > 1 && !disk.supports_multi_conn() {
+            return Err(Error::InvalidNumQueues(num_queues));
+        }
+
+        let queue_handlers = (0..num_queues as usize)
+            .map(|index| {
+                let file_engine = match probe.take() {
+                    Some(engine) => engine,
+                    None => disk.open_file_engine(deadline)?,
+                };
+                Ok(QueueHandler::new(index, &disk, file_engine))
+            })
+            .collect::<result::Result<Vec<QueueHandler>, Error>>()?;
+        Ok((disk, queue_handlers))
+    }
+
+    // Probes the backing file, which isn't an NBD socket.
+    fn new(
         disk_image_path: String,
         is_disk_read_only: bool,
         cache_type: CacheType,
         file_engine_type: FileEngineType,
+        direct_io: bool,
+        sq_poll: bool,
     ) -> result::Result<Self, Error> {
-        let mut disk_image = OpenOptions::new()
-            .read(true)
-            .write(!is_disk_read_only)
-            .open(PathBuf::from(&disk_image_path))
-            .map_err(Error::BackingFile)?;
+        let mut disk_image = Self::open_file(&disk_image_path, is_disk_read_only, direct_io)?;
         let disk_size = disk_image
             .seek(SeekFrom::End(0))
             .map_err(Error::BackingFile)? as u64;
@@ -112,25 +184,80 @@ impl DiskProperties {
 
         Ok(Self {
             cache_type,
+            direct_io,
+            sq_poll,
+            is_read_only: is_disk_read_only,
             nsectors: disk_size >> SECTOR_SHIFT,
-            image_id: Self::build_disk_image_id(&disk_image),
+            image_id: Self::build_disk_image_id(&disk_image_path),
             file_path: disk_image_path,
-            file_engine: FileEngine::from_file(disk_image, file_engine_type)
-                .map_err(Error::FileEngine)?,
+            file_engine_type,
+            nbd_flags: None,
         })
     }
 
-    pub fn file_engine(&self) -> &FileEngine<PendingRequest> {
-        &self.file_engine
+    // Describes the export of the NBD server listening on the socket at `socket_path`.
+    fn new_nbd(
+        socket_path: String,
+        is_disk_read_only: bool,
+        cache_type: CacheType,
+        direct_io: bool,
+        export: nbd::NbdExport,
+    ) -> result::Result<Self, Error> {
+        if export.has_flag(nbd::NBD_FLAG_READ_ONLY) && !is_disk_read_only {
+            return Err(Error::FileEngine(block_io::Error::Nbd(
+                nbd::Error::ReadOnlyExport,
+            )));
+        }
+
+        Ok(Self {
+            cache_type,
+            direct_io,
+            // The NBD engine doesn't use io_uring.
+            sq_poll: false,
+            is_read_only: is_disk_read_only,
+            nsectors: export.size >> SECTOR_SHIFT,
+            image_id: Self::build_disk_image_id(&socket_path),
+            file_path: socket_path,
+            file_engine_type: FileEngineType::Nbd,
+            nbd_flags: Some(export.flags),
+        })
     }
 
-    pub fn file_engine_mut(&mut self) -> &mut FileEngine<PendingRequest> {
-        &mut self.file_engine
+    fn open_file(
+        disk_image_path: &str,
+        is_disk_read_only: bool,
+        direct_io: bool,
+    ) -> result::Result<File, Error> {
+        let mut open_options = OpenOptions::new();
+        open_options.read(true).write(!is_disk_read_only);
+        if direct_io {
+            open_options.custom_flags(libc::O_DIRECT);
+        }
+        open_options
+            .open(PathBuf::from(disk_image_path))
+            .map_err(Error::BackingFile)
     }
 
-    #[cfg(test)]
-    pub fn file(&self) -> &File {
-        &self.file_engine.file()
+    /// Opens an IO engine on the backing file.
+    ///
+    /// Each engine gets its own file description, so that the sync engines of several queues
+    /// don't share the file offset.
+    fn open_file_engine(
+        &self,
+        deadline: Instant,
+    ) -> result::Result<FileEngine<PendingRequest>, Error> {
+        if self.file_engine_type == FileEngineType::Nbd {
+            // Each engine gets its own connection to the server instead, set up by `deadline`.
+            return FileEngine::connect_nbd(&self.file_path, deadline).map_err(Error::FileEngine);
+        }
+        let disk_image = Self::open_file(&self.file_path, self.is_read_only, self.direct_io)?;
+        FileEngine::from_file(
+            disk_image,
+            self.file_engine_type,
+            self.direct_io,
+            self.sq_poll,
+        )
+        .map_err(Error::FileEngine)
     }
 
     pub fn nsectors(&self) -> u64 {
@@ -141,8 +268,8 @@ impl DiskProperties {
         &self.image_id
     }
 
-    fn build_device_id(disk_file: &File) -> result::Result<String, Error> {
-        let blk_metadata = disk_file.metadata().map_err(Error::GetFileMetadata)?;
+    fn build_device_id(disk_path: &str) -> result::Result<String, Error> {
+        let blk_metadata = std::fs::metadata(disk_path).map_err(Error::GetFileMetadata)?;
         // This is how kvmtool does it.
         let device_id = format!(
             "{}{}{}",
@@ -153,9 +280,9 @@ impl DiskProperties {
         Ok(device_id)
     }
 
-    fn build_disk_image_id(disk_file: &File) -> [u8; VIRTIO_BLK_ID_BYTES as usize] {
+    fn build_disk_image_id(disk_path: &str) -> [u8; VIRTIO_BLK_ID_BYTES as usize] {
         let mut default_id = [0; VIRTIO_BLK_ID_BYTES as usize];
-        match Self::build_device_id(disk_file) {
+        match Self::build_device_id(disk_path) {
             Err(_) => {
                 warn!("Could not generate device id. We'll use a default.");
             }
@@ -177,19 +304,342 @@ impl DiskProperties {
 
     /// Provides vec containing the virtio block configuration space
     /// buffer. The config space is populated with the disk size based
-    /// on the backing file size.
-    pub fn virtio_block_config_space(&self) -> Vec<u8> {
+    /// on the backing file size, with the maximum number of data
+    /// segments of a request, with the number of queues, and with the
+    /// limits of the discard and write zeroes requests.
+    pub fn virtio_block_config_space(&self, num_queues: u16) -> Vec<u8> {
         // The config space is little endian.
         let mut config = Vec::with_capacity(CONFIG_SPACE_SIZE);
-        for i in 0..CONFIG_SPACE_SIZE {
-            config.push((self.nsectors >> (8 * i)) as u8);
-        }
+        config.extend_from_slice(&self.nsectors.to_le_bytes());
+        // The maximum size of a segment isn't advertised.
+        config.extend_from_slice(&0u32.to_le_bytes());
+        config.extend_from_slice(&SEG_MAX.to_le_bytes());
+        // The geometry, topology and writeback fields aren't advertised.
+        config.resize(NUM_QUEUES_CONFIG_OFFSET, 0);
+        config.extend_from_slice(&num_queues.to_le_bytes());
+        // Discard and write zeroes requests hold a single range, which is handed over to
+        // `fallocate` as a whole. NBD commands limit the range to 32 bits worth of bytes.
+        let max_sectors = match self.file_engine_type {
+            FileEngineType::Nbd => u32::MAX >> SECTOR_SHIFT,
+            _ => u32::MAX,
+        };
+        config.extend_from_slice(&max_sectors.to_le_bytes());
+        config.extend_from_slice(&1u32.to_le_bytes());
+        config.extend_from_slice(&1u32.to_le_bytes());
+        config.extend_from_slice(&max_sectors.to_le_bytes());
+        config.extend_from_slice(&1u32.to_le_bytes());
+        // Write zeroes requests may deallocate the range.
+        config.push(1);
+        config.resize(CONFIG_SPACE_SIZE, 0);
         config
     }
 
     pub fn cache_type(&self) -> CacheType {
         self.cache_type
     }
+
+    pub fn direct_io(&self) -> bool {
+        self.direct_io
+    }
+
+    pub fn sq_poll(&self) -> bool {
+        self.sq_poll
+    }
+
+    pub fn file_engine_type(&self) -> FileEngineType {
+        self.file_engine_type
+    }
+
+    // Backing files support all the commands, while NBD exports advertise the ones they support.
+    fn backend_supports(&self, nbd_flag: u16) -> bool {
+        self.nbd_flags.map_or(true, |flags| flags & nbd_flag != 0)
+    }
+
+    pub fn supports_flush(&self) -> bool {
+        self.backend_supports(nbd::NBD_FLAG_SEND_FLUSH)
+    }
+
+    pub fn supports_discard(&self) -> bool {
+        self.backend_supports(nbd::NBD_FLAG_SEND_TRIM)
+    }
+
+    pub fn supports_write_zeroes(&self) -> bool {
+        self.backend_supports(nbd::NBD_FLAG_SEND_WRITE_ZEROES)
+    }
+
+    /// Whether the backend can be used by several queues at once.
+    ///
+    /// Each queue of an NBD drive has its own connection, which only sees the writes of the
+    /// others once they complete if the server allows it.
+    pub fn supports_multi_conn(&self) -> bool {
+        self.backend_supports(nbd::NBD_FLAG_CAN_MULTI_CONN)
+    }
+}
+
+/// The IO engine and the processing state of a virtio queue of the block device.
+///
+/// The handler is driven either by the device on the VMM thread, or by an IO thread dedicated to
+/// its queue.
+pub(crate) struct QueueHandler {
+    // The index of the queue, which selects its metrics.
+    index: usize,
+    cache_type: CacheType,
+    pub(crate) file_engine: FileEngine<PendingRequest>,
+    pub(crate) is_io_engine_throttled: bool,
+    // The number of requests submitted to the IO engine which didn't complete yet.
+    in_flight: usize,
+}
+
+impl QueueHandler {
+    fn new(index: usize, disk: &DiskProperties, file_engine: FileEngine<PendingRequest>) -> Self {
+        QueueHandler {
+            index,
+            cache_type: disk.cache_type(),
+            file_engine,
+            is_io_engine_throttled: false,
+            in_flight: 0,
+        }
+    }
+
+    /// The file descriptor signaling the completion of the requests submitted to the IO engine.
+    pub fn completion_fd(&self) -> Option<RawFd> {
+        match &self.file_engine {
+            FileEngine::Async(engine) => Some(engine.completion_evt().as_raw_fd()),
+            FileEngine::Sync(_) => None,
+            FileEngine::Nbd(engine) => engine.completion_fd(),
+        }
+    }
+
+    /// The completion event to register with the event loop, along with the events to wait for.
+    pub fn completion_event(&self) -> Option<(RawFd, EventSet)> {
+        let event_set = match &self.file_engine {
+            // The NBD commands which don't fit in the socket are sent once it's writable.
+            FileEngine::Nbd(engine) if engine.has_unsent_commands() => EventSet::IN | EventSet::OUT,
+            _ => EventSet::IN,
+        };
+        self.completion_fd().map(|fd| (fd, event_set))
+    }
+
+    fn add_used_descriptor(
+        queue: &mut Queue,
+        index: u16,
+        len: u32,
+        mem: &GuestMemoryMmap,
+        irq_trigger: &IrqTrigger,
+    ) {
+        queue
+            .add_used(mem, index, len)
+            .unwrap_or_else(|e| error!("Failed to add available descriptor head {}: {}", index, e));
+
+        if queue.prepare_kick(mem) {
+            irq_trigger.trigger_irq(IrqType::Vring).unwrap_or_else(|_| {
+                METRICS.block.event_fails.inc();
+            });
+        }
+    }
+
+    fn update_queue_depth(&mut self, in_flight: usize) {
+        self.in_flight = in_flight;
+        METRICS.block.queue_depth[self.index].store(in_flight);
+    }
+
+    pub fn process_queue(
+        &mut self,
+        queue: &mut Queue,
+        disk: &DiskProperties,
+        mem: &GuestMemoryMmap,
+        irq_trigger: &IrqTrigger,
+        rate_limiter: &mut RateLimiter,
+    ) {
+        let mut used_any = false;
+
+        while let Some(head) = queue.pop_or_enable_notification(mem) {
+            let processing_result = match Request::parse(&head, mem, disk.nsectors()) {
+                Ok(request) => {
+                    if request.rate_limit(rate_limiter) {
+                        // Stop processing the queue and return this descriptor chain to the
+                        // avail ring, for later processing.
+                        queue.undo_pop();
+                        METRICS.block.rate_limiter_throttled_events.inc();
+                        match request.io_direction() {
+                            Some(IoDirection::Read) => {
+                                METRICS.block.read_rate_limiter_throttled_events.inc()
+                            }
+                            Some(IoDirection::Write) => {
+                                METRICS.block.write_rate_limiter_throttled_events.inc()
+                            }
+                            None => (),
+                        }
+                        break;
+                    }
+
+                    used_any = true;
+                    request.process(&mut self.file_engine, disk.image_id(), head.index, mem)
+                }
+                Err(e) => {
+                    error!("Failed to parse available descriptor chain: {:?}", e);
+                    METRICS.block.execute_fails.inc();
+                    ProcessingResult::Executed(FinishedRequest {
+                        num_bytes_to_mem: 0,
+                        desc_idx: head.index,
+                    })
+                }
+            };
+
+            match processing_result {
+                ProcessingResult::Submitted => {
+                    self.update_queue_depth(self.in_flight + 1);
+                }
+                ProcessingResult::Throttled => {
+                    queue.undo_pop();
+                    self.is_io_engine_throttled = true;
+                    break;
+                }
+                ProcessingResult::Executed(finished) => {
+                    Self::add_used_descriptor(
+                        queue,
+                        head.index,
+                        finished.num_bytes_to_mem,
+                        mem,
+                        irq_trigger,
+                    );
+                }
+            }
+        }
+
+        if let FileEngine::Async(engine) = &mut self.file_engine {
+            if let Err(e) = engine.kick_submission_queue() {
+                error!("Error submitting pending block requests: {:?}", e);
+            }
+        }
+
+        if !used_any {
+            METRICS.block.no_avail_buffer.inc();
+        }
+    }
+
+    fn process_async_completion_queue(
+        &mut self,
+        queue: &mut Queue,
+        mem: &GuestMemoryMmap,
+        irq_trigger: &IrqTrigger,
+    ) {
+        let mut completed = 0;
+
+        loop {
+            let (pending, res) = match &mut self.file_engine {
+                FileEngine::Async(engine) => match engine.pop(mem) {
+                    Err(error) => {
+                        error!("Failed to read completed io_uring entry: {:?}", error);
+                        break;
+                    }
+                    Ok(None) => break,
+                    Ok(Some(cqe)) => {
+                        let res = cqe.result().map_err(|error| {
+                            IoErr::FileEngine(block_io::Error::Async(async_io::Error::IO(error)))
+                        });
+                        (cqe.user_data(), res)
+                    }
+                },
+                FileEngine::Nbd(engine) => match engine.pop(mem) {
+                    None => break,
+                    Some((pending, res)) => (
+                        pending,
+                        res.map_err(|error| IoErr::FileEngine(block_io::Error::Nbd(error))),
+                    ),
+                },
+                FileEngine::Sync(_) => {
+                    error!("The block device doesn't use an async IO engine");
+                    return;
+                }
+            };
+            let finished = pending.finish(mem, res);
+            completed += 1;
+
+            Self::add_used_descriptor(
+                queue,
+                finished.desc_idx,
+                finished.num_bytes_to_mem,
+                mem,
+                irq_trigger,
+            );
+        }
+
+        self.update_queue_depth(self.in_flight.saturating_sub(completed));
+    }
+
+    pub fn process_async_completion_event(
+        &mut self,
+        queue: &mut Queue,
+        disk: &DiskProperties,
+        mem: &GuestMemoryMmap,
+        irq_trigger: &IrqTrigger,
+        rate_limiter: &mut RateLimiter,
+    ) {
+        match &mut self.file_engine {
+            FileEngine::Async(engine) => {
+                if let Err(e) = engine.completion_evt().read() {
+                    error!("Failed to get async completion event: {:?}", e);
+                    return;
+                }
+            }
+            // Upon losing the connection, the in-flight requests still complete, with an error.
+            FileEngine::Nbd(engine) => {
+                if let Err(e) = engine.process_io() {
+                    error!("Failed to exchange NBD commands: {:?}", e);
+                }
+            }
+            FileEngine::Sync(_) => {
+                error!("The block device doesn't use an async IO engine");
+                return;
+            }
+        }
+
+        self.process_async_completion_queue(queue, mem, irq_trigger);
+
+        if self.is_io_engine_throttled {
+            self.is_io_engine_throttled = false;
+            self.process_queue(queue, disk, mem, irq_trigger, rate_limiter);
+        }
+    }
+
+    // Registers the guest memory with the async IO engine.
+    // Registration is an optimization, so the queue keeps working without it.
+    pub fn register_guest_memory(&mut self, mem: &GuestMemoryMmap) {
+        if let FileEngine::Async(engine) = &mut self.file_engine {
+            if let Err(e) = engine.register_memory(mem) {
+                warn!(
+                    "Block: Failed to register guest memory with the IO engine, \
+                     falling back to unregistered buffers: {:?}",
+                    e
+                );
+            }
+        }
+    }
+
+    /// Completes the in-flight requests, so that the queue can be saved.
+    pub fn prepare_save(&mut self, queue: &mut Queue, mem: &GuestMemoryMmap, irq: &IrqTrigger) {
+        if let Err(e) = self.file_engine.drain_and_flush(false) {
+            error!("Failed to drain ops and flush block data: {:?}", e);
+        }
+        if !matches!(self.file_engine, FileEngine::Sync(_)) {
+            self.process_async_completion_queue(queue, mem, irq);
+        }
+    }
+}
+
+impl Drop for QueueHandler {
+    fn drop(&mut self) {
+        let res = match self.cache_type {
+            CacheType::Unsafe => self.file_engine.drain(true),
+            CacheType::Writeback => self.file_engine.drain_and_flush(true),
+        };
+        if let Err(e) = res {
+            error!("Failed to drain ops on drop: {:?}", e);
+        }
+        // The completions of the in-flight requests were discarded.
+        self.update_queue_depth(0);
+    }
 }
 
 /// Virtio device for exposing block level read/write operations on a host file.
@@ -205,7 +655,7 @@ pub struct Block {
 
     // Transport related fields.
     pub(crate) queues: Vec<Queue>,
-    pub(crate) queue_evts: [EventFd; 1],
+    pub(crate) queue_evts: Vec<EventFd>,
     pub(crate) device_state: DeviceState,
     pub(crate) irq_trigger: IrqTrigger,
 
@@ -214,19 +664,20 @@ pub struct Block {
     pub(crate) partuuid: Option<String>,
     pub(crate) root_device: bool,
     pub(crate) rate_limiter: RateLimiter,
-    is_io_engine_throttled: bool,
-}
-
-macro_rules! unwrap_async_file_engine_or_return {
-    ($file_engine: expr) => {
-        match $file_engine {
-            FileEngine::Async(engine) => engine,
-            FileEngine::Sync(_) => {
-                error!("The block device doesn't use an async IO engine");
-                return;
-            }
-        }
-    };
+    // One handler per queue, while the queues are processed on the VMM thread.
+    pub(crate) queue_handlers: Vec<QueueHandler>,
+    // The completion events of the queue handlers registered with the event loop.
+    pub(crate) completion_events: Vec<(RawFd, EventSet)>,
+    // The handlers replaced by a disk update, which are kept until their completion events are
+    // un-registered from the event loop.
+    pub(crate) retired_queue_handlers: Vec<QueueHandler>,
+    // Whether the queues are processed on dedicated IO threads once the device is activated.
+    pub(crate) io_threads: bool,
+    // The running IO threads, which took over the queue handlers. While they run, they hold the
+    // live state of the queues, and `queues` only holds their configuration.
+    threads: Vec<IoThread>,
+    // Whether the guest memory gets registered with the async IO engine upon activation.
+    pub(crate) fixed_buffers: bool,
 }
 
 impl Block {
@@ -243,34 +694,62 @@ impl Block {
         is_disk_root: bool,
         rate_limiter: RateLimiter,
         file_engine_type: FileEngineType,
+        direct_io: bool,
+        num_queues: u16,
+        io_threads: bool,
+        fixed_buffers: bool,
+        sq_poll: bool,
     ) -> result::Result<Block, Error> {
-        let disk_properties = DiskProperties::new(
+        if num_queues == 0 || num_queues > MAX_NUM_QUEUES {
+            return Err(Error::InvalidNumQueues(num_queues));
+        }
+
+        let (disk_properties, queue_handlers) = DiskProperties::open(
             disk_image_path,
             is_disk_read_only,
             cache_type,
             file_engine_type,
+            direct_io,
+            sq_poll,
+            num_queues,
         )?;
 
-        let mut avail_features = (1u64 << VIRTIO_F_VERSION_1) | (1u64 << VIRTIO_RING_F_EVENT_IDX);
+        let mut avail_features = (1u64 << VIRTIO_F_VERSION_1)
+            | (1u64 << VIRTIO_RING_F_EVENT_IDX)
+            | (1u64 << VIRTIO_BLK_F_SEG_MAX);
 
-        if cache_type == CacheType::Writeback {
+        if cache_type == CacheType::Writeback && disk_properties.supports_flush() {
             avail_features |= 1u64 << VIRTIO_BLK_F_FLUSH;
         }
 
         if is_disk_read_only {
             avail_features |= 1u64 << VIRTIO_BLK_F_RO;
+        } else {
+            if disk_properties.supports_discard() {
+                avail_features |= 1u64 << VIRTIO_BLK_F_DISCARD;
+            }
+            if disk_properties.supports_write_zeroes() {
+                avail_features |= 1u64 << VIRTIO_BLK_F_WRITE_ZEROES;
+            }
         };
 
-        let queue_evts = [EventFd::new(libc::EFD_NONBLOCK).map_err(Error::EventFd)?];
+        if num_queues > 1 {
+            avail_features |= 1u64 << VIRTIO_BLK_F_MQ;
+        }
+
+        let queue_evts = (0..num_queues)
+            .map(|_| EventFd::new(libc::EFD_NONBLOCK))
+            .collect::<std::io::Result<Vec<EventFd>>>()
+            .map_err(Error::EventFd)?;
 
-        let queues = QUEUE_SIZES.iter().map(|&s| Queue::new(s)).collect();
+        let queues = (0..num_queues).map(|_| Queue::new(QUEUE_SIZE)).collect();
 
         Ok(Block {
             id,
             root_device: is_disk_root,
             partuuid,
             rate_limiter,
-            config_space: disk_properties.virtio_block_config_space(),
+            config_space: disk_properties.virtio_block_config_space(num_queues),
             disk: disk_properties,
             avail_features,
             acked_features: 0u64,
@@ -279,53 +758,52 @@ impl Block {
             device_state: DeviceState::Inactive,
             irq_trigger: IrqTrigger::new().map_err(Error::IrqTrigger)?,
             activate_evt: EventFd::new(libc::EFD_NONBLOCK).map_err(Error::EventFd)?,
-            is_io_engine_throttled: false,
+            queue_handlers,
+            completion_events: Vec::new(),
+            retired_queue_handlers: Vec::new(),
+            io_threads,
+            threads: Vec::new(),
+            fixed_buffers,
         })
     }
 
-    pub(crate) fn process_queue_event(&mut self) {
+    pub(crate) fn process_queue_event(&mut self, queue_index: usize) {
         METRICS.block.queue_event_count.inc();
-        if let Err(e) = self.queue_evts[0].read() {
+        if let Err(e) = self.queue_evts[queue_index].read() {
             error!("Failed to get queue event: {:?}", e);
             METRICS.block.event_fails.inc();
         } else if self.rate_limiter.is_blocked() {
             METRICS.block.rate_limiter_throttled_events.inc();
-        } else if self.is_io_engine_throttled {
+        } else if self.queue_handlers[queue_index].is_io_engine_throttled {
             METRICS.block.io_engine_throttled_events.inc();
         } else {
-            self.process_virtio_queues();
+            self.process_queue(queue_index);
         }
     }
 
     /// Process device virtio queue(s).
     pub fn process_virtio_queues(&mut self) {
-        self.process_queue(0);
+        if !self.threads.is_empty() {
+            // The IO threads are kicked through the queue events.
+            for queue_evt in self.queue_evts.iter() {
+                if let Err(e) = queue_evt.write(1) {
+                    error!("Failed to kick block IO thread: {:?}", e);
+                }
+            }
+        }
+        for queue_index in 0..self.queue_handlers.len() {
+            self.process_queue(queue_index);
+        }
     }
 
     pub(crate) fn process_rate_limiter_event(&mut self) {
         METRICS.block.rate_limiter_event_count.inc();
         // Upon rate limiter event, call the rate limiter handler
-        // and restart processing the queue.
+        // and restart processing the queues.
         if self.rate_limiter.event_handler().is_ok() {
-            self.process_queue(0);
-        }
-    }
-
-    fn add_used_descriptor(
-        queue: &mut Queue,
-        index: u16,
-        len: u32,
-        mem: &GuestMemoryMmap,
-        irq_trigger: &IrqTrigger,
-    ) {
-        queue
-            .add_used(mem, index, len)
-            .unwrap_or_else(|e| error!("Failed to add available descriptor head {}: {}", index, e));
-
-        if queue.prepare_kick(mem) {
-            irq_trigger.trigger_irq(IrqType::Vring).unwrap_or_else(|_| {
-                METRICS.block.event_fails.inc();
-            });
+            for queue_index in 0..self.queue_handlers.len() {
+                self.process_queue(queue_index);
+            }
         }
     }
 
@@ -333,130 +811,62 @@ impl Block {
         // This is safe since we checked in the event handler that the device is activated.
         let mem = self.device_state.mem().unwrap();
 
-        let queue = &mut self.queues[queue_index];
-        let mut used_any = false;
-
-        while let Some(head) = queue.pop_or_enable_notification(mem) {
-            let processing_result = match Request::parse(&head, mem, self.disk.nsectors()) {
-                Ok(request) => {
-                    if request.rate_limit(&mut self.rate_limiter) {
-                        // Stop processing the queue and return this descriptor chain to the
-                        // avail ring, for later processing.
-                        queue.undo_pop();
-                        METRICS.block.rate_limiter_throttled_events.inc();
-                        break;
-                    }
-
-                    used_any = true;
-                    request.process(&mut self.disk, head.index, mem)
-                }
-                Err(e) => {
-                    error!("Failed to parse available descriptor chain: {:?}", e);
-                    METRICS.block.execute_fails.inc();
-                    ProcessingResult::Executed(FinishedRequest {
-                        num_bytes_to_mem: 0,
-                        desc_idx: head.index,
-                    })
-                }
-            };
-
-            match processing_result {
-                ProcessingResult::Submitted => {}
-                ProcessingResult::Throttled => {
-                    queue.undo_pop();
-                    self.is_io_engine_throttled = true;
-                    break;
-                }
-                ProcessingResult::Executed(finished) => {
-                    Self::add_used_descriptor(
-                        queue,
-                        head.index,
-                        finished.num_bytes_to_mem,
-                        mem,
-                        &self.irq_trigger,
-                    );
-                }
-            }
-        }
-
-        if let FileEngine::Async(engine) = self.disk.file_engine_mut() {
-            if let Err(e) = engine.kick_submission_queue() {
-                error!("Error submitting pending block requests: {:?}", e);
-            }
-        }
-
-        if !used_any {
-            METRICS.block.no_avail_buffer.inc();
-        }
+        self.queue_handlers[queue_index].process_queue(
+            &mut self.queues[queue_index],
+            &self.disk,
+            mem,
+            &self.irq_trigger,
+            &mut self.rate_limiter,
+        );
     }
 
-    fn process_async_completion_queue(&mut self) {
-        let engine = unwrap_async_file_engine_or_return!(&mut self.disk.file_engine);
-
+    pub fn process_async_completion_event(&mut self, queue_index: usize) {
         // This is safe since we checked in the event handler that the device is activated.
         let mem = self.device_state.mem().unwrap();
-        let queue = &mut self.queues[0];
-
-        loop {
-            match engine.pop(mem) {
-                Err(error) => {
-                    error!("Failed to read completed io_uring entry: {:?}", error);
-                    break;
-                }
-                Ok(None) => break,
-                Ok(Some(cqe)) => {
-                    let res = cqe.result();
-                    let user_data = cqe.user_data();
-
-                    let (pending, res) = match res {
-                        Ok(count) => (user_data, Ok(count)),
-                        Err(error) => (
-                            user_data,
-                            Err(IoErr::FileEngine(block_io::Error::Async(
-                                async_io::Error::IO(error),
-                            ))),
-                        ),
-                    };
-                    let finished = pending.finish(mem, res);
 
-                    Self::add_used_descriptor(
-                        queue,
-                        finished.desc_idx,
-                        finished.num_bytes_to_mem,
-                        mem,
-                        &self.irq_trigger,
-                    );
-                }
-            }
-        }
-    }
-
-    pub fn process_async_completion_event(&mut self) {
-        let engine = unwrap_async_file_engine_or_return!(&mut self.disk.file_engine);
-
-        if let Err(e) = engine.completion_evt().read() {
-            error!("Failed to get async completion event: {:?}", e);
-            return;
-        }
-
-        self.process_async_completion_queue();
-
-        if self.is_io_engine_throttled {
-            self.is_io_engine_throttled = false;
-            self.process_queue(0);
-        }
+        self.queue_handlers[queue_index].process_async_completion_event(
+            &mut self.queues[queue_index],
+            &self.disk,
+            mem,
+            &self.irq_trigger,
+            &mut self.rate_limiter,
+        );
     }
 
     /// Update the backing file and the config space of the block device.
     pub fn update_disk_image(&mut self, disk_image_path: String) -> result::Result<(), Error> {
-        let disk_properties = DiskProperties::new(
+        let (disk_properties, mut queue_handlers) = DiskProperties::open(
             disk_image_path,
             self.is_read_only(),
             self.cache_type(),
             self.file_engine_type(),
+            self.direct_io(),
+            self.sq_poll(),
+            self.queues.len() as u16,
         )?;
+        if let (true, DeviceState::Activated(mem)) = (self.fixed_buffers, &self.device_state) {
+            for handler in queue_handlers.iter_mut() {
+                handler.register_guest_memory(mem);
+            }
+        }
+
+        if self.threads.is_empty() {
+            let retired_queue_handlers =
+                std::mem::replace(&mut self.queue_handlers, queue_handlers);
+            self.retired_queue_handlers.extend(retired_queue_handlers);
+        } else {
+            let mut queue_handlers: Vec<Option<QueueHandler>> =
+                queue_handlers.into_iter().map(Some).collect();
+            for thread in self.threads.iter() {
+                if let Some(handler) = queue_handlers[thread.index()].take() {
+                    thread.send(Command::UpdateDisk(disk_properties.clone(), handler));
+                }
+            }
+        }
         self.disk = disk_properties;
-        self.config_space = self.disk.virtio_block_config_space();
+        self.config_space = self
+            .disk
+            .virtio_block_config_space(self.queues.len() as u16);
 
         // Kick the driver to pick up the changes.
         self.irq_trigger.trigger_irq(IrqType::Config).unwrap();
@@ -465,11 +875,67 @@ impl Block {
         Ok(())
     }
 
+    // Registers the guest memory with the async IO engines, if the device is activated.
+    pub(crate) fn register_guest_memory(&mut self) {
+        if !self.fixed_buffers {
+            return;
+        }
+        if let DeviceState::Activated(mem) = &self.device_state {
+            for handler in self.queue_handlers.iter_mut() {
+                handler.register_guest_memory(mem);
+            }
+        }
+    }
+
+    // Hands the queue handlers over to dedicated IO threads, if the device is configured so.
+    // This must run on the VMM thread, as the threads inherit its seccomp filter.
+    pub(crate) fn start_io_threads(&mut self) {
+        if !self.io_threads || !self.threads.is_empty() {
+            return;
+        }
+        let mem = match self.device_state.mem() {
+            Some(mem) => mem,
+            None => return,
+        };
+
+        for (index, handler) in self.queue_handlers.drain(..).enumerate() {
+            match IoThread::spawn(
+                index,
+                self.queues[index].clone(),
+                &self.queue_evts[index],
+                handler,
+                self.disk.clone(),
+                mem.clone(),
+                &self.irq_trigger,
+            ) {
+                Ok(thread) => self.threads.push(thread),
+                Err(e) => {
+                    error!(
+                        "Failed to start the IO thread of block queue {}: {}",
+                        index, e
+                    );
+                    METRICS.block.activate_fails.inc();
+                }
+            }
+        }
+    }
+
     /// Updates the parameters for the rate limiter
     pub fn update_rate_limiter(&mut self, bytes: BucketUpdate, ops: BucketUpdate) {
         self.rate_limiter.update_buckets(bytes, ops);
     }
 
+    /// Updates the parameters for the rate limiter buckets dedicated to `direction`.
+    pub fn update_direction_rate_limiter(
+        &mut self,
+        direction: IoDirection,
+        bytes: BucketUpdate,
+        ops: BucketUpdate,
+    ) {
+        self.rate_limiter
+            .update_direction_buckets(direction, bytes, ops);
+    }
+
     /// Provides the ID of this block device.
     pub fn id(&self) -> &String {
         &self.id
@@ -500,22 +966,46 @@ impl Block {
         self.disk.cache_type()
     }
 
+    /// Specifies whether the backing file is opened with `O_DIRECT`.
+    pub fn direct_io(&self) -> bool {
+        self.disk.direct_io()
+    }
+
+    /// Specifies whether the guest memory is registered with the async IO engines.
+    ///
+    /// Registered memory is pinned on the host, so it can't be reclaimed by the balloon.
+    pub fn fixed_buffers(&self) -> bool {
+        self.fixed_buffers
+    }
+
+    /// Specifies whether the submission queues of the async IO engines are polled by kernel
+    /// threads.
+    pub fn sq_poll(&self) -> bool {
+        self.disk.sq_poll()
+    }
+
+    /// Provides the number of virtio queues of this block device.
+    pub fn num_queues(&self) -> u16 {
+        self.queues.len() as u16
+    }
+
+    /// Specifies whether the queues are processed on dedicated IO threads.
+    pub fn io_threads(&self) -> bool {
+        self.io_threads
+    }
+
     /// Provides non-mutable reference to this device's rate limiter.
     pub fn rate_limiter(&self) -> &RateLimiter {
         &self.rate_limiter
     }
 
     pub fn file_engine_type(&self) -> FileEngineType {
-        match self.disk.file_engine() {
-            FileEngine::Sync(_) => FileEngineType::Sync,
-            FileEngine::Async(_) => FileEngineType::Async,
-        }
+        self.disk.file_engine_type()
     }
 
-    fn drain_and_flush(&mut self, discard: bool) {
-        if let Err(e) = self.disk.file_engine_mut().drain_and_flush(discard) {
-            error!("Failed to drain ops and flush block data: {:?}", e);
-        }
+    #[cfg(test)]
+    pub(crate) fn file(&self) -> &File {
+        self.queue_handlers[0].file_engine.file()
     }
 
     pub fn prepare_save(&mut self) {
@@ -523,9 +1013,18 @@ impl Block {
             return;
         }
 
-        self.drain_and_flush(false);
-        if let FileEngine::Async(_engine) = self.disk.file_engine_mut() {
-            self.process_async_completion_queue();
+        // The IO threads hold the live state of their queues.
+        for thread in self.threads.iter() {
+            match thread.save() {
+                Some(queue) => self.queues[thread.index()] = queue,
+                None => error!("Failed to save the state of a block queue from its IO thread"),
+            }
+        }
+
+        // This is safe since we checked that the device is activated.
+        let mem = self.device_state.mem().unwrap();
+        for (handler, queue) in self.queue_handlers.iter_mut().zip(self.queues.iter_mut()) {
+            handler.prepare_save(queue, mem, &self.irq_trigger);
         }
     }
 }
@@ -615,38 +1114,25 @@ impl VirtioDevice for Block {
     }
 }
 
-impl Drop for Block {
-    fn drop(&mut self) {
-        match self.disk.cache_type {
-            CacheType::Unsafe => {
-                if let Err(e) = self.disk.file_engine_mut().drain(true) {
-                    error!("Failed to drain ops on drop: {:?}", e);
-                }
-            }
-            CacheType::Writeback => {
-                self.drain_and_flush(true);
-            }
-        };
-    }
-}
-
 #[cfg(test)]
 pub(crate) mod tests {
     use std::fs::metadata;
     use std::io::Read;
     use std::os::unix::ffi::OsStrExt;
+    use std::os::unix::fs::FileExt;
     use std::thread;
     use std::time::Duration;
     use std::u32;
 
     use super::*;
     use crate::virtio::queue::tests::*;
-    use rate_limiter::TokenType;
+    use rate_limiter::{TokenBucket, TokenType};
     use utils::skip_if_io_uring_unsupported;
     use utils::tempfile::TempFile;
     use vm_memory::{Address, Bytes, GuestAddress};
 
     use crate::check_metric_after_block;
+    use crate::virtio::block::io::nbd::tests::MockNbdServer;
     use crate::virtio::block::test_utils::{
         default_block, default_engine_type_for_kv, set_queue, set_rate_limiter,
         simulate_async_completion_event, simulate_queue_and_async_completion_events,
@@ -667,16 +1153,26 @@ pub(crate) mod tests {
             true,
             CacheType::Unsafe,
             default_engine_type_for_kv(),
+            false,
+            false,
         )
         .unwrap();
 
         assert_eq!(size, SECTOR_SIZE * num_sectors);
         assert_eq!(disk_properties.nsectors, num_sectors);
-        let cfg = disk_properties.virtio_block_config_space();
+        let cfg = disk_properties.virtio_block_config_space(4);
         assert_eq!(cfg.len(), CONFIG_SPACE_SIZE);
-        for (i, byte) in cfg.iter().enumerate() {
-            assert_eq!(*byte, (num_sectors >> (8 * i)) as u8);
-        }
+        assert_eq!(cfg[..8], num_sectors.to_le_bytes());
+        assert_eq!(cfg[8..12], [0u8; 4]);
+        assert_eq!(cfg[12..16], SEG_MAX.to_le_bytes());
+        assert_eq!(cfg[16..NUM_QUEUES_CONFIG_OFFSET], [0u8; 18]);
+        assert_eq!(cfg[34..DISCARD_CONFIG_OFFSET], 4u16.to_le_bytes());
+        assert_eq!(cfg[36..40], u32::MAX.to_le_bytes());
+        assert_eq!(cfg[40..44], 1u32.to_le_bytes());
+        assert_eq!(cfg[44..48], 1u32.to_le_bytes());
+        assert_eq!(cfg[48..52], u32::MAX.to_le_bytes());
+        assert_eq!(cfg[52..56], 1u32.to_le_bytes());
+        assert_eq!(cfg[56..], [1u8, 0, 0, 0]);
         // Testing `backing_file.virtio_block_disk_image_id()` implies
         // duplicating that logic in tests, so skipping it.
 
@@ -685,6 +1181,8 @@ pub(crate) mod tests {
             true,
             CacheType::Unsafe,
             default_engine_type_for_kv(),
+            false,
+            false,
         )
         .is_err());
     }
@@ -695,7 +1193,11 @@ pub(crate) mod tests {
 
         assert_eq!(block.device_type(), TYPE_BLOCK);
 
-        let features: u64 = (1u64 << VIRTIO_F_VERSION_1) | (1u64 << VIRTIO_RING_F_EVENT_IDX);
+        let features: u64 = (1u64 << VIRTIO_F_VERSION_1)
+            | (1u64 << VIRTIO_RING_F_EVENT_IDX)
+            | (1u64 << VIRTIO_BLK_F_SEG_MAX)
+            | (1u64 << VIRTIO_BLK_F_DISCARD)
+            | (1u64 << VIRTIO_BLK_F_WRITE_ZEROES);
 
         assert_eq!(block.avail_features_by_page(0), features as u32);
         assert_eq!(block.avail_features_by_page(1), (features >> 32) as u32);
@@ -710,22 +1212,102 @@ pub(crate) mod tests {
         assert_eq!(block.acked_features, features);
     }
 
+    #[test]
+    fn test_multiple_queues() {
+        let f = TempFile::new().unwrap();
+        f.as_file().set_len(0x1000).unwrap();
+        let new_block = |num_queues| {
+            Block::new(
+                "test".to_string(),
+                None,
+                CacheType::Unsafe,
+                f.as_path().to_str().unwrap().to_string(),
+                false,
+                false,
+                RateLimiter::default(),
+                default_engine_type_for_kv(),
+                false,
+                num_queues,
+                false,
+                false,
+                false,
+            )
+        };
+
+        // The number of queues is bounded.
+        assert!(matches!(new_block(0), Err(Error::InvalidNumQueues(0))));
+        assert!(matches!(
+            new_block(MAX_NUM_QUEUES + 1),
+            Err(Error::InvalidNumQueues(_))
+        ));
+
+        let mut block = new_block(4).unwrap();
+        assert!(block.has_feature(u64::from(VIRTIO_BLK_F_MQ)));
+        assert_eq!(block.num_queues(), 4);
+        assert_eq!(block.queues().len(), 4);
+        assert_eq!(block.queue_events().len(), 4);
+        assert_eq!(block.queue_handlers.len(), 4);
+        let mut num_queues = [0u8; 2];
+        block.read_config(NUM_QUEUES_CONFIG_OFFSET as u64, &mut num_queues);
+        assert_eq!(num_queues, 4u16.to_le_bytes());
+        // A single queue device doesn't advertise the feature.
+        assert!(!new_block(1)
+            .unwrap()
+            .has_feature(u64::from(VIRTIO_BLK_F_MQ)));
+
+        // Each queue is processed on its own.
+        let mem = default_mem();
+        let vq = VirtQueue::new(GuestAddress(0), &mem, 16);
+        set_queue(&mut block, 2, vq.create_queue());
+        block.activate(mem.clone()).unwrap();
+        initialize_virtqueue(&vq);
+
+        let request_type_addr = GuestAddress(vq.dtable[0].addr.get());
+        let status_addr = GuestAddress(vq.dtable[2].addr.get());
+        mem.write_obj::<u32>(VIRTIO_BLK_T_IN, request_type_addr)
+            .unwrap();
+
+        block.queue_evts[2].write(1).unwrap();
+        block.process_queue_event(2);
+        if let FileEngine::Async(engine) = &mut block.queue_handlers[2].file_engine {
+            engine.drain(false).unwrap();
+            thread::sleep(Duration::from_millis(150));
+            block.process_async_completion_event(2);
+        }
+
+        assert_eq!(vq.used.idx.get(), 1);
+        assert_eq!(vq.used.ring[0].get().id, 0);
+        assert_eq!(mem.read_obj::<u32>(status_addr).unwrap(), VIRTIO_BLK_S_OK);
+        assert_eq!(block.queues[0].next_used.0, 0);
+        assert_eq!(block.queues[2].next_used.0, 1);
+    }
+
     #[test]
     fn test_virtio_read_config() {
         let block = default_block(default_engine_type_for_kv());
 
         let mut actual_config_space = [0u8; CONFIG_SPACE_SIZE];
         block.read_config(0, &mut actual_config_space);
-        // This will read the number of sectors.
+        // This will read the number of sectors, the maximum segment size, the maximum
+        // number of segments, the number of queues and the limits of the discard and write
+        // zeroes requests.
         // The block's backing file size is 0x1000, so there are 8 (4096/512) sectors.
         // The config space is little endian.
-        let expected_config_space: [u8; CONFIG_SPACE_SIZE] =
-            [0x08, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00];
+        let mut expected_config_space = [0u8; CONFIG_SPACE_SIZE];
+        expected_config_space[..16].copy_from_slice(&[
+            0x08, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0xfe, 0x00,
+            0x00, 0x00,
+        ]);
+        expected_config_space[NUM_QUEUES_CONFIG_OFFSET..DISCARD_CONFIG_OFFSET]
+            .copy_from_slice(&[0x01, 0x00]);
+        expected_config_space[DISCARD_CONFIG_OFFSET..].copy_from_slice(&[
+            0xff, 0xff, 0xff, 0xff, 0x01, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0xff, 0xff,
+            0xff, 0xff, 0x01, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00,
+        ]);
         assert_eq!(actual_config_space, expected_config_space);
 
         // Invalid read.
-        let expected_config_space: [u8; CONFIG_SPACE_SIZE] =
-            [0xd, 0xe, 0xa, 0xd, 0xb, 0xe, 0xe, 0xf];
+        let expected_config_space = [0xd; CONFIG_SPACE_SIZE];
         actual_config_space = expected_config_space;
         block.read_config(CONFIG_SPACE_SIZE as u64 + 1, &mut actual_config_space);
 
@@ -737,8 +1319,11 @@ pub(crate) mod tests {
     fn test_virtio_write_config() {
         let mut block = default_block(default_engine_type_for_kv());
 
-        let expected_config_space: [u8; CONFIG_SPACE_SIZE] =
-            [0x00, 0x50, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00];
+        let mut expected_config_space = [0u8; CONFIG_SPACE_SIZE];
+        expected_config_space[..16].copy_from_slice(&[
+            0x00, 0x50, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x01,
+            0x00, 0x00,
+        ]);
         block.write_config(0, &expected_config_space);
 
         let mut actual_config_space = [0u8; CONFIG_SPACE_SIZE];
@@ -746,7 +1331,10 @@ pub(crate) mod tests {
         assert_eq!(actual_config_space, expected_config_space);
 
         // If priviledged user writes to `/dev/mem`, in block config space - byte by byte.
-        let expected_config_space = [0x11, 0x22, 0x33, 0x44, 0x55, 0x66, 0x00, 0x11];
+        expected_config_space[..16].copy_from_slice(&[
+            0x11, 0x22, 0x33, 0x44, 0x55, 0x66, 0x00, 0x11, 0x00, 0x00, 0x00, 0x00, 0x80, 0x00,
+            0x00, 0x00,
+        ]);
         for i in 0..expected_config_space.len() {
             block.write_config(i as u64, &expected_config_space[i..=i]);
         }
@@ -754,8 +1342,10 @@ pub(crate) mod tests {
         assert_eq!(actual_config_space, expected_config_space);
 
         // Invalid write.
-        let new_config_space = [0xd, 0xe, 0xa, 0xd, 0xb, 0xe, 0xe, 0xf];
-        block.write_config(5, &new_config_space);
+        let new_config_space = [
+            0xd, 0xe, 0xa, 0xd, 0xb, 0xe, 0xe, 0xf, 0xd, 0xe, 0xa, 0xd, 0xb, 0xe, 0xe, 0xf,
+        ];
+        block.write_config(CONFIG_SPACE_SIZE as u64 - 5, &new_config_space);
         // Make sure nothing got written.
         block.read_config(0, &mut actual_config_space);
         assert_eq!(actual_config_space, expected_config_space);
@@ -993,8 +1583,8 @@ pub(crate) mod tests {
 
             // Check that the data wasn't written to the file
             let mut buf = [0u8; 512];
-            block.disk.file().seek(SeekFrom::Start(0)).unwrap();
-            block.disk.file().read_exact(&mut buf).unwrap();
+            block.file().seek(SeekFrom::Start(0)).unwrap();
+            block.file().read_exact(&mut buf).unwrap();
             assert_eq!(buf, empty_data.as_slice());
         }
 
@@ -1127,8 +1717,8 @@ pub(crate) mod tests {
                 .set(VIRTQ_DESC_F_NEXT | VIRTQ_DESC_F_WRITE);
             mem.write_slice(empty_data.as_slice(), data_addr).unwrap();
 
-            let size = block.disk.file().seek(SeekFrom::End(0)).unwrap();
-            block.disk.file().set_len(size / 2).unwrap();
+            let size = block.file().seek(SeekFrom::End(0)).unwrap();
+            block.file().set_len(size / 2).unwrap();
             mem.write_obj(10, GuestAddress(request_type_addr.0 + 8))
                 .unwrap();
 
@@ -1156,8 +1746,8 @@ pub(crate) mod tests {
                 .flags
                 .set(VIRTQ_DESC_F_NEXT | VIRTQ_DESC_F_WRITE);
 
-            let size = block.disk.file().seek(SeekFrom::End(0)).unwrap();
-            block.disk.file().set_len(size / 2).unwrap();
+            let size = block.file().seek(SeekFrom::End(0)).unwrap();
+            block.file().set_len(size / 2).unwrap();
             // Update sector number: stored at `request_type_addr.0 + 8`
             mem.write_obj(5, GuestAddress(request_type_addr.0 + 8))
                 .unwrap();
@@ -1195,8 +1785,8 @@ pub(crate) mod tests {
             mem.write_obj(1, GuestAddress(request_type_addr.0 + 8))
                 .unwrap();
 
-            block.disk.file().seek(SeekFrom::Start(512)).unwrap();
-            block.disk.file().write_all(&rand_data[512..]).unwrap();
+            block.file().seek(SeekFrom::Start(512)).unwrap();
+            block.file().write_all(&rand_data[512..]).unwrap();
 
             simulate_queue_and_async_completion_events(&mut block, true);
 
@@ -1300,6 +1890,87 @@ pub(crate) mod tests {
         }
     }
 
+    #[test]
+    fn test_discard_and_write_zeroes() {
+        let mut block = default_block(default_engine_type_for_kv());
+        let mem = default_mem();
+        let vq = VirtQueue::new(GuestAddress(0), &mem, 16);
+        set_queue(&mut block, 0, vq.create_queue());
+        block.activate(mem.clone()).unwrap();
+        initialize_virtqueue(&vq);
+
+        let request_type_addr = GuestAddress(vq.dtable[0].addr.get());
+        let data_addr = GuestAddress(vq.dtable[1].addr.get());
+        let status_addr = GuestAddress(vq.dtable[2].addr.get());
+        // The range is described by the data, which the device reads.
+        vq.dtable[1].flags.set(VIRTQ_DESC_F_NEXT);
+        vq.dtable[1].len.set(16);
+
+        // Fill the backing file.
+        let data = vec![0xffu8; 0x1000];
+        block.file().write_all_at(&data, 0).unwrap();
+
+        let requests = [
+            // Discard sectors 0 and 1.
+            (VIRTIO_BLK_T_DISCARD, 0u64, 2u32, 0u32, VIRTIO_BLK_S_OK),
+            // Zero sectors 2 and 3, deallocating them.
+            (
+                VIRTIO_BLK_T_WRITE_ZEROES,
+                2,
+                2,
+                VIRTIO_BLK_WRITE_ZEROES_FLAG_UNMAP,
+                VIRTIO_BLK_S_OK,
+            ),
+            // Zero sector 4.
+            (VIRTIO_BLK_T_WRITE_ZEROES, 4, 1, 0, VIRTIO_BLK_S_OK),
+            // Discard requests don't support the unmap flag.
+            (
+                VIRTIO_BLK_T_DISCARD,
+                5,
+                1,
+                VIRTIO_BLK_WRITE_ZEROES_FLAG_UNMAP,
+                VIRTIO_BLK_S_UNSUPP,
+            ),
+        ];
+        for &(request_type, sector, num_sectors, flags, status) in requests.iter() {
+            vq.used.idx.set(0);
+            set_queue(&mut block, 0, vq.create_queue());
+
+            mem.write_obj::<u32>(request_type, request_type_addr)
+                .unwrap();
+            mem.write_obj::<u64>(sector, data_addr).unwrap();
+            mem.write_obj::<u32>(num_sectors, data_addr.unchecked_add(8))
+                .unwrap();
+            mem.write_obj::<u32>(flags, data_addr.unchecked_add(12))
+                .unwrap();
+
+            simulate_queue_and_async_completion_events(&mut block, true);
+            assert_eq!(vq.used.idx.get(), 1);
+            assert_eq!(vq.used.ring[0].get().id, 0);
+            assert_eq!(vq.used.ring[0].get().len, 1);
+            assert_eq!(mem.read_obj::<u32>(status_addr).unwrap(), status);
+        }
+
+        // Check that the first 5 sectors are zeroed and the size didn't change.
+        let mut buf = vec![0u8; 0x1000];
+        block.file().read_exact_at(&mut buf, 0).unwrap();
+        assert_eq!(buf[..0xa00], [0u8; 0xa00][..]);
+        assert_eq!(buf[0xa00..], data[0xa00..]);
+
+        // The range can't exceed the disk.
+        vq.used.idx.set(0);
+        set_queue(&mut block, 0, vq.create_queue());
+        mem.write_obj::<u32>(VIRTIO_BLK_T_DISCARD, request_type_addr)
+            .unwrap();
+        mem.write_obj::<u64>(7, data_addr).unwrap();
+        mem.write_obj::<u32>(2, data_addr.unchecked_add(8)).unwrap();
+        mem.write_obj::<u32>(0, data_addr.unchecked_add(12))
+            .unwrap();
+        simulate_queue_event(&mut block, Some(true));
+        assert_eq!(vq.used.idx.get(), 1);
+        assert_eq!(vq.used.ring[0].get().len, 0);
+    }
+
     #[test]
     fn test_get_device_id() {
         let mut block = default_block(default_engine_type_for_kv());
@@ -1312,7 +1983,7 @@ pub(crate) mod tests {
         let request_type_addr = GuestAddress(vq.dtable[0].addr.get());
         let data_addr = GuestAddress(vq.dtable[1].addr.get());
         let status_addr = GuestAddress(vq.dtable[2].addr.get());
-        let blk_metadata = block.disk.file().metadata();
+        let blk_metadata = block.file().metadata();
 
         // Test that the driver receives the correct device id.
         {
@@ -1436,25 +2107,25 @@ pub(crate) mod tests {
             // Run scenario that doesn't trigger FullSq Error: Add sq_size flush requests.
             add_flush_requests_batch(&mut block, &mem, &vq, IO_URING_NUM_ENTRIES);
             simulate_queue_event(&mut block, Some(false));
-            assert_eq!(block.is_io_engine_throttled, false);
+            assert_eq!(block.queue_handlers[0].is_io_engine_throttled, false);
             simulate_async_completion_event(&mut block, true);
             check_flush_requests_batch(IO_URING_NUM_ENTRIES, &mem, &vq);
 
             // Run scenario that triggers FullSqError : Add sq_size + 10 flush requests.
             add_flush_requests_batch(&mut block, &mem, &vq, IO_URING_NUM_ENTRIES + 10);
             simulate_queue_event(&mut block, Some(false));
-            assert_eq!(block.is_io_engine_throttled, true);
+            assert_eq!(block.queue_handlers[0].is_io_engine_throttled, true);
             // When the async_completion_event is triggered:
             // 1. sq_size requests should be processed processed.
             // 2. is_io_engine_throttled should be set back to false.
             // 3. process_queue() should be called again.
             simulate_async_completion_event(&mut block, true);
-            assert_eq!(block.is_io_engine_throttled, false);
+            assert_eq!(block.queue_handlers[0].is_io_engine_throttled, false);
             check_flush_requests_batch(IO_URING_NUM_ENTRIES, &mem, &vq);
             // check that process_queue() was called again resulting in the processing of the
             // remaining 10 ops.
             simulate_async_completion_event(&mut block, true);
-            assert_eq!(block.is_io_engine_throttled, false);
+            assert_eq!(block.queue_handlers[0].is_io_engine_throttled, false);
             check_flush_requests_batch(IO_URING_NUM_ENTRIES + 10, &mem, &vq);
         }
 
@@ -1470,20 +2141,20 @@ pub(crate) mod tests {
             // completion. Then try to push another entry.
             add_flush_requests_batch(&mut block, &mem, &vq, IO_URING_NUM_ENTRIES);
             simulate_queue_event(&mut block, Some(false));
-            assert_eq!(block.is_io_engine_throttled, false);
+            assert_eq!(block.queue_handlers[0].is_io_engine_throttled, false);
             thread::sleep(Duration::from_millis(150));
             add_flush_requests_batch(&mut block, &mem, &vq, IO_URING_NUM_ENTRIES);
             simulate_queue_event(&mut block, Some(false));
-            assert_eq!(block.is_io_engine_throttled, false);
+            assert_eq!(block.queue_handlers[0].is_io_engine_throttled, false);
             thread::sleep(Duration::from_millis(150));
 
             add_flush_requests_batch(&mut block, &mem, &vq, 1);
             simulate_queue_event(&mut block, Some(false));
-            assert_eq!(block.is_io_engine_throttled, true);
+            assert_eq!(block.queue_handlers[0].is_io_engine_throttled, true);
             assert_eq!(block.queues[0].len(&mem), 1);
 
             simulate_async_completion_event(&mut block, true);
-            assert_eq!(block.is_io_engine_throttled, false);
+            assert_eq!(block.queue_handlers[0].is_io_engine_throttled, false);
             check_flush_requests_batch(IO_URING_NUM_ENTRIES * 2, &mem, &vq);
         }
     }
@@ -1652,6 +2323,85 @@ pub(crate) mod tests {
         }
     }
 
+    #[test]
+    fn test_direction_rate_limiter() {
+        let mut block = default_block(default_engine_type_for_kv());
+        let mem = default_mem();
+        let vq = VirtQueue::new(GuestAddress(0), &mem, 16);
+        set_queue(&mut block, 0, vq.create_queue());
+        block.activate(mem.clone()).unwrap();
+        initialize_virtqueue(&vq);
+
+        let request_type_addr = GuestAddress(vq.dtable[0].addr.get());
+        let data_addr = GuestAddress(vq.dtable[1].addr.get());
+        let status_addr = GuestAddress(vq.dtable[2].addr.get());
+
+        // Only limit writes, to 10 ops/s with bucket size of 1 ops.
+        let mut rl = RateLimiter::default();
+        rl.update_direction_buckets(
+            IoDirection::Write,
+            BucketUpdate::None,
+            BucketUpdate::Update(TokenBucket::new(1, 0, 100).unwrap()),
+        );
+        // Use up the write budget.
+        assert!(rl.consume_directional(1, TokenType::Ops, IoDirection::Write));
+        // Reads are not affected.
+        assert!(rl.consume_directional(1, TokenType::Ops, IoDirection::Read));
+
+        set_rate_limiter(&mut block, rl);
+
+        mem.write_obj::<u32>(VIRTIO_BLK_T_OUT, request_type_addr)
+            .unwrap();
+        // Make data read only, 512 bytes in len, and set the actual value to be written.
+        vq.dtable[1].flags.set(VIRTQ_DESC_F_NEXT);
+        vq.dtable[1].len.set(512);
+        mem.write_obj::<u64>(123_456_789, data_addr).unwrap();
+
+        // Following write procedure should fail because of the write ops rate limiting.
+        {
+            let read_throttled = METRICS.block.read_rate_limiter_throttled_events.count();
+            // Trigger the attempt to write.
+            check_metric_after_block!(
+                &METRICS.block.write_rate_limiter_throttled_events,
+                1,
+                simulate_queue_event(&mut block, Some(false))
+            );
+            assert_eq!(
+                METRICS.block.read_rate_limiter_throttled_events.count(),
+                read_throttled
+            );
+
+            // Assert that limiter is blocked.
+            assert!(block.rate_limiter.is_blocked());
+            // Make sure the data is still queued for processing.
+            assert_eq!(vq.used.idx.get(), 0);
+        }
+
+        // Wait for 100ms to give the rate-limiter timer a chance to replenish.
+        // Wait for an extra 50ms to make sure the timerfd event makes its way from the kernel.
+        thread::sleep(Duration::from_millis(150));
+
+        // Following write procedure should succeed because write ops budget should now be
+        // available.
+        {
+            check_metric_after_block!(
+                &METRICS.block.write_rate_limiter_throttled_events,
+                0,
+                block.process_rate_limiter_event()
+            );
+            // Validate the rate_limiter is no longer blocked.
+            assert!(!block.rate_limiter.is_blocked());
+            // Complete async IO ops if needed
+            simulate_async_completion_event(&mut block, true);
+
+            // Make sure the data queue advanced.
+            assert_eq!(vq.used.idx.get(), 1);
+            assert_eq!(vq.used.ring[0].get().id, 0);
+            assert_eq!(vq.used.ring[0].get().len, 1);
+            assert_eq!(mem.read_obj::<u32>(status_addr).unwrap(), VIRTIO_BLK_S_OK);
+        }
+    }
+
     #[test]
     fn test_update_disk_image() {
         let mut block = default_block(default_engine_type_for_kv());
@@ -1668,10 +2418,90 @@ pub(crate) mod tests {
             .update_disk_image(String::from(path.to_str().unwrap()))
             .unwrap();
 
+        assert_eq!(block.file().metadata().unwrap().st_ino(), mdata.st_ino());
+        assert_eq!(block.disk.image_id, id.as_slice());
+    }
+
+    #[test]
+    fn test_nbd() {
+        let mut disk = vec![0u8; 0x2000];
+        disk[..0x1000].iter_mut().for_each(|byte| *byte = 0xab);
+        let flags = nbd::NBD_FLAG_HAS_FLAGS | nbd::NBD_FLAG_SEND_FLUSH | nbd::NBD_FLAG_SEND_TRIM;
+        let server = MockNbdServer::new(disk.clone(), flags, true);
+        let new_block = |path: &str, is_disk_read_only, num_queues| {
+            Block::new(
+                "test".to_string(),
+                None,
+                CacheType::Writeback,
+                path.to_string(),
+                is_disk_read_only,
+                false,
+                RateLimiter::default(),
+                FileEngineType::Nbd,
+                false,
+                num_queues,
+                false,
+                false,
+                false,
+            )
+        };
+
+        // The features follow the commands supported by the export.
+        let mut block = new_block(server.path(), false, 1).unwrap();
+        assert_eq!(block.disk.nsectors(), 0x10);
+        assert!(block.has_feature(u64::from(VIRTIO_BLK_F_FLUSH)));
+        assert!(block.has_feature(u64::from(VIRTIO_BLK_F_DISCARD)));
+        assert!(!block.has_feature(u64::from(VIRTIO_BLK_F_WRITE_ZEROES)));
+        let mut max_discard_sectors = [0u8; 4];
+        block.read_config(DISCARD_CONFIG_OFFSET as u64, &mut max_discard_sectors);
         assert_eq!(
-            block.disk.file().metadata().unwrap().st_ino(),
-            mdata.st_ino()
+            max_discard_sectors,
+            (u32::MAX >> SECTOR_SHIFT).to_le_bytes()
         );
-        assert_eq!(block.disk.image_id, id.as_slice());
+        // Each queue has its own connection, which requires the export to allow it.
+        assert!(matches!(
+            new_block(server.path(), false, 2),
+            Err(Error::InvalidNumQueues(2))
+        ));
+
+        // Requests complete upon the replies of the server.
+        let mem = default_mem();
+        let vq = VirtQueue::new(GuestAddress(0), &mem, 16);
+        set_queue(&mut block, 0, vq.create_queue());
+        block.activate(mem.clone()).unwrap();
+        initialize_virtqueue(&vq);
+
+        let request_type_addr = GuestAddress(vq.dtable[0].addr.get());
+        let data_addr = GuestAddress(vq.dtable[1].addr.get());
+        let status_addr = GuestAddress(vq.dtable[2].addr.get());
+        mem.write_obj::<u32>(VIRTIO_BLK_T_IN, request_type_addr)
+            .unwrap();
+
+        simulate_queue_and_async_completion_events(&mut block, true);
+        assert_eq!(vq.used.idx.get(), 1);
+        assert_eq!(vq.used.ring[0].get().id, 0);
+        assert_eq!(mem.read_obj::<u32>(status_addr).unwrap(), VIRTIO_BLK_S_OK);
+        let mut buf = vec![0u8; 0x1000];
+        mem.read_slice(&mut buf, data_addr).unwrap();
+        assert_eq!(buf, disk[..0x1000]);
+
+        // Read-only exports only back read-only drives.
+        let server = MockNbdServer::new(disk, flags | nbd::NBD_FLAG_READ_ONLY, true);
+        assert!(matches!(
+            new_block(server.path(), false, 1),
+            Err(Error::FileEngine(block_io::Error::Nbd(
+                nbd::Error::ReadOnlyExport
+            )))
+        ));
+        let block = new_block(server.path(), true, 1).unwrap();
+        assert!(block.has_feature(u64::from(VIRTIO_BLK_F_RO)));
+        assert!(!block.has_feature(u64::from(VIRTIO_BLK_F_DISCARD)));
+
+        // No server listens on the socket.
+        let f = TempFile::new().unwrap();
+        assert!(matches!(
+            new_block(f.as_path().to_str().unwrap(), false, 1),
+            Err(Error::FileEngine(block_io::Error::Nbd(nbd::Error::IO(_))))
+        ));
     }
 }
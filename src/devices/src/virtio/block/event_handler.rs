@@ -6,22 +6,55 @@ use event_manager::{EventOps, Events, MutEventSubscriber};
 use logger::{debug, error, warn};
 use utils::epoll::EventSet;
 
-use super::io::FileEngine;
-use crate::virtio::block::device::Block;
+use crate::virtio::block::device::{Block, QueueHandler};
 use crate::virtio::VirtioDevice;
 
 impl Block {
-    fn register_runtime_events(&self, ops: &mut EventOps) {
-        if let Err(e) = ops.add(Events::new(&self.queue_evts[0], EventSet::IN)) {
-            error!("Failed to register queue event: {}", e);
+    fn register_runtime_events(&mut self, ops: &mut EventOps) {
+        if self.io_threads {
+            // The queue and completion events are handled by the IO threads.
+            self.start_io_threads();
+        } else {
+            for queue_evt in self.queue_evts.iter() {
+                if let Err(e) = ops.add(Events::new(queue_evt, EventSet::IN)) {
+                    error!("Failed to register queue event: {}", e);
+                }
+            }
+            self.update_completion_events(ops);
         }
         if let Err(e) = ops.add(Events::new(&self.rate_limiter, EventSet::IN)) {
             error!("Failed to register ratelimiter event: {}", e);
         }
-        if let FileEngine::Async(engine) = self.disk.file_engine() {
-            if let Err(e) = ops.add(Events::new(engine.completion_evt(), EventSet::IN)) {
+    }
+
+    // Registers the completion events of the queue handlers, in place of the ones registered
+    // previously. Disk updates replace the handlers, lost NBD connections stop signaling
+    // completions and NBD commands waiting to be sent wait for their socket to be writable, while
+    // the file descriptors must stay open until they're un-registered.
+    fn update_completion_events(&mut self, ops: &mut EventOps) {
+        let completion_events = self
+            .queue_handlers
+            .iter()
+            .filter_map(QueueHandler::completion_event);
+        if self.retired_queue_handlers.is_empty()
+            && completion_events
+                .clone()
+                .eq(self.completion_events.iter().copied())
+        {
+            return;
+        }
+
+        for (fd, event_set) in self.completion_events.drain(..) {
+            if let Err(e) = ops.remove(Events::new_raw(fd, event_set)) {
+                error!("Failed to un-register IO engine completion event: {}", e);
+            }
+        }
+        self.retired_queue_handlers.clear();
+        for (fd, event_set) in completion_events {
+            if let Err(e) = ops.add(Events::new_raw(fd, event_set)) {
                 error!("Failed to register IO engine completion event: {}", e);
             }
+            self.completion_events.push((fd, event_set));
         }
     }
 
@@ -31,11 +64,14 @@ impl Block {
         }
     }
 
-    fn process_activate_event(&self, ops: &mut EventOps) {
+    fn process_activate_event(&mut self, ops: &mut EventOps) {
         debug!("block: activate event");
         if let Err(e) = self.activate_evt.read() {
             error!("Failed to consume block activate event: {:?}", e);
         }
+        // Registering the guest memory and starting the IO threads is done here rather than on
+        // activation, so that it happens on the VMM thread.
+        self.register_guest_memory();
         self.register_runtime_events(ops);
         if let Err(e) = ops.remove(Events::new(&self.activate_evt, EventSet::IN)) {
             error!("Failed to un-register activate event: {}", e);
@@ -50,8 +86,10 @@ impl MutEventSubscriber for Block {
         let event_set = event.event_set();
 
         // TODO: also check for errors. Pending high level discussions on how we want
-        // to handle errors in devices.
-        let supported_events = EventSet::IN;
+        // to handle errors in devices. NBD connections hang up when the server goes away,
+        // which the IO engine handles upon reading its socket, and become writable once the
+        // server makes room for the commands waiting to be sent.
+        let supported_events = EventSet::IN | EventSet::OUT | EventSet::HANG_UP | EventSet::ERROR;
         if !supported_events.contains(event_set) {
             warn!(
                 "Block: Received unknown event: {:?} from source: {:?}",
@@ -61,21 +99,29 @@ impl MutEventSubscriber for Block {
         }
 
         if self.is_activated() {
-            let queue_evt = self.queue_evts[0].as_raw_fd();
+            let maybe_queue_index = self
+                .queue_evts
+                .iter()
+                .position(|queue_evt| queue_evt.as_raw_fd() == source);
             let rate_limiter_evt = self.rate_limiter.as_raw_fd();
             let activate_fd = self.activate_evt.as_raw_fd();
-            let maybe_completion_fd = match self.disk.file_engine() {
-                FileEngine::Async(engine) => Some(engine.completion_evt().as_raw_fd()),
-                FileEngine::Sync(_) => None,
-            };
+            let maybe_completion_index = self
+                .queue_handlers
+                .iter()
+                .position(|handler| handler.completion_fd() == Some(source));
 
             // Looks better than C style if/else if/else.
             match source {
-                _ if queue_evt == source => self.process_queue_event(),
                 _ if rate_limiter_evt == source => self.process_rate_limiter_event(),
                 _ if activate_fd == source => self.process_activate_event(ops),
-                _ if maybe_completion_fd == Some(source) => self.process_async_completion_event(),
-                _ => warn!("Block: Spurious event received: {:?}", source),
+                _ => match (maybe_queue_index, maybe_completion_index) {
+                    (Some(queue_index), _) => self.process_queue_event(queue_index),
+                    (None, Some(queue_index)) => self.process_async_completion_event(queue_index),
+                    (None, None) => warn!("Block: Spurious event received: {:?}", source),
+                },
+            }
+            if !self.io_threads {
+                self.update_completion_events(ops);
             }
         } else {
             warn!(
@@ -101,11 +147,15 @@ impl MutEventSubscriber for Block {
 #[cfg(test)]
 pub mod tests {
     use std::sync::{Arc, Mutex};
+    use std::thread;
+    use std::time::Duration;
 
     use super::*;
     use crate::virtio::block::device::FileEngineType;
+    use crate::virtio::block::io::nbd::tests::MockNbdServer;
+    use crate::virtio::block::io::nbd::NBD_FLAG_HAS_FLAGS;
     use crate::virtio::block::test_utils::{
-        default_block, set_queue, simulate_async_completion_event,
+        default_block, default_block_with_path, set_queue, simulate_async_completion_event,
     };
     use crate::virtio::queue::tests::*;
     use crate::virtio::test_utils::{default_mem, initialize_virtqueue, VirtQueue};
@@ -165,4 +215,103 @@ pub mod tests {
         assert_eq!(vq.used.ring[0].get().len, 1);
         assert_eq!(mem.read_obj::<u32>(status_addr).unwrap(), VIRTIO_BLK_S_OK);
     }
+
+    #[test]
+    fn test_io_threads() {
+        let mut event_manager = EventManager::new().unwrap();
+        let mut block = default_block(FileEngineType::default());
+        block.io_threads = true;
+        let mem = default_mem();
+        let vq = VirtQueue::new(GuestAddress(0), &mem, 16);
+        set_queue(&mut block, 0, vq.create_queue());
+        initialize_virtqueue(&vq);
+
+        let block = Arc::new(Mutex::new(block));
+        let _id = event_manager.add_subscriber(block.clone());
+
+        let request_type_addr = GuestAddress(vq.dtable[0].addr.get());
+        let status_addr = GuestAddress(vq.dtable[2].addr.get());
+
+        // Push a 'Read' operation and trigger the queue event.
+        mem.write_obj::<u32>(VIRTIO_BLK_T_IN, request_type_addr)
+            .unwrap();
+        block.lock().unwrap().queue_evts[0].write(1).unwrap();
+
+        // Activating the device moves the processing of the queue to an IO thread.
+        block.lock().unwrap().activate(mem.clone()).unwrap();
+        let ev_count = event_manager.run_with_timeout(50).unwrap();
+        assert_eq!(ev_count, 1);
+        assert!(block.lock().unwrap().queue_handlers.is_empty());
+
+        // The pending queue event is handled by the IO thread, without going through the
+        // event manager of the device.
+        for _ in 0..100 {
+            if vq.used.idx.get() == 1 {
+                break;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(vq.used.idx.get(), 1);
+        assert_eq!(vq.used.ring[0].get().id, 0);
+        assert_eq!(mem.read_obj::<u32>(status_addr).unwrap(), VIRTIO_BLK_S_OK);
+
+        // Saving the device gets the state of the queue back from the IO thread.
+        block.lock().unwrap().prepare_save();
+        assert_eq!(block.lock().unwrap().queues[0].next_used.0, 1);
+    }
+
+    #[test]
+    fn test_nbd_completion_events() {
+        let disk = vec![0xab; 0x2000];
+        let server = MockNbdServer::new(disk.clone(), NBD_FLAG_HAS_FLAGS, true);
+        let mut event_manager = EventManager::new().unwrap();
+        let mut block = default_block_with_path(server.path().to_string(), FileEngineType::Nbd);
+        let mem = default_mem();
+        let vq = VirtQueue::new(GuestAddress(0), &mem, 16);
+        set_queue(&mut block, 0, vq.create_queue());
+        initialize_virtqueue(&vq);
+
+        let block = Arc::new(Mutex::new(block));
+        let _id = event_manager.add_subscriber(block.clone());
+
+        let request_type_addr = GuestAddress(vq.dtable[0].addr.get());
+        let status_addr = GuestAddress(vq.dtable[2].addr.get());
+        mem.write_obj::<u32>(VIRTIO_BLK_T_IN, request_type_addr)
+            .unwrap();
+
+        // Activating the device registers the socket of its NBD connection.
+        block.lock().unwrap().activate(mem.clone()).unwrap();
+        let ev_count = event_manager.run_with_timeout(50).unwrap();
+        assert_eq!(ev_count, 1);
+        let completion_events = block.lock().unwrap().completion_events.clone();
+        assert_eq!(completion_events.len(), 1);
+        assert_eq!(completion_events[0].1, EventSet::IN);
+
+        // The request completes once the reply of the server comes in.
+        block.lock().unwrap().queue_evts[0].write(1).unwrap();
+        for _ in 0..100 {
+            event_manager.run_with_timeout(10).unwrap();
+            if vq.used.idx.get() == 1 {
+                break;
+            }
+        }
+        assert_eq!(vq.used.idx.get(), 1);
+        assert_eq!(vq.used.ring[0].get().id, 0);
+        assert_eq!(mem.read_obj::<u32>(status_addr).unwrap(), VIRTIO_BLK_S_OK);
+
+        // Updating the disk switches over to a new connection upon the next event.
+        let new_server = MockNbdServer::new(disk, NBD_FLAG_HAS_FLAGS, true);
+        block
+            .lock()
+            .unwrap()
+            .update_disk_image(new_server.path().to_string())
+            .unwrap();
+        assert_eq!(block.lock().unwrap().retired_queue_handlers.len(), 1);
+        block.lock().unwrap().queue_evts[0].write(1).unwrap();
+        event_manager.run_with_timeout(50).unwrap();
+        let block = block.lock().unwrap();
+        assert!(block.retired_queue_handlers.is_empty());
+        assert_eq!(block.completion_events.len(), 1);
+        assert_ne!(block.completion_events, completion_events);
+    }
 }
@@ -1,6 +1,7 @@
 // Copyright 2021 Amazon.com, Inc. or its affiliates. All Rights Reserved.
 // SPDX-License-Identifier: Apache-2.0
 
+use std::cmp;
 use std::fs::File;
 use std::marker::PhantomData;
 use std::os::unix::io::AsRawFd;
@@ -8,14 +9,16 @@ use std::os::unix::io::AsRawFd;
 use io_uring::{
     operation::{Cqe, OpCode, Operation},
     restriction::Restriction,
-    Error as IoUringError, IoUring,
+    Error as IoUringError, IoUring, MAX_FIXED_BUFFER_LEN,
 };
 
+use logger::error;
 use utils::eventfd::EventFd;
-use vm_memory::{mark_dirty_mem, GuestAddress, GuestMemory, GuestMemoryMmap};
+use vm_memory::{mark_dirty_mem, GuestMemory, GuestMemoryMmap, GuestMemoryRegion};
 
-use crate::virtio::block::io::UserDataError;
-use crate::virtio::block::IO_URING_NUM_ENTRIES;
+use crate::virtio::block::io::bounce_buffer::{self, BounceBuffer};
+use crate::virtio::block::io::{IoSegment, UserDataError};
+use crate::virtio::block::{IO_URING_NUM_ENTRIES, IO_URING_SQ_THREAD_IDLE_MS};
 
 #[derive(Debug)]
 pub enum Error {
@@ -31,32 +34,59 @@ pub struct AsyncFileEngine<T> {
     file: File,
     ring: IoUring,
     completion_evt: EventFd,
+    // Whether the file was opened with `O_DIRECT`.
+    direct_io: bool,
+    // Host address ranges of the registered buffers, in the order of their buffer index.
+    fixed_buffers: Vec<(usize, usize)>,
     phantom: PhantomData<T>,
 }
 
 pub struct WrappedUserData<T> {
-    addr: Option<GuestAddress>,
+    // Guest memory written by the operation, which is marked dirty upon completion.
+    dirty_segments: Vec<IoSegment>,
+    // The io vectors of a vectored operation, which need to outlive it.
+    _iovecs: Vec<libc::iovec>,
+    // The aligned buffer standing in for unaligned guest memory in a direct IO operation.
+    // The data read into it is copied to the dirty segments upon completion.
+    bounce_buffer: Option<BounceBuffer>,
     user_data: T,
 }
 
 impl<T> WrappedUserData<T> {
     fn new(user_data: T) -> Self {
         WrappedUserData {
-            addr: None,
+            dirty_segments: Vec::new(),
+            _iovecs: Vec::new(),
+            bounce_buffer: None,
             user_data,
         }
     }
 
-    fn new_with_dirty_tracking(addr: GuestAddress, user_data: T) -> Self {
+    fn new_with_dirty_tracking(segments: &[IoSegment], user_data: T) -> Self {
         WrappedUserData {
-            addr: Some(addr),
+            dirty_segments: segments.to_vec(),
+            _iovecs: Vec::new(),
+            bounce_buffer: None,
             user_data,
         }
     }
 
     fn mark_dirty_mem_and_unwrap(self, mem: &GuestMemoryMmap, count: u32) -> T {
-        if let Some(addr) = self.addr {
-            mark_dirty_mem(mem, addr, count as usize)
+        if let Some(buffer) = self.bounce_buffer.as_ref() {
+            // The segments were validated when the operation was pushed.
+            if let Err(e) = buffer.copy_to_segments(mem, &self.dirty_segments, count as usize) {
+                error!("Failed to copy the bounced data to guest memory: {:?}", e);
+            }
+        }
+
+        let mut remaining = count as usize;
+        for segment in self.dirty_segments.iter() {
+            if remaining == 0 {
+                break;
+            }
+            let len = cmp::min(segment.len as usize, remaining);
+            mark_dirty_mem(mem, segment.addr, len);
+            remaining -= len;
         }
 
         self.user_data
@@ -64,27 +94,54 @@ impl<T> WrappedUserData<T> {
 }
 
 impl<T> AsyncFileEngine<T> {
-    pub fn from_file(file: File) -> Result<AsyncFileEngine<T>, Error> {
+    /// Sets up an io_uring on `file`. With `sq_poll`, the submission queue is polled by a
+    /// kernel thread, which saves the system calls submitting the requests at the cost of
+    /// host CPU time.
+    pub fn from_file(
+        file: File,
+        direct_io: bool,
+        sq_poll: bool,
+    ) -> Result<AsyncFileEngine<T>, Error> {
         let completion_evt = EventFd::new(libc::EFD_NONBLOCK).map_err(Error::EventFd)?;
-        let ring = IoUring::new(
-            IO_URING_NUM_ENTRIES as u32,
-            vec![&file],
-            vec![
-                // Make sure we only allow operations on pre-registered fds.
-                Restriction::RequireFixedFds,
-                // Allowlist of opcodes.
-                Restriction::AllowOpCode(OpCode::Read),
-                Restriction::AllowOpCode(OpCode::Write),
-                Restriction::AllowOpCode(OpCode::Fsync),
-            ],
-            Some(completion_evt.as_raw_fd()),
-        )
+        let restrictions = vec![
+            // Make sure we only allow operations on pre-registered fds.
+            Restriction::RequireFixedFds,
+            // Allowlist of opcodes.
+            Restriction::AllowOpCode(OpCode::Read),
+            Restriction::AllowOpCode(OpCode::Write),
+            Restriction::AllowOpCode(OpCode::Readv),
+            Restriction::AllowOpCode(OpCode::Writev),
+            Restriction::AllowOpCode(OpCode::ReadFixed),
+            Restriction::AllowOpCode(OpCode::WriteFixed),
+            Restriction::AllowOpCode(OpCode::Fsync),
+            Restriction::AllowOpCode(OpCode::Fallocate),
+            // Allow registering the guest memory once the device is activated.
+            Restriction::AllowBufferRegistration,
+        ];
+        let ring = if sq_poll {
+            IoUring::with_sq_poll(
+                IO_URING_NUM_ENTRIES as u32,
+                vec![&file],
+                restrictions,
+                Some(completion_evt.as_raw_fd()),
+                IO_URING_SQ_THREAD_IDLE_MS,
+            )
+        } else {
+            IoUring::new(
+                IO_URING_NUM_ENTRIES as u32,
+                vec![&file],
+                restrictions,
+                Some(completion_evt.as_raw_fd()),
+            )
+        }
         .map_err(Error::IoUring)?;
 
         Ok(AsyncFileEngine {
             file,
             ring,
             completion_evt,
+            direct_io,
+            fixed_buffers: Vec::new(),
             phantom: PhantomData,
         })
     }
@@ -98,71 +155,193 @@ impl<T> AsyncFileEngine<T> {
         &self.completion_evt
     }
 
-    pub fn push_read(
+    /// Registers the guest memory as fixed buffers, so that the host kernel doesn't have to map
+    /// the data of each request.
+    ///
+    /// The memory stays pinned for as long as the engine exists. Registration fails for
+    /// file-backed memory, or when exceeding `RLIMIT_MEMLOCK`.
+    pub fn register_memory(&mut self, mem: &GuestMemoryMmap) -> Result<(), Error> {
+        let mut buffers = Vec::new();
+        for region in mem.iter() {
+            let host_addr = mem
+                .get_host_address(region.start_addr())
+                .map_err(Error::GuestMemory)? as usize;
+            let region_len = region.len() as usize;
+            // Regions larger than the maximum buffer length are split across several buffers.
+            for offset in (0..region_len).step_by(MAX_FIXED_BUFFER_LEN) {
+                buffers.push((
+                    host_addr + offset,
+                    cmp::min(MAX_FIXED_BUFFER_LEN, region_len - offset),
+                ));
+            }
+        }
+
+        let iovecs: Vec<libc::iovec> = buffers
+            .iter()
+            .map(|&(addr, len)| libc::iovec {
+                iov_base: addr as *mut libc::c_void,
+                iov_len: len,
+            })
+            .collect();
+        self.ring
+            .register_buffers(&iovecs)
+            .map_err(Error::IoUring)?;
+        self.fixed_buffers = buffers;
+
+        Ok(())
+    }
+
+    // Returns the index of the registered buffer containing the host memory range, if any.
+    fn fixed_buffer_index(&self, addr: usize, len: u32) -> Option<u16> {
+        self.fixed_buffers
+            .iter()
+            .position(|&(buf_addr, buf_len)| {
+                addr >= buf_addr && addr + len as usize <= buf_addr + buf_len
+            })
+            // Safe to truncate since a ring accepts at most 1024 buffers.
+            .map(|index| index as u16)
+    }
+
+    fn iovecs(
+        mem: &GuestMemoryMmap,
+        segments: &[IoSegment],
+    ) -> Result<Vec<libc::iovec>, vm_memory::GuestMemoryError> {
+        segments
+            .iter()
+            .map(|segment| {
+                mem.get_slice(segment.addr, segment.len as usize)
+                    .map(|slice| libc::iovec {
+                        iov_base: slice.as_ptr() as *mut libc::c_void,
+                        iov_len: segment.len as usize,
+                    })
+            })
+            .collect()
+    }
+
+    // Pushes a single operation transferring the data of all the segments.
+    fn push_transfer(
         &mut self,
+        is_write: bool,
         offset: u64,
         mem: &GuestMemoryMmap,
-        addr: GuestAddress,
-        count: u32,
-        user_data: T,
+        segments: &[IoSegment],
+        mut wrapped_user_data: WrappedUserData<T>,
     ) -> Result<(), UserDataError<T, Error>> {
-        let buf = match mem.get_slice(addr, count as usize) {
-            Ok(slice) => slice.as_ptr(),
+        let iovecs = match Self::iovecs(mem, segments) {
+            Ok(iovecs) => iovecs,
             Err(e) => {
                 return Err(UserDataError {
-                    user_data,
+                    user_data: wrapped_user_data.user_data,
                     error: Error::GuestMemory(e),
                 });
             }
         };
 
-        let wrapped_user_data = WrappedUserData::new_with_dirty_tracking(addr, user_data);
+        let operation = if self.direct_io && !bounce_buffer::is_aligned(segments) {
+            let mut buffer = BounceBuffer::new(bounce_buffer::segments_len(segments));
+            if is_write {
+                if let Err(e) = buffer.copy_from_segments(mem, segments) {
+                    return Err(UserDataError {
+                        user_data: wrapped_user_data.user_data,
+                        error: Error::GuestMemory(e),
+                    });
+                }
+            }
+            let buf = buffer.as_slice().as_ptr() as usize;
+            let count = buffer.as_slice().len() as u32;
+            // Moving the buffer doesn't move its heap allocation, which the kernel accesses.
+            wrapped_user_data.bounce_buffer = Some(buffer);
+            if is_write {
+                Operation::write(0, buf, count, offset, wrapped_user_data)
+            } else {
+                Operation::read(0, buf, count, offset, wrapped_user_data)
+            }
+        } else if iovecs.len() == 1 {
+            let buf = iovecs[0].iov_base as usize;
+            let count = iovecs[0].iov_len as u32;
+            match (self.fixed_buffer_index(buf, count), is_write) {
+                (Some(index), false) => {
+                    Operation::read_fixed(0, buf, count, offset, index, wrapped_user_data)
+                }
+                (Some(index), true) => {
+                    Operation::write_fixed(0, buf, count, offset, index, wrapped_user_data)
+                }
+                (None, false) => Operation::read(0, buf, count, offset, wrapped_user_data),
+                (None, true) => Operation::write(0, buf, count, offset, wrapped_user_data),
+            }
+        } else {
+            let iovecs_addr = iovecs.as_ptr() as usize;
+            // Safe to truncate since a descriptor chain can't be longer than the queue.
+            let iovecs_len = iovecs.len() as u32;
+            // Moving the vector doesn't move its heap allocation, which the kernel reads.
+            wrapped_user_data._iovecs = iovecs;
+            if is_write {
+                Operation::writev(0, iovecs_addr, iovecs_len, offset, wrapped_user_data)
+            } else {
+                Operation::readv(0, iovecs_addr, iovecs_len, offset, wrapped_user_data)
+            }
+        };
 
         // Safe because we trust that the host kernel will pass us back a completed entry with this
         // same `user_data`, so that the value will not be leaked.
-        unsafe {
-            self.ring.push(Operation::read(
-                0,
-                buf as usize,
-                count,
-                offset,
-                wrapped_user_data,
-            ))
-        }
-        .map_err(|err_tuple| UserDataError {
+        unsafe { self.ring.push(operation) }.map_err(|err_tuple| UserDataError {
             user_data: err_tuple.1.user_data,
             error: Error::IoUring(err_tuple.0),
         })
     }
 
+    pub fn push_read(
+        &mut self,
+        offset: u64,
+        mem: &GuestMemoryMmap,
+        segments: &[IoSegment],
+        user_data: T,
+    ) -> Result<(), UserDataError<T, Error>> {
+        let wrapped_user_data = WrappedUserData::new_with_dirty_tracking(segments, user_data);
+        self.push_transfer(false, offset, mem, segments, wrapped_user_data)
+    }
+
     pub fn push_write(
         &mut self,
         offset: u64,
         mem: &GuestMemoryMmap,
-        addr: GuestAddress,
-        count: u32,
+        segments: &[IoSegment],
         user_data: T,
     ) -> Result<(), UserDataError<T, Error>> {
-        let buf = match mem.get_slice(addr, count as usize) {
-            Ok(slice) => slice.as_ptr(),
-            Err(e) => {
-                return Err(UserDataError {
-                    user_data,
-                    error: Error::GuestMemory(e),
-                });
+        let wrapped_user_data = WrappedUserData::new(user_data);
+        self.push_transfer(true, offset, mem, segments, wrapped_user_data)
+    }
+
+    pub fn push_flush(&mut self, user_data: T) -> Result<(), UserDataError<T, Error>> {
+        let wrapped_user_data = WrappedUserData::new(user_data);
+
+        // Safe because we trust that the host kernel will pass us back a completed entry with this
+        // same `user_data`, so that the value will not be leaked.
+        unsafe { self.ring.push(Operation::fsync(0, wrapped_user_data)) }.map_err(|err_tuple| {
+            UserDataError {
+                user_data: err_tuple.1.user_data,
+                error: Error::IoUring(err_tuple.0),
             }
-        };
+        })
+    }
 
+    pub fn push_fallocate(
+        &mut self,
+        mode: u32,
+        offset: u64,
+        len: u64,
+        user_data: T,
+    ) -> Result<(), UserDataError<T, Error>> {
         let wrapped_user_data = WrappedUserData::new(user_data);
 
         // Safe because we trust that the host kernel will pass us back a completed entry with this
         // same `user_data`, so that the value will not be leaked.
         unsafe {
-            self.ring.push(Operation::write(
+            self.ring.push(Operation::fallocate(
                 0,
-                buf as usize,
-                count,
+                mode,
                 offset,
+                len,
                 wrapped_user_data,
             ))
         }
@@ -172,19 +351,6 @@ impl<T> AsyncFileEngine<T> {
         })
     }
 
-    pub fn push_flush(&mut self, user_data: T) -> Result<(), UserDataError<T, Error>> {
-        let wrapped_user_data = WrappedUserData::new(user_data);
-
-        // Safe because we trust that the host kernel will pass us back a completed entry with this
-        // same `user_data`, so that the value will not be leaked.
-        unsafe { self.ring.push(Operation::fsync(0, wrapped_user_data)) }.map_err(|err_tuple| {
-            UserDataError {
-                user_data: err_tuple.1.user_data,
-                error: Error::IoUring(err_tuple.0),
-            }
-        })
-    }
-
     pub fn kick_submission_queue(&mut self) -> Result<(), Error> {
         self.ring.submit().map(|_| ()).map_err(Error::IoUring)
     }
@@ -0,0 +1,152 @@
+// Copyright 2022 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+use std::cmp;
+
+use vm_memory::{Address, Bytes, GuestMemoryError, GuestMemoryMmap};
+
+use crate::virtio::block::io::IoSegment;
+use crate::virtio::block::SECTOR_SIZE;
+
+/// The alignment of the buffer addresses and lengths of direct IO transfers.
+pub const DIRECT_IO_ALIGNMENT: usize = SECTOR_SIZE as usize;
+
+/// Returns whether the segments can be transferred to or from a file opened with `O_DIRECT`.
+///
+/// Guest memory regions are page aligned both in the guest and on the host, so checking the
+/// guest addresses is enough.
+pub fn is_aligned(segments: &[IoSegment]) -> bool {
+    segments.iter().all(|segment| {
+        segment.addr.raw_value() % DIRECT_IO_ALIGNMENT as u64 == 0
+            && segment.len as usize % DIRECT_IO_ALIGNMENT == 0
+    })
+}
+
+/// Returns the total length of the segments.
+pub fn segments_len(segments: &[IoSegment]) -> usize {
+    segments.iter().map(|segment| segment.len as usize).sum()
+}
+
+/// A host buffer suitably aligned for direct IO, through which the data of unaligned guest
+/// segments is transferred.
+pub struct BounceBuffer {
+    buf: Vec<u8>,
+    offset: usize,
+    len: usize,
+}
+
+impl BounceBuffer {
+    pub fn new(len: usize) -> Self {
+        // Over-allocate so that an aligned range of `len` bytes always fits.
+        let buf = vec![0u8; len + DIRECT_IO_ALIGNMENT];
+        let misalignment = buf.as_ptr() as usize % DIRECT_IO_ALIGNMENT;
+        let offset = (DIRECT_IO_ALIGNMENT - misalignment) % DIRECT_IO_ALIGNMENT;
+        BounceBuffer { buf, offset, len }
+    }
+
+    pub fn as_slice(&self) -> &[u8] {
+        &self.buf[self.offset..self.offset + self.len]
+    }
+
+    pub fn as_mut_slice(&mut self) -> &mut [u8] {
+        &mut self.buf[self.offset..self.offset + self.len]
+    }
+
+    /// Gathers the data of the segments into the buffer.
+    pub fn copy_from_segments(
+        &mut self,
+        mem: &GuestMemoryMmap,
+        segments: &[IoSegment],
+    ) -> Result<(), GuestMemoryError> {
+        let mut buf = self.as_mut_slice();
+        for segment in segments {
+            let len = cmp::min(segment.len as usize, buf.len());
+            let (head, tail) = buf.split_at_mut(len);
+            mem.read_slice(head, segment.addr)?;
+            buf = tail;
+        }
+        Ok(())
+    }
+
+    /// Scatters the first `count` bytes of the buffer across the segments.
+    pub fn copy_to_segments(
+        &self,
+        mem: &GuestMemoryMmap,
+        segments: &[IoSegment],
+        count: usize,
+    ) -> Result<(), GuestMemoryError> {
+        let mut data = &self.as_slice()[..cmp::min(count, self.len)];
+        for segment in segments {
+            if data.is_empty() {
+                break;
+            }
+            let len = cmp::min(segment.len as usize, data.len());
+            mem.write_slice(&data[..len], segment.addr)?;
+            data = &data[len..];
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use vm_memory::GuestAddress;
+
+    #[test]
+    fn test_is_aligned() {
+        assert!(is_aligned(&[]));
+        assert!(is_aligned(&[
+            IoSegment::new(GuestAddress(0x1000), 0x200),
+            IoSegment::new(GuestAddress(0x2200), 0x1000),
+        ]));
+        assert!(!is_aligned(&[IoSegment::new(GuestAddress(0x1001), 0x200)]));
+        assert!(!is_aligned(&[
+            IoSegment::new(GuestAddress(0x1000), 0x200),
+            IoSegment::new(GuestAddress(0x2000), 0x100),
+        ]));
+    }
+
+    #[test]
+    fn test_bounce_buffer() {
+        let mem =
+            vm_memory::test_utils::create_anon_guest_memory(&[(GuestAddress(0), 0x4000)], false)
+                .unwrap();
+        let segments = [
+            IoSegment::new(GuestAddress(0x1003), 0x100),
+            IoSegment::new(GuestAddress(0x3001), 0x300),
+        ];
+        let len = segments_len(&segments);
+        assert_eq!(len, 0x400);
+
+        let mut buffer = BounceBuffer::new(len);
+        assert_eq!(buffer.as_slice().as_ptr() as usize % DIRECT_IO_ALIGNMENT, 0);
+        assert_eq!(buffer.as_slice().len(), len);
+
+        // Gather the data of the segments.
+        mem.write_slice(&[1u8; 0x100], GuestAddress(0x1003))
+            .unwrap();
+        mem.write_slice(&[2u8; 0x300], GuestAddress(0x3001))
+            .unwrap();
+        buffer.copy_from_segments(&mem, &segments).unwrap();
+        assert_eq!(&buffer.as_slice()[..0x100], &[1u8; 0x100][..]);
+        assert_eq!(&buffer.as_slice()[0x100..], &[2u8; 0x300][..]);
+
+        // Scatter only part of the data.
+        buffer.as_mut_slice().copy_from_slice(&[3u8; 0x400]);
+        buffer.copy_to_segments(&mem, &segments, 0x180).unwrap();
+        let mut data = [0u8; 0x300];
+        mem.read_slice(&mut data[..0x100], GuestAddress(0x1003))
+            .unwrap();
+        assert_eq!(&data[..0x100], &[3u8; 0x100][..]);
+        mem.read_slice(&mut data, GuestAddress(0x3001)).unwrap();
+        assert_eq!(&data[..0x80], &[3u8; 0x80][..]);
+        assert_eq!(&data[0x80..], &[2u8; 0x280][..]);
+
+        // Invalid segments.
+        let segments = [IoSegment::new(GuestAddress(0x3f00), 0x400)];
+        assert!(buffer.copy_from_segments(&mem, &segments).is_err());
+        assert!(buffer.copy_to_segments(&mem, &segments, 0x400).is_err());
+    }
+}
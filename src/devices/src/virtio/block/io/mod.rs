@@ -2,16 +2,33 @@
 // SPDX-License-Identifier: Apache-2.0
 
 pub mod async_io;
+pub mod bounce_buffer;
+pub mod nbd;
 pub mod sync_io;
 
 use std::fs::File;
+use std::time::Instant;
 
 pub use self::async_io::AsyncFileEngine;
+pub use self::nbd::NbdEngine;
 pub use self::sync_io::SyncFileEngine;
 use crate::virtio::block::device::FileEngineType;
 
 use vm_memory::{GuestAddress, GuestMemoryMmap};
 
+/// A contiguous chunk of guest memory holding part of the data of a request.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct IoSegment {
+    pub addr: GuestAddress,
+    pub len: u32,
+}
+
+impl IoSegment {
+    pub fn new(addr: GuestAddress, len: u32) -> Self {
+        IoSegment { addr, len }
+    }
+}
+
 #[cfg_attr(test, derive(Debug, PartialEq))]
 pub struct UserDataOk<T> {
     pub user_data: T,
@@ -28,16 +45,25 @@ pub enum FileEngineOk<T> {
 pub enum Error {
     Sync(sync_io::Error),
     Async(async_io::Error),
+    Nbd(nbd::Error),
     UnsupportedEngine(FileEngineType),
     GetKernelVersion(utils::kernel_version::Error),
 }
 
 impl Error {
     pub fn is_throttling_err(&self) -> bool {
-        if let Error::Async(async_io::Error::IoUring(e)) = self {
-            return e.is_throttling_err();
+        match self {
+            Error::Async(async_io::Error::IoUring(e)) => e.is_throttling_err(),
+            Error::Nbd(nbd::Error::FullQueue) => true,
+            _ => false,
+        }
+    }
+
+    fn from_nbd<T>(e: UserDataError<T, nbd::Error>) -> UserDataError<T, Error> {
+        UserDataError {
+            user_data: e.user_data,
+            error: Error::Nbd(e.error),
         }
-        false
     }
 }
 
@@ -51,10 +77,18 @@ pub enum FileEngine<T> {
     #[allow(unused)]
     Async(AsyncFileEngine<T>),
     Sync(SyncFileEngine),
+    Nbd(NbdEngine<T>),
 }
 
 impl<T> FileEngine<T> {
-    pub fn from_file(file: File, engine_type: FileEngineType) -> Result<FileEngine<T>, Error> {
+    /// Opens an IO engine of type `engine_type` on `file`. Polling the submission queue is only
+    /// done by the `Async` engine.
+    pub fn from_file(
+        file: File,
+        engine_type: FileEngineType,
+        direct_io: bool,
+        sq_poll: bool,
+    ) -> Result<FileEngine<T>, Error> {
         if !engine_type
             .is_supported()
             .map_err(Error::GetKernelVersion)?
@@ -63,17 +97,29 @@ impl<T> FileEngine<T> {
         }
         match engine_type {
             FileEngineType::Async => Ok(FileEngine::Async(
-                AsyncFileEngine::from_file(file).map_err(Error::Async)?,
+                AsyncFileEngine::from_file(file, direct_io, sq_poll).map_err(Error::Async)?,
             )),
-            FileEngineType::Sync => Ok(FileEngine::Sync(SyncFileEngine::from_file(file))),
+            FileEngineType::Sync => {
+                Ok(FileEngine::Sync(SyncFileEngine::from_file(file, direct_io)))
+            }
+            // NBD exports aren't backed by a local file.
+            FileEngineType::Nbd => Err(Error::UnsupportedEngine(engine_type)),
         }
     }
 
+    /// Connects to the NBD server listening on `socket_path`, failing past `deadline`.
+    pub fn connect_nbd(socket_path: &str, deadline: Instant) -> Result<FileEngine<T>, Error> {
+        NbdEngine::connect_until(socket_path, deadline)
+            .map(FileEngine::Nbd)
+            .map_err(Error::Nbd)
+    }
+
     #[cfg(test)]
     pub fn file(&self) -> &File {
         match self {
             FileEngine::Async(engine) => engine.file(),
             FileEngine::Sync(engine) => engine.file(),
+            FileEngine::Nbd(_) => unreachable!("NBD engines have no backing file"),
         }
     }
 
@@ -81,27 +127,28 @@ impl<T> FileEngine<T> {
         &mut self,
         offset: u64,
         mem: &GuestMemoryMmap,
-        addr: GuestAddress,
-        count: u32,
+        segments: &[IoSegment],
         user_data: T,
     ) -> Result<FileEngineOk<T>, UserDataError<T, Error>> {
         match self {
-            FileEngine::Async(engine) => {
-                match engine.push_read(offset, mem, addr, count, user_data) {
-                    Ok(_) => Ok(FileEngineOk::Submitted),
-                    Err(e) => Err(UserDataError {
-                        user_data: e.user_data,
-                        error: Error::Async(e.error),
-                    }),
-                }
-            }
-            FileEngine::Sync(engine) => match engine.read(offset, mem, addr, count) {
+            FileEngine::Async(engine) => match engine.push_read(offset, mem, segments, user_data) {
+                Ok(_) => Ok(FileEngineOk::Submitted),
+                Err(e) => Err(UserDataError {
+                    user_data: e.user_data,
+                    error: Error::Async(e.error),
+                }),
+            },
+            FileEngine::Sync(engine) => match engine.read(offset, mem, segments) {
                 Ok(count) => Ok(FileEngineOk::Executed(UserDataOk { user_data, count })),
                 Err(e) => Err(UserDataError {
                     user_data,
                     error: Error::Sync(e),
                 }),
             },
+            FileEngine::Nbd(engine) => engine
+                .push_read(offset, segments, user_data)
+                .map(|_| FileEngineOk::Submitted)
+                .map_err(Error::from_nbd),
         }
     }
 
@@ -109,13 +156,12 @@ impl<T> FileEngine<T> {
         &mut self,
         offset: u64,
         mem: &GuestMemoryMmap,
-        addr: GuestAddress,
-        count: u32,
+        segments: &[IoSegment],
         user_data: T,
     ) -> Result<FileEngineOk<T>, UserDataError<T, Error>> {
         match self {
             FileEngine::Async(engine) => {
-                match engine.push_write(offset, mem, addr, count, user_data) {
+                match engine.push_write(offset, mem, segments, user_data) {
                     Ok(_) => Ok(FileEngineOk::Submitted),
                     Err(e) => Err(UserDataError {
                         user_data: e.user_data,
@@ -123,13 +169,17 @@ impl<T> FileEngine<T> {
                     }),
                 }
             }
-            FileEngine::Sync(engine) => match engine.write(offset, mem, addr, count) {
+            FileEngine::Sync(engine) => match engine.write(offset, mem, segments) {
                 Ok(count) => Ok(FileEngineOk::Executed(UserDataOk { user_data, count })),
                 Err(e) => Err(UserDataError {
                     user_data,
                     error: Error::Sync(e),
                 }),
             },
+            FileEngine::Nbd(engine) => engine
+                .push_write(offset, mem, segments, user_data)
+                .map(|_| FileEngineOk::Submitted)
+                .map_err(Error::from_nbd),
         }
     }
 
@@ -152,13 +202,95 @@ impl<T> FileEngine<T> {
                     error: Error::Sync(e),
                 }),
             },
+            FileEngine::Nbd(engine) => engine
+                .push_flush(user_data)
+                .map(|_| FileEngineOk::Submitted)
+                .map_err(Error::from_nbd),
         }
     }
 
+    fn fallocate(
+        &mut self,
+        mode: i32,
+        offset: u64,
+        len: u64,
+        user_data: T,
+    ) -> Result<FileEngineOk<T>, UserDataError<T, Error>> {
+        match self {
+            FileEngine::Async(engine) => {
+                match engine.push_fallocate(mode as u32, offset, len, user_data) {
+                    Ok(_) => Ok(FileEngineOk::Submitted),
+                    Err(e) => Err(UserDataError {
+                        user_data: e.user_data,
+                        error: Error::Async(e.error),
+                    }),
+                }
+            }
+            FileEngine::Sync(engine) => match engine.fallocate(mode, offset, len) {
+                Ok(_) => Ok(FileEngineOk::Executed(UserDataOk {
+                    user_data,
+                    count: 0,
+                })),
+                Err(e) => Err(UserDataError {
+                    user_data,
+                    error: Error::Sync(e),
+                }),
+            },
+            // Callers dispatch NBD engines to the matching commands instead.
+            FileEngine::Nbd(_) => unreachable!("NBD engines don't allocate files"),
+        }
+    }
+
+    /// Deallocates the range, which then reads back as zeroes.
+    pub fn discard(
+        &mut self,
+        offset: u64,
+        len: u64,
+        user_data: T,
+    ) -> Result<FileEngineOk<T>, UserDataError<T, Error>> {
+        if let FileEngine::Nbd(engine) = self {
+            // Safe to truncate since the length of a request fits in 32 bits.
+            return engine
+                .push_trim(offset, len as u32, user_data)
+                .map(|_| FileEngineOk::Submitted)
+                .map_err(Error::from_nbd);
+        }
+        self.fallocate(
+            libc::FALLOC_FL_PUNCH_HOLE | libc::FALLOC_FL_KEEP_SIZE,
+            offset,
+            len,
+            user_data,
+        )
+    }
+
+    /// Zeroes the range, deallocating it if `unmap` is set.
+    pub fn write_zeroes(
+        &mut self,
+        offset: u64,
+        len: u64,
+        unmap: bool,
+        user_data: T,
+    ) -> Result<FileEngineOk<T>, UserDataError<T, Error>> {
+        if let FileEngine::Nbd(engine) = self {
+            // Safe to truncate since the length of a request fits in 32 bits.
+            return engine
+                .push_write_zeroes(offset, len as u32, unmap, user_data)
+                .map(|_| FileEngineOk::Submitted)
+                .map_err(Error::from_nbd);
+        }
+        let mode = if unmap {
+            libc::FALLOC_FL_PUNCH_HOLE
+        } else {
+            libc::FALLOC_FL_ZERO_RANGE
+        };
+        self.fallocate(mode | libc::FALLOC_FL_KEEP_SIZE, offset, len, user_data)
+    }
+
     pub fn drain(&mut self, discard: bool) -> Result<(), Error> {
         match self {
             FileEngine::Async(engine) => engine.drain(discard).map_err(Error::Async),
             FileEngine::Sync(_engine) => Ok(()),
+            FileEngine::Nbd(engine) => engine.drain(discard).map_err(Error::Nbd),
         }
     }
 
@@ -166,18 +298,22 @@ impl<T> FileEngine<T> {
         match self {
             FileEngine::Async(engine) => engine.drain_and_flush(discard).map_err(Error::Async),
             FileEngine::Sync(engine) => engine.flush().map_err(Error::Sync),
+            FileEngine::Nbd(engine) => engine.drain_and_flush(discard).map_err(Error::Nbd),
         }
     }
 }
 
 #[cfg(test)]
 pub mod tests {
+    use std::fs::OpenOptions;
     use std::os::unix::ffi::OsStrExt;
+    use std::os::unix::fs::{FileExt, OpenOptionsExt};
     use std::os::unix::io::FromRawFd;
 
     use super::*;
     use crate::virtio::block::device::FileEngineType;
     use crate::virtio::block::request::PendingRequest;
+    use crate::virtio::block::test_utils::direct_io_temp_file;
     use utils::kernel_version::{min_kernel_version_for_io_uring, KernelVersion};
     use utils::tempfile::TempFile;
     use utils::{skip_if_io_uring_supported, skip_if_io_uring_unsupported};
@@ -230,6 +366,27 @@ pub mod tests {
         }
     }
 
+    fn assert_execution(
+        mem: &GuestMemoryMmap,
+        engine: &mut FileEngine<()>,
+        res: Result<FileEngineOk<()>, UserDataError<(), Error>>,
+        count: u32,
+    ) {
+        match res {
+            Ok(FileEngineOk::Submitted) => assert_async_execution(mem, engine, count),
+            Ok(FileEngineOk::Executed(res)) => assert_eq!(res.count, count),
+            Err(e) => panic!("expected Ok, received {:?}", e.error),
+        }
+    }
+
+    fn supported_engine_types() -> Vec<FileEngineType> {
+        let mut engine_types = vec![FileEngineType::Sync];
+        if KernelVersion::get().unwrap() >= min_kernel_version_for_io_uring() {
+            engine_types.push(FileEngineType::Async);
+        }
+        engine_types
+    }
+
     fn create_mem() -> GuestMemoryMmap {
         vm_memory::test_utils::create_anon_guest_memory(&[(GuestAddress(0), MEM_LEN)], true)
             .unwrap()
@@ -256,7 +413,9 @@ pub mod tests {
         assert!(matches!(
             FileEngine::<PendingRequest>::from_file(
                 TempFile::new().unwrap().into_file(),
-                FileEngineType::Async
+                FileEngineType::Async,
+                false,
+                false
             ),
             Err(Error::UnsupportedEngine(FileEngineType::Async))
         ));
@@ -267,17 +426,17 @@ pub mod tests {
         // Check invalid file
         let mem = create_mem();
         let file = unsafe { File::from_raw_fd(-2) };
-        let mut engine = FileEngine::from_file(file, FileEngineType::Sync).unwrap();
-        let res = engine.read(0, &mem, GuestAddress(0), 0, ());
+        let mut engine = FileEngine::from_file(file, FileEngineType::Sync, false, false).unwrap();
+        let res = engine.read(0, &mem, &[IoSegment::new(GuestAddress(0), 0)], ());
         assert_err!(res, Error::Sync(sync_io::Error::Seek(_e)));
-        let res = engine.write(0, &mem, GuestAddress(0), 0, ());
+        let res = engine.write(0, &mem, &[IoSegment::new(GuestAddress(0), 0)], ());
         assert_err!(res, Error::Sync(sync_io::Error::Seek(_e)));
         let res = engine.flush(());
         assert_err!(res, Error::Sync(sync_io::Error::SyncAll(_e)));
 
         // Create backing file.
         let file = TempFile::new().unwrap().into_file();
-        let mut engine = FileEngine::from_file(file, FileEngineType::Sync).unwrap();
+        let mut engine = FileEngine::from_file(file, FileEngineType::Sync, false, false).unwrap();
 
         let data = utils::rand::rand_alphanumerics(FILE_LEN as usize)
             .as_bytes()
@@ -288,12 +447,15 @@ pub mod tests {
         let addr = GuestAddress(MEM_LEN as u64 - partial_len);
         mem.write(&data, addr).unwrap();
         assert_sync_execution!(
-            engine.write(0, &mem, addr, FILE_LEN, ()),
+            engine.write(0, &mem, &[IoSegment::new(addr, FILE_LEN)], ()),
             partial_len as u32
         );
         // Partial read
         let mem = create_mem();
-        assert_sync_execution!(engine.read(0, &mem, addr, FILE_LEN, ()), partial_len as u32);
+        assert_sync_execution!(
+            engine.read(0, &mem, &[IoSegment::new(addr, FILE_LEN)], ()),
+            partial_len as u32
+        );
         // Check data
         let mut buf = vec![0u8; partial_len as usize];
         mem.read_slice(&mut buf, addr).unwrap();
@@ -305,13 +467,13 @@ pub mod tests {
         let addr = GuestAddress(0);
         mem.write(&data, addr).unwrap();
         assert_sync_execution!(
-            engine.write(offset, &mem, addr, partial_len, ()),
+            engine.write(offset, &mem, &[IoSegment::new(addr, partial_len)], ()),
             partial_len as u32
         );
         // Offset read
         let mem = create_mem();
         assert_sync_execution!(
-            engine.read(offset, &mem, addr, partial_len, ()),
+            engine.read(offset, &mem, &[IoSegment::new(addr, partial_len)], ()),
             partial_len as u32
         );
         // Check data
@@ -322,13 +484,13 @@ pub mod tests {
         // Full write
         mem.write(&data, GuestAddress(0)).unwrap();
         assert_sync_execution!(
-            engine.write(0, &mem, GuestAddress(0), FILE_LEN, ()),
+            engine.write(0, &mem, &[IoSegment::new(GuestAddress(0), FILE_LEN)], ()),
             FILE_LEN
         );
         // Full read
         let mem = create_mem();
         assert_sync_execution!(
-            engine.read(0, &mem, GuestAddress(0), FILE_LEN, ()),
+            engine.read(0, &mem, &[IoSegment::new(GuestAddress(0), FILE_LEN)], ()),
             FILE_LEN
         );
         // Check data
@@ -348,11 +510,12 @@ pub mod tests {
 
         // Check invalid file
         let file = unsafe { File::from_raw_fd(-2) };
-        assert!(FileEngine::<()>::from_file(file, FileEngineType::Async).is_err());
+        assert!(FileEngine::<()>::from_file(file, FileEngineType::Async, false, false).is_err());
 
         // Create backing file.
         let file = TempFile::new().unwrap().into_file();
-        let mut engine = FileEngine::<()>::from_file(file, FileEngineType::Async).unwrap();
+        let mut engine =
+            FileEngine::<()>::from_file(file, FileEngineType::Async, false, false).unwrap();
 
         let data = utils::rand::rand_alphanumerics(FILE_LEN as usize)
             .as_bytes()
@@ -367,11 +530,11 @@ pub mod tests {
         let partial_len = 50;
         let addr = GuestAddress(0);
         mem.write(&data, addr).unwrap();
-        assert_queued!(engine.write(offset, &mem, addr, partial_len, ()));
+        assert_queued!(engine.write(offset, &mem, &[IoSegment::new(addr, partial_len)], ()));
         assert_async_execution(&mem, &mut engine, partial_len as u32);
         // Offset read
         let mem = create_mem();
-        assert_queued!(engine.read(offset, &mem, addr, partial_len, ()));
+        assert_queued!(engine.read(offset, &mem, &[IoSegment::new(addr, partial_len)], ()));
         assert_async_execution(&mem, &mut engine, partial_len as u32);
         // Check data
         let mut buf = vec![0u8; partial_len as usize];
@@ -383,12 +546,12 @@ pub mod tests {
 
         // Full write
         mem.write(&data, GuestAddress(0)).unwrap();
-        assert_queued!(engine.write(0, &mem, addr, FILE_LEN, ()));
+        assert_queued!(engine.write(0, &mem, &[IoSegment::new(addr, FILE_LEN)], ()));
         assert_async_execution(&mem, &mut engine, FILE_LEN as u32);
 
         // Full read
         let mem = create_mem();
-        assert_queued!(engine.read(0, &mem, addr, FILE_LEN, ()));
+        assert_queued!(engine.read(0, &mem, &[IoSegment::new(addr, FILE_LEN)], ()));
         assert_async_execution(&mem, &mut engine, FILE_LEN as u32);
         // Check data
         let mut buf = vec![0u8; FILE_LEN as usize];
@@ -405,4 +568,227 @@ pub mod tests {
         assert!(engine.drain(true).is_ok());
         assert!(engine.drain_and_flush(true).is_ok());
     }
+
+    #[test]
+    fn test_multiple_segments() {
+        let data = utils::rand::rand_alphanumerics(FILE_LEN as usize)
+            .as_bytes()
+            .to_vec();
+        let half_len = FILE_LEN / 2;
+        // Scatter the data across both pages of memory, in reverse order.
+        let segments = [
+            IoSegment::new(GuestAddress(4096), half_len),
+            IoSegment::new(GuestAddress(0), half_len),
+        ];
+
+        for engine_type in supported_engine_types() {
+            let is_sync = engine_type == FileEngineType::Sync;
+            let file = TempFile::new().unwrap().into_file();
+            let mut engine = FileEngine::<()>::from_file(file, engine_type, false, false).unwrap();
+
+            // Vectored write
+            let mem = create_mem();
+            mem.write_slice(&data[..half_len as usize], segments[0].addr)
+                .unwrap();
+            mem.write_slice(&data[half_len as usize..], segments[1].addr)
+                .unwrap();
+            let res = engine.write(0, &mem, &segments, ());
+            assert_execution(&mem, &mut engine, res, FILE_LEN);
+
+            // Vectored read
+            let mem = create_mem();
+            let res = engine.read(0, &mem, &segments, ());
+            assert_execution(&mem, &mut engine, res, FILE_LEN);
+            // Check data
+            let mut buf = vec![0u8; FILE_LEN as usize];
+            mem.read_slice(&mut buf[..half_len as usize], segments[0].addr)
+                .unwrap();
+            mem.read_slice(&mut buf[half_len as usize..], segments[1].addr)
+                .unwrap();
+            assert_eq!(buf, data);
+            // Check that the file holds the data contiguously.
+            let mut buf = vec![0u8; FILE_LEN as usize];
+            engine.file().read_exact_at(&mut buf, 0).unwrap();
+            assert_eq!(buf, data);
+
+            // Partial read: the transfer stops at the first segment which doesn't fit in memory.
+            // Partial transfers can't be tested for the async engine, as io_uring returns an
+            // error code for accessing unmapped memory.
+            if is_sync {
+                let mem = create_mem();
+                let partial_segments = [
+                    IoSegment::new(GuestAddress(0), half_len),
+                    IoSegment::new(GuestAddress(MEM_LEN as u64 - 50), half_len),
+                    IoSegment::new(GuestAddress(4096), half_len),
+                ];
+                let res = engine.read(0, &mem, &partial_segments, ());
+                assert_execution(&mem, &mut engine, res, half_len + 50);
+            }
+        }
+    }
+
+    #[test]
+    fn test_async_fixed_buffers() {
+        skip_if_io_uring_unsupported!();
+
+        let file = TempFile::new().unwrap().into_file();
+        let mut engine =
+            FileEngine::<()>::from_file(file, FileEngineType::Async, false, false).unwrap();
+        let mem = create_mem();
+        if let FileEngine::Async(ref mut engine) = engine {
+            engine.register_memory(&mem).unwrap();
+            // Buffers can only be registered once.
+            assert!(engine.register_memory(&mem).is_err());
+        }
+
+        let data = utils::rand::rand_alphanumerics(FILE_LEN as usize)
+            .as_bytes()
+            .to_vec();
+        let addr = GuestAddress(100);
+
+        // Write from a registered buffer
+        mem.write_slice(&data, addr).unwrap();
+        assert_queued!(engine.write(0, &mem, &[IoSegment::new(addr, FILE_LEN)], ()));
+        assert_async_execution(&mem, &mut engine, FILE_LEN);
+
+        // Read into a registered buffer
+        mem.write_slice(&[0u8; FILE_LEN as usize], addr).unwrap();
+        assert_queued!(engine.read(0, &mem, &[IoSegment::new(addr, FILE_LEN)], ()));
+        assert_async_execution(&mem, &mut engine, FILE_LEN);
+        // Check data
+        let mut buf = vec![0u8; FILE_LEN as usize];
+        mem.read_slice(&mut buf, addr).unwrap();
+        assert_eq!(buf, data);
+
+        // Memory outside of the registered buffers is still usable.
+        let other_mem = create_mem();
+        assert_queued!(engine.read(0, &other_mem, &[IoSegment::new(addr, FILE_LEN)], ()));
+        assert_async_execution(&other_mem, &mut engine, FILE_LEN);
+        other_mem.read_slice(&mut buf, addr).unwrap();
+        assert_eq!(buf, data);
+        check_dirty_mem(&other_mem, addr, FILE_LEN);
+    }
+
+    #[test]
+    fn test_async_sq_poll() {
+        skip_if_io_uring_unsupported!();
+
+        let file = TempFile::new().unwrap().into_file();
+        let mut engine =
+            FileEngine::<()>::from_file(file, FileEngineType::Async, false, true).unwrap();
+        let mem = create_mem();
+        let data = utils::rand::rand_alphanumerics(FILE_LEN as usize)
+            .as_bytes()
+            .to_vec();
+        let addr = GuestAddress(0);
+
+        mem.write_slice(&data, addr).unwrap();
+        assert_queued!(engine.write(0, &mem, &[IoSegment::new(addr, FILE_LEN)], ()));
+        assert_async_execution(&mem, &mut engine, FILE_LEN);
+
+        let mem = create_mem();
+        assert_queued!(engine.read(0, &mem, &[IoSegment::new(addr, FILE_LEN)], ()));
+        assert_async_execution(&mem, &mut engine, FILE_LEN);
+        let mut buf = vec![0u8; FILE_LEN as usize];
+        mem.read_slice(&mut buf, addr).unwrap();
+        assert_eq!(buf, data);
+    }
+
+    #[test]
+    fn test_direct_io() {
+        let data = utils::rand::rand_alphanumerics(FILE_LEN as usize)
+            .as_bytes()
+            .to_vec();
+        let half_len = FILE_LEN / 2;
+        // Aligned segments are transferred in place, unaligned ones are bounced.
+        let aligned_segments = [
+            IoSegment::new(GuestAddress(4096), half_len),
+            IoSegment::new(GuestAddress(0), half_len),
+        ];
+        let unaligned_segments = [
+            IoSegment::new(GuestAddress(4097), half_len - 1),
+            IoSegment::new(GuestAddress(3), half_len + 1),
+        ];
+
+        for segments in [&aligned_segments, &unaligned_segments].iter() {
+            for engine_type in supported_engine_types() {
+                let temp_file = direct_io_temp_file();
+                let file = OpenOptions::new()
+                    .read(true)
+                    .write(true)
+                    .custom_flags(libc::O_DIRECT)
+                    .open(temp_file.as_path())
+                    .unwrap();
+                let mut engine =
+                    FileEngine::<()>::from_file(file, engine_type, true, false).unwrap();
+
+                // Write
+                let mem = create_mem();
+                let first_len = segments[0].len as usize;
+                mem.write_slice(&data[..first_len], segments[0].addr)
+                    .unwrap();
+                mem.write_slice(&data[first_len..], segments[1].addr)
+                    .unwrap();
+                let res = engine.write(0, &mem, *segments, ());
+                assert_execution(&mem, &mut engine, res, FILE_LEN);
+                // Check that the file holds the data contiguously.
+                let mut buf = vec![0u8; FILE_LEN as usize];
+                temp_file.as_file().read_exact_at(&mut buf, 0).unwrap();
+                assert_eq!(buf, data);
+
+                // Read
+                let mem = create_mem();
+                let res = engine.read(0, &mem, *segments, ());
+                assert_execution(&mem, &mut engine, res, FILE_LEN);
+                // Check data
+                let mut buf = vec![0u8; FILE_LEN as usize];
+                mem.read_slice(&mut buf[..first_len], segments[0].addr)
+                    .unwrap();
+                mem.read_slice(&mut buf[first_len..], segments[1].addr)
+                    .unwrap();
+                assert_eq!(buf, data);
+                // Check dirty mem
+                check_dirty_mem(&mem, segments[0].addr, segments[0].len);
+                check_dirty_mem(&mem, segments[1].addr, segments[1].len);
+            }
+        }
+    }
+
+    #[test]
+    fn test_discard_and_write_zeroes() {
+        let data = vec![0xffu8; FILE_LEN as usize];
+
+        for engine_type in supported_engine_types() {
+            let temp_file = TempFile::new().unwrap();
+            temp_file.as_file().write_all_at(&data, 0).unwrap();
+            let file = OpenOptions::new()
+                .read(true)
+                .write(true)
+                .open(temp_file.as_path())
+                .unwrap();
+            let mem = create_mem();
+            let mut engine = FileEngine::<()>::from_file(file, engine_type, false, false).unwrap();
+
+            // Discard
+            let res = engine.discard(0, 256, ());
+            assert_execution(&mem, &mut engine, res, 0);
+            // Write zeroes, deallocating the range
+            let res = engine.write_zeroes(256, 256, true, ());
+            assert_execution(&mem, &mut engine, res, 0);
+            // Write zeroes, keeping the range allocated
+            let res = engine.write_zeroes(512, 256, false, ());
+            assert_execution(&mem, &mut engine, res, 0);
+
+            // Check that the file size didn't change.
+            assert_eq!(
+                temp_file.as_file().metadata().unwrap().len(),
+                u64::from(FILE_LEN)
+            );
+            // Check data
+            let mut buf = vec![0u8; FILE_LEN as usize];
+            temp_file.as_file().read_exact_at(&mut buf, 0).unwrap();
+            assert_eq!(buf[..768], [0u8; 768][..]);
+            assert_eq!(buf[768..], data[768..]);
+        }
+    }
 }
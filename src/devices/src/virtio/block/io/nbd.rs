@@ -0,0 +1,1546 @@
+// Copyright 2022 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Client for the fixed newstyle NBD protocol, serving the block requests from the default export
+//! of a server listening on a Unix socket.
+//!
+//! The protocol is described at
+//! <https://github.com/NetworkBlockDevice/nbd/blob/master/doc/proto.md>.
+
+use std::collections::{HashMap, VecDeque};
+use std::convert::TryInto;
+use std::io::{self, Read, Write};
+use std::net::Shutdown;
+use std::ops::Range;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::os::unix::net::UnixStream;
+use std::time::{Duration, Instant};
+
+use logger::error;
+use utils::epoll::{ControlOperation, Epoll, EpollEvent, EventSet};
+use vm_memory::{GuestMemoryError, GuestMemoryMmap};
+
+use crate::virtio::block::io::bounce_buffer::{self, BounceBuffer};
+use crate::virtio::block::io::{IoSegment, UserDataError};
+
+// Magic numbers of the handshake and transmission messages.
+const NBD_MAGIC: u64 = 0x4e42_444d_4147_4943;
+const NBD_OPTS_MAGIC: u64 = 0x4948_4156_454f_5054;
+const NBD_REP_MAGIC: u64 = 0x0003_e889_0455_65a9;
+const NBD_REQUEST_MAGIC: u32 = 0x2560_9513;
+const NBD_SIMPLE_REPLY_MAGIC: u32 = 0x6744_6698;
+const NBD_STRUCTURED_REPLY_MAGIC: u32 = 0x668e_33ef;
+
+// Handshake flags, sent by the server and the client respectively.
+const NBD_FLAG_FIXED_NEWSTYLE: u16 = 1 << 0;
+const NBD_FLAG_NO_ZEROES: u16 = 1 << 1;
+const NBD_FLAG_C_FIXED_NEWSTYLE: u32 = 1 << 0;
+const NBD_FLAG_C_NO_ZEROES: u32 = 1 << 1;
+
+// Handshake options, and their replies.
+const NBD_OPT_GO: u32 = 7;
+const NBD_OPT_STRUCTURED_REPLY: u32 = 8;
+const NBD_REP_ACK: u32 = 1;
+const NBD_REP_INFO: u32 = 3;
+const NBD_REP_FLAG_ERROR: u32 = 1 << 31;
+const NBD_INFO_EXPORT: u16 = 0;
+
+/// Transmission flags, advertising the commands supported by an export.
+pub const NBD_FLAG_HAS_FLAGS: u16 = 1 << 0;
+pub const NBD_FLAG_READ_ONLY: u16 = 1 << 1;
+pub const NBD_FLAG_SEND_FLUSH: u16 = 1 << 2;
+pub const NBD_FLAG_SEND_TRIM: u16 = 1 << 5;
+pub const NBD_FLAG_SEND_WRITE_ZEROES: u16 = 1 << 6;
+pub const NBD_FLAG_CAN_MULTI_CONN: u16 = 1 << 8;
+
+// Commands, and their flags.
+const NBD_CMD_READ: u16 = 0;
+const NBD_CMD_WRITE: u16 = 1;
+const NBD_CMD_DISC: u16 = 2;
+const NBD_CMD_FLUSH: u16 = 3;
+const NBD_CMD_TRIM: u16 = 4;
+const NBD_CMD_WRITE_ZEROES: u16 = 6;
+const NBD_CMD_FLAG_NO_HOLE: u16 = 1 << 1;
+
+// Structured reply chunks.
+const NBD_REPLY_FLAG_DONE: u16 = 1 << 0;
+const NBD_REPLY_TYPE_NONE: u16 = 0;
+const NBD_REPLY_TYPE_OFFSET_DATA: u16 = 1;
+const NBD_REPLY_TYPE_OFFSET_HOLE: u16 = 2;
+const NBD_REPLY_TYPE_ERROR_BIT: u16 = 1 << 15;
+
+const OPTION_REPLY_HEADER_LEN: usize = 20;
+const REQUEST_LEN: usize = 28;
+const SIMPLE_REPLY_LEN: usize = 16;
+const STRUCTURED_REPLY_HEADER_LEN: usize = 20;
+// Option replies only hold export information and error messages, which are short.
+const MAX_OPTION_REPLY_LEN: u32 = 64 << 10;
+// Error chunks hold the error, along with a message of up to 4KiB and an offset.
+const MAX_ERROR_CHUNK_LEN: usize = 4 + 2 + 4096 + 8;
+// The amount of data read from the socket at once.
+const RECV_LEN: usize = 64 << 10;
+// The number of commands in flight at once, past which the submission of requests is throttled.
+pub const MAX_IN_FLIGHT: usize = 128;
+/// How long connecting to the server, handshake included, may take. The connections run on the
+/// thread of the API request configuring the drive, so those of all its queues share the deadline.
+pub const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+// How long draining the in-flight commands may take, as when pausing the microVM for a snapshot.
+const DRAIN_TIMEOUT: Duration = Duration::from_secs(30);
+
+#[derive(Debug)]
+pub enum Error {
+    /// Exchanging data with the server failed.
+    IO(io::Error),
+    /// The server doesn't follow the protocol.
+    Protocol(&'static str),
+    /// The server refused an option of the handshake.
+    OptionRefused {
+        option: u32,
+        reply: u32,
+    },
+    /// The server failed a command.
+    Command(io::Error),
+    /// The export doesn't support the command.
+    UnsupportedCommand(u16),
+    /// The export is read-only, unlike the drive.
+    ReadOnlyExport,
+    /// The connection to the server is lost.
+    Disconnected,
+    /// Too many commands are in flight.
+    FullQueue,
+    /// The server didn't answer in time.
+    Timeout,
+    GuestMemory(GuestMemoryError),
+}
+
+/// The properties of an export, as advertised by the server.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct NbdExport {
+    /// The size of the export, in bytes.
+    pub size: u64,
+    /// The transmission flags of the export.
+    pub flags: u16,
+}
+
+impl NbdExport {
+    pub fn has_flag(&self, flag: u16) -> bool {
+        self.flags & flag != 0
+    }
+}
+
+fn be_u16(buf: &[u8]) -> u16 {
+    u16::from_be_bytes(buf[..2].try_into().unwrap())
+}
+
+fn be_u32(buf: &[u8]) -> u32 {
+    u32::from_be_bytes(buf[..4].try_into().unwrap())
+}
+
+fn be_u64(buf: &[u8]) -> u64 {
+    u64::from_be_bytes(buf[..8].try_into().unwrap())
+}
+
+// NBD errors have the values of the matching Linux errnos.
+fn command_error(errno: u32) -> Error {
+    Error::Command(io::Error::from_raw_os_error(errno as i32))
+}
+
+// Waits for `socket`, registered with `epoll`, to become ready for `events`, until `deadline`.
+//
+// The socket is non-blocking, and waiting through epoll rather than socket timeouts keeps the
+// system calls within those allowed to the VMM thread.
+fn wait(
+    epoll: &Epoll,
+    socket: &UnixStream,
+    events: EventSet,
+    deadline: Instant,
+) -> Result<(), Error> {
+    let fd = socket.as_raw_fd();
+    epoll
+        .ctl(
+            ControlOperation::Modify,
+            fd,
+            EpollEvent::new(events, fd as u64),
+        )
+        .map_err(Error::IO)?;
+    let mut ready = [EpollEvent::default()];
+    loop {
+        // Rounded up to whole milliseconds, so as not to wake up before the deadline.
+        let timeout = deadline.saturating_duration_since(Instant::now());
+        let timeout_ms = (timeout.as_micros() + 999) / 1000;
+        if timeout_ms == 0 {
+            return Err(Error::Timeout);
+        }
+        // The deadline is checked again once the wait times out or gets interrupted.
+        match epoll.wait(timeout_ms.try_into().unwrap_or(i32::MAX), &mut ready) {
+            Ok(0) => {}
+            Ok(_) => return Ok(()),
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(Error::IO(e)),
+        }
+    }
+}
+
+// The socket of a connection going through the handshake, whose reads and writes wait for it to
+// become ready until the deadline of the connection, failing with `TimedOut` past it.
+struct HandshakeStream<'a> {
+    socket: &'a UnixStream,
+    epoll: &'a Epoll,
+    deadline: Instant,
+}
+
+impl HandshakeStream<'_> {
+    fn retry<F>(&self, events: EventSet, mut f: F) -> io::Result<usize>
+    where
+        F: FnMut() -> io::Result<usize>,
+    {
+        loop {
+            match f() {
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                    wait(self.epoll, self.socket, events, self.deadline).map_err(|e| match e {
+                        Error::IO(e) => e,
+                        _ => io::Error::from(io::ErrorKind::TimedOut),
+                    })?
+                }
+                res => return res,
+            }
+        }
+    }
+}
+
+impl Read for HandshakeStream<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut socket = self.socket;
+        self.retry(EventSet::IN, || socket.read(buf))
+    }
+}
+
+impl Write for HandshakeStream<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut socket = self.socket;
+        self.retry(EventSet::OUT, || socket.write(buf))
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+fn send_option(socket: &mut HandshakeStream, option: u32, data: &[u8]) -> Result<(), Error> {
+    let mut msg = Vec::with_capacity(16 + data.len());
+    msg.extend_from_slice(&NBD_OPTS_MAGIC.to_be_bytes());
+    msg.extend_from_slice(&option.to_be_bytes());
+    msg.extend_from_slice(&(data.len() as u32).to_be_bytes());
+    msg.extend_from_slice(data);
+    socket.write_all(&msg).map_err(Error::IO)
+}
+
+// Receives a reply to `option`, returning its type and data.
+fn recv_option_reply(socket: &mut HandshakeStream, option: u32) -> Result<(u32, Vec<u8>), Error> {
+    let mut header = [0u8; OPTION_REPLY_HEADER_LEN];
+    socket.read_exact(&mut header).map_err(Error::IO)?;
+    if be_u64(&header[0..8]) != NBD_REP_MAGIC || be_u32(&header[8..12]) != option {
+        return Err(Error::Protocol("invalid option reply"));
+    }
+    let len = be_u32(&header[16..20]);
+    if len > MAX_OPTION_REPLY_LEN {
+        return Err(Error::Protocol("option reply too long"));
+    }
+    let mut data = vec![0u8; len as usize];
+    socket.read_exact(&mut data).map_err(Error::IO)?;
+    Ok((be_u32(&header[12..16]), data))
+}
+
+// Selects the default export of the server, returning its properties and whether the server
+// sends structured replies.
+fn handshake(socket: &mut HandshakeStream) -> Result<(NbdExport, bool), Error> {
+    let mut greeting = [0u8; 18];
+    socket.read_exact(&mut greeting).map_err(Error::IO)?;
+    if be_u64(&greeting[0..8]) != NBD_MAGIC || be_u64(&greeting[8..16]) != NBD_OPTS_MAGIC {
+        return Err(Error::Protocol("not a newstyle server"));
+    }
+    let handshake_flags = be_u16(&greeting[16..18]);
+    if handshake_flags & NBD_FLAG_FIXED_NEWSTYLE == 0 {
+        return Err(Error::Protocol("fixed newstyle negotiation unsupported"));
+    }
+    let mut client_flags = NBD_FLAG_C_FIXED_NEWSTYLE;
+    if handshake_flags & NBD_FLAG_NO_ZEROES != 0 {
+        client_flags |= NBD_FLAG_C_NO_ZEROES;
+    }
+    socket
+        .write_all(&client_flags.to_be_bytes())
+        .map_err(Error::IO)?;
+
+    // Servers which don't support structured replies send the data of reads in simple replies.
+    send_option(socket, NBD_OPT_STRUCTURED_REPLY, &[])?;
+    let structured_replies = match recv_option_reply(socket, NBD_OPT_STRUCTURED_REPLY)?.0 {
+        NBD_REP_ACK => true,
+        reply if reply & NBD_REP_FLAG_ERROR != 0 => false,
+        _ => return Err(Error::Protocol("unexpected structured reply option reply")),
+    };
+
+    // The default export has an empty name. The server always sends its size and flags, so no
+    // additional information is requested.
+    let mut go_data = Vec::new();
+    go_data.extend_from_slice(&0u32.to_be_bytes());
+    go_data.extend_from_slice(&0u16.to_be_bytes());
+    send_option(socket, NBD_OPT_GO, &go_data)?;
+    let mut export = None;
+    loop {
+        match recv_option_reply(socket, NBD_OPT_GO)? {
+            (NBD_REP_ACK, _) => break,
+            (NBD_REP_INFO, data) => {
+                if data.len() >= 2 && be_u16(&data[0..2]) == NBD_INFO_EXPORT {
+                    if data.len() != 12 {
+                        return Err(Error::Protocol("invalid export information"));
+                    }
+                    export = Some(NbdExport {
+                        size: be_u64(&data[2..10]),
+                        flags: be_u16(&data[10..12]),
+                    });
+                }
+            }
+            (reply, _) if reply & NBD_REP_FLAG_ERROR != 0 => {
+                return Err(Error::OptionRefused {
+                    option: NBD_OPT_GO,
+                    reply,
+                })
+            }
+            _ => return Err(Error::Protocol("unexpected export option reply")),
+        }
+    }
+
+    export
+        .map(|export| (export, structured_replies))
+        .ok_or(Error::Protocol("missing export information"))
+}
+
+// A command sent to the server, until its reply is consumed.
+struct Command {
+    r#type: u16,
+    offset: u64,
+    len: u32,
+    // The segments receiving the data of a read.
+    segments: Vec<IoSegment>,
+    // The data of a read, which is copied to the segments once the command completes.
+    data: Option<BounceBuffer>,
+    // The ranges of the data of a read received in structured reply chunks, which have to cover
+    // all of it.
+    chunks: Vec<Range<usize>>,
+    error: Option<Error>,
+}
+
+impl Command {
+    fn new(r#type: u16, offset: u64, len: u32) -> Self {
+        Command {
+            r#type,
+            offset,
+            len,
+            segments: Vec::new(),
+            data: None,
+            chunks: Vec::new(),
+            error: None,
+        }
+    }
+
+    fn new_read(offset: u64, segments: &[IoSegment]) -> Self {
+        let len = bounce_buffer::segments_len(segments);
+        Command {
+            segments: segments.to_vec(),
+            data: Some(BounceBuffer::new(len)),
+            // Safe to truncate since the length of a request fits in 32 bits.
+            ..Command::new(NBD_CMD_READ, offset, len as u32)
+        }
+    }
+
+    // Returns the range of the data of the command covered by a chunk of `len` bytes at
+    // `offset` on the export.
+    fn chunk_range(&mut self, offset: u64, len: usize) -> Result<Range<usize>, Error> {
+        let range = offset
+            .checked_sub(self.offset)
+            .map(|start| start as usize)
+            .filter(|start| start.saturating_add(len) <= self.len as usize)
+            .map(|start| start..start + len)
+            .ok_or(Error::Protocol("reply chunk out of the command range"))?;
+        self.chunks.push(range.clone());
+        Ok(range)
+    }
+
+    // Fails a read whose structured reply leaves some of its data out.
+    fn check_chunks(&mut self) {
+        if self.r#type != NBD_CMD_READ || self.error.is_some() {
+            return;
+        }
+        self.chunks.sort_unstable_by_key(|range| range.start);
+        let mut covered = 0;
+        for range in self.chunks.iter() {
+            if range.start > covered {
+                break;
+            }
+            covered = std::cmp::max(covered, range.end);
+        }
+        if covered < self.len as usize {
+            self.error = Some(Error::Protocol("read reply missing some data"));
+        }
+    }
+
+    // Provides the number of bytes transferred by the command.
+    fn finish(self, mem: &GuestMemoryMmap) -> Result<u32, Error> {
+        if let Some(error) = self.error {
+            return Err(error);
+        }
+        match (self.r#type, self.data) {
+            (NBD_CMD_READ, Some(data)) => data
+                .copy_to_segments(mem, &self.segments, self.len as usize)
+                .map(|_| self.len)
+                .map_err(Error::GuestMemory),
+            (NBD_CMD_WRITE, _) => Ok(self.len),
+            _ => Ok(0),
+        }
+    }
+}
+
+/// IO engine forwarding the requests to an NBD server.
+///
+/// Commands are queued on the submission of a request, sent as the socket has room for them, and
+/// complete once their replies are received from the socket, which makes the socket the completion
+/// event of the engine. The socket never blocks once connected.
+pub struct NbdEngine<T> {
+    socket: UnixStream,
+    // Waits for the socket to become ready, while draining the in-flight commands.
+    epoll: Epoll,
+    export: NbdExport,
+    structured_replies: bool,
+    // Whether the connection is usable. It stops being so once the server goes away, or breaks
+    // the protocol.
+    connected: bool,
+    next_cookie: u64,
+    // The commands waiting for their reply, by cookie. The commands sent by the engine itself
+    // don't carry user data.
+    in_flight: HashMap<u64, (Command, Option<T>)>,
+    // The queued commands which aren't fully sent yet.
+    tx_buf: Vec<u8>,
+    // The data received from the server which doesn't form a whole reply yet.
+    rx_buf: Vec<u8>,
+    completed: VecDeque<(T, Command)>,
+    // The result of the last flush sent by the engine itself.
+    flush_result: Option<Result<(), Error>>,
+}
+
+impl<T> NbdEngine<T> {
+    #[cfg(test)]
+    pub fn connect(path: &str) -> Result<Self, Error> {
+        Self::connect_until(path, Instant::now() + CONNECT_TIMEOUT)
+    }
+
+    /// Connects to the server listening on `path`, and selects its default export, failing if
+    /// the handshake isn't over by `deadline`.
+    ///
+    /// Connecting to the socket itself only waits while the backlog of the server is full.
+    pub fn connect_until(path: &str, deadline: Instant) -> Result<Self, Error> {
+        let socket = UnixStream::connect(path).map_err(Error::IO)?;
+        socket.set_nonblocking(true).map_err(Error::IO)?;
+        let epoll = Epoll::new().map_err(Error::IO)?;
+        let fd = socket.as_raw_fd();
+        epoll
+            .ctl(
+                ControlOperation::Add,
+                fd,
+                EpollEvent::new(EventSet::IN, fd as u64),
+            )
+            .map_err(Error::IO)?;
+
+        let mut stream = HandshakeStream {
+            socket: &socket,
+            epoll: &epoll,
+            deadline,
+        };
+        let (export, structured_replies) = handshake(&mut stream).map_err(|e| match e {
+            Error::IO(e) if e.kind() == io::ErrorKind::TimedOut => Error::Timeout,
+            e => e,
+        })?;
+
+        Ok(NbdEngine {
+            socket,
+            epoll,
+            export,
+            structured_replies,
+            connected: true,
+            next_cookie: 0,
+            in_flight: HashMap::new(),
+            tx_buf: Vec::new(),
+            rx_buf: Vec::new(),
+            completed: VecDeque::new(),
+            flush_result: None,
+        })
+    }
+
+    pub fn export(&self) -> NbdExport {
+        self.export
+    }
+
+    /// The file descriptor becoming readable upon the completion of commands, while connected.
+    pub fn completion_fd(&self) -> Option<RawFd> {
+        if self.connected {
+            Some(self.socket.as_raw_fd())
+        } else {
+            None
+        }
+    }
+
+    /// Whether some queued commands wait for the socket to become writable.
+    pub fn has_unsent_commands(&self) -> bool {
+        !self.tx_buf.is_empty()
+    }
+
+    // Queues a command, returning its cookie.
+    fn queue(&mut self, command: &Command, flags: u16, payload: &[u8]) -> Result<u64, Error> {
+        if !self.connected {
+            return Err(Error::Disconnected);
+        }
+
+        let cookie = self.next_cookie;
+        self.next_cookie = self.next_cookie.wrapping_add(1);
+        self.tx_buf.reserve(REQUEST_LEN + payload.len());
+        self.tx_buf
+            .extend_from_slice(&NBD_REQUEST_MAGIC.to_be_bytes());
+        self.tx_buf.extend_from_slice(&flags.to_be_bytes());
+        self.tx_buf.extend_from_slice(&command.r#type.to_be_bytes());
+        self.tx_buf.extend_from_slice(&cookie.to_be_bytes());
+        self.tx_buf.extend_from_slice(&command.offset.to_be_bytes());
+        self.tx_buf.extend_from_slice(&command.len.to_be_bytes());
+        self.tx_buf.extend_from_slice(payload);
+
+        Ok(cookie)
+    }
+
+    fn push(
+        &mut self,
+        command: Command,
+        flags: u16,
+        payload: &[u8],
+        user_data: T,
+    ) -> Result<(), UserDataError<T, Error>> {
+        if self.connected && self.in_flight.len() >= MAX_IN_FLIGHT {
+            return Err(UserDataError {
+                user_data,
+                error: Error::FullQueue,
+            });
+        }
+        match self.queue(&command, flags, payload) {
+            Ok(cookie) => {
+                self.in_flight.insert(cookie, (command, Some(user_data)));
+                // Failing to send the command fails it along with the other in-flight ones.
+                if let Err(e) = self.send() {
+                    error!("Failed to send NBD commands: {:?}", e);
+                    self.disconnect();
+                }
+                Ok(())
+            }
+            Err(error) => Err(UserDataError { user_data, error }),
+        }
+    }
+
+    // Checks that the export supports a command, before sending it.
+    fn check_flag(&self, flag: u16, r#type: u16) -> Result<(), Error> {
+        if self.export.has_flag(flag) {
+            Ok(())
+        } else {
+            Err(Error::UnsupportedCommand(r#type))
+        }
+    }
+
+    pub fn push_read(
+        &mut self,
+        offset: u64,
+        segments: &[IoSegment],
+        user_data: T,
+    ) -> Result<(), UserDataError<T, Error>> {
+        self.push(Command::new_read(offset, segments), 0, &[], user_data)
+    }
+
+    pub fn push_write(
+        &mut self,
+        offset: u64,
+        mem: &GuestMemoryMmap,
+        segments: &[IoSegment],
+        user_data: T,
+    ) -> Result<(), UserDataError<T, Error>> {
+        let mut payload = BounceBuffer::new(bounce_buffer::segments_len(segments));
+        if let Err(e) = payload.copy_from_segments(mem, segments) {
+            return Err(UserDataError {
+                user_data,
+                error: Error::GuestMemory(e),
+            });
+        }
+        // Safe to truncate since the length of a request fits in 32 bits.
+        let command = Command::new(NBD_CMD_WRITE, offset, payload.as_slice().len() as u32);
+        self.push(command, 0, payload.as_slice(), user_data)
+    }
+
+    pub fn push_flush(&mut self, user_data: T) -> Result<(), UserDataError<T, Error>> {
+        if let Err(error) = self.check_flag(NBD_FLAG_SEND_FLUSH, NBD_CMD_FLUSH) {
+            return Err(UserDataError { user_data, error });
+        }
+        self.push(Command::new(NBD_CMD_FLUSH, 0, 0), 0, &[], user_data)
+    }
+
+    pub fn push_trim(
+        &mut self,
+        offset: u64,
+        len: u32,
+        user_data: T,
+    ) -> Result<(), UserDataError<T, Error>> {
+        if let Err(error) = self.check_flag(NBD_FLAG_SEND_TRIM, NBD_CMD_TRIM) {
+            return Err(UserDataError { user_data, error });
+        }
+        self.push(Command::new(NBD_CMD_TRIM, offset, len), 0, &[], user_data)
+    }
+
+    /// Zeroes the range, letting the server deallocate it if `unmap` is set.
+    pub fn push_write_zeroes(
+        &mut self,
+        offset: u64,
+        len: u32,
+        unmap: bool,
+        user_data: T,
+    ) -> Result<(), UserDataError<T, Error>> {
+        if let Err(error) = self.check_flag(NBD_FLAG_SEND_WRITE_ZEROES, NBD_CMD_WRITE_ZEROES) {
+            return Err(UserDataError { user_data, error });
+        }
+        let flags = if unmap { 0 } else { NBD_CMD_FLAG_NO_HOLE };
+        let command = Command::new(NBD_CMD_WRITE_ZEROES, offset, len);
+        self.push(command, flags, &[], user_data)
+    }
+
+    // Fails the in-flight commands, and stops using the connection.
+    fn disconnect(&mut self) {
+        if !self.connected {
+            return;
+        }
+        self.connected = false;
+        // The socket stays open until the engine is dropped, so that its file descriptor may be
+        // un-registered from the event loop in the meantime.
+        if let Err(e) = self.socket.shutdown(Shutdown::Both) {
+            error!("Failed to shut down the NBD connection: {:?}", e);
+        }
+        self.tx_buf.clear();
+        self.rx_buf.clear();
+        let in_flight: Vec<(Command, Option<T>)> =
+            self.in_flight.drain().map(|(_, entry)| entry).collect();
+        for (mut command, user_data) in in_flight {
+            command.error = Some(Error::Disconnected);
+            self.complete(command, user_data);
+        }
+    }
+
+    fn complete(&mut self, command: Command, user_data: Option<T>) {
+        match user_data {
+            Some(user_data) => self.completed.push_back((user_data, command)),
+            None => self.flush_result = Some(command.error.map_or(Ok(()), Err)),
+        }
+    }
+
+    // Sends the queued commands, until the socket is full.
+    fn send(&mut self) -> Result<(), Error> {
+        while !self.tx_buf.is_empty() {
+            // Safe because the buffer is valid for reads of its length, and we check the return
+            // value. A lost connection is reported as an error rather than by SIGPIPE.
+            let ret = unsafe {
+                libc::send(
+                    self.socket.as_raw_fd(),
+                    self.tx_buf.as_ptr() as *const libc::c_void,
+                    self.tx_buf.len(),
+                    libc::MSG_NOSIGNAL,
+                )
+            };
+            if ret >= 0 {
+                self.tx_buf.drain(..ret as usize);
+                continue;
+            }
+
+            let e = io::Error::last_os_error();
+            match e.kind() {
+                io::ErrorKind::Interrupted => continue,
+                io::ErrorKind::WouldBlock => break,
+                _ => return Err(Error::IO(e)),
+            }
+        }
+        Ok(())
+    }
+
+    // Receives data from the server, returning whether any was available.
+    fn recv(&mut self) -> Result<bool, Error> {
+        let len = self.rx_buf.len();
+        self.rx_buf.resize(len + RECV_LEN, 0);
+        loop {
+            // Safe because the buffer is valid for writes of `RECV_LEN` bytes past `len`, and we
+            // check the return value.
+            let ret = unsafe {
+                libc::recv(
+                    self.socket.as_raw_fd(),
+                    self.rx_buf[len..].as_mut_ptr() as *mut libc::c_void,
+                    RECV_LEN,
+                    0,
+                )
+            };
+            if ret >= 0 {
+                self.rx_buf.truncate(len + ret as usize);
+                return match ret {
+                    0 => Err(Error::Disconnected),
+                    _ => Ok(true),
+                };
+            }
+
+            let e = io::Error::last_os_error();
+            match e.kind() {
+                io::ErrorKind::Interrupted => continue,
+                io::ErrorKind::WouldBlock => {
+                    self.rx_buf.truncate(len);
+                    return Ok(false);
+                }
+                _ => {
+                    self.rx_buf.truncate(len);
+                    return Err(Error::IO(e));
+                }
+            }
+        }
+    }
+
+    /// Sends the queued commands and receives the replies sent by the server, without blocking.
+    ///
+    /// Upon error, the connection is closed and the in-flight commands fail.
+    pub fn process_io(&mut self) -> Result<(), Error> {
+        let mut res = self.send();
+        while self.connected && res.is_ok() {
+            match self.recv() {
+                Ok(true) => res = self.parse_replies(),
+                Ok(false) => break,
+                Err(e) => res = Err(e),
+            }
+        }
+        if res.is_err() {
+            self.disconnect();
+        }
+        res
+    }
+
+    // Waits for the socket to become ready for the pending transfers, until `deadline`.
+    fn poll(&self, deadline: Instant) -> Result<(), Error> {
+        let mut events = EventSet::IN;
+        if self.has_unsent_commands() {
+            events |= EventSet::OUT;
+        }
+        wait(&self.epoll, &self.socket, events, deadline)
+    }
+
+    // Consumes the whole replies received so far.
+    fn parse_replies(&mut self) -> Result<(), Error> {
+        let mut rx_buf = std::mem::take(&mut self.rx_buf);
+        let mut start = 0;
+        let res = loop {
+            match self.parse_reply(&rx_buf[start..]) {
+                Ok(Some(len)) => start += len,
+                Ok(None) => break Ok(()),
+                Err(e) => break Err(e),
+            }
+        };
+        rx_buf.drain(..start);
+        self.rx_buf = rx_buf;
+        res
+    }
+
+    // Consumes the reply at the start of `buf`, returning its length, if it was fully received.
+    fn parse_reply(&mut self, buf: &[u8]) -> Result<Option<usize>, Error> {
+        if buf.len() < 4 {
+            return Ok(None);
+        }
+        match be_u32(&buf[0..4]) {
+            NBD_SIMPLE_REPLY_MAGIC => self.parse_simple_reply(buf),
+            NBD_STRUCTURED_REPLY_MAGIC if self.structured_replies => {
+                self.parse_structured_reply(buf)
+            }
+            _ => Err(Error::Protocol("invalid reply magic")),
+        }
+    }
+
+    fn parse_simple_reply(&mut self, buf: &[u8]) -> Result<Option<usize>, Error> {
+        if buf.len() < SIMPLE_REPLY_LEN {
+            return Ok(None);
+        }
+        let errno = be_u32(&buf[4..8]);
+        let cookie = be_u64(&buf[8..16]);
+        let (command, _) = self
+            .in_flight
+            .get(&cookie)
+            .ok_or(Error::Protocol("reply to an unknown command"))?;
+        // Successful reads are followed by their data.
+        let data_len = match (command.r#type, errno) {
+            (NBD_CMD_READ, 0) => command.len as usize,
+            _ => 0,
+        };
+        if buf.len() < SIMPLE_REPLY_LEN + data_len {
+            return Ok(None);
+        }
+
+        // Safe to unwrap since the command was found above.
+        let (mut command, user_data) = self.in_flight.remove(&cookie).unwrap();
+        if errno != 0 {
+            command.error = Some(command_error(errno));
+        } else if let Some(data) = command.data.as_mut() {
+            data.as_mut_slice()
+                .copy_from_slice(&buf[SIMPLE_REPLY_LEN..SIMPLE_REPLY_LEN + data_len]);
+        }
+        self.complete(command, user_data);
+
+        Ok(Some(SIMPLE_REPLY_LEN + data_len))
+    }
+
+    fn parse_structured_reply(&mut self, buf: &[u8]) -> Result<Option<usize>, Error> {
+        if buf.len() < STRUCTURED_REPLY_HEADER_LEN {
+            return Ok(None);
+        }
+        let flags = be_u16(&buf[4..6]);
+        let reply_type = be_u16(&buf[6..8]);
+        let cookie = be_u64(&buf[8..16]);
+        let len = be_u32(&buf[16..20]) as usize;
+        let (command, _) = self
+            .in_flight
+            .get_mut(&cookie)
+            .ok_or(Error::Protocol("reply to an unknown command"))?;
+        // Don't wait for chunks longer than any valid one.
+        if len > std::cmp::max(command.len as usize + 8, MAX_ERROR_CHUNK_LEN) {
+            return Err(Error::Protocol("reply chunk too long"));
+        }
+        if buf.len() < STRUCTURED_REPLY_HEADER_LEN + len {
+            return Ok(None);
+        }
+
+        let payload = &buf[STRUCTURED_REPLY_HEADER_LEN..STRUCTURED_REPLY_HEADER_LEN + len];
+        match reply_type {
+            NBD_REPLY_TYPE_NONE if len == 0 && flags & NBD_REPLY_FLAG_DONE != 0 => {}
+            NBD_REPLY_TYPE_OFFSET_DATA if command.r#type == NBD_CMD_READ && len >= 8 => {
+                let range = command.chunk_range(be_u64(&payload[0..8]), len - 8)?;
+                if let Some(data) = command.data.as_mut() {
+                    data.as_mut_slice()[range].copy_from_slice(&payload[8..]);
+                }
+            }
+            NBD_REPLY_TYPE_OFFSET_HOLE if command.r#type == NBD_CMD_READ && len == 12 => {
+                let hole_len = be_u32(&payload[8..12]);
+                let range = command.chunk_range(be_u64(&payload[0..8]), hole_len as usize)?;
+                if let Some(data) = command.data.as_mut() {
+                    data.as_mut_slice()[range]
+                        .iter_mut()
+                        .for_each(|byte| *byte = 0);
+                }
+            }
+            reply_type if reply_type & NBD_REPLY_TYPE_ERROR_BIT != 0 && len >= 6 => {
+                // Only the first error of a command is reported.
+                if command.error.is_none() {
+                    command.error = Some(command_error(be_u32(&payload[0..4])));
+                }
+            }
+            _ => return Err(Error::Protocol("invalid reply chunk")),
+        }
+
+        if flags & NBD_REPLY_FLAG_DONE != 0 {
+            // Safe to unwrap since the command was found above.
+            let (mut command, user_data) = self.in_flight.remove(&cookie).unwrap();
+            command.check_chunks();
+            self.complete(command, user_data);
+        }
+
+        Ok(Some(STRUCTURED_REPLY_HEADER_LEN + len))
+    }
+
+    /// Pops a completed command, along with the number of bytes it transferred.
+    ///
+    /// The data of reads is copied to the guest memory at this point.
+    pub fn pop(&mut self, mem: &GuestMemoryMmap) -> Option<(T, Result<u32, Error>)> {
+        self.completed
+            .pop_front()
+            .map(|(user_data, command)| (user_data, command.finish(mem)))
+    }
+
+    /// Waits for the replies to all the in-flight commands.
+    ///
+    /// The connection is closed if the server takes too long to reply.
+    pub fn drain(&mut self, discard: bool) -> Result<(), Error> {
+        self.drain_until(Instant::now() + DRAIN_TIMEOUT, discard)
+    }
+
+    fn drain_until(&mut self, deadline: Instant, discard: bool) -> Result<(), Error> {
+        let mut res = Ok(());
+        while self.connected && !self.in_flight.is_empty() {
+            res = self.poll(deadline).and_then(|_| self.process_io());
+            if res.is_err() {
+                self.disconnect();
+            }
+        }
+        if discard {
+            self.completed.clear();
+        }
+        res
+    }
+
+    pub fn drain_and_flush(&mut self, discard: bool) -> Result<(), Error> {
+        self.drain(discard)?;
+        if !self.export.has_flag(NBD_FLAG_SEND_FLUSH) {
+            return Ok(());
+        }
+
+        let command = Command::new(NBD_CMD_FLUSH, 0, 0);
+        let cookie = self.queue(&command, 0, &[])?;
+        self.in_flight.insert(cookie, (command, None));
+        self.flush_result = None;
+        self.drain(false)?;
+        self.flush_result.take().unwrap_or(Err(Error::Disconnected))
+    }
+}
+
+impl<T> Drop for NbdEngine<T> {
+    fn drop(&mut self) {
+        if self.connected {
+            // Let the server know that the client goes away, unless the socket is full.
+            let res = self
+                .queue(&Command::new(NBD_CMD_DISC, 0, 0), 0, &[])
+                .and_then(|_| self.send());
+            if let Err(e) = res {
+                error!("Failed to disconnect from the NBD server: {:?}", e);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::os::unix::net::UnixListener;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::mpsc::channel;
+    use std::sync::{Arc, Mutex};
+    use std::thread::{self, JoinHandle};
+    use std::time::Duration;
+
+    use super::*;
+    use crate::virtio::block::SECTOR_SIZE;
+    use utils::tempfile::TempFile;
+    use vm_memory::{Bytes, GuestAddress};
+
+    const NBD_REP_ERR_UNSUP: u32 = NBD_REP_FLAG_ERROR | 1;
+    const NBD_REPLY_TYPE_ERROR: u16 = NBD_REPLY_TYPE_ERROR_BIT | 1;
+
+    const DISK_LEN: usize = 0x4000;
+    const MEM_LEN: usize = 0x8000;
+    const ALL_FLAGS: u16 = NBD_FLAG_HAS_FLAGS
+        | NBD_FLAG_SEND_FLUSH
+        | NBD_FLAG_SEND_TRIM
+        | NBD_FLAG_SEND_WRITE_ZEROES
+        | NBD_FLAG_CAN_MULTI_CONN;
+
+    #[derive(Clone, Copy)]
+    struct ExportConfig {
+        flags: u16,
+        structured_replies: bool,
+    }
+
+    struct MockRequest {
+        r#type: u16,
+        cookie: u64,
+        offset: u64,
+        len: u32,
+    }
+
+    /// An NBD server exporting an in-memory disk on a Unix socket, with a thread per connection.
+    pub(crate) struct MockNbdServer {
+        path: String,
+        disk: Arc<Mutex<Vec<u8>>>,
+        stop: Arc<AtomicBool>,
+        handle: Option<JoinHandle<()>>,
+    }
+
+    impl MockNbdServer {
+        pub fn new(disk: Vec<u8>, flags: u16, structured_replies: bool) -> Self {
+            let path = socket_path();
+            let listener = UnixListener::bind(&path).unwrap();
+            listener.set_nonblocking(true).unwrap();
+            let config = ExportConfig {
+                flags,
+                structured_replies,
+            };
+            let disk = Arc::new(Mutex::new(disk));
+            let stop = Arc::new(AtomicBool::new(false));
+
+            let handle = {
+                let disk = disk.clone();
+                let stop = stop.clone();
+                thread::spawn(move || {
+                    while !stop.load(Ordering::SeqCst) {
+                        match listener.accept() {
+                            Ok((stream, _)) => {
+                                let disk = disk.clone();
+                                thread::spawn(move || serve(stream, &disk, config));
+                            }
+                            Err(_) => thread::sleep(Duration::from_millis(10)),
+                        }
+                    }
+                })
+            };
+
+            MockNbdServer {
+                path,
+                disk,
+                stop,
+                handle: Some(handle),
+            }
+        }
+
+        pub fn path(&self) -> &str {
+            &self.path
+        }
+
+        pub fn disk(&self) -> Vec<u8> {
+            self.disk.lock().unwrap().clone()
+        }
+    }
+
+    impl Drop for MockNbdServer {
+        fn drop(&mut self) {
+            self.stop.store(true, Ordering::SeqCst);
+            if let Some(handle) = self.handle.take() {
+                handle.join().unwrap();
+            }
+            std::fs::remove_file(&self.path).unwrap();
+        }
+    }
+
+    fn socket_path() -> String {
+        TempFile::new()
+            .unwrap()
+            .as_path()
+            .to_str()
+            .unwrap()
+            .to_string()
+    }
+
+    fn serve(mut stream: UnixStream, disk: &Mutex<Vec<u8>>, config: ExportConfig) {
+        let size = disk.lock().unwrap().len() as u64;
+        if serve_handshake(&mut stream, size, config).is_ok() {
+            // The client may go away at any point.
+            let _ = serve_commands(&mut stream, disk, config);
+        }
+    }
+
+    fn send_option_reply(
+        stream: &mut UnixStream,
+        option: u32,
+        reply: u32,
+        data: &[u8],
+    ) -> io::Result<()> {
+        let mut msg = Vec::new();
+        msg.extend_from_slice(&NBD_REP_MAGIC.to_be_bytes());
+        msg.extend_from_slice(&option.to_be_bytes());
+        msg.extend_from_slice(&reply.to_be_bytes());
+        msg.extend_from_slice(&(data.len() as u32).to_be_bytes());
+        msg.extend_from_slice(data);
+        stream.write_all(&msg)
+    }
+
+    fn serve_handshake(stream: &mut UnixStream, size: u64, config: ExportConfig) -> io::Result<()> {
+        let mut greeting = Vec::new();
+        greeting.extend_from_slice(&NBD_MAGIC.to_be_bytes());
+        greeting.extend_from_slice(&NBD_OPTS_MAGIC.to_be_bytes());
+        greeting.extend_from_slice(&(NBD_FLAG_FIXED_NEWSTYLE | NBD_FLAG_NO_ZEROES).to_be_bytes());
+        stream.write_all(&greeting)?;
+        let mut client_flags = [0u8; 4];
+        stream.read_exact(&mut client_flags)?;
+        assert_eq!(
+            be_u32(&client_flags),
+            NBD_FLAG_C_FIXED_NEWSTYLE | NBD_FLAG_C_NO_ZEROES
+        );
+
+        loop {
+            let mut header = [0u8; 16];
+            stream.read_exact(&mut header)?;
+            assert_eq!(be_u64(&header[0..8]), NBD_OPTS_MAGIC);
+            let option = be_u32(&header[8..12]);
+            let mut data = vec![0u8; be_u32(&header[12..16]) as usize];
+            stream.read_exact(&mut data)?;
+
+            match option {
+                NBD_OPT_STRUCTURED_REPLY if config.structured_replies => {
+                    send_option_reply(stream, option, NBD_REP_ACK, &[])?
+                }
+                NBD_OPT_GO => {
+                    let mut info = Vec::new();
+                    info.extend_from_slice(&NBD_INFO_EXPORT.to_be_bytes());
+                    info.extend_from_slice(&size.to_be_bytes());
+                    info.extend_from_slice(&config.flags.to_be_bytes());
+                    send_option_reply(stream, option, NBD_REP_INFO, &info)?;
+                    return send_option_reply(stream, option, NBD_REP_ACK, &[]);
+                }
+                _ => send_option_reply(stream, option, NBD_REP_ERR_UNSUP, &[])?,
+            }
+        }
+    }
+
+    fn read_request(stream: &mut UnixStream) -> io::Result<MockRequest> {
+        let mut header = [0u8; REQUEST_LEN];
+        stream.read_exact(&mut header)?;
+        assert_eq!(be_u32(&header[0..4]), NBD_REQUEST_MAGIC);
+        Ok(MockRequest {
+            r#type: be_u16(&header[6..8]),
+            cookie: be_u64(&header[8..16]),
+            offset: be_u64(&header[16..24]),
+            len: be_u32(&header[24..28]),
+        })
+    }
+
+    fn send_simple_reply(
+        stream: &mut UnixStream,
+        cookie: u64,
+        errno: u32,
+        data: &[u8],
+    ) -> io::Result<()> {
+        let mut msg = Vec::new();
+        msg.extend_from_slice(&NBD_SIMPLE_REPLY_MAGIC.to_be_bytes());
+        msg.extend_from_slice(&errno.to_be_bytes());
+        msg.extend_from_slice(&cookie.to_be_bytes());
+        msg.extend_from_slice(data);
+        stream.write_all(&msg)
+    }
+
+    fn send_chunk(
+        stream: &mut UnixStream,
+        cookie: u64,
+        flags: u16,
+        reply_type: u16,
+        payload: &[u8],
+    ) -> io::Result<()> {
+        let mut msg = Vec::new();
+        msg.extend_from_slice(&NBD_STRUCTURED_REPLY_MAGIC.to_be_bytes());
+        msg.extend_from_slice(&flags.to_be_bytes());
+        msg.extend_from_slice(&reply_type.to_be_bytes());
+        msg.extend_from_slice(&cookie.to_be_bytes());
+        msg.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+        msg.extend_from_slice(payload);
+        stream.write_all(&msg)
+    }
+
+    // Sends the data of a read as one chunk per sector, in reverse order, with the zeroed sectors
+    // sent as holes.
+    fn send_read_chunks(
+        stream: &mut UnixStream,
+        cookie: u64,
+        offset: u64,
+        data: &[u8],
+    ) -> io::Result<()> {
+        for (index, sector) in data.chunks(SECTOR_SIZE as usize).enumerate().rev() {
+            let mut payload = (offset + index as u64 * SECTOR_SIZE).to_be_bytes().to_vec();
+            if sector.iter().all(|&byte| byte == 0) {
+                payload.extend_from_slice(&(sector.len() as u32).to_be_bytes());
+                send_chunk(stream, cookie, 0, NBD_REPLY_TYPE_OFFSET_HOLE, &payload)?;
+            } else {
+                payload.extend_from_slice(sector);
+                send_chunk(stream, cookie, 0, NBD_REPLY_TYPE_OFFSET_DATA, &payload)?;
+            }
+        }
+        send_chunk(
+            stream,
+            cookie,
+            NBD_REPLY_FLAG_DONE,
+            NBD_REPLY_TYPE_NONE,
+            &[],
+        )
+    }
+
+    fn serve_commands(
+        stream: &mut UnixStream,
+        disk: &Mutex<Vec<u8>>,
+        config: ExportConfig,
+    ) -> io::Result<()> {
+        loop {
+            let request = read_request(stream)?;
+            let mut payload = Vec::new();
+            if request.r#type == NBD_CMD_WRITE {
+                payload.resize(request.len as usize, 0);
+                stream.read_exact(&mut payload)?;
+            }
+
+            let mut disk = disk.lock().unwrap();
+            let range = request.offset as usize..(request.offset + u64::from(request.len)) as usize;
+            if range.end > disk.len() {
+                let errno = libc::EINVAL as u32;
+                if request.r#type == NBD_CMD_READ && config.structured_replies {
+                    let mut error = errno.to_be_bytes().to_vec();
+                    error.extend_from_slice(&0u16.to_be_bytes());
+                    let flags = NBD_REPLY_FLAG_DONE;
+                    send_chunk(stream, request.cookie, flags, NBD_REPLY_TYPE_ERROR, &error)?;
+                } else {
+                    send_simple_reply(stream, request.cookie, errno, &[])?;
+                }
+                continue;
+            }
+
+            match request.r#type {
+                NBD_CMD_READ if config.structured_replies => {
+                    send_read_chunks(stream, request.cookie, request.offset, &disk[range])?
+                }
+                NBD_CMD_READ => send_simple_reply(stream, request.cookie, 0, &disk[range])?,
+                NBD_CMD_WRITE => {
+                    disk[range].copy_from_slice(&payload);
+                    send_simple_reply(stream, request.cookie, 0, &[])?
+                }
+                NBD_CMD_TRIM | NBD_CMD_WRITE_ZEROES => {
+                    disk[range].iter_mut().for_each(|byte| *byte = 0);
+                    send_simple_reply(stream, request.cookie, 0, &[])?
+                }
+                NBD_CMD_FLUSH => send_simple_reply(stream, request.cookie, 0, &[])?,
+                NBD_CMD_DISC => return Ok(()),
+                _ => send_simple_reply(stream, request.cookie, libc::EINVAL as u32, &[])?,
+            }
+        }
+    }
+
+    fn create_mem() -> GuestMemoryMmap {
+        vm_memory::test_utils::create_anon_guest_memory(&[(GuestAddress(0), MEM_LEN)], true)
+            .unwrap()
+    }
+
+    // Waits for the completion of the single in-flight command.
+    fn complete(engine: &mut NbdEngine<u32>, mem: &GuestMemoryMmap) -> (u32, Result<u32, Error>) {
+        engine.drain(false).unwrap();
+        engine.pop(mem).unwrap()
+    }
+
+    #[test]
+    fn test_handshake() {
+        for &structured_replies in &[true, false] {
+            let server = MockNbdServer::new(vec![0; DISK_LEN], ALL_FLAGS, structured_replies);
+            let engine = NbdEngine::<()>::connect(server.path()).unwrap();
+            assert_eq!(
+                engine.export(),
+                NbdExport {
+                    size: DISK_LEN as u64,
+                    flags: ALL_FLAGS
+                }
+            );
+            assert!(engine.export().has_flag(NBD_FLAG_SEND_TRIM));
+            assert!(!engine.export().has_flag(NBD_FLAG_READ_ONLY));
+            assert_eq!(engine.structured_replies, structured_replies);
+            assert!(engine.completion_fd().is_some());
+        }
+
+        // No server listens on the socket.
+        assert!(matches!(
+            NbdEngine::<()>::connect(&socket_path()),
+            Err(Error::IO(_))
+        ));
+
+        // The server doesn't speak NBD.
+        let path = socket_path();
+        let listener = UnixListener::bind(&path).unwrap();
+        let server = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            stream.write_all(&[0u8; 18]).unwrap();
+        });
+        assert!(matches!(
+            NbdEngine::<()>::connect(&path),
+            Err(Error::Protocol(_))
+        ));
+        server.join().unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        // The server never greets the client.
+        let path = socket_path();
+        let listener = UnixListener::bind(&path).unwrap();
+        let deadline = Instant::now() + Duration::from_millis(100);
+        assert!(matches!(
+            NbdEngine::<()>::connect_until(&path, deadline),
+            Err(Error::Timeout)
+        ));
+        drop(listener);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_commands() {
+        for &structured_replies in &[true, false] {
+            let mut disk = vec![0u8; DISK_LEN];
+            for (index, byte) in disk[..0x1000].iter_mut().enumerate() {
+                *byte = (index % 255) as u8 + 1;
+            }
+            let server = MockNbdServer::new(disk.clone(), ALL_FLAGS, structured_replies);
+            let mut engine = NbdEngine::connect(server.path()).unwrap();
+            let mem = create_mem();
+
+            // Read some data followed by a hole, across two segments.
+            mem.write_slice(&[0xff; 0x1000], GuestAddress(0x2000))
+                .unwrap();
+            let segments = [
+                IoSegment::new(GuestAddress(0), 0x800),
+                IoSegment::new(GuestAddress(0x2000), 0x1000),
+            ];
+            engine.push_read(0x800, &segments, 1).unwrap();
+            let (user_data, res) = complete(&mut engine, &mem);
+            assert_eq!((user_data, res.unwrap()), (1, 0x1800));
+            let mut buf = vec![0u8; 0x1800];
+            mem.read_slice(&mut buf[..0x800], GuestAddress(0)).unwrap();
+            mem.read_slice(&mut buf[0x800..], GuestAddress(0x2000))
+                .unwrap();
+            assert_eq!(buf, disk[0x800..0x2000]);
+
+            // Write to the hole.
+            mem.write_slice(&[0xaa; 0x200], GuestAddress(0x4000))
+                .unwrap();
+            let segments = [IoSegment::new(GuestAddress(0x4000), 0x200)];
+            engine.push_write(0x3000, &mem, &segments, 2).unwrap();
+            let (user_data, res) = complete(&mut engine, &mem);
+            assert_eq!((user_data, res.unwrap()), (2, 0x200));
+            disk[0x3000..0x3200]
+                .iter_mut()
+                .for_each(|byte| *byte = 0xaa);
+            assert_eq!(server.disk(), disk);
+
+            // Trim and zero the first sectors.
+            engine.push_trim(0, 0x200, 3).unwrap();
+            let (user_data, res) = complete(&mut engine, &mem);
+            assert_eq!((user_data, res.unwrap()), (3, 0));
+            engine.push_write_zeroes(0x200, 0x200, false, 4).unwrap();
+            let (user_data, res) = complete(&mut engine, &mem);
+            assert_eq!((user_data, res.unwrap()), (4, 0));
+            disk[..0x400].iter_mut().for_each(|byte| *byte = 0);
+            assert_eq!(server.disk(), disk);
+
+            // Several commands may be in flight.
+            engine.push_flush(5).unwrap();
+            engine.push_read(0x3000, &segments, 6).unwrap();
+            engine.drain(false).unwrap();
+            assert_eq!(engine.pop(&mem).unwrap().0, 5);
+            assert_eq!(engine.pop(&mem).unwrap().0, 6);
+            assert!(engine.pop(&mem).is_none());
+
+            engine.drain_and_flush(false).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_errors() {
+        for &structured_replies in &[true, false] {
+            let server =
+                MockNbdServer::new(vec![0; DISK_LEN], NBD_FLAG_HAS_FLAGS, structured_replies);
+            let mut engine = NbdEngine::connect(server.path()).unwrap();
+            let mem = create_mem();
+            let segments = [IoSegment::new(GuestAddress(0), 0x200)];
+
+            // The server fails reads beyond the end of the export.
+            engine.push_read(DISK_LEN as u64, &segments, 1).unwrap();
+            match complete(&mut engine, &mem) {
+                (1, Err(Error::Command(e))) => assert_eq!(e.raw_os_error(), Some(libc::EINVAL)),
+                _ => panic!("Unexpected completion"),
+            }
+
+            // The commands not supported by the export aren't sent.
+            assert!(matches!(
+                engine.push_flush(2),
+                Err(UserDataError {
+                    user_data: 2,
+                    error: Error::UnsupportedCommand(NBD_CMD_FLUSH)
+                })
+            ));
+            assert!(matches!(
+                engine.push_trim(0, 0x200, 3),
+                Err(UserDataError {
+                    user_data: 3,
+                    error: Error::UnsupportedCommand(NBD_CMD_TRIM)
+                })
+            ));
+            assert!(matches!(
+                engine.push_write_zeroes(0, 0x200, true, 4),
+                Err(UserDataError {
+                    user_data: 4,
+                    error: Error::UnsupportedCommand(NBD_CMD_WRITE_ZEROES)
+                })
+            ));
+
+            // The connection is still usable.
+            engine.push_read(0, &segments, 5).unwrap();
+            let (user_data, res) = complete(&mut engine, &mem);
+            assert_eq!((user_data, res.unwrap()), (5, 0x200));
+            // Flushing the export is skipped.
+            engine.drain_and_flush(false).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_disconnect() {
+        let path = socket_path();
+        let listener = UnixListener::bind(&path).unwrap();
+        let server = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let config = ExportConfig {
+                flags: ALL_FLAGS,
+                structured_replies: true,
+            };
+            serve_handshake(&mut stream, DISK_LEN as u64, config).unwrap();
+            // Go away without replying to the first command.
+            read_request(&mut stream).unwrap();
+        });
+        let mut engine = NbdEngine::connect(&path).unwrap();
+        let mem = create_mem();
+        let segments = [IoSegment::new(GuestAddress(0), 0x200)];
+
+        engine.push_read(0, &segments, 1).unwrap();
+        server.join().unwrap();
+        // The in-flight command fails once the connection is lost.
+        assert!(matches!(engine.process_io(), Err(Error::Disconnected)));
+        assert!(matches!(
+            engine.pop(&mem),
+            Some((1, Err(Error::Disconnected)))
+        ));
+        assert!(engine.completion_fd().is_none());
+
+        // And so do the next ones.
+        assert!(matches!(
+            engine.push_read(0, &segments, 2),
+            Err(UserDataError {
+                user_data: 2,
+                error: Error::Disconnected
+            })
+        ));
+        assert!(matches!(
+            engine.drain_and_flush(true),
+            Err(Error::Disconnected)
+        ));
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_incomplete_read() {
+        let path = socket_path();
+        let listener = UnixListener::bind(&path).unwrap();
+        let server = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let config = ExportConfig {
+                flags: ALL_FLAGS,
+                structured_replies: true,
+            };
+            serve_handshake(&mut stream, DISK_LEN as u64, config).unwrap();
+            // Only send the second sector of the first read.
+            let request = read_request(&mut stream).unwrap();
+            let mut payload = (request.offset + SECTOR_SIZE).to_be_bytes().to_vec();
+            payload.extend_from_slice(&[0xaa; SECTOR_SIZE as usize]);
+            let (flags, reply_type) = (NBD_REPLY_FLAG_DONE, NBD_REPLY_TYPE_OFFSET_DATA);
+            send_chunk(&mut stream, request.cookie, flags, reply_type, &payload).unwrap();
+            let request = read_request(&mut stream).unwrap();
+            let data = [0xbb; 2 * SECTOR_SIZE as usize];
+            send_read_chunks(&mut stream, request.cookie, request.offset, &data).unwrap();
+            assert_eq!(read_request(&mut stream).unwrap().r#type, NBD_CMD_DISC);
+        });
+        let mut engine = NbdEngine::connect(&path).unwrap();
+        let mem = create_mem();
+        let segments = [IoSegment::new(GuestAddress(0), 2 * SECTOR_SIZE as u32)];
+
+        // A read whose reply leaves a gap fails.
+        engine.push_read(0, &segments, 1).unwrap();
+        assert!(matches!(
+            complete(&mut engine, &mem),
+            (1, Err(Error::Protocol(_)))
+        ));
+        // The connection is still usable.
+        engine.push_read(0, &segments, 2).unwrap();
+        let (user_data, res) = complete(&mut engine, &mem);
+        assert_eq!((user_data, res.unwrap()), (2, 2 * SECTOR_SIZE as u32));
+
+        drop(engine);
+        server.join().unwrap();
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_drain_timeout() {
+        let path = socket_path();
+        let listener = UnixListener::bind(&path).unwrap();
+        let (stop, stopped) = channel();
+        let server = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let config = ExportConfig {
+                flags: ALL_FLAGS,
+                structured_replies: true,
+            };
+            serve_handshake(&mut stream, DISK_LEN as u64, config).unwrap();
+            // Never reply to the command.
+            read_request(&mut stream).unwrap();
+            stopped.recv().unwrap();
+        });
+        let mut engine = NbdEngine::connect(&path).unwrap();
+        let mem = create_mem();
+
+        engine.push_flush(1).unwrap();
+        let deadline = Instant::now() + Duration::from_millis(100);
+        assert!(matches!(
+            engine.drain_until(deadline, false),
+            Err(Error::Timeout)
+        ));
+        // The command fails along with the connection.
+        assert!(matches!(
+            engine.pop(&mem),
+            Some((1, Err(Error::Disconnected)))
+        ));
+        assert!(engine.completion_fd().is_none());
+
+        stop.send(()).unwrap();
+        server.join().unwrap();
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_backpressure() {
+        let path = socket_path();
+        let listener = UnixListener::bind(&path).unwrap();
+        let (resume, resumed) = channel();
+        let server = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let config = ExportConfig {
+                flags: ALL_FLAGS,
+                structured_replies: true,
+            };
+            serve_handshake(&mut stream, DISK_LEN as u64, config).unwrap();
+            // Stop reading the commands for a while.
+            resumed.recv().unwrap();
+            let disk = Mutex::new(vec![0u8; DISK_LEN]);
+            serve_commands(&mut stream, &disk, config).unwrap();
+        });
+        let mut engine = NbdEngine::connect(&path).unwrap();
+        let mem = create_mem();
+        let segments = [IoSegment::new(GuestAddress(0), 0x2000)];
+
+        // The commands which don't fit in the socket are queued, rather than blocking.
+        for user_data in 0..MAX_IN_FLIGHT {
+            engine
+                .push_write(0, &mem, &segments, user_data as u32)
+                .unwrap();
+        }
+        assert!(engine.has_unsent_commands());
+        // Past the limit of in-flight commands, submissions are throttled.
+        assert!(matches!(
+            engine.push_flush(MAX_IN_FLIGHT as u32),
+            Err(UserDataError {
+                user_data,
+                error: Error::FullQueue
+            }) if user_data == MAX_IN_FLIGHT as u32
+        ));
+
+        resume.send(()).unwrap();
+        engine.drain(false).unwrap();
+        assert!(!engine.has_unsent_commands());
+        for user_data in 0..MAX_IN_FLIGHT {
+            let (completed, res) = engine.pop(&mem).unwrap();
+            assert_eq!((completed, res.unwrap()), (user_data as u32, 0x2000));
+        }
+        assert!(engine.pop(&mem).is_none());
+
+        drop(engine);
+        server.join().unwrap();
+        std::fs::remove_file(&path).unwrap();
+    }
+}
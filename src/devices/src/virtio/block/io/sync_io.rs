@@ -1,14 +1,19 @@
 // Copyright 2021 Amazon.com, Inc. or its affiliates. All Rights Reserved.
 // SPDX-License-Identifier: Apache-2.0
 
-use std::io::{Seek, SeekFrom, Write};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::os::unix::io::AsRawFd;
 use std::result::Result;
 
 use std::fs::File;
-use vm_memory::{Bytes, GuestAddress, GuestMemoryError, GuestMemoryMmap};
+use vm_memory::{Bytes, GuestMemoryError, GuestMemoryMmap};
+
+use crate::virtio::block::io::bounce_buffer::{self, BounceBuffer};
+use crate::virtio::block::io::IoSegment;
 
 #[derive(Debug)]
 pub enum Error {
+    Fallocate(std::io::Error),
     Flush(std::io::Error),
     Seek(std::io::Error),
     SyncAll(std::io::Error),
@@ -17,13 +22,20 @@ pub enum Error {
 
 pub struct SyncFileEngine {
     file: File,
+    // Whether the file was opened with `O_DIRECT`.
+    direct_io: bool,
 }
 
 unsafe impl Send for SyncFileEngine {}
 
 impl SyncFileEngine {
-    pub fn from_file(file: File) -> SyncFileEngine {
-        SyncFileEngine { file }
+    pub fn from_file(file: File, direct_io: bool) -> SyncFileEngine {
+        SyncFileEngine { file, direct_io }
+    }
+
+    // Returns whether the segments have to go through a bounce buffer.
+    fn needs_bounce(&self, segments: &[IoSegment]) -> bool {
+        self.direct_io && !bounce_buffer::is_aligned(segments)
     }
 
     #[cfg(test)]
@@ -35,30 +47,122 @@ impl SyncFileEngine {
         &mut self,
         offset: u64,
         mem: &GuestMemoryMmap,
-        addr: GuestAddress,
-        count: u32,
+        segments: &[IoSegment],
     ) -> Result<u32, Error> {
+        if self.needs_bounce(segments) {
+            return self.read_bounced(offset, mem, segments);
+        }
+
         self.file
             .seek(SeekFrom::Start(offset))
             .map_err(Error::Seek)?;
-        mem.read_from(addr, &mut self.file, count as usize)
-            .map(|count| count as u32)
-            .map_err(Error::Transfer)
+
+        let mut count = 0;
+        for segment in segments {
+            let segment_count = mem
+                .read_from(segment.addr, &mut self.file, segment.len as usize)
+                .map_err(Error::Transfer)? as u32;
+            count += segment_count;
+            // Stop at the first partial transfer, the data can't have gaps.
+            if segment_count < segment.len {
+                break;
+            }
+        }
+        Ok(count)
     }
 
     pub fn write(
         &mut self,
         offset: u64,
         mem: &GuestMemoryMmap,
-        addr: GuestAddress,
-        count: u32,
+        segments: &[IoSegment],
+    ) -> Result<u32, Error> {
+        if self.needs_bounce(segments) {
+            return self.write_bounced(offset, mem, segments);
+        }
+
+        self.file
+            .seek(SeekFrom::Start(offset))
+            .map_err(Error::Seek)?;
+
+        let mut count = 0;
+        for segment in segments {
+            let segment_count = mem
+                .write_to(segment.addr, &mut self.file, segment.len as usize)
+                .map_err(Error::Transfer)? as u32;
+            count += segment_count;
+            // Stop at the first partial transfer, the data can't have gaps.
+            if segment_count < segment.len {
+                break;
+            }
+        }
+        Ok(count)
+    }
+
+    fn read_bounced(
+        &mut self,
+        offset: u64,
+        mem: &GuestMemoryMmap,
+        segments: &[IoSegment],
     ) -> Result<u32, Error> {
+        let mut buffer = BounceBuffer::new(bounce_buffer::segments_len(segments));
         self.file
             .seek(SeekFrom::Start(offset))
             .map_err(Error::Seek)?;
-        mem.write_to(addr, &mut self.file, count as usize)
-            .map(|count| count as u32)
-            .map_err(Error::Transfer)
+
+        let mut count = 0;
+        let buf = buffer.as_mut_slice();
+        while count < buf.len() {
+            match self.file.read(&mut buf[count..]) {
+                // End of file.
+                Ok(0) => break,
+                Ok(n) => count += n,
+                Err(e) if e.kind() == std::io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(Error::Transfer(GuestMemoryError::IOError(e))),
+            }
+        }
+
+        buffer
+            .copy_to_segments(mem, segments, count)
+            .map_err(Error::Transfer)?;
+        Ok(count as u32)
+    }
+
+    fn write_bounced(
+        &mut self,
+        offset: u64,
+        mem: &GuestMemoryMmap,
+        segments: &[IoSegment],
+    ) -> Result<u32, Error> {
+        let mut buffer = BounceBuffer::new(bounce_buffer::segments_len(segments));
+        buffer
+            .copy_from_segments(mem, segments)
+            .map_err(Error::Transfer)?;
+
+        self.file
+            .seek(SeekFrom::Start(offset))
+            .map_err(Error::Seek)?;
+        self.file
+            .write_all(buffer.as_slice())
+            .map_err(|e| Error::Transfer(GuestMemoryError::IOError(e)))?;
+        Ok(buffer.as_slice().len() as u32)
+    }
+
+    /// Manipulates the allocated space of the file, as described by `fallocate(2)`.
+    pub fn fallocate(&mut self, mode: i32, offset: u64, len: u64) -> Result<(), Error> {
+        // Safe because the file descriptor is valid and we check the return value.
+        let ret = unsafe {
+            libc::fallocate(
+                self.file.as_raw_fd(),
+                mode,
+                offset as libc::off_t,
+                len as libc::off_t,
+            )
+        };
+        if ret != 0 {
+            return Err(Error::Fallocate(std::io::Error::last_os_error()));
+        }
+        Ok(())
     }
 
     pub fn flush(&mut self) -> Result<(), Error> {
@@ -0,0 +1,323 @@
+// Copyright 2022 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Processing of the block device queues on dedicated IO threads.
+
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+
+use event_manager::{EventManager, EventOps, Events, MutEventSubscriber, SubscriberOps};
+use logger::{error, warn, IncMetric, METRICS};
+use rate_limiter::RateLimiter;
+use utils::epoll::EventSet;
+use utils::eventfd::EventFd;
+use vm_memory::GuestMemoryMmap;
+
+use super::device::{DiskProperties, QueueHandler};
+use crate::virtio::{IrqTrigger, Queue};
+
+/// Requests sent by the device to one of its IO threads.
+pub(crate) enum Command {
+    /// Completes the in-flight requests and sends back the state of the queue.
+    Save(Sender<Queue>),
+    /// Switches over to the handler of an updated disk image.
+    UpdateDisk(DiskProperties, QueueHandler),
+    /// Exits the thread, once the in-flight requests complete.
+    Stop,
+}
+
+/// A queue processed on an IO thread, along with everything needed to process it.
+struct QueueWorker {
+    queue: Queue,
+    queue_evt: EventFd,
+    handler: QueueHandler,
+    // The completion event of the handler registered with the event loop.
+    completion_event: Option<(RawFd, EventSet)>,
+    disk: DiskProperties,
+    mem: GuestMemoryMmap,
+    irq_trigger: IrqTrigger,
+    // IO threads aren't used along with rate limiting, so this rate limiter never throttles.
+    rate_limiter: RateLimiter,
+    commands: Receiver<Command>,
+    command_evt: EventFd,
+    stopped: bool,
+}
+
+impl QueueWorker {
+    fn run(self) {
+        let mut event_manager: EventManager<Arc<Mutex<QueueWorker>>> = match EventManager::new() {
+            Ok(event_manager) => event_manager,
+            Err(e) => {
+                error!(
+                    "Failed to create the event manager of a block IO thread: {:?}",
+                    e
+                );
+                return;
+            }
+        };
+        let worker = Arc::new(Mutex::new(self));
+        event_manager.add_subscriber(worker.clone());
+
+        while !worker.lock().expect("Poisoned lock").stopped {
+            if let Err(e) = event_manager.run() {
+                error!(
+                    "Failed to run the event manager of a block IO thread: {:?}",
+                    e
+                );
+                break;
+            }
+        }
+    }
+
+    fn process_queue_event(&mut self) {
+        METRICS.block.queue_event_count.inc();
+        if let Err(e) = self.queue_evt.read() {
+            error!("Failed to get queue event: {:?}", e);
+            METRICS.block.event_fails.inc();
+        } else if self.handler.is_io_engine_throttled {
+            METRICS.block.io_engine_throttled_events.inc();
+        } else {
+            self.handler.process_queue(
+                &mut self.queue,
+                &self.disk,
+                &self.mem,
+                &self.irq_trigger,
+                &mut self.rate_limiter,
+            );
+        }
+    }
+
+    fn process_commands(&mut self, ops: &mut EventOps) {
+        if let Err(e) = self.command_evt.read() {
+            error!("Failed to get block IO thread command event: {:?}", e);
+        }
+
+        while let Ok(command) = self.commands.try_recv() {
+            match command {
+                Command::Save(sender) => {
+                    self.handler
+                        .prepare_save(&mut self.queue, &self.mem, &self.irq_trigger);
+                    if sender.send(self.queue.clone()).is_err() {
+                        error!("Failed to send the state of a block queue");
+                    }
+                }
+                Command::UpdateDisk(disk, handler) => {
+                    // The new handler brings its own IO engine, and so its own completion event.
+                    let old_handler = std::mem::replace(&mut self.handler, handler);
+                    self.disk = disk;
+                    self.update_completion_event(ops);
+                    drop(old_handler);
+                }
+                Command::Stop => self.stopped = true,
+            }
+        }
+    }
+
+    // Registers the completion event of the handler, in place of the one registered previously,
+    // whose file descriptor must still be open.
+    fn update_completion_event(&mut self, ops: &mut EventOps) {
+        let completion_event = self.handler.completion_event();
+        if completion_event == self.completion_event {
+            return;
+        }
+        if let Some((fd, event_set)) = self.completion_event.take() {
+            if let Err(e) = ops.remove(Events::new_raw(fd, event_set)) {
+                error!("Failed to un-register IO engine completion event: {}", e);
+            }
+        }
+        if let Some((fd, event_set)) = completion_event {
+            if let Err(e) = ops.add(Events::new_raw(fd, event_set)) {
+                error!("Failed to register IO engine completion event: {}", e);
+            }
+        }
+        self.completion_event = completion_event;
+    }
+}
+
+impl MutEventSubscriber for QueueWorker {
+    fn process(&mut self, event: Events, ops: &mut EventOps) {
+        let source = event.fd();
+        let event_set = event.event_set();
+
+        // NBD connections hang up when the server goes away, and become writable once the server
+        // makes room for the commands waiting to be sent.
+        let supported_events = EventSet::IN | EventSet::OUT | EventSet::HANG_UP | EventSet::ERROR;
+        if !supported_events.contains(event_set) {
+            warn!(
+                "Block IO thread: Received unknown event: {:?} from source: {:?}",
+                event_set, source
+            );
+            return;
+        }
+
+        match source {
+            _ if self.queue_evt.as_raw_fd() == source => self.process_queue_event(),
+            _ if self.command_evt.as_raw_fd() == source => self.process_commands(ops),
+            _ if self.completion_event.map(|(fd, _)| fd) == Some(source) => {
+                self.handler.process_async_completion_event(
+                    &mut self.queue,
+                    &self.disk,
+                    &self.mem,
+                    &self.irq_trigger,
+                    &mut self.rate_limiter,
+                )
+            }
+            _ => warn!("Block IO thread: Spurious event received: {:?}", source),
+        }
+        // A lost NBD connection stops signaling completions.
+        self.update_completion_event(ops);
+    }
+
+    fn init(&mut self, ops: &mut EventOps) {
+        if let Err(e) = ops.add(Events::new(&self.queue_evt, EventSet::IN)) {
+            error!("Failed to register queue event: {}", e);
+        }
+        if let Err(e) = ops.add(Events::new(&self.command_evt, EventSet::IN)) {
+            error!("Failed to register block IO thread command event: {}", e);
+        }
+        self.update_completion_event(ops);
+    }
+}
+
+/// Handle through which the device drives the IO thread of one of its queues.
+pub(crate) struct IoThread {
+    index: usize,
+    commands: Sender<Command>,
+    command_evt: EventFd,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl IoThread {
+    /// Starts processing the queue at `index` on a new thread.
+    pub fn spawn(
+        index: usize,
+        queue: Queue,
+        queue_evt: &EventFd,
+        handler: QueueHandler,
+        disk: DiskProperties,
+        mem: GuestMemoryMmap,
+        irq_trigger: &IrqTrigger,
+    ) -> std::io::Result<Self> {
+        let command_evt = EventFd::new(libc::EFD_NONBLOCK)?;
+        let (commands, receiver) = channel();
+        let worker = QueueWorker {
+            queue,
+            queue_evt: queue_evt.try_clone()?,
+            handler,
+            completion_event: None,
+            disk,
+            mem,
+            irq_trigger: irq_trigger.try_clone()?,
+            rate_limiter: RateLimiter::default(),
+            commands: receiver,
+            command_evt: command_evt.try_clone()?,
+            stopped: false,
+        };
+        let handle = thread::Builder::new().spawn(move || worker.run())?;
+
+        Ok(IoThread {
+            index,
+            commands,
+            command_evt,
+            handle: Some(handle),
+        })
+    }
+
+    /// The index of the queue processed by the thread.
+    pub fn index(&self) -> usize {
+        self.index
+    }
+
+    pub fn send(&self, command: Command) {
+        if self.commands.send(command).is_err() {
+            error!("Failed to send a command to block IO thread {}", self.index);
+            return;
+        }
+        if let Err(e) = self.command_evt.write(1) {
+            error!("Failed to signal block IO thread {}: {:?}", self.index, e);
+        }
+    }
+
+    /// Waits for the in-flight requests of the queue to complete, and provides its state.
+    pub fn save(&self) -> Option<Queue> {
+        let (sender, receiver) = channel();
+        self.send(Command::Save(sender));
+        receiver.recv().ok()
+    }
+}
+
+impl Drop for IoThread {
+    fn drop(&mut self) {
+        self.send(Command::Stop);
+        if let Some(handle) = self.handle.take() {
+            if handle.join().is_err() {
+                error!("Block IO thread {} panicked", self.index);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    use crate::virtio::block::device::FileEngineType;
+    use crate::virtio::block::test_utils::default_block;
+    use crate::virtio::test_utils::{default_mem, initialize_virtqueue, VirtQueue};
+    use crate::virtio::VirtioDevice;
+    use virtio_gen::virtio_blk::*;
+    use vm_memory::{Bytes, GuestAddress};
+
+    #[test]
+    fn test_io_thread() {
+        let mut block = default_block(FileEngineType::Sync);
+        let mem = default_mem();
+        let vq = VirtQueue::new(GuestAddress(0), &mem, 16);
+        block.queues[0] = vq.create_queue();
+        block.activate(mem.clone()).unwrap();
+        initialize_virtqueue(&vq);
+
+        let handler = block.queue_handlers.pop().unwrap();
+        let io_thread = IoThread::spawn(
+            0,
+            block.queues[0].clone(),
+            &block.queue_evts[0],
+            handler,
+            block.disk.clone(),
+            mem.clone(),
+            &block.irq_trigger,
+        )
+        .unwrap();
+        assert_eq!(io_thread.index(), 0);
+
+        // Request the ID of the device from the thread.
+        let request_type_addr = GuestAddress(vq.dtable[0].addr.get());
+        mem.write_obj::<u32>(VIRTIO_BLK_T_GET_ID, request_type_addr)
+            .unwrap();
+        vq.dtable[1].len.set(VIRTIO_BLK_ID_BYTES);
+        block.queue_evts[0].write(1).unwrap();
+        for _ in 0..100 {
+            if vq.used.idx.get() == 1 {
+                break;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(vq.used.idx.get(), 1);
+
+        // The saved state of the queue reflects the processed request.
+        let queue = io_thread.save().unwrap();
+        assert_eq!(queue.next_used.0, 1);
+        assert_eq!(vq.used.ring[0].get().id, 0);
+        let mut id = vec![0; VIRTIO_BLK_ID_BYTES as usize];
+        mem.read_slice(&mut id, GuestAddress(vq.dtable[1].addr.get()))
+            .unwrap();
+        assert_eq!(id, block.disk.image_id());
+
+        // Dropping the handle stops the thread.
+        drop(io_thread);
+    }
+}
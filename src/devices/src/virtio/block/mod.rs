@@ -4,6 +4,7 @@
 pub mod device;
 pub mod event_handler;
 mod io;
+mod io_thread;
 pub mod persist;
 pub mod request;
 pub mod test_utils;
@@ -14,15 +15,27 @@ pub use self::request::*;
 
 use vm_memory::GuestMemoryError;
 
-pub const CONFIG_SPACE_SIZE: usize = 8;
+pub const CONFIG_SPACE_SIZE: usize = 60;
+// The offset of the discard and write zeroes fields in the config space.
+pub const DISCARD_CONFIG_OFFSET: usize = 36;
 pub const SECTOR_SHIFT: u8 = 9;
 pub const SECTOR_SIZE: u64 = (0x01_u64) << SECTOR_SHIFT;
 pub const QUEUE_SIZE: u16 = 256;
-pub const NUM_QUEUES: usize = 1;
-pub const QUEUE_SIZES: &[u16] = &[QUEUE_SIZE];
-// The virtio queue can hold up to 256 descriptors, but 1 request spreads across 2-3 descriptors.
+pub const DEFAULT_NUM_QUEUES: u16 = 1;
+// The number of queues is bounded by the per-queue metrics.
+pub const MAX_NUM_QUEUES: u16 = 16;
+// The offset of the number of queues in the config space.
+pub const NUM_QUEUES_CONFIG_OFFSET: usize = 34;
+// The maximum number of data descriptors of a request, leaving room for the header and status
+// descriptors in the chain.
+pub const SEG_MAX: u32 = QUEUE_SIZE as u32 - 2;
+// The virtio queue can hold up to 256 descriptors, but 1 request spreads across at least 2
+// descriptors and is submitted as a single io_uring entry.
 // So we can use 128 IO_URING entries without ever triggering a FullSq Error.
 pub const IO_URING_NUM_ENTRIES: u16 = 128;
+// How long the kernel thread polling the submission queue of an io_uring keeps spinning without
+// new submissions, before going to sleep.
+pub const IO_URING_SQ_THREAD_IDLE_MS: u32 = 100;
 
 #[derive(Debug)]
 pub enum Error {
@@ -36,6 +49,9 @@ pub enum Error {
     GuestMemory(GuestMemoryError),
     /// The data length is invalid.
     InvalidDataLength,
+    /// The number of queues is either zero, above `MAX_NUM_QUEUES`, or above one while the
+    /// NBD export doesn't support multiple connections.
+    InvalidNumQueues(u16),
     /// The requested operation would cause a seek beyond disk end.
     InvalidOffset,
     /// Guest gave us a read only descriptor that protocol says to write to.
@@ -7,7 +7,7 @@ use std::sync::atomic::AtomicUsize;
 use std::sync::Arc;
 
 use logger::warn;
-use rate_limiter::{persist::RateLimiterState, RateLimiter};
+use rate_limiter::{persist::RateLimiterState, RateLimiter, RateLimiterGroup};
 use snapshot::Persist;
 use utils::kernel_version::min_kernel_version_for_io_uring;
 use versionize::{VersionMap, Versionize, VersionizeError, VersionizeResult};
@@ -51,6 +51,16 @@ impl From<CacheTypeState> for CacheType {
 pub enum FileEngineTypeState {
     Sync,
     Async,
+    #[version(start = 2, default_fn = "default_nbd")]
+    Nbd,
+}
+
+impl FileEngineTypeState {
+    fn default_nbd(&self, _target_version: u16) -> VersionizeResult<Self> {
+        Err(VersionizeError::Semantic(
+            "Target version does not implement the NBD block backend.".to_owned(),
+        ))
+    }
 }
 
 impl From<FileEngineType> for FileEngineTypeState {
@@ -58,6 +68,7 @@ impl From<FileEngineType> for FileEngineTypeState {
         match file_engine_type {
             FileEngineType::Sync => FileEngineTypeState::Sync,
             FileEngineType::Async => FileEngineTypeState::Async,
+            FileEngineType::Nbd => FileEngineTypeState::Nbd,
         }
     }
 }
@@ -67,6 +78,7 @@ impl From<FileEngineTypeState> for FileEngineType {
         match file_engine_type_state {
             FileEngineTypeState::Sync => FileEngineType::Sync,
             FileEngineTypeState::Async => FileEngineType::Async,
+            FileEngineTypeState::Nbd => FileEngineType::Nbd,
         }
     }
 }
@@ -99,6 +111,18 @@ pub struct BlockState {
     // v1.0 are incompatible with older FC versions (due to incompatible notification suppression
     // feature).
     file_engine_type: FileEngineTypeState,
+    #[version(start = 4, ser_fn = "block_direct_io_ser")]
+    direct_io: bool,
+    #[version(
+        start = 4,
+        ser_fn = "block_num_queues_ser",
+        default_fn = "default_num_queues"
+    )]
+    num_queues: u16,
+    #[version(start = 4, ser_fn = "block_io_threads_ser")]
+    io_threads: bool,
+    #[version(start = 4, ser_fn = "block_sq_poll_ser")]
+    sq_poll: bool,
 }
 
 impl BlockState {
@@ -113,6 +137,53 @@ impl BlockState {
         Ok(())
     }
 
+    fn block_direct_io_ser(&mut self, target_version: u16) -> VersionizeResult<()> {
+        if target_version < 4 && self.direct_io {
+            warn!(
+                "Target version does not implement direct IO. \
+                The backing file will be opened through the page cache."
+            );
+        }
+
+        Ok(())
+    }
+
+    fn block_num_queues_ser(&mut self, target_version: u16) -> VersionizeResult<()> {
+        if target_version < 4 && self.num_queues != DEFAULT_NUM_QUEUES {
+            return Err(VersionizeError::Semantic(
+                "Target version does not support multiple block queues.".to_owned(),
+            ));
+        }
+
+        Ok(())
+    }
+
+    fn block_io_threads_ser(&mut self, target_version: u16) -> VersionizeResult<()> {
+        if target_version < 4 && self.io_threads {
+            warn!(
+                "Target version does not implement IO threads. \
+                The block queues will be processed on the VMM thread."
+            );
+        }
+
+        Ok(())
+    }
+
+    fn block_sq_poll_ser(&mut self, target_version: u16) -> VersionizeResult<()> {
+        if target_version < 4 && self.sq_poll {
+            warn!(
+                "Target version does not implement submission queue polling. \
+                The IO requests will be submitted through system calls."
+            );
+        }
+
+        Ok(())
+    }
+
+    fn default_num_queues(_source_version: u16) -> u16 {
+        DEFAULT_NUM_QUEUES
+    }
+
     fn default_cache_type_flush(_source_version: u16) -> CacheTypeState {
         CacheTypeState::Unsafe
     }
@@ -120,6 +191,8 @@ impl BlockState {
 
 pub struct BlockConstructorArgs {
     pub mem: GuestMemoryMmap,
+    /// The rate limiter group the device was attached to, as groups are saved separately.
+    pub rate_limiter_group: Option<RateLimiterGroup>,
 }
 
 impl Persist<'_> for Block {
@@ -138,6 +211,10 @@ impl Persist<'_> for Block {
             virtio_state: VirtioDeviceState::from_device(self),
             rate_limiter_state: self.rate_limiter.save(),
             file_engine_type: FileEngineTypeState::from(self.file_engine_type()),
+            direct_io: self.direct_io(),
+            num_queues: self.num_queues(),
+            io_threads: self.io_threads(),
+            sq_poll: self.sq_poll(),
         }
     }
 
@@ -146,8 +223,9 @@ impl Persist<'_> for Block {
         state: &Self::State,
     ) -> Result<Self, Self::Error> {
         let is_disk_read_only = state.virtio_state.avail_features & (1u64 << VIRTIO_BLK_F_RO) != 0;
-        let rate_limiter =
+        let mut rate_limiter =
             RateLimiter::restore((), &state.rate_limiter_state).map_err(Error::RateLimiter)?;
+        rate_limiter.set_group(constructor_args.rate_limiter_group.clone());
 
         let mut block = Block::new(
             state.id.clone(),
@@ -158,6 +236,13 @@ impl Persist<'_> for Block {
             state.root_device,
             rate_limiter,
             state.file_engine_type.into(),
+            state.direct_io,
+            state.num_queues,
+            state.io_threads,
+            // The restored guest memory may be file-backed, which can't be registered with
+            // io_uring.
+            false,
+            state.sq_poll,
         )
         .or_else(|err| match err {
             Error::FileEngine(io::Error::UnsupportedEngine(FileEngineType::Async)) => {
@@ -168,8 +253,9 @@ impl Persist<'_> for Block {
                     min_kernel_version_for_io_uring()
                 );
 
-                let rate_limiter = RateLimiter::restore((), &state.rate_limiter_state)
+                let mut rate_limiter = RateLimiter::restore((), &state.rate_limiter_state)
                     .map_err(Error::RateLimiter)?;
+                rate_limiter.set_group(constructor_args.rate_limiter_group.clone());
                 Block::new(
                     state.id.clone(),
                     state.partuuid.clone(),
@@ -179,6 +265,11 @@ impl Persist<'_> for Block {
                     state.root_device,
                     rate_limiter,
                     FileEngineType::Sync,
+                    state.direct_io,
+                    state.num_queues,
+                    state.io_threads,
+                    false,
+                    false,
                 )
             }
             other_err => Err(other_err),
@@ -186,7 +277,12 @@ impl Persist<'_> for Block {
 
         block.queues = state
             .virtio_state
-            .build_queues_checked(&constructor_args.mem, TYPE_BLOCK, NUM_QUEUES, QUEUE_SIZE)
+            .build_queues_checked(
+                &constructor_args.mem,
+                TYPE_BLOCK,
+                state.num_queues as usize,
+                QUEUE_SIZE,
+            )
             .map_err(Error::Persist)?;
         block.irq_trigger.irq_status =
             Arc::new(AtomicUsize::new(state.virtio_state.interrupt_status));
@@ -207,6 +303,7 @@ mod tests {
     use crate::virtio::device::VirtioDevice;
     use utils::tempfile::TempFile;
 
+    use crate::virtio::block::test_utils::direct_io_temp_file;
     use crate::virtio::test_utils::default_mem;
     use std::sync::atomic::Ordering;
 
@@ -256,6 +353,11 @@ mod tests {
             false,
             RateLimiter::default(),
             FileEngineType::default(),
+            false,
+            1,
+            false,
+            false,
+            false,
         )
         .unwrap();
 
@@ -272,6 +374,108 @@ mod tests {
             .is_ok());
     }
 
+    #[test]
+    fn test_direct_io_persistence() {
+        let f = direct_io_temp_file();
+        f.as_file().set_len(0x1000).unwrap();
+
+        let block = Block::new(
+            "test".to_string(),
+            None,
+            CacheType::Unsafe,
+            f.as_path().to_str().unwrap().to_string(),
+            false,
+            false,
+            RateLimiter::default(),
+            FileEngineType::default(),
+            true,
+            1,
+            false,
+            false,
+            false,
+        )
+        .unwrap();
+        assert!(block.direct_io());
+
+        let mut version_map = VersionMap::new();
+        version_map
+            .new_version()
+            .set_type_version(BlockState::type_id(), 3)
+            .new_version()
+            .set_type_version(BlockState::type_id(), 4);
+
+        // The direct IO option is only saved from version 4 of the block state onwards.
+        for &(version, direct_io) in [(2, false), (3, true)].iter() {
+            let mut mem = vec![0; 4096];
+            <Block as Persist>::save(&block)
+                .serialize(&mut mem.as_mut_slice(), &version_map, version)
+                .unwrap();
+
+            let restored_block = Block::restore(
+                BlockConstructorArgs {
+                    mem: default_mem(),
+                    rate_limiter_group: None,
+                },
+                &BlockState::deserialize(&mut mem.as_slice(), &version_map, version).unwrap(),
+            )
+            .unwrap();
+            assert_eq!(restored_block.direct_io(), direct_io);
+        }
+    }
+
+    #[test]
+    fn test_multiple_queues_persistence() {
+        let f = TempFile::new().unwrap();
+        f.as_file().set_len(0x1000).unwrap();
+
+        let block = Block::new(
+            "test".to_string(),
+            None,
+            CacheType::Unsafe,
+            f.as_path().to_str().unwrap().to_string(),
+            false,
+            false,
+            RateLimiter::default(),
+            FileEngineType::default(),
+            false,
+            4,
+            true,
+            false,
+            true,
+        )
+        .unwrap();
+
+        let mut version_map = VersionMap::new();
+        version_map
+            .new_version()
+            .set_type_version(BlockState::type_id(), 3)
+            .new_version()
+            .set_type_version(BlockState::type_id(), 4);
+
+        // Versions prior to 4 of the block state only support a single queue.
+        let mut mem = vec![0; 4096];
+        assert!(<Block as Persist>::save(&block)
+            .serialize(&mut mem.as_mut_slice(), &version_map, 2)
+            .is_err());
+
+        <Block as Persist>::save(&block)
+            .serialize(&mut mem.as_mut_slice(), &version_map, 3)
+            .unwrap();
+        let restored_block = Block::restore(
+            BlockConstructorArgs {
+                mem: default_mem(),
+                rate_limiter_group: None,
+            },
+            &BlockState::deserialize(&mut mem.as_slice(), &version_map, 3).unwrap(),
+        )
+        .unwrap();
+        assert_eq!(restored_block.num_queues(), 4);
+        assert_eq!(restored_block.queues(), block.queues());
+        assert_eq!(restored_block.queue_events().len(), 4);
+        assert!(restored_block.io_threads());
+        assert!(restored_block.sq_poll());
+    }
+
     #[test]
     fn test_file_engine_type() {
         // Test conversions between FileEngineType and FileEngineTypeState.
@@ -285,9 +489,31 @@ mod tests {
         );
         assert_eq!(FileEngineType::Async, FileEngineTypeState::Async.into());
         assert_eq!(FileEngineType::Sync, FileEngineTypeState::Sync.into());
+        assert_eq!(
+            FileEngineTypeState::Nbd,
+            FileEngineTypeState::from(FileEngineType::Nbd)
+        );
+        assert_eq!(FileEngineType::Nbd, FileEngineTypeState::Nbd.into());
         // Test default impl.
         assert_eq!(FileEngineTypeState::default(), FileEngineTypeState::Sync);
 
+        // The NBD engine can't be saved for versions which predate it.
+        let mut mem = vec![0; 16];
+        let mut nbd_version_map = VersionMap::new();
+        assert!(FileEngineTypeState::Nbd
+            .serialize(&mut mem.as_mut_slice(), &nbd_version_map, 1)
+            .is_err());
+        nbd_version_map
+            .new_version()
+            .set_type_version(FileEngineTypeState::type_id(), 2);
+        FileEngineTypeState::Nbd
+            .serialize(&mut mem.as_mut_slice(), &nbd_version_map, 2)
+            .unwrap();
+        assert_eq!(
+            FileEngineTypeState::deserialize(&mut mem.as_slice(), &nbd_version_map, 2).unwrap(),
+            FileEngineTypeState::Nbd
+        );
+
         let f = TempFile::new().unwrap();
         f.as_file().set_len(0x1000).unwrap();
         let mut version_map = VersionMap::new();
@@ -310,6 +536,11 @@ mod tests {
                 // Need to use Sync because it will otherwise return an error.
                 // We'll overwrite the state instead.
                 FileEngineType::Sync,
+                false,
+                1,
+                false,
+                false,
+                false,
             )
             .unwrap();
 
@@ -326,7 +557,10 @@ mod tests {
 
             // Restore the block device.
             let restored_block = Block::restore(
-                BlockConstructorArgs { mem: default_mem() },
+                BlockConstructorArgs {
+                    mem: default_mem(),
+                    rate_limiter_group: None,
+                },
                 &BlockState::deserialize(&mut mem.as_slice(), &version_map, 2).unwrap(),
             )
             .unwrap();
@@ -353,6 +587,11 @@ mod tests {
             false,
             RateLimiter::default(),
             FileEngineType::default(),
+            false,
+            1,
+            false,
+            false,
+            false,
         )
         .unwrap();
         let guest_mem = default_mem();
@@ -366,8 +605,12 @@ mod tests {
             .unwrap();
 
         // Restore the block device.
+        let group = RateLimiterGroup::new("disks".to_string(), 0, 0, 0, 1, 0, 100);
         let restored_block = Block::restore(
-            BlockConstructorArgs { mem: guest_mem },
+            BlockConstructorArgs {
+                mem: guest_mem,
+                rate_limiter_group: Some(group.clone()),
+            },
             &BlockState::deserialize(&mut mem.as_slice(), &version_map, 1).unwrap(),
         )
         .unwrap();
@@ -382,8 +625,11 @@ mod tests {
             block.interrupt_status().load(Ordering::Relaxed)
         );
         assert_eq!(restored_block.is_activated(), block.is_activated());
+        assert!(!restored_block.fixed_buffers);
 
         // Test that block specific fields are the same.
         assert_eq!(restored_block.disk.file_path(), block.disk.file_path());
+        // The restored device is attached to the rate limiter group.
+        assert_eq!(restored_block.rate_limiter().group(), Some(&group));
     }
 }
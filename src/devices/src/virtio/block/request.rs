@@ -5,6 +5,7 @@
 // Use of this source code is governed by a BSD-style license that can be
 // found in the THIRD-PARTY file.
 
+use std::cmp;
 use std::convert::From;
 use std::result;
 
@@ -13,10 +14,10 @@ use virtio_gen::virtio_blk::*;
 use vm_memory::{ByteValued, Bytes, GuestAddress, GuestMemoryError, GuestMemoryMmap};
 
 use super::super::DescriptorChain;
+use super::io::IoSegment;
 use super::{io as block_io, Error, SECTOR_SHIFT};
-use crate::virtio::block::device::DiskProperties;
 use crate::virtio::SECTOR_SIZE;
-use rate_limiter::{RateLimiter, TokenType};
+use rate_limiter::{IoDirection, RateLimiter, TokenType};
 
 #[derive(Debug)]
 pub enum IoErr {
@@ -31,6 +32,8 @@ pub enum RequestType {
     Out,
     Flush,
     GetDeviceID,
+    Discard,
+    WriteZeroes,
     Unsupported(u32),
 }
 
@@ -41,6 +44,8 @@ impl From<u32> for RequestType {
             VIRTIO_BLK_T_OUT => RequestType::Out,
             VIRTIO_BLK_T_FLUSH => RequestType::Flush,
             VIRTIO_BLK_T_GET_ID => RequestType::GetDeviceID,
+            VIRTIO_BLK_T_DISCARD => RequestType::Discard,
+            VIRTIO_BLK_T_WRITE_ZEROES => RequestType::WriteZeroes,
             t => RequestType::Unsupported(t),
         }
     }
@@ -154,6 +159,18 @@ impl PendingRequest {
                     num_bytes_to_mem: 0,
                 }
             }
+            (Ok(_), RequestType::Discard) => {
+                METRICS.block.discard_count.inc();
+                Status::Ok {
+                    num_bytes_to_mem: 0,
+                }
+            }
+            (Ok(_), RequestType::WriteZeroes) => {
+                METRICS.block.write_zeroes_count.inc();
+                Status::Ok {
+                    num_bytes_to_mem: 0,
+                }
+            }
             (Ok(transferred_data_len), RequestType::GetDeviceID) => {
                 Status::from_data(self.data_len, transferred_data_len, true)
             }
@@ -210,13 +227,30 @@ impl RequestHeader {
     }
 }
 
+/// The range affected by a discard or write zeroes request, which is held in the data of the
+/// request.
+#[derive(Copy, Clone, Default)]
+#[repr(C)]
+struct DiscardWriteZeroesSegment {
+    sector: u64,
+    num_sectors: u32,
+    flags: u32,
+}
+
+// Safe because DiscardWriteZeroesSegment only contains plain data.
+unsafe impl ByteValued for DiscardWriteZeroesSegment {}
+
 #[cfg_attr(test, derive(Debug, PartialEq))]
 pub struct Request {
     pub r#type: RequestType,
     pub data_len: u32,
     pub status_addr: GuestAddress,
     sector: u64,
-    data_addr: GuestAddress,
+    segments: Vec<IoSegment>,
+    // The number of sectors affected by a discard or write zeroes request, starting at `sector`.
+    num_sectors: u32,
+    // Whether a write zeroes request allows deallocating the range.
+    unmap: bool,
 }
 
 impl Request {
@@ -234,41 +268,49 @@ impl Request {
         let mut req = Request {
             r#type: RequestType::from(request_header.request_type),
             sector: request_header.sector,
-            data_addr: GuestAddress(0),
+            segments: Vec::new(),
             data_len: 0,
             status_addr: GuestAddress(0),
+            num_sectors: 0,
+            unmap: false,
         };
 
-        let data_desc;
-        let status_desc;
-        let desc = avail_desc
+        let mut desc = avail_desc
             .next_descriptor()
             .ok_or(Error::DescriptorChainTooShort)?;
 
-        if !desc.has_next() {
-            status_desc = desc;
-            // Only flush requests are allowed to skip the data descriptor.
-            if req.r#type != RequestType::Flush {
-                return Err(Error::DescriptorChainTooShort);
-            }
-        } else {
-            data_desc = desc;
-            status_desc = data_desc
-                .next_descriptor()
-                .ok_or(Error::DescriptorChainTooShort)?;
-
-            if data_desc.is_write_only() && req.r#type == RequestType::Out {
+        // All the descriptors between the header and the status hold data.
+        while desc.has_next() {
+            if desc.is_write_only()
+                && matches!(
+                    req.r#type,
+                    RequestType::Out | RequestType::Discard | RequestType::WriteZeroes
+                )
+            {
                 return Err(Error::UnexpectedWriteOnlyDescriptor);
             }
-            if !data_desc.is_write_only() && req.r#type == RequestType::In {
+            if !desc.is_write_only() && req.r#type == RequestType::In {
                 return Err(Error::UnexpectedReadOnlyDescriptor);
             }
-            if !data_desc.is_write_only() && req.r#type == RequestType::GetDeviceID {
+            if !desc.is_write_only() && req.r#type == RequestType::GetDeviceID {
                 return Err(Error::UnexpectedReadOnlyDescriptor);
             }
 
-            req.data_addr = data_desc.addr;
-            req.data_len = data_desc.len;
+            req.data_len = req
+                .data_len
+                .checked_add(desc.len)
+                .ok_or(Error::InvalidDataLength)?;
+            req.segments.push(IoSegment::new(desc.addr, desc.len));
+
+            desc = desc
+                .next_descriptor()
+                .ok_or(Error::DescriptorChainTooShort)?;
+        }
+        let status_desc = desc;
+
+        // Only flush requests are allowed to skip the data descriptors.
+        if req.segments.is_empty() && req.r#type != RequestType::Flush {
+            return Err(Error::DescriptorChainTooShort);
         }
 
         // check request validity
@@ -291,6 +333,30 @@ impl Request {
                     return Err(Error::InvalidDataLength);
                 }
             }
+            RequestType::Discard | RequestType::WriteZeroes => {
+                // The device advertises a single range per request.
+                if req.data_len as usize != std::mem::size_of::<DiscardWriteZeroesSegment>() {
+                    return Err(Error::InvalidDataLength);
+                }
+                let mut range = DiscardWriteZeroesSegment::default();
+                req.read_from_segments(range.as_mut_slice(), mem)
+                    .map_err(Error::GuestMemory)?;
+                let top_sector = range
+                    .sector
+                    .checked_add(u64::from(range.num_sectors))
+                    .ok_or(Error::InvalidOffset)?;
+                if top_sector > num_disk_sectors {
+                    return Err(Error::InvalidOffset);
+                }
+                let unmap = range.flags & VIRTIO_BLK_WRITE_ZEROES_FLAG_UNMAP != 0;
+                // The unmap flag is reserved for write zeroes requests.
+                if unmap && req.r#type == RequestType::Discard {
+                    req.r#type = RequestType::Unsupported(VIRTIO_BLK_T_DISCARD);
+                }
+                req.sector = range.sector;
+                req.num_sectors = range.num_sectors;
+                req.unmap = unmap;
+            }
             _ => {}
         }
 
@@ -308,19 +374,42 @@ impl Request {
         Ok(req)
     }
 
+    /// Returns the direction of the data transfer performed by the request, if any.
+    pub(crate) fn io_direction(&self) -> Option<IoDirection> {
+        match self.r#type {
+            RequestType::In => Some(IoDirection::Read),
+            RequestType::Out => Some(IoDirection::Write),
+            _ => None,
+        }
+    }
+
+    // Consumes tokens from the rate limiter, including the buckets dedicated to the direction
+    // of the request.
+    fn consume_tokens(
+        &self,
+        rate_limiter: &mut RateLimiter,
+        tokens: u64,
+        token_type: TokenType,
+    ) -> bool {
+        match self.io_direction() {
+            Some(direction) => rate_limiter.consume_directional(tokens, token_type, direction),
+            None => rate_limiter.consume(tokens, token_type),
+        }
+    }
+
     pub(crate) fn rate_limit(&self, rate_limiter: &mut RateLimiter) -> bool {
         // If limiter.consume() fails it means there is no more TokenType::Ops
         // budget and rate limiting is in effect.
-        if !rate_limiter.consume(1, TokenType::Ops) {
+        if !self.consume_tokens(rate_limiter, 1, TokenType::Ops) {
             return true;
         }
         // Exercise the rate limiter only if this request is of data transfer type.
-        if self.r#type == RequestType::In || self.r#type == RequestType::Out {
+        if let Some(direction) = self.io_direction() {
             // If limiter.consume() fails it means there is no more TokenType::Bytes
             // budget and rate limiting is in effect.
-            if !rate_limiter.consume(u64::from(self.data_len), TokenType::Bytes) {
+            if !self.consume_tokens(rate_limiter, u64::from(self.data_len), TokenType::Bytes) {
                 // Revert the OPS consume().
-                rate_limiter.manual_replenish(1, TokenType::Ops);
+                rate_limiter.manual_replenish_directional(1, TokenType::Ops, direction);
                 return true;
             }
         }
@@ -332,6 +421,10 @@ impl Request {
         self.sector << SECTOR_SHIFT
     }
 
+    fn range_len(&self) -> u64 {
+        u64::from(self.num_sectors) << SECTOR_SHIFT
+    }
+
     fn to_pending_request(&self, desc_idx: u16) -> PendingRequest {
         PendingRequest {
             r#type: self.r#type,
@@ -341,32 +434,60 @@ impl Request {
         }
     }
 
+    // Fills `buf` from the guest memory pointed to by the data segments, in order.
+    fn read_from_segments(
+        &self,
+        mut buf: &mut [u8],
+        mem: &GuestMemoryMmap,
+    ) -> result::Result<(), GuestMemoryError> {
+        for segment in self.segments.iter() {
+            if buf.is_empty() {
+                break;
+            }
+            let len = cmp::min(segment.len as usize, buf.len());
+            let (head, tail) = buf.split_at_mut(len);
+            mem.read_slice(head, segment.addr)?;
+            buf = tail;
+        }
+        Ok(())
+    }
+
+    // Writes `data` to the guest memory pointed to by the data segments, in order.
+    fn write_to_segments(
+        &self,
+        mut data: &[u8],
+        mem: &GuestMemoryMmap,
+    ) -> result::Result<(), GuestMemoryError> {
+        for segment in self.segments.iter() {
+            if data.is_empty() {
+                break;
+            }
+            let len = cmp::min(segment.len as usize, data.len());
+            mem.write_slice(&data[..len], segment.addr)?;
+            data = &data[len..];
+        }
+        Ok(())
+    }
+
     pub(crate) fn process(
         self,
-        disk: &mut DiskProperties,
+        file_engine: &mut block_io::FileEngine<PendingRequest>,
+        image_id: &[u8],
         desc_idx: u16,
         mem: &GuestMemoryMmap,
     ) -> ProcessingResult {
         let pending = self.to_pending_request(desc_idx);
         let res = match self.r#type {
-            RequestType::In => disk.file_engine_mut().read(
-                self.offset(),
-                mem,
-                self.data_addr,
-                self.data_len,
-                pending,
-            ),
-            RequestType::Out => disk.file_engine_mut().write(
-                self.offset(),
-                mem,
-                self.data_addr,
-                self.data_len,
-                pending,
-            ),
-            RequestType::Flush => disk.file_engine_mut().flush(pending),
+            RequestType::In => file_engine.read(self.offset(), mem, &self.segments, pending),
+            RequestType::Out => file_engine.write(self.offset(), mem, &self.segments, pending),
+            RequestType::Flush => file_engine.flush(pending),
+            RequestType::Discard => file_engine.discard(self.offset(), self.range_len(), pending),
+            RequestType::WriteZeroes => {
+                file_engine.write_zeroes(self.offset(), self.range_len(), self.unmap, pending)
+            }
             RequestType::GetDeviceID => {
-                let res = mem
-                    .write_slice(disk.image_id(), self.data_addr)
+                let res = self
+                    .write_to_segments(image_id, mem)
                     .map(|_| VIRTIO_BLK_ID_BYTES)
                     .map_err(IoErr::GetId);
                 return ProcessingResult::Executed(pending.finish(mem, res));
@@ -416,6 +537,8 @@ mod tests {
             VIRTIO_BLK_T_OUT,
             VIRTIO_BLK_T_FLUSH,
             VIRTIO_BLK_T_GET_ID,
+            VIRTIO_BLK_T_DISCARD,
+            VIRTIO_BLK_T_WRITE_ZEROES,
         ];
 
         for request_type in supported_request_types {
@@ -442,6 +565,14 @@ mod tests {
             RequestType::from(VIRTIO_BLK_T_GET_ID),
             RequestType::GetDeviceID
         );
+        assert_eq!(
+            RequestType::from(VIRTIO_BLK_T_DISCARD),
+            RequestType::Discard
+        );
+        assert_eq!(
+            RequestType::from(VIRTIO_BLK_T_WRITE_ZEROES),
+            RequestType::WriteZeroes
+        );
         assert_eq!(RequestType::from(42), RequestType::Unsupported(42));
     }
 
@@ -525,7 +656,13 @@ mod tests {
 
             if check_data {
                 let data_desc = &self.vq.dtable[Self::DATA_DESC];
-                assert_eq!(request.data_addr.raw_value(), data_desc.addr.get());
+                assert_eq!(
+                    request.segments,
+                    vec![IoSegment::new(
+                        GuestAddress(data_desc.addr.get()),
+                        data_desc.len.get()
+                    )]
+                );
                 assert_eq!(request.data_len, data_desc.len.get());
             }
 
@@ -688,6 +825,115 @@ mod tests {
         queue.check_parse(true);
     }
 
+    #[test]
+    fn test_parse_multiple_data_descriptors() {
+        let mem = &create_anon_guest_memory(&[(GuestAddress(0), 0x10000)], false).unwrap();
+        let mut vq = VirtQueue::new(GuestAddress(0), &mem, 16);
+        vq.avail.ring[0].set(0);
+        vq.avail.idx.set(1);
+
+        let request_header = RequestHeader::new(VIRTIO_BLK_T_IN, 10);
+        vq.dtable[0].set(0x1000, 0x1000, VIRTQ_DESC_F_NEXT, 1);
+        vq.dtable[0].set_data(request_header.as_slice());
+        vq.dtable[1].set(0x4000, 0x200, VIRTQ_DESC_F_NEXT | VIRTQ_DESC_F_WRITE, 2);
+        vq.dtable[2].set(0x2000, 0x400, VIRTQ_DESC_F_NEXT | VIRTQ_DESC_F_WRITE, 3);
+        vq.dtable[3].set(0x3000, 0x1000, VIRTQ_DESC_F_WRITE, 0);
+
+        let mut q = vq.create_queue();
+        let request = Request::parse(&q.pop(mem).unwrap(), mem, NUM_DISK_SECTORS).unwrap();
+        assert_eq!(request.r#type, RequestType::In);
+        assert_eq!(request.data_len, 0x600);
+        assert_eq!(
+            request.segments,
+            vec![
+                IoSegment::new(GuestAddress(0x4000), 0x200),
+                IoSegment::new(GuestAddress(0x2000), 0x400)
+            ]
+        );
+        assert_eq!(request.status_addr, GuestAddress(0x3000));
+
+        // Only the total data length needs to be a multiple of 512.
+        vq.dtable[1].len.set(0x100);
+        vq.dtable[2].len.set(0x300);
+        let mut q = vq.create_queue();
+        let request = Request::parse(&q.pop(mem).unwrap(), mem, NUM_DISK_SECTORS).unwrap();
+        assert_eq!(request.data_len, 0x400);
+        vq.dtable[2].len.set(0x200);
+        let mut q = vq.create_queue();
+        assert!(matches!(
+            Request::parse(&q.pop(mem).unwrap(), mem, NUM_DISK_SECTORS),
+            Err(Error::InvalidDataLength)
+        ));
+
+        // All the data descriptors must be writable for IN.
+        vq.dtable[2].len.set(0x300);
+        vq.dtable[2].flags.set(VIRTQ_DESC_F_NEXT);
+        let mut q = vq.create_queue();
+        assert!(matches!(
+            Request::parse(&q.pop(mem).unwrap(), mem, NUM_DISK_SECTORS),
+            Err(Error::UnexpectedReadOnlyDescriptor)
+        ));
+    }
+
+    #[test]
+    fn test_parse_discard_write_zeroes() {
+        let mem = &create_anon_guest_memory(&[(GuestAddress(0), 0x10000)], false).unwrap();
+        let mut queue = RequestVirtQueue::new(GuestAddress(0), &mem);
+        let range_len = std::mem::size_of::<DiscardWriteZeroesSegment>() as u32;
+
+        for &request_type in [VIRTIO_BLK_T_DISCARD, VIRTIO_BLK_T_WRITE_ZEROES].iter() {
+            let request_header = RequestHeader::new(request_type, 0);
+            queue.set_hdr_desc(0x1000, 0x1000, VIRTQ_DESC_F_NEXT, request_header);
+            queue.set_status_desc(0x3000, 0x1000, VIRTQ_DESC_F_WRITE);
+
+            // Write only data descriptor.
+            queue.set_data_desc(0x2000, range_len, VIRTQ_DESC_F_NEXT | VIRTQ_DESC_F_WRITE);
+            queue.check_parse_err(Error::UnexpectedWriteOnlyDescriptor);
+
+            // More than one range.
+            queue.mut_data_desc().flags.set(VIRTQ_DESC_F_NEXT);
+            queue.mut_data_desc().len.set(2 * range_len);
+            queue.check_parse_err(Error::InvalidDataLength);
+
+            // The range exceeds the disk.
+            queue.mut_data_desc().len.set(range_len);
+            let range = DiscardWriteZeroesSegment {
+                sector: NUM_DISK_SECTORS - 1,
+                num_sectors: 2,
+                flags: 0,
+            };
+            mem.write_obj(range, GuestAddress(0x2000)).unwrap();
+            let mut q = queue.vq.create_queue();
+            assert!(matches!(
+                Request::parse(&q.pop(mem).unwrap(), mem, NUM_DISK_SECTORS),
+                Err(Error::InvalidOffset)
+            ));
+
+            // Valid range.
+            let range = DiscardWriteZeroesSegment {
+                sector: NUM_DISK_SECTORS - 2,
+                num_sectors: 2,
+                flags: VIRTIO_BLK_WRITE_ZEROES_FLAG_UNMAP,
+            };
+            mem.write_obj(range, GuestAddress(0x2000)).unwrap();
+            let mut q = queue.vq.create_queue();
+            let request = Request::parse(&q.pop(mem).unwrap(), mem, NUM_DISK_SECTORS).unwrap();
+            assert_eq!(request.sector, NUM_DISK_SECTORS - 2);
+            assert_eq!(request.offset(), (NUM_DISK_SECTORS - 2) << SECTOR_SHIFT);
+            assert_eq!(request.range_len(), 2 << SECTOR_SHIFT);
+            assert!(request.unmap);
+            // Discard requests don't support the unmap flag.
+            if request_type == VIRTIO_BLK_T_DISCARD {
+                assert_eq!(
+                    request.r#type,
+                    RequestType::Unsupported(VIRTIO_BLK_T_DISCARD)
+                );
+            } else {
+                assert_eq!(request.r#type, RequestType::WriteZeroes);
+            }
+        }
+    }
+
     /// -------------------------------------
     /// BEGIN PROPERTY BASED TESTING
     use proptest::arbitrary::Arbitrary;
@@ -729,9 +975,9 @@ mod tests {
                     1u32,
                     std::sync::Arc::new(Strategy::prop_map(any::<u32>(), |id| {
                         // Random unsupported requests for our implementation start at
-                        // VIRTIO_BLK_T_GET_ID + 1 = 9.
-                        // This can be further refined to include unsupported requests ids < 9.
-                        RequestType::Unsupported(id.checked_add(9).unwrap_or(9))
+                        // VIRTIO_BLK_T_WRITE_ZEROES + 1 = 14.
+                        // This can be further refined to include unsupported requests ids < 14.
+                        RequestType::Unsupported(id.checked_add(14).unwrap_or(14))
                     })),
                 ),
             ))
@@ -745,6 +991,8 @@ mod tests {
                 RequestType::Out => VIRTIO_BLK_T_OUT,
                 RequestType::Flush => VIRTIO_BLK_T_FLUSH,
                 RequestType::GetDeviceID => VIRTIO_BLK_T_GET_ID,
+                RequestType::Discard => VIRTIO_BLK_T_DISCARD,
+                RequestType::WriteZeroes => VIRTIO_BLK_T_WRITE_ZEROES,
                 RequestType::Unsupported(id) => id,
             }
         }
@@ -757,6 +1005,7 @@ mod tests {
             RequestType::Out => VIRTQ_DESC_F_NEXT,
             RequestType::Flush => VIRTQ_DESC_F_NEXT,
             RequestType::GetDeviceID => VIRTQ_DESC_F_NEXT | VIRTQ_DESC_F_WRITE,
+            RequestType::Discard | RequestType::WriteZeroes => VIRTQ_DESC_F_NEXT,
             RequestType::Unsupported(_) => VIRTQ_DESC_F_NEXT,
         }
     }
@@ -849,7 +1098,9 @@ mod tests {
             data_len: valid_data_len,
             status_addr,
             sector: sector & (NUM_DISK_SECTORS - sectors_len),
-            data_addr,
+            segments: vec![IoSegment::new(data_addr, valid_data_len)],
+            num_sectors: 0,
+            unmap: false,
         };
         let request_header = RequestHeader::new(virtio_request_id, request.sector);
 
@@ -861,14 +1112,14 @@ mod tests {
         );
         // Flush requests have no data desc.
         if request.r#type == RequestType::Flush {
-            request.data_addr = GuestAddress(0);
+            request.segments.clear();
             request.data_len = 0;
             rq.mut_hdr_desc()
                 .next
                 .set(RequestVirtQueue::STATUS_DESC as u16);
         } else {
             rq.set_data_desc(
-                request.data_addr.0,
+                data_addr.0,
                 request.data_len,
                 request_type_flags(request.r#type),
             )
@@ -27,6 +27,15 @@ pub fn default_block(file_engine_type: FileEngineType) -> Block {
     default_block_with_path(f.as_path().to_str().unwrap().to_string(), file_engine_type)
 }
 
+/// Create a temporary file in the build directory, which supports direct IO, unlike the tmpfs
+/// usually mounted at /tmp.
+#[cfg(test)]
+pub fn direct_io_temp_file() -> TempFile {
+    // Test binaries are built in the target directory.
+    let exe = std::env::current_exe().unwrap();
+    TempFile::new_in(exe.parent().unwrap()).unwrap()
+}
+
 /// Return the Async FileEngineType if supported by the host, otherwise default to Sync.
 pub fn default_engine_type_for_kv() -> FileEngineType {
     if KernelVersion::get().unwrap() >= min_kernel_version_for_io_uring() {
@@ -52,6 +61,11 @@ pub fn default_block_with_path(path: String, file_engine_type: FileEngineType) -
         false,
         rate_limiter,
         file_engine_type,
+        false,
+        1,
+        false,
+        false,
+        false,
     )
     .unwrap()
 }
@@ -73,7 +87,7 @@ pub fn simulate_queue_event(b: &mut Block, maybe_expected_irq: Option<bool>) {
     // Trigger the queue event.
     b.queue_evts[0].write(1).unwrap();
     // Handle event.
-    b.process_queue_event();
+    b.process_queue_event(0);
     // Validate the queue operation finished successfully.
     if let Some(expected_irq) = maybe_expected_irq {
         assert_eq!(b.irq_trigger.has_pending_irq(IrqType::Vring), expected_irq);
@@ -82,13 +96,22 @@ pub fn simulate_queue_event(b: &mut Block, maybe_expected_irq: Option<bool>) {
 
 #[cfg(test)]
 pub fn simulate_async_completion_event(b: &mut Block, expected_irq: bool) {
-    if let FileEngine::Async(engine) = b.disk.file_engine_mut() {
-        // Wait for all the async operations to complete.
-        engine.drain(false).unwrap();
-        // Wait for the async completion event to be sent.
-        thread::sleep(Duration::from_millis(150));
-        // Handle event.
-        b.process_async_completion_event();
+    match &mut b.queue_handlers[0].file_engine {
+        FileEngine::Async(engine) => {
+            // Wait for all the async operations to complete.
+            engine.drain(false).unwrap();
+            // Wait for the async completion event to be sent.
+            thread::sleep(Duration::from_millis(150));
+            // Handle event.
+            b.process_async_completion_event(0);
+        }
+        FileEngine::Nbd(_) => {
+            // Wait for the server to reply.
+            thread::sleep(Duration::from_millis(150));
+            // Handle event.
+            b.process_async_completion_event(0);
+        }
+        FileEngine::Sync(_) => (),
     }
 
     // Validate if there are pending IRQs.
@@ -97,12 +120,12 @@ pub fn simulate_async_completion_event(b: &mut Block, expected_irq: bool) {
 
 #[cfg(test)]
 pub fn simulate_queue_and_async_completion_events(b: &mut Block, expected_irq: bool) {
-    match b.disk.file_engine_mut() {
-        FileEngine::Async(_) => {
+    match b.file_engine_type() {
+        FileEngineType::Async | FileEngineType::Nbd => {
             simulate_queue_event(b, None);
             simulate_async_completion_event(b, expected_irq);
         }
-        FileEngine::Sync(_) => {
+        FileEngineType::Sync => {
             simulate_queue_event(b, Some(expected_irq));
         }
     }
@@ -58,6 +58,14 @@ impl IrqTrigger {
         })
     }
 
+    /// Creates a trigger for the same interrupt, to be used from another thread.
+    pub fn try_clone(&self) -> std::io::Result<Self> {
+        Ok(Self {
+            irq_status: self.irq_status.clone(),
+            irq_evt: self.irq_evt.try_clone()?,
+        })
+    }
+
     pub fn trigger_irq(&self, irq_type: IrqType) -> std::result::Result<(), std::io::Error> {
         let irq = match irq_type {
             IrqType::Config => VIRTIO_MMIO_INT_CONFIG,
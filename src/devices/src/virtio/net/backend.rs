@@ -0,0 +1,51 @@
+// Copyright 2022 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Defines the interface between the virtio net device model and the host side of the network.
+
+use std::io::{Read, Write};
+use std::os::unix::io::AsRawFd;
+
+use crate::virtio::net::tap::Tap;
+use crate::virtio::net::user::{UserNet, UserNetConfig};
+
+/// The host side of a virtio net device.
+///
+/// A backend exchanges Ethernet frames with the device model. Every frame passed to `write`, or
+/// returned by `read`, is prefixed by a VNET header. Reads and writes must never block; when no
+/// frame is available, `read` returns an error of kind `WouldBlock`. The file descriptor returned
+/// by `as_raw_fd` becomes readable (it's polled in edge triggered mode) when new frames might be
+/// available.
+pub trait NetBackend: Read + Write + AsRawFd + Send {
+    /// Returns the name of the host interface which backs the device, if any.
+    fn host_iface_name(&self) -> Option<&str> {
+        None
+    }
+
+    /// Returns the configuration of the user mode networking stack, if the backend is one.
+    fn user_net_config(&self) -> Option<&UserNetConfig> {
+        None
+    }
+
+    /// Returns `true` if the backend can handle frames with partial checksums and segmentation
+    /// offloads, as described by the VNET header.
+    fn supports_offloads(&self) -> bool {
+        false
+    }
+}
+
+impl NetBackend for Tap {
+    fn host_iface_name(&self) -> Option<&str> {
+        Some(self.if_name_as_str())
+    }
+
+    fn supports_offloads(&self) -> bool {
+        true
+    }
+}
+
+impl NetBackend for UserNet {
+    fn user_net_config(&self) -> Option<&UserNetConfig> {
+        Some(self.config())
+    }
+}
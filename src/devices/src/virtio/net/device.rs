@@ -5,9 +5,12 @@
 // Use of this source code is governed by a BSD-style license that can be
 // found in the THIRD-PARTY file.
 
+use crate::virtio::net::backend::NetBackend;
+use crate::virtio::net::egress_filter::EgressFilter;
 use crate::virtio::net::tap::Tap;
 #[cfg(test)]
 use crate::virtio::net::test_utils::Mocks;
+use crate::virtio::net::user::{UserNet, UserNetConfig};
 use crate::virtio::net::Error;
 use crate::virtio::net::Result;
 use crate::virtio::net::{MAX_BUFFER_SIZE, QUEUE_SIZE, QUEUE_SIZES, RX_INDEX, TX_INDEX};
@@ -20,7 +23,7 @@ use dumbo::pdu::ethernet::EthernetFrame;
 use libc::EAGAIN;
 use logger::{error, warn, IncMetric, METRICS};
 use mmds::data_store::Mmds;
-use mmds::ns::MmdsNetworkStack;
+use mmds::ns::{MmdsNetworkStack, MmdsNetworkStackConfig};
 use rate_limiter::{BucketUpdate, RateLimiter, TokenType};
 #[cfg(not(test))]
 use std::io;
@@ -95,7 +98,7 @@ unsafe impl ByteValued for ConfigSpace {}
 pub struct Net {
     pub(crate) id: String,
 
-    pub tap: Tap,
+    pub(crate) backend: Box<dyn NetBackend>,
 
     pub(crate) avail_features: u64,
     pub(crate) acked_features: u64,
@@ -125,6 +128,8 @@ pub struct Net {
 
     pub mmds_ns: Option<MmdsNetworkStack>,
 
+    pub(crate) egress_filter: Option<EgressFilter>,
+
     #[cfg(test)]
     pub(crate) mocks: Mocks,
 }
@@ -150,13 +155,51 @@ impl Net {
         tap.set_vnet_hdr_size(vnet_hdr_size)
             .map_err(Error::TapSetVnetHdrSize)?;
 
-        let mut avail_features = 1 << VIRTIO_NET_F_GUEST_CSUM
-            | 1 << VIRTIO_NET_F_CSUM
-            | 1 << VIRTIO_NET_F_GUEST_TSO4
-            | 1 << VIRTIO_NET_F_GUEST_UFO
-            | 1 << VIRTIO_NET_F_HOST_TSO4
-            | 1 << VIRTIO_NET_F_HOST_UFO
-            | 1 << VIRTIO_F_VERSION_1;
+        Self::new_with_backend(
+            id,
+            Box::new(tap),
+            guest_mac,
+            rx_rate_limiter,
+            tx_rate_limiter,
+        )
+    }
+
+    /// Create a new virtio network device backed by the user mode networking stack.
+    pub fn new_with_user_net(
+        id: String,
+        config: UserNetConfig,
+        guest_mac: Option<&MacAddr>,
+        rx_rate_limiter: RateLimiter,
+        tx_rate_limiter: RateLimiter,
+    ) -> Result<Self> {
+        let user_net = UserNet::new(config, guest_mac.copied()).map_err(Error::UserNet)?;
+
+        Self::new_with_backend(
+            id,
+            Box::new(user_net),
+            guest_mac,
+            rx_rate_limiter,
+            tx_rate_limiter,
+        )
+    }
+
+    /// Create a new virtio network device with the given backend.
+    pub fn new_with_backend(
+        id: String,
+        backend: Box<dyn NetBackend>,
+        guest_mac: Option<&MacAddr>,
+        rx_rate_limiter: RateLimiter,
+        tx_rate_limiter: RateLimiter,
+    ) -> Result<Self> {
+        let mut avail_features = 1 << VIRTIO_F_VERSION_1;
+        if backend.supports_offloads() {
+            avail_features |= 1 << VIRTIO_NET_F_GUEST_CSUM
+                | 1 << VIRTIO_NET_F_CSUM
+                | 1 << VIRTIO_NET_F_GUEST_TSO4
+                | 1 << VIRTIO_NET_F_GUEST_UFO
+                | 1 << VIRTIO_NET_F_HOST_TSO4
+                | 1 << VIRTIO_NET_F_HOST_UFO;
+        }
 
         let mut config_space = ConfigSpace::default();
         if let Some(mac) = guest_mac {
@@ -175,7 +218,7 @@ impl Net {
 
         Ok(Net {
             id,
-            tap,
+            backend,
             avail_features,
             acked_features: 0u64,
             queues,
@@ -193,6 +236,7 @@ impl Net {
             activate_evt: EventFd::new(libc::EFD_NONBLOCK).map_err(Error::EventFd)?,
             config_space,
             mmds_ns: None,
+            egress_filter: None,
             guest_mac: guest_mac.copied(),
 
             #[cfg(test)]
@@ -211,8 +255,18 @@ impl Net {
     }
 
     /// Provides the host IFACE name of this net device.
+    ///
+    /// The name is empty if the device is not backed by a host interface.
     pub fn iface_name(&self) -> String {
-        self.tap.if_name_as_str().to_string()
+        self.backend
+            .host_iface_name()
+            .unwrap_or_default()
+            .to_string()
+    }
+
+    /// Provides the user mode networking configuration of this net device, if any.
+    pub fn user_net_config(&self) -> Option<&UserNetConfig> {
+        self.backend.user_net_config()
     }
 
     /// Provides the MmdsNetworkStack of this net device.
@@ -221,12 +275,22 @@ impl Net {
     }
 
     /// Configures the `MmdsNetworkStack` to allow device to forward MMDS requests.
-    /// If the device already supports MMDS, updates the IPv4 address.
-    pub fn configure_mmds_network_stack(&mut self, ipv4_addr: Ipv4Addr, mmds: Arc<Mutex<Mmds>>) {
+    /// If the device already supports MMDS, updates the IPv4 address and the stack parameters.
+    pub fn configure_mmds_network_stack(
+        &mut self,
+        ipv4_addr: Ipv4Addr,
+        config: MmdsNetworkStackConfig,
+        mmds: Arc<Mutex<Mmds>>,
+    ) {
         if let Some(mmds_ns) = self.mmds_ns.as_mut() {
             mmds_ns.set_ipv4_addr(ipv4_addr);
+            mmds_ns.set_config(config);
         } else {
-            self.mmds_ns = Some(MmdsNetworkStack::new_with_defaults(Some(ipv4_addr), mmds))
+            self.mmds_ns = Some(MmdsNetworkStack::new_with_config(
+                Some(ipv4_addr),
+                config,
+                mmds,
+            ))
         }
     }
 
@@ -235,6 +299,16 @@ impl Net {
         self.mmds_ns = None
     }
 
+    /// Provides the egress filter of this net device.
+    pub fn egress_filter(&self) -> Option<&EgressFilter> {
+        self.egress_filter.as_ref()
+    }
+
+    /// Sets the filter applied to the frames the guest sends on the host TAP.
+    pub fn set_egress_filter(&mut self, egress_filter: Option<EgressFilter>) {
+        self.egress_filter = egress_filter;
+    }
+
     /// Provides a reference to the configured RX rate limiter.
     pub fn rx_rate_limiter(&self) -> &RateLimiter {
         &self.rx_rate_limiter
@@ -386,7 +460,8 @@ impl Net {
         false
     }
 
-    // Tries to detour the frame to MMDS and if MMDS doesn't accept it, sends it on the host TAP.
+    // Tries to detour the frame to MMDS and if MMDS doesn't accept it, sends it to the host
+    // backend. Frames rejected by the egress filter (if any) are dropped instead.
     //
     // `frame_buf` should contain the frame bytes in a slice of exact length.
     // Returns whether MMDS consumed the frame.
@@ -394,8 +469,9 @@ impl Net {
         mmds_ns: Option<&mut MmdsNetworkStack>,
         rate_limiter: &mut RateLimiter,
         frame_buf: &[u8],
-        tap: &mut Tap,
+        backend: &mut dyn NetBackend,
         guest_mac: Option<MacAddr>,
+        egress_filter: Option<&EgressFilter>,
     ) -> Result<bool> {
         let checked_frame = |frame_buf| {
             frame_bytes_from_buf(frame_buf).map_err(|e| {
@@ -417,7 +493,7 @@ impl Net {
             }
         }
 
-        // This frame goes to the TAP.
+        // This frame goes to the host backend.
 
         // Check for guest MAC spoofing.
         if let Some(mac) = guest_mac {
@@ -428,7 +504,14 @@ impl Net {
             });
         }
 
-        match tap.write(frame_buf) {
+        if let Some(filter) = egress_filter {
+            if let Err(reason) = filter.check_frame(checked_frame(frame_buf)?) {
+                reason.inc_metric();
+                return Ok(false);
+            }
+        }
+
+        match backend.write(frame_buf) {
             Ok(_) => {
                 METRICS.net.tx_bytes_count.add(frame_buf.len());
                 METRICS.net.tx_packets_count.inc();
@@ -601,8 +684,9 @@ impl Net {
                 self.mmds_ns.as_mut(),
                 &mut self.tx_rate_limiter,
                 &self.tx_frame_buf[..read_count],
-                &mut self.tap,
+                self.backend.as_mut(),
                 self.guest_mac,
+                self.egress_filter.as_ref(),
             )
             .unwrap_or(false);
             if frame_consumed_by_mmds && !self.rx_deferred_frame {
@@ -644,7 +728,7 @@ impl Net {
 
     #[cfg(not(test))]
     fn read_tap(&mut self) -> io::Result<usize> {
-        self.tap.read(&mut self.rx_frame_buf)
+        self.backend.read(&mut self.rx_frame_buf)
     }
 
     pub fn process_rx_queue_event(&mut self) {
@@ -877,7 +961,7 @@ pub mod tests {
                     io::ErrorKind::Other,
                     "Read tap synthetically failed.",
                 )),
-                ReadTapMock::TapFrame => self.tap.read(&mut self.rx_frame_buf),
+                ReadTapMock::TapFrame => self.backend.read(&mut self.rx_frame_buf),
             }
         }
     }
@@ -1203,7 +1287,7 @@ pub mod tests {
     fn test_tx_missing_queue_signal() {
         let mut th = TestHelper::default();
         th.activate_net();
-        let tap_traffic_simulator = TapTrafficSimulator::new(if_index(&th.net().tap));
+        let tap_traffic_simulator = TapTrafficSimulator::new(if_index(&th.net().iface_name()));
 
         th.add_desc_chain(NetQueue::Tx, 0, &[(0, 4096, 0)]);
         th.net().queue_evts[TX_INDEX].read().unwrap();
@@ -1223,7 +1307,7 @@ pub mod tests {
     fn test_tx_writeable_descriptor() {
         let mut th = TestHelper::default();
         th.activate_net();
-        let tap_traffic_simulator = TapTrafficSimulator::new(if_index(&th.net().tap));
+        let tap_traffic_simulator = TapTrafficSimulator::new(if_index(&th.net().iface_name()));
 
         let desc_list = [(0, 100, 0), (1, 100, VIRTQ_DESC_F_WRITE), (2, 500, 0)];
         th.add_desc_chain(NetQueue::Tx, 0, &desc_list);
@@ -1242,7 +1326,7 @@ pub mod tests {
     fn test_tx_short_frame() {
         let mut th = TestHelper::default();
         th.activate_net();
-        let tap_traffic_simulator = TapTrafficSimulator::new(if_index(&th.net().tap));
+        let tap_traffic_simulator = TapTrafficSimulator::new(if_index(&th.net().iface_name()));
 
         // Send an invalid frame (too small, VNET header missing).
         th.add_desc_chain(NetQueue::Tx, 0, &[(0, 1, 0)]);
@@ -1264,7 +1348,7 @@ pub mod tests {
     fn test_tx_partial_read() {
         let mut th = TestHelper::default();
         th.activate_net();
-        let tap_traffic_simulator = TapTrafficSimulator::new(if_index(&th.net().tap));
+        let tap_traffic_simulator = TapTrafficSimulator::new(if_index(&th.net().iface_name()));
 
         // The descriptor chain is created so that the last descriptor doesn't fit in the
         // guest memory.
@@ -1292,7 +1376,7 @@ pub mod tests {
     fn test_tx_retry() {
         let mut th = TestHelper::default();
         th.activate_net();
-        let tap_traffic_simulator = TapTrafficSimulator::new(if_index(&th.net().tap));
+        let tap_traffic_simulator = TapTrafficSimulator::new(if_index(&th.net().iface_name()));
 
         // Add invalid descriptor chain - writeable descriptor.
         th.add_desc_chain(
@@ -1332,7 +1416,7 @@ pub mod tests {
     fn test_tx_complex_descriptor() {
         let mut th = TestHelper::default();
         th.activate_net();
-        let tap_traffic_simulator = TapTrafficSimulator::new(if_index(&th.net().tap));
+        let tap_traffic_simulator = TapTrafficSimulator::new(if_index(&th.net().iface_name()));
 
         // Add gaps between the descriptor ids in order to ensure that we follow
         // the `next` field.
@@ -1360,7 +1444,7 @@ pub mod tests {
     fn test_tx_multiple_frame() {
         let mut th = TestHelper::default();
         th.activate_net();
-        let tap_traffic_simulator = TapTrafficSimulator::new(if_index(&th.net().tap));
+        let tap_traffic_simulator = TapTrafficSimulator::new(if_index(&th.net().iface_name()));
 
         // Write the first frame to the Tx queue
         let desc_list = [(0, 50, 0), (1, 100, 0), (2, 150, 0)];
@@ -1444,8 +1528,9 @@ pub mod tests {
                 net.mmds_ns.as_mut(),
                 &mut net.tx_rate_limiter,
                 &frame_buf[..frame_len],
-                &mut net.tap,
+                net.backend.as_mut(),
                 Some(src_mac),
+                None,
             )
             .unwrap())
         );
@@ -1478,8 +1563,9 @@ pub mod tests {
                 net.mmds_ns.as_mut(),
                 &mut net.tx_rate_limiter,
                 &frame_buf[..frame_len],
-                &mut net.tap,
+                net.backend.as_mut(),
                 Some(guest_mac),
+                None,
             )
         );
 
@@ -1491,8 +1577,72 @@ pub mod tests {
                 net.mmds_ns.as_mut(),
                 &mut net.tx_rate_limiter,
                 &frame_buf[..frame_len],
-                &mut net.tap,
+                net.backend.as_mut(),
                 Some(not_guest_mac),
+                None,
+            )
+        );
+    }
+
+    #[test]
+    fn test_egress_filter() {
+        let mut net = default_net();
+
+        let guest_mac = MacAddr::parse_str("11:11:11:11:11:11").unwrap();
+        let not_guest_mac = MacAddr::parse_str("33:33:33:33:33:33").unwrap();
+        let guest_ip = Ipv4Addr::new(10, 1, 2, 3);
+        let not_guest_ip = Ipv4Addr::new(10, 1, 2, 4);
+        let dst_mac = MacAddr::parse_str("22:22:22:22:22:22").unwrap();
+        let dst_ip = Ipv4Addr::new(10, 1, 1, 1);
+
+        net.set_egress_filter(Some(EgressFilter::new(
+            guest_mac,
+            vec![std::net::IpAddr::V4(guest_ip)],
+        )));
+        assert_eq!(net.egress_filter().unwrap().guest_mac(), &guest_mac);
+
+        // A legit frame goes to the TAP.
+        let (frame_buf, frame_len) = create_arp_request(guest_mac, guest_ip, dst_mac, dst_ip);
+        check_metric_after_block!(
+            &METRICS.net.tx_filter_spoofed_mac_drops,
+            0,
+            Net::write_to_mmds_or_tap(
+                net.mmds_ns.as_mut(),
+                &mut net.tx_rate_limiter,
+                &frame_buf[..frame_len],
+                net.backend.as_mut(),
+                Some(guest_mac),
+                net.egress_filter.as_ref(),
+            )
+        );
+
+        // A frame with a spoofed MAC is dropped.
+        let (frame_buf, frame_len) = create_arp_request(not_guest_mac, guest_ip, dst_mac, dst_ip);
+        check_metric_after_block!(
+            &METRICS.net.tx_filter_spoofed_mac_drops,
+            1,
+            Net::write_to_mmds_or_tap(
+                net.mmds_ns.as_mut(),
+                &mut net.tx_rate_limiter,
+                &frame_buf[..frame_len],
+                net.backend.as_mut(),
+                Some(guest_mac),
+                net.egress_filter.as_ref(),
+            )
+        );
+
+        // A frame with a spoofed IP is dropped.
+        let (frame_buf, frame_len) = create_arp_request(guest_mac, not_guest_ip, dst_mac, dst_ip);
+        check_metric_after_block!(
+            &METRICS.net.tx_filter_spoofed_ip_drops,
+            1,
+            Net::write_to_mmds_or_tap(
+                net.mmds_ns.as_mut(),
+                &mut net.tx_rate_limiter,
+                &frame_buf[..frame_len],
+                net.backend.as_mut(),
+                Some(guest_mac),
+                net.egress_filter.as_ref(),
             )
         );
     }
@@ -0,0 +1,469 @@
+// Copyright 2022 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Anti-spoofing filter applied to the frames transmitted by the guest.
+//!
+//! The filter only lets through frames which carry the configured guest MAC as source address
+//! and, for IPv4/IPv6 packets and ARP frames, one of the allowed IP addresses as source address.
+//! Frames originating from a DHCP/DHCPv6 server and IPv6 router advertisements are always
+//! dropped. All parsing is done using the `dumbo::pdu` helpers.
+
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+use dumbo::pdu::arp::EthIPv4ArpFrame;
+use dumbo::pdu::ethernet::{EthernetFrame, ETHERTYPE_ARP, ETHERTYPE_IPV4, ETHERTYPE_IPV6};
+use dumbo::pdu::ipv4::{IPv4Packet, PROTOCOL_UDP};
+use dumbo::pdu::ipv6::{IPv6Packet, PROTOCOL_ICMPV6};
+use dumbo::pdu::udp::UdpDatagram;
+use logger::{IncMetric, METRICS};
+use utils::net::mac::MacAddr;
+
+// Length of an IPv4 header without options.
+const IPV4_MIN_HEADER_LEN: usize = 20;
+
+const DHCP_SERVER_PORT: u16 = 67;
+const DHCP_CLIENT_PORT: u16 = 68;
+const DHCPV6_SERVER_PORT: u16 = 547;
+
+const ICMPV6_ROUTER_ADVERTISEMENT: u8 = 134;
+
+// IPv6 extension headers which may precede an ICMPv6 message, and which we know how to skip.
+const IPV6_EXT_HOP_BY_HOP: u8 = 0;
+const IPV6_EXT_ROUTING: u8 = 43;
+const IPV6_EXT_FRAGMENT: u8 = 44;
+const IPV6_EXT_DEST_OPTS: u8 = 60;
+
+/// The reason for which a frame was rejected by the filter.
+#[derive(Debug, PartialEq)]
+pub enum DropReason {
+    /// The source MAC address is not the guest MAC.
+    SpoofedMac,
+    /// The source IP address is not in the list of allowed addresses.
+    SpoofedIp,
+    /// The frame carries a DHCP or DHCPv6 server message.
+    DhcpServer,
+    /// The frame carries an ICMPv6 router advertisement.
+    RouterAdvertisement,
+    /// The frame is malformed or uses an unsupported ethertype.
+    Unsupported,
+}
+
+impl DropReason {
+    /// Increments the metric associated with this drop reason.
+    pub fn inc_metric(&self) {
+        match self {
+            DropReason::SpoofedMac => &METRICS.net.tx_filter_spoofed_mac_drops,
+            DropReason::SpoofedIp => &METRICS.net.tx_filter_spoofed_ip_drops,
+            DropReason::DhcpServer => &METRICS.net.tx_filter_dhcp_server_drops,
+            DropReason::RouterAdvertisement => &METRICS.net.tx_filter_router_adv_drops,
+            DropReason::Unsupported => &METRICS.net.tx_filter_unsupported_drops,
+        }
+        .inc();
+    }
+}
+
+/// L2/L3 egress filter for a network device.
+#[derive(Clone, Debug, PartialEq)]
+pub struct EgressFilter {
+    guest_mac: MacAddr,
+    allowed_ips: Vec<IpAddr>,
+}
+
+impl EgressFilter {
+    /// Creates a filter which only accepts frames sent from `guest_mac` and `allowed_ips`.
+    pub fn new(guest_mac: MacAddr, allowed_ips: Vec<IpAddr>) -> Self {
+        EgressFilter {
+            guest_mac,
+            allowed_ips,
+        }
+    }
+
+    /// Returns the MAC address enforced by the filter.
+    pub fn guest_mac(&self) -> &MacAddr {
+        &self.guest_mac
+    }
+
+    /// Returns the list of allowed source IP addresses.
+    pub fn allowed_ips(&self) -> &[IpAddr] {
+        &self.allowed_ips
+    }
+
+    /// Checks whether `frame` (an Ethernet frame, without the VNET header) may be sent on the
+    /// host interface.
+    pub fn check_frame(&self, frame: &[u8]) -> Result<(), DropReason> {
+        let eth_frame = EthernetFrame::from_bytes(frame).map_err(|_| DropReason::Unsupported)?;
+
+        if eth_frame.src_mac() != self.guest_mac {
+            return Err(DropReason::SpoofedMac);
+        }
+
+        match eth_frame.ethertype() {
+            ETHERTYPE_ARP => self.check_arp(eth_frame.payload()),
+            ETHERTYPE_IPV4 => self.check_ipv4(eth_frame.payload()),
+            ETHERTYPE_IPV6 => self.check_ipv6(eth_frame.payload()),
+            _ => Err(DropReason::Unsupported),
+        }
+    }
+
+    fn is_allowed(&self, addr: IpAddr) -> bool {
+        self.allowed_ips.contains(&addr)
+    }
+
+    fn check_arp(&self, payload: &[u8]) -> Result<(), DropReason> {
+        // Ethernet frames may be padded, so we only look at the ARP frame bytes.
+        let arp_bytes = payload
+            .get(..dumbo::ETH_IPV4_FRAME_LEN)
+            .ok_or(DropReason::Unsupported)?;
+        let arp_frame =
+            EthIPv4ArpFrame::from_bytes(arp_bytes).map_err(|_| DropReason::Unsupported)?;
+
+        if arp_frame.sha() != self.guest_mac {
+            return Err(DropReason::SpoofedMac);
+        }
+
+        // ARP probes use the unspecified address as sender address.
+        let spa = arp_frame.spa();
+        if spa != Ipv4Addr::UNSPECIFIED && !self.is_allowed(IpAddr::V4(spa)) {
+            return Err(DropReason::SpoofedIp);
+        }
+
+        Ok(())
+    }
+
+    fn check_ipv4(&self, payload: &[u8]) -> Result<(), DropReason> {
+        // Strip any Ethernet padding before handing the bytes to the strict IPv4 parser.
+        let total_len = IPv4Packet::from_bytes_unchecked(
+            payload
+                .get(..IPV4_MIN_HEADER_LEN)
+                .ok_or(DropReason::Unsupported)?,
+        )
+        .total_len() as usize;
+        let packet = IPv4Packet::from_bytes(
+            payload.get(..total_len).ok_or(DropReason::Unsupported)?,
+            false,
+        )
+        .map_err(|_| DropReason::Unsupported)?;
+
+        let udp_ports = if packet.protocol() == PROTOCOL_UDP {
+            let udp = UdpDatagram::from_bytes(packet.payload(), None)
+                .map_err(|_| DropReason::Unsupported)?;
+            Some((udp.source_port(), udp.destination_port()))
+        } else {
+            None
+        };
+
+        if let Some((DHCP_SERVER_PORT, _)) = udp_ports {
+            return Err(DropReason::DhcpServer);
+        }
+
+        let src_addr = packet.source_address();
+        if src_addr == Ipv4Addr::UNSPECIFIED {
+            // A DHCP client does not have an address yet.
+            if udp_ports == Some((DHCP_CLIENT_PORT, DHCP_SERVER_PORT)) {
+                return Ok(());
+            }
+            return Err(DropReason::SpoofedIp);
+        }
+
+        if !self.is_allowed(IpAddr::V4(src_addr)) {
+            return Err(DropReason::SpoofedIp);
+        }
+
+        Ok(())
+    }
+
+    fn check_ipv6(&self, payload: &[u8]) -> Result<(), DropReason> {
+        let packet = IPv6Packet::from_bytes(payload).map_err(|_| DropReason::Unsupported)?;
+        let (next_header, upper_layer) =
+            Self::skip_ipv6_ext_headers(packet.next_header(), packet.payload())?;
+
+        match next_header {
+            PROTOCOL_ICMPV6 => {
+                if upper_layer.first() == Some(&ICMPV6_ROUTER_ADVERTISEMENT) {
+                    return Err(DropReason::RouterAdvertisement);
+                }
+            }
+            PROTOCOL_UDP => {
+                let udp = UdpDatagram::from_bytes(upper_layer, None)
+                    .map_err(|_| DropReason::Unsupported)?;
+                if udp.source_port() == DHCPV6_SERVER_PORT {
+                    return Err(DropReason::DhcpServer);
+                }
+            }
+            _ => (),
+        }
+
+        let src_addr = packet.source_address();
+        // Duplicate address detection and MLD reports may be sent before an address is assigned.
+        if src_addr == Ipv6Addr::UNSPECIFIED && next_header == PROTOCOL_ICMPV6 {
+            return Ok(());
+        }
+
+        if !self.is_allowed(IpAddr::V6(src_addr)) {
+            return Err(DropReason::SpoofedIp);
+        }
+
+        Ok(())
+    }
+
+    // Walks the IPv6 extension header chain and returns the upper layer protocol together with
+    // its bytes. Fragmented packets are rejected, because the upper layer header might not be
+    // present in the first fragment, which would allow smuggling router advertisements.
+    fn skip_ipv6_ext_headers(
+        mut next_header: u8,
+        mut bytes: &[u8],
+    ) -> Result<(u8, &[u8]), DropReason> {
+        loop {
+            match next_header {
+                IPV6_EXT_HOP_BY_HOP | IPV6_EXT_ROUTING | IPV6_EXT_DEST_OPTS => {
+                    // The second byte holds the header length in 8-octet units, not including
+                    // the first 8 octets.
+                    let header_len = bytes
+                        .get(1)
+                        .map(|len| (usize::from(*len) + 1) * 8)
+                        .ok_or(DropReason::Unsupported)?;
+                    if bytes.len() < header_len {
+                        return Err(DropReason::Unsupported);
+                    }
+                    next_header = bytes[0];
+                    bytes = &bytes[header_len..];
+                }
+                IPV6_EXT_FRAGMENT => return Err(DropReason::Unsupported),
+                _ => return Ok((next_header, bytes)),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use dumbo::pdu::arp::{EthIPv4ArpFrame, ETH_IPV4_FRAME_LEN};
+    use dumbo::pdu::ethernet::PAYLOAD_OFFSET as ETH_PAYLOAD_OFFSET;
+    use dumbo::pdu::ipv4::PROTOCOL_TCP;
+
+    const GUEST_MAC: &str = "11:11:11:11:11:11";
+    const OTHER_MAC: &str = "33:33:33:33:33:33";
+
+    fn filter() -> EgressFilter {
+        EgressFilter::new(
+            MacAddr::parse_str(GUEST_MAC).unwrap(),
+            vec![
+                IpAddr::V4(Ipv4Addr::new(10, 0, 0, 2)),
+                IpAddr::V6(Ipv6Addr::new(0xfe80, 0, 0, 0, 0, 0, 0, 2)),
+            ],
+        )
+    }
+
+    fn eth_frame(src_mac: &str, ethertype: u16, payload: &[u8]) -> Vec<u8> {
+        let mut frame = vec![0u8; ETH_PAYLOAD_OFFSET + payload.len()];
+        let mut eth = EthernetFrame::write_incomplete(
+            frame.as_mut_slice(),
+            MacAddr::parse_str(OTHER_MAC).unwrap(),
+            MacAddr::parse_str(src_mac).unwrap(),
+            ethertype,
+        )
+        .unwrap();
+        eth.inner_mut().payload_mut().copy_from_slice(payload);
+        frame
+    }
+
+    fn ipv4_udp(src: Ipv4Addr, src_port: u16, dst_port: u16) -> Vec<u8> {
+        let mut packet = vec![0u8; 28];
+        packet[0] = 0x45;
+        packet[2..4].copy_from_slice(&28u16.to_be_bytes());
+        packet[9] = PROTOCOL_UDP;
+        packet[12..16].copy_from_slice(&src.octets());
+        packet[20..22].copy_from_slice(&src_port.to_be_bytes());
+        packet[22..24].copy_from_slice(&dst_port.to_be_bytes());
+        packet[24..26].copy_from_slice(&8u16.to_be_bytes());
+        packet
+    }
+
+    fn ipv6(src: Ipv6Addr, next_header: u8, payload: &[u8]) -> Vec<u8> {
+        let mut packet = vec![0u8; 40 + payload.len()];
+        packet[0] = 0x60;
+        packet[4..6].copy_from_slice(&(payload.len() as u16).to_be_bytes());
+        packet[6] = next_header;
+        packet[8..24].copy_from_slice(&src.octets());
+        packet[40..].copy_from_slice(payload);
+        packet
+    }
+
+    #[test]
+    fn test_mac_check() {
+        let f = filter();
+        let packet = ipv4_udp(Ipv4Addr::new(10, 0, 0, 2), 1234, 53);
+
+        assert_eq!(
+            f.check_frame(&eth_frame(GUEST_MAC, ETHERTYPE_IPV4, &packet)),
+            Ok(())
+        );
+        assert_eq!(
+            f.check_frame(&eth_frame(OTHER_MAC, ETHERTYPE_IPV4, &packet)),
+            Err(DropReason::SpoofedMac)
+        );
+        assert_eq!(
+            f.check_frame(&[0u8; ETH_PAYLOAD_OFFSET - 1]),
+            Err(DropReason::Unsupported)
+        );
+        assert_eq!(
+            f.check_frame(&eth_frame(GUEST_MAC, 0x1234, &packet)),
+            Err(DropReason::Unsupported)
+        );
+    }
+
+    #[test]
+    fn test_arp() {
+        let f = filter();
+        let guest_mac = MacAddr::parse_str(GUEST_MAC).unwrap();
+        let other_mac = MacAddr::parse_str(OTHER_MAC).unwrap();
+        let tpa = Ipv4Addr::new(10, 0, 0, 1);
+        let mut arp = [0u8; ETH_IPV4_FRAME_LEN];
+
+        for (sha, spa, expected) in vec![
+            (guest_mac, Ipv4Addr::new(10, 0, 0, 2), Ok(())),
+            (guest_mac, Ipv4Addr::UNSPECIFIED, Ok(())),
+            (
+                guest_mac,
+                Ipv4Addr::new(10, 0, 0, 3),
+                Err(DropReason::SpoofedIp),
+            ),
+            (
+                other_mac,
+                Ipv4Addr::new(10, 0, 0, 2),
+                Err(DropReason::SpoofedMac),
+            ),
+        ] {
+            EthIPv4ArpFrame::write_reply(arp.as_mut(), sha, spa, other_mac, tpa).unwrap();
+            // Pad the frame, as some guests do.
+            let mut payload = arp.to_vec();
+            payload.extend_from_slice(&[0u8; 18]);
+            assert_eq!(
+                f.check_frame(&eth_frame(GUEST_MAC, ETHERTYPE_ARP, &payload)),
+                expected
+            );
+        }
+
+        assert_eq!(
+            f.check_frame(&eth_frame(GUEST_MAC, ETHERTYPE_ARP, &arp[..10])),
+            Err(DropReason::Unsupported)
+        );
+    }
+
+    #[test]
+    fn test_ipv4() {
+        let f = filter();
+
+        // Spoofed source address.
+        let packet = ipv4_udp(Ipv4Addr::new(10, 0, 0, 3), 1234, 53);
+        assert_eq!(
+            f.check_frame(&eth_frame(GUEST_MAC, ETHERTYPE_IPV4, &packet)),
+            Err(DropReason::SpoofedIp)
+        );
+
+        // DHCP server traffic, even from an allowed address.
+        let packet = ipv4_udp(Ipv4Addr::new(10, 0, 0, 2), 67, 68);
+        assert_eq!(
+            f.check_frame(&eth_frame(GUEST_MAC, ETHERTYPE_IPV4, &packet)),
+            Err(DropReason::DhcpServer)
+        );
+
+        // DHCP client traffic from the unspecified address.
+        let packet = ipv4_udp(Ipv4Addr::UNSPECIFIED, 68, 67);
+        assert_eq!(
+            f.check_frame(&eth_frame(GUEST_MAC, ETHERTYPE_IPV4, &packet)),
+            Ok(())
+        );
+        let packet = ipv4_udp(Ipv4Addr::UNSPECIFIED, 1234, 53);
+        assert_eq!(
+            f.check_frame(&eth_frame(GUEST_MAC, ETHERTYPE_IPV4, &packet)),
+            Err(DropReason::SpoofedIp)
+        );
+
+        // Padded packets are accepted.
+        let mut packet = ipv4_udp(Ipv4Addr::new(10, 0, 0, 2), 1234, 53);
+        packet[9] = PROTOCOL_TCP;
+        packet.extend_from_slice(&[0u8; 18]);
+        assert_eq!(
+            f.check_frame(&eth_frame(GUEST_MAC, ETHERTYPE_IPV4, &packet)),
+            Ok(())
+        );
+
+        // Malformed packets are dropped.
+        let mut packet = ipv4_udp(Ipv4Addr::new(10, 0, 0, 2), 1234, 53);
+        packet[0] = 0x55;
+        assert_eq!(
+            f.check_frame(&eth_frame(GUEST_MAC, ETHERTYPE_IPV4, &packet)),
+            Err(DropReason::Unsupported)
+        );
+
+        // So are the ones with a truncated UDP header, which can't be checked for DHCP.
+        let mut packet = ipv4_udp(Ipv4Addr::new(10, 0, 0, 2), 67, 68);
+        packet.truncate(24);
+        packet[2..4].copy_from_slice(&24u16.to_be_bytes());
+        assert_eq!(
+            f.check_frame(&eth_frame(GUEST_MAC, ETHERTYPE_IPV4, &packet)),
+            Err(DropReason::Unsupported)
+        );
+    }
+
+    #[test]
+    fn test_ipv6() {
+        let f = filter();
+        let allowed = Ipv6Addr::new(0xfe80, 0, 0, 0, 0, 0, 0, 2);
+        let spoofed = Ipv6Addr::new(0xfe80, 0, 0, 0, 0, 0, 0, 3);
+        // ICMPv6 echo request.
+        let echo = [128u8, 0, 0, 0, 0, 0, 0, 0];
+        // ICMPv6 router advertisement.
+        let ra = [ICMPV6_ROUTER_ADVERTISEMENT, 0, 0, 0, 0, 0, 0, 0];
+
+        let packet = ipv6(allowed, PROTOCOL_ICMPV6, &echo);
+        assert_eq!(
+            f.check_frame(&eth_frame(GUEST_MAC, ETHERTYPE_IPV6, &packet)),
+            Ok(())
+        );
+        let packet = ipv6(spoofed, PROTOCOL_ICMPV6, &echo);
+        assert_eq!(
+            f.check_frame(&eth_frame(GUEST_MAC, ETHERTYPE_IPV6, &packet)),
+            Err(DropReason::SpoofedIp)
+        );
+        let packet = ipv6(Ipv6Addr::UNSPECIFIED, PROTOCOL_ICMPV6, &echo);
+        assert_eq!(
+            f.check_frame(&eth_frame(GUEST_MAC, ETHERTYPE_IPV6, &packet)),
+            Ok(())
+        );
+
+        let packet = ipv6(allowed, PROTOCOL_ICMPV6, &ra);
+        assert_eq!(
+            f.check_frame(&eth_frame(GUEST_MAC, ETHERTYPE_IPV6, &packet)),
+            Err(DropReason::RouterAdvertisement)
+        );
+
+        // Router advertisement hidden behind a hop-by-hop options header.
+        let mut payload = vec![PROTOCOL_ICMPV6, 0, 0, 0, 0, 0, 0, 0];
+        payload.extend_from_slice(&ra);
+        let packet = ipv6(allowed, IPV6_EXT_HOP_BY_HOP, &payload);
+        assert_eq!(
+            f.check_frame(&eth_frame(GUEST_MAC, ETHERTYPE_IPV6, &packet)),
+            Err(DropReason::RouterAdvertisement)
+        );
+
+        // Fragments are dropped.
+        let packet = ipv6(allowed, IPV6_EXT_FRAGMENT, &payload);
+        assert_eq!(
+            f.check_frame(&eth_frame(GUEST_MAC, ETHERTYPE_IPV6, &packet)),
+            Err(DropReason::Unsupported)
+        );
+
+        // DHCPv6 server traffic.
+        let mut udp = vec![0u8; 8];
+        udp[0..2].copy_from_slice(&DHCPV6_SERVER_PORT.to_be_bytes());
+        let packet = ipv6(allowed, PROTOCOL_UDP, &udp);
+        assert_eq!(
+            f.check_frame(&eth_frame(GUEST_MAC, ETHERTYPE_IPV6, &packet)),
+            Err(DropReason::DhcpServer)
+        );
+    }
+}
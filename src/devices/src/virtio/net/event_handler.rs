@@ -24,8 +24,8 @@ impl Net {
         if let Err(e) = ops.add(Events::new(&self.tx_rate_limiter, EventSet::IN)) {
             error!("Failed to register tx queue event: {}", e);
         }
-        if let Err(e) = ops.add(Events::new(
-            &self.tap,
+        if let Err(e) = ops.add(Events::new_raw(
+            self.backend.as_raw_fd(),
             EventSet::IN | EventSet::EDGE_TRIGGERED,
         )) {
             error!("Failed to register tap event: {}", e);
@@ -71,7 +71,7 @@ impl MutEventSubscriber for Net {
             let virtq_tx_ev_fd = self.queue_evts[TX_INDEX].as_raw_fd();
             let rx_rate_limiter_fd = self.rx_rate_limiter.as_raw_fd();
             let tx_rate_limiter_fd = self.tx_rate_limiter.as_raw_fd();
-            let tap_fd = self.tap.as_raw_fd();
+            let tap_fd = self.backend.as_raw_fd();
             let activate_fd = self.activate_evt.as_raw_fd();
 
             // Looks better than C style if/else if/else.
@@ -12,14 +12,20 @@ pub const RX_INDEX: usize = 0;
 // The index of the tx queue from Net device queues/queues_evts vector.
 pub const TX_INDEX: usize = 1;
 
+pub mod backend;
 pub mod device;
+pub mod egress_filter;
 pub mod event_handler;
 pub mod persist;
 mod tap;
 pub mod test_utils;
+pub mod user;
 
+pub use self::backend::NetBackend;
 pub use self::device::Net;
+pub use self::egress_filter::EgressFilter;
 pub use self::event_handler::*;
+pub use self::user::{Ipv4Network, PortForward, UserNet, UserNetConfig};
 pub use tap::Error as TapError;
 
 #[derive(Debug)]
@@ -38,6 +44,8 @@ pub enum Error {
     IO(io::Error),
     /// The VNET header is missing from the frame.
     VnetHeaderMissing,
+    /// Creating the user mode networking backend failed.
+    UserNet(user::Error),
 }
 
 pub type Result<T> = result::Result<T, Error>;
@@ -4,18 +4,21 @@
 //! Defines the structures needed for saving/restoring net devices.
 
 use std::io;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
 use std::sync::atomic::AtomicUsize;
 use std::sync::{Arc, Mutex};
 
 use mmds::{data_store::Mmds, ns::MmdsNetworkStack, persist::MmdsNetworkStackState};
-use rate_limiter::{persist::RateLimiterState, RateLimiter};
+use rate_limiter::{persist::RateLimiterState, RateLimiter, RateLimiterGroup};
 use snapshot::Persist;
 use utils::net::mac::{MacAddr, MAC_ADDR_LEN};
-use versionize::{VersionMap, Versionize, VersionizeResult};
+use versionize::{VersionMap, Versionize, VersionizeError, VersionizeResult};
 use versionize_derive::Versionize;
 use vm_memory::GuestMemoryMmap;
 
 use super::device::{ConfigSpace, Net};
+use super::egress_filter::EgressFilter;
+use super::user::{Ipv4Network, PortForward, UserNetConfig};
 use super::{NUM_QUEUES, QUEUE_SIZE};
 
 use crate::virtio::persist::{Error as VirtioStateError, VirtioDeviceState};
@@ -27,6 +30,37 @@ pub struct NetConfigSpaceState {
     guest_mac: [u8; MAC_ADDR_LEN],
 }
 
+#[derive(Clone, Versionize)]
+// NOTICE: Any changes to this structure require a snapshot version bump.
+pub struct EgressFilterState {
+    guest_mac: [u8; MAC_ADDR_LEN],
+    allowed_ips: Vec<String>,
+}
+
+#[derive(Clone, Versionize)]
+// NOTICE: Any changes to this structure require a snapshot version bump.
+pub struct PortForwardState {
+    host_addr: String,
+    guest_port: u16,
+}
+
+#[derive(Clone, Versionize)]
+// NOTICE: Any changes to this structure require a snapshot version bump.
+pub struct Ipv4NetworkState {
+    ip: String,
+    prefix_len: u8,
+}
+
+#[derive(Clone, Versionize)]
+// NOTICE: Any changes to this structure require a snapshot version bump.
+pub struct UserNetState {
+    gateway_ip: String,
+    guest_ip: String,
+    port_forwards: Vec<PortForwardState>,
+    host_loopback_ports: Vec<u16>,
+    allowed_destinations: Vec<Ipv4NetworkState>,
+}
+
 #[derive(Clone, Versionize)]
 // NOTICE: Any changes to this structure require a snapshot version bump.
 pub struct NetState {
@@ -37,11 +71,102 @@ pub struct NetState {
     pub mmds_ns: Option<MmdsNetworkStackState>,
     config_space: NetConfigSpaceState,
     virtio_state: VirtioDeviceState,
+    #[version(start = 2, ser_fn = "egress_filter_serialize")]
+    egress_filter: Option<EgressFilterState>,
+    #[version(start = 2, ser_fn = "user_net_serialize")]
+    user_net: Option<UserNetState>,
+}
+
+impl NetState {
+    fn egress_filter_serialize(&mut self, target_version: u16) -> VersionizeResult<()> {
+        // Silently dropping the filter would let the guest spoof traffic after restore.
+        if target_version < 2 && self.egress_filter.is_some() {
+            return Err(VersionizeError::Semantic(
+                "Target version does not implement the network egress filter.".to_owned(),
+            ));
+        }
+
+        Ok(())
+    }
+
+    fn user_net_serialize(&mut self, target_version: u16) -> VersionizeResult<()> {
+        if target_version < 2 && self.user_net.is_some() {
+            return Err(VersionizeError::Semantic(
+                "Target version does not implement user mode networking.".to_owned(),
+            ));
+        }
+
+        Ok(())
+    }
+}
+
+impl From<&UserNetConfig> for UserNetState {
+    fn from(config: &UserNetConfig) -> Self {
+        UserNetState {
+            gateway_ip: config.gateway_ip.to_string(),
+            guest_ip: config.guest_ip.to_string(),
+            port_forwards: config
+                .port_forwards
+                .iter()
+                .map(|forward| PortForwardState {
+                    host_addr: forward.host_addr.to_string(),
+                    guest_port: forward.guest_port,
+                })
+                .collect(),
+            host_loopback_ports: config.host_loopback_ports.clone(),
+            allowed_destinations: config
+                .allowed_destinations
+                .iter()
+                .map(|network| Ipv4NetworkState {
+                    ip: network.ip.to_string(),
+                    prefix_len: network.prefix_len,
+                })
+                .collect(),
+        }
+    }
+}
+
+impl UserNetState {
+    fn to_config(&self) -> std::result::Result<UserNetConfig, Error> {
+        let parse_ip = |ip: &String| {
+            ip.parse::<Ipv4Addr>()
+                .map_err(|_| Error::InvalidUserNet(ip.clone()))
+        };
+        let port_forwards = self
+            .port_forwards
+            .iter()
+            .map(|forward| {
+                Ok(PortForward {
+                    host_addr: forward
+                        .host_addr
+                        .parse::<SocketAddr>()
+                        .map_err(|_| Error::InvalidUserNet(forward.host_addr.clone()))?,
+                    guest_port: forward.guest_port,
+                })
+            })
+            .collect::<std::result::Result<Vec<_>, Error>>()?;
+        let allowed_destinations = self
+            .allowed_destinations
+            .iter()
+            .map(|network| Ok(Ipv4Network::new(parse_ip(&network.ip)?, network.prefix_len)))
+            .collect::<std::result::Result<Vec<_>, Error>>()?;
+
+        Ok(UserNetConfig {
+            gateway_ip: parse_ip(&self.gateway_ip)?,
+            guest_ip: parse_ip(&self.guest_ip)?,
+            port_forwards,
+            host_loopback_ports: self.host_loopback_ports.clone(),
+            allowed_destinations,
+        })
+    }
 }
 
 pub struct NetConstructorArgs {
     pub mem: GuestMemoryMmap,
     pub mmds: Option<Arc<Mutex<Mmds>>>,
+    /// The rate limiter groups the device was attached to, as groups are saved separately.
+    pub rx_rate_limiter_group: Option<RateLimiterGroup>,
+    pub tx_rate_limiter_group: Option<RateLimiterGroup>,
 }
 
 #[derive(Debug)]
@@ -50,6 +175,8 @@ pub enum Error {
     CreateRateLimiter(io::Error),
     VirtioState(VirtioStateError),
     NoMmdsDataStore,
+    InvalidEgressFilter(String),
+    InvalidUserNet(String),
 }
 
 impl Persist<'_> for Net {
@@ -68,6 +195,19 @@ impl Persist<'_> for Net {
                 guest_mac: self.config_space.guest_mac,
             },
             virtio_state: VirtioDeviceState::from_device(self),
+            egress_filter: self.egress_filter().map(|filter| {
+                let mut guest_mac = [0u8; MAC_ADDR_LEN];
+                guest_mac.copy_from_slice(filter.guest_mac().get_bytes());
+                EgressFilterState {
+                    guest_mac,
+                    allowed_ips: filter
+                        .allowed_ips()
+                        .iter()
+                        .map(|ip| ip.to_string())
+                        .collect(),
+                }
+            }),
+            user_net: self.user_net_config().map(UserNetState::from),
         }
     }
 
@@ -76,17 +216,30 @@ impl Persist<'_> for Net {
         state: &Self::State,
     ) -> std::result::Result<Self, Self::Error> {
         // RateLimiter::restore() can fail at creating a timerfd.
-        let rx_rate_limiter = RateLimiter::restore((), &state.rx_rate_limiter_state)
+        let mut rx_rate_limiter = RateLimiter::restore((), &state.rx_rate_limiter_state)
             .map_err(Error::CreateRateLimiter)?;
-        let tx_rate_limiter = RateLimiter::restore((), &state.tx_rate_limiter_state)
+        rx_rate_limiter.set_group(constructor_args.rx_rate_limiter_group);
+        let mut tx_rate_limiter = RateLimiter::restore((), &state.tx_rate_limiter_state)
             .map_err(Error::CreateRateLimiter)?;
-        let mut net = Net::new_with_tap(
-            state.id.clone(),
-            state.tap_if_name.clone(),
-            None,
-            rx_rate_limiter,
-            tx_rate_limiter,
-        )
+        tx_rate_limiter.set_group(constructor_args.tx_rate_limiter_group);
+        // Connections handled by the user mode networking backend are not saved, so the guest
+        // sees them being reset after restore.
+        let mut net = match &state.user_net {
+            Some(user_net_state) => Net::new_with_user_net(
+                state.id.clone(),
+                user_net_state.to_config()?,
+                None,
+                rx_rate_limiter,
+                tx_rate_limiter,
+            ),
+            None => Net::new_with_tap(
+                state.id.clone(),
+                state.tap_if_name.clone(),
+                None,
+                rx_rate_limiter,
+                tx_rate_limiter,
+            ),
+        }
         .map_err(Error::CreateNet)?;
 
         // We trust the MMIODeviceManager::restore to pass us an MMDS data store reference if
@@ -122,6 +275,21 @@ impl Persist<'_> for Net {
             &state.config_space.guest_mac[..MAC_ADDR_LEN],
         ));
 
+        if let Some(filter_state) = &state.egress_filter {
+            let allowed_ips = filter_state
+                .allowed_ips
+                .iter()
+                .map(|ip| {
+                    ip.parse::<IpAddr>()
+                        .map_err(|_| Error::InvalidEgressFilter(ip.clone()))
+                })
+                .collect::<std::result::Result<Vec<_>, _>>()?;
+            net.egress_filter = Some(EgressFilter::new(
+                MacAddr::from_bytes_unchecked(&filter_state.guest_mac[..MAC_ADDR_LEN]),
+                allowed_ips,
+            ));
+        }
+
         if state.virtio_state.activated {
             net.device_state = DeviceState::Activated(constructor_args.mem);
         }
@@ -172,6 +340,8 @@ mod tests {
                 NetConstructorArgs {
                     mem: guest_mem,
                     mmds: mmds_ds,
+                    rx_rate_limiter_group: None,
+                    tx_rate_limiter_group: None,
                 },
                 &NetState::deserialize(&mut mem.as_slice(), &version_map, 1).unwrap(),
             ) {
@@ -199,6 +369,94 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_egress_filter_persistence() {
+        let mut net = default_net_no_mmds();
+        let filter = EgressFilter::new(
+            MacAddr::parse_str("11:11:11:11:11:11").unwrap(),
+            vec!["10.0.0.2".parse().unwrap(), "fe80::2".parse().unwrap()],
+        );
+        net.set_egress_filter(Some(filter.clone()));
+
+        let mut mem = vec![0; 4096];
+        let mut version_map = VersionMap::new();
+        version_map
+            .new_version()
+            .set_type_version(NetState::type_id(), 2);
+
+        // The filter cannot be saved in a version which does not support it.
+        assert!(<Net as Persist>::save(&net)
+            .serialize(&mut mem.as_mut_slice(), &version_map, 1)
+            .is_err());
+
+        <Net as Persist>::save(&net)
+            .serialize(&mut mem.as_mut_slice(), &version_map, 2)
+            .unwrap();
+        drop(net);
+
+        let restored_net = Net::restore(
+            NetConstructorArgs {
+                mem: default_guest_memory(),
+                mmds: None,
+                rx_rate_limiter_group: None,
+                tx_rate_limiter_group: None,
+            },
+            &NetState::deserialize(&mut mem.as_slice(), &version_map, 2).unwrap(),
+        )
+        .unwrap();
+        assert_eq!(restored_net.egress_filter(), Some(&filter));
+    }
+
+    #[test]
+    fn test_user_net_persistence() {
+        let config = UserNetConfig {
+            port_forwards: vec![PortForward {
+                host_addr: "127.0.0.1:0".parse().unwrap(),
+                guest_port: 22,
+            }],
+            host_loopback_ports: vec![8080],
+            allowed_destinations: vec![Ipv4Network::new(Ipv4Addr::new(192, 168, 0, 0), 16)],
+            ..Default::default()
+        };
+        let net = Net::new_with_user_net(
+            "user-net".to_string(),
+            config.clone(),
+            None,
+            RateLimiter::default(),
+            RateLimiter::default(),
+        )
+        .unwrap();
+
+        let mut mem = vec![0; 4096];
+        let mut version_map = VersionMap::new();
+        version_map
+            .new_version()
+            .set_type_version(NetState::type_id(), 2);
+
+        // The backend cannot be saved in a version which does not support it.
+        assert!(<Net as Persist>::save(&net)
+            .serialize(&mut mem.as_mut_slice(), &version_map, 1)
+            .is_err());
+
+        <Net as Persist>::save(&net)
+            .serialize(&mut mem.as_mut_slice(), &version_map, 2)
+            .unwrap();
+        drop(net);
+
+        let restored_net = Net::restore(
+            NetConstructorArgs {
+                mem: default_guest_memory(),
+                mmds: None,
+                rx_rate_limiter_group: None,
+                tx_rate_limiter_group: None,
+            },
+            &NetState::deserialize(&mut mem.as_slice(), &version_map, 2).unwrap(),
+        )
+        .unwrap();
+        assert_eq!(restored_net.user_net_config(), Some(&config));
+        assert_eq!(restored_net.iface_name(), "");
+    }
+
     #[test]
     fn test_persistence() {
         let mmds = Some(Arc::new(Mutex::new(Mmds::default())));
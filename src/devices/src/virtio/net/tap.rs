@@ -50,7 +50,7 @@ pub struct Tap {
 
 // Returns a byte vector representing the contents of a null terminated C string which
 // contains if_name.
-fn build_terminated_if_name(if_name: &str) -> Result<[u8; IFACE_NAME_MAX_LEN]> {
+pub(crate) fn build_terminated_if_name(if_name: &str) -> Result<[u8; IFACE_NAME_MAX_LEN]> {
     // Convert the string slice to bytes, and shadow the variable,
     // since we no longer need the &str version.
     let if_name = if_name.as_bytes();
@@ -261,8 +261,8 @@ pub mod tests {
     #[test]
     fn test_read() {
         let mut tap = Tap::open_named("").unwrap();
-        enable(&tap);
-        let tap_traffic_simulator = TapTrafficSimulator::new(if_index(&tap));
+        enable(tap.if_name_as_str());
+        let tap_traffic_simulator = TapTrafficSimulator::new(if_index(tap.if_name_as_str()));
 
         let packet = utils::rand::rand_alphanumerics(PAYLOAD_SIZE);
         tap_traffic_simulator.push_tx_packet(packet.as_bytes());
@@ -278,8 +278,8 @@ pub mod tests {
     #[test]
     fn test_write() {
         let mut tap = Tap::open_named("").unwrap();
-        enable(&tap);
-        let tap_traffic_simulator = TapTrafficSimulator::new(if_index(&tap));
+        enable(tap.if_name_as_str());
+        let tap_traffic_simulator = TapTrafficSimulator::new(if_index(tap.if_name_as_str()));
 
         let mut packet = [0u8; PACKET_SIZE];
         let payload = utils::rand::rand_alphanumerics(PAYLOAD_SIZE);
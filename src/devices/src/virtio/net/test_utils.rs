@@ -14,11 +14,11 @@ use std::{mem, result};
 
 #[cfg(test)]
 use crate::virtio::net::device::vnet_hdr_len;
-use crate::virtio::net::tap::{Error, IfReqBuilder, Tap};
+use crate::virtio::net::tap::{build_terminated_if_name, Error, IfReqBuilder};
 use crate::virtio::test_utils::VirtQueue;
 use crate::virtio::{Net, Queue, QueueError};
 use mmds::data_store::Mmds;
-use mmds::ns::MmdsNetworkStack;
+use mmds::ns::{MmdsNetworkStack, MmdsNetworkStackConfig};
 
 use rate_limiter::RateLimiter;
 use vm_memory::{GuestAddress, GuestMemoryMmap};
@@ -47,9 +47,10 @@ pub fn default_net() -> Net {
     .unwrap();
     net.configure_mmds_network_stack(
         MmdsNetworkStack::default_ipv4_addr(),
+        MmdsNetworkStackConfig::default(),
         Arc::new(Mutex::new(Mmds::default())),
     );
-    enable(&net.tap);
+    enable(&net.iface_name());
 
     net
 }
@@ -68,7 +69,7 @@ pub fn default_net_no_mmds() -> Net {
         RateLimiter::default(),
     )
     .unwrap();
-    enable(&net.tap);
+    enable(&net.iface_name());
 
     net
 }
@@ -224,10 +225,10 @@ pub fn virtqueues(mem: &GuestMemoryMmap) -> (VirtQueue, VirtQueue) {
     (rxq, txq)
 }
 
-pub fn if_index(tap: &Tap) -> i32 {
+pub fn if_index(if_name: &str) -> i32 {
     let sock = create_socket();
     let ifreq = IfReqBuilder::new()
-        .if_name(&tap.if_name)
+        .if_name(&build_terminated_if_name(if_name).unwrap())
         .execute(&sock, c_ulong::from(net_gen::sockios::SIOCGIFINDEX))
         .unwrap();
 
@@ -235,20 +236,20 @@ pub fn if_index(tap: &Tap) -> i32 {
 }
 
 /// Enable the tap interface.
-pub fn enable(tap: &Tap) {
+pub fn enable(if_name: &str) {
     // Disable IPv6 router advertisment requests
     Command::new("sh")
         .arg("-c")
         .arg(format!(
             "echo 0 > /proc/sys/net/ipv6/conf/{}/accept_ra",
-            tap.if_name_as_str()
+            if_name
         ))
         .output()
         .unwrap();
 
     let sock = create_socket();
     IfReqBuilder::new()
-        .if_name(&tap.if_name)
+        .if_name(&build_terminated_if_name(if_name).unwrap())
         .flags(
             (net_gen::net_device_flags_IFF_UP
                 | net_gen::net_device_flags_IFF_RUNNING
@@ -261,7 +262,7 @@ pub fn enable(tap: &Tap) {
 #[cfg(test)]
 pub(crate) fn inject_tap_tx_frame(net: &Net, len: usize) -> Vec<u8> {
     assert!(len >= vnet_hdr_len());
-    let tap_traffic_simulator = TapTrafficSimulator::new(if_index(&net.tap));
+    let tap_traffic_simulator = TapTrafficSimulator::new(if_index(&net.iface_name()));
     let mut frame = utils::rand::rand_alphanumerics(len - vnet_hdr_len())
         .as_bytes()
         .to_vec();
@@ -0,0 +1,1389 @@
+// Copyright 2022 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! User mode networking backend, which does not require a host tap device.
+//!
+//! The guest sits on a point-to-point Ethernet link with a virtual gateway. ARP requests for any
+//! address other than the guest one are answered with the gateway MAC. The guest TCP connections
+//! are terminated by the backend (using the `dumbo` TCP implementation) and re-originated from
+//! the Firecracker process through regular host sockets, while UDP datagrams are relayed through
+//! one host socket per flow. Traffic directed to the gateway address is sent to the host
+//! loopback interface, but only for the ports explicitly allowed in the configuration. The host
+//! addresses, as well as private and link-local networks, can only be reached when allowed in the
+//! configuration. Host TCP ports can also be forwarded to guest ports.
+//!
+//! Only IPv4 is supported. ICMP and fragmented packets are dropped.
+
+mod tcp;
+mod udp;
+
+use std::cmp::min;
+use std::collections::{HashMap, VecDeque};
+use std::io::{self, Read, Write};
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4, TcpListener};
+use std::num::Wrapping;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::time::Duration;
+use std::{fmt, result};
+
+use dumbo::pdu::arp::{EthIPv4ArpFrame, ETH_IPV4_FRAME_LEN};
+use dumbo::pdu::bytes::NetworkBytes;
+use dumbo::pdu::ethernet::{EthernetFrame, ETHERTYPE_ARP, ETHERTYPE_IPV4};
+use dumbo::pdu::ipv4::{IPv4Packet, PROTOCOL_TCP, PROTOCOL_UDP};
+use dumbo::pdu::tcp::{Flags as TcpFlags, TcpSegment};
+use dumbo::pdu::udp::{UdpDatagram, UDP_HEADER_SIZE};
+use dumbo::tcp::RstConfig;
+use logger::{warn, IncMetric, METRICS};
+use timerfd::{ClockId, SetTimeFlags, TimerFd, TimerState};
+use utils::epoll::{ControlOperation, Epoll, EpollEvent, EventSet};
+use utils::eventfd::EventFd;
+use utils::net::mac::MacAddr;
+use utils::time::{get_time_us, ClockType};
+
+use self::tcp::TcpProxy;
+use self::udp::UdpFlow;
+use crate::virtio::net::device::vnet_hdr_len;
+
+/// The MAC address of the virtual gateway.
+pub const GATEWAY_MAC: [u8; 6] = [0x52, 0x55, 0x0a, 0x00, 0x02, 0x02];
+/// The default IPv4 address of the virtual gateway.
+pub const DEFAULT_GATEWAY_IP: Ipv4Addr = Ipv4Addr::new(10, 0, 2, 2);
+/// The default IPv4 address of the guest.
+pub const DEFAULT_GUEST_IP: Ipv4Addr = Ipv4Addr::new(10, 0, 2, 15);
+
+// Maximum length of the Ethernet frames sent to the guest (standard MTU plus header).
+const MAX_FRAME_LEN: usize = 1514;
+// Maximum number of frames waiting to be read by the device.
+const MAX_QUEUED_FRAMES: usize = 256;
+const MAX_TCP_CONNECTIONS: usize = 1024;
+const MAX_UDP_FLOWS: usize = 256;
+// Largest UDP payload which fits in a frame sent to the guest.
+const MAX_UDP_PAYLOAD_LEN: usize = 1472;
+// Length of an IPv4 header without options.
+const IPV4_MIN_HEADER_LEN: usize = 20;
+// The "more fragments" IPv4 flag.
+const IPV4_FLAG_MF: u8 = 0b001;
+// Connections forwarded from the host appear to come from the gateway, using a source port
+// from the dynamic range.
+const FORWARD_PORT_MIN: u16 = 49152;
+// Drives TCP retransmissions and UDP flow expiration while there are active flows.
+const TIMER_PERIOD: Duration = Duration::from_millis(100);
+const EPOLL_EVENTS_LEN: usize = 32;
+// Networks which the guest can only reach when they're explicitly allowed: "this" network, the
+// private networks (RFC 1918), the shared address space (RFC 6598) and link-local addresses
+// (which include cloud metadata services).
+const RESTRICTED_NETWORKS: [Ipv4Network; 6] = [
+    Ipv4Network::new(Ipv4Addr::new(0, 0, 0, 0), 8),
+    Ipv4Network::new(Ipv4Addr::new(10, 0, 0, 0), 8),
+    Ipv4Network::new(Ipv4Addr::new(100, 64, 0, 0), 10),
+    Ipv4Network::new(Ipv4Addr::new(169, 254, 0, 0), 16),
+    Ipv4Network::new(Ipv4Addr::new(172, 16, 0, 0), 12),
+    Ipv4Network::new(Ipv4Addr::new(192, 168, 0, 0), 16),
+];
+
+/// Errors associated with the user mode networking backend.
+#[derive(Debug)]
+pub enum Error {
+    /// Failed to bind the host socket of a port forward.
+    BindPortForward(SocketAddr, io::Error),
+    /// Failed to create the epoll fd.
+    EpollCreate(io::Error),
+    /// Failed to register a file descriptor with epoll.
+    EpollAdd(io::Error),
+    /// EventFd error.
+    EventFd(io::Error),
+    /// Failed to create the timer.
+    TimerFd(io::Error),
+    /// An allowed destination has a prefix length larger than 32.
+    InvalidAllowedDestination(Ipv4Network),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::Error::*;
+
+        match self {
+            BindPortForward(addr, e) => write!(f, "Cannot bind port forward to {}: {}", addr, e),
+            EpollCreate(e) => write!(f, "Cannot create epoll fd: {}", e),
+            EpollAdd(e) => write!(f, "Cannot add fd to epoll: {}", e),
+            EventFd(e) => write!(f, "EventFd error: {}", e),
+            TimerFd(e) => write!(f, "Cannot create timer: {}", e),
+            InvalidAllowedDestination(network) => write!(
+                f,
+                "Invalid allowed destination {}/{}",
+                network.ip, network.prefix_len
+            ),
+        }
+    }
+}
+
+type Result<T> = result::Result<T, Error>;
+
+/// Forwards connections accepted on a host address to a guest TCP port.
+#[derive(Clone, Debug, PartialEq)]
+pub struct PortForward {
+    /// The host address to listen on.
+    pub host_addr: SocketAddr,
+    /// The guest port which receives the connections.
+    pub guest_port: u16,
+}
+
+/// A range of IPv4 addresses, given by a network address and a prefix length.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Ipv4Network {
+    /// The network address. Only its first `prefix_len` bits are relevant.
+    pub ip: Ipv4Addr,
+    /// The length of the network prefix, in bits.
+    pub prefix_len: u8,
+}
+
+impl Ipv4Network {
+    /// Creates a new network from an address and a prefix length.
+    pub const fn new(ip: Ipv4Addr, prefix_len: u8) -> Self {
+        Ipv4Network { ip, prefix_len }
+    }
+
+    /// Returns `true` if `ip` belongs to the network.
+    pub fn contains(&self, ip: Ipv4Addr) -> bool {
+        let mask = u32::MAX
+            .checked_shl(32 - u32::from(min(self.prefix_len, 32)))
+            .unwrap_or(0);
+        u32::from(ip) & mask == u32::from(self.ip) & mask
+    }
+}
+
+/// Configuration of the user mode networking backend.
+#[derive(Clone, Debug, PartialEq)]
+pub struct UserNetConfig {
+    /// The address of the virtual gateway.
+    pub gateway_ip: Ipv4Addr,
+    /// The address the guest is expected to use.
+    pub guest_ip: Ipv4Addr,
+    /// Host TCP ports forwarded to the guest.
+    pub port_forwards: Vec<PortForward>,
+    /// Host loopback ports the guest can reach through the gateway address. Any other traffic
+    /// sent to the gateway is refused.
+    pub host_loopback_ports: Vec<u16>,
+    /// Networks the guest can reach in addition to the public addresses. The host addresses,
+    /// private and link-local networks are refused unless covered by one of these.
+    pub allowed_destinations: Vec<Ipv4Network>,
+}
+
+impl Default for UserNetConfig {
+    fn default() -> Self {
+        UserNetConfig {
+            gateway_ip: DEFAULT_GATEWAY_IP,
+            guest_ip: DEFAULT_GUEST_IP,
+            port_forwards: Vec::new(),
+            host_loopback_ports: Vec::new(),
+            allowed_destinations: Vec::new(),
+        }
+    }
+}
+
+/// Identifies a TCP connection or UDP flow, from the point of view of the guest.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub(crate) struct FlowKey {
+    guest_port: u16,
+    // The remote address, as seen by the guest.
+    remote: SocketAddrV4,
+}
+
+/// An epoll listener, registered under the backend's epoll fd.
+#[derive(Clone, Copy)]
+enum EpollListener {
+    /// The host socket of the port forward with the given index.
+    PortForward(usize),
+    /// The host socket of a TCP connection.
+    Tcp(FlowKey),
+    /// The host socket of a UDP flow.
+    Udp(FlowKey),
+    /// The periodic timer.
+    Timer,
+    /// Frames were queued outside of a read.
+    Wakeup,
+}
+
+/// The frames waiting to be read by the device.
+pub(crate) struct FrameQueue {
+    frames: VecDeque<Vec<u8>>,
+    // Makes the backend fd readable when frames become available outside of a read.
+    wakeup_evt: EventFd,
+    // The destination of the frames sent to the guest.
+    guest_mac: Option<MacAddr>,
+}
+
+impl FrameQueue {
+    fn is_full(&self) -> bool {
+        self.frames.len() >= MAX_QUEUED_FRAMES
+    }
+
+    /// Queues an Ethernet frame with the given ethertype, sent by the gateway. The payload is
+    /// written by `f`, which returns its length.
+    ///
+    /// Returns `false` if the frame could not be queued, in which case `f` may not be called.
+    fn push_eth<F>(&mut self, ethertype: u16, f: F) -> bool
+    where
+        F: FnOnce(&mut [u8]) -> Option<usize>,
+    {
+        let guest_mac = match self.guest_mac {
+            Some(mac) => mac,
+            None => return false,
+        };
+        if self.is_full() {
+            return false;
+        }
+
+        // The VNET header is left zeroed.
+        let mut buf = vec![0u8; vnet_hdr_len() + MAX_FRAME_LEN];
+        let len = {
+            let gateway_mac = MacAddr::from_bytes_unchecked(&GATEWAY_MAC);
+            let mut frame = match EthernetFrame::write_incomplete(
+                &mut buf[vnet_hdr_len()..],
+                guest_mac,
+                gateway_mac,
+                ethertype,
+            ) {
+                Ok(frame) => frame,
+                Err(_) => return false,
+            };
+            match f(frame.inner_mut().payload_mut()) {
+                Some(len) => frame.with_payload_len_unchecked(len).len(),
+                None => return false,
+            }
+        };
+        buf.truncate(vnet_hdr_len() + len);
+
+        if self.frames.is_empty() {
+            if let Err(e) = self.wakeup_evt.write(1) {
+                warn!("user net: failed to signal queued frames: {:?}", e);
+            }
+        }
+        self.frames.push_back(buf);
+        true
+    }
+
+    /// Queues an IPv4 packet with the given addresses and protocol. The payload is written by
+    /// `f`, which returns its length.
+    fn push_ipv4<F>(&mut self, src: Ipv4Addr, dst: Ipv4Addr, protocol: u8, f: F) -> bool
+    where
+        F: FnOnce(&mut [u8]) -> Option<usize>,
+    {
+        self.push_eth(ETHERTYPE_IPV4, |buf| {
+            let mut packet = IPv4Packet::write_header(buf, protocol, src, dst).ok()?;
+            let len = f(packet.inner_mut().payload_mut())?;
+            Some(packet.with_payload_len_unchecked(len, true).len())
+        })
+    }
+}
+
+/// User mode networking backend.
+pub struct UserNet {
+    config: UserNetConfig,
+    epoll: Epoll,
+    listeners: HashMap<RawFd, EpollListener>,
+    timer: TimerFd,
+    timer_armed: bool,
+    frames: FrameQueue,
+    port_forwards: Vec<TcpListener>,
+    tcp: HashMap<FlowKey, TcpProxy>,
+    udp: HashMap<FlowKey, UdpFlow>,
+    next_forward_port: u16,
+    // Scratch buffer for the datagrams received on UDP sockets.
+    host_buf: Vec<u8>,
+}
+
+impl UserNet {
+    /// Creates a new backend, binding the host sockets of all port forwards.
+    ///
+    /// Until the guest sends a frame, replies are directed to `guest_mac` (if present).
+    pub fn new(config: UserNetConfig, guest_mac: Option<MacAddr>) -> Result<Self> {
+        if let Some(network) = config
+            .allowed_destinations
+            .iter()
+            .find(|network| network.prefix_len > 32)
+        {
+            return Err(Error::InvalidAllowedDestination(*network));
+        }
+
+        let mut port_forwards = Vec::with_capacity(config.port_forwards.len());
+        for forward in config.port_forwards.iter() {
+            let listener = TcpListener::bind(forward.host_addr)
+                .and_then(|listener| listener.set_nonblocking(true).map(|_| listener))
+                .map_err(|e| Error::BindPortForward(forward.host_addr, e))?;
+            port_forwards.push(listener);
+        }
+
+        let mut user_net = UserNet {
+            config,
+            epoll: Epoll::new().map_err(Error::EpollCreate)?,
+            listeners: HashMap::new(),
+            timer: TimerFd::new_custom(ClockId::Monotonic, true, true).map_err(Error::TimerFd)?,
+            timer_armed: false,
+            frames: FrameQueue {
+                frames: VecDeque::new(),
+                wakeup_evt: EventFd::new(libc::EFD_NONBLOCK).map_err(Error::EventFd)?,
+                guest_mac,
+            },
+            port_forwards,
+            tcp: HashMap::new(),
+            udp: HashMap::new(),
+            next_forward_port: FORWARD_PORT_MIN,
+            host_buf: vec![0u8; u16::MAX as usize],
+        };
+
+        user_net.add_listener(
+            user_net.frames.wakeup_evt.as_raw_fd(),
+            EpollListener::Wakeup,
+        )?;
+        user_net.add_listener(user_net.timer.as_raw_fd(), EpollListener::Timer)?;
+        let forward_fds: Vec<RawFd> = user_net
+            .port_forwards
+            .iter()
+            .map(|listener| listener.as_raw_fd())
+            .collect();
+        for (idx, fd) in forward_fds.into_iter().enumerate() {
+            user_net.add_listener(fd, EpollListener::PortForward(idx))?;
+        }
+
+        Ok(user_net)
+    }
+
+    /// Returns the configuration of the backend.
+    pub fn config(&self) -> &UserNetConfig {
+        &self.config
+    }
+
+    fn add_listener(&mut self, fd: RawFd, listener: EpollListener) -> Result<()> {
+        self.epoll
+            .ctl(
+                ControlOperation::Add,
+                fd,
+                EpollEvent::new(EventSet::IN, fd as u64),
+            )
+            .map_err(Error::EpollAdd)?;
+        self.listeners.insert(fd, listener);
+        Ok(())
+    }
+
+    fn remove_listener(&mut self, fd: RawFd) {
+        if self.listeners.remove(&fd).is_some() {
+            if let Err(e) = self
+                .epoll
+                .ctl(ControlOperation::Delete, fd, EpollEvent::default())
+            {
+                warn!("user net: failed to remove epoll listener: {:?}", e);
+            }
+        }
+    }
+
+    // Brings the epoll registration of a TCP socket in line with the events it's interested in.
+    fn update_tcp_listener(&mut self, fd: RawFd, key: FlowKey, old: EventSet, new: EventSet) {
+        if old == new {
+            return;
+        }
+
+        let op = if old.is_empty() {
+            ControlOperation::Add
+        } else if new.is_empty() {
+            ControlOperation::Delete
+        } else {
+            ControlOperation::Modify
+        };
+        if let Err(e) = self.epoll.ctl(op, fd, EpollEvent::new(new, fd as u64)) {
+            warn!("user net: failed to update epoll listener: {:?}", e);
+            METRICS.net.user_socket_fails.inc();
+        }
+
+        if new.is_empty() {
+            self.listeners.remove(&fd);
+        } else {
+            self.listeners.insert(fd, EpollListener::Tcp(key));
+        }
+    }
+
+    // Keeps the timer running only while there are active flows.
+    fn update_timer(&mut self) {
+        let needed = !self.tcp.is_empty() || !self.udp.is_empty();
+        if needed == self.timer_armed {
+            return;
+        }
+
+        let state = if needed {
+            TimerState::Periodic {
+                current: TIMER_PERIOD,
+                interval: TIMER_PERIOD,
+            }
+        } else {
+            TimerState::Disarmed
+        };
+        self.timer.set_state(state, SetTimeFlags::Default);
+        self.timer_armed = needed;
+    }
+
+    // Returns the host address which corresponds to an address seen by the guest, if the guest
+    // is allowed to reach it. The host loopback services are only exposed on the allowed ports,
+    // while the other host addresses, private and link-local networks have to be allowed
+    // explicitly.
+    fn host_addr(&self, remote: SocketAddrV4) -> Option<SocketAddrV4> {
+        let ip = *remote.ip();
+        if ip == self.config.gateway_ip {
+            return if self.config.host_loopback_ports.contains(&remote.port()) {
+                Some(SocketAddrV4::new(Ipv4Addr::LOCALHOST, remote.port()))
+            } else {
+                None
+            };
+        }
+
+        if self
+            .config
+            .allowed_destinations
+            .iter()
+            .any(|network| network.contains(ip))
+        {
+            return Some(remote);
+        }
+        // Destinations which can't be checked are refused.
+        let restricted = RESTRICTED_NETWORKS
+            .iter()
+            .any(|network| network.contains(ip))
+            || udp::is_host_addr(ip).unwrap_or(true);
+        if restricted {
+            None
+        } else {
+            Some(remote)
+        }
+    }
+
+    fn handle_frame(&mut self, frame: &[u8]) {
+        let handled = match EthernetFrame::from_bytes(frame) {
+            Ok(eth_frame) => {
+                // Replies are sent to the MAC address last used by the guest.
+                self.frames.guest_mac = Some(eth_frame.src_mac());
+                match eth_frame.ethertype() {
+                    ETHERTYPE_ARP => self.handle_arp(eth_frame.payload()),
+                    ETHERTYPE_IPV4 => self.handle_ipv4(eth_frame.payload()),
+                    _ => false,
+                }
+            }
+            Err(_) => false,
+        };
+
+        if !handled {
+            METRICS.net.user_dropped_frames.inc();
+        }
+    }
+
+    fn handle_arp(&mut self, payload: &[u8]) -> bool {
+        // Ethernet frames may be padded, so we only look at the ARP frame bytes.
+        let arp_frame = match payload
+            .get(..ETH_IPV4_FRAME_LEN)
+            .and_then(|bytes| EthIPv4ArpFrame::request_from_bytes(bytes).ok())
+        {
+            Some(arp_frame) => arp_frame,
+            None => return false,
+        };
+
+        // The gateway answers for every address except the guest one, so all traffic is
+        // directed to it.
+        let tpa = arp_frame.tpa();
+        if tpa != self.config.guest_ip {
+            let (sha, spa) = (arp_frame.sha(), arp_frame.spa());
+            let gateway_mac = MacAddr::from_bytes_unchecked(&GATEWAY_MAC);
+            self.frames.push_eth(ETHERTYPE_ARP, |buf| {
+                EthIPv4ArpFrame::write_reply(
+                    buf.get_mut(..ETH_IPV4_FRAME_LEN)?,
+                    gateway_mac,
+                    tpa,
+                    sha,
+                    spa,
+                )
+                .ok()
+                .map(|reply| reply.len())
+            });
+        }
+        true
+    }
+
+    fn handle_ipv4(&mut self, payload: &[u8]) -> bool {
+        if payload.len() < IPV4_MIN_HEADER_LEN {
+            return false;
+        }
+        // Ethernet frames may be padded, so we only look at the bytes covered by the packet.
+        let total_len = IPv4Packet::from_bytes_unchecked(payload).total_len() as usize;
+        let packet = match payload
+            .get(..total_len)
+            .and_then(|bytes| IPv4Packet::from_bytes(bytes, true).ok())
+        {
+            Some(packet) => packet,
+            None => return false,
+        };
+
+        if packet.source_address() != self.config.guest_ip {
+            return false;
+        }
+        let (flags, fragment_offset) = packet.flags_and_fragment_offset();
+        if flags & IPV4_FLAG_MF != 0 || fragment_offset != 0 {
+            return false;
+        }
+        let dst = packet.destination_address();
+        // The host loopback interface is only reachable through the gateway address.
+        if dst.is_broadcast() || dst.is_multicast() || dst.is_unspecified() || dst.is_loopback() {
+            return false;
+        }
+
+        match packet.protocol() {
+            PROTOCOL_TCP => self.handle_tcp(dst, packet.payload()),
+            PROTOCOL_UDP => self.handle_udp(dst, packet.payload()),
+            _ => false,
+        }
+    }
+
+    fn handle_tcp(&mut self, dst: Ipv4Addr, bytes: &[u8]) -> bool {
+        let segment = match TcpSegment::from_bytes(bytes, Some((self.config.guest_ip, dst))) {
+            Ok(segment) => segment,
+            Err(_) => return false,
+        };
+        let key = FlowKey {
+            guest_port: segment.source_port(),
+            remote: SocketAddrV4::new(dst, segment.destination_port()),
+        };
+        let now = get_time_us(ClockType::Monotonic);
+
+        if let Some(proxy) = self.tcp.get_mut(&key) {
+            proxy.receive_segment(&segment, now);
+        } else if segment.flags_after_ns() == TcpFlags::SYN {
+            self.open_tcp(key, &segment);
+        } else if !segment.flags_after_ns().intersects(TcpFlags::RST) {
+            self.push_rst(key, RstConfig::new(&segment));
+        }
+
+        self.service_tcp(key, now);
+        true
+    }
+
+    // Starts connecting to the host address corresponding to a SYN sent by the guest.
+    fn open_tcp<T: NetworkBytes>(&mut self, key: FlowKey, syn: &TcpSegment<T>) {
+        let proxy = if self.tcp.len() < MAX_TCP_CONNECTIONS {
+            self.host_addr(key.remote)
+                .and_then(|addr| tcp::connect(addr).ok())
+                .and_then(|stream| TcpProxy::passive_open(syn, stream).ok())
+        } else {
+            None
+        };
+
+        match proxy {
+            Some(proxy) => {
+                METRICS.net.user_tcp_connections.inc();
+                self.tcp.insert(key, proxy);
+                self.update_timer();
+            }
+            None => {
+                METRICS.net.user_tcp_connect_fails.inc();
+                let ack = Wrapping(syn.sequence_number()) + Wrapping(1);
+                self.push_rst(key, RstConfig::Ack(ack.0));
+            }
+        }
+    }
+
+    fn push_rst(&mut self, key: FlowKey, rst_cfg: RstConfig) {
+        let (seq, ack, flags) = rst_cfg.seq_ack_tcp_flags();
+        let remote_ip = *key.remote.ip();
+        let guest_ip = self.config.guest_ip;
+
+        self.frames
+            .push_ipv4(remote_ip, guest_ip, PROTOCOL_TCP, |buf| {
+                TcpSegment::write_segment::<[u8]>(
+                    buf,
+                    key.remote.port(),
+                    key.guest_port,
+                    seq,
+                    ack,
+                    flags,
+                    0,
+                    None,
+                    None,
+                    0,
+                    None,
+                    Some((remote_ip, guest_ip)),
+                )
+                .ok()
+                .map(|segment| segment.len())
+            });
+    }
+
+    // Sends the pending segments of a connection to the guest, and removes the connection once
+    // it's done.
+    fn service_tcp(&mut self, key: FlowKey, now: u64) {
+        let (fd, done, old_evset, new_evset) = match self.tcp.get_mut(&key) {
+            Some(proxy) => {
+                proxy.write_segments(&mut self.frames, &key, self.config.guest_ip, now);
+                (
+                    proxy.as_raw_fd(),
+                    proxy.is_done(),
+                    proxy.registered,
+                    proxy.evset(),
+                )
+            }
+            None => return,
+        };
+
+        let new_evset = if done { EventSet::empty() } else { new_evset };
+        self.update_tcp_listener(fd, key, old_evset, new_evset);
+
+        if done {
+            self.tcp.remove(&key);
+            self.update_timer();
+        } else if let Some(proxy) = self.tcp.get_mut(&key) {
+            proxy.registered = new_evset;
+        }
+    }
+
+    fn handle_udp(&mut self, dst: Ipv4Addr, bytes: &[u8]) -> bool {
+        let guest_ip = self.config.guest_ip;
+        let datagram = match UdpDatagram::from_bytes(bytes, Some((guest_ip, dst))) {
+            Ok(datagram) => datagram,
+            Err(_) => return false,
+        };
+        let len = datagram.len() as usize;
+        if len < UDP_HEADER_SIZE || len > bytes.len() {
+            return false;
+        }
+        let key = FlowKey {
+            guest_port: datagram.source_port(),
+            remote: SocketAddrV4::new(dst, datagram.destination_port()),
+        };
+        let now = get_time_us(ClockType::Monotonic);
+
+        if !self.udp.contains_key(&key) {
+            if self.udp.len() >= MAX_UDP_FLOWS {
+                return false;
+            }
+            let host_addr = match self.host_addr(key.remote) {
+                Some(addr) => addr,
+                None => return false,
+            };
+            let flow = match UdpFlow::connect(host_addr, now) {
+                Ok(flow) => flow,
+                Err(_) => {
+                    METRICS.net.user_socket_fails.inc();
+                    return false;
+                }
+            };
+            if self
+                .add_listener(flow.as_raw_fd(), EpollListener::Udp(key))
+                .is_err()
+            {
+                METRICS.net.user_socket_fails.inc();
+                return false;
+            }
+            METRICS.net.user_udp_flows.inc();
+            self.udp.insert(key, flow);
+            self.update_timer();
+        }
+
+        // The unwrap is safe because the flow was inserted above if missing.
+        let flow = self.udp.get_mut(&key).unwrap();
+        if flow.send(&bytes[UDP_HEADER_SIZE..len], now).is_err() {
+            METRICS.net.user_socket_fails.inc();
+        }
+        true
+    }
+
+    // Handles the events which occurred on the host side, and sends the resulting frames to
+    // the guest.
+    fn process_host_events(&mut self) {
+        let mut events = vec![EpollEvent::new(EventSet::empty(), 0); EPOLL_EVENTS_LEN];
+        let count = match self.epoll.wait(0, events.as_mut_slice()) {
+            Ok(count) => count,
+            Err(e) => {
+                warn!("user net: failed to consume epoll events: {:?}", e);
+                0
+            }
+        };
+        let now = get_time_us(ClockType::Monotonic);
+
+        for ev in &events[..count] {
+            // The unwrap is safe because the events are filled in by `epoll::wait()`, and
+            // therefore contain only valid epoll flags.
+            let evset = EventSet::from_bits(ev.events).unwrap();
+            match self.listeners.get(&ev.fd()).copied() {
+                Some(EpollListener::Wakeup) => {
+                    let _ = self.frames.wakeup_evt.read();
+                }
+                Some(EpollListener::Timer) => {
+                    self.timer.read();
+                    self.expire_udp_flows(now);
+                }
+                Some(EpollListener::PortForward(idx)) => self.accept_forwarded(idx, now),
+                Some(EpollListener::Tcp(key)) => self.handle_tcp_socket(key, evset),
+                Some(EpollListener::Udp(key)) => self.relay_udp(key, now),
+                None => {}
+            }
+        }
+
+        // Give every connection the chance to send data, window updates and retransmissions.
+        let keys: Vec<FlowKey> = self.tcp.keys().copied().collect();
+        for key in keys {
+            self.service_tcp(key, now);
+        }
+    }
+
+    fn accept_forwarded(&mut self, idx: usize, now: u64) {
+        loop {
+            let stream = match self.port_forwards[idx].accept() {
+                Ok((stream, _)) => stream,
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(_) => {
+                    METRICS.net.user_socket_fails.inc();
+                    break;
+                }
+            };
+
+            if self.tcp.len() >= MAX_TCP_CONNECTIONS {
+                // Dropping the stream closes the host connection.
+                METRICS.net.user_tcp_connect_fails.inc();
+                continue;
+            }
+
+            let guest_port = self.config.port_forwards[idx].guest_port;
+            let key = FlowKey {
+                guest_port,
+                remote: self.alloc_forward_addr(guest_port),
+            };
+            match TcpProxy::active_open(stream) {
+                Ok(proxy) => {
+                    METRICS.net.user_tcp_connections.inc();
+                    self.tcp.insert(key, proxy);
+                    self.update_timer();
+                    self.service_tcp(key, now);
+                }
+                Err(_) => METRICS.net.user_socket_fails.inc(),
+            }
+        }
+    }
+
+    // Picks the gateway address used as the remote endpoint of a forwarded connection.
+    fn alloc_forward_addr(&mut self, guest_port: u16) -> SocketAddrV4 {
+        // This terminates because there are more ports in the range than connections.
+        loop {
+            let port = self.next_forward_port;
+            self.next_forward_port = if port == u16::MAX {
+                FORWARD_PORT_MIN
+            } else {
+                port + 1
+            };
+
+            let remote = SocketAddrV4::new(self.config.gateway_ip, port);
+            if !self.tcp.contains_key(&FlowKey { guest_port, remote }) {
+                return remote;
+            }
+        }
+    }
+
+    fn handle_tcp_socket(&mut self, key: FlowKey, evset: EventSet) {
+        if let Some(proxy) = self.tcp.get_mut(&key) {
+            if proxy.is_connecting() {
+                proxy.finish_connect();
+            }
+            if evset.intersects(EventSet::OUT) {
+                proxy.write_to_host();
+            }
+            if evset.intersects(EventSet::IN | EventSet::HANG_UP | EventSet::ERROR) {
+                proxy.read_from_host();
+            }
+        }
+    }
+
+    fn relay_udp(&mut self, key: FlowKey, now: u64) {
+        let guest_ip = self.config.guest_ip;
+        let remote_ip = *key.remote.ip();
+        let flow = match self.udp.get_mut(&key) {
+            Some(flow) => flow,
+            None => return,
+        };
+
+        // Datagrams left on the socket are read after the device drains the queue.
+        while !self.frames.is_full() {
+            let len = match flow.recv(&mut self.host_buf, now) {
+                Ok(len) => len,
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(_) => {
+                    METRICS.net.user_socket_fails.inc();
+                    break;
+                }
+            };
+            if len > MAX_UDP_PAYLOAD_LEN {
+                METRICS.net.user_dropped_frames.inc();
+                continue;
+            }
+
+            let payload = &self.host_buf[..len];
+            let pushed = self
+                .frames
+                .push_ipv4(remote_ip, guest_ip, PROTOCOL_UDP, |buf| {
+                    UdpDatagram::write_incomplete_datagram(buf, payload)
+                        .ok()
+                        .map(|datagram| {
+                            datagram
+                                .finalize(
+                                    key.remote.port(),
+                                    key.guest_port,
+                                    Some((remote_ip, guest_ip)),
+                                )
+                                .len() as usize
+                        })
+                });
+            if !pushed {
+                METRICS.net.user_dropped_frames.inc();
+            }
+        }
+    }
+
+    fn expire_udp_flows(&mut self, now: u64) {
+        let expired: Vec<FlowKey> = self
+            .udp
+            .iter()
+            .filter(|(_, flow)| flow.is_expired(now))
+            .map(|(key, _)| *key)
+            .collect();
+
+        for key in expired {
+            if let Some(flow) = self.udp.remove(&key) {
+                self.remove_listener(flow.as_raw_fd());
+            }
+        }
+        self.update_timer();
+    }
+}
+
+impl Read for UserNet {
+    /// Reads the next frame destined to the guest, including the VNET header.
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.frames.frames.is_empty() {
+            self.process_host_events();
+        }
+
+        match self.frames.frames.pop_front() {
+            Some(frame) => {
+                let len = min(frame.len(), buf.len());
+                buf[..len].copy_from_slice(&frame[..len]);
+                Ok(len)
+            }
+            None => Err(io::Error::from_raw_os_error(libc::EAGAIN)),
+        }
+    }
+}
+
+impl Write for UserNet {
+    /// Handles a frame sent by the guest, including the VNET header.
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match buf.get(vnet_hdr_len()..) {
+            Some(frame) => self.handle_frame(frame),
+            None => METRICS.net.user_dropped_frames.inc(),
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl AsRawFd for UserNet {
+    fn as_raw_fd(&self) -> RawFd {
+        self.epoll.as_raw_fd()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::ErrorKind;
+    use std::net::{TcpStream, UdpSocket};
+    use std::thread;
+
+    use dumbo::pdu::arp::OPER_REPLY;
+
+    use super::*;
+
+    const GUEST_MAC: &str = "12:34:56:78:9a:bc";
+    const GUEST_PORT: u16 = 1234;
+    const POLL_ATTEMPTS: usize = 100;
+
+    fn guest_mac() -> MacAddr {
+        MacAddr::parse_str(GUEST_MAC).unwrap()
+    }
+
+    // Builds a frame sent by the guest, including the VNET header.
+    fn guest_frame<F>(ethertype: u16, f: F) -> Vec<u8>
+    where
+        F: FnOnce(&mut [u8]) -> usize,
+    {
+        let mut buf = vec![0u8; vnet_hdr_len() + MAX_FRAME_LEN];
+        let gateway_mac = MacAddr::from_bytes_unchecked(&GATEWAY_MAC);
+        let len = {
+            let mut frame = EthernetFrame::write_incomplete(
+                &mut buf[vnet_hdr_len()..],
+                gateway_mac,
+                guest_mac(),
+                ethertype,
+            )
+            .unwrap();
+            let payload_len = f(frame.inner_mut().payload_mut());
+            frame.with_payload_len_unchecked(payload_len).len()
+        };
+        buf.truncate(vnet_hdr_len() + len);
+        buf
+    }
+
+    fn guest_tcp_frame(
+        dst: SocketAddrV4,
+        seq: u32,
+        ack: u32,
+        flags: TcpFlags,
+        payload: &[u8],
+    ) -> Vec<u8> {
+        let src = DEFAULT_GUEST_IP;
+        guest_frame(ETHERTYPE_IPV4, |buf| {
+            let mut packet = IPv4Packet::write_header(buf, PROTOCOL_TCP, src, *dst.ip()).unwrap();
+            let mss = if flags.intersects(TcpFlags::SYN) {
+                Some(1460)
+            } else {
+                None
+            };
+            let len = TcpSegment::write_segment::<[u8]>(
+                packet.inner_mut().payload_mut(),
+                GUEST_PORT,
+                dst.port(),
+                seq,
+                ack,
+                flags,
+                10000,
+                mss,
+                None,
+                1460,
+                Some((payload, payload.len())),
+                Some((src, *dst.ip())),
+            )
+            .unwrap()
+            .len();
+            packet.with_payload_len_unchecked(len, true).len()
+        })
+    }
+
+    // Reads frames until `check` returns a value, or gives up after a while.
+    fn poll_frames<T, F>(user_net: &mut UserNet, mut check: F) -> T
+    where
+        F: FnMut(&[u8]) -> Option<T>,
+    {
+        let mut buf = vec![0u8; vnet_hdr_len() + MAX_FRAME_LEN];
+        for _ in 0..POLL_ATTEMPTS {
+            match user_net.read(&mut buf) {
+                Ok(len) => {
+                    if let Some(value) = check(&buf[vnet_hdr_len()..len]) {
+                        return value;
+                    }
+                }
+                Err(e) => {
+                    assert_eq!(e.kind(), ErrorKind::WouldBlock);
+                    thread::sleep(Duration::from_millis(10));
+                }
+            }
+        }
+        panic!("expected frame not received");
+    }
+
+    // Returns the sequence number, ack number, flags and payload of a TCP segment sent to the
+    // guest.
+    fn tcp_segment(frame: &[u8]) -> Option<(u32, u32, TcpFlags, Vec<u8>)> {
+        let eth_frame = EthernetFrame::from_bytes(frame).unwrap();
+        if eth_frame.ethertype() != ETHERTYPE_IPV4 {
+            return None;
+        }
+        let packet = IPv4Packet::from_bytes(eth_frame.payload(), true).unwrap();
+        if packet.protocol() != PROTOCOL_TCP {
+            return None;
+        }
+        let segment = TcpSegment::from_bytes(
+            packet.payload(),
+            Some((packet.source_address(), packet.destination_address())),
+        )
+        .unwrap();
+        Some((
+            segment.sequence_number(),
+            segment.ack_number(),
+            segment.flags_after_ns(),
+            segment.payload().to_vec(),
+        ))
+    }
+
+    fn host_read(stream: &mut TcpStream, len: usize) -> Vec<u8> {
+        let mut buf = vec![0u8; len];
+        stream.read_exact(&mut buf).unwrap();
+        buf
+    }
+
+    #[test]
+    fn test_arp() {
+        let mut user_net = UserNet::new(UserNetConfig::default(), None).unwrap();
+        let mut buf = vec![0u8; vnet_hdr_len() + MAX_FRAME_LEN];
+        assert_eq!(
+            user_net.read(&mut buf).unwrap_err().kind(),
+            ErrorKind::WouldBlock
+        );
+
+        let request = guest_frame(ETHERTYPE_ARP, |buf| {
+            EthIPv4ArpFrame::write_request(
+                &mut buf[..ETH_IPV4_FRAME_LEN],
+                guest_mac(),
+                DEFAULT_GUEST_IP,
+                MacAddr::from_bytes_unchecked(&[0; 6]),
+                DEFAULT_GATEWAY_IP,
+            )
+            .unwrap()
+            .len()
+        });
+        assert_eq!(user_net.write(&request).unwrap(), request.len());
+
+        let len = user_net.read(&mut buf).unwrap();
+        let eth_frame = EthernetFrame::from_bytes(&buf[vnet_hdr_len()..len]).unwrap();
+        assert_eq!(eth_frame.dst_mac(), guest_mac());
+        assert_eq!(eth_frame.ethertype(), ETHERTYPE_ARP);
+        let reply = EthIPv4ArpFrame::from_bytes(eth_frame.payload()).unwrap();
+        assert_eq!(reply.operation(), OPER_REPLY);
+        assert_eq!(reply.sha(), MacAddr::from_bytes_unchecked(&GATEWAY_MAC));
+        assert_eq!(reply.spa(), DEFAULT_GATEWAY_IP);
+        assert_eq!(reply.tha(), guest_mac());
+        assert_eq!(reply.tpa(), DEFAULT_GUEST_IP);
+
+        // Requests for the guest address are not answered.
+        let request = guest_frame(ETHERTYPE_ARP, |buf| {
+            EthIPv4ArpFrame::write_request(
+                &mut buf[..ETH_IPV4_FRAME_LEN],
+                guest_mac(),
+                DEFAULT_GUEST_IP,
+                MacAddr::from_bytes_unchecked(&[0; 6]),
+                DEFAULT_GUEST_IP,
+            )
+            .unwrap()
+            .len()
+        });
+        user_net.write(&request).unwrap();
+        assert_eq!(
+            user_net.read(&mut buf).unwrap_err().kind(),
+            ErrorKind::WouldBlock
+        );
+    }
+
+    #[test]
+    fn test_invalid_frames() {
+        let mut user_net = UserNet::new(UserNetConfig::default(), Some(guest_mac())).unwrap();
+        let dropped = METRICS.net.user_dropped_frames.count();
+
+        // Missing VNET header.
+        user_net.write(&[0u8; 4]).unwrap();
+        // Unsupported ethertype.
+        user_net.write(&guest_frame(0x86dd, |_| 40)).unwrap();
+        // Spoofed source address.
+        let frame = guest_frame(ETHERTYPE_IPV4, |buf| {
+            IPv4Packet::write_header(
+                buf,
+                PROTOCOL_UDP,
+                Ipv4Addr::new(10, 0, 2, 16),
+                DEFAULT_GATEWAY_IP,
+            )
+            .unwrap()
+            .with_payload_len_unchecked(0, true)
+            .len()
+        });
+        user_net.write(&frame).unwrap();
+        // The host loopback interface cannot be reached directly.
+        let frame = guest_frame(ETHERTYPE_IPV4, |buf| {
+            IPv4Packet::write_header(buf, PROTOCOL_UDP, DEFAULT_GUEST_IP, Ipv4Addr::LOCALHOST)
+                .unwrap()
+                .with_payload_len_unchecked(0, true)
+                .len()
+        });
+        user_net.write(&frame).unwrap();
+
+        assert!(METRICS.net.user_dropped_frames.count() >= dropped + 4);
+    }
+
+    #[test]
+    fn test_tcp_to_gateway() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let remote = SocketAddrV4::new(DEFAULT_GATEWAY_IP, port);
+
+        // The loopback port is not reachable unless explicitly allowed.
+        let mut user_net = UserNet::new(UserNetConfig::default(), Some(guest_mac())).unwrap();
+        user_net
+            .write(&guest_tcp_frame(remote, 1000, 0, TcpFlags::SYN, &[]))
+            .unwrap();
+        let (_, _, flags, _) = poll_frames(&mut user_net, tcp_segment);
+        assert!(flags.intersects(TcpFlags::RST));
+        listener.set_nonblocking(true).unwrap();
+        assert_eq!(listener.accept().unwrap_err().kind(), ErrorKind::WouldBlock);
+        listener.set_nonblocking(false).unwrap();
+
+        let config = UserNetConfig {
+            host_loopback_ports: vec![port],
+            ..Default::default()
+        };
+        let mut user_net = UserNet::new(config, Some(guest_mac())).unwrap();
+
+        // The guest opens a connection to the gateway, which ends up on the loopback listener.
+        let guest_isn = 1000;
+        user_net
+            .write(&guest_tcp_frame(remote, guest_isn, 0, TcpFlags::SYN, &[]))
+            .unwrap();
+        let (mut stream, _) = listener.accept().unwrap();
+
+        let (isn, ack, flags, _) = poll_frames(&mut user_net, tcp_segment);
+        assert_eq!(flags, TcpFlags::SYN | TcpFlags::ACK);
+        assert_eq!(ack, guest_isn + 1);
+
+        // Guest to host data.
+        user_net
+            .write(&guest_tcp_frame(
+                remote,
+                guest_isn + 1,
+                isn + 1,
+                TcpFlags::ACK | TcpFlags::PSH,
+                b"hello",
+            ))
+            .unwrap();
+        assert_eq!(host_read(&mut stream, 5), b"hello");
+
+        // Host to guest data.
+        stream.write_all(b"world").unwrap();
+        let payload = poll_frames(&mut user_net, |frame| {
+            tcp_segment(frame).and_then(|(seq, _, _, payload)| {
+                if payload.is_empty() {
+                    None
+                } else {
+                    assert_eq!(seq, isn + 1);
+                    Some(payload)
+                }
+            })
+        });
+        assert_eq!(payload, b"world");
+
+        // The host closes the connection, so the guest gets a FIN.
+        drop(stream);
+        poll_frames(&mut user_net, |frame| {
+            tcp_segment(frame).and_then(|(_, _, flags, _)| {
+                if flags.intersects(TcpFlags::FIN) {
+                    Some(())
+                } else {
+                    None
+                }
+            })
+        });
+    }
+
+    #[test]
+    fn test_allowed_destinations() {
+        let network = Ipv4Network::new(Ipv4Addr::new(192, 168, 1, 0), 24);
+        assert!(network.contains(Ipv4Addr::new(192, 168, 1, 200)));
+        assert!(!network.contains(Ipv4Addr::new(192, 168, 2, 1)));
+        assert!(Ipv4Network::new(Ipv4Addr::UNSPECIFIED, 0).contains(Ipv4Addr::BROADCAST));
+        let host = Ipv4Network::new(Ipv4Addr::new(10, 0, 0, 1), 32);
+        assert!(host.contains(Ipv4Addr::new(10, 0, 0, 1)));
+        assert!(!host.contains(Ipv4Addr::new(10, 0, 0, 2)));
+
+        assert!(udp::is_host_addr(Ipv4Addr::LOCALHOST).unwrap());
+
+        // Private, shared and link-local destinations are refused by default.
+        let user_net = UserNet::new(UserNetConfig::default(), None).unwrap();
+        for ip in &[
+            Ipv4Addr::new(10, 1, 2, 3),
+            Ipv4Addr::new(100, 64, 0, 1),
+            Ipv4Addr::new(169, 254, 169, 254),
+            Ipv4Addr::new(172, 31, 0, 1),
+            Ipv4Addr::new(192, 168, 0, 1),
+        ] {
+            assert_eq!(user_net.host_addr(SocketAddrV4::new(*ip, 80)), None);
+        }
+
+        let config = UserNetConfig {
+            allowed_destinations: vec![Ipv4Network::new(Ipv4Addr::new(10, 1, 0, 0), 16)],
+            ..Default::default()
+        };
+        let user_net = UserNet::new(config, None).unwrap();
+        let remote = SocketAddrV4::new(Ipv4Addr::new(10, 1, 2, 3), 80);
+        assert_eq!(user_net.host_addr(remote), Some(remote));
+        let remote = SocketAddrV4::new(Ipv4Addr::new(10, 2, 0, 1), 80);
+        assert_eq!(user_net.host_addr(remote), None);
+
+        let config = UserNetConfig {
+            allowed_destinations: vec![Ipv4Network::new(Ipv4Addr::UNSPECIFIED, 33)],
+            ..Default::default()
+        };
+        assert!(matches!(
+            UserNet::new(config, None),
+            Err(Error::InvalidAllowedDestination(_))
+        ));
+    }
+
+    #[test]
+    fn test_tcp_connection_refused() {
+        // Find a port nobody listens on.
+        let port = TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let remote = SocketAddrV4::new(DEFAULT_GATEWAY_IP, port);
+
+        let config = UserNetConfig {
+            host_loopback_ports: vec![port],
+            ..Default::default()
+        };
+        let mut user_net = UserNet::new(config, Some(guest_mac())).unwrap();
+        user_net
+            .write(&guest_tcp_frame(remote, 1000, 0, TcpFlags::SYN, &[]))
+            .unwrap();
+
+        let (_, ack, flags, _) = poll_frames(&mut user_net, tcp_segment);
+        assert!(flags.intersects(TcpFlags::RST));
+        assert_eq!(ack, 1001);
+
+        // Segments for unknown connections get a RST.
+        user_net
+            .write(&guest_tcp_frame(remote, 1001, 5000, TcpFlags::ACK, &[]))
+            .unwrap();
+        let (seq, _, flags, _) = poll_frames(&mut user_net, tcp_segment);
+        assert_eq!(flags, TcpFlags::RST);
+        assert_eq!(seq, 5000);
+    }
+
+    #[test]
+    fn test_port_forward() {
+        let host_addr = {
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            listener.local_addr().unwrap()
+        };
+        let config = UserNetConfig {
+            port_forwards: vec![PortForward {
+                host_addr,
+                guest_port: 80,
+            }],
+            ..Default::default()
+        };
+        let mut user_net = UserNet::new(config, Some(guest_mac())).unwrap();
+
+        // The address is already in use.
+        assert!(matches!(
+            UserNet::new(user_net.config().clone(), None),
+            Err(Error::BindPortForward(addr, _)) if addr == host_addr
+        ));
+
+        let mut stream = TcpStream::connect(host_addr).unwrap();
+
+        // The backend opens a connection to the guest port.
+        let (isn, remote) = poll_frames(&mut user_net, |frame| {
+            let eth_frame = EthernetFrame::from_bytes(frame).unwrap();
+            let packet = IPv4Packet::from_bytes(eth_frame.payload(), true).unwrap();
+            let segment = TcpSegment::from_bytes(packet.payload(), None).unwrap();
+            assert_eq!(segment.flags_after_ns(), TcpFlags::SYN);
+            assert_eq!(segment.destination_port(), 80);
+            assert_eq!(packet.source_address(), DEFAULT_GATEWAY_IP);
+            assert!(segment.source_port() >= FORWARD_PORT_MIN);
+            Some((
+                segment.sequence_number(),
+                SocketAddrV4::new(DEFAULT_GATEWAY_IP, segment.source_port()),
+            ))
+        });
+
+        // The guest accepts it.
+        let guest_isn = 5000;
+        let frame = guest_frame(ETHERTYPE_IPV4, |buf| {
+            let mut packet =
+                IPv4Packet::write_header(buf, PROTOCOL_TCP, DEFAULT_GUEST_IP, *remote.ip())
+                    .unwrap();
+            let len = TcpSegment::write_segment::<[u8]>(
+                packet.inner_mut().payload_mut(),
+                80,
+                remote.port(),
+                guest_isn,
+                isn + 1,
+                TcpFlags::SYN | TcpFlags::ACK,
+                10000,
+                Some(1460),
+                None,
+                1460,
+                None,
+                Some((DEFAULT_GUEST_IP, *remote.ip())),
+            )
+            .unwrap()
+            .len();
+            packet.with_payload_len_unchecked(len, true).len()
+        });
+        user_net.write(&frame).unwrap();
+
+        // The connection is established, so host data makes it to the guest.
+        stream.write_all(b"ping").unwrap();
+        let payload = poll_frames(&mut user_net, |frame| {
+            tcp_segment(frame).and_then(|(_, ack, _, payload)| {
+                assert_eq!(ack, guest_isn + 1);
+                if payload.is_empty() {
+                    None
+                } else {
+                    Some(payload)
+                }
+            })
+        });
+        assert_eq!(payload, b"ping");
+    }
+
+    #[test]
+    fn test_udp() {
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let port = socket.local_addr().unwrap().port();
+
+        let config = UserNetConfig {
+            host_loopback_ports: vec![port],
+            ..Default::default()
+        };
+        let mut user_net = UserNet::new(config, Some(guest_mac())).unwrap();
+        let frame = guest_frame(ETHERTYPE_IPV4, |buf| {
+            let mut packet =
+                IPv4Packet::write_header(buf, PROTOCOL_UDP, DEFAULT_GUEST_IP, DEFAULT_GATEWAY_IP)
+                    .unwrap();
+            let len =
+                UdpDatagram::write_incomplete_datagram(packet.inner_mut().payload_mut(), b"ping")
+                    .unwrap()
+                    .finalize(
+                        GUEST_PORT,
+                        port,
+                        Some((DEFAULT_GUEST_IP, DEFAULT_GATEWAY_IP)),
+                    )
+                    .len();
+            packet.with_payload_len_unchecked(len as usize, true).len()
+        });
+        user_net.write(&frame).unwrap();
+
+        let mut buf = [0u8; 16];
+        let (len, peer) = socket.recv_from(&mut buf).unwrap();
+        assert_eq!(&buf[..len], b"ping");
+        socket.send_to(b"pong", peer).unwrap();
+
+        let payload = poll_frames(&mut user_net, |frame| {
+            let eth_frame = EthernetFrame::from_bytes(frame).unwrap();
+            let packet = IPv4Packet::from_bytes(eth_frame.payload(), true).unwrap();
+            assert_eq!(packet.protocol(), PROTOCOL_UDP);
+            assert_eq!(packet.source_address(), DEFAULT_GATEWAY_IP);
+            assert_eq!(packet.destination_address(), DEFAULT_GUEST_IP);
+            let datagram = UdpDatagram::from_bytes(
+                packet.payload(),
+                Some((DEFAULT_GATEWAY_IP, DEFAULT_GUEST_IP)),
+            )
+            .unwrap();
+            assert_eq!(datagram.source_port(), port);
+            assert_eq!(datagram.destination_port(), GUEST_PORT);
+            Some(datagram.payload().to_vec())
+        });
+        assert_eq!(payload, b"pong");
+    }
+}
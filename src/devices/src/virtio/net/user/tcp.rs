@@ -0,0 +1,354 @@
+// Copyright 2022 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Proxies TCP connections between the guest and host sockets.
+//!
+//! The guest side of every connection is handled by a `dumbo` TCP `Connection`, while the host
+//! side is a regular non-blocking `TcpStream`. Data flows between the two through a pair of
+//! bounded buffers.
+
+use std::cmp::min;
+use std::io::{self, Read, Write};
+use std::mem;
+use std::net::{Ipv4Addr, Shutdown, SocketAddrV4, TcpStream};
+use std::num::{NonZeroU16, NonZeroU64, Wrapping};
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+
+use dumbo::pdu::bytes::NetworkBytes;
+use dumbo::pdu::ipv4::PROTOCOL_TCP;
+use dumbo::pdu::tcp::TcpSegment;
+use dumbo::tcp::connection::{Connection, PassiveOpenError, PayloadSource, RecvStatusFlags};
+use dumbo::tcp::seq_after;
+use logger::{IncMetric, METRICS};
+use utils::epoll::EventSet;
+
+use super::{FlowKey, FrameQueue};
+
+// The receive window advertised to the guest. Data from the guest is buffered until it can be
+// written to the host socket, so this also bounds the size of that buffer.
+const RWND_SIZE: u32 = 65535;
+// Upper bound for the amount of data read from the host socket, which was not yet acknowledged
+// by the guest.
+const SEND_BUF_SIZE: usize = 65535;
+// The MSS advertised to the guest for connections opened by the backend. Segments of this size
+// fit in a standard Ethernet frame.
+const MSS: u16 = 1460;
+// Retransmission timeout, in microseconds.
+const RTO_PERIOD: u64 = 200_000;
+const RTO_COUNT_MAX: u16 = 15;
+
+/// Starts a non-blocking connection attempt to `addr`.
+///
+/// The returned stream becomes writable once the attempt completes, and its outcome can be
+/// retrieved afterwards using `TcpStream::take_error`.
+pub(super) fn connect(addr: SocketAddrV4) -> io::Result<TcpStream> {
+    // This is safe because we check the return value.
+    let fd = unsafe {
+        libc::socket(
+            libc::AF_INET,
+            libc::SOCK_STREAM | libc::SOCK_NONBLOCK | libc::SOCK_CLOEXEC,
+            0,
+        )
+    };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // This is safe because we just created the file descriptor, and nothing else owns it.
+    let stream = unsafe { TcpStream::from_raw_fd(fd) };
+
+    let sockaddr = libc::sockaddr_in {
+        sin_family: libc::AF_INET as libc::sa_family_t,
+        sin_port: addr.port().to_be(),
+        sin_addr: libc::in_addr {
+            s_addr: u32::from(*addr.ip()).to_be(),
+        },
+        sin_zero: [0; 8],
+    };
+    // This is safe because `sockaddr` is a valid `sockaddr_in`, and we check the return value.
+    let ret = unsafe {
+        libc::connect(
+            fd,
+            &sockaddr as *const libc::sockaddr_in as *const libc::sockaddr,
+            mem::size_of::<libc::sockaddr_in>() as libc::socklen_t,
+        )
+    };
+    if ret < 0 {
+        let err = io::Error::last_os_error();
+        if err.raw_os_error() != Some(libc::EINPROGRESS) {
+            return Err(err);
+        }
+    }
+
+    Ok(stream)
+}
+
+fn rto_period() -> NonZeroU64 {
+    // The unwrap is safe because the constant is greater than 0.
+    NonZeroU64::new(RTO_PERIOD).unwrap()
+}
+
+fn rto_count_max() -> NonZeroU16 {
+    // The unwrap is safe because the constant is greater than 0.
+    NonZeroU16::new(RTO_COUNT_MAX).unwrap()
+}
+
+/// A TCP connection between the guest and a host socket.
+pub(super) struct TcpProxy {
+    conn: Connection,
+    stream: TcpStream,
+    // The host connection attempt is still in progress.
+    connecting: bool,
+    // Data received from the guest, which was not yet written to the host socket.
+    to_host: Vec<u8>,
+    // Data read from the host socket, which was not yet acknowledged by the guest. The first byte
+    // has the sequence number `to_guest_seq`.
+    to_guest: Vec<u8>,
+    to_guest_seq: Wrapping<u32>,
+    // The host socket reached end of file.
+    host_eof: bool,
+    // The write half of the host socket has been shut down, after the guest sent a FIN.
+    host_shutdown: bool,
+    // The connection has been reset, either by us or by the guest.
+    reset: bool,
+    // The events currently registered for the host socket.
+    pub registered: EventSet,
+}
+
+impl TcpProxy {
+    fn new(conn: Connection, stream: TcpStream, connecting: bool) -> Self {
+        let to_guest_seq = conn.first_not_sent();
+        TcpProxy {
+            conn,
+            stream,
+            connecting,
+            to_host: Vec::new(),
+            to_guest: Vec::new(),
+            to_guest_seq,
+            host_eof: false,
+            host_shutdown: false,
+            reset: false,
+            registered: EventSet::empty(),
+        }
+    }
+
+    /// Creates a proxy for a connection opened by the guest with the given `SYN`.
+    ///
+    /// The `stream` is expected to be connecting to the host address. The guest does not get a
+    /// `SYNACK` until the connection attempt succeeds.
+    pub fn passive_open<T: NetworkBytes>(
+        syn: &TcpSegment<T>,
+        stream: TcpStream,
+    ) -> Result<Self, PassiveOpenError> {
+        let conn = Connection::passive_open(syn, RWND_SIZE, rto_period(), rto_count_max())?;
+        Ok(Self::new(conn, stream, true))
+    }
+
+    /// Creates a proxy for a connection accepted on the host, which is forwarded to the guest.
+    pub fn active_open(stream: TcpStream) -> io::Result<Self> {
+        stream.set_nonblocking(true)?;
+        // The unwrap is safe because the constant is greater than 0.
+        let mss = NonZeroU16::new(MSS).unwrap();
+        let conn = Connection::active_open(RWND_SIZE, mss, rto_period(), rto_count_max());
+        Ok(Self::new(conn, stream, false))
+    }
+
+    fn fail(&mut self) {
+        METRICS.net.user_socket_fails.inc();
+        self.conn.reset();
+        self.reset = true;
+    }
+
+    /// Completes the connection attempt to the host.
+    ///
+    /// The guest connection is reset if the attempt failed.
+    pub fn finish_connect(&mut self) {
+        if !self.connecting {
+            return;
+        }
+        self.connecting = false;
+
+        if !matches!(self.stream.take_error(), Ok(None)) {
+            METRICS.net.user_tcp_connect_fails.inc();
+            self.conn.reset();
+            self.reset = true;
+        }
+    }
+
+    /// Handles a segment sent by the guest.
+    pub fn receive_segment<T: NetworkBytes>(&mut self, s: &TcpSegment<T>, now: u64) {
+        let old_len = self.to_host.len();
+        self.to_host.resize(old_len + s.payload_len(), 0);
+
+        let received = match self
+            .conn
+            .receive_segment(s, &mut self.to_host[old_len..], now)
+        {
+            Ok((len, flags)) => {
+                if flags.intersects(RecvStatusFlags::RESET_RECEIVED) {
+                    self.reset = true;
+                }
+                len.map_or(0, |len| len.get())
+            }
+            Err(_) => 0,
+        };
+        self.to_host.truncate(old_len + received);
+
+        self.drop_acked_data();
+        self.write_to_host();
+    }
+
+    // Drops the data acknowledged by the guest from the send buffer.
+    fn drop_acked_data(&mut self) {
+        let ack = self.conn.highest_ack_received();
+        if seq_after(ack, self.to_guest_seq) {
+            // The acknowledgement may also cover our FIN, which takes up one sequence number.
+            let acked = min((ack - self.to_guest_seq).0 as usize, self.to_guest.len());
+            self.to_guest.drain(..acked);
+            self.to_guest_seq += Wrapping(acked as u32);
+        }
+    }
+
+    /// Writes as much of the data received from the guest as possible to the host socket.
+    pub fn write_to_host(&mut self) {
+        if self.connecting || self.reset {
+            return;
+        }
+
+        let mut written = 0;
+        while written < self.to_host.len() {
+            match self.stream.write(&self.to_host[written..]) {
+                Ok(len) => written += len,
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(_) => {
+                    self.fail();
+                    return;
+                }
+            }
+        }
+
+        if written > 0 {
+            self.to_host.drain(..written);
+            // The guest can send more data now.
+            self.conn.advance_local_rwnd_edge(written as u32);
+        }
+
+        if self.to_host.is_empty() && self.conn.fin_received() && !self.host_shutdown {
+            // The guest closed its half of the connection, so we do the same on the host side.
+            let _ = self.stream.shutdown(Shutdown::Write);
+            self.host_shutdown = true;
+        }
+    }
+
+    /// Reads data from the host socket, as long as there is room in the send buffer.
+    pub fn read_from_host(&mut self) {
+        if self.connecting || self.reset || self.host_eof {
+            return;
+        }
+
+        while self.to_guest.len() < SEND_BUF_SIZE {
+            let old_len = self.to_guest.len();
+            self.to_guest.resize(SEND_BUF_SIZE, 0);
+            let result = self.stream.read(&mut self.to_guest[old_len..]);
+            let len = match result {
+                Ok(len) => len,
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => 0,
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+                    self.to_guest.truncate(old_len);
+                    return;
+                }
+                Err(_) => {
+                    self.to_guest.truncate(old_len);
+                    self.fail();
+                    return;
+                }
+            };
+            self.to_guest.truncate(old_len + len);
+
+            if matches!(result, Ok(0)) {
+                self.host_eof = true;
+                return;
+            }
+        }
+    }
+
+    /// Writes the segments which are ready to be sent to the guest.
+    pub fn write_segments(
+        &mut self,
+        frames: &mut FrameQueue,
+        key: &FlowKey,
+        guest_ip: Ipv4Addr,
+        now: u64,
+    ) {
+        if self.connecting {
+            return;
+        }
+
+        // Our FIN can only be sent after all the data read from the host socket.
+        let send_buf_end = self.to_guest_seq + Wrapping(self.to_guest.len() as u32);
+        if self.host_eof && self.conn.first_not_sent() == send_buf_end {
+            self.conn.close();
+        }
+
+        let remote_ip = *key.remote.ip();
+        let remote_port = key.remote.port();
+        loop {
+            let conn = &mut self.conn;
+            let payload_src: PayloadSource<[u8]> = if self.to_guest.is_empty() {
+                None
+            } else {
+                Some((self.to_guest.as_slice(), self.to_guest_seq))
+            };
+
+            let written = frames.push_ipv4(remote_ip, guest_ip, PROTOCOL_TCP, |buf| {
+                match conn.write_next_segment(buf, 0, payload_src, now) {
+                    Ok(Some(segment)) => Some(
+                        segment
+                            .finalize(remote_port, key.guest_port, Some((remote_ip, guest_ip)))
+                            .len(),
+                    ),
+                    _ => None,
+                }
+            });
+
+            if !written {
+                break;
+            }
+        }
+    }
+
+    /// Returns `true` if the connection attempt to the host is still in progress.
+    pub fn is_connecting(&self) -> bool {
+        self.connecting
+    }
+
+    /// Returns `true` if the proxy can be removed.
+    pub fn is_done(&self) -> bool {
+        self.conn.is_done() && (self.reset || self.to_host.is_empty())
+    }
+
+    /// Returns the events which should be monitored on the host socket.
+    pub fn evset(&self) -> EventSet {
+        if self.connecting {
+            return EventSet::OUT;
+        }
+
+        let mut evset = EventSet::empty();
+        if self.reset {
+            return evset;
+        }
+        if !self.host_eof && self.to_guest.len() < SEND_BUF_SIZE {
+            evset |= EventSet::IN;
+        }
+        if !self.to_host.is_empty() {
+            evset |= EventSet::OUT;
+        }
+        evset
+    }
+}
+
+impl AsRawFd for TcpProxy {
+    fn as_raw_fd(&self) -> RawFd {
+        self.stream.as_raw_fd()
+    }
+}
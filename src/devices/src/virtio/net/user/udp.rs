@@ -0,0 +1,69 @@
+// Copyright 2022 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Relays UDP datagrams between the guest and host sockets.
+
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, SocketAddrV4, UdpSocket};
+use std::os::unix::io::{AsRawFd, RawFd};
+
+// How long (in microseconds) a flow can go without any traffic before being removed.
+const FLOW_TIMEOUT: u64 = 120_000_000;
+
+/// Returns `true` if the packets sent to `ip` are delivered to the host itself.
+///
+/// Connecting a UDP socket doesn't send anything, but selects the source address of the route
+/// towards the destination, which is the destination itself for the host addresses.
+pub(super) fn is_host_addr(ip: Ipv4Addr) -> io::Result<bool> {
+    let socket = UdpSocket::bind(SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, 0))?;
+    // The port is irrelevant to the route lookup.
+    socket.connect(SocketAddrV4::new(ip, 1))?;
+    Ok(socket.local_addr()?.ip() == IpAddr::V4(ip))
+}
+
+/// A UDP flow between a guest port and a remote address.
+///
+/// Each flow uses a dedicated host socket, which is connected to the remote address, so replies
+/// can be directed back to the guest port that originated the flow.
+pub(super) struct UdpFlow {
+    socket: UdpSocket,
+    last_active: u64,
+}
+
+impl UdpFlow {
+    /// Creates a new flow towards `host_addr`.
+    pub fn connect(host_addr: SocketAddrV4, now: u64) -> io::Result<Self> {
+        let socket = UdpSocket::bind(SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, 0))?;
+        socket.set_nonblocking(true)?;
+        socket.connect(host_addr)?;
+
+        Ok(UdpFlow {
+            socket,
+            last_active: now,
+        })
+    }
+
+    /// Sends a datagram sent by the guest to the remote address.
+    pub fn send(&mut self, data: &[u8], now: u64) -> io::Result<()> {
+        self.last_active = now;
+        self.socket.send(data).map(|_| ())
+    }
+
+    /// Receives the next datagram sent by the remote address.
+    pub fn recv(&mut self, buf: &mut [u8], now: u64) -> io::Result<usize> {
+        let len = self.socket.recv(buf)?;
+        self.last_active = now;
+        Ok(len)
+    }
+
+    /// Returns `true` if the flow has been idle for too long.
+    pub fn is_expired(&self, now: u64) -> bool {
+        now.saturating_sub(self.last_active) >= FLOW_TIMEOUT
+    }
+}
+
+impl AsRawFd for UdpFlow {
+    fn as_raw_fd(&self) -> RawFd {
+        self.socket.as_raw_fd()
+    }
+}
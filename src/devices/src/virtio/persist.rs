@@ -213,11 +213,12 @@ impl Persist<'_> for MmioTransport {
 mod tests {
     use super::*;
     use crate::virtio::mmio::tests::DummyDevice;
-    use crate::virtio::{net, Block, Net, Vsock, VsockUnixBackend};
+    use crate::virtio::{net, Block, Net, Vsock, VsockConnLimits, VsockUnixBackend};
 
     use crate::virtio::block::device::FileEngineType;
     use crate::virtio::block::test_utils::default_block_with_path;
     use crate::virtio::test_utils::default_mem;
+    use rate_limiter::RateLimiter;
     use utils::tempfile::TempFile;
 
     const DEFAULT_QUEUE_MAX_SIZE: u16 = 256;
@@ -420,8 +421,15 @@ mod tests {
         // Remove the file so the path can be used by the socket.
         temp_uds_path.remove().unwrap();
         let uds_path = String::from(temp_uds_path.as_path().to_str().unwrap());
-        let backend = VsockUnixBackend::new(guest_cid, uds_path).unwrap();
-        let vsock = Vsock::new(guest_cid, backend).unwrap();
+        let backend =
+            VsockUnixBackend::new(guest_cid, uds_path, VsockConnLimits::default()).unwrap();
+        let vsock = Vsock::new(
+            guest_cid,
+            backend,
+            RateLimiter::default(),
+            RateLimiter::default(),
+        )
+        .unwrap();
         let vsock = Arc::new(Mutex::new(vsock));
         let mmio_transport = MmioTransport::new(mem.clone(), vsock.clone());
 
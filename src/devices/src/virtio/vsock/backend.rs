@@ -0,0 +1,127 @@
+// Copyright 2022 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Defines the host side backends which can be selected for a vsock device.
+
+use std::os::unix::io::{AsRawFd, RawFd};
+
+use utils::epoll::EventSet;
+use vm_memory::GuestMemoryMmap;
+
+use super::packet::VsockPacket;
+use super::tcp::{Error as VsockTcpBackendError, VsockTcpBackend, VsockTcpConfig};
+use super::unix::{Error as VsockUnixBackendError, VsockUnixBackend};
+use super::{Result, VsockBackend, VsockChannel, VsockConnLimits, VsockEpollListener};
+
+/// The host side of a vsock device.
+pub enum VsockHostBackend {
+    /// Guest ports are mapped to Unix domain sockets.
+    Unix(VsockUnixBackend),
+    /// Guest ports are mapped to TCP endpoints.
+    Tcp(VsockTcpBackend),
+}
+
+/// Errors encountered when creating a vsock backend.
+#[derive(Debug)]
+pub enum VsockHostBackendError {
+    /// Failed to create the Unix domain sockets backend.
+    Unix(VsockUnixBackendError),
+    /// Failed to create the TCP backend.
+    Tcp(VsockTcpBackendError),
+    /// A saved TCP backend address cannot be parsed.
+    InvalidTcpAddr(String),
+}
+
+impl VsockHostBackend {
+    /// Creates a Unix domain sockets backend listening on `host_sock_path`.
+    pub fn new_unix(
+        cid: u64,
+        host_sock_path: String,
+        conn_limits: VsockConnLimits,
+    ) -> std::result::Result<Self, VsockHostBackendError> {
+        VsockUnixBackend::new(cid, host_sock_path, conn_limits)
+            .map(VsockHostBackend::Unix)
+            .map_err(VsockHostBackendError::Unix)
+    }
+
+    /// Creates a TCP backend using the port map in `config`.
+    pub fn new_tcp(
+        cid: u64,
+        config: VsockTcpConfig,
+        conn_limits: VsockConnLimits,
+    ) -> std::result::Result<Self, VsockHostBackendError> {
+        VsockTcpBackend::new(cid, config, conn_limits)
+            .map(VsockHostBackend::Tcp)
+            .map_err(VsockHostBackendError::Tcp)
+    }
+
+    /// Returns the path of the host Unix socket, if the backend uses Unix domain sockets.
+    pub fn host_sock_path(&self) -> Option<&str> {
+        match self {
+            VsockHostBackend::Unix(backend) => Some(backend.host_sock_path()),
+            VsockHostBackend::Tcp(_) => None,
+        }
+    }
+
+    /// Returns the port map, if the backend uses TCP.
+    pub fn tcp_config(&self) -> Option<&VsockTcpConfig> {
+        match self {
+            VsockHostBackend::Unix(_) => None,
+            VsockHostBackend::Tcp(backend) => Some(backend.config()),
+        }
+    }
+
+    /// Returns the connection limits enforced by the backend.
+    pub fn conn_limits(&self) -> &VsockConnLimits {
+        match self {
+            VsockHostBackend::Unix(backend) => backend.conn_limits(),
+            VsockHostBackend::Tcp(backend) => backend.conn_limits(),
+        }
+    }
+
+    fn as_backend(&self) -> &dyn VsockBackend {
+        match self {
+            VsockHostBackend::Unix(backend) => backend,
+            VsockHostBackend::Tcp(backend) => backend,
+        }
+    }
+
+    fn as_backend_mut(&mut self) -> &mut dyn VsockBackend {
+        match self {
+            VsockHostBackend::Unix(backend) => backend,
+            VsockHostBackend::Tcp(backend) => backend,
+        }
+    }
+}
+
+impl VsockChannel for VsockHostBackend {
+    fn recv_pkt(&mut self, pkt: &mut VsockPacket, mem: &GuestMemoryMmap) -> Result<()> {
+        self.as_backend_mut().recv_pkt(pkt, mem)
+    }
+
+    fn send_pkt(&mut self, pkt: &VsockPacket, mem: &GuestMemoryMmap) -> Result<()> {
+        self.as_backend_mut().send_pkt(pkt, mem)
+    }
+
+    fn has_pending_rx(&self) -> bool {
+        self.as_backend().has_pending_rx()
+    }
+}
+
+impl AsRawFd for VsockHostBackend {
+    fn as_raw_fd(&self) -> RawFd {
+        self.as_backend().as_raw_fd()
+    }
+}
+
+impl VsockEpollListener for VsockHostBackend {
+    fn get_polled_evset(&self) -> EventSet {
+        self.as_backend().get_polled_evset()
+    }
+
+    fn notify(&mut self, evset: EventSet) {
+        self.as_backend_mut().notify(evset)
+    }
+}
+
+impl VsockBackend for VsockHostBackend {}
@@ -22,6 +22,15 @@
 ///         consume it.  If that data can't be forwarded straight to the host stream, we'll
 ///         have to store it in a buffer (and flush it at a later time). Vsock flow control
 ///         ensures that our TX buffer doesn't overflow.
+///
+/// SOCK_SEQPACKET connections are handled by the same state machine, with the host stream being
+/// a sequential packet socket. The difference lies in the data path, where message boundaries
+/// need to be preserved:
+/// - guest messages can span several TX packets, the last of which carries the
+///   VSOCK_FLAGS_SEQ_EOM flag. They are reassembled in a `MsgBuf`, and then written to the host
+///   socket one message at a time; and
+/// - host messages are read whole, and then delivered to the guest in as many RX packets as
+///   needed, the last of which carries the VSOCK_FLAGS_SEQ_EOM and VSOCK_FLAGS_SEQ_EOR flags.
 // The code in this file is best read with a fresh memory of the vsock protocol inner-workings.
 // To help with that, here is a
 //
@@ -78,6 +87,14 @@
 //          2. The receiver can be proactive, and send VSOCK_OP_CREDIT_UPDATE packet, whenever
 //             it thinks its peer's information is out of date.
 //          Our implementation uses the proactive approach.
+//
+// 4. Sequential packets
+//    If the VIRTIO_VSOCK_F_SEQPACKET feature has been negotiated, connections can also have the
+//    VSOCK_TYPE_SEQPACKET type, with the same handshake, termination and flow control as stream
+//    connections. Data packets (VSOCK_OP_RW) carry two additional header flags:
+//    - VSOCK_FLAGS_SEQ_EOM: the packet carries the end of a message; and
+//    - VSOCK_FLAGS_SEQ_EOR: the packet carries the end of a record (i.e. the message was sent
+//      with MSG_EOR).
 use std::io::{ErrorKind, Read, Write};
 use std::num::Wrapping;
 use std::os::unix::io::{AsRawFd, RawFd};
@@ -90,6 +107,7 @@ use super::super::defs::uapi;
 use super::super::packet::VsockPacket;
 use super::super::{Result as VsockResult, VsockChannel, VsockEpollListener, VsockError};
 use super::defs;
+use super::msgbuf::MsgBuf;
 use super::txbuf::TxBuf;
 use super::{ConnState, Error, PendingRx, PendingRxSet, Result};
 use vm_memory::{GuestMemoryError, GuestMemoryMmap};
@@ -109,8 +127,17 @@ pub struct VsockConnection<S: Read + Write + AsRawFd> {
     peer_port: u32,
     /// The (connected) host-side stream.
     stream: S,
+    /// The connection type: either VSOCK_TYPE_STREAM or VSOCK_TYPE_SEQPACKET.
+    type_: u16,
     /// The TX buffer for this connection.
     tx_buf: TxBuf,
+    /// The TX message buffer, used instead of `tx_buf` by SOCK_SEQPACKET connections.
+    tx_msg_buf: MsgBuf,
+    /// The message last read from a SOCK_SEQPACKET host stream, while being delivered to the
+    /// peer.
+    rx_msg: Vec<u8>,
+    /// The number of `rx_msg` bytes that have already been delivered to the peer.
+    rx_msg_off: usize,
     /// Total number of bytes that have been successfully written to `self.stream`, either
     /// directly, or flushed from `self.tx_buf`.
     fwd_cnt: Wrapping<u32>,
@@ -210,7 +237,13 @@ where
             let max_len = std::cmp::min(pkt.buf_size(), self.peer_avail_credit());
 
             // Read data from the stream straight to the RX buffer, for maximum throughput.
-            match pkt.read_at_offset_from(mem, 0, &mut self.stream, max_len) {
+            // Sequential packets need to go through the RX message buffer instead.
+            let read_res = if self.type_ == uapi::VSOCK_TYPE_SEQPACKET {
+                self.read_msg_chunk(pkt, mem, max_len)
+            } else {
+                pkt.read_at_offset_from(mem, 0, &mut self.stream, max_len)
+            };
+            match read_res {
                 Ok(read_cnt) => {
                     if read_cnt == 0 {
                         // A 0-length read means the host stream was closed down. In that case,
@@ -293,7 +326,8 @@ where
             ConnState::Established | ConnState::PeerClosed(_, false)
                 if pkt.op() == uapi::VSOCK_OP_RW =>
             {
-                if pkt.buf_size() == 0 {
+                // An empty packet can still mark the end of a message.
+                if pkt.buf_size() == 0 && !pkt.is_eom() {
                     info!(
                         "vsock: dropping empty data packet from guest (lp={}, pp={}",
                         self.local_port, self.peer_port
@@ -301,8 +335,12 @@ where
                     return Ok(());
                 }
 
-                // Unwrapping here is safe, since we just checked `pkt.buf()` above.
-                if let Err(err) = self.send_bytes(mem, &pkt) {
+                let res = if self.type_ == uapi::VSOCK_TYPE_SEQPACKET {
+                    self.send_msg_bytes(mem, &pkt)
+                } else {
+                    self.send_bytes(mem, &pkt)
+                };
+                if let Err(err) = res {
                     // If we can't write to the host stream, that's an unrecoverable error, so
                     // we'll terminate this connection.
                     warn!(
@@ -338,7 +376,7 @@ where
                 let send_off = pkt.flags() & uapi::VSOCK_FLAGS_SHUTDOWN_SEND != 0;
                 self.state = ConnState::PeerClosed(recv_off, send_off);
                 if recv_off && send_off {
-                    if self.tx_buf.is_empty() {
+                    if !self.tx_pending() {
                         self.pending_rx.insert(PendingRx::Rst);
                     } else {
                         self.expiry = Some(
@@ -355,7 +393,7 @@ where
             {
                 *recv_off = *recv_off || (pkt.flags() & uapi::VSOCK_FLAGS_SHUTDOWN_RCV != 0);
                 *send_off = *send_off || (pkt.flags() & uapi::VSOCK_FLAGS_SHUTDOWN_SEND != 0);
-                if *recv_off && *send_off && self.tx_buf.is_empty() {
+                if *recv_off && *send_off && !self.tx_pending() {
                     self.pending_rx.insert(PendingRx::Rst);
                 }
             }
@@ -385,6 +423,17 @@ where
             }
         };
 
+        // A host message may be waiting for peer credit, in order to be fully delivered. Since
+        // the host stream has nothing left to signal, we'll resume the delivery ourselves.
+        match self.state {
+            ConnState::Established | ConnState::PeerClosed(false, _)
+                if self.rx_msg_pending() && !self.need_credit_update_from_peer() =>
+            {
+                self.pending_rx.insert(PendingRx::Rw);
+            }
+            _ => (),
+        }
+
         Ok(())
     }
 
@@ -419,7 +468,7 @@ where
     /// - data can be written to the host stream, and the TX buffer needs to be flushed.
     fn get_polled_evset(&self) -> EventSet {
         let mut evset = EventSet::empty();
-        if !self.tx_buf.is_empty() {
+        if self.tx_pending() {
             // There's data waiting in the TX buffer, so we are interested in being notified
             // when writing to the host stream wouldn't block.
             evset.insert(EventSet::OUT);
@@ -429,6 +478,8 @@ where
         match self.state {
             ConnState::Killed | ConnState::LocalClosed | ConnState::PeerClosed(true, _) => (),
             _ if self.need_credit_update_from_peer() => (),
+            // No more messages should be read before the current one is fully delivered.
+            _ if self.rx_msg_pending() => (),
             _ => evset.insert(EventSet::IN),
         }
         evset
@@ -445,35 +496,39 @@ where
         if evset.contains(EventSet::OUT) {
             // Data can be written to the host stream. Time to flush out the TX buffer.
             //
-            if self.tx_buf.is_empty() {
+            if !self.tx_pending() {
                 METRICS.vsock.conn_event_fails.inc();
                 info!("vsock: connection received unexpected EPOLLOUT event");
                 return;
             }
-            let flushed = self
-                .tx_buf
-                .flush_to(&mut self.stream)
-                .unwrap_or_else(|err| {
-                    METRICS.vsock.tx_flush_fails.inc();
-                    warn!(
-                        "vsock: error flushing TX buf for (lp={}, pp={}): {:?}",
-                        self.local_port, self.peer_port, err
-                    );
-                    match err {
-                        Error::TxBufFlush(inner) if inner.kind() == ErrorKind::WouldBlock => {
-                            // This should never happen (EWOULDBLOCK after EPOLLOUT), but
-                            // it does, so let's absorb it.
-                        }
-                        _ => self.kill(),
-                    };
-                    0
-                });
-            self.fwd_cnt += Wrapping(flushed as u32);
-            METRICS.vsock.tx_bytes_count.add(flushed as usize);
+            if self.type_ == uapi::VSOCK_TYPE_SEQPACKET {
+                self.flush_tx_msgs();
+            } else {
+                let flushed = self
+                    .tx_buf
+                    .flush_to(&mut self.stream)
+                    .unwrap_or_else(|err| {
+                        METRICS.vsock.tx_flush_fails.inc();
+                        warn!(
+                            "vsock: error flushing TX buf for (lp={}, pp={}): {:?}",
+                            self.local_port, self.peer_port, err
+                        );
+                        match err {
+                            Error::TxBufFlush(inner) if inner.kind() == ErrorKind::WouldBlock => {
+                                // This should never happen (EWOULDBLOCK after EPOLLOUT), but
+                                // it does, so let's absorb it.
+                            }
+                            _ => self.kill(),
+                        };
+                        0
+                    });
+                self.fwd_cnt += Wrapping(flushed as u32);
+                METRICS.vsock.tx_bytes_count.add(flushed as usize);
+            }
 
             // If this connection was shutting down, but is waiting to drain the TX buffer
             // before forceful termination, the wait might be over.
-            if self.state == ConnState::PeerClosed(true, true) && self.tx_buf.is_empty() {
+            if self.state == ConnState::PeerClosed(true, true) && !self.tx_pending() {
                 self.pending_rx.insert(PendingRx::Rst);
             } else if self.peer_needs_credit_update() {
                 // If we've freed up some more buffer space, we may need to let the peer know it
@@ -503,8 +558,12 @@ where
             local_port,
             peer_port,
             stream,
+            type_: uapi::VSOCK_TYPE_STREAM,
             state: ConnState::PeerInit,
             tx_buf: TxBuf::new(),
+            tx_msg_buf: MsgBuf::new(),
+            rx_msg: Vec::new(),
+            rx_msg_off: 0,
             fwd_cnt: Wrapping(0),
             peer_buf_alloc,
             peer_fwd_cnt: Wrapping(0),
@@ -529,8 +588,12 @@ where
             local_port,
             peer_port,
             stream,
+            type_: uapi::VSOCK_TYPE_STREAM,
             state: ConnState::LocalInit,
             tx_buf: TxBuf::new(),
+            tx_msg_buf: MsgBuf::new(),
+            rx_msg: Vec::new(),
+            rx_msg_off: 0,
             fwd_cnt: Wrapping(0),
             peer_buf_alloc: 0,
             peer_fwd_cnt: Wrapping(0),
@@ -541,6 +604,18 @@ where
         }
     }
 
+    /// Turn this connection into a SOCK_SEQPACKET connection, backed by a sequential packet
+    /// host socket.
+    pub fn with_seqpacket(mut self) -> Self {
+        self.type_ = uapi::VSOCK_TYPE_SEQPACKET;
+        self
+    }
+
+    /// Get the connection type: either VSOCK_TYPE_STREAM or VSOCK_TYPE_SEQPACKET.
+    pub fn type_(&self) -> u16 {
+        self.type_
+    }
+
     /// Check if there is an expiry (kill) timer set for this connection, sometime in the
     /// future.
     pub fn will_expire(&self) -> bool {
@@ -636,6 +711,120 @@ where
         Ok(())
     }
 
+    /// Send a sequential packet data fragment to the host socket.
+    ///
+    /// Fragments are reassembled in the TX message buffer. Once the end of a message is reached,
+    /// the message is written to the host socket, or left in the buffer if the host socket
+    /// isn't ready to receive it.
+    fn send_msg_bytes(
+        &mut self,
+        mem: &GuestMemoryMmap,
+        pkt: &VsockPacket,
+    ) -> std::result::Result<(), VsockError> {
+        let len = pkt.len() as usize;
+        if len > 0 {
+            pkt.write_from_offset_to(mem, 0, &mut self.tx_msg_buf, len)?;
+        }
+        if !pkt.is_eom() {
+            return Ok(());
+        }
+
+        // If there are messages in the TX buffer, that means we're already registered for
+        // EPOLLOUT events on the underlying socket, and this message will get flushed along
+        // with the older ones.
+        let had_msgs = self.tx_msg_buf.has_msgs();
+        self.tx_msg_buf.end_msg();
+        if !had_msgs {
+            self.flush_tx_msgs();
+        }
+
+        Ok(())
+    }
+
+    /// Flush complete messages from the TX message buffer to the host socket.
+    fn flush_tx_msgs(&mut self) {
+        let flushed = self
+            .tx_msg_buf
+            .flush_to(&mut self.stream)
+            .unwrap_or_else(|err| {
+                match err {
+                    Error::TxBufFlush(ref inner) if inner.kind() == ErrorKind::WouldBlock => {
+                        // Absorb any would-block errors, since we can always try again later.
+                    }
+                    _ => {
+                        METRICS.vsock.tx_flush_fails.inc();
+                        warn!(
+                            "vsock: error flushing TX msg buf for (lp={}, pp={}): {:?}",
+                            self.local_port, self.peer_port, err
+                        );
+                        self.kill();
+                    }
+                };
+                0
+            });
+        self.fwd_cnt += Wrapping(flushed as u32);
+        METRICS.vsock.tx_bytes_count.add(flushed);
+    }
+
+    /// Read the next chunk of a host message into `pkt`, reading a new message from the
+    /// host socket first, if needed.
+    ///
+    /// Returns the number of bytes read into `pkt`, with `0` meaning that the host socket was
+    /// closed. Errors reading from the host socket are reported in the same way as
+    /// `VsockPacket::read_at_offset_from()` reports them.
+    fn read_msg_chunk(
+        &mut self,
+        pkt: &mut VsockPacket,
+        mem: &GuestMemoryMmap,
+        max_len: usize,
+    ) -> std::result::Result<usize, VsockError> {
+        if !self.rx_msg_pending() {
+            self.rx_msg.resize(defs::CONN_MAX_MSG_SIZE, 0);
+            let read_res = self.stream.read(&mut self.rx_msg);
+            match read_res {
+                // Note: an empty message can't be told apart from the host socket being closed,
+                // so it is handled as the latter.
+                Ok(cnt) => self.rx_msg.truncate(cnt),
+                Err(err) => {
+                    self.rx_msg.clear();
+                    return Err(VsockError::GuestMemoryMmap(GuestMemoryError::IOError(err)));
+                }
+            }
+            self.rx_msg_off = 0;
+            if self.rx_msg.is_empty() {
+                return Ok(0);
+            }
+        }
+
+        let len = std::cmp::min(max_len, self.rx_msg.len() - self.rx_msg_off);
+        let mut chunk = &self.rx_msg[self.rx_msg_off..self.rx_msg_off + len];
+        let read_cnt = pkt.read_at_offset_from(mem, 0, &mut chunk, len)?;
+        self.rx_msg_off += read_cnt;
+
+        if self.rx_msg_pending() {
+            // There's more of this message to deliver.
+            self.pending_rx.insert(PendingRx::Rw);
+        } else {
+            // Each host message is a complete record.
+            pkt.set_flag(uapi::VSOCK_FLAGS_SEQ_EOM)
+                .set_flag(uapi::VSOCK_FLAGS_SEQ_EOR);
+            self.rx_msg.clear();
+            self.rx_msg_off = 0;
+        }
+
+        Ok(read_cnt)
+    }
+
+    /// Check if a host message is in the process of being delivered to the peer.
+    fn rx_msg_pending(&self) -> bool {
+        self.rx_msg_off < self.rx_msg.len()
+    }
+
+    /// Check if there is any TX data waiting to be flushed to the host stream.
+    fn tx_pending(&self) -> bool {
+        !self.tx_buf.is_empty() || self.tx_msg_buf.has_msgs()
+    }
+
     /// Check if the credit information the peer has last received from us is outdated.
     fn peer_needs_credit_update(&self) -> bool {
         let peer_seen_free_buf =
@@ -661,7 +850,8 @@ where
             .set_dst_cid(self.peer_cid)
             .set_src_port(self.local_port)
             .set_dst_port(self.peer_port)
-            .set_type(uapi::VSOCK_TYPE_STREAM)
+            .set_type(self.type_)
+            .set_flags(0)
             .set_buf_alloc(defs::CONN_TX_BUF_SIZE)
             .set_fwd_cnt(self.fwd_cnt.0)
     }
@@ -808,6 +998,14 @@ mod tests {
         }
 
         fn new(conn_state: ConnState) -> Self {
+            Self::new_with_type(conn_state, uapi::VSOCK_TYPE_STREAM)
+        }
+
+        fn new_seqpacket() -> Self {
+            Self::new_with_type(ConnState::Established, uapi::VSOCK_TYPE_SEQPACKET)
+        }
+
+        fn new_with_type(conn_state: ConnState, type_: u16) -> Self {
             let vsock_test_ctx = TestContext::new();
             let mut handler_ctx = vsock_test_ctx.create_event_handler_context();
             let stream = TestStream::new();
@@ -845,6 +1043,10 @@ mod tests {
                 }
                 other => panic!("invalid ctx state: {:?}", other),
             };
+            let conn = match type_ {
+                uapi::VSOCK_TYPE_SEQPACKET => conn.with_seqpacket(),
+                _ => conn,
+            };
             assert_eq!(conn.state, conn_state);
             Self {
                 _vsock_test_ctx: vsock_test_ctx,
@@ -889,6 +1091,27 @@ mod tests {
             init_pkt(&mut self.pkt, op, len)
         }
 
+        fn init_msg_pkt(&mut self, data: &[u8], eom: bool) -> &VsockPacket {
+            self.init_data_pkt(data);
+            self.pkt
+                .set_type(uapi::VSOCK_TYPE_SEQPACKET)
+                .set_flags(if eom { uapi::VSOCK_FLAGS_SEQ_EOM } else { 0 });
+            &self.pkt
+        }
+
+        fn pkt_data(&self) -> Vec<u8> {
+            let mut buf = vec![];
+            self.pkt
+                .write_from_offset_to(
+                    &self._vsock_test_ctx.mem,
+                    0,
+                    &mut buf,
+                    self.pkt.len() as usize,
+                )
+                .unwrap();
+            buf
+        }
+
         fn init_data_pkt(&mut self, data: &[u8]) -> &VsockPacket {
             assert!(data.len() <= self.pkt.buf_size());
             self.init_pkt(uapi::VSOCK_OP_RW, data.len() as u32);
@@ -1264,4 +1487,214 @@ mod tests {
         ctx.recv();
         assert_eq!(ctx.pkt.op(), uapi::VSOCK_OP_RST);
     }
+
+    #[test]
+    fn test_seqpacket_peer_request() {
+        let mut ctx = CsmTestContext::new(ConnState::PeerInit);
+        ctx.conn = ctx.conn.with_seqpacket();
+        assert_eq!(ctx.conn.type_(), uapi::VSOCK_TYPE_SEQPACKET);
+        ctx.recv();
+        // The connection type should be reflected in all the packets yielded by the connection.
+        assert_eq!(ctx.pkt.op(), uapi::VSOCK_OP_RESPONSE);
+        assert_eq!(ctx.pkt.type_(), uapi::VSOCK_TYPE_SEQPACKET);
+        assert_eq!(ctx.conn.state, ConnState::Established);
+
+        ctx.conn.kill();
+        ctx.recv();
+        assert_eq!(ctx.pkt.op(), uapi::VSOCK_OP_RST);
+        assert_eq!(ctx.pkt.type_(), uapi::VSOCK_TYPE_SEQPACKET);
+    }
+
+    #[test]
+    fn test_seqpacket_rx_data() {
+        // Test case: a message that fits in a single RX buffer should be delivered in a single
+        // packet, marked as the end of both a message and a record.
+        {
+            let mut ctx = CsmTestContext::new_seqpacket();
+            let data = &[1, 2, 3, 4];
+            ctx.set_stream(TestStream::new_with_read_buf(data));
+            ctx.notify_epollin();
+            ctx.recv();
+            assert_eq!(ctx.pkt.op(), uapi::VSOCK_OP_RW);
+            assert_eq!(ctx.pkt.type_(), uapi::VSOCK_TYPE_SEQPACKET);
+            assert!(ctx.pkt.is_eom());
+            assert!(ctx.pkt.is_eor());
+            assert_eq!(ctx.pkt_data(), data);
+            assert!(!ctx.conn.has_pending_rx());
+        }
+
+        // Test case: a message that doesn't fit in a single RX buffer should be split across
+        // several packets, with only the last one marked as the end of the message.
+        {
+            let mut ctx = CsmTestContext::new_seqpacket();
+            let data: Vec<u8> = (0..ctx.pkt.buf_size() + 16).map(|i| i as u8).collect();
+            ctx.set_stream(TestStream::new_with_read_buf(&data));
+            ctx.notify_epollin();
+            ctx.recv();
+            assert_eq!(ctx.pkt.len() as usize, ctx.pkt.buf_size());
+            assert!(!ctx.pkt.is_eom());
+            let mut msg = ctx.pkt_data();
+
+            // No more data should be read from the host stream, until the message is fully
+            // delivered.
+            assert!(!ctx.conn.get_polled_evset().contains(EventSet::IN));
+            assert!(ctx.conn.has_pending_rx());
+            ctx.recv();
+            assert_eq!(ctx.pkt.len(), 16);
+            assert!(ctx.pkt.is_eom());
+            msg.extend(ctx.pkt_data());
+            assert_eq!(msg, data);
+            assert!(ctx.conn.get_polled_evset().contains(EventSet::IN));
+        }
+
+        // Test case: a message delivery that runs out of peer credit should be resumed once
+        // the peer sends a credit update.
+        {
+            let mut ctx = CsmTestContext::new_seqpacket();
+            let data = &[1, 2, 3, 4, 5, 6];
+            ctx.set_peer_credit(4);
+            ctx.set_stream(TestStream::new_with_read_buf(data));
+            ctx.notify_epollin();
+            ctx.recv();
+            assert_eq!(ctx.pkt_data(), &data[..4]);
+            assert!(!ctx.pkt.is_eom());
+            ctx.recv();
+            assert_eq!(ctx.pkt.op(), uapi::VSOCK_OP_CREDIT_REQUEST);
+            assert!(!ctx.conn.has_pending_rx());
+
+            let rx_cnt = ctx.conn.rx_cnt.0;
+            ctx.init_pkt(uapi::VSOCK_OP_CREDIT_UPDATE, 0)
+                .set_type(uapi::VSOCK_TYPE_SEQPACKET)
+                .set_fwd_cnt(rx_cnt);
+            ctx.send();
+            assert!(ctx.conn.has_pending_rx());
+            ctx.recv();
+            assert_eq!(ctx.pkt.op(), uapi::VSOCK_OP_RW);
+            assert_eq!(ctx.pkt_data(), &data[4..]);
+            assert!(ctx.pkt.is_eom());
+        }
+
+        // Test case: a closed host stream should have the connection shut down.
+        {
+            let mut ctx = CsmTestContext::new_seqpacket();
+            let mut stream = TestStream::new();
+            stream.read_state = StreamState::Closed;
+            ctx.set_stream(stream);
+            ctx.notify_epollin();
+            ctx.recv();
+            assert_eq!(ctx.pkt.op(), uapi::VSOCK_OP_SHUTDOWN);
+            assert!(ctx.conn.will_expire());
+        }
+
+        // Test case: a host stream read error should have the connection reset.
+        {
+            let mut ctx = CsmTestContext::new_seqpacket();
+            let mut stream = TestStream::new();
+            stream.read_state = StreamState::Error(ErrorKind::PermissionDenied);
+            ctx.set_stream(stream);
+            ctx.notify_epollin();
+            ctx.recv();
+            assert_eq!(ctx.pkt.op(), uapi::VSOCK_OP_RST);
+        }
+    }
+
+    #[test]
+    fn test_seqpacket_tx_data() {
+        let mut ctx = CsmTestContext::new_seqpacket();
+
+        // Fragments should be held back until the end of the message.
+        ctx.init_msg_pkt(&[1, 2, 3], false);
+        ctx.send();
+        ctx.init_msg_pkt(&[4, 5], false);
+        ctx.send();
+        assert!(ctx.conn.stream.write_buf.is_empty());
+        assert_eq!(ctx.conn.fwd_cnt, Wrapping(0));
+        // A partial message doesn't need flushing.
+        assert!(!ctx.conn.get_polled_evset().contains(EventSet::OUT));
+
+        ctx.init_msg_pkt(&[6], true);
+        ctx.send();
+        assert_eq!(ctx.conn.stream.write_buf, vec![1, 2, 3, 4, 5, 6]);
+        assert_eq!(ctx.conn.fwd_cnt, Wrapping(6));
+        assert!(ctx.conn.tx_msg_buf.is_empty());
+
+        // An empty packet can end a message, too.
+        ctx.init_msg_pkt(&[7, 8], false);
+        ctx.send();
+        ctx.init_pkt(uapi::VSOCK_OP_RW, 0)
+            .set_type(uapi::VSOCK_TYPE_SEQPACKET)
+            .set_flags(uapi::VSOCK_FLAGS_SEQ_EOM);
+        ctx.send();
+        assert_eq!(ctx.conn.stream.write_buf, vec![1, 2, 3, 4, 5, 6, 7, 8]);
+        assert_eq!(ctx.conn.fwd_cnt, Wrapping(8));
+    }
+
+    #[test]
+    fn test_seqpacket_tx_buffering() {
+        let mut ctx = CsmTestContext::new_seqpacket();
+
+        let mut stream = TestStream::new();
+        stream.write_state = StreamState::WouldBlock;
+        ctx.set_stream(stream);
+
+        // Complete messages that can't be written to the host stream should be buffered.
+        ctx.init_msg_pkt(&[1, 2, 3, 4], true);
+        ctx.send();
+        ctx.init_msg_pkt(&[5, 6], true);
+        ctx.send();
+        assert!(ctx.conn.get_polled_evset().contains(EventSet::OUT));
+        assert_eq!(ctx.conn.tx_msg_buf.len(), 6);
+        assert_eq!(ctx.conn.fwd_cnt, Wrapping(0));
+
+        // A peer shutdown should wait for the buffered messages to be flushed.
+        ctx.init_pkt(uapi::VSOCK_OP_SHUTDOWN, 0)
+            .set_flags(uapi::VSOCK_FLAGS_SHUTDOWN_RCV | uapi::VSOCK_FLAGS_SHUTDOWN_SEND);
+        ctx.send();
+        assert!(!ctx.conn.has_pending_rx());
+        assert!(ctx.conn.will_expire());
+
+        ctx.set_stream(TestStream::new());
+        ctx.notify_epollout();
+        assert!(ctx.conn.tx_msg_buf.is_empty());
+        assert_eq!(ctx.conn.stream.write_buf, vec![1, 2, 3, 4, 5, 6]);
+        assert_eq!(ctx.conn.fwd_cnt, Wrapping(6));
+        assert!(ctx.conn.has_pending_rx());
+        ctx.recv();
+        assert_eq!(ctx.pkt.op(), uapi::VSOCK_OP_RST);
+    }
+
+    #[test]
+    fn test_seqpacket_stream_write_error() {
+        let mut ctx = CsmTestContext::new_seqpacket();
+        let mut stream = TestStream::new();
+        stream.write_state = StreamState::Closed;
+        ctx.set_stream(stream);
+
+        ctx.init_msg_pkt(&[1, 2, 3, 4], true);
+        ctx.send();
+        assert_eq!(ctx.conn.state, ConnState::Killed);
+        ctx.recv();
+        assert_eq!(ctx.pkt.op(), uapi::VSOCK_OP_RST);
+    }
+
+    #[test]
+    fn test_seqpacket_peer_credit_misbehavior() {
+        let mut ctx = CsmTestContext::new_seqpacket();
+
+        // Fill up the TX message buffer, without ever ending the message.
+        let data = vec![0u8; ctx.pkt.buf_size()];
+        for _i in 0..(csm_defs::CONN_TX_BUF_SIZE / data.len() as u32) {
+            ctx.init_msg_pkt(data.as_slice(), false);
+            ctx.send();
+        }
+        assert_eq!(ctx.conn.state, ConnState::Established);
+
+        // Then try to send more data.
+        ctx.send();
+
+        // The connection should've committed suicide.
+        assert_eq!(ctx.conn.state, ConnState::Killed);
+        ctx.recv();
+        assert_eq!(ctx.pkt.op(), uapi::VSOCK_OP_RST);
+    }
 }
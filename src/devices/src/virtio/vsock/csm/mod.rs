@@ -4,6 +4,7 @@
 /// This module implements our vsock connection state machine. The heavy lifting is done by
 /// `connection::VsockConnection`, while this file only defines some constants and helper structs.
 mod connection;
+mod msgbuf;
 mod txbuf;
 
 use std::fmt;
@@ -23,6 +24,10 @@ pub mod defs {
 
     /// Connection graceful shutdown timeout, in millis.
     pub const CONN_SHUTDOWN_TIMEOUT_MS: u64 = 2000;
+
+    /// Maximum size of a message read from a SOCK_SEQPACKET host socket. Longer messages are
+    /// truncated.
+    pub const CONN_MAX_MSG_SIZE: usize = 64 * 1024;
 }
 
 #[derive(Debug)]
@@ -0,0 +1,256 @@
+// Copyright 2022 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+//
+
+use std::collections::VecDeque;
+use std::io::{ErrorKind, Write};
+
+use super::defs;
+use super::{Error, Result};
+
+/// A message buffer, used by SOCK_SEQPACKET vsock connections to reassemble and buffer TX
+/// (guest -> host) messages. Unlike `TxBuf`, message boundaries are preserved: guest messages
+/// can span several data packets, and each complete message needs to be written to the host
+/// socket with a single write.
+pub struct MsgBuf {
+    /// The message that is still being reassembled (i.e. its last packet, carrying the
+    /// VSOCK_FLAGS_SEQ_EOM flag, hasn't arrived yet).
+    partial: Vec<u8>,
+    /// Complete messages, waiting to be flushed out.
+    msgs: VecDeque<Vec<u8>>,
+    /// Total number of bytes held by this buffer (partial and complete messages).
+    len: usize,
+}
+
+impl MsgBuf {
+    /// Total buffer size, in bytes.
+    const SIZE: usize = defs::CONN_TX_BUF_SIZE as usize;
+
+    /// Message buffer constructor.
+    pub fn new() -> Self {
+        Self {
+            partial: Vec::new(),
+            msgs: VecDeque::new(),
+            len: 0,
+        }
+    }
+
+    /// Get the used length of this buffer - number of bytes that have been pushed in, but not
+    /// yet flushed out.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Append a byte slice to the message being reassembled.
+    ///
+    /// Either the entire source slice will be pushed to the buffer, or none of it, if there
+    /// isn't enough room, in which case `Err(Error::TxBufFull)` is returned.
+    pub fn push(&mut self, src: &[u8]) -> Result<()> {
+        if self.len + src.len() > Self::SIZE {
+            return Err(Error::TxBufFull);
+        }
+        self.partial.extend_from_slice(src);
+        self.len += src.len();
+        Ok(())
+    }
+
+    /// Mark the end of the message being reassembled, making it ready to be flushed out.
+    pub fn end_msg(&mut self) {
+        let msg = std::mem::take(&mut self.partial);
+        self.msgs.push_back(msg);
+    }
+
+    /// Flush complete messages to a writable socket, one write per message.
+    ///
+    /// Return the number of bytes that have been transferred out of the buffer and into the
+    /// socket. Flushing stops at the first message that can't be written out.
+    pub fn flush_to<W>(&mut self, sink: &mut W) -> Result<usize>
+    where
+        W: Write,
+    {
+        let mut flushed = 0;
+
+        while let Some(msg) = self.msgs.front() {
+            let written = match sink.write(msg) {
+                Ok(cnt) => cnt,
+                // We've already written some messages in a previous pass, so we'll consider the
+                // flush action a success, and try again later.
+                Err(_) if flushed > 0 => break,
+                Err(err) => return Err(Error::TxBufFlush(err)),
+            };
+            // Writes to a sequential packet socket are atomic. Anything other than a full
+            // write means the message boundary was lost.
+            if written != msg.len() {
+                return Err(Error::TxBufFlush(std::io::Error::from(
+                    ErrorKind::WriteZero,
+                )));
+            }
+
+            self.len -= written;
+            flushed += written;
+            self.msgs.pop_front();
+        }
+
+        Ok(flushed)
+    }
+
+    /// Check if the buffer holds any complete message that hasn't yet been flushed out.
+    pub fn has_msgs(&self) -> bool {
+        !self.msgs.is_empty()
+    }
+
+    /// Check if the buffer holds any data (complete or partial messages).
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+}
+
+impl Write for MsgBuf {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.push(buf)
+            .map(|()| buf.len())
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Error as IoError;
+    use std::io::Result as IoResult;
+
+    /// A sink that keeps track of message boundaries, i.e. one write is one message.
+    struct TestSink {
+        msgs: Vec<Vec<u8>>,
+        err: Option<IoError>,
+        max_msgs: usize,
+    }
+
+    impl TestSink {
+        fn new() -> Self {
+            Self {
+                msgs: Vec::new(),
+                err: None,
+                max_msgs: usize::MAX,
+            }
+        }
+    }
+
+    impl Write for TestSink {
+        fn write(&mut self, src: &[u8]) -> IoResult<usize> {
+            if self.err.is_some() {
+                return Err(self.err.take().unwrap());
+            }
+            if self.msgs.len() == self.max_msgs {
+                return Err(IoError::from(ErrorKind::WouldBlock));
+            }
+            self.msgs.push(src.to_vec());
+            Ok(src.len())
+        }
+        fn flush(&mut self) -> IoResult<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_reassembly() {
+        let mut msgbuf = MsgBuf::new();
+        let mut sink = TestSink::new();
+        assert!(msgbuf.is_empty());
+
+        // A partial message should not be flushed out.
+        msgbuf.push(&[1, 2]).unwrap();
+        msgbuf.write_all(&[3, 4]).unwrap();
+        assert!(!msgbuf.is_empty());
+        assert!(!msgbuf.has_msgs());
+        assert_eq!(msgbuf.flush_to(&mut sink).unwrap(), 0);
+        assert!(sink.msgs.is_empty());
+
+        msgbuf.end_msg();
+        msgbuf.push(&[5, 6, 7]).unwrap();
+        msgbuf.end_msg();
+        // Empty messages are valid, too.
+        msgbuf.end_msg();
+        assert!(msgbuf.has_msgs());
+        assert_eq!(msgbuf.len(), 7);
+
+        assert_eq!(msgbuf.flush_to(&mut sink).unwrap(), 7);
+        assert_eq!(sink.msgs, vec![vec![1, 2, 3, 4], vec![5, 6, 7], vec![]]);
+        assert!(msgbuf.is_empty());
+        assert!(!msgbuf.has_msgs());
+    }
+
+    #[test]
+    fn test_push_error() {
+        let mut msgbuf = MsgBuf::new();
+        let tmp = vec![0u8; MsgBuf::SIZE - 1];
+
+        // The limit applies to partial and complete messages alike.
+        msgbuf.push(tmp.as_slice()).unwrap();
+        msgbuf.end_msg();
+        match msgbuf.push(&[1, 2]) {
+            Err(Error::TxBufFull) => (),
+            other => panic!("Unexpected result: {:?}", other),
+        }
+
+        match msgbuf.write(&[1, 2]) {
+            Err(e) => {
+                assert_eq!(
+                    format!("{}", e),
+                    "Attempted to push data to a full TX buffer"
+                );
+            }
+            other => panic!("Unexpected result: {:?}", other),
+        }
+        msgbuf.push(&[1]).unwrap();
+    }
+
+    #[test]
+    fn test_incomplete_flush() {
+        let mut msgbuf = MsgBuf::new();
+        let mut sink = TestSink::new();
+
+        msgbuf.push(&[1, 2]).unwrap();
+        msgbuf.end_msg();
+        msgbuf.push(&[3, 4]).unwrap();
+        msgbuf.end_msg();
+
+        // Only the first message fits; the second one should stay in the buffer.
+        sink.max_msgs = 1;
+        assert_eq!(msgbuf.flush_to(&mut sink).unwrap(), 2);
+        assert_eq!(msgbuf.len(), 2);
+        assert!(msgbuf.has_msgs());
+
+        // Nothing fits anymore, so the flush should fail.
+        match msgbuf.flush_to(&mut sink) {
+            Err(Error::TxBufFlush(ref err)) if err.kind() == ErrorKind::WouldBlock => (),
+            other => panic!("Unexpected result: {:?}", other),
+        }
+
+        sink.max_msgs = 2;
+        assert_eq!(msgbuf.flush_to(&mut sink).unwrap(), 2);
+        assert!(msgbuf.is_empty());
+        assert_eq!(sink.msgs, vec![vec![1, 2], vec![3, 4]]);
+    }
+
+    #[test]
+    fn test_flush_error() {
+        const EACCESS: i32 = 13;
+
+        let mut msgbuf = MsgBuf::new();
+        let mut sink = TestSink::new();
+
+        msgbuf.push(&[1, 2, 3, 4]).unwrap();
+        msgbuf.end_msg();
+        sink.err = Some(IoError::from_raw_os_error(EACCESS));
+        match msgbuf.flush_to(&mut sink) {
+            Err(Error::TxBufFlush(ref err)) if err.kind() == ErrorKind::PermissionDenied => (),
+            other => panic!("Unexpected result: {:?}", other),
+        }
+        assert_eq!(msgbuf.len(), 4);
+    }
+}
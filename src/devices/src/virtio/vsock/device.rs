@@ -13,17 +13,20 @@ use std::result;
 /// https://docs.oasis-open.org/virtio/virtio/v1.1/virtio-v1.1.html
 ///
 /// The vsock device has two input parameters: a CID to identify the device, and a `VsockBackend`
-/// to use for offloading vsock traffic.
+/// to use for offloading vsock traffic. The packets exchanged with the backend can optionally be
+/// throttled by an RX and a TX rate limiter.
 ///
 /// Upon its activation, the vsock device registers handlers for the following events/FDs:
 /// - an RX queue FD;
 /// - a TX queue FD;
-/// - an event queue FD; and
-/// - a backend FD.
+/// - an event queue FD;
+/// - a backend FD; and
+/// - the RX and TX rate limiter FDs.
 use std::sync::atomic::AtomicUsize;
 use std::sync::Arc;
 
 use logger::{debug, error, warn, IncMetric, METRICS};
+use rate_limiter::{BucketUpdate, RateLimiter, TokenType};
 use utils::byte_order;
 use utils::eventfd::EventFd;
 use vm_memory::{Bytes, GuestMemoryMmap};
@@ -48,14 +51,18 @@ pub(crate) const VIRTIO_VSOCK_EVENT_TRANSPORT_RESET: u32 = 0;
 /// - VIRTIO_F_VERSION_1: the device conforms to at least version 1.0 of the VirtIO spec.
 /// - VIRTIO_F_IN_ORDER: the device returns used buffers in the same order that the driver makes
 ///   them available.
-pub(crate) const AVAIL_FEATURES: u64 =
-    1 << uapi::VIRTIO_F_VERSION_1 as u64 | 1 << uapi::VIRTIO_F_IN_ORDER as u64;
+/// - VIRTIO_VSOCK_F_SEQPACKET: the device supports SOCK_SEQPACKET connections.
+pub(crate) const AVAIL_FEATURES: u64 = 1 << uapi::VIRTIO_F_VERSION_1 as u64
+    | 1 << uapi::VIRTIO_F_IN_ORDER as u64
+    | 1 << uapi::VIRTIO_VSOCK_F_SEQPACKET as u64;
 
 pub struct Vsock<B> {
     cid: u64,
     pub(crate) queues: Vec<VirtQueue>,
     pub(crate) queue_events: Vec<EventFd>,
     pub(crate) backend: B,
+    pub(crate) rx_rate_limiter: RateLimiter,
+    pub(crate) tx_rate_limiter: RateLimiter,
     pub(crate) avail_features: u64,
     pub(crate) acked_features: u64,
     pub(crate) irq_trigger: IrqTrigger,
@@ -77,7 +84,13 @@ impl<B> Vsock<B>
 where
     B: VsockBackend,
 {
-    pub fn with_queues(cid: u64, backend: B, queues: Vec<VirtQueue>) -> super::Result<Vsock<B>> {
+    pub fn with_queues(
+        cid: u64,
+        backend: B,
+        queues: Vec<VirtQueue>,
+        rx_rate_limiter: RateLimiter,
+        tx_rate_limiter: RateLimiter,
+    ) -> super::Result<Vsock<B>> {
         let mut queue_events = Vec::new();
         for _ in 0..queues.len() {
             queue_events.push(EventFd::new(libc::EFD_NONBLOCK).map_err(VsockError::EventFd)?);
@@ -88,6 +101,8 @@ where
             queues,
             queue_events,
             backend,
+            rx_rate_limiter,
+            tx_rate_limiter,
             avail_features: AVAIL_FEATURES,
             acked_features: 0,
             irq_trigger: IrqTrigger::new().map_err(VsockError::EventFd)?,
@@ -96,13 +111,18 @@ where
         })
     }
 
-    /// Create a new virtio-vsock device with the given VM CID and vsock backend.
-    pub fn new(cid: u64, backend: B) -> super::Result<Vsock<B>> {
+    /// Create a new virtio-vsock device with the given VM CID, vsock backend and rate limiters.
+    pub fn new(
+        cid: u64,
+        backend: B,
+        rx_rate_limiter: RateLimiter,
+        tx_rate_limiter: RateLimiter,
+    ) -> super::Result<Vsock<B>> {
         let queues: Vec<VirtQueue> = defs::QUEUE_SIZES
             .iter()
             .map(|&max_size| VirtQueue::new(max_size))
             .collect();
-        Self::with_queues(cid, backend, queues)
+        Self::with_queues(cid, backend, queues, rx_rate_limiter, tx_rate_limiter)
     }
 
     pub fn id(&self) -> &str {
@@ -117,6 +137,33 @@ where
         &self.backend
     }
 
+    /// Provides a reference to the rate limiter applied to the packets sent to the guest.
+    pub fn rx_rate_limiter(&self) -> &RateLimiter {
+        &self.rx_rate_limiter
+    }
+
+    /// Provides a reference to the rate limiter applied to the packets sent by the guest.
+    pub fn tx_rate_limiter(&self) -> &RateLimiter {
+        &self.tx_rate_limiter
+    }
+
+    /// Updates the parameters for the rate limiters.
+    pub fn patch_rate_limiters(
+        &mut self,
+        rx_bytes: BucketUpdate,
+        rx_ops: BucketUpdate,
+        tx_bytes: BucketUpdate,
+        tx_ops: BucketUpdate,
+    ) {
+        self.rx_rate_limiter.update_buckets(rx_bytes, rx_ops);
+        self.tx_rate_limiter.update_buckets(tx_bytes, tx_ops);
+    }
+
+    /// Check if the driver has negotiated SOCK_SEQPACKET support.
+    pub fn seqpacket_negotiated(&self) -> bool {
+        self.acked_features & (1 << uapi::VIRTIO_VSOCK_F_SEQPACKET as u64) != 0
+    }
+
     /// Signal the guest driver that we've used some virtio buffers that it had previously made
     /// available.
     pub fn signal_used_queue(&self) -> result::Result<(), DeviceError> {
@@ -129,6 +176,11 @@ where
     /// Walk the driver-provided RX queue buffers and attempt to fill them up with any data that we
     /// have pending. Return `true` if descriptors have been added to the used ring, and `false`
     /// otherwise.
+    ///
+    /// The size of a packet is only known after the backend has filled it in, so the RX rate
+    /// limiter is checked for an operation token beforehand, and charged with the packet bytes
+    /// afterwards. A packet exceeding the bytes budget is still delivered, but the RX queue
+    /// processing is paused until the limiter gets replenished.
     pub fn process_rx(&mut self) -> bool {
         debug!("vsock: process_rx()");
         // This is safe since we checked in the event handler that the device is activated.
@@ -137,9 +189,21 @@ where
         let mut have_used = false;
 
         while let Some(head) = self.queues[RXQ_INDEX].pop(mem) {
+            if !self.rx_rate_limiter.consume(1, TokenType::Ops) {
+                self.queues[RXQ_INDEX].undo_pop();
+                METRICS.vsock.rx_rate_limiter_throttled.inc();
+                break;
+            }
+
             let used_len = match VsockPacket::from_rx_virtq_head(&head) {
                 Ok(mut pkt) => {
                     if self.backend.recv_pkt(&mut pkt, mem).is_ok() {
+                        if !self
+                            .rx_rate_limiter
+                            .consume(u64::from(pkt.len()), TokenType::Bytes)
+                        {
+                            METRICS.vsock.rx_rate_limiter_throttled.inc();
+                        }
                         match pkt.commit_hdr(mem) {
                             // This addition cannot overflow, because packet length
                             // is previously validated against `MAX_PKT_BUF_SIZE`
@@ -158,6 +222,7 @@ where
                         // We are using a consuming iterator over the virtio buffers, so, if we can't
                         // fill in this buffer, we'll need to undo the last iterator step.
                         self.queues[RXQ_INDEX].undo_pop();
+                        self.rx_rate_limiter.manual_replenish(1, TokenType::Ops);
                         break;
                     }
                 }
@@ -203,7 +268,39 @@ where
                 }
             };
 
+            // SOCK_SEQPACKET packets are only valid if the driver has negotiated support for
+            // them. Otherwise, they are discarded before reaching the backend.
+            if pkt.type_() == uapi::VSOCK_TYPE_SEQPACKET && !self.seqpacket_negotiated() {
+                warn!("vsock: discarding SOCK_SEQPACKET packet, feature not negotiated");
+                have_used = true;
+                self.queues[TXQ_INDEX]
+                    .add_used(mem, head.index, 0)
+                    .unwrap_or_else(|e| {
+                        error!("Failed to add available descriptor {}: {}", head.index, e);
+                    });
+                continue;
+            }
+
+            // The packet stays in the TX queue until the rate limiter allows it through.
+            if !self.tx_rate_limiter.consume(1, TokenType::Ops) {
+                self.queues[TXQ_INDEX].undo_pop();
+                METRICS.vsock.tx_rate_limiter_throttled.inc();
+                break;
+            }
+            if !self
+                .tx_rate_limiter
+                .consume(u64::from(pkt.len()), TokenType::Bytes)
+            {
+                self.tx_rate_limiter.manual_replenish(1, TokenType::Ops);
+                self.queues[TXQ_INDEX].undo_pop();
+                METRICS.vsock.tx_rate_limiter_throttled.inc();
+                break;
+            }
+
             if self.backend.send_pkt(&pkt, mem).is_err() {
+                self.tx_rate_limiter.manual_replenish(1, TokenType::Ops);
+                self.tx_rate_limiter
+                    .manual_replenish(u64::from(pkt.len()), TokenType::Bytes);
                 self.queues[TXQ_INDEX].undo_pop();
                 break;
             }
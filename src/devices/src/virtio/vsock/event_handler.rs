@@ -22,6 +22,8 @@
 /// - on backend event:
 ///   - forward the event to the backend; then
 ///   - again, attempt to fetch any incoming packets queued by the backend into virtio RX buffers.
+/// - on rate limiter event:
+///   - resume the TX or RX queue processing that the limiter had throttled.
 use std::os::unix::io::AsRawFd;
 
 use event_manager::{EventOps, Events, MutEventSubscriber};
@@ -98,6 +100,34 @@ where
         false
     }
 
+    pub fn handle_rx_rate_limiter_event(&mut self) -> bool {
+        debug!("vsock: RX rate limiter event");
+        METRICS.vsock.rx_rate_limiter_event_count.inc();
+
+        if let Err(e) = self.rx_rate_limiter.event_handler() {
+            error!("Failed to get vsock rx rate limiter event: {:?}", e);
+            return false;
+        }
+        // There might be enough budget now to deliver the pending packets.
+        self.backend.has_pending_rx() && self.process_rx()
+    }
+
+    pub fn handle_tx_rate_limiter_event(&mut self) -> bool {
+        debug!("vsock: TX rate limiter event");
+        METRICS.vsock.tx_rate_limiter_event_count.inc();
+
+        if let Err(e) = self.tx_rate_limiter.event_handler() {
+            error!("Failed to get vsock tx rate limiter event: {:?}", e);
+            return false;
+        }
+        // There might be enough budget now to send the packets left in the TX queue.
+        let mut raise_irq = self.process_tx();
+        if self.backend.has_pending_rx() {
+            raise_irq |= self.process_rx();
+        }
+        raise_irq
+    }
+
     pub fn notify_backend(&mut self, evset: EventSet) -> bool {
         debug!("vsock: backend event");
 
@@ -127,6 +157,12 @@ where
         if let Err(e) = ops.add(Events::new(&self.backend, self.backend.get_polled_evset())) {
             error!("Failed to register vsock backend event: {}", e);
         }
+        if let Err(e) = ops.add(Events::new(&self.rx_rate_limiter, EventSet::IN)) {
+            error!("Failed to register rx rate limiter event: {}", e);
+        }
+        if let Err(e) = ops.add(Events::new(&self.tx_rate_limiter, EventSet::IN)) {
+            error!("Failed to register tx rate limiter event: {}", e);
+        }
     }
 
     fn register_activate_event(&self, ops: &mut EventOps) {
@@ -158,6 +194,8 @@ where
         let txq = self.queue_events[TXQ_INDEX].as_raw_fd();
         let evq = self.queue_events[EVQ_INDEX].as_raw_fd();
         let backend = self.backend.as_raw_fd();
+        let rx_rate_limiter = self.rx_rate_limiter.as_raw_fd();
+        let tx_rate_limiter = self.tx_rate_limiter.as_raw_fd();
         let activate_evt = self.activate_evt.as_raw_fd();
 
         if self.is_activated() {
@@ -169,6 +207,8 @@ where
                 _ if source == backend => {
                     raise_irq = self.notify_backend(evset);
                 }
+                _ if source == rx_rate_limiter => raise_irq = self.handle_rx_rate_limiter_event(),
+                _ if source == tx_rate_limiter => raise_irq = self.handle_tx_rate_limiter_event(),
                 _ if source == activate_evt => {
                     self.handle_activate_event(ops);
                 }
@@ -201,13 +241,17 @@ where
 #[cfg(test)]
 mod tests {
     use std::sync::{Arc, Mutex};
+    use std::thread;
+    use std::time::Duration;
 
     use super::super::*;
     use super::*;
 
+    use crate::virtio::vsock::defs::uapi;
     use crate::virtio::vsock::packet::VSOCK_PKT_HDR_SIZE;
     use crate::virtio::vsock::test_utils::{EventHandlerContext, TestContext};
     use event_manager::{EventManager, SubscriberOps};
+    use rate_limiter::{RateLimiter, TokenType};
     use vm_memory::Bytes;
 
     #[test]
@@ -279,6 +323,32 @@ mod tests {
             assert_eq!(ctx.device.backend.tx_ok_cnt, 0);
         }
 
+        // Test case:
+        // - the driver sent a SOCK_SEQPACKET packet, without having negotiated the feature.
+        // - the driver sent a SOCK_SEQPACKET packet, after having negotiated the feature.
+        for negotiated in [false, true].iter() {
+            let test_ctx = TestContext::new();
+            let mut ctx = test_ctx.create_event_handler_context();
+            ctx.mock_activate(test_ctx.mem.clone());
+            if *negotiated {
+                ctx.device
+                    .set_acked_features(1 << uapi::VIRTIO_VSOCK_F_SEQPACKET as u64);
+            }
+
+            // The packet type is found at offset 28 in the packet header.
+            let type_addr = vm_memory::GuestAddress(ctx.guest_txvq.dtable[0].addr.get() + 28);
+            test_ctx
+                .mem
+                .write_obj(uapi::VSOCK_TYPE_SEQPACKET.to_le(), type_addr)
+                .unwrap();
+            ctx.signal_txq_event();
+
+            // The available descriptor should have been consumed either way, but the packet
+            // should only reach the backend if the feature was negotiated.
+            assert_eq!(ctx.guest_txvq.used.idx.get(), 1);
+            assert_eq!(ctx.device.backend.tx_ok_cnt, *negotiated as usize);
+        }
+
         // Test case: spurious TXQ_EVENT.
         {
             let test_ctx = TestContext::new();
@@ -401,6 +471,80 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_rate_limiter_event() {
+        // Test case:
+        // - the driver has something to send, but the TX rate limiter is out of budget; and
+        // - the TX queue processing resumes once the limiter gets replenished.
+        {
+            let test_ctx = TestContext::new();
+            let mut ctx = test_ctx.create_event_handler_context();
+            ctx.mock_activate(test_ctx.mem.clone());
+
+            // Allow 10 packets/s, and use up the budget.
+            let mut rl = RateLimiter::new(0, 0, 0, 1, 0, 100).unwrap();
+            assert!(rl.consume(1, TokenType::Ops));
+            ctx.device.tx_rate_limiter = rl;
+
+            let throttled = METRICS.vsock.tx_rate_limiter_throttled.count();
+            ctx.device.backend.set_pending_rx(false);
+            ctx.signal_txq_event();
+
+            // The TX packet should have been left in the queue.
+            assert!(ctx.device.tx_rate_limiter.is_blocked());
+            assert_eq!(
+                METRICS.vsock.tx_rate_limiter_throttled.count(),
+                throttled + 1
+            );
+            assert_eq!(ctx.guest_txvq.used.idx.get(), 0);
+            assert_eq!(ctx.device.backend.tx_ok_cnt, 0);
+
+            // Wait for the limiter timer to fire.
+            thread::sleep(Duration::from_millis(200));
+            assert!(ctx.device.handle_tx_rate_limiter_event());
+            assert!(!ctx.device.tx_rate_limiter.is_blocked());
+            assert_eq!(ctx.guest_txvq.used.idx.get(), 1);
+            assert_eq!(ctx.device.backend.tx_ok_cnt, 1);
+        }
+
+        // Test case:
+        // - the backend has pending RX data, but the RX rate limiter is out of budget; and
+        // - the RX queue processing resumes once the limiter gets replenished.
+        {
+            let test_ctx = TestContext::new();
+            let mut ctx = test_ctx.create_event_handler_context();
+            ctx.mock_activate(test_ctx.mem.clone());
+
+            let mut rl = RateLimiter::new(0, 0, 0, 1, 0, 100).unwrap();
+            assert!(rl.consume(1, TokenType::Ops));
+            ctx.device.rx_rate_limiter = rl;
+
+            ctx.device.backend.set_pending_rx(true);
+            ctx.signal_rxq_event();
+            assert!(ctx.device.rx_rate_limiter.is_blocked());
+            assert_eq!(ctx.guest_rxvq.used.idx.get(), 0);
+            assert_eq!(ctx.device.backend.rx_ok_cnt, 0);
+
+            thread::sleep(Duration::from_millis(200));
+            assert!(ctx.device.handle_rx_rate_limiter_event());
+            assert_eq!(ctx.guest_rxvq.used.idx.get(), 1);
+            assert_eq!(ctx.device.backend.rx_ok_cnt, 1);
+        }
+
+        // Test case: spurious rate limiter events.
+        {
+            let test_ctx = TestContext::new();
+            let mut ctx = test_ctx.create_event_handler_context();
+            ctx.mock_activate(test_ctx.mem.clone());
+
+            ctx.device.backend.set_pending_rx(true);
+            assert!(!ctx.device.handle_rx_rate_limiter_event());
+            assert!(!ctx.device.handle_tx_rate_limiter_event());
+            assert_eq!(ctx.guest_rxvq.used.idx.get(), 0);
+            assert_eq!(ctx.guest_txvq.used.idx.get(), 0);
+        }
+    }
+
     // Creates an epoll handler context and attempts to assemble a VsockPkt from the descriptor
     // chains available on the rx and tx virtqueues, but first it will set the addr and len
     // of the descriptor specified by desc_idx to the provided values. We are only using this
@@ -5,11 +5,13 @@
 // Use of this source code is governed by a BSD-style license that can be
 // found in the THIRD-PARTY file.
 
+mod backend;
 mod csm;
 mod device;
 mod event_handler;
 mod packet;
 pub mod persist;
+mod tcp;
 pub mod test_utils;
 mod unix;
 
@@ -17,9 +19,13 @@ use std::os::unix::io::AsRawFd;
 
 use crate::virtio::persist::Error as VirtioStateError;
 
+pub use self::backend::{VsockHostBackend, VsockHostBackendError};
 pub use self::defs::uapi::VIRTIO_ID_VSOCK as TYPE_VSOCK;
-pub use self::defs::VSOCK_DEV_ID;
+pub use self::defs::{VSOCK_DEV_ID, VSOCK_MAX_CONNECTIONS};
 pub use self::device::Vsock;
+pub use self::tcp::{
+    Error as VsockTcpBackendError, VsockTcpBackend, VsockTcpConfig, VsockTcpForward,
+};
 pub use self::unix::{Error as VsockUnixBackendError, VsockUnixBackend};
 
 use utils::epoll::EventSet;
@@ -43,6 +49,9 @@ mod defs {
     /// Max vsock packet data/buffer size.
     pub const MAX_PKT_BUF_SIZE: usize = 64 * 1024;
 
+    /// Maximum number of established connections that a backend can handle.
+    pub const VSOCK_MAX_CONNECTIONS: usize = 1023;
+
     pub mod uapi {
 
         /// Virtio feature flags.
@@ -54,6 +63,12 @@ mod defs {
         /// The device conforms to the virtio spec version 1.0.
         pub const VIRTIO_F_VERSION_1: u32 = 32;
 
+        /// Vsock feature flags.
+        /// Defined in `/include/uapi/linux/virtio_vsock.h`.
+        ///
+        /// The device supports SOCK_SEQPACKET sockets.
+        pub const VIRTIO_VSOCK_F_SEQPACKET: u32 = 1;
+
         /// Virtio vsock device ID.
         /// Defined in `include/uapi/linux/virtio_ids.h`.
         pub const VIRTIO_ID_VSOCK: u32 = 19;
@@ -83,12 +98,21 @@ mod defs {
         pub const VSOCK_FLAGS_SHUTDOWN_RCV: u32 = 1;
         /// Valid with a VSOCK_OP_SHUTDOWN packet: the packet sender will send no more data.
         pub const VSOCK_FLAGS_SHUTDOWN_SEND: u32 = 2;
+        /// Valid with a SOCK_SEQPACKET VSOCK_OP_RW packet: the packet carries the end of a
+        /// message.
+        pub const VSOCK_FLAGS_SEQ_EOM: u32 = 1;
+        /// Valid with a SOCK_SEQPACKET VSOCK_OP_RW packet: the packet carries the end of a
+        /// record (i.e. the message was sent with MSG_EOR).
+        pub const VSOCK_FLAGS_SEQ_EOR: u32 = 2;
 
         /// Vsock packet type.
         /// Defined in `/include/uapi/linux/virtio_vsock.h`.
         ///
-        /// Stream / connection-oriented packet (the only currently valid type).
+        /// Stream / connection-oriented packet.
         pub const VSOCK_TYPE_STREAM: u16 = 1;
+        /// Sequential packet / message-oriented, connection-oriented packet. Only valid when
+        /// VIRTIO_VSOCK_F_SEQPACKET has been negotiated.
+        pub const VSOCK_TYPE_SEQPACKET: u16 = 2;
 
         pub const VSOCK_HOST_CID: u64 = 2;
     }
@@ -116,6 +140,8 @@ pub enum VsockError {
     NoData,
     /// A data buffer was expected for the provided packet, but it is missing.
     PktBufMissing,
+    /// Error restoring a rate limiter.
+    RateLimiter(std::io::Error),
     /// Encountered an unexpected write-only virtio descriptor.
     UnreadableDescriptor,
     /// Encountered an unexpected read-only virtio descriptor.
@@ -127,6 +153,34 @@ pub enum VsockError {
 
 type Result<T> = std::result::Result<T, VsockError>;
 
+/// Limits enforced by the vsock backends on the connections they handle.
+#[derive(Clone, Debug, PartialEq)]
+pub struct VsockConnLimits {
+    /// Maximum number of established connections, guest and host initiated alike.
+    pub max_connections: usize,
+    /// Ports the guest is allowed to connect to. If `None`, guest-initiated connections are
+    /// allowed on any port.
+    pub allowed_ports: Option<Vec<u32>>,
+}
+
+impl Default for VsockConnLimits {
+    fn default() -> Self {
+        VsockConnLimits {
+            max_connections: defs::VSOCK_MAX_CONNECTIONS,
+            allowed_ports: None,
+        }
+    }
+}
+
+impl VsockConnLimits {
+    /// Checks whether the guest is allowed to connect to `port`.
+    pub fn allows_port(&self, port: u32) -> bool {
+        self.allowed_ports
+            .as_ref()
+            .map_or(true, |ports| ports.contains(&port))
+    }
+}
+
 /// A passive, event-driven object, that needs to be notified whenever an epoll-able event occurs.
 /// An event-polling control loop will use `as_raw_fd()` and `get_polled_evset()` to query
 /// the listener for the file descriptor and the set of events it's interested in. When such an
@@ -161,6 +215,10 @@ pub trait VsockChannel {
 }
 
 /// The vsock backend, which is basically an epoll-event-driven vsock channel.
-/// Currently, the only implementation we have is `crate::virtio::unix::muxer::VsockMuxer`, which
-/// translates guest-side vsock connections to host-side Unix domain socket connections.
+/// There are two implementations:
+/// - `crate::virtio::unix::muxer::VsockMuxer`, which translates guest-side vsock connections to
+///   host-side Unix domain socket connections; and
+/// - `crate::virtio::tcp::muxer::VsockTcpMuxer`, which translates guest-side vsock connections
+///   to host-side TCP connections.
+/// `VsockHostBackend` wraps either of them, so that the backend can be selected at runtime.
 pub trait VsockBackend: VsockChannel + VsockEpollListener + Send {}
@@ -57,7 +57,8 @@ pub struct VsockPacketHeader {
     dst_port: u32,
     // Data length (in bytes) - may be 0, if there is no data buffer.
     len: u32,
-    // Socket type. Currently, only connection-oriented streams are defined by the vsock protocol.
+    // Socket type - either a connection-oriented stream (VSOCK_TYPE_STREAM), or a
+    // connection-oriented, message-oriented sequential packet (VSOCK_TYPE_SEQPACKET).
     type_: u16,
     // Operation ID - one of the VSOCK_OP_* values; e.g.
     // - VSOCK_OP_RW: a data packet;
@@ -66,7 +67,8 @@ pub struct VsockPacketHeader {
     // etc (see `super::defs::uapi` for the full list).
     op: u16,
     // Additional options (flags) associated with the current operation (`op`).
-    // Currently, only used with shutdown requests (VSOCK_OP_SHUTDOWN).
+    // Currently, only used with shutdown requests (VSOCK_OP_SHUTDOWN), and with sequential
+    // packet data (VSOCK_OP_RW), to mark message and record boundaries.
     flags: u32,
     // Size (in bytes) of the packet sender receive buffer (for the connection to which this packet
     // belongs).
@@ -383,6 +385,20 @@ impl VsockPacket {
         self
     }
 
+    /// Check if this sequential packet data packet carries the end of a message.
+    pub fn is_eom(&self) -> bool {
+        self.type_() == defs::uapi::VSOCK_TYPE_SEQPACKET
+            && self.op() == defs::uapi::VSOCK_OP_RW
+            && self.flags() & defs::uapi::VSOCK_FLAGS_SEQ_EOM != 0
+    }
+
+    /// Check if this sequential packet data packet carries the end of a record.
+    pub fn is_eor(&self) -> bool {
+        self.type_() == defs::uapi::VSOCK_TYPE_SEQPACKET
+            && self.op() == defs::uapi::VSOCK_OP_RW
+            && self.flags() & defs::uapi::VSOCK_FLAGS_SEQ_EOR != 0
+    }
+
     pub fn buf_alloc(&self) -> u32 {
         u32::from_le(self.hdr.buf_alloc)
     }
@@ -411,7 +427,7 @@ mod tests {
 
     use super::*;
     use crate::virtio::test_utils::VirtqDesc as GuestQDesc;
-    use crate::virtio::vsock::defs::MAX_PKT_BUF_SIZE;
+    use crate::virtio::vsock::defs::{uapi, MAX_PKT_BUF_SIZE};
     use crate::virtio::vsock::device::{RXQ_INDEX, TXQ_INDEX};
     use crate::virtio::vsock::test_utils::TestContext;
     use crate::virtio::VIRTQ_DESC_F_WRITE;
@@ -648,6 +664,22 @@ mod tests {
         pkt.set_flag(0b1000);
         assert_eq!(pkt.flags(), flags);
 
+        // Test message and record boundary flags. These are only valid for sequential packet
+        // data packets.
+        pkt.set_type(uapi::VSOCK_TYPE_STREAM)
+            .set_op(uapi::VSOCK_OP_RW)
+            .set_flags(uapi::VSOCK_FLAGS_SEQ_EOM | uapi::VSOCK_FLAGS_SEQ_EOR);
+        assert!(!pkt.is_eom());
+        assert!(!pkt.is_eor());
+        pkt.set_type(uapi::VSOCK_TYPE_SEQPACKET);
+        assert!(pkt.is_eom());
+        assert!(pkt.is_eor());
+        pkt.set_flags(uapi::VSOCK_FLAGS_SEQ_EOM);
+        assert!(pkt.is_eom());
+        assert!(!pkt.is_eor());
+        pkt.set_op(uapi::VSOCK_OP_SHUTDOWN);
+        assert!(!pkt.is_eom());
+
         pkt.hdr = VsockPacketHeader::default();
         assert_eq!(pkt.src_cid(), 0);
         assert_eq!(pkt.dst_cid(), 0);
@@ -3,10 +3,12 @@
 
 //! Defines state and support structures for persisting Vsock devices and backends.
 
+use std::net::SocketAddr;
 use std::sync::atomic::AtomicUsize;
 use std::sync::Arc;
 
 use super::*;
+use rate_limiter::{persist::RateLimiterState, RateLimiter};
 use snapshot::Persist;
 use versionize::{VersionMap, Versionize, VersionizeError, VersionizeResult};
 use versionize_derive::Versionize;
@@ -28,6 +30,32 @@ pub struct VsockState {
 pub struct VsockFrontendState {
     pub cid: u64,
     virtio_state: VirtioDeviceState,
+    #[version(start = 2, ser_fn = "rx_rate_limiter_serialize")]
+    rx_rate_limiter_state: Option<RateLimiterState>,
+    #[version(start = 2, ser_fn = "tx_rate_limiter_serialize")]
+    tx_rate_limiter_state: Option<RateLimiterState>,
+}
+
+impl VsockFrontendState {
+    fn rx_rate_limiter_serialize(&mut self, target_version: u16) -> VersionizeResult<()> {
+        if target_version < 2 && self.rx_rate_limiter_state.is_some() {
+            return Err(VersionizeError::Semantic(
+                "Target version does not implement the vsock RX rate limiter.".to_owned(),
+            ));
+        }
+
+        Ok(())
+    }
+
+    fn tx_rate_limiter_serialize(&mut self, target_version: u16) -> VersionizeResult<()> {
+        if target_version < 2 && self.tx_rate_limiter_state.is_some() {
+            return Err(VersionizeError::Semantic(
+                "Target version does not implement the vsock TX rate limiter.".to_owned(),
+            ));
+        }
+
+        Ok(())
+    }
 }
 
 /// An enum for the serializable backend state types.
@@ -35,6 +63,16 @@ pub struct VsockFrontendState {
 // NOTICE: Any changes to this structure require a snapshot version bump.
 pub enum VsockBackendState {
     Uds(VsockUdsState),
+    #[version(start = 2, default_fn = "default_tcp")]
+    Tcp(VsockTcpState),
+}
+
+impl VsockBackendState {
+    fn default_tcp(&self, _target_version: u16) -> VersionizeResult<Self> {
+        Err(VersionizeError::Semantic(
+            "Target version does not implement the vsock TCP backend.".to_owned(),
+        ))
+    }
 }
 
 /// The Vsock Unix Backend serializable state.
@@ -43,40 +81,155 @@ pub enum VsockBackendState {
 pub struct VsockUdsState {
     /// The path for the UDS socket.
     pub(crate) path: String,
+    /// The connection limits, if different from the defaults.
+    #[version(start = 2, ser_fn = "conn_limits_serialize")]
+    pub(crate) conn_limits: Option<VsockConnLimitsState>,
+}
+
+impl VsockUdsState {
+    fn conn_limits_serialize(&mut self, target_version: u16) -> VersionizeResult<()> {
+        // Silently dropping the limits would let the guest reach ports it was denied.
+        if target_version < 2 && self.conn_limits.is_some() {
+            return Err(VersionizeError::Semantic(
+                "Target version does not implement vsock connection limits.".to_owned(),
+            ));
+        }
+
+        Ok(())
+    }
+}
+
+/// The vsock connection limits serializable state.
+#[derive(Clone, Versionize)]
+// NOTICE: Any changes to this structure require a snapshot version bump.
+pub struct VsockConnLimitsState {
+    max_connections: u64,
+    allowed_ports: Option<Vec<u32>>,
+}
+
+impl VsockConnLimitsState {
+    /// Returns the state of `conn_limits`, or `None` if these are the default limits.
+    fn from_limits(conn_limits: &VsockConnLimits) -> Option<Self> {
+        if *conn_limits == VsockConnLimits::default() {
+            return None;
+        }
+        Some(VsockConnLimitsState {
+            max_connections: conn_limits.max_connections as u64,
+            allowed_ports: conn_limits.allowed_ports.clone(),
+        })
+    }
+
+    fn to_limits(state: &Option<Self>) -> VsockConnLimits {
+        state
+            .as_ref()
+            .map_or_else(VsockConnLimits::default, |state| VsockConnLimits {
+                max_connections: state.max_connections as usize,
+                allowed_ports: state.allowed_ports.clone(),
+            })
+    }
+}
+
+/// A guest port to host TCP address mapping.
+#[derive(Clone, Versionize)]
+// NOTICE: Any changes to this structure require a snapshot version bump.
+pub struct VsockTcpForwardState {
+    guest_port: u32,
+    host_addr: String,
 }
 
-/// A helper structure that holds the constructor arguments for VsockUnixBackend
+/// The Vsock TCP Backend serializable state.
+#[derive(Clone, Versionize)]
+// NOTICE: Any changes to this structure require a snapshot version bump.
+pub struct VsockTcpState {
+    connect: Vec<VsockTcpForwardState>,
+    listen: Vec<VsockTcpForwardState>,
+    conn_limits: Option<VsockConnLimitsState>,
+}
+
+impl VsockTcpState {
+    fn new(config: &VsockTcpConfig, conn_limits: &VsockConnLimits) -> Self {
+        let to_state = |forwards: &Vec<VsockTcpForward>| {
+            forwards
+                .iter()
+                .map(|forward| VsockTcpForwardState {
+                    guest_port: forward.guest_port,
+                    host_addr: forward.host_addr.to_string(),
+                })
+                .collect()
+        };
+        VsockTcpState {
+            connect: to_state(&config.connect),
+            listen: to_state(&config.listen),
+            conn_limits: VsockConnLimitsState::from_limits(conn_limits),
+        }
+    }
+
+    fn to_config(&self) -> std::result::Result<VsockTcpConfig, VsockHostBackendError> {
+        let to_config = |forwards: &Vec<VsockTcpForwardState>| {
+            forwards
+                .iter()
+                .map(|forward| {
+                    Ok(VsockTcpForward {
+                        guest_port: forward.guest_port,
+                        host_addr: forward.host_addr.parse::<SocketAddr>().map_err(|_| {
+                            VsockHostBackendError::InvalidTcpAddr(forward.host_addr.clone())
+                        })?,
+                    })
+                })
+                .collect::<std::result::Result<Vec<_>, _>>()
+        };
+        Ok(VsockTcpConfig {
+            connect: to_config(&self.connect)?,
+            listen: to_config(&self.listen)?,
+        })
+    }
+}
+
+/// A helper structure that holds the constructor arguments for the Vsock device.
 pub struct VsockConstructorArgs<B> {
     pub mem: GuestMemoryMmap,
     pub backend: B,
 }
 
-/// A helper structure that holds the constructor arguments for VsockUnixBackend
+/// A helper structure that holds the constructor arguments for VsockHostBackend
 pub struct VsockUdsConstructorArgs {
     // cid available in VsockFrontendState.
     pub cid: u64,
 }
 
-impl Persist<'_> for VsockUnixBackend {
+impl Persist<'_> for VsockHostBackend {
     type State = VsockBackendState;
     type ConstructorArgs = VsockUdsConstructorArgs;
-    type Error = VsockUnixBackendError;
+    type Error = VsockHostBackendError;
 
     fn save(&self) -> Self::State {
-        VsockBackendState::Uds(VsockUdsState {
-            path: self.host_sock_path.clone(),
-        })
+        match self {
+            VsockHostBackend::Unix(backend) => VsockBackendState::Uds(VsockUdsState {
+                path: backend.host_sock_path.clone(),
+                conn_limits: VsockConnLimitsState::from_limits(backend.conn_limits()),
+            }),
+            VsockHostBackend::Tcp(backend) => {
+                VsockBackendState::Tcp(VsockTcpState::new(backend.config(), backend.conn_limits()))
+            }
+        }
     }
 
     fn restore(
         constructor_args: Self::ConstructorArgs,
         state: &Self::State,
     ) -> std::result::Result<Self, Self::Error> {
+        // Connections are not saved, so the guest sees them being reset after restore.
         match state {
-            VsockBackendState::Uds(uds_state) => Ok(VsockUnixBackend::new(
+            VsockBackendState::Uds(uds_state) => VsockHostBackend::new_unix(
                 constructor_args.cid,
                 uds_state.path.clone(),
-            )?),
+                VsockConnLimitsState::to_limits(&uds_state.conn_limits),
+            ),
+            VsockBackendState::Tcp(tcp_state) => VsockHostBackend::new_tcp(
+                constructor_args.cid,
+                tcp_state.to_config()?,
+                VsockConnLimitsState::to_limits(&tcp_state.conn_limits),
+            ),
         }
     }
 }
@@ -90,9 +243,20 @@ where
     type Error = VsockError;
 
     fn save(&self) -> Self::State {
+        // Unconfigured rate limiters are not saved, so that the state stays compatible with
+        // versions which don't implement them.
+        let rate_limiter_state = |rate_limiter: &RateLimiter| {
+            if rate_limiter.bandwidth().is_none() && rate_limiter.ops().is_none() {
+                None
+            } else {
+                Some(rate_limiter.save())
+            }
+        };
         VsockFrontendState {
             cid: self.cid(),
             virtio_state: VirtioDeviceState::from_device(self),
+            rx_rate_limiter_state: rate_limiter_state(&self.rx_rate_limiter),
+            tx_rate_limiter_state: rate_limiter_state(&self.tx_rate_limiter),
         }
     }
 
@@ -110,7 +274,23 @@ where
                 defs::QUEUE_SIZE,
             )
             .map_err(VsockError::VirtioState)?;
-        let mut vsock = Self::with_queues(state.cid, constructor_args.backend, queues)?;
+        let restore_rate_limiter = |state: &Option<RateLimiterState>| {
+            state.as_ref().map_or_else(
+                || Ok(RateLimiter::default()),
+                |state| RateLimiter::restore((), state),
+            )
+        };
+        let rx_rate_limiter =
+            restore_rate_limiter(&state.rx_rate_limiter_state).map_err(VsockError::RateLimiter)?;
+        let tx_rate_limiter =
+            restore_rate_limiter(&state.tx_rate_limiter_state).map_err(VsockError::RateLimiter)?;
+        let mut vsock = Self::with_queues(
+            state.cid,
+            constructor_args.backend,
+            queues,
+            rx_rate_limiter,
+            tx_rate_limiter,
+        )?;
 
         vsock.acked_features = state.virtio_state.acked_features;
         vsock.avail_features = state.virtio_state.avail_features;
@@ -142,6 +322,7 @@ pub(crate) mod tests {
         fn save(&self) -> Self::State {
             VsockBackendState::Uds(VsockUdsState {
                 path: "test".to_owned(),
+                conn_limits: None,
             })
         }
 
@@ -150,7 +331,7 @@ pub(crate) mod tests {
             state: &Self::State,
         ) -> std::result::Result<Self, Self::Error> {
             match state {
-                VsockBackendState::Uds(_) => Ok(TestBackend::new()),
+                VsockBackendState::Uds(_) | VsockBackendState::Tcp(_) => Ok(TestBackend::new()),
             }
         }
     }
@@ -192,6 +373,7 @@ pub(crate) mod tests {
                         assert_eq!(uds_state.path, "test".to_owned());
                         TestBackend::new()
                     }
+                    VsockBackendState::Tcp(_) => unreachable!(),
                 },
             },
             &restored_state.frontend,
@@ -235,4 +417,109 @@ pub(crate) mod tests {
         restored_device.read_config(2, &mut data);
         assert_eq!(data, [0u8, 1, 2, 3, 4, 5, 6, 7]);
     }
+
+    #[test]
+    fn test_persist_tcp_backend() {
+        let config = VsockTcpConfig {
+            connect: vec![VsockTcpForward {
+                guest_port: 1026,
+                host_addr: "127.0.0.1:8080".parse().unwrap(),
+            }],
+            listen: vec![VsockTcpForward {
+                guest_port: 1025,
+                host_addr: "127.0.0.1:0".parse().unwrap(),
+            }],
+        };
+        let conn_limits = VsockConnLimits {
+            max_connections: 16,
+            allowed_ports: Some(vec![1026]),
+        };
+        let backend = VsockHostBackend::new_tcp(3, config.clone(), conn_limits.clone()).unwrap();
+
+        let mut mem = vec![0; 4096];
+        let mut version_map = VersionMap::new();
+        version_map
+            .new_version()
+            .set_type_version(VsockBackendState::type_id(), 2);
+
+        // The backend cannot be saved in a version which does not support it.
+        assert!(backend
+            .save()
+            .serialize(&mut mem.as_mut_slice(), &version_map, 1)
+            .is_err());
+
+        backend
+            .save()
+            .serialize(&mut mem.as_mut_slice(), &version_map, 2)
+            .unwrap();
+        drop(backend);
+
+        let restored_backend = VsockHostBackend::restore(
+            VsockUdsConstructorArgs { cid: 3 },
+            &VsockBackendState::deserialize(&mut mem.as_slice(), &version_map, 2).unwrap(),
+        )
+        .unwrap();
+        assert_eq!(restored_backend.tcp_config(), Some(&config));
+        assert_eq!(restored_backend.conn_limits(), &conn_limits);
+        assert!(restored_backend.host_sock_path().is_none());
+    }
+
+    #[test]
+    fn test_persist_limits() {
+        let mut ctx = TestContext::new();
+        ctx.device.rx_rate_limiter = RateLimiter::new(1000, 0, 100, 0, 0, 0).unwrap();
+
+        let mut mem = vec![0; 4096];
+        let mut version_map = VersionMap::new();
+        version_map
+            .new_version()
+            .set_type_version(VsockFrontendState::type_id(), 2)
+            .set_type_version(VsockUdsState::type_id(), 2);
+
+        // Configured rate limiters cannot be saved in a version which does not support them.
+        assert!(ctx
+            .device
+            .save()
+            .serialize(&mut mem.as_mut_slice(), &version_map, 1)
+            .is_err());
+
+        ctx.device
+            .save()
+            .serialize(&mut mem.as_mut_slice(), &version_map, 2)
+            .unwrap();
+        let restored_device = Vsock::restore(
+            VsockConstructorArgs {
+                mem: ctx.mem.clone(),
+                backend: TestBackend::new(),
+            },
+            &VsockFrontendState::deserialize(&mut mem.as_slice(), &version_map, 2).unwrap(),
+        )
+        .unwrap();
+        assert_eq!(restored_device.rx_rate_limiter, ctx.device.rx_rate_limiter);
+        assert_eq!(restored_device.tx_rate_limiter, RateLimiter::default());
+
+        // The same goes for connection limits.
+        let state = VsockUdsState {
+            path: "test".to_owned(),
+            conn_limits: VsockConnLimitsState::from_limits(&VsockConnLimits {
+                max_connections: 1,
+                allowed_ports: None,
+            }),
+        };
+        assert!(state
+            .serialize(&mut mem.as_mut_slice(), &version_map, 1)
+            .is_err());
+        state
+            .serialize(&mut mem.as_mut_slice(), &version_map, 2)
+            .unwrap();
+        let restored_state =
+            VsockUdsState::deserialize(&mut mem.as_slice(), &version_map, 2).unwrap();
+        assert_eq!(
+            VsockConnLimitsState::to_limits(&restored_state.conn_limits).max_connections,
+            1
+        );
+
+        // Default limits are not saved at all.
+        assert!(VsockConnLimitsState::from_limits(&VsockConnLimits::default()).is_none());
+    }
 }
@@ -0,0 +1,132 @@
+// Copyright 2022 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+//
+
+/// This module implements the TCP backend for vsock - a mediator between guest-side AF_VSOCK
+/// sockets and host-side TCP endpoints. Unlike the Unix domain sockets backend, vsock ports are
+/// mapped to TCP addresses through a static port map:
+/// - a guest-initiated connection to a vsock port is forwarded to the host address configured
+///   for that port; and
+/// - a connection accepted on one of the configured host addresses is forwarded to the guest
+///   port configured for that address.
+/// Connection states are handled by `super::csm::VsockConnection`, while the RX and kill queues
+/// are shared with the Unix domain sockets backend.
+mod muxer;
+
+use std::io;
+use std::mem;
+use std::net::{SocketAddr, TcpStream};
+use std::os::unix::io::FromRawFd;
+
+pub use muxer::VsockTcpMuxer as VsockTcpBackend;
+
+/// Associates a guest vsock port with a host TCP address.
+#[derive(Clone, Debug, PartialEq)]
+pub struct VsockTcpForward {
+    /// The guest vsock port.
+    pub guest_port: u32,
+    /// The host TCP address.
+    pub host_addr: SocketAddr,
+}
+
+/// Port map of the TCP vsock backend.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct VsockTcpConfig {
+    /// Guest-initiated connections to `guest_port` are forwarded to `host_addr`.
+    pub connect: Vec<VsockTcpForward>,
+    /// Connections accepted on `host_addr` are forwarded to `guest_port`.
+    pub listen: Vec<VsockTcpForward>,
+}
+
+#[derive(Debug)]
+pub enum Error {
+    /// The same guest port is mapped to more than one host address.
+    DuplicateGuestPort(u32),
+    /// Error registering a new epoll-listening FD.
+    EpollAdd(std::io::Error),
+    /// Error creating an epoll FD.
+    EpollFdCreate(std::io::Error),
+    /// Error accepting a new connection on a host TCP listener.
+    TcpAccept(std::io::Error),
+    /// Error binding a host TCP listener.
+    TcpBind(std::io::Error),
+    /// Error connecting to a host TCP endpoint.
+    TcpConnect(std::io::Error),
+    /// Muxer connection limit reached.
+    TooManyConnections,
+}
+
+type Result<T> = std::result::Result<T, Error>;
+type MuxerConnection = super::csm::VsockConnection<TcpStream>;
+
+/// Starts a non-blocking connection attempt to `addr`.
+///
+/// The returned stream becomes writable once the attempt completes, and its outcome can be
+/// retrieved afterwards using `TcpStream::take_error`.
+fn connect(addr: &SocketAddr) -> io::Result<TcpStream> {
+    let family = match addr {
+        SocketAddr::V4(_) => libc::AF_INET,
+        SocketAddr::V6(_) => libc::AF_INET6,
+    };
+    // This is safe because we check the return value.
+    let fd = unsafe {
+        libc::socket(
+            family,
+            libc::SOCK_STREAM | libc::SOCK_NONBLOCK | libc::SOCK_CLOEXEC,
+            0,
+        )
+    };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // This is safe because we just created the file descriptor, and nothing else owns it.
+    let stream = unsafe { TcpStream::from_raw_fd(fd) };
+
+    // This is safe because the socket addresses are valid for their respective families, and we
+    // check the return value.
+    let ret = match addr {
+        SocketAddr::V4(addr) => {
+            let sockaddr = libc::sockaddr_in {
+                sin_family: libc::AF_INET as libc::sa_family_t,
+                sin_port: addr.port().to_be(),
+                sin_addr: libc::in_addr {
+                    s_addr: u32::from(*addr.ip()).to_be(),
+                },
+                sin_zero: [0; 8],
+            };
+            unsafe {
+                libc::connect(
+                    fd,
+                    &sockaddr as *const libc::sockaddr_in as *const libc::sockaddr,
+                    mem::size_of::<libc::sockaddr_in>() as libc::socklen_t,
+                )
+            }
+        }
+        SocketAddr::V6(addr) => {
+            let sockaddr = libc::sockaddr_in6 {
+                sin6_family: libc::AF_INET6 as libc::sa_family_t,
+                sin6_port: addr.port().to_be(),
+                sin6_flowinfo: addr.flowinfo(),
+                sin6_addr: libc::in6_addr {
+                    s6_addr: addr.ip().octets(),
+                },
+                sin6_scope_id: addr.scope_id(),
+            };
+            unsafe {
+                libc::connect(
+                    fd,
+                    &sockaddr as *const libc::sockaddr_in6 as *const libc::sockaddr,
+                    mem::size_of::<libc::sockaddr_in6>() as libc::socklen_t,
+                )
+            }
+        }
+    };
+    if ret < 0 {
+        let err = io::Error::last_os_error();
+        if err.raw_os_error() != Some(libc::EINPROGRESS) {
+            return Err(err);
+        }
+    }
+
+    Ok(stream)
+}
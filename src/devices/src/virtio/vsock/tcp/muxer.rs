@@ -0,0 +1,1031 @@
+// Copyright 2022 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+//
+
+/// `VsockTcpMuxer` is the device-facing component of the TCP vsock backend. It follows the same
+/// design as the Unix domain sockets muxer (see `unix/muxer.rs`):
+/// 1. Vsock connection multiplexer:
+///    Connections are stored in a `HashMap`, keyed by a (host_port, guest_port) tuple, and
+///    guest packets are routed to their owning `VsockConnection`. Connection request packets
+///    sent to a mapped guest port lead to a new TCP connection to the host address configured
+///    for that port.
+/// 2. Event dispatcher
+///    The muxer registers a nested epoll FD into the main VMM epolling loop, and routes the
+///    events triggered under it to either:
+///    1. a listening TCP socket, when a new host-initiated connection is ready to be accepted;
+///       the connection is immediately forwarded to the guest port mapped to the listening
+///       address, since there is no handshake to be read from the host end;
+///    2. a connecting TCP socket, when a non-blocking connection attempt made on behalf of the
+///       guest completes; the guest only gets a response (or an RST) at that point; or
+///    3. a connected TCP socket, that belongs to a `VsockConnection`.
+use std::collections::{HashMap, HashSet};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::os::unix::io::{AsRawFd, RawFd};
+
+use logger::{debug, error, info, warn, IncMetric, METRICS};
+use utils::epoll::{ControlOperation, Epoll, EpollEvent, EventSet};
+use vm_memory::GuestMemoryMmap;
+
+use super::super::defs::uapi;
+use super::super::packet::VsockPacket;
+use super::super::unix::{ConnMapKey, MuxerKillQ, MuxerRx, MuxerRxQ};
+use super::super::{
+    Result as VsockResult, VsockBackend, VsockChannel, VsockConnLimits, VsockEpollListener,
+    VsockError,
+};
+use super::MuxerConnection;
+use super::{connect, Error, Result, VsockTcpConfig};
+
+/// An epoll listener, registered under the muxer's nested epoll FD.
+enum EpollListener {
+    /// The listener is a `MuxerConnection`, identified by `key`, and interested in the events
+    /// in `evset`.
+    Connection { key: ConnMapKey, evset: EventSet },
+    /// A listener interested in new host-initiated connections, which are forwarded to
+    /// `peer_port`.
+    HostSock { sock: TcpListener, peer_port: u32 },
+    /// A listener interested in the outcome of a guest-initiated connection attempt, which
+    /// will be identified by `key` once established.
+    PendingConnect {
+        stream: TcpStream,
+        key: ConnMapKey,
+        peer_buf_alloc: u32,
+    },
+}
+
+/// The TCP vsock connection multiplexer.
+pub struct VsockTcpMuxer {
+    /// Guest CID.
+    cid: u64,
+    /// The port map this muxer was created with.
+    config: VsockTcpConfig,
+    /// The host addresses guest-initiated connections are forwarded to, keyed by guest port.
+    connect_map: HashMap<u32, SocketAddr>,
+    /// A hash map used to store the active connections.
+    conn_map: HashMap<ConnMapKey, MuxerConnection>,
+    /// A hash map used to store epoll event listeners / handlers.
+    listener_map: HashMap<RawFd, EpollListener>,
+    /// The RX queue. Items in this queue are consumed by `VsockTcpMuxer::recv_pkt()`.
+    rxq: MuxerRxQ,
+    /// A queue used for terminating connections that are taking too long to shut down.
+    killq: MuxerKillQ,
+    /// The nested epoll event set, used to register epoll listeners.
+    epoll: Epoll,
+    /// A hash set used to keep track of used host-side (local) ports, in order to assign local
+    /// ports to host-initiated connections.
+    local_port_set: HashSet<u32>,
+    /// The last used host-side port.
+    local_port_last: u32,
+    /// Connection limits enforced by this muxer.
+    conn_limits: VsockConnLimits,
+}
+
+impl VsockChannel for VsockTcpMuxer {
+    /// Deliver a vsock packet to the guest vsock driver.
+    ///
+    /// Retuns:
+    /// - `Ok(())`: `pkt` has been successfully filled in; or
+    /// - `Err(VsockError::NoData)`: there was no available data with which to fill in the
+    ///   packet.
+    fn recv_pkt(&mut self, pkt: &mut VsockPacket, mem: &GuestMemoryMmap) -> VsockResult<()> {
+        if self.rxq.is_empty() && !self.rxq.is_synced() {
+            self.rxq = MuxerRxQ::from_conn_map(&self.conn_map);
+        }
+
+        while let Some(rx) = self.rxq.peek() {
+            let res = match rx {
+                MuxerRx::RstPkt {
+                    local_port,
+                    peer_port,
+                    type_,
+                } => {
+                    pkt.set_op(uapi::VSOCK_OP_RST)
+                        .set_src_cid(uapi::VSOCK_HOST_CID)
+                        .set_dst_cid(self.cid)
+                        .set_src_port(local_port)
+                        .set_dst_port(peer_port)
+                        .set_len(0)
+                        .set_type(type_)
+                        .set_flags(0)
+                        .set_buf_alloc(0)
+                        .set_fwd_cnt(0);
+                    self.rxq.pop().unwrap();
+                    return Ok(());
+                }
+
+                MuxerRx::ConnRx(key) => {
+                    let mut conn_res = Err(VsockError::NoData);
+                    let mut do_pop = true;
+                    self.apply_conn_mutation(key, |conn| {
+                        conn_res = conn.recv_pkt(pkt, mem);
+                        do_pop = !conn.has_pending_rx();
+                    });
+                    if do_pop {
+                        self.rxq.pop().unwrap();
+                    }
+                    conn_res
+                }
+            };
+
+            if res.is_ok() {
+                // An RST packet means the connection has to be removed from the active
+                // connection pool.
+                if pkt.op() == uapi::VSOCK_OP_RST {
+                    self.remove_connection(ConnMapKey {
+                        local_port: pkt.src_port(),
+                        peer_port: pkt.dst_port(),
+                    });
+                }
+
+                debug!("vsock: tcp muxer: RX pkt: {:?}", pkt.hdr());
+                return Ok(());
+            }
+        }
+
+        Err(VsockError::NoData)
+    }
+
+    /// Deliver a guest-generated packet to its destination in the vsock backend.
+    ///
+    /// Returns:
+    /// always `Ok(())` - the packet has been consumed, and its virtio TX buffers can be
+    /// returned to the guest vsock driver.
+    fn send_pkt(&mut self, pkt: &VsockPacket, mem: &GuestMemoryMmap) -> VsockResult<()> {
+        let conn_key = ConnMapKey {
+            local_port: pkt.dst_port(),
+            peer_port: pkt.src_port(),
+        };
+
+        debug!(
+            "vsock: tcp muxer.send[rxq.len={}]: {:?}",
+            self.rxq.len(),
+            pkt.hdr()
+        );
+
+        // Only stream connections can be forwarded to TCP endpoints.
+        if pkt.type_() != uapi::VSOCK_TYPE_STREAM {
+            self.enq_rst(pkt.dst_port(), pkt.src_port(), pkt.type_());
+            return Ok(());
+        }
+
+        if pkt.dst_cid() != uapi::VSOCK_HOST_CID {
+            info!(
+                "vsock: dropping guest packet for unknown CID: {:?}",
+                pkt.hdr()
+            );
+            return Ok(());
+        }
+
+        if !self.conn_map.contains_key(&conn_key) {
+            if pkt.op() == uapi::VSOCK_OP_REQUEST {
+                self.handle_peer_request_pkt(pkt);
+            } else {
+                self.enq_rst(pkt.dst_port(), pkt.src_port(), pkt.type_());
+            }
+            return Ok(());
+        }
+
+        if pkt.op() == uapi::VSOCK_OP_RST {
+            self.remove_connection(conn_key);
+            return Ok(());
+        }
+
+        let mut res: VsockResult<()> = Ok(());
+        self.apply_conn_mutation(conn_key, |conn| {
+            res = conn.send_pkt(pkt, mem);
+        });
+
+        res
+    }
+
+    /// Check if the muxer has any pending RX data, with which to fill a guest-provided RX
+    /// buffer.
+    fn has_pending_rx(&self) -> bool {
+        !self.rxq.is_empty() || !self.rxq.is_synced()
+    }
+}
+
+impl AsRawFd for VsockTcpMuxer {
+    /// Get the FD to be registered for polling upstream. This will be the muxer's nested epoll
+    /// FD.
+    fn as_raw_fd(&self) -> RawFd {
+        self.epoll.as_raw_fd()
+    }
+}
+
+impl VsockEpollListener for VsockTcpMuxer {
+    /// Get the epoll events to be polled upstream.
+    fn get_polled_evset(&self) -> EventSet {
+        EventSet::IN
+    }
+
+    /// Notify the muxer about a pending event having occured under its nested epoll FD.
+    fn notify(&mut self, _: EventSet) {
+        debug!("vsock: tcp muxer received kick");
+
+        let mut epoll_events = vec![EpollEvent::new(EventSet::empty(), 0); 32];
+        match self.epoll.wait(0, epoll_events.as_mut_slice()) {
+            Ok(ev_cnt) => {
+                for ev in &epoll_events[0..ev_cnt] {
+                    self.handle_event(
+                        ev.fd(),
+                        // It's ok to unwrap here, since the `epoll_events[i].events` is filled
+                        // in by `epoll::wait()`, and therefore contains only valid epoll
+                        // flags.
+                        EventSet::from_bits(ev.events).unwrap(),
+                    );
+                }
+            }
+            Err(e) => {
+                warn!("vsock: failed to consume tcp muxer epoll event: {}", e);
+                METRICS.vsock.muxer_event_fails.inc();
+            }
+        }
+    }
+}
+
+impl VsockBackend for VsockTcpMuxer {}
+
+impl VsockTcpMuxer {
+    /// Muxer constructor.
+    pub fn new(cid: u64, config: VsockTcpConfig, conn_limits: VsockConnLimits) -> Result<Self> {
+        let mut connect_map = HashMap::with_capacity(config.connect.len());
+        for forward in config.connect.iter() {
+            if connect_map
+                .insert(forward.guest_port, forward.host_addr)
+                .is_some()
+            {
+                return Err(Error::DuplicateGuestPort(forward.guest_port));
+            }
+        }
+
+        // Bind on the host addresses, so we can accept host-initiated connections.
+        let host_socks = config
+            .listen
+            .iter()
+            .map(|forward| {
+                TcpListener::bind(forward.host_addr)
+                    .and_then(|sock| sock.set_nonblocking(true).map(|_| sock))
+                    .map(|sock| (sock, forward.guest_port))
+                    .map_err(Error::TcpBind)
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        let mut muxer = Self {
+            cid,
+            config,
+            connect_map,
+            epoll: Epoll::new().map_err(Error::EpollFdCreate)?,
+            rxq: MuxerRxQ::new(),
+            conn_map: HashMap::with_capacity(conn_limits.max_connections),
+            listener_map: HashMap::with_capacity(conn_limits.max_connections + host_socks.len()),
+            killq: MuxerKillQ::new(),
+            local_port_last: (1u32 << 30) - 1,
+            local_port_set: HashSet::with_capacity(conn_limits.max_connections),
+            conn_limits,
+        };
+
+        // Listen on the host sockets, for incoming connections.
+        for (sock, peer_port) in host_socks {
+            muxer.add_listener(
+                sock.as_raw_fd(),
+                EpollListener::HostSock { sock, peer_port },
+            )?;
+        }
+        Ok(muxer)
+    }
+
+    /// Returns the port map this muxer was created with.
+    pub fn config(&self) -> &VsockTcpConfig {
+        &self.config
+    }
+
+    /// Returns the connection limits enforced by this muxer.
+    pub fn conn_limits(&self) -> &VsockConnLimits {
+        &self.conn_limits
+    }
+
+    /// Handle/dispatch an epoll event to its listener.
+    fn handle_event(&mut self, fd: RawFd, event_set: EventSet) {
+        debug!(
+            "vsock: tcp muxer processing event: fd={}, evset={:?}",
+            fd, event_set
+        );
+
+        match self.listener_map.get_mut(&fd) {
+            Some(EpollListener::Connection { key, evset: _ }) => {
+                let key_copy = *key;
+                self.apply_conn_mutation(key_copy, |conn| {
+                    conn.notify(event_set);
+                });
+            }
+
+            // A new host-initiated connection is ready to be accepted.
+            Some(EpollListener::HostSock { sock, peer_port }) => {
+                let peer_port = *peer_port;
+                let accept_res = sock.accept();
+                if self.conn_map.len() >= self.conn_limits.max_connections {
+                    // If we're already maxed-out on connections, the freshly accepted stream
+                    // is dropped (and thus closed) right away.
+                    warn!("vsock: connection limit reached; refusing new host connection");
+                    METRICS.vsock.conns_rejected.inc();
+                    return;
+                }
+                accept_res
+                    .and_then(|(stream, _)| stream.set_nonblocking(true).map(|_| stream))
+                    .map_err(Error::TcpAccept)
+                    .and_then(|stream| self.add_local_connection(stream, peer_port))
+                    .unwrap_or_else(|err| {
+                        warn!("vsock: unable to accept local tcp connection: {:?}", err);
+                    });
+            }
+
+            // A guest-initiated connection attempt has completed, one way or the other.
+            Some(EpollListener::PendingConnect { .. }) => {
+                if let Some(EpollListener::PendingConnect {
+                    stream,
+                    key,
+                    peer_buf_alloc,
+                }) = self.remove_listener(fd)
+                {
+                    self.complete_peer_connect(stream, key, peer_buf_alloc)
+                }
+            }
+
+            _ => {
+                info!(
+                    "vsock: unexpected event: fd={:?}, evset={:?}",
+                    fd, event_set
+                );
+                METRICS.vsock.muxer_event_fails.inc();
+            }
+        }
+    }
+
+    /// Add a host-initiated connection, forwarded to `peer_port`, to the connection pool.
+    fn add_local_connection(&mut self, stream: TcpStream, peer_port: u32) -> Result<()> {
+        let local_port = self.allocate_local_port();
+        let res = self.add_connection(
+            ConnMapKey {
+                local_port,
+                peer_port,
+            },
+            MuxerConnection::new_local_init(
+                stream,
+                uapi::VSOCK_HOST_CID,
+                self.cid,
+                local_port,
+                peer_port,
+            ),
+        );
+        if res.is_err() {
+            self.free_local_port(local_port);
+        }
+        res
+    }
+
+    /// Add a new connection to the active connection pool.
+    fn add_connection(&mut self, key: ConnMapKey, conn: MuxerConnection) -> Result<()> {
+        // We might need to make room for this new connection, so let's sweep the kill queue
+        // first.
+        self.sweep_killq();
+
+        if self.conn_map.len() >= self.conn_limits.max_connections {
+            info!(
+                "vsock: tcp muxer connection limit reached ({})",
+                self.conn_limits.max_connections
+            );
+            METRICS.vsock.conns_rejected.inc();
+            return Err(Error::TooManyConnections);
+        }
+
+        self.add_listener(
+            conn.as_raw_fd(),
+            EpollListener::Connection {
+                key,
+                evset: conn.get_polled_evset(),
+            },
+        )
+        .map(|_| {
+            if conn.has_pending_rx() {
+                // Worst case scenario, the RX queue will get desynchronized, but we'll handle
+                // that the next time we need to yield an RX packet.
+                self.rxq.push(MuxerRx::ConnRx(key));
+            }
+            self.conn_map.insert(key, conn);
+            METRICS.vsock.conns_added.inc();
+        })
+    }
+
+    /// Remove a connection from the active connection poll.
+    fn remove_connection(&mut self, key: ConnMapKey) {
+        if let Some(conn) = self.conn_map.remove(&key) {
+            self.remove_listener(conn.as_raw_fd());
+            METRICS.vsock.conns_removed.inc();
+        }
+        self.free_local_port(key.local_port);
+    }
+
+    /// Schedule a connection for immediate termination.
+    fn kill_connection(&mut self, key: ConnMapKey) {
+        let mut had_rx = false;
+        METRICS.vsock.conns_killed.inc();
+
+        self.conn_map.entry(key).and_modify(|conn| {
+            had_rx = conn.has_pending_rx();
+            conn.kill();
+        });
+        if !had_rx {
+            self.rxq.push(MuxerRx::ConnRx(key));
+        }
+    }
+
+    /// Register a new epoll listener under the muxer's nested epoll FD.
+    fn add_listener(&mut self, fd: RawFd, listener: EpollListener) -> Result<()> {
+        let evset = match listener {
+            EpollListener::Connection { evset, .. } => evset,
+            EpollListener::HostSock { .. } => EventSet::IN,
+            EpollListener::PendingConnect { .. } => EventSet::OUT,
+        };
+
+        self.epoll
+            .ctl(ControlOperation::Add, fd, EpollEvent::new(evset, fd as u64))
+            .map(|_| {
+                self.listener_map.insert(fd, listener);
+            })
+            .map_err(Error::EpollAdd)?;
+
+        Ok(())
+    }
+
+    /// Remove (and return) a previously registered epoll listener.
+    fn remove_listener(&mut self, fd: RawFd) -> Option<EpollListener> {
+        let maybe_listener = self.listener_map.remove(&fd);
+
+        if maybe_listener.is_some() {
+            self.epoll
+                .ctl(ControlOperation::Delete, fd, EpollEvent::default())
+                .unwrap_or_else(|err| {
+                    warn!(
+                        "vsock: tcp muxer: error removing epoll listener for fd {:?}: {:?}",
+                        fd, err
+                    );
+                });
+        }
+
+        maybe_listener
+    }
+
+    /// Allocate a host-side port to be assigned to a new host-initiated connection.
+    fn allocate_local_port(&mut self) -> u32 {
+        loop {
+            self.local_port_last = (self.local_port_last + 1) & !(1 << 31) | (1 << 30);
+            if self.local_port_set.insert(self.local_port_last) {
+                break;
+            }
+        }
+        self.local_port_last
+    }
+
+    /// Mark a previously used host-side port as free.
+    fn free_local_port(&mut self, port: u32) {
+        self.local_port_set.remove(&port);
+    }
+
+    /// Handle a new connection request comming from our peer (the guest vsock driver).
+    ///
+    /// This will start a non-blocking connection attempt to the host address mapped to the
+    /// destination port, and wait for it to complete. If the destination port is not mapped,
+    /// or the attempt can't be started, a new RST packet will be scheduled for delivery to the
+    /// guest. Requests for ports missing from the allow-list are refused right away.
+    fn handle_peer_request_pkt(&mut self, pkt: &VsockPacket) {
+        if !self.conn_limits.allows_port(pkt.dst_port()) {
+            info!(
+                "vsock: refusing guest connection to port {}: not allowed",
+                pkt.dst_port()
+            );
+            METRICS.vsock.conns_rejected.inc();
+            self.enq_rst(pkt.dst_port(), pkt.src_port(), pkt.type_());
+            return;
+        }
+
+        let host_addr = match self.connect_map.get(&pkt.dst_port()) {
+            Some(host_addr) => *host_addr,
+            None => {
+                self.enq_rst(pkt.dst_port(), pkt.src_port(), pkt.type_());
+                return;
+            }
+        };
+
+        connect(&host_addr)
+            .map_err(Error::TcpConnect)
+            .and_then(|stream| {
+                self.add_listener(
+                    stream.as_raw_fd(),
+                    EpollListener::PendingConnect {
+                        stream,
+                        key: ConnMapKey {
+                            local_port: pkt.dst_port(),
+                            peer_port: pkt.src_port(),
+                        },
+                        peer_buf_alloc: pkt.buf_alloc(),
+                    },
+                )
+            })
+            .unwrap_or_else(|err| {
+                info!("vsock: unable to connect to {}: {:?}", host_addr, err);
+                self.enq_rst(pkt.dst_port(), pkt.src_port(), pkt.type_())
+            });
+    }
+
+    /// Handle the outcome of a guest-initiated connection attempt.
+    ///
+    /// If the attempt succeeded, a new connection object will be created and added to the
+    /// connection pool, which will send a response to the guest. Otherwise, a new RST packet
+    /// will be scheduled for delivery to the guest.
+    fn complete_peer_connect(&mut self, stream: TcpStream, key: ConnMapKey, peer_buf_alloc: u32) {
+        match stream.take_error() {
+            Ok(None) => (),
+            Ok(Some(err)) | Err(err) => {
+                info!(
+                    "vsock: connection to {} failed: {:?}",
+                    self.connect_map[&key.local_port], err
+                );
+                self.enq_rst(key.local_port, key.peer_port, uapi::VSOCK_TYPE_STREAM);
+                return;
+            }
+        }
+
+        self.add_connection(
+            key,
+            MuxerConnection::new_peer_init(
+                stream,
+                uapi::VSOCK_HOST_CID,
+                self.cid,
+                key.local_port,
+                key.peer_port,
+                peer_buf_alloc,
+            ),
+        )
+        .unwrap_or_else(|_| self.enq_rst(key.local_port, key.peer_port, uapi::VSOCK_TYPE_STREAM));
+    }
+
+    /// Perform an action that might mutate a connection's state.
+    ///
+    /// This is used as shorthand for repetitive tasks that need to be performed after a
+    /// connection object mutates. E.g.
+    /// - update the connection's epoll listener;
+    /// - schedule the connection to be queried for RX data;
+    /// - kill the connection if an unrecoverable error occurs.
+    fn apply_conn_mutation<F>(&mut self, key: ConnMapKey, mut_fn: F)
+    where
+        F: FnOnce(&mut MuxerConnection),
+    {
+        if let Some(conn) = self.conn_map.get_mut(&key) {
+            let had_rx = conn.has_pending_rx();
+            let was_expiring = conn.will_expire();
+
+            mut_fn(conn);
+
+            if !had_rx && conn.has_pending_rx() {
+                self.rxq.push(MuxerRx::ConnRx(key));
+            }
+
+            if !was_expiring && conn.will_expire() {
+                // It's safe to unwrap here, since `conn.will_expire()` already guaranteed that
+                // an `conn.expiry` is available.
+                self.killq.push(key, conn.expiry().unwrap());
+            }
+
+            let fd = conn.as_raw_fd();
+            let new_evset = conn.get_polled_evset();
+            if new_evset.is_empty() {
+                self.remove_listener(fd);
+                return;
+            }
+            if let Some(EpollListener::Connection { evset, .. }) = self.listener_map.get_mut(&fd) {
+                if *evset != new_evset {
+                    debug!(
+                        "vsock: updating listener for (lp={}, pp={}): old={:?}, new={:?}",
+                        key.local_port, key.peer_port, *evset, new_evset
+                    );
+
+                    *evset = new_evset;
+                    self.epoll
+                        .ctl(
+                            ControlOperation::Modify,
+                            fd,
+                            EpollEvent::new(new_evset, fd as u64),
+                        )
+                        .unwrap_or_else(|err| {
+                            self.kill_connection(key);
+                            error!(
+                                "vsock: error updating epoll listener for (lp={}, pp={}): {:?}",
+                                key.local_port, key.peer_port, err
+                            );
+                            METRICS.vsock.muxer_event_fails.inc();
+                        });
+                }
+            } else {
+                self.add_listener(
+                    fd,
+                    EpollListener::Connection {
+                        key,
+                        evset: new_evset,
+                    },
+                )
+                .unwrap_or_else(|err| {
+                    self.kill_connection(key);
+                    error!(
+                        "vsock: error updating epoll listener for (lp={}, pp={}): {:?}",
+                        key.local_port, key.peer_port, err
+                    );
+                    METRICS.vsock.muxer_event_fails.inc();
+                });
+            }
+        }
+    }
+
+    /// Check if any connections have timed out, and if so, schedule them for immediate
+    /// termination.
+    fn sweep_killq(&mut self) {
+        while let Some(key) = self.killq.pop() {
+            let mut kill = false;
+            self.conn_map
+                .entry(key)
+                .and_modify(|conn| kill = conn.has_expired());
+            if kill {
+                self.kill_connection(key);
+            }
+        }
+
+        if self.killq.is_empty() && !self.killq.is_synced() {
+            self.killq = MuxerKillQ::from_conn_map(&self.conn_map);
+            METRICS.vsock.killq_resync.inc();
+            self.sweep_killq();
+        }
+    }
+
+    /// Enqueue an RST packet into `self.rxq`.
+    fn enq_rst(&mut self, local_port: u32, peer_port: u32, type_: u16) {
+        let pushed = self.rxq.push(MuxerRx::RstPkt {
+            local_port,
+            peer_port,
+            type_,
+        });
+        if !pushed {
+            warn!(
+                "vsock: tcp muxer.rxq full; dropping RST packet for lp={}, pp={}",
+                local_port, peer_port
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Read, Write};
+
+    use super::super::VsockTcpForward;
+    use super::*;
+    use crate::virtio::vsock::device::RXQ_INDEX;
+    use crate::virtio::vsock::test_utils::TestContext as VsockTestContext;
+
+    const PEER_CID: u64 = 3;
+    const PEER_BUF_ALLOC: u32 = 64 * 1024;
+
+    struct MuxerTestContext {
+        _vsock_test_ctx: VsockTestContext,
+        pkt: VsockPacket,
+        muxer: VsockTcpMuxer,
+    }
+
+    impl MuxerTestContext {
+        fn new(config: VsockTcpConfig) -> Self {
+            Self::new_with_limits(config, VsockConnLimits::default())
+        }
+
+        fn new_with_limits(config: VsockTcpConfig, conn_limits: VsockConnLimits) -> Self {
+            let vsock_test_ctx = VsockTestContext::new();
+            let mut handler_ctx = vsock_test_ctx.create_event_handler_context();
+            let pkt = VsockPacket::from_rx_virtq_head(
+                &handler_ctx.device.queues[RXQ_INDEX]
+                    .pop(&vsock_test_ctx.mem)
+                    .unwrap(),
+            )
+            .unwrap();
+
+            let muxer = VsockTcpMuxer::new(PEER_CID, config, conn_limits).unwrap();
+            Self {
+                _vsock_test_ctx: vsock_test_ctx,
+                pkt,
+                muxer,
+            }
+        }
+
+        fn init_pkt(&mut self, local_port: u32, peer_port: u32, op: u16) -> &mut VsockPacket {
+            self.pkt
+                .set_type(uapi::VSOCK_TYPE_STREAM)
+                .set_src_cid(PEER_CID)
+                .set_dst_cid(uapi::VSOCK_HOST_CID)
+                .set_src_port(peer_port)
+                .set_dst_port(local_port)
+                .set_op(op)
+                .set_buf_alloc(PEER_BUF_ALLOC)
+        }
+
+        fn init_data_pkt(
+            &mut self,
+            local_port: u32,
+            peer_port: u32,
+            data: &[u8],
+        ) -> &mut VsockPacket {
+            assert!(data.len() <= self.pkt.buf_size());
+            self.init_pkt(local_port, peer_port, uapi::VSOCK_OP_RW)
+                .set_len(data.len() as u32);
+            self.pkt
+                .read_at_offset_from(
+                    &self._vsock_test_ctx.mem,
+                    0,
+                    &mut std::io::Cursor::new(data.to_vec()),
+                    data.len(),
+                )
+                .unwrap();
+            &mut self.pkt
+        }
+
+        fn pkt_data(&self) -> Vec<u8> {
+            let mut buf = vec![];
+            self.pkt
+                .write_from_offset_to(
+                    &self._vsock_test_ctx.mem,
+                    0,
+                    &mut buf,
+                    self.pkt.len() as usize,
+                )
+                .unwrap();
+            buf
+        }
+
+        fn send(&mut self) {
+            self.muxer
+                .send_pkt(&self.pkt, &self._vsock_test_ctx.mem)
+                .unwrap();
+        }
+
+        fn recv(&mut self) {
+            self.muxer
+                .recv_pkt(&mut self.pkt, &self._vsock_test_ctx.mem)
+                .unwrap();
+        }
+
+        fn notify_muxer(&mut self) {
+            self.muxer.notify(EventSet::IN);
+        }
+
+        // Notifies the muxer until it has an RX packet to yield, since connection attempts
+        // complete asynchronously.
+        fn wait_for_rx(&mut self) {
+            for _ in 0..100 {
+                self.notify_muxer();
+                if self.muxer.has_pending_rx() {
+                    return;
+                }
+                std::thread::sleep(std::time::Duration::from_millis(10));
+            }
+            panic!("The muxer has no pending RX.");
+        }
+
+        fn listen_addr(&self, guest_port: u32) -> SocketAddr {
+            self.muxer
+                .listener_map
+                .values()
+                .find_map(|listener| match listener {
+                    EpollListener::HostSock { sock, peer_port } if *peer_port == guest_port => {
+                        Some(sock.local_addr().unwrap())
+                    }
+                    _ => None,
+                })
+                .unwrap()
+        }
+    }
+
+    fn forward(guest_port: u32, host_addr: SocketAddr) -> VsockTcpForward {
+        VsockTcpForward {
+            guest_port,
+            host_addr,
+        }
+    }
+
+    fn any_local_addr() -> SocketAddr {
+        "127.0.0.1:0".parse().unwrap()
+    }
+
+    #[test]
+    fn test_muxer_epoll_listener() {
+        let ctx = MuxerTestContext::new(VsockTcpConfig::default());
+        assert_eq!(ctx.muxer.as_raw_fd(), ctx.muxer.epoll.as_raw_fd());
+        assert_eq!(ctx.muxer.get_polled_evset(), EventSet::IN);
+    }
+
+    #[test]
+    fn test_muxer_config() {
+        let config = VsockTcpConfig {
+            connect: vec![forward(1026, "127.0.0.1:8080".parse().unwrap())],
+            listen: vec![forward(1025, any_local_addr())],
+        };
+        let ctx = MuxerTestContext::new(config.clone());
+        assert_eq!(ctx.muxer.config(), &config);
+        assert_eq!(ctx.muxer.listener_map.len(), 1);
+
+        let config = VsockTcpConfig {
+            connect: vec![
+                forward(1026, "127.0.0.1:8080".parse().unwrap()),
+                forward(1026, "127.0.0.1:8081".parse().unwrap()),
+            ],
+            listen: vec![],
+        };
+        match VsockTcpMuxer::new(PEER_CID, config, VsockConnLimits::default()) {
+            Err(Error::DuplicateGuestPort(1026)) => (),
+            _ => panic!("Unexpected result."),
+        }
+    }
+
+    #[test]
+    fn test_peer_connection() {
+        const LOCAL_PORT: u32 = 1026;
+        const UNMAPPED_PORT: u32 = 1027;
+        const REFUSED_PORT: u32 = 1028;
+        const PEER_PORT: u32 = 1025;
+
+        let listener = TcpListener::bind(any_local_addr()).unwrap();
+        // Grab an address nobody listens on.
+        let refused_addr = TcpListener::bind(any_local_addr())
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let mut ctx = MuxerTestContext::new(VsockTcpConfig {
+            connect: vec![
+                forward(LOCAL_PORT, listener.local_addr().unwrap()),
+                forward(REFUSED_PORT, refused_addr),
+            ],
+            listen: vec![],
+        });
+
+        // Test peer connection to an unmapped port.
+        ctx.init_pkt(UNMAPPED_PORT, PEER_PORT, uapi::VSOCK_OP_REQUEST);
+        ctx.send();
+        ctx.recv();
+        assert_eq!(ctx.pkt.op(), uapi::VSOCK_OP_RST);
+        assert_eq!(ctx.pkt.src_port(), UNMAPPED_PORT);
+        assert_eq!(ctx.pkt.dst_port(), PEER_PORT);
+        assert!(ctx.muxer.conn_map.is_empty());
+
+        // Test peer connection refused by the host endpoint.
+        ctx.init_pkt(REFUSED_PORT, PEER_PORT, uapi::VSOCK_OP_REQUEST);
+        ctx.send();
+        ctx.wait_for_rx();
+        ctx.recv();
+        assert_eq!(ctx.pkt.op(), uapi::VSOCK_OP_RST);
+        assert_eq!(ctx.pkt.src_port(), REFUSED_PORT);
+        assert_eq!(ctx.pkt.dst_port(), PEER_PORT);
+        assert!(ctx.muxer.conn_map.is_empty());
+
+        // Test peer connection accepted. The guest only gets a response once the host
+        // connection is established.
+        ctx.init_pkt(LOCAL_PORT, PEER_PORT, uapi::VSOCK_OP_REQUEST);
+        ctx.send();
+        assert!(ctx.muxer.conn_map.is_empty());
+        ctx.wait_for_rx();
+        assert_eq!(ctx.muxer.conn_map.len(), 1);
+        let (mut stream, _) = listener.accept().unwrap();
+        ctx.recv();
+        assert_eq!(ctx.pkt.op(), uapi::VSOCK_OP_RESPONSE);
+        assert_eq!(ctx.pkt.src_cid(), uapi::VSOCK_HOST_CID);
+        assert_eq!(ctx.pkt.dst_cid(), PEER_CID);
+        assert_eq!(ctx.pkt.src_port(), LOCAL_PORT);
+        assert_eq!(ctx.pkt.dst_port(), PEER_PORT);
+
+        // Test guest -> host data flow.
+        let data = [1, 2, 3, 4];
+        ctx.init_data_pkt(LOCAL_PORT, PEER_PORT, &data);
+        ctx.send();
+        let mut buf = vec![0; data.len()];
+        stream.read_exact(buf.as_mut_slice()).unwrap();
+        assert_eq!(buf.as_slice(), data);
+
+        // Test host -> guest data flow.
+        let data = [5u8, 6, 7, 8];
+        stream.write_all(&data).unwrap();
+        ctx.notify_muxer();
+        assert!(ctx.muxer.has_pending_rx());
+        ctx.recv();
+        assert_eq!(ctx.pkt.op(), uapi::VSOCK_OP_RW);
+        assert_eq!(ctx.pkt.src_port(), LOCAL_PORT);
+        assert_eq!(ctx.pkt.dst_port(), PEER_PORT);
+        assert_eq!(ctx.pkt_data(), data);
+        assert!(!ctx.muxer.has_pending_rx());
+    }
+
+    #[test]
+    fn test_conn_limits() {
+        const LOCAL_PORT: u32 = 1026;
+        const DENIED_PORT: u32 = 1027;
+        const PEER_PORT: u32 = 1025;
+
+        let listener = TcpListener::bind(any_local_addr()).unwrap();
+        let mut ctx = MuxerTestContext::new_with_limits(
+            VsockTcpConfig {
+                connect: vec![
+                    forward(LOCAL_PORT, listener.local_addr().unwrap()),
+                    forward(DENIED_PORT, listener.local_addr().unwrap()),
+                ],
+                listen: vec![],
+            },
+            VsockConnLimits {
+                max_connections: 1,
+                allowed_ports: Some(vec![LOCAL_PORT]),
+            },
+        );
+
+        // Mapped ports missing from the allow-list are refused without connecting.
+        ctx.init_pkt(DENIED_PORT, PEER_PORT, uapi::VSOCK_OP_REQUEST);
+        ctx.send();
+        assert!(ctx.muxer.has_pending_rx());
+        ctx.recv();
+        assert_eq!(ctx.pkt.op(), uapi::VSOCK_OP_RST);
+        assert_eq!(ctx.pkt.src_port(), DENIED_PORT);
+
+        ctx.init_pkt(LOCAL_PORT, PEER_PORT, uapi::VSOCK_OP_REQUEST);
+        ctx.send();
+        ctx.wait_for_rx();
+        ctx.recv();
+        assert_eq!(ctx.pkt.op(), uapi::VSOCK_OP_RESPONSE);
+        assert_eq!(ctx.muxer.conn_map.len(), 1);
+
+        // The connection limit has been reached, so a second guest connection is refused.
+        ctx.init_pkt(LOCAL_PORT, PEER_PORT + 1, uapi::VSOCK_OP_REQUEST);
+        ctx.send();
+        ctx.wait_for_rx();
+        ctx.recv();
+        assert_eq!(ctx.pkt.op(), uapi::VSOCK_OP_RST);
+        assert_eq!(ctx.pkt.dst_port(), PEER_PORT + 1);
+        assert_eq!(ctx.muxer.conn_map.len(), 1);
+    }
+
+    #[test]
+    fn test_local_connection() {
+        const PEER_PORT: u32 = 1025;
+
+        let mut ctx = MuxerTestContext::new(VsockTcpConfig {
+            connect: vec![],
+            listen: vec![forward(PEER_PORT, any_local_addr())],
+        });
+        let mut stream = TcpStream::connect(ctx.listen_addr(PEER_PORT)).unwrap();
+        ctx.notify_muxer();
+
+        // The accepted connection should be forwarded to the mapped guest port right away.
+        let local_port = ctx.muxer.local_port_last;
+        let key = ConnMapKey {
+            local_port,
+            peer_port: PEER_PORT,
+        };
+        assert!(ctx.muxer.conn_map.contains_key(&key));
+        assert!(ctx.muxer.has_pending_rx());
+        ctx.recv();
+        assert_eq!(ctx.pkt.op(), uapi::VSOCK_OP_REQUEST);
+        assert_eq!(ctx.pkt.src_port(), local_port);
+        assert_eq!(ctx.pkt.dst_port(), PEER_PORT);
+
+        ctx.init_pkt(local_port, PEER_PORT, uapi::VSOCK_OP_RESPONSE);
+        ctx.send();
+
+        // Test guest -> host data flow.
+        let data = [1, 2, 3, 4];
+        ctx.init_data_pkt(local_port, PEER_PORT, &data);
+        ctx.send();
+        let mut buf = vec![0u8; data.len()];
+        stream.read_exact(buf.as_mut_slice()).unwrap();
+        assert_eq!(buf.as_slice(), &data);
+
+        // Test host -> guest data flow.
+        let data = [5, 6, 7, 8];
+        stream.write_all(&data).unwrap();
+        ctx.notify_muxer();
+        assert!(ctx.muxer.has_pending_rx());
+        ctx.recv();
+        assert_eq!(ctx.pkt.op(), uapi::VSOCK_OP_RW);
+        assert_eq!(ctx.pkt_data(), data);
+
+        // Closing the host stream should lead to a graceful shutdown, and the connection
+        // should get removed after the peer replies with an RST.
+        drop(stream);
+        ctx.notify_muxer();
+        ctx.recv();
+        assert_eq!(ctx.pkt.op(), uapi::VSOCK_OP_SHUTDOWN);
+        ctx.init_pkt(local_port, PEER_PORT, uapi::VSOCK_OP_RST);
+        ctx.send();
+        assert!(!ctx.muxer.conn_map.contains_key(&key));
+        assert!(!ctx.muxer.local_port_set.contains(&local_port));
+    }
+}
@@ -12,6 +12,7 @@ use crate::virtio::{
     VirtioDevice, Vsock, VsockBackend, VsockChannel, VsockEpollListener, VsockError,
     VIRTQ_DESC_F_NEXT, VIRTQ_DESC_F_WRITE,
 };
+use rate_limiter::RateLimiter;
 use utils::epoll::EventSet;
 use utils::eventfd::EventFd;
 use vm_memory::{GuestAddress, GuestMemoryMmap};
@@ -127,7 +128,13 @@ impl TestContext {
             cid: CID,
             mem,
             mem_size: MEM_SIZE,
-            device: Vsock::new(CID, TestBackend::new()).unwrap(),
+            device: Vsock::new(
+                CID,
+                TestBackend::new(),
+                RateLimiter::default(),
+                RateLimiter::default(),
+            )
+            .unwrap(),
         }
     }
 
@@ -164,7 +171,14 @@ impl TestContext {
             guest_rxvq,
             guest_txvq,
             guest_evvq,
-            device: Vsock::with_queues(self.cid, TestBackend::new(), queues).unwrap(),
+            device: Vsock::with_queues(
+                self.cid,
+                TestBackend::new(),
+                queues,
+                RateLimiter::default(),
+                RateLimiter::default(),
+            )
+            .unwrap(),
         }
     }
 }
@@ -7,16 +7,25 @@
 /// `muxer::VsockMuxer`, a connection multiplexer that uses `super::csm::VsockConnection` for
 /// handling vsock connection states.
 /// Check out `muxer.rs` for a more detailed explanation of the inner workings of this backend.
+/// Guest SOCK_SEQPACKET connections are forwarded to host-side SOCK_SEQPACKET AF_UNIX sockets.
 mod muxer;
 mod muxer_killq;
 mod muxer_rxq;
 
+use std::io;
+use std::mem;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::io::FromRawFd;
+use std::os::unix::net::UnixStream;
+use std::path::Path;
+
 pub use muxer::VsockMuxer as VsockUnixBackend;
+// The connection bookkeeping helpers are shared with the TCP backend.
+pub(super) use muxer::{ConnMapKey, MuxerRx};
+pub(super) use muxer_killq::MuxerKillQ;
+pub(super) use muxer_rxq::MuxerRxQ;
 
 mod defs {
-    /// Maximum number of established connections that we can handle.
-    pub const MAX_CONNECTIONS: usize = 1023;
-
     /// Size of the muxer RX packet queue.
     pub const MUXER_RXQ_SIZE: usize = 256;
 
@@ -45,4 +54,50 @@ pub enum Error {
 }
 
 type Result<T> = std::result::Result<T, Error>;
-type MuxerConnection = super::csm::VsockConnection<std::os::unix::net::UnixStream>;
+type MuxerConnection = super::csm::VsockConnection<UnixStream>;
+
+/// Builds the Unix socket address of `path`.
+fn sockaddr_un<P: AsRef<Path>>(path: P) -> io::Result<libc::sockaddr_un> {
+    let path = path.as_ref().as_os_str().as_bytes();
+    // This is safe because all-zeroes is a valid `sockaddr_un`.
+    let mut addr: libc::sockaddr_un = unsafe { mem::zeroed() };
+    // The path needs to be NUL-terminated.
+    if path.len() >= addr.sun_path.len() {
+        return Err(io::Error::from(io::ErrorKind::InvalidInput));
+    }
+    addr.sun_family = libc::AF_UNIX as libc::sa_family_t;
+    for (dst, src) in addr.sun_path.iter_mut().zip(path.iter()) {
+        *dst = *src as libc::c_char;
+    }
+    Ok(addr)
+}
+
+/// Connects a SOCK_SEQPACKET Unix socket to `path`.
+///
+/// Since the standard library only offers stream Unix sockets, the connected socket is wrapped
+/// in a `UnixStream`. Each read from, or write to, this stream carries a single message.
+fn connect_seqpacket<P: AsRef<Path>>(path: P) -> io::Result<UnixStream> {
+    let addr = sockaddr_un(path)?;
+
+    // This is safe because we check the return value.
+    let fd = unsafe { libc::socket(libc::AF_UNIX, libc::SOCK_SEQPACKET | libc::SOCK_CLOEXEC, 0) };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // This is safe because we just created the file descriptor, and nothing else owns it.
+    let stream = unsafe { UnixStream::from_raw_fd(fd) };
+
+    // This is safe because `addr` is a valid `sockaddr_un`, and we check the return value.
+    let ret = unsafe {
+        libc::connect(
+            fd,
+            &addr as *const libc::sockaddr_un as *const libc::sockaddr,
+            mem::size_of::<libc::sockaddr_un>() as libc::socklen_t,
+        )
+    };
+    if ret < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(stream)
+}
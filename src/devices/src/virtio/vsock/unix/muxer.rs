@@ -43,20 +43,20 @@ use super::super::csm::ConnState;
 use super::super::defs::uapi;
 use super::super::packet::VsockPacket;
 use super::super::{
-    Result as VsockResult, VsockBackend, VsockChannel, VsockEpollListener, VsockError,
+    Result as VsockResult, VsockBackend, VsockChannel, VsockConnLimits, VsockEpollListener,
+    VsockError,
 };
-use super::defs;
 use super::muxer_killq::MuxerKillQ;
 use super::muxer_rxq::MuxerRxQ;
 use super::MuxerConnection;
-use super::{Error, Result};
+use super::{connect_seqpacket, Error, Result};
 
 /// A unique identifier of a `MuxerConnection` object. Connections are stored in a hash map,
 /// keyed by a `ConnMapKey` object.
 #[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
 pub struct ConnMapKey {
-    local_port: u32,
-    peer_port: u32,
+    pub(crate) local_port: u32,
+    pub(crate) peer_port: u32,
 }
 
 /// A muxer RX queue item.
@@ -64,8 +64,12 @@ pub struct ConnMapKey {
 pub enum MuxerRx {
     /// The packet must be fetched from the connection identified by `ConnMapKey`.
     ConnRx(ConnMapKey),
-    /// The muxer must produce an RST packet.
-    RstPkt { local_port: u32, peer_port: u32 },
+    /// The muxer must produce an RST packet, of type `type_`.
+    RstPkt {
+        local_port: u32,
+        peer_port: u32,
+        type_: u16,
+    },
 }
 
 /// An epoll listener, registered under the muxer's nested epoll FD.
@@ -110,6 +114,8 @@ pub struct VsockMuxer {
     local_port_set: HashSet<u32>,
     /// The last used host-side port.
     local_port_last: u32,
+    /// Connection limits enforced by this muxer.
+    conn_limits: VsockConnLimits,
 }
 
 impl VsockChannel for VsockMuxer {
@@ -134,6 +140,7 @@ impl VsockChannel for VsockMuxer {
                 MuxerRx::RstPkt {
                     local_port,
                     peer_port,
+                    type_,
                 } => {
                     pkt.set_op(uapi::VSOCK_OP_RST)
                         .set_src_cid(uapi::VSOCK_HOST_CID)
@@ -141,7 +148,7 @@ impl VsockChannel for VsockMuxer {
                         .set_src_port(local_port)
                         .set_dst_port(peer_port)
                         .set_len(0)
-                        .set_type(uapi::VSOCK_TYPE_STREAM)
+                        .set_type(type_)
                         .set_flags(0)
                         .set_buf_alloc(0)
                         .set_fwd_cnt(0);
@@ -204,10 +211,11 @@ impl VsockChannel for VsockMuxer {
             pkt.hdr()
         );
 
-        // If this packet has an unsupported type (!=stream), we must send back an RST.
+        // If this packet has an unsupported type (neither stream nor seqpacket), we must send
+        // back an RST.
         //
-        if pkt.type_() != uapi::VSOCK_TYPE_STREAM {
-            self.enq_rst(pkt.dst_port(), pkt.src_port());
+        if pkt.type_() != uapi::VSOCK_TYPE_STREAM && pkt.type_() != uapi::VSOCK_TYPE_SEQPACKET {
+            self.enq_rst(pkt.dst_port(), pkt.src_port(), pkt.type_());
             return Ok(());
         }
 
@@ -230,7 +238,7 @@ impl VsockChannel for VsockMuxer {
                 self.handle_peer_request_pkt(&pkt);
             } else {
                 // Send back an RST, to let the drive know we weren't expecting this packet.
-                self.enq_rst(pkt.dst_port(), pkt.src_port());
+                self.enq_rst(pkt.dst_port(), pkt.src_port(), pkt.type_());
             }
             return Ok(());
         }
@@ -307,7 +315,7 @@ impl VsockBackend for VsockMuxer {}
 
 impl VsockMuxer {
     /// Muxer constructor.
-    pub fn new(cid: u64, host_sock_path: String) -> Result<Self> {
+    pub fn new(cid: u64, host_sock_path: String, conn_limits: VsockConnLimits) -> Result<Self> {
         // Open/bind on the host Unix socket, so we can accept host-initiated
         // connections.
         let host_sock = UnixListener::bind(&host_sock_path)
@@ -320,11 +328,12 @@ impl VsockMuxer {
             host_sock_path,
             epoll: Epoll::new().map_err(Error::EpollFdCreate)?,
             rxq: MuxerRxQ::new(),
-            conn_map: HashMap::with_capacity(defs::MAX_CONNECTIONS),
-            listener_map: HashMap::with_capacity(defs::MAX_CONNECTIONS + 1),
+            conn_map: HashMap::with_capacity(conn_limits.max_connections),
+            listener_map: HashMap::with_capacity(conn_limits.max_connections + 1),
             killq: MuxerKillQ::new(),
             local_port_last: (1u32 << 30) - 1,
-            local_port_set: HashSet::with_capacity(defs::MAX_CONNECTIONS),
+            local_port_set: HashSet::with_capacity(conn_limits.max_connections),
+            conn_limits,
         };
 
         // Listen on the host initiated socket, for incoming connections.
@@ -336,6 +345,11 @@ impl VsockMuxer {
         &self.host_sock_path
     }
 
+    /// Returns the connection limits enforced by this muxer.
+    pub fn conn_limits(&self) -> &VsockConnLimits {
+        &self.conn_limits
+    }
+
     /// Handle/dispatch an epoll event to its listener.
     fn handle_event(&mut self, fd: RawFd, event_set: EventSet) {
         debug!(
@@ -359,10 +373,11 @@ impl VsockMuxer {
 
             // A new host-initiated connection is ready to be accepted.
             Some(EpollListener::HostSock) => {
-                if self.conn_map.len() == defs::MAX_CONNECTIONS {
+                if self.conn_map.len() >= self.conn_limits.max_connections {
                     // If we're already maxed-out on connections, we'll just accept and
                     // immediately discard this potentially new one.
                     warn!("vsock: connection limit reached; refusing new host connection");
+                    METRICS.vsock.conns_rejected.inc();
                     self.host_sock.accept().map(|_| 0).unwrap_or(0);
                     return;
                 }
@@ -476,11 +491,12 @@ impl VsockMuxer {
         //   termination.
         self.sweep_killq();
 
-        if self.conn_map.len() >= defs::MAX_CONNECTIONS {
+        if self.conn_map.len() >= self.conn_limits.max_connections {
             info!(
                 "vsock: muxer connection limit reached ({})",
-                defs::MAX_CONNECTIONS
+                self.conn_limits.max_connections
             );
+            METRICS.vsock.conns_rejected.inc();
             return Err(Error::TooManyConnections);
         }
 
@@ -592,32 +608,55 @@ impl VsockMuxer {
     /// Handle a new connection request comming from our peer (the guest vsock driver).
     ///
     /// This will attempt to connect to a host-side Unix socket, expected to be listening at
-    /// the file system path corresponing to the destination port. If successful, a new
-    /// connection object will be created and added to the connection pool. On failure, a new
-    /// RST packet will be scheduled for delivery to the guest.
+    /// the file system path corresponing to the destination port. SOCK_SEQPACKET requests are
+    /// forwarded to a sequential packet Unix socket, while stream requests are forwarded to a
+    /// stream Unix socket. If successful, a new connection object will be created and added to
+    /// the connection pool. On failure, a new RST packet will be scheduled for delivery to the
+    /// guest. Requests for ports missing from the allow-list are refused right away.
     fn handle_peer_request_pkt(&mut self, pkt: &VsockPacket) {
+        if !self.conn_limits.allows_port(pkt.dst_port()) {
+            info!(
+                "vsock: refusing guest connection to port {}: not allowed",
+                pkt.dst_port()
+            );
+            METRICS.vsock.conns_rejected.inc();
+            self.enq_rst(pkt.dst_port(), pkt.src_port(), pkt.type_());
+            return;
+        }
+
         let port_path = format!("{}_{}", self.host_sock_path, pkt.dst_port());
+        let seqpacket = pkt.type_() == uapi::VSOCK_TYPE_SEQPACKET;
 
-        UnixStream::connect(port_path)
+        let stream = if seqpacket {
+            connect_seqpacket(&port_path)
+        } else {
+            UnixStream::connect(port_path)
+        };
+        stream
             .and_then(|stream| stream.set_nonblocking(true).map(|_| stream))
             .map_err(Error::UnixConnect)
             .and_then(|stream| {
+                let conn = MuxerConnection::new_peer_init(
+                    stream,
+                    uapi::VSOCK_HOST_CID,
+                    self.cid,
+                    pkt.dst_port(),
+                    pkt.src_port(),
+                    pkt.buf_alloc(),
+                );
                 self.add_connection(
                     ConnMapKey {
                         local_port: pkt.dst_port(),
                         peer_port: pkt.src_port(),
                     },
-                    MuxerConnection::new_peer_init(
-                        stream,
-                        uapi::VSOCK_HOST_CID,
-                        self.cid,
-                        pkt.dst_port(),
-                        pkt.src_port(),
-                        pkt.buf_alloc(),
-                    ),
+                    if seqpacket {
+                        conn.with_seqpacket()
+                    } else {
+                        conn
+                    },
                 )
             })
-            .unwrap_or_else(|_| self.enq_rst(pkt.dst_port(), pkt.src_port()));
+            .unwrap_or_else(|_| self.enq_rst(pkt.dst_port(), pkt.src_port(), pkt.type_()));
     }
 
     /// Perform an action that might mutate a connection's state.
@@ -757,10 +796,11 @@ impl VsockMuxer {
     /// Enqueue errors aren't propagated up the call chain, since there is nothing we can do to
     /// handle them. We do, however, log a warning, since not being able to enqueue an RST
     /// packet means we have to drop it, which is not normal operation.
-    fn enq_rst(&mut self, local_port: u32, peer_port: u32) {
+    fn enq_rst(&mut self, local_port: u32, peer_port: u32, type_: u16) {
         let pushed = self.rxq.push(MuxerRx::RstPkt {
             local_port,
             peer_port,
+            type_,
         });
         if !pushed {
             warn!(
@@ -775,11 +815,13 @@ impl VsockMuxer {
 mod tests {
     use std::io::{Read, Write};
     use std::ops::Drop;
+    use std::os::unix::io::FromRawFd;
     use std::os::unix::net::{UnixListener, UnixStream};
     use std::path::{Path, PathBuf};
     use utils::tempfile::TempFile;
 
     use super::super::super::csm::defs as csm_defs;
+    use super::super::defs;
     use super::*;
     use crate::virtio::vsock::test_utils::TestContext as VsockTestContext;
 
@@ -813,6 +855,10 @@ mod tests {
 
     impl MuxerTestContext {
         fn new(name: &str) -> Self {
+            Self::new_with_limits(name, VsockConnLimits::default())
+        }
+
+        fn new_with_limits(name: &str, conn_limits: VsockConnLimits) -> Self {
             let vsock_test_ctx = VsockTestContext::new();
             let mut handler_ctx = vsock_test_ctx.create_event_handler_context();
             let pkt = VsockPacket::from_rx_virtq_head(
@@ -822,7 +868,7 @@ mod tests {
             )
             .unwrap();
 
-            let muxer = VsockMuxer::new(PEER_CID, get_file(name)).unwrap();
+            let muxer = VsockMuxer::new(PEER_CID, get_file(name), conn_limits).unwrap();
             Self {
                 _vsock_test_ctx: vsock_test_ctx,
                 pkt,
@@ -894,6 +940,10 @@ mod tests {
             LocalListener::new(format!("{}_{}", self.muxer.host_sock_path, port))
         }
 
+        fn create_seqpacket_listener(&self, port: u32) -> LocalListener {
+            LocalListener::new_seqpacket(format!("{}_{}", self.muxer.host_sock_path, port))
+        }
+
         fn local_connect(&mut self, peer_port: u32) -> (UnixStream, u32) {
             let (init_local_lsn_count, init_conn_lsn_count) = self.count_epoll_listeners();
 
@@ -962,6 +1012,32 @@ mod tests {
                 sock,
             }
         }
+        fn new_seqpacket<P: AsRef<Path> + Clone>(path: P) -> Self {
+            let path_buf = path.as_ref().to_path_buf();
+            let addr = super::super::sockaddr_un(path).unwrap();
+            // The standard library only offers stream Unix sockets, so we'll wrap a sequential
+            // packet listening socket in a `UnixListener`.
+            let sock = unsafe {
+                let fd = libc::socket(libc::AF_UNIX, libc::SOCK_SEQPACKET | libc::SOCK_CLOEXEC, 0);
+                assert!(fd >= 0);
+                let sock = UnixListener::from_raw_fd(fd);
+                assert_eq!(
+                    libc::bind(
+                        fd,
+                        &addr as *const libc::sockaddr_un as *const libc::sockaddr,
+                        std::mem::size_of::<libc::sockaddr_un>() as libc::socklen_t,
+                    ),
+                    0
+                );
+                assert_eq!(libc::listen(fd, 1), 0);
+                sock
+            };
+            sock.set_nonblocking(true).unwrap();
+            Self {
+                path: path_buf,
+                sock,
+            }
+        }
         fn accept(&mut self) -> UnixStream {
             let (stream, _) = self.sock.accept().unwrap();
             stream.set_nonblocking(true).unwrap();
@@ -1003,7 +1079,7 @@ mod tests {
     fn test_bad_peer_pkt() {
         const LOCAL_PORT: u32 = 1026;
         const PEER_PORT: u32 = 1025;
-        const SOCK_DGRAM: u16 = 2;
+        const SOCK_DGRAM: u16 = 3;
 
         let mut ctx = MuxerTestContext::new("bad_peer_pkt");
         ctx.init_pkt(LOCAL_PORT, PEER_PORT, uapi::VSOCK_OP_REQUEST)
@@ -1011,10 +1087,11 @@ mod tests {
         ctx.send();
 
         // The guest sent a SOCK_DGRAM packet. Per the vsock spec, we need to reply with an RST
-        // packet, since vsock only supports stream sockets.
+        // packet, since we only support stream and seqpacket sockets.
         assert!(ctx.muxer.has_pending_rx());
         ctx.recv();
         assert_eq!(ctx.pkt.op(), uapi::VSOCK_OP_RST);
+        assert_eq!(ctx.pkt.type_(), SOCK_DGRAM);
         assert_eq!(ctx.pkt.src_cid(), uapi::VSOCK_HOST_CID);
         assert_eq!(ctx.pkt.dst_cid(), PEER_CID);
         assert_eq!(ctx.pkt.src_port(), LOCAL_PORT);
@@ -1047,6 +1124,81 @@ mod tests {
         assert!(!ctx.muxer.has_pending_rx());
     }
 
+    #[test]
+    fn test_seqpacket_peer_connection() {
+        const LOCAL_PORT: u32 = 1026;
+        const PEER_PORT: u32 = 1025;
+
+        let mut ctx = MuxerTestContext::new("seqpacket_peer_connection");
+
+        // Test peer connection refused. The RST packet should have the request type.
+        ctx.init_pkt(LOCAL_PORT, PEER_PORT, uapi::VSOCK_OP_REQUEST)
+            .set_type(uapi::VSOCK_TYPE_SEQPACKET);
+        ctx.send();
+        ctx.recv();
+        assert_eq!(ctx.pkt.op(), uapi::VSOCK_OP_RST);
+        assert_eq!(ctx.pkt.type_(), uapi::VSOCK_TYPE_SEQPACKET);
+
+        // A SOCK_SEQPACKET request can't be forwarded to a stream socket.
+        {
+            let _listener = ctx.create_local_listener(LOCAL_PORT);
+            ctx.init_pkt(LOCAL_PORT, PEER_PORT, uapi::VSOCK_OP_REQUEST)
+                .set_type(uapi::VSOCK_TYPE_SEQPACKET);
+            ctx.send();
+            ctx.recv();
+            assert_eq!(ctx.pkt.op(), uapi::VSOCK_OP_RST);
+        }
+
+        // Test peer connection accepted.
+        let mut listener = ctx.create_seqpacket_listener(LOCAL_PORT);
+        ctx.init_pkt(LOCAL_PORT, PEER_PORT, uapi::VSOCK_OP_REQUEST)
+            .set_type(uapi::VSOCK_TYPE_SEQPACKET);
+        ctx.send();
+        assert_eq!(ctx.muxer.conn_map.len(), 1);
+        let mut stream = listener.accept();
+        ctx.recv();
+        assert_eq!(ctx.pkt.op(), uapi::VSOCK_OP_RESPONSE);
+        assert_eq!(ctx.pkt.type_(), uapi::VSOCK_TYPE_SEQPACKET);
+
+        // Test guest -> host data flow. A message split across packets should be received by
+        // the host in a single read.
+        ctx.init_data_pkt(LOCAL_PORT, PEER_PORT, &[1, 2])
+            .set_type(uapi::VSOCK_TYPE_SEQPACKET)
+            .set_flags(0);
+        ctx.send();
+        ctx.init_data_pkt(LOCAL_PORT, PEER_PORT, &[3])
+            .set_type(uapi::VSOCK_TYPE_SEQPACKET)
+            .set_flags(uapi::VSOCK_FLAGS_SEQ_EOM);
+        ctx.send();
+        let mut buf = vec![0; 32];
+        let len = stream.read(buf.as_mut_slice()).unwrap();
+        assert_eq!(&buf[..len], &[1, 2, 3]);
+
+        // Test host -> guest data flow. Message boundaries should be preserved.
+        stream.write_all(&[5, 6, 7]).unwrap();
+        stream.write_all(&[8]).unwrap();
+        for msg in [vec![5u8, 6, 7], vec![8u8]].iter() {
+            ctx.notify_muxer();
+            assert!(ctx.muxer.has_pending_rx());
+            ctx.recv();
+            assert_eq!(ctx.pkt.op(), uapi::VSOCK_OP_RW);
+            assert_eq!(ctx.pkt.type_(), uapi::VSOCK_TYPE_SEQPACKET);
+            assert!(ctx.pkt.is_eom());
+            assert_eq!(ctx.pkt.len() as usize, msg.len());
+            let mut buf = vec![];
+            ctx.pkt
+                .write_from_offset_to(&ctx._vsock_test_ctx.mem, 0, &mut buf, msg.len())
+                .unwrap();
+            assert_eq!(&buf, msg);
+        }
+
+        // Test connection reset.
+        ctx.init_pkt(LOCAL_PORT, PEER_PORT, uapi::VSOCK_OP_RST)
+            .set_type(uapi::VSOCK_TYPE_SEQPACKET);
+        ctx.send();
+        assert!(ctx.muxer.conn_map.is_empty());
+    }
+
     #[test]
     fn test_peer_connection() {
         const LOCAL_PORT: u32 = 1026;
@@ -1122,6 +1274,57 @@ mod tests {
         assert!(!ctx.muxer.has_pending_rx());
     }
 
+    #[test]
+    fn test_conn_limits() {
+        const LOCAL_PORT: u32 = 1026;
+        const DENIED_PORT: u32 = 1027;
+        const PEER_PORT: u32 = 1025;
+
+        let mut ctx = MuxerTestContext::new_with_limits(
+            "conn_limits",
+            VsockConnLimits {
+                max_connections: 1,
+                allowed_ports: Some(vec![LOCAL_PORT]),
+            },
+        );
+        assert_eq!(ctx.muxer.conn_limits().max_connections, 1);
+        let _listener = ctx.create_local_listener(LOCAL_PORT);
+        let _denied_listener = ctx.create_local_listener(DENIED_PORT);
+        let rejected = METRICS.vsock.conns_rejected.count();
+
+        // Ports missing from the allow-list are refused, even if a host socket is listening.
+        ctx.init_pkt(DENIED_PORT, PEER_PORT, uapi::VSOCK_OP_REQUEST);
+        ctx.send();
+        assert!(ctx.muxer.conn_map.is_empty());
+        ctx.recv();
+        assert_eq!(ctx.pkt.op(), uapi::VSOCK_OP_RST);
+        assert_eq!(ctx.pkt.src_port(), DENIED_PORT);
+        assert_eq!(METRICS.vsock.conns_rejected.count(), rejected + 1);
+
+        ctx.init_pkt(LOCAL_PORT, PEER_PORT, uapi::VSOCK_OP_REQUEST);
+        ctx.send();
+        assert_eq!(ctx.muxer.conn_map.len(), 1);
+        ctx.recv();
+        assert_eq!(ctx.pkt.op(), uapi::VSOCK_OP_RESPONSE);
+
+        // The connection limit has been reached, so a second guest connection is refused.
+        ctx.init_pkt(LOCAL_PORT, PEER_PORT + 1, uapi::VSOCK_OP_REQUEST);
+        ctx.send();
+        assert_eq!(ctx.muxer.conn_map.len(), 1);
+        ctx.recv();
+        assert_eq!(ctx.pkt.op(), uapi::VSOCK_OP_RST);
+        assert_eq!(ctx.pkt.dst_port(), PEER_PORT + 1);
+        assert_eq!(METRICS.vsock.conns_rejected.count(), rejected + 2);
+
+        // Host-initiated connections are closed right away.
+        let mut stream = UnixStream::connect(ctx.muxer.host_sock_path.clone()).unwrap();
+        ctx.notify_muxer();
+        assert_eq!(ctx.count_epoll_listeners(), (0, 1));
+        let mut buf = [0u8; 1];
+        assert_eq!(stream.read(&mut buf).unwrap(), 0);
+        assert_eq!(METRICS.vsock.conns_rejected.count(), rejected + 3);
+    }
+
     #[test]
     fn test_local_connection() {
         let mut ctx = MuxerTestContext::new("local_connection");
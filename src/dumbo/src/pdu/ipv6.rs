@@ -0,0 +1,201 @@
+// Copyright 2022 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Contains support for parsing the fixed header of IPv6 packets.
+//!
+//! Only read access is provided, and extension headers are not interpreted. A picture of the
+//! IPv6 fixed header can be found [here].
+//!
+//! [here]: https://en.wikipedia.org/wiki/IPv6_packet#Fixed_header
+
+use std::convert::TryFrom;
+use std::net::Ipv6Addr;
+use std::result::Result;
+
+use crate::pdu::bytes::{InnerBytes, NetworkBytes};
+
+const VERSION_OFFSET: usize = 0;
+const PAYLOAD_LEN_OFFSET: usize = 4;
+const NEXT_HEADER_OFFSET: usize = 6;
+const HOP_LIMIT_OFFSET: usize = 7;
+const SOURCE_ADDRESS_OFFSET: usize = 8;
+const DESTINATION_ADDRESS_OFFSET: usize = 24;
+/// Length of the IPv6 fixed header.
+pub const HEADER_LEN: usize = 40;
+
+/// Indicates version 6 of the IP protocol
+pub const IPV6_VERSION: u8 = 0x06;
+
+/// The next header value associated with ICMPv6.
+pub const PROTOCOL_ICMPV6: u8 = 0x3a;
+
+/// Describes the errors which may occur while handling IPv6 packets.
+#[derive(Debug, PartialEq)]
+pub enum Error {
+    /// The payload length is larger than the remaining bytes in the slice.
+    InvalidPayloadLen,
+    /// The length of the given slice is less than the IPv6 fixed header length.
+    SliceTooShort,
+    /// The version header field is invalid.
+    Version,
+}
+
+/// Interprets the inner bytes as an IPv6 packet.
+pub struct IPv6Packet<'a, T: 'a> {
+    bytes: InnerBytes<'a, T>,
+}
+
+#[allow(clippy::len_without_is_empty)]
+impl<'a, T: NetworkBytes> IPv6Packet<'a, T> {
+    /// Interpret `bytes` as an IPv6Packet without checking the validity of the header fields, and
+    /// the length of the inner byte sequence.
+    ///
+    /// # Panics
+    ///
+    /// This method does not panic, but further method calls on the resulting object may panic if
+    /// `bytes` contains invalid input.
+    #[inline]
+    pub fn from_bytes_unchecked(bytes: T) -> Self {
+        IPv6Packet {
+            bytes: InnerBytes::new(bytes),
+        }
+    }
+
+    /// Attempts to interpret `bytes` as an IPv6 packet, checking the validity of the version
+    /// field and that the payload fits inside the inner byte sequence.
+    pub fn from_bytes(bytes: T) -> Result<Self, Error> {
+        let bytes_len = bytes.len();
+
+        if bytes_len < HEADER_LEN {
+            return Err(Error::SliceTooShort);
+        }
+
+        let packet = IPv6Packet::from_bytes_unchecked(bytes);
+
+        if packet.version() != IPV6_VERSION {
+            return Err(Error::Version);
+        }
+
+        // Ethernet frames may carry padding after the packet, so we only check that the payload
+        // does not go past the end of the slice.
+        if HEADER_LEN + packet.payload_len() as usize > bytes_len {
+            return Err(Error::InvalidPayloadLen);
+        }
+
+        Ok(packet)
+    }
+
+    /// Returns the value of the `version` header field.
+    #[inline]
+    pub fn version(&self) -> u8 {
+        self.bytes[VERSION_OFFSET] >> 4
+    }
+
+    /// Returns the value of the `payload length` header field.
+    #[inline]
+    pub fn payload_len(&self) -> u16 {
+        self.bytes.ntohs_unchecked(PAYLOAD_LEN_OFFSET)
+    }
+
+    /// Returns the value of the `next header` header field.
+    #[inline]
+    pub fn next_header(&self) -> u8 {
+        self.bytes[NEXT_HEADER_OFFSET]
+    }
+
+    /// Returns the value of the `hop limit` header field.
+    #[inline]
+    pub fn hop_limit(&self) -> u8 {
+        self.bytes[HOP_LIMIT_OFFSET]
+    }
+
+    /// Returns the source IPv6 address of the packet.
+    #[inline]
+    pub fn source_address(&self) -> Ipv6Addr {
+        Self::address_at(&self.bytes, SOURCE_ADDRESS_OFFSET)
+    }
+
+    /// Returns the destination IPv6 address of the packet.
+    #[inline]
+    pub fn destination_address(&self) -> Ipv6Addr {
+        Self::address_at(&self.bytes, DESTINATION_ADDRESS_OFFSET)
+    }
+
+    /// Returns a byte slice that contains the payload of the packet, as described by the
+    /// `payload length` header field.
+    ///
+    /// # Panics
+    ///
+    /// This method may panic if the packet was not created using `from_bytes`, and the value of
+    /// the `payload length` header field is invalid.
+    #[inline]
+    pub fn payload(&self) -> &[u8] {
+        &self.bytes[HEADER_LEN..HEADER_LEN + self.payload_len() as usize]
+    }
+
+    /// Returns the length of the inner byte sequence.
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.bytes.len()
+    }
+
+    #[inline]
+    fn address_at(bytes: &[u8], offset: usize) -> Ipv6Addr {
+        // The slice has exactly 16 bytes, so the conversion cannot fail.
+        let octets = <[u8; 16]>::try_from(&bytes[offset..offset + 16]).unwrap();
+        Ipv6Addr::from(octets)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn write_header(buf: &mut [u8], payload_len: u16, next_header: u8) {
+        buf[VERSION_OFFSET] = IPV6_VERSION << 4;
+        buf[PAYLOAD_LEN_OFFSET..PAYLOAD_LEN_OFFSET + 2].copy_from_slice(&payload_len.to_be_bytes());
+        buf[NEXT_HEADER_OFFSET] = next_header;
+        buf[HOP_LIMIT_OFFSET] = 255;
+        buf[SOURCE_ADDRESS_OFFSET..DESTINATION_ADDRESS_OFFSET]
+            .copy_from_slice(&Ipv6Addr::new(0xfe80, 0, 0, 0, 0, 0, 0, 1).octets());
+        buf[DESTINATION_ADDRESS_OFFSET..HEADER_LEN]
+            .copy_from_slice(&Ipv6Addr::new(0xff02, 0, 0, 0, 0, 0, 0, 2).octets());
+    }
+
+    #[test]
+    fn test_from_bytes() {
+        let mut a = [0u8; 100];
+
+        assert_eq!(
+            IPv6Packet::from_bytes(&a[..HEADER_LEN - 1]).err().unwrap(),
+            Error::SliceTooShort
+        );
+        assert_eq!(
+            IPv6Packet::from_bytes(a.as_ref()).err().unwrap(),
+            Error::Version
+        );
+
+        write_header(a.as_mut(), 61, PROTOCOL_ICMPV6);
+        assert_eq!(
+            IPv6Packet::from_bytes(a.as_ref()).err().unwrap(),
+            Error::InvalidPayloadLen
+        );
+
+        write_header(a.as_mut(), 16, PROTOCOL_ICMPV6);
+        let p = IPv6Packet::from_bytes(a.as_ref()).unwrap();
+        assert_eq!(p.version(), IPV6_VERSION);
+        assert_eq!(p.payload_len(), 16);
+        assert_eq!(p.next_header(), PROTOCOL_ICMPV6);
+        assert_eq!(p.hop_limit(), 255);
+        assert_eq!(
+            p.source_address(),
+            Ipv6Addr::new(0xfe80, 0, 0, 0, 0, 0, 0, 1)
+        );
+        assert_eq!(
+            p.destination_address(),
+            Ipv6Addr::new(0xff02, 0, 0, 0, 0, 0, 0, 2)
+        );
+        assert_eq!(p.payload().len(), 16);
+        assert_eq!(p.len(), 100);
+    }
+}
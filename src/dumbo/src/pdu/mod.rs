@@ -16,6 +16,7 @@ pub mod arp;
 pub mod bytes;
 pub mod ethernet;
 pub mod ipv4;
+pub mod ipv6;
 pub mod tcp;
 pub mod udp;
 
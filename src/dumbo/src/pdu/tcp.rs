@@ -36,8 +36,13 @@ const MAX_HEADER_LEN: usize = 60;
 const OPTION_KIND_EOL: u8 = 0x00;
 const OPTION_KIND_NOP: u8 = 0x01;
 const OPTION_KIND_MSS: u8 = 0x02;
+const OPTION_KIND_WINDOW_SCALE: u8 = 0x03;
 
 const OPTION_LEN_MSS: usize = 0x04;
+const OPTION_LEN_WINDOW_SCALE: usize = 0x03;
+
+/// The largest window scale shift count allowed by RFC 7323.
+pub const WINDOW_SCALE_MAX: u8 = 14;
 
 // An arbitrarily chosen value, used for sanity checks.
 const MSS_MIN: u16 = 100;
@@ -83,6 +88,8 @@ pub enum Error {
     MssOption,
     /// The remaining segment length cannot accommodate the MSS option.
     MssRemaining,
+    /// The remaining segment length cannot accommodate the window scale option.
+    WindowScaleRemaining,
     /// The specified slice is shorter than the header length.
     SliceTooShort,
 }
@@ -260,6 +267,43 @@ impl<'a, T: NetworkBytes> TcpSegment<'a, T> {
         Ok(None)
     }
 
+    /// Looks for the window scale option in the TCP header options.
+    ///
+    /// Returns the shift count advertised by the other endpoint (capped at [`WINDOW_SCALE_MAX`],
+    /// as mandated by RFC 7323), or `None` if the option is not present.
+    ///
+    /// # Panics
+    ///
+    /// This method may panic if the value of `header_len` is invalid.
+    ///
+    /// [`WINDOW_SCALE_MAX`]: constant.WINDOW_SCALE_MAX.html
+    pub fn parse_window_scale_option_unchecked(&self, header_len: usize) -> Option<u8> {
+        let b = self.options_unchecked(header_len);
+        let mut i = 0;
+
+        // The window scale option is 3 bytes wide, so we need at least 3 more bytes to look for it.
+        while i + 2 < b.len() {
+            match b[i] {
+                OPTION_KIND_EOL => break,
+                OPTION_KIND_NOP => {
+                    i += 1;
+                }
+                OPTION_KIND_WINDOW_SCALE => {
+                    return Some(min(b[i + 2], WINDOW_SCALE_MAX));
+                }
+                _ => {
+                    // Some other option; skip it, unless the length is bogus.
+                    let opt_len = b[i + 1] as usize;
+                    if opt_len < 2 {
+                        break;
+                    }
+                    i += opt_len;
+                }
+            }
+        }
+        None
+    }
+
     /// Interprets `bytes` as a TCP segment without any validity checks.
     ///
     /// # Panics
@@ -405,6 +449,8 @@ impl<'a, T: NetworkBytesMut> TcpSegment<'a, T> {
     /// * `flags_after_ns` - TCP flags to set (except `NS`, which is always set to 0).
     /// * `window_size` - Value to write in the `window size` field.
     /// * `mss_option` - When a value is specified, use it to add a TCP MSS option to the header.
+    /// * `window_scale_option` - When a value is specified, use it to add a TCP window scale
+    ///    option (preceded by a `NOP` for alignment) to the header.
     /// * `mss_remaining` - Represents an upper bound on the payload length (the number of bytes
     ///    used up by things like IP options have to be subtracted from the MSS). There is some
     ///    redundancy looking at this argument and the next one, so we might end up removing
@@ -424,6 +470,7 @@ impl<'a, T: NetworkBytesMut> TcpSegment<'a, T> {
         flags_after_ns: Flags,
         window_size: u16,
         mss_option: Option<u16>,
+        window_scale_option: Option<u8>,
         mss_remaining: u16,
         payload: Option<(&R, usize)>,
         compute_checksum: Option<(Ipv4Addr, Ipv4Addr)>,
@@ -435,6 +482,7 @@ impl<'a, T: NetworkBytesMut> TcpSegment<'a, T> {
             flags_after_ns,
             window_size,
             mss_option,
+            window_scale_option,
             mss_remaining,
             payload,
         )?
@@ -445,7 +493,7 @@ impl<'a, T: NetworkBytesMut> TcpSegment<'a, T> {
     /// and `checksum` fields.
     ///
     /// This method writes the rest of the segment, including data (when available). Only the `MSS`
-    /// option is supported for now. The `NS` flag, `URG` flag, and `urgent pointer` field are set
+    /// and window scale options are supported for now. The `NS` flag, `URG` flag, and `urgent pointer` field are set
     /// to 0.
     ///
     /// # Arguments
@@ -456,6 +504,8 @@ impl<'a, T: NetworkBytesMut> TcpSegment<'a, T> {
     /// * `flags_after_ns` - TCP flags to set (except `NS`, which is always set to 0).
     /// * `window_size` - Value to write in the `window size` field.
     /// * `mss_option` - When a value is specified, use it to add a TCP MSS option to the header.
+    /// * `window_scale_option` - When a value is specified, use it to add a TCP window scale
+    ///    option (preceded by a `NOP` for alignment) to the header.
     /// * `mss_remaining` - Represents an upper bound on the payload length (the number of bytes
     ///    used up by things like IP options have to be subtracted from the MSS). There is some
     ///    redundancy looking at this argument and the next one, so we might end up removing
@@ -473,6 +523,7 @@ impl<'a, T: NetworkBytesMut> TcpSegment<'a, T> {
         flags_after_ns: Flags,
         window_size: u16,
         mss_option: Option<u16>,
+        window_scale_option: Option<u8>,
         mss_remaining: u16,
         payload: Option<(&R, usize)>,
     ) -> Result<Incomplete<Self>, Error> {
@@ -482,14 +533,21 @@ impl<'a, T: NetworkBytesMut> TcpSegment<'a, T> {
         let mut segment_len = OPTIONS_OFFSET;
 
         // The TCP options will require this much more bytes.
-        let options_len = if mss_option.is_some() {
+        let mut options_len = 0;
+        if mss_option.is_some() {
             mss_left = mss_left
                 .checked_sub(OPTION_LEN_MSS)
                 .ok_or(Error::MssRemaining)?;
-            OPTION_LEN_MSS
-        } else {
-            0
-        };
+            options_len += OPTION_LEN_MSS;
+        }
+        // The window scale option is prefixed by a NOP, so the header length stays a multiple
+        // of 4 bytes.
+        if window_scale_option.is_some() {
+            mss_left = mss_left
+                .checked_sub(OPTION_LEN_WINDOW_SCALE + 1)
+                .ok_or(Error::WindowScaleRemaining)?;
+            options_len += OPTION_LEN_WINDOW_SCALE + 1;
+        }
 
         segment_len += options_len;
 
@@ -509,10 +567,20 @@ impl<'a, T: NetworkBytesMut> TcpSegment<'a, T> {
             .set_urgent_pointer(0);
 
         // Let's write the MSS option if we have to.
+        let mut option_offset = OPTIONS_OFFSET;
         if let Some(value) = mss_option {
-            segment.bytes[OPTIONS_OFFSET] = OPTION_KIND_MSS;
-            segment.bytes[OPTIONS_OFFSET + 1] = OPTION_LEN_MSS as u8;
-            segment.bytes.htons_unchecked(OPTIONS_OFFSET + 2, value);
+            segment.bytes[option_offset] = OPTION_KIND_MSS;
+            segment.bytes[option_offset + 1] = OPTION_LEN_MSS as u8;
+            segment.bytes.htons_unchecked(option_offset + 2, value);
+            option_offset += OPTION_LEN_MSS;
+        }
+
+        // And the window scale option as well.
+        if let Some(shift) = window_scale_option {
+            segment.bytes[option_offset] = OPTION_KIND_NOP;
+            segment.bytes[option_offset + 1] = OPTION_KIND_WINDOW_SCALE;
+            segment.bytes[option_offset + 2] = OPTION_LEN_WINDOW_SCALE as u8;
+            segment.bytes[option_offset + 3] = shift;
         }
 
         let payload_bytes_count = if let Some((payload_buf, max_payload_bytes)) = payload {
@@ -661,6 +729,7 @@ mod tests {
                 flags_after_ns,
                 window_size,
                 mss_option,
+                None,
                 mss_left,
                 payload,
                 Some((src_addr, dst_addr)),
@@ -719,6 +788,7 @@ mod tests {
                 flags_after_ns,
                 window_size,
                 mss_option,
+                None,
                 mss_left,
                 Some((c.as_ref(), c.len())),
                 Some((src_addr, dst_addr)),
@@ -780,6 +850,7 @@ mod tests {
                 flags_after_ns,
                 window_size,
                 mss_option,
+                None,
                 mss_left,
                 payload,
                 Some((src_addr, dst_addr)),
@@ -799,6 +870,7 @@ mod tests {
                 flags_after_ns,
                 window_size,
                 mss_option,
+                None,
                 0,
                 payload,
                 Some((src_addr, dst_addr)),
@@ -807,4 +879,103 @@ mod tests {
             Error::MssRemaining
         );
     }
+
+    #[test]
+    fn test_window_scale_option() {
+        let mut a = [0u8; 100];
+        let src_addr = Ipv4Addr::new(10, 1, 2, 3);
+        let dst_addr = Ipv4Addr::new(192, 168, 44, 77);
+
+        // Both options present.
+        let segment = TcpSegment::write_segment::<[u8]>(
+            a.as_mut(),
+            1234,
+            5678,
+            1,
+            0,
+            Flags::SYN,
+            65535,
+            Some(1460),
+            Some(7),
+            1460,
+            None,
+            Some((src_addr, dst_addr)),
+        )
+        .unwrap();
+        let header_len = OPTIONS_OFFSET + OPTION_LEN_MSS + OPTION_LEN_WINDOW_SCALE + 1;
+        assert_eq!(segment.header_len(), header_len);
+        assert_eq!(
+            segment.parse_mss_option_unchecked(header_len).unwrap(),
+            NonZeroU16::new(1460)
+        );
+        assert_eq!(
+            segment.parse_window_scale_option_unchecked(header_len),
+            Some(7)
+        );
+
+        // Only the window scale option, with a shift count above the maximum.
+        let segment = TcpSegment::write_segment::<[u8]>(
+            a.as_mut(),
+            1234,
+            5678,
+            1,
+            0,
+            Flags::SYN,
+            65535,
+            None,
+            Some(20),
+            1460,
+            None,
+            Some((src_addr, dst_addr)),
+        )
+        .unwrap();
+        let header_len = OPTIONS_OFFSET + OPTION_LEN_WINDOW_SCALE + 1;
+        assert_eq!(segment.header_len(), header_len);
+        assert_eq!(segment.parse_mss_option_unchecked(header_len), Ok(None));
+        assert_eq!(
+            segment.parse_window_scale_option_unchecked(header_len),
+            Some(WINDOW_SCALE_MAX)
+        );
+
+        // No options at all.
+        let segment = TcpSegment::write_segment::<[u8]>(
+            a.as_mut(),
+            1234,
+            5678,
+            1,
+            0,
+            Flags::SYN,
+            65535,
+            None,
+            None,
+            1460,
+            None,
+            Some((src_addr, dst_addr)),
+        )
+        .unwrap();
+        assert_eq!(
+            segment.parse_window_scale_option_unchecked(OPTIONS_OFFSET),
+            None
+        );
+
+        // Not enough room left for the window scale option.
+        assert_eq!(
+            TcpSegment::write_segment::<[u8]>(
+                a.as_mut(),
+                1234,
+                5678,
+                1,
+                0,
+                Flags::SYN,
+                65535,
+                Some(1460),
+                Some(7),
+                OPTION_LEN_MSS as u16 + 2,
+                None,
+                Some((src_addr, dst_addr)),
+            )
+            .unwrap_err(),
+            Error::WindowScaleRemaining
+        );
+    }
 }
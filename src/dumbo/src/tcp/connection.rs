@@ -1,15 +1,16 @@
 // Copyright 2018 Amazon.com, Inc. or its affiliates. All Rights Reserved.
 // SPDX-License-Identifier: Apache-2.0
 
-//! This module contains a minimalist TCP [`Connection`] implementation, which only supports
-//! passive open scenarios, and some auxiliary logic and data structures.
+//! This module contains a minimalist TCP [`Connection`] implementation, which mainly targets
+//! passive open scenarios (with limited support for active opens), and some auxiliary logic and
+//! data structures.
 //!
 //! [`Connection`]: struct.Connection.html
 
 use std::num::{NonZeroU16, NonZeroU64, NonZeroUsize, Wrapping};
 
 use crate::pdu::bytes::NetworkBytes;
-use crate::pdu::tcp::{Error as TcpSegmentError, Flags as TcpFlags, TcpSegment};
+use crate::pdu::tcp::{Error as TcpSegmentError, Flags as TcpFlags, TcpSegment, WINDOW_SCALE_MAX};
 use crate::pdu::Incomplete;
 use crate::tcp::{
     seq_after, seq_at_or_after, NextSegmentStatus, RstConfig, MAX_WINDOW_SIZE, MSS_DEFAULT,
@@ -34,6 +35,10 @@ bitflags! {
         const FIN_ACKED =           1 << 4;
         // The connection is reset, because we either sent, or received a RST segment.
         const RESET =               1 << 5;
+        // The connection was created via active open, and starts by sending a SYN.
+        const ACTIVE_OPEN =         1 << 6;
+        // We sent at least one SYN segment (only meaningful for active opens).
+        const SYN_SENT =            1 << 7;
     }
 }
 
@@ -123,11 +128,11 @@ pub enum WriteNextError {
 /// improvements/changes may happen in the future (this also goes for other aspects of the
 /// current implementation).
 ///
-/// A `Connection` object can only be created via passive open, and will not recognize/use any TCP
+/// A `Connection` object is usually created via passive open, and will not recognize/use any TCP
 /// options except `MSS` during the handshake. The associated state machine is similar to how
 /// TCP normally functions, but there are some differences:
 ///
-/// * Since only passive opens are supported, a `Connection` can only be instantiated in response
+/// * A passively opened `Connection` can only be instantiated in response
 ///   to an incoming `SYN` segment. If the segment is valid, it will start directly in a state
 ///   called `SYN_RECEIVED`. The valid events at this point are receiving a retransmission of the
 ///   previous `SYN` (which does nothing), and getting the chance to write a `SYNACK`, which also
@@ -136,6 +141,11 @@ pub enum WriteNextError {
 /// * In the `SYNACK_SENT` state, the connection awaits an `ACK` for the `SYNACK`. A
 ///   retransmission of the original `SYN` moves the state back to `SYN_RECEIVED`. A valid `ACK`
 ///   advances the state to `ESTABLISHED`. Any unexpected/invalid segment resets the connection.
+/// * An actively opened `Connection` starts by sending a `SYN` at the first opportunity, which is
+///   retransmitted until a `SYNACK` that acknowledges it arrives. Such a `SYNACK` moves the
+///   connection directly to `ESTABLISHED` (the final `ACK` of the handshake is enqueued). Only
+///   `RST` segments which acknowledge our `SYN` are accepted before that point, and segments
+///   carrying an invalid `ACK` number reset the connection. Simultaneous opens are not supported.
 /// * While `ESTABLISHED`, the connection will only reset if it receives a `RST` or a `SYN`.
 ///   Invalid segments are simply ignored. `FIN` handling is simplifed: when [`close`] is invoked
 ///   the connection records the `FIN` sequence number, and starts setting the `FIN` flag (when
@@ -186,6 +196,11 @@ pub struct Connection {
     send_rst: Option<RstConfig>,
     // The MSS used when sending data segments.
     mss: u16,
+    // The window scale shift count we advertise on our SYN or SYNACK, if any.
+    local_wscale: Option<u8>,
+    // The window scale shift count advertised by the other endpoint, if any. Window scaling is
+    // only in effect when both this and local_wscale are present (RFC 7323).
+    remote_wscale: Option<u8>,
     // If true, send an ACK segment at the first opportunity. ACKs can piggyback data segments, so
     // we'll only send an empty ACK segment if we can't transmit any data.
     pending_ack: bool,
@@ -203,6 +218,20 @@ fn parse_mss_option<T: NetworkBytes>(segment: &TcpSegment<T>) -> Result<u16, Pas
     }
 }
 
+fn parse_window_scale_option<T: NetworkBytes>(segment: &TcpSegment<T>) -> Option<u8> {
+    segment.parse_window_scale_option_unchecked(segment.header_len())
+}
+
+// Returns the smallest shift count which allows a receive window of size `local_rwnd_size` to be
+// advertised using the 16 bit window size field.
+fn window_scale_for(local_rwnd_size: u32) -> u8 {
+    let mut shift = 0;
+    while shift < WINDOW_SCALE_MAX && local_rwnd_size >> shift > u32::from(u16::max_value()) {
+        shift += 1;
+    }
+    shift
+}
+
 fn is_valid_syn<T: NetworkBytes>(segment: &TcpSegment<T>) -> bool {
     segment.flags_after_ns() == TcpFlags::SYN && segment.payload_len() == 0
 }
@@ -229,18 +258,19 @@ impl Connection {
             return Err(PassiveOpenError::InvalidSyn);
         }
 
-        // TODO: If we ever implement window scaling, change the part that computes
-        // remote_rwnd_edge below.
-
-        // We only care about the MSS option for now.
         let mss = parse_mss_option(segment)?;
 
+        // We only scale windows if the other endpoint asked for it on the SYN.
+        let remote_wscale = parse_window_scale_option(segment);
+        let local_wscale = remote_wscale.map(|_| window_scale_for(local_rwnd_size));
+
         // This is going to get sent on the SYNACK.
         let ack_to_send = Wrapping(segment.sequence_number()) + Wrapping(1);
 
         // Let's pick the initial sequence number.
         let isn = Wrapping(xor_psuedo_rng_u32());
         let first_not_sent = isn + Wrapping(1);
+        // The window size from a SYN is never scaled.
         let remote_rwnd_edge = first_not_sent + Wrapping(u32::from(segment.window_size()));
 
         Ok(Connection {
@@ -260,12 +290,65 @@ impl Connection {
             send_fin: None,
             send_rst: None,
             mss,
+            local_wscale,
+            remote_wscale,
             pending_ack: false,
             dup_ack: false,
             status_flags: ConnStatusFlags::SYN_RECEIVED,
         })
     }
 
+    /// Creates a new `Connection` which initiates the three-way handshake with the other endpoint.
+    ///
+    /// The connection sends a `SYN` at the first opportunity, and becomes established after
+    /// receiving a valid `SYNACK`. The other endpoint is considered to be the one which sends
+    /// segments to the connection later on, so its identity is not known by the `Connection`.
+    ///
+    /// # Arguments
+    ///
+    /// * `local_rwnd_size` - Initial size of the local receive window.
+    /// * `mss` - The `MSS` value advertised to the other endpoint. The connection sends data
+    ///   segments which are no larger than the minimum between this value and the `MSS`
+    ///   advertised by the other endpoint.
+    /// * `rto_period` - How long the connection waits before a retransmission timeout fires for
+    ///   the first segment which has not been acknowledged yet. This uses an opaque time unit.
+    /// * `rto_count_max` - How many consecutive timeout-based retransmission may occur before
+    ///   the connection resets itself.
+    pub fn active_open(
+        local_rwnd_size: u32,
+        mss: NonZeroU16,
+        rto_period: NonZeroU64,
+        rto_count_max: NonZeroU16,
+    ) -> Self {
+        let isn = Wrapping(xor_psuedo_rng_u32());
+        let first_not_sent = isn + Wrapping(1);
+
+        Connection {
+            // We don't know the ISN of the other endpoint yet. The right edge of the local
+            // receive window is relative to this value, and gets adjusted when the SYNACK arrives.
+            ack_to_send: Wrapping(0),
+            highest_ack_received: isn,
+            first_not_sent,
+            local_rwnd_edge: Wrapping(local_rwnd_size),
+            remote_rwnd_edge: first_not_sent,
+            rto_start: 0,
+            rto_period: rto_period.get(),
+            rto_count: 0,
+            rto_count_max: rto_count_max.get(),
+            fin_received: None,
+            send_fin: None,
+            send_rst: None,
+            mss: mss.get(),
+            // We always offer to scale windows; this only takes effect if the other endpoint
+            // also sends the option on the SYNACK.
+            local_wscale: Some(window_scale_for(local_rwnd_size)),
+            remote_wscale: None,
+            pending_ack: false,
+            dup_ack: false,
+            status_flags: ConnStatusFlags::ACTIVE_OPEN,
+        }
+    }
+
     fn flags_intersect(&self, flags: ConnStatusFlags) -> bool {
         self.status_flags.intersects(flags)
     }
@@ -290,6 +373,18 @@ impl Connection {
         self.flags_intersect(ConnStatusFlags::SYNACK_SENT)
     }
 
+    fn is_active_open(&self) -> bool {
+        self.flags_intersect(ConnStatusFlags::ACTIVE_OPEN)
+    }
+
+    fn syn_sent(&self) -> bool {
+        self.flags_intersect(ConnStatusFlags::SYN_SENT)
+    }
+
+    fn syn_pending(&self) -> bool {
+        self.is_active_open() && !self.syn_sent()
+    }
+
     fn is_reset(&self) -> bool {
         self.flags_intersect(ConnStatusFlags::RESET)
     }
@@ -311,6 +406,7 @@ impl Connection {
         }
 
         matches!(parse_mss_option(segment), Ok(mss) if mss == self.mss)
+            && parse_window_scale_option(segment) == self.remote_wscale
     }
 
     fn reset_for_segment<T: NetworkBytes>(&mut self, s: &TcpSegment<T>) {
@@ -334,10 +430,22 @@ impl Connection {
             && matches!(self.send_fin, Some(fin_seq) if fin_seq == self.highest_ack_received)
     }
 
-    // Returns the window size which should be written to an outgoing segment. This is going to be
-    // even more useful when we'll support window scaling.
-    fn local_rwnd(&self) -> u16 {
-        let rwnd = (self.local_rwnd_edge - self.ack_to_send).0;
+    // Returns the shift counts applied to the local and remote windows, which are both 0 unless
+    // window scaling has been negotiated.
+    fn window_shifts(&self) -> (u8, u8) {
+        match (self.local_wscale, self.remote_wscale) {
+            (Some(local), Some(remote)) => (local, remote),
+            _ => (0, 0),
+        }
+    }
+
+    // Returns the window size which should be written to an outgoing segment. The window size
+    // of SYN and SYNACK segments is never scaled.
+    fn local_rwnd(&self, is_syn: bool) -> u16 {
+        let mut rwnd = (self.local_rwnd_edge - self.ack_to_send).0;
+        if !is_syn {
+            rwnd >>= self.window_shifts().0;
+        }
 
         if rwnd > u32::from(u16::max_value()) {
             u16::max_value()
@@ -346,9 +454,9 @@ impl Connection {
         }
     }
 
-    // Will actually become meaningful when/if we implement window scaling.
+    // Returns the actual size of the window advertised by the other endpoint on a non-SYN segment.
     fn remote_window_size(&self, window_size: u16) -> u32 {
-        u32::from(window_size)
+        u32::from(window_size) << self.window_shifts().1
     }
 
     // Computes the remote rwnd edge given the ACK number and window size from an incoming segment.
@@ -377,7 +485,7 @@ impl Connection {
     /// endpoint to signal the connection should be reset.
     #[inline]
     pub fn make_rst_config(&self) -> RstConfig {
-        if self.is_established() {
+        if self.is_established() || self.is_active_open() {
             RstConfig::Seq(self.first_not_sent.0)
         } else {
             RstConfig::Ack(self.ack_to_send.0)
@@ -475,6 +583,7 @@ impl Connection {
     #[inline]
     pub fn control_segment_or_timeout_status(&self) -> NextSegmentStatus {
         if self.synack_pending()
+            || self.syn_pending()
             || self.rst_pending()
             || self.can_send_first_fin()
             || self.pending_ack
@@ -498,6 +607,74 @@ impl Connection {
         Ok((None, RecvStatusFlags::CONN_RESETTING | flags))
     }
 
+    // Handles an incoming segment for an actively opened connection which is not ESTABLISHED yet.
+    // The only valid segments at this point are a SYNACK which acknowledges our SYN, or a RST
+    // carrying the same acknowledgement number.
+    fn receive_synack<T: NetworkBytes>(
+        &mut self,
+        s: &TcpSegment<T>,
+        now: u64,
+    ) -> Result<(Option<NonZeroUsize>, RecvStatusFlags), RecvError> {
+        let segment_flags = s.flags_after_ns();
+        let valid_ack = segment_flags.intersects(TcpFlags::ACK)
+            && self.syn_sent()
+            && Wrapping(s.ack_number()) == self.first_not_sent;
+
+        if segment_flags.intersects(TcpFlags::RST) {
+            if valid_ack {
+                self.set_flags(ConnStatusFlags::RESET);
+                return Ok((None, RecvStatusFlags::RESET_RECEIVED));
+            } else {
+                return Ok((None, RecvStatusFlags::INVALID_RST));
+            }
+        }
+
+        if !valid_ack {
+            if segment_flags.intersects(TcpFlags::ACK) {
+                return self.reset_for_segment_helper(s, RecvStatusFlags::INVALID_ACK);
+            }
+            // We don't support simultaneous opens, so SYN segments are ignored, together with
+            // anything else which does not carry an ACK.
+            return Ok((None, RecvStatusFlags::INVALID_SEGMENT));
+        }
+
+        if segment_flags != TcpFlags::SYN | TcpFlags::ACK || s.payload_len() > 0 {
+            return self.reset_for_segment_helper(s, RecvStatusFlags::INVALID_SEGMENT);
+        }
+
+        let remote_mss = match parse_mss_option(s) {
+            Ok(value) => value,
+            Err(_) => return self.reset_for_segment_helper(s, RecvStatusFlags::INVALID_SEGMENT),
+        };
+
+        if remote_mss < self.mss {
+            self.mss = remote_mss;
+        }
+
+        // The window size carried by the SYNACK is not scaled, so we record the shift count of
+        // the other endpoint only after computing the remote rwnd edge below.
+        let remote_wscale = parse_window_scale_option(s);
+        if remote_wscale.is_none() {
+            self.local_wscale = None;
+        }
+
+        let local_rwnd_size = self.local_rwnd_edge - self.ack_to_send;
+        self.ack_to_send = Wrapping(s.sequence_number()) + Wrapping(1);
+        self.local_rwnd_edge = self.ack_to_send + local_rwnd_size;
+
+        self.highest_ack_received = self.first_not_sent;
+        self.remote_rwnd_edge = self.compute_remote_rwnd_edge(self.first_not_sent, s.window_size());
+        self.remote_wscale = remote_wscale;
+        self.rto_count = 0;
+        self.rto_start = now;
+
+        self.set_flags(ConnStatusFlags::ESTABLISHED);
+        // This ACK completes the three-way handshake.
+        self.enqueue_ack();
+
+        Ok((None, RecvStatusFlags::empty()))
+    }
+
     /// Handles an incoming segment.
     ///
     /// When no errors occur, returns a pair consisting of how many
@@ -520,16 +697,18 @@ impl Connection {
             return Err(RecvError::ConnectionReset);
         }
 
-        // TODO: The following logic fully makes sense only for a passive open (which is what we
-        // currently support). Things must change a bit if/when we also implement active opens.
+        if self.is_active_open() && !self.is_established() {
+            return self.receive_synack(s, now);
+        }
+
+        // The rest of the logic is shared by connections created via passive and active opens,
+        // with the exception of the handshake related parts, which only apply to passive opens.
 
         let segment_flags = s.flags_after_ns();
 
         if segment_flags.intersects(TcpFlags::RST) {
             let seq = Wrapping(s.sequence_number());
             // We accept the RST only if it carries an in-window sequence number.
-            // TODO: If/when we support active opens, we'll also have to accept RST/SYN segments,
-            // which must acknowledge our SYN to be valid.
             if seq_at_or_after(seq, self.ack_to_send) && seq_after(self.local_rwnd_edge, seq) {
                 self.set_flags(ConnStatusFlags::RESET);
                 return Ok((None, RecvStatusFlags::RESET_RECEIVED));
@@ -541,7 +720,15 @@ impl Connection {
         let payload_len = s.len() - s.header_len();
         let mut recv_status_flags = RecvStatusFlags::empty();
 
-        if !self.synack_sent() {
+        if self.is_established() {
+            // Reaching this branch means the connection is ESTABLISHED. The only thing we want to
+            // do right now is reset if we get segments which carry the SYN flag, because they are
+            // obviously invalid, and something must be really wrong.
+            // TODO: Is it an overreaction to reset here?
+            if s.flags_after_ns().intersects(TcpFlags::SYN) {
+                return self.reset_for_segment_helper(s, RecvStatusFlags::INVALID_SEGMENT);
+            }
+        } else if !self.synack_sent() {
             // We received another segment before getting the chance to send a SYNACK. It's either
             // a retransmitted SYN, or something that does not make sense.
             if self.is_same_syn(s) {
@@ -549,7 +736,7 @@ impl Connection {
             } else {
                 return self.reset_for_segment_helper(s, RecvStatusFlags::INVALID_SEGMENT);
             }
-        } else if !self.is_established() {
+        } else {
             // So at this point we've sent at least one SYNACK, but the connection is not
             // ESTABLISHED yet. We only accept SYN retransmissions and ACKs. I'm not sure that
             // it's completely forbidden to sent an ACK + data in response to a SYNACK, so we don't
@@ -563,14 +750,6 @@ impl Connection {
                 // retransmission.
                 return self.reset_for_segment_helper(s, RecvStatusFlags::INVALID_SEGMENT);
             }
-        } else {
-            // Reaching this branch means the connection is ESTABLISHED. The only thing we want to
-            // do right now is reset if we get segments which carry the SYN flag, because they are
-            // obviously invalid, and something must be really wrong.
-            // TODO: Is it an overreaction to reset here?
-            if s.flags_after_ns().intersects(TcpFlags::SYN) {
-                return self.reset_for_segment_helper(s, RecvStatusFlags::INVALID_SEGMENT);
-            }
         }
 
         // The ACK number can only be valid when ACK flag is set. The following logic applies to
@@ -733,11 +912,12 @@ impl Connection {
         flags_after_ns: TcpFlags,
         payload: Option<(&R, usize)>,
     ) -> Result<Incomplete<TcpSegment<'a, &'a mut [u8]>>, WriteNextError> {
-        // Write the MSS option on SYNACK segments.
-        let mss_option = if flags_after_ns == TcpFlags::SYN | TcpFlags::ACK {
-            Some(self.mss)
+        // Write the MSS and window scale options on SYN and SYNACK segments.
+        let is_syn = flags_after_ns.intersects(TcpFlags::SYN);
+        let (mss_option, window_scale_option) = if is_syn {
+            (Some(self.mss), self.local_wscale)
         } else {
-            None
+            (None, None)
         };
 
         let segment = TcpSegment::write_incomplete_segment(
@@ -745,8 +925,9 @@ impl Connection {
             seq.0,
             ack.0,
             flags_after_ns,
-            self.local_rwnd(),
+            self.local_rwnd(is_syn),
             mss_option,
+            window_scale_option,
             self.mss
                 .checked_sub(mss_reserved)
                 .ok_or(WriteNextError::MssRemaining)?,
@@ -777,9 +958,13 @@ impl Connection {
             ack = Wrapping(t.1);
             flags_after_ns = t.2;
         } else if !self.is_established() {
-            // We can only send SYNACKs on this branch. The ISN should be right before
+            // We can only send SYNs or SYNACKs on this branch. The ISN should be right before
             // self.first_not_sent.
-            flags_after_ns |= TcpFlags::SYN | TcpFlags::ACK;
+            flags_after_ns |= if self.is_active_open() {
+                TcpFlags::SYN
+            } else {
+                TcpFlags::SYN | TcpFlags::ACK
+            };
             seq = self.first_not_sent - Wrapping(1);
         } else {
             // If we got to this point, the connection is ESTABLISHED, and we're not sending a RST.
@@ -823,10 +1008,6 @@ impl Connection {
         payload_src: PayloadSource<R>,
         now: u64,
     ) -> Result<Option<Incomplete<TcpSegment<'a, &'a mut [u8]>>>, WriteNextError> {
-        // TODO: like receive_segment(), this function is specific in some ways to Connections
-        // created via passive open. When/if we also implement active opens, some things will
-        // have to change.
-
         if self.is_reset() {
             return Err(WriteNextError::ConnectionReset);
         }
@@ -839,6 +1020,14 @@ impl Connection {
             return Ok(Some(segment));
         }
 
+        // Actively opened connections start by sending a SYN.
+        if self.syn_pending() {
+            let segment = self.write_control_segment::<R>(buf, mss_reserved)?;
+            self.set_flags(ConnStatusFlags::SYN_SENT);
+            self.rto_start = now;
+            return Ok(Some(segment));
+        }
+
         // The first thing we have to do is reply with a SYNACK if needed.
         if self.synack_pending() {
             let segment = self.write_control_segment::<R>(buf, mss_reserved)?;
@@ -847,8 +1036,8 @@ impl Connection {
             return Ok(Some(segment));
         }
 
-        // Resend a SYNACK if the RTO expired. Otherwise, no reason to continue until the connection
-        // becomes ESTABLISHED.
+        // Resend a SYN or SYNACK if the RTO expired. Otherwise, no reason to continue until the
+        // connection becomes ESTABLISHED.
         if !self.is_established() {
             if self.rto_expired(now) {
                 // If we exceeded the maximum retransmission count, reset the connection and call
@@ -1034,6 +1223,7 @@ pub(crate) mod tests {
         remote_window_size: u16,
         pub mss: u16,
         pub mss_reserved: u16,
+        pub window_scale: Option<u8>,
         local_rwnd_size: u32,
         remote_isn: u32,
         pub rto_period: u64,
@@ -1050,6 +1240,7 @@ pub(crate) mod tests {
                 remote_window_size: 11000,
                 mss: 1100,
                 mss_reserved: 0,
+                window_scale: None,
                 local_rwnd_size: 10000,
                 remote_isn: 12_345_678,
                 rto_period: 100_000,
@@ -1078,7 +1269,11 @@ pub(crate) mod tests {
             add_mss_option: bool,
             payload: Option<(&[u8], usize)>,
         ) -> TcpSegment<'a, &'a mut [u8]> {
-            let mss_option = if add_mss_option { Some(self.mss) } else { None };
+            let (mss_option, window_scale_option) = if add_mss_option {
+                (Some(self.mss), self.window_scale)
+            } else {
+                (None, None)
+            };
             TcpSegment::write_segment(
                 buf,
                 self.src_port,
@@ -1088,6 +1283,7 @@ pub(crate) mod tests {
                 TcpFlags::empty(),
                 self.remote_window_size,
                 mss_option,
+                window_scale_option,
                 self.mss.checked_sub(self.mss_reserved).unwrap(),
                 payload,
                 None,
@@ -1777,4 +1973,221 @@ pub(crate) mod tests {
         // and we don't wait for our FIN to be ACKed.
         assert!(c.is_done());
     }
+
+    #[test]
+    fn test_window_scaling() {
+        let mut buf1 = [0u8; 100];
+        let mut buf2 = [0u8; 100];
+
+        let mut t = ConnectionTester::new();
+        t.window_scale = Some(3);
+        t.local_rwnd_size = 1_000_000;
+
+        let syn = t.write_syn(buf1.as_mut());
+        let mut c = t.passive_open(&syn).unwrap();
+        // The SYN window size is not scaled.
+        assert_eq!(
+            c.remote_rwnd_edge,
+            c.first_not_sent + Wrapping(u32::from(t.remote_window_size))
+        );
+        let conn_isn = c.first_not_sent.0.wrapping_sub(1);
+
+        // The SYNACK carries both options, and an unscaled (but clamped) window size.
+        {
+            let s = t.write_next_segment(&mut c, None).unwrap().unwrap();
+            check_control_segment(&s, 8, TcpFlags::SYN | TcpFlags::ACK);
+            assert_eq!(parse_mss_option(&s).unwrap(), t.mss);
+            // 1_000_000 >> 4 is the first value which fits in 16 bits.
+            assert_eq!(parse_window_scale_option(&s), Some(4));
+            assert_eq!(s.window_size(), u16::max_value());
+        }
+
+        // A retransmitted SYN is recognized only if it carries the same options.
+        assert!(c.is_same_syn(&syn));
+        t.window_scale = Some(2);
+        assert!(!c.is_same_syn(&t.write_syn(buf2.as_mut())));
+        t.window_scale = None;
+        assert!(!c.is_same_syn(&t.write_syn(buf2.as_mut())));
+        t.window_scale = Some(3);
+
+        // Complete the handshake. From now on, the remote window is scaled.
+        let mut ctrl = t.write_ctrl(buf2.as_mut());
+        ctrl.set_flags_after_ns(TcpFlags::ACK)
+            .set_sequence_number(t.remote_isn.wrapping_add(1))
+            .set_ack_number(conn_isn.wrapping_add(1))
+            .set_window_size(10_000);
+        t.receive_segment(&mut c, &ctrl).unwrap();
+        assert!(c.is_established());
+        assert_eq!(c.remote_rwnd_edge, c.first_not_sent + Wrapping(80_000));
+
+        // The local window is advertised using our own shift count.
+        assert_eq!(c.local_rwnd(false), (1_000_000 >> 4) as u16);
+        assert_eq!(c.local_rwnd(true), u16::max_value());
+
+        // We can send more than 64 KiB without waiting for an ACK.
+        let send_buf = vec![0u8; 80_000];
+        let payload_src = Some((send_buf.as_slice(), c.first_not_sent));
+        let mut sent = 0;
+        while let Some(s) = t.write_next_segment(&mut c, payload_src).unwrap() {
+            assert_eq!(s.window_size(), (1_000_000 >> 4) as u16);
+            sent += s.payload_len();
+        }
+        assert_eq!(sent, 80_000);
+
+        // Other endpoints which don't ask for window scaling get no window scale option.
+        let mut t = ConnectionTester::new();
+        t.local_rwnd_size = 1_000_000;
+        let mut c = t.passive_open(&t.write_syn(buf1.as_mut())).unwrap();
+        {
+            let s = t.write_next_segment(&mut c, None).unwrap().unwrap();
+            check_control_segment(&s, 4, TcpFlags::SYN | TcpFlags::ACK);
+            assert_eq!(parse_window_scale_option(&s), None);
+        }
+        assert_eq!(c.window_shifts(), (0, 0));
+    }
+
+    #[test]
+    fn test_active_open() {
+        let mut t = ConnectionTester::new();
+        let mut c = Connection::active_open(
+            t.local_rwnd_size,
+            NonZeroU16::new(t.mss).unwrap(),
+            NonZeroU64::new(t.rto_period).unwrap(),
+            NonZeroU16::new(t.rto_count_max).unwrap(),
+        );
+        let conn_isn = c.first_not_sent.0.wrapping_sub(1);
+        let mut buf = [0u8; 2000];
+
+        assert_eq!(
+            c.control_segment_or_timeout_status(),
+            NextSegmentStatus::Available
+        );
+
+        // Nothing other than a RST or a SYNACK is accepted before sending the SYN.
+        {
+            let mut s = t.write_syn(buf.as_mut());
+            s.set_flags_after_ns(TcpFlags::SYN | TcpFlags::ACK)
+                .set_ack_number(conn_isn.wrapping_add(1));
+            assert_eq!(
+                t.receive_segment(&mut c, &s).unwrap(),
+                (
+                    None,
+                    RecvStatusFlags::CONN_RESETTING | RecvStatusFlags::INVALID_ACK
+                )
+            );
+        }
+
+        let mut c = Connection::active_open(
+            t.local_rwnd_size,
+            NonZeroU16::new(t.mss).unwrap(),
+            NonZeroU64::new(t.rto_period).unwrap(),
+            NonZeroU16::new(t.rto_count_max).unwrap(),
+        );
+        let conn_isn = c.first_not_sent.0.wrapping_sub(1);
+
+        // The first segment is a SYN which carries the MSS and window scale options.
+        {
+            let s = t.write_next_segment(&mut c, None).unwrap().unwrap();
+            check_control_segment(&s, 8, TcpFlags::SYN);
+            assert_eq!(s.sequence_number(), conn_isn);
+            assert_eq!(parse_mss_option(&s).unwrap(), t.mss);
+            assert_eq!(parse_window_scale_option(&s), Some(0));
+        }
+        assert!(t.write_next_segment(&mut c, None).unwrap().is_none());
+
+        // The SYN is retransmitted when the RTO expires.
+        t.now += t.rto_period;
+        {
+            let s = t.write_next_segment(&mut c, None).unwrap().unwrap();
+            check_control_segment(&s, 8, TcpFlags::SYN);
+            assert_eq!(s.sequence_number(), conn_isn);
+        }
+
+        // Simultaneous opens are not supported, so a plain SYN is ignored.
+        {
+            let s = t.write_syn(buf.as_mut());
+            assert_eq!(
+                t.receive_segment(&mut c, &s).unwrap(),
+                (None, RecvStatusFlags::INVALID_SEGMENT)
+            );
+        }
+
+        // A RST which does not acknowledge our SYN is invalid.
+        {
+            let mut s = t.write_ctrl(buf.as_mut());
+            s.set_flags_after_ns(TcpFlags::RST | TcpFlags::ACK)
+                .set_ack_number(conn_isn);
+            assert_eq!(
+                t.receive_segment(&mut c, &s).unwrap(),
+                (None, RecvStatusFlags::INVALID_RST)
+            );
+        }
+
+        // A valid SYNACK establishes the connection, and the final ACK of the handshake follows.
+        let mss = t.mss;
+        t.mss = 1000;
+        {
+            let mut s = t.write_syn(buf.as_mut());
+            s.set_flags_after_ns(TcpFlags::SYN | TcpFlags::ACK)
+                .set_ack_number(conn_isn.wrapping_add(1));
+            assert_eq!(
+                t.receive_segment(&mut c, &s).unwrap(),
+                (None, RecvStatusFlags::empty())
+            );
+        }
+        assert!(c.is_established());
+        // We use the smaller MSS value.
+        assert_eq!(c.mss, 1000);
+        t.mss = mss;
+        // The SYNACK did not carry the window scale option, so windows are not scaled.
+        assert_eq!(c.window_shifts(), (0, 0));
+        assert_eq!(c.local_wscale, None);
+
+        {
+            let s = t.write_next_segment(&mut c, None).unwrap().unwrap();
+            check_acks(&s, t.remote_isn.wrapping_add(1), TcpFlags::empty());
+            assert_eq!(s.sequence_number(), conn_isn.wrapping_add(1));
+        }
+
+        // Data can flow in both directions afterwards.
+        let data = [1u8; 500];
+        {
+            let payload_src = Some((data.as_ref(), c.first_not_sent));
+            let s = t.write_next_segment(&mut c, payload_src).unwrap().unwrap();
+            assert_eq!(s.payload_len(), 500);
+            assert_eq!(s.sequence_number(), conn_isn.wrapping_add(1));
+        }
+        {
+            let mut s = t.write_data(buf.as_mut(), data[..100].as_ref());
+            s.set_flags_after_ns(TcpFlags::ACK)
+                .set_sequence_number(t.remote_isn.wrapping_add(1))
+                .set_ack_number(conn_isn.wrapping_add(501));
+            let mut recv_buf = [0u8; 2000];
+            assert_eq!(
+                c.receive_segment(&s, recv_buf.as_mut(), t.now).unwrap(),
+                (NonZeroUsize::new(100), RecvStatusFlags::empty())
+            );
+        }
+        assert_eq!(c.highest_ack_received, c.first_not_sent);
+
+        // Finally, a RST which acknowledges the SYN resets a connection before it's established.
+        let mut c = Connection::active_open(
+            t.local_rwnd_size,
+            NonZeroU16::new(t.mss).unwrap(),
+            NonZeroU64::new(t.rto_period).unwrap(),
+            NonZeroU16::new(t.rto_count_max).unwrap(),
+        );
+        let conn_isn = c.first_not_sent.0.wrapping_sub(1);
+        t.write_next_segment(&mut c, None).unwrap().unwrap();
+        {
+            let mut s = t.write_ctrl(buf.as_mut());
+            s.set_flags_after_ns(TcpFlags::RST | TcpFlags::ACK)
+                .set_ack_number(conn_isn.wrapping_add(1));
+            assert_eq!(
+                t.receive_segment(&mut c, &s).unwrap(),
+                (None, RecvStatusFlags::RESET_RECEIVED)
+            );
+        }
+        assert!(c.is_done());
+    }
 }
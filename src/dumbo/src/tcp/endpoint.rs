@@ -37,6 +37,11 @@ const CONNECTION_RTO_COUNT_MAX: u16 = 15;
 // since it effectively limits the size of the keys (URIs) we're willing to use.
 const RCV_BUF_MAX_SIZE: usize = 2500;
 
+// Pipelined requests are only parsed while there are fewer unacknowledged response bytes than
+// this, so a guest which keeps sending requests without reading the responses cannot make us
+// buffer an unbounded amount of data. A single response may still be larger than this value.
+const PIPELINED_RESPONSE_BUF_MAX_SIZE: usize = 65536;
+
 // Represents the local endpoint of a HTTP over TCP connection which carries GET requests
 // to the MMDS.
 pub struct Endpoint {
@@ -46,9 +51,9 @@ pub struct Endpoint {
     // Represents the next available position in the buffer.
     receive_buf_left: usize,
     // This is filled with the HTTP response bytes after we parse a request and generate the reply.
+    // Responses to pipelined requests are appended in order, and bytes are removed from the
+    // front of the buffer as they get acknowledged by the other endpoint.
     response_buf: Vec<u8>,
-    // Initial response sequence, used to track if the entire `response_buf` was sent.
-    initial_response_seq: Wrapping<u32>,
     // Represents the sequence number associated with the first byte from response_buf.
     response_seq: Wrapping<u32>,
     // The TCP connection that does all the receiving/sending work.
@@ -103,7 +108,6 @@ impl Endpoint {
             // created via passive open only, so this points to the sequence number right after
             // the SYNACK. It might stop working like that if/when the implementation changes.
             response_seq: connection.first_not_sent(),
-            initial_response_seq: connection.first_not_sent(),
             connection,
             last_segment_received_timestamp: timestamp_cycles(),
             eviction_threshold: eviction_threshold.get(),
@@ -123,10 +127,10 @@ impl Endpoint {
         )
     }
 
-    pub fn receive_segment<T: NetworkBytes, F: FnOnce(Request) -> Response>(
+    pub fn receive_segment<T: NetworkBytes, F: FnMut(Request) -> Response>(
         &mut self,
         s: &TcpSegment<T>,
-        callback: F,
+        mut callback: F,
     ) {
         if self.stop_receiving {
             return;
@@ -165,73 +169,48 @@ impl Endpoint {
             self.receive_buf_left += len.get();
         };
 
-        if !self.response_buf.is_empty()
-            && self.connection.highest_ack_received()
-                == self.initial_response_seq + Wrapping(self.response_buf.len() as u32)
-        {
-            // If we got here, then we still have some response bytes to send (which are
-            // stored in self.response_buf).
-
-            // It seems we just received the last ACK we were waiting for, so the entire
-            // response has been successfully received. Set the new response_seq and clear
-            // the response_buf.
+        // Drop the response bytes which have been acknowledged by the other endpoint. The
+        // connection never reports ACKs for bytes it did not send, so acked is at most
+        // response_buf.len().
+        let acked = (self.connection.highest_ack_received() - self.response_seq).0 as usize;
+        if acked > 0 && acked <= self.response_buf.len() {
+            self.response_buf.drain(..acked);
             self.response_seq = self.connection.highest_ack_received();
-            self.initial_response_seq = self.response_seq;
-            self.response_buf.clear();
         }
 
-        if self.response_buf.is_empty() {
-            // There's no pending response currently, so we're back to waiting for a request to be
-            // available in self.receive_buf.
-
-            // The following is some ugly but workable code that attempts to find the end of an
-            // HTTP 1.x request in receive_buf. We need to do this for now because parse_request_bytes()
-            // expects the entire request contents as parameter.
-            if self.receive_buf_left > 2 {
-                let b = self.receive_buf.as_mut();
-                for i in 0..self.receive_buf_left - 1 {
-                    // We're basically looking for a double new line, which can only appear at the
-                    // end of a valid request.
-                    if b[i] == b'\n' {
-                        let end = if b[i + 1] == b'\n' {
-                            i + 2
-                        } else if i + 3 <= self.receive_buf_left && &b[i + 1..i + 3] == b"\r\n" {
-                            i + 3
-                        } else {
-                            continue;
-                        };
-
-                        // We found a potential request, let's parse it.
-                        let response = parse_request_bytes(&b[..end], callback);
-
-                        // The unwrap is safe because a Vec will allocate more space until all the
-                        // writes succeed.
-                        response.write_all(&mut self.response_buf).unwrap();
-
-                        // Sanity check because the current logic operates under this assumption.
-                        assert!(self.response_buf.len() < u32::MAX as usize);
-
-                        // We have to remove the bytes up to end from receive_buf, by shifting the
-                        // others to the beginning of the buffer, and updating receive_buf_left.
-                        // Also, advance the rwnd edge of the inner connection.
-                        // TODO: Maximum efficiency.
-                        for j in 0..b.len() - end {
-                            b[j] = b[j + end];
-                        }
-                        self.receive_buf_left -= end;
-                        self.connection.advance_local_rwnd_edge(end as u32);
-                        break;
-                    }
-                }
-            }
+        // HTTP/1.1 connections are persistent, and the other endpoint may send several requests
+        // without waiting for the responses (pipelining). We handle every complete request found
+        // in receive_buf, and append the responses to response_buf in the same order.
+        while self.response_buf.len() < PIPELINED_RESPONSE_BUF_MAX_SIZE {
+            let end = match self.find_request_end() {
+                Some(end) => end,
+                None => break,
+            };
 
-            if self.receive_buf_left == self.receive_buf.len() {
-                // If we get here the buffer is full, but we still couldn't identify the end of a
-                // request, so we reset because we are over the maximum request size.
-                self.connection.reset();
-                self.stop_receiving = true;
-                return;
-            }
+            // We found a potential request, let's parse it.
+            let response = parse_request_bytes(&self.receive_buf[..end], &mut callback);
+
+            // The unwrap is safe because a Vec will allocate more space until all the
+            // writes succeed.
+            response.write_all(&mut self.response_buf).unwrap();
+
+            // Sanity check because the current logic operates under this assumption.
+            assert!(self.response_buf.len() < MAX_WINDOW_SIZE as usize);
+
+            // We have to remove the bytes up to end from receive_buf, by shifting the others to
+            // the beginning of the buffer, and updating receive_buf_left. Also, advance the rwnd
+            // edge of the inner connection.
+            self.receive_buf.copy_within(end..self.receive_buf_left, 0);
+            self.receive_buf_left -= end;
+            self.connection.advance_local_rwnd_edge(end as u32);
+        }
+
+        if self.receive_buf_left == self.receive_buf.len() && self.find_request_end().is_none() {
+            // If we get here the buffer is full, but we still couldn't identify the end of a
+            // request, so we reset because we are over the maximum request size.
+            self.connection.reset();
+            self.stop_receiving = true;
+            return;
         }
 
         // We close the connection after receiving a FIN, and making sure there are no more
@@ -241,17 +220,38 @@ impl Endpoint {
         }
     }
 
+    // The following is some ugly but workable code that attempts to find the end of an HTTP 1.x
+    // request in receive_buf. We need to do this for now because parse_request_bytes() expects
+    // the entire request contents as parameter. Returns the length of the first request.
+    fn find_request_end(&self) -> Option<usize> {
+        if self.receive_buf_left <= 2 {
+            return None;
+        }
+
+        let b = &self.receive_buf[..self.receive_buf_left];
+        for i in 0..b.len() - 1 {
+            // We're basically looking for a double new line, which can only appear at the
+            // end of a valid request.
+            if b[i] == b'\n' {
+                if b[i + 1] == b'\n' {
+                    return Some(i + 2);
+                } else if i + 3 <= b.len() && &b[i + 1..i + 3] == b"\r\n" {
+                    return Some(i + 3);
+                }
+            }
+        }
+        None
+    }
+
     pub fn write_next_segment<'a>(
         &mut self,
         buf: &'a mut [u8],
         mss_reserved: u16,
     ) -> Option<Incomplete<TcpSegment<'a, &'a mut [u8]>>> {
+        // We pass every unacknowledged response byte, so the connection is also able to
+        // retransmit data when needed.
         let tcp_payload_src = if !self.response_buf.is_empty() {
-            let offset = self.response_seq - self.initial_response_seq;
-            Some((
-                self.response_buf.split_at(offset.0 as usize).1,
-                self.response_seq,
-            ))
+            Some((self.response_buf.as_slice(), self.response_seq))
         } else {
             None
         };
@@ -262,10 +262,7 @@ impl Endpoint {
             tcp_payload_src,
             timestamp_cycles(),
         ) {
-            Ok(write_result) => write_result.map(|segment| {
-                self.response_seq += Wrapping(segment.inner().payload_len() as u32);
-                segment
-            }),
+            Ok(write_result) => write_result,
             Err(_) => {
                 METRICS.mmds.tx_errors.inc();
                 None
@@ -285,7 +282,8 @@ impl Endpoint {
     }
 
     pub fn next_segment_status(&self) -> NextSegmentStatus {
-        let can_send_new_data = !self.response_buf.is_empty()
+        let response_end = self.response_seq + Wrapping(self.response_buf.len() as u32);
+        let can_send_new_data = seq_after(response_end, self.connection.first_not_sent())
             && seq_after(
                 self.connection.remote_rwnd_edge(),
                 self.connection.first_not_sent(),
@@ -571,6 +569,89 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_pipelining() {
+        let mut buf1 = [0u8; 500];
+        let mut buf2 = [0u8; 500];
+        let mut write_buf = [0u8; RCV_BUF_MAX_SIZE + 100];
+
+        let t = ConnectionTester::new();
+        let syn = t.write_syn(buf1.as_mut());
+        let remote_isn = syn.sequence_number();
+        let mut e = Endpoint::new_with_defaults(&syn).unwrap();
+
+        let endpoint_isn = e
+            .write_next_segment(write_buf.as_mut(), t.mss_reserved)
+            .unwrap()
+            .inner()
+            .sequence_number();
+        let mut ctrl = t.write_ctrl(buf2.as_mut());
+        ctrl.set_flags_after_ns(TcpFlags::ACK);
+        ctrl.set_ack_number(endpoint_isn.wrapping_add(1));
+        e.receive_segment(&ctrl, mock_callback);
+        assert!(e.connection.is_established());
+
+        // Three requests carried by a single segment, followed by the beginning of a fourth one.
+        let requests = b"GET http://169.254.169.255/a HTTP/1.1\r\n\r\n\
+                         GET http://169.254.169.255/b HTTP/1.1\r\n\r\n\
+                         GET http://169.254.169.255/c HTTP/1.1\r\n\r\n\
+                         GET http://169.254.169.255/d";
+        let mut count = 0;
+        {
+            let mut data = t.write_data(write_buf.as_mut(), requests.as_ref());
+            data.set_flags_after_ns(TcpFlags::ACK);
+            data.set_sequence_number(remote_isn.wrapping_add(1));
+            data.set_ack_number(endpoint_isn.wrapping_add(1));
+            e.receive_segment(&data, |request| {
+                count += 1;
+                mock_callback(request)
+            });
+        }
+        // Every complete request has been handled, in a single call.
+        assert_eq!(count, 3);
+        assert_eq!(e.receive_buf_left, "GET http://169.254.169.255/d".len());
+
+        // The responses are sent back to back, in order.
+        let response_len = e.response_buf.len();
+        let endpoint_first_not_sent = {
+            let s = e
+                .write_next_segment(write_buf.as_mut(), t.mss_reserved)
+                .unwrap();
+            let response = from_utf8(s.inner().payload()).unwrap();
+            assert_eq!(response.matches("200").count(), 3);
+            assert_eq!(s.inner().payload_len(), response_len);
+            s.inner()
+                .sequence_number()
+                .wrapping_add(s.inner().payload_len() as u32)
+        };
+        // Nothing else to send until we get an ACK.
+        assert_eq!(e.next_segment_status(), NextSegmentStatus::Nothing);
+
+        // Acknowledge part of the responses, and finish the fourth request.
+        let remote_first_not_sent = remote_isn.wrapping_add(1 + requests.len() as u32);
+        {
+            let rest = b"\r\n\r\n";
+            let mut data = t.write_data(write_buf.as_mut(), rest.as_ref());
+            data.set_flags_after_ns(TcpFlags::ACK);
+            data.set_sequence_number(remote_first_not_sent);
+            data.set_ack_number(endpoint_first_not_sent.wrapping_sub(10));
+            e.receive_segment(&data, mock_callback);
+        }
+        // The acknowledged bytes are gone, and the fourth response got appended.
+        assert_eq!(e.receive_buf_left, 0);
+        assert_eq!(e.response_buf.len(), 10 + response_len / 3);
+        assert_eq!(e.response_seq.0, endpoint_first_not_sent.wrapping_sub(10));
+
+        // Only the response to the last request is sent.
+        {
+            let s = e
+                .write_next_segment(write_buf.as_mut(), t.mss_reserved)
+                .unwrap();
+            assert_eq!(s.inner().sequence_number(), endpoint_first_not_sent);
+            assert_eq!(s.inner().payload_len(), response_len / 3);
+        }
+    }
+
     #[test]
     fn test_parse_request_bytes_error() {
         // Test unsupported HTTP version.
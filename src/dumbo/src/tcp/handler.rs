@@ -162,17 +162,16 @@ impl TcpIPv4Handler {
         max_connections: NonZeroUsize,
         max_pending_resets: NonZeroUsize,
     ) -> Self {
-        let max_connections = max_connections.get();
-        let max_pending_resets = max_pending_resets.get();
+        // The limits may be configured by the user, so nothing is allocated upfront.
         TcpIPv4Handler {
             local_ipv4_addr,
             local_port,
-            connections: HashMap::with_capacity(max_connections),
-            max_connections,
-            active_connections: HashSet::with_capacity(max_connections),
+            connections: HashMap::new(),
+            max_connections: max_connections.get(),
+            active_connections: HashSet::new(),
             next_timeout: None,
-            rst_queue: Vec::with_capacity(max_pending_resets),
-            max_pending_resets,
+            rst_queue: Vec::new(),
+            max_pending_resets: max_pending_resets.get(),
         }
     }
 
@@ -204,7 +203,7 @@ impl TcpIPv4Handler {
     /// Contains logic for handling incoming segments.
     ///
     /// Any changes to the state of the handler are communicated through an `Ok(RecvEvent)`.
-    pub fn receive_packet<T: NetworkBytes, F: FnOnce(Request) -> Response>(
+    pub fn receive_packet<T: NetworkBytes, F: FnMut(Request) -> Response>(
         &mut self,
         packet: &IPv4Packet<T>,
         callback: F,
@@ -407,6 +406,7 @@ impl TcpIPv4Handler {
                 flags_after_ns,
                 10000,
                 None,
+                None,
                 0,
                 None,
             )
@@ -610,6 +610,7 @@ mod tests {
                 TcpFlags::empty(),
                 10000,
                 None,
+                None,
                 100,
                 None,
                 None,
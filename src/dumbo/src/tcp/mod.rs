@@ -116,6 +116,7 @@ mod tests {
             TcpFlags::empty(),
             0,
             None,
+            None,
             100,
             None,
             None,
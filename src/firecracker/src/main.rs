@@ -6,7 +6,7 @@ mod metrics;
 use std::fs::{self, File};
 use std::io;
 use std::panic;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::process;
 use std::sync::{Arc, Mutex};
 
@@ -17,7 +17,8 @@ use snapshot::Snapshot;
 use utils::arg_parser::{ArgParser, Argument};
 use utils::terminal::Terminal;
 use utils::validators::validate_instance_id;
-use vmm::seccomp_filters::{get_filters, SeccompConfig};
+use vmm::persist::check_snapshot_compatibility;
+use vmm::seccomp_filters::{get_filters, save_learned_filters, SeccompConfig};
 use vmm::signal_handler::register_signal_handlers;
 use vmm::version_map::{FC_VERSION_TO_SNAP_VERSION, VERSION_MAP};
 use vmm::vmm_config::instance_info::{InstanceInfo, VmState};
@@ -119,7 +120,7 @@ fn main_exitable() -> ExitCode {
         .arg(
             Argument::new("seccomp-filter")
                 .takes_value(true)
-                .forbids(vec!["no-seccomp"])
+                .forbids(vec!["no-seccomp", "seccomp-learn"])
                 .help(
                     "Optional parameter which allows specifying the path to a custom seccomp filter. For advanced users."
                 ),
@@ -127,10 +128,25 @@ fn main_exitable() -> ExitCode {
         .arg(
             Argument::new("no-seccomp")
                 .takes_value(false)
-                .forbids(vec!["seccomp-filter"])
+                .forbids(vec!["seccomp-filter", "seccomp-learn"])
                 .help("Optional parameter which allows starting and using a microVM without seccomp filtering. \
                     Not recommended.")
         )
+        .arg(
+            Argument::new("seccomp-learn")
+                .takes_value(true)
+                .forbids(vec!["no-seccomp", "seccomp-filter"])
+                .help("Optional parameter which allows recording the syscalls made by Firecracker instead of \
+                    filtering them. The learned filters are written to the provided path, in the seccompiler \
+                    JSON format, when Firecracker exits. Not for production use.")
+        )
+        .arg(
+            Argument::new("seccomp-notif-socket")
+                .takes_value(true)
+                .requires("seccomp-filter")
+                .help("Optional path to a SOCK_SEQPACKET unix domain socket, to which the listeners of the \
+                    custom seccomp filters using the `user_notif` action are sent. For advanced users.")
+        )
         .arg(
             Argument::new("start-time-us")
                 .takes_value(true)
@@ -201,6 +217,17 @@ fn main_exitable() -> ExitCode {
                 .takes_value(true)
                 .help("Print the data format version of the provided snapshot state file.")
         )
+        .arg(
+            Argument::new("check-snapshot")
+                .takes_value(true)
+                .help("Check whether the host supports the CPU features of the provided snapshot state file.")
+        )
+        .arg(
+            Argument::new("json-report")
+                .takes_value(false)
+                .requires("check-snapshot")
+                .help("Print the snapshot check report in JSON format.")
+        )
         .arg(
             Argument::new("http-api-max-payload-size")
                 .takes_value(true)
@@ -234,6 +261,13 @@ fn main_exitable() -> ExitCode {
                 return vmm::FC_EXIT_CODE_OK;
             }
 
+            if let Some(snapshot_path) = arg_parser.arguments().single_value("check-snapshot") {
+                return check_snapshot(
+                    snapshot_path,
+                    arg_parser.arguments().flag_present("json-report"),
+                );
+            }
+
             arg_parser.arguments()
         }
     };
@@ -286,6 +320,7 @@ fn main_exitable() -> ExitCode {
     let mut seccomp_filters: BpfThreadMap = match SeccompConfig::from_args(
         arguments.flag_present("no-seccomp"),
         arguments.single_value("seccomp-filter"),
+        arguments.single_value("seccomp-learn").is_some(),
     )
     .and_then(get_filters)
     {
@@ -295,6 +330,12 @@ fn main_exitable() -> ExitCode {
         }
     };
 
+    if let Some(path) = arguments.single_value("seccomp-notif-socket") {
+        if let Err(e) = seccompiler::connect_user_notif_socket(Path::new(path)) {
+            return generic_error_exit(&format!("Seccomp error: {}", e));
+        }
+    }
+
     let vmm_config_json = arguments
         .single_value("config-file")
         .map(fs::read_to_string)
@@ -315,7 +356,7 @@ fn main_exitable() -> ExitCode {
                 .expect("'http-api-max-payload-size' parameter expected to be of 'usize' type.")
         });
 
-    if api_enabled {
+    let exit_code = if api_enabled {
         let bind_path = arguments
             .single_value("api-sock")
             .map(PathBuf::from)
@@ -361,7 +402,16 @@ fn main_exitable() -> ExitCode {
             payload_limit,
             metadata_json.as_deref(),
         )
+    };
+
+    if let Some(path) = arguments.single_value("seccomp-learn") {
+        if let Err(e) = save_learned_filters(Path::new(path)) {
+            return generic_error_exit(&format!("Seccomp error: {}", e));
+        }
+        info!("Learned seccomp filters written to {}", path);
     }
+
+    exit_code
 }
 
 fn main() {
@@ -433,6 +483,31 @@ fn print_snapshot_data_format(snapshot_path: &str) {
     println!("v{}", key);
 }
 
+// Check whether the host supports the CPU features of the provided snapshot state file.
+fn check_snapshot(snapshot_path: &str, json_report: bool) -> ExitCode {
+    let report = match check_snapshot_compatibility(Path::new(snapshot_path), VERSION_MAP.clone()) {
+        Ok(report) => report,
+        Err(err) => {
+            return generic_error_exit(&format!("Cannot check the snapshot state file: {}", err))
+        }
+    };
+
+    if json_report {
+        println!(
+            "{}",
+            serde_json::to_string_pretty(&report).expect("Cannot serialize the report")
+        );
+    } else {
+        println!("{}", report);
+    }
+
+    if report.is_compatible() {
+        vmm::FC_EXIT_CODE_OK
+    } else {
+        vmm::FC_EXIT_CODE_GENERIC_ERROR
+    }
+}
+
 // Configure and start a microVM as described by the command-line JSON.
 fn build_microvm_from_json(
     seccomp_filters: &BpfThreadMap,
@@ -0,0 +1,254 @@
+// Copyright 2022 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+//
+// Benchmark testing
+//
+// Compare the ways a block request can be handed over to io_uring, in terms of throughput and
+// CPU time:
+//  - one read/write per data segment, against a single readv/writev
+//  - a read/write of a contiguous buffer, against a read/write of a registered buffer
+//  - a regular ring, against a ring whose submission queue is polled by a kernel thread
+//
+// The CPU time is measured for the whole process. The submission queue polling thread is only
+// accounted to the process on host kernels newer than 5.12.
+
+use std::os::unix::fs::FileExt;
+use std::time::Duration;
+
+use criterion::{criterion_group, criterion_main, Bencher, BenchmarkId, Criterion, Throughput};
+use io_uring::operation::Operation;
+use io_uring::IoUring;
+use utils::kernel_version::{min_kernel_version_for_io_uring, KernelVersion};
+use utils::tempfile::TempFile;
+use vm_memory::MmapRegion;
+
+const NUM_ENTRIES: u32 = 128;
+const REQUEST_LEN: usize = 64 << 10;
+const SEGMENTS: usize = 16;
+const SEGMENT_LEN: usize = REQUEST_LEN / SEGMENTS;
+const SQ_THREAD_IDLE_MS: u32 = 100;
+
+#[derive(Clone, Copy, Debug)]
+enum Direction {
+    Read,
+    Write,
+}
+
+#[derive(Clone, Copy, Debug)]
+enum Submission {
+    // One operation per data segment, as done for requests spanning several descriptors.
+    PerSegment,
+    // A single vectored operation for all the data segments.
+    Vectored,
+    // A single operation on a contiguous buffer.
+    Contiguous,
+    // A single operation on a contiguous, registered buffer.
+    Fixed,
+}
+
+struct Target {
+    ring: IoUring,
+    region: MmapRegion,
+    iovecs: Vec<libc::iovec>,
+    // Keep the backing file around for as long as the ring uses it.
+    _file: TempFile,
+}
+
+impl Target {
+    fn new(sq_poll: bool) -> Option<Self> {
+        let file = TempFile::new().unwrap();
+        file.as_file().write_all_at(&[0u8; REQUEST_LEN], 0).unwrap();
+
+        let ring = if sq_poll {
+            match IoUring::with_sq_poll(
+                NUM_ENTRIES,
+                vec![file.as_file()],
+                vec![],
+                None,
+                SQ_THREAD_IDLE_MS,
+            ) {
+                Ok(ring) => ring,
+                Err(e) => {
+                    println!("Skipping the submission queue polling benchmarks: {:?}", e);
+                    return None;
+                }
+            }
+        } else {
+            IoUring::new(NUM_ENTRIES, vec![file.as_file()], vec![], None).unwrap()
+        };
+
+        let region = MmapRegion::build(
+            None,
+            REQUEST_LEN,
+            libc::PROT_READ | libc::PROT_WRITE,
+            libc::MAP_ANONYMOUS | libc::MAP_PRIVATE,
+        )
+        .unwrap();
+        let iovecs = (0..SEGMENTS)
+            .map(|i| libc::iovec {
+                // Safe because the segments lie within the region.
+                iov_base: unsafe { region.as_ptr().add(i * SEGMENT_LEN) } as *mut libc::c_void,
+                iov_len: SEGMENT_LEN,
+            })
+            .collect();
+
+        let mut target = Target {
+            ring,
+            region,
+            iovecs,
+            _file: file,
+        };
+        target
+            .ring
+            .register_buffers(&[libc::iovec {
+                iov_base: target.region.as_ptr() as *mut libc::c_void,
+                iov_len: REQUEST_LEN,
+            }])
+            .unwrap();
+
+        Some(target)
+    }
+
+    fn operations(&self, direction: Direction, submission: Submission) -> Vec<Operation<u32>> {
+        let addr = self.region.as_ptr() as usize;
+        let len = REQUEST_LEN as u32;
+        match (direction, submission) {
+            (Direction::Read, Submission::PerSegment) => (0..SEGMENTS)
+                .map(|i| {
+                    let off = i * SEGMENT_LEN;
+                    Operation::read(0, addr + off, SEGMENT_LEN as u32, off as u64, 0)
+                })
+                .collect(),
+            (Direction::Write, Submission::PerSegment) => (0..SEGMENTS)
+                .map(|i| {
+                    let off = i * SEGMENT_LEN;
+                    Operation::write(0, addr + off, SEGMENT_LEN as u32, off as u64, 0)
+                })
+                .collect(),
+            (Direction::Read, Submission::Vectored) => vec![Operation::readv(
+                0,
+                self.iovecs.as_ptr() as usize,
+                SEGMENTS as u32,
+                0,
+                0,
+            )],
+            (Direction::Write, Submission::Vectored) => vec![Operation::writev(
+                0,
+                self.iovecs.as_ptr() as usize,
+                SEGMENTS as u32,
+                0,
+                0,
+            )],
+            (Direction::Read, Submission::Contiguous) => vec![Operation::read(0, addr, len, 0, 0)],
+            (Direction::Write, Submission::Contiguous) => {
+                vec![Operation::write(0, addr, len, 0, 0)]
+            }
+            (Direction::Read, Submission::Fixed) => {
+                vec![Operation::read_fixed(0, addr, len, 0, 0, 0)]
+            }
+            (Direction::Write, Submission::Fixed) => {
+                vec![Operation::write_fixed(0, addr, len, 0, 0, 0)]
+            }
+        }
+    }
+
+    fn do_request(&mut self, direction: Direction, submission: Submission) {
+        for op in self.operations(direction, submission) {
+            // Safe because all the pushed operations are popped below.
+            unsafe { self.ring.push(op) }.unwrap();
+        }
+        self.ring.submit_and_wait_all().unwrap();
+
+        let mut count = 0;
+        // Safe because we only push operations with `u32` user data.
+        while let Some(cqe) = unsafe { self.ring.pop::<u32>() }.unwrap() {
+            count += cqe.result().unwrap();
+        }
+        assert_eq!(count as usize, REQUEST_LEN);
+    }
+}
+
+fn cpu_time() -> Duration {
+    let mut ts = libc::timespec {
+        tv_sec: 0,
+        tv_nsec: 0,
+    };
+    // Safe because the timespec is valid and we check the return value.
+    assert_eq!(
+        unsafe { libc::clock_gettime(libc::CLOCK_PROCESS_CPUTIME_ID, &mut ts) },
+        0
+    );
+    Duration::new(ts.tv_sec as u64, ts.tv_nsec as u32)
+}
+
+fn bench_request(
+    b: &mut Bencher,
+    target: &mut Target,
+    direction: Direction,
+    submission: Submission,
+    cpu: bool,
+) {
+    if cpu {
+        b.iter_custom(|iters| {
+            let start = cpu_time();
+            for _ in 0..iters {
+                target.do_request(direction, submission);
+            }
+            cpu_time() - start
+        })
+    } else {
+        b.iter(|| target.do_request(direction, submission))
+    }
+}
+
+pub fn criterion_benchmark(c: &mut Criterion) {
+    if KernelVersion::get().unwrap() < min_kernel_version_for_io_uring() {
+        println!("Skipping the io_uring benchmarks: unsupported host kernel.");
+        return;
+    }
+
+    let mut target = Target::new(false).unwrap();
+    let mut sq_poll_target = Target::new(true);
+
+    for &direction in [Direction::Read, Direction::Write].iter() {
+        for &cpu in [false, true].iter() {
+            let mut group = c.benchmark_group(format!(
+                "{:?} {}",
+                direction,
+                if cpu { "CPU time" } else { "wall time" }
+            ));
+            group.throughput(Throughput::Bytes(REQUEST_LEN as u64));
+
+            for &submission in [
+                Submission::PerSegment,
+                Submission::Vectored,
+                Submission::Contiguous,
+                Submission::Fixed,
+            ]
+            .iter()
+            {
+                group.bench_function(
+                    BenchmarkId::from_parameter(format!("{:?}", submission)),
+                    |b| bench_request(b, &mut target, direction, submission, cpu),
+                );
+            }
+            if let Some(sq_poll_target) = sq_poll_target.as_mut() {
+                group.bench_function(BenchmarkId::from_parameter("SqPoll"), |b| {
+                    bench_request(b, sq_poll_target, direction, Submission::Contiguous, cpu)
+                });
+            }
+
+            group.finish();
+        }
+    }
+}
+
+criterion_group! {
+    name = benches;
+    config = Criterion::default().sample_size(10);
+    targets = criterion_benchmark
+}
+
+criterion_main! {
+    benches
+}
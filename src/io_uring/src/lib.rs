@@ -6,7 +6,8 @@
 //!
 //! Aims to provide an easy-to-use interface, while making some Firecracker-specific simplifying
 //! assumptions. The crate does not currently aim at supporting all io_uring features and use
-//! cases. For example, it only works with pre-registered fds and read/write/fsync requests.
+//! cases. For example, it only works with pre-registered fds and read/write/fsync/fallocate
+//! requests, with the vectored and registered buffers variants of reads and writes.
 //!
 //! Requires at least kernel version 5.10.51.
 //! For more information on io_uring, refer to the man pages.
@@ -36,9 +37,21 @@ use queue::submission::SubmissionQueue;
 use restriction::Restriction;
 
 // IO_uring operations that we require to be supported by the host kernel.
-const REQUIRED_OPS: [OpCode; 2] = [OpCode::Read, OpCode::Write];
+const REQUIRED_OPS: [OpCode; 7] = [
+    OpCode::Read,
+    OpCode::Write,
+    OpCode::Readv,
+    OpCode::Writev,
+    OpCode::ReadFixed,
+    OpCode::WriteFixed,
+    OpCode::Fallocate,
+];
 // Taken from linux/fs/io_uring.c
 const IORING_MAX_FIXED_FILES: usize = 1 << 15;
+// Taken from linux/fs/io_uring.c (`UIO_MAXIOV`). Newer kernels allow more buffers.
+const IORING_MAX_FIXED_BUFFERS: usize = 1 << 10;
+/// The maximum length of a registered buffer. Taken from linux/fs/io_uring.c
+pub const MAX_FIXED_BUFFER_LEN: usize = 1 << 30;
 
 type Result<T> = std::result::Result<T, Error>;
 
@@ -53,12 +66,18 @@ pub enum Error {
     FamError(utils::fam::Error),
     /// The number of ops in the ring is >= CQ::count
     FullCQueue,
+    /// Buffer was not registered.
+    InvalidFixedBuffer(u16),
     /// Fd was not registered.
     InvalidFixedFd(i32),
     /// There are no registered fds.
     NoRegisteredFds,
     /// Error probing the io_uring subsystem.
     Probe(IOError),
+    /// Attempted to register too many buffers, or a buffer larger than `MAX_FIXED_BUFFER_LEN`.
+    RegisterBufferLimitExceeded,
+    /// Could not register buffers.
+    RegisterBuffers(IOError),
     /// Could not register eventfd.
     RegisterEventfd(IOError),
     /// Could not register file.
@@ -90,6 +109,7 @@ impl Error {
 /// Main object representing an io_uring instance.
 pub struct IoUring {
     registered_fds_count: u32,
+    registered_buffers_count: u32,
     squeue: SubmissionQueue,
     cqueue: CompletionQueue,
     // Make sure the fd is declared after the queues, so that it isn't dropped before them.
@@ -118,6 +138,40 @@ impl IoUring {
         files: Vec<&File>,
         restrictions: Vec<Restriction>,
         eventfd: Option<RawFd>,
+    ) -> Result<Self> {
+        Self::setup(num_entries, files, restrictions, eventfd, None)
+    }
+
+    /// Create a new instance, whose submission queue is polled by a kernel thread.
+    ///
+    /// Submitting operations doesn't require a system call as long as the kernel thread is
+    /// awake. The thread goes to sleep after `sq_thread_idle` milliseconds without new
+    /// submissions, and it is woken up by the next `submit`.
+    /// Requires `CAP_SYS_ADMIN` on host kernels older than 5.11.
+    ///
+    /// See [`IoUring::new`](struct.IoUring.html#method.new) for the other arguments.
+    pub fn with_sq_poll(
+        num_entries: u32,
+        files: Vec<&File>,
+        restrictions: Vec<Restriction>,
+        eventfd: Option<RawFd>,
+        sq_thread_idle: u32,
+    ) -> Result<Self> {
+        Self::setup(
+            num_entries,
+            files,
+            restrictions,
+            eventfd,
+            Some(sq_thread_idle),
+        )
+    }
+
+    fn setup(
+        num_entries: u32,
+        files: Vec<&File>,
+        restrictions: Vec<Restriction>,
+        eventfd: Option<RawFd>,
+        sq_thread_idle: Option<u32>,
     ) -> Result<Self> {
         let mut params = io_uring_params {
             // Create the ring as disabled, so that we may register restrictions.
@@ -125,6 +179,10 @@ impl IoUring {
 
             ..Default::default()
         };
+        if let Some(sq_thread_idle) = sq_thread_idle {
+            params.flags |= bindings::IORING_SETUP_SQPOLL;
+            params.sq_thread_idle = sq_thread_idle;
+        }
 
         // Safe because values are valid and we check the return value.
         let fd = SyscallReturnCode(unsafe {
@@ -150,6 +208,7 @@ impl IoUring {
             cqueue,
             fd: file,
             registered_fds_count: 0,
+            registered_buffers_count: 0,
             num_ops: 0,
         };
 
@@ -182,6 +241,11 @@ impl IoUring {
                 Err((Error::InvalidFixedFd(fd), op.user_data()))
             }
             _ => {
+                if let Some(buf_index) = op.buf_index {
+                    if u32::from(buf_index) >= self.registered_buffers_count {
+                        return Err((Error::InvalidFixedBuffer(buf_index), op.user_data()));
+                    }
+                }
                 if self.num_ops >= self.cqueue.count() {
                     return Err((Error::FullCQueue, op.user_data()));
                 }
@@ -245,6 +309,37 @@ impl IoUring {
         self.num_ops
     }
 
+    /// Register `buffers` for use by the `ReadFixed` and `WriteFixed` operations, which refer to
+    /// them by their index in `buffers`.
+    ///
+    /// Buffers can only be registered once. The registration pins the memory of the buffers,
+    /// which is accounted against `RLIMIT_MEMLOCK`. If the ring is restricted, the
+    /// [`AllowBufferRegistration`](restriction/enum.Restriction.html) restriction is required.
+    pub fn register_buffers(&mut self, buffers: &[libc::iovec]) -> Result<()> {
+        if buffers.len() > IORING_MAX_FIXED_BUFFERS
+            || buffers.iter().any(|buf| buf.iov_len > MAX_FIXED_BUFFER_LEN)
+        {
+            return Err(Error::RegisterBufferLimitExceeded);
+        }
+
+        // Safe because values are valid and we check the return value.
+        SyscallReturnCode(unsafe {
+            libc::syscall(
+                libc::SYS_io_uring_register,
+                self.fd.as_raw_fd(),
+                bindings::IORING_REGISTER_BUFFERS,
+                buffers.as_ptr(),
+                buffers.len(),
+            ) as libc::c_int
+        })
+        .into_empty_result()
+        .map_err(Error::RegisterBuffers)?;
+
+        // Safe to truncate since buffers.len() <= IORING_MAX_FIXED_BUFFERS
+        self.registered_buffers_count = buffers.len() as u32;
+        Ok(())
+    }
+
     fn enable(&mut self) -> Result<()> {
         // Safe because values are valid and we check the return value.
         SyscallReturnCode(unsafe {
@@ -18,6 +18,9 @@ use crate::bindings::{self, io_uring_sqe, IOSQE_FIXED_FILE_BIT};
 /// The index of a registered fd.
 pub type FixedFd = u32;
 
+/// The index of a registered buffer.
+pub type FixedBuffer = u16;
+
 #[repr(u8)]
 #[derive(Clone, Copy)]
 #[cfg_attr(test, derive(Debug))]
@@ -29,6 +32,16 @@ pub enum OpCode {
     Write = bindings::IORING_OP_WRITE as u8,
     /// Fsync operation.
     Fsync = bindings::IORING_OP_FSYNC as u8,
+    /// Vectored read operation.
+    Readv = bindings::IORING_OP_READV as u8,
+    /// Vectored write operation.
+    Writev = bindings::IORING_OP_WRITEV as u8,
+    /// Read operation into a registered buffer.
+    ReadFixed = bindings::IORING_OP_READ_FIXED as u8,
+    /// Write operation from a registered buffer.
+    WriteFixed = bindings::IORING_OP_WRITE_FIXED as u8,
+    /// Fallocate operation.
+    Fallocate = bindings::IORING_OP_FALLOCATE as u8,
 }
 
 // Useful for outputting errors.
@@ -38,6 +51,11 @@ impl From<OpCode> for &'static str {
             OpCode::Read => "read",
             OpCode::Write => "write",
             OpCode::Fsync => "fsync",
+            OpCode::Readv => "readv",
+            OpCode::Writev => "writev",
+            OpCode::ReadFixed => "read_fixed",
+            OpCode::WriteFixed => "write_fixed",
+            OpCode::Fallocate => "fallocate",
         }
     }
 }
@@ -51,6 +69,7 @@ pub struct Operation<T> {
     pub(crate) len: Option<u32>,
     flags: u8,
     pub(crate) offset: Option<u64>,
+    pub(crate) buf_index: Option<FixedBuffer>,
     user_data: Box<T>,
 }
 
@@ -84,6 +103,7 @@ impl<T> Operation<T> {
             len: Some(len),
             flags: 0,
             offset: Some(offset),
+            buf_index: None,
             user_data: Box::new(user_data),
         }
     }
@@ -97,6 +117,7 @@ impl<T> Operation<T> {
             len: Some(len),
             flags: 0,
             offset: Some(offset),
+            buf_index: None,
             user_data: Box::new(user_data),
         }
     }
@@ -110,6 +131,102 @@ impl<T> Operation<T> {
             len: None,
             flags: 0,
             offset: None,
+            buf_index: None,
+            user_data: Box::new(user_data),
+        }
+    }
+
+    /// Construct a vectored read operation.
+    ///
+    /// `iovecs` is the address of an array of `iovecs_len` `libc::iovec`s, which must stay valid
+    /// until the operation completes.
+    pub fn readv(fd: FixedFd, iovecs: usize, iovecs_len: u32, offset: u64, user_data: T) -> Self {
+        Self {
+            fd,
+            opcode: OpCode::Readv,
+            addr: Some(iovecs),
+            len: Some(iovecs_len),
+            flags: 0,
+            offset: Some(offset),
+            buf_index: None,
+            user_data: Box::new(user_data),
+        }
+    }
+
+    /// Construct a vectored write operation.
+    ///
+    /// `iovecs` is the address of an array of `iovecs_len` `libc::iovec`s, which must stay valid
+    /// until the operation completes.
+    pub fn writev(fd: FixedFd, iovecs: usize, iovecs_len: u32, offset: u64, user_data: T) -> Self {
+        Self {
+            fd,
+            opcode: OpCode::Writev,
+            addr: Some(iovecs),
+            len: Some(iovecs_len),
+            flags: 0,
+            offset: Some(offset),
+            buf_index: None,
+            user_data: Box::new(user_data),
+        }
+    }
+
+    /// Construct a read operation into the registered buffer with index `buf_index`.
+    /// The `[addr, addr + len)` range must lie within that buffer.
+    pub fn read_fixed(
+        fd: FixedFd,
+        addr: usize,
+        len: u32,
+        offset: u64,
+        buf_index: FixedBuffer,
+        user_data: T,
+    ) -> Self {
+        Self {
+            fd,
+            opcode: OpCode::ReadFixed,
+            addr: Some(addr),
+            len: Some(len),
+            flags: 0,
+            offset: Some(offset),
+            buf_index: Some(buf_index),
+            user_data: Box::new(user_data),
+        }
+    }
+
+    /// Construct a write operation from the registered buffer with index `buf_index`.
+    /// The `[addr, addr + len)` range must lie within that buffer.
+    pub fn write_fixed(
+        fd: FixedFd,
+        addr: usize,
+        len: u32,
+        offset: u64,
+        buf_index: FixedBuffer,
+        user_data: T,
+    ) -> Self {
+        Self {
+            fd,
+            opcode: OpCode::WriteFixed,
+            addr: Some(addr),
+            len: Some(len),
+            flags: 0,
+            offset: Some(offset),
+            buf_index: Some(buf_index),
+            user_data: Box::new(user_data),
+        }
+    }
+
+    /// Construct a fallocate operation.
+    ///
+    /// `mode` takes the same flags as the `fallocate` system call, e.g. `libc::FALLOC_FL_PUNCH_HOLE`.
+    pub fn fallocate(fd: FixedFd, mode: u32, offset: u64, len: u64, user_data: T) -> Self {
+        Self {
+            fd,
+            opcode: OpCode::Fallocate,
+            // The length of the range goes in the address field of the sqe.
+            addr: Some(len as usize),
+            len: Some(mode),
+            flags: 0,
+            offset: Some(offset),
+            buf_index: None,
             user_data: Box::new(user_data),
         }
     }
@@ -154,6 +271,14 @@ impl<T> Operation<T> {
         if let Some(offset) = self.offset {
             inner.__bindgen_anon_1.off = offset;
         }
+
+        if let Some(buf_index) = self.buf_index {
+            inner
+                .__bindgen_anon_4
+                .__bindgen_anon_1
+                .__bindgen_anon_1
+                .buf_index = buf_index;
+        }
         inner.user_data = Box::into_raw(self.user_data) as u64;
 
         Sqe::new(inner)
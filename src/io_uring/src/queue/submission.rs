@@ -6,7 +6,7 @@ use std::mem;
 use std::num::Wrapping;
 use std::os::unix::io::RawFd;
 use std::result::Result;
-use std::sync::atomic::Ordering;
+use std::sync::atomic::{fence, Ordering};
 
 use utils::syscall::SyscallReturnCode;
 use vm_memory::{Bytes, MmapRegion, VolatileMemory, VolatileMemoryError};
@@ -34,6 +34,7 @@ pub(crate) struct SubmissionQueue {
     // Offsets.
     head_off: usize,
     tail_off: usize,
+    flags_off: usize,
 
     // Cached values.
     ring_mask: u32,
@@ -47,6 +48,8 @@ pub(crate) struct SubmissionQueue {
 
     // Number of ops yet to be submitted.
     to_submit: u32,
+    // Whether the queue is polled by a kernel thread.
+    sq_poll: bool,
 }
 
 impl SubmissionQueue {
@@ -76,6 +79,7 @@ impl SubmissionQueue {
             io_uring_fd,
             head_off: params.sq_off.head as usize,
             tail_off: params.sq_off.tail as usize,
+            flags_off: params.sq_off.flags as usize,
             ring_mask,
             count: params.sq_entries,
             // We can init this to 0 and cache it because we are the only ones modifying it.
@@ -83,6 +87,7 @@ impl SubmissionQueue {
             ring,
             sqes,
             to_submit: 0,
+            sq_poll: (params.flags & bindings::IORING_SETUP_SQPOLL) != 0,
         })
     }
 
@@ -137,6 +142,16 @@ impl SubmissionQueue {
         if min_complete > 0 {
             flags |= bindings::IORING_ENTER_GETEVENTS;
         }
+
+        if self.sq_poll {
+            // The kernel thread picks up the new entries on its own, unless it went to sleep.
+            if self.needs_wakeup()? {
+                flags |= bindings::IORING_ENTER_SQ_WAKEUP;
+            } else if min_complete == 0 {
+                return Ok(mem::replace(&mut self.to_submit, 0));
+            }
+        }
+
         // Safe because values are valid and we check the return value.
         let submitted = SyscallReturnCode(unsafe {
             libc::syscall(
@@ -160,6 +175,18 @@ impl SubmissionQueue {
         Ok(submitted)
     }
 
+    fn needs_wakeup(&self) -> Result<bool, Error> {
+        // Make sure the kernel thread observes the new tail before we check whether it's asleep.
+        fence(Ordering::SeqCst);
+        let flags = self
+            .ring
+            .as_volatile_slice()
+            .load::<u32>(self.flags_off, Ordering::Relaxed)
+            .map_err(Error::VolatileMemory)?;
+
+        Ok((flags & bindings::IORING_SQ_NEED_WAKEUP) != 0)
+    }
+
     fn mmap(
         io_uring_fd: RawFd,
         params: &bindings::io_uring_params,
@@ -5,6 +5,8 @@
 //!
 //! One can configure the restrictions to only allow certain operations and/or allow only ops on
 //! registered files.
+//! Once restricted, the ring rejects all the registration requests which weren't explicitly
+//! allowed.
 //! If passed to the [`IoUring`] constructor, they take effect immediately and can never be
 //! deactivated.
 //!
@@ -21,6 +23,8 @@ pub enum Restriction {
     AllowOpCode(OpCode),
     /// Only allow operations on pre-registered fds.
     RequireFixedFds,
+    /// Allow registering buffers after the ring was enabled.
+    AllowBufferRegistration,
 }
 
 impl From<&Restriction> for bindings::io_uring_restriction {
@@ -39,6 +43,10 @@ impl From<&Restriction> for bindings::io_uring_restriction {
                 instance.opcode = bindings::IORING_RESTRICTION_SQE_FLAGS_REQUIRED as u16;
                 instance.__bindgen_anon_1.sqe_flags = 1 << bindings::IOSQE_FIXED_FILE_BIT;
             }
+            AllowBufferRegistration => {
+                instance.opcode = bindings::IORING_RESTRICTION_REGISTER_OP as u16;
+                instance.__bindgen_anon_1.register_op = bindings::IORING_REGISTER_BUFFERS as u8;
+            }
         };
 
         instance
@@ -365,3 +365,267 @@ fn test_read() {
     // Verify the result.
     assert_eq!(buf, &init_contents[..]);
 }
+
+#[test]
+fn test_fallocate() {
+    skip_if_io_uring_unsupported!();
+
+    const NUM_BYTES: usize = 0x3000;
+    // Setup.
+    let file = TempFile::new().unwrap().into_file();
+    let mut ring = IoUring::new(
+        NUM_ENTRIES,
+        vec![&file],
+        vec![
+            Restriction::RequireFixedFds,
+            Restriction::AllowOpCode(OpCode::Fallocate),
+        ],
+        None,
+    )
+    .unwrap();
+    file.write_all_at(&[0xff; NUM_BYTES], 0).unwrap();
+
+    // Punch a hole in the middle of the file, which must keep its size.
+    let mode = (libc::FALLOC_FL_PUNCH_HOLE | libc::FALLOC_FL_KEEP_SIZE) as u32;
+    unsafe { ring.push(Operation::fallocate(0, mode, 0x1000, 0x1000, 0u8)) }.unwrap();
+    assert_eq!(ring.submit_and_wait_all().unwrap(), 1);
+    let cqe = unsafe { ring.pop::<u8>() }.unwrap().unwrap();
+    assert_eq!(cqe.result().unwrap(), 0);
+
+    let mut buf = [0u8; NUM_BYTES];
+    file.read_exact_at(&mut buf, 0).unwrap();
+    assert!(buf[..0x1000].iter().all(|&b| b == 0xff));
+    assert!(buf[0x1000..0x2000].iter().all(|&b| b == 0));
+    assert!(buf[0x2000..].iter().all(|&b| b == 0xff));
+    assert_eq!(file.metadata().unwrap().len(), NUM_BYTES as u64);
+
+    // Extend the file.
+    unsafe { ring.push(Operation::fallocate(0, 0, 0, 2 * NUM_BYTES as u64, 0u8)) }.unwrap();
+    assert_eq!(ring.submit_and_wait_all().unwrap(), 1);
+    let cqe = unsafe { ring.pop::<u8>() }.unwrap().unwrap();
+    assert_eq!(cqe.result().unwrap(), 0);
+    assert_eq!(file.metadata().unwrap().len(), 2 * NUM_BYTES as u64);
+}
+
+#[test]
+fn test_vectored_ops() {
+    skip_if_io_uring_unsupported!();
+
+    // Test that a single vectored op transfers data to and from multiple buffers.
+
+    const NUM_BYTES: usize = 100;
+    // Setup.
+    let file = TempFile::new().unwrap().into_file();
+    let mut ring = IoUring::new(NUM_ENTRIES, vec![&file], vec![], None).unwrap();
+
+    let contents: Vec<u8> = (0..(NUM_BYTES as u8)).collect();
+    let (head, tail) = contents.split_at(NUM_BYTES / 4);
+    let iovecs = [
+        libc::iovec {
+            iov_base: head.as_ptr() as *mut libc::c_void,
+            iov_len: head.len(),
+        },
+        libc::iovec {
+            iov_base: tail.as_ptr() as *mut libc::c_void,
+            iov_len: tail.len(),
+        },
+    ];
+    unsafe {
+        ring.push(Operation::writev(0, iovecs.as_ptr() as usize, 2, 0, 71u8))
+            .unwrap()
+    };
+    assert_eq!(ring.submit_and_wait_all().unwrap(), 1);
+    let cqe = unsafe { ring.pop::<u8>().unwrap().unwrap() };
+    assert_eq!(cqe.result().unwrap(), NUM_BYTES as u32);
+
+    let mut buf = [0u8; NUM_BYTES];
+    file.read_exact_at(&mut buf, 0).unwrap();
+    assert_eq!(buf, &contents[..]);
+
+    // Read the data back, split in a different way.
+    let mut head = [0u8; NUM_BYTES / 2];
+    let mut tail = [0u8; NUM_BYTES / 2];
+    let iovecs = [
+        libc::iovec {
+            iov_base: head.as_mut_ptr() as *mut libc::c_void,
+            iov_len: head.len(),
+        },
+        libc::iovec {
+            iov_base: tail.as_mut_ptr() as *mut libc::c_void,
+            iov_len: tail.len(),
+        },
+    ];
+    unsafe {
+        ring.push(Operation::readv(0, iovecs.as_ptr() as usize, 2, 0, 72u8))
+            .unwrap()
+    };
+    assert_eq!(ring.submit_and_wait_all().unwrap(), 1);
+    let cqe = unsafe { ring.pop::<u8>().unwrap().unwrap() };
+    assert_eq!(cqe.result().unwrap(), NUM_BYTES as u32);
+    assert_eq!(head, &contents[..NUM_BYTES / 2]);
+    assert_eq!(tail, &contents[NUM_BYTES / 2..]);
+}
+
+#[test]
+fn test_fixed_buffers() {
+    skip_if_io_uring_unsupported!();
+
+    const NUM_BYTES: usize = 100;
+    // Setup.
+    let file = TempFile::new().unwrap().into_file();
+    let mem_region: MmapRegion = MmapRegion::build(
+        None,
+        NUM_BYTES as usize,
+        libc::PROT_READ | libc::PROT_WRITE,
+        libc::MAP_ANONYMOUS | libc::MAP_PRIVATE,
+    )
+    .unwrap();
+    let addr = mem_region.as_ptr() as usize;
+    let buffers = [libc::iovec {
+        iov_base: mem_region.as_ptr() as *mut libc::c_void,
+        iov_len: NUM_BYTES,
+    }];
+
+    // Buffers must be registered before use.
+    let mut ring = IoUring::new(NUM_ENTRIES, vec![&file], vec![], None).unwrap();
+    assert!(matches!(
+        unsafe { ring.push(Operation::write_fixed(0, addr, 4, 0, 0, 71u8)) },
+        Err((Error::InvalidFixedBuffer(0), 71))
+    ));
+    assert!(matches!(
+        ring.register_buffers(&[libc::iovec {
+            iov_base: mem_region.as_ptr() as *mut libc::c_void,
+            iov_len: io_uring::MAX_FIXED_BUFFER_LEN + 1,
+        }]),
+        Err(Error::RegisterBufferLimitExceeded)
+    ));
+
+    // Restricted rings reject buffer registration, unless allowed.
+    let mut ring = IoUring::new(
+        NUM_ENTRIES,
+        vec![&file],
+        vec![Restriction::RequireFixedFds],
+        None,
+    )
+    .unwrap();
+    assert!(matches!(
+        ring.register_buffers(&buffers),
+        Err(Error::RegisterBuffers(_))
+    ));
+
+    let mut ring = IoUring::new(
+        NUM_ENTRIES,
+        vec![&file],
+        vec![
+            Restriction::RequireFixedFds,
+            Restriction::AllowBufferRegistration,
+            Restriction::AllowOpCode(OpCode::ReadFixed),
+            Restriction::AllowOpCode(OpCode::WriteFixed),
+        ],
+        None,
+    )
+    .unwrap();
+    ring.register_buffers(&buffers).unwrap();
+    assert!(matches!(
+        unsafe { ring.push(Operation::write_fixed(0, addr, 4, 0, 1, 71u8)) },
+        Err((Error::InvalidFixedBuffer(1), 71))
+    ));
+
+    // Write the sorted values 1-100 from the registered buffer.
+    let contents: Vec<u8> = (0..(NUM_BYTES as u8)).collect();
+    mem_region
+        .as_volatile_slice()
+        .write_slice(&contents, 0)
+        .unwrap();
+    unsafe {
+        ring.push(Operation::write_fixed(
+            0,
+            addr,
+            NUM_BYTES as u32,
+            0,
+            0,
+            71u8,
+        ))
+        .unwrap()
+    };
+    assert_eq!(ring.submit_and_wait_all().unwrap(), 1);
+    let cqe = unsafe { ring.pop::<u8>().unwrap().unwrap() };
+    assert_eq!(cqe.result().unwrap(), NUM_BYTES as u32);
+    let mut buf = [0u8; NUM_BYTES];
+    file.read_exact_at(&mut buf, 0).unwrap();
+    assert_eq!(buf, &contents[..]);
+
+    // Read the second half of the file back, into the start of the registered buffer.
+    mem_region
+        .as_volatile_slice()
+        .write_slice(&[0u8; NUM_BYTES], 0)
+        .unwrap();
+    unsafe {
+        ring.push(Operation::read_fixed(
+            0,
+            addr,
+            (NUM_BYTES / 2) as u32,
+            (NUM_BYTES / 2) as u64,
+            0,
+            72u8,
+        ))
+        .unwrap()
+    };
+    assert_eq!(ring.submit_and_wait_all().unwrap(), 1);
+    let cqe = unsafe { ring.pop::<u8>().unwrap().unwrap() };
+    assert_eq!(cqe.result().unwrap(), (NUM_BYTES / 2) as u32);
+    let mut buf = [0u8; NUM_BYTES / 2];
+    mem_region
+        .as_volatile_slice()
+        .read_slice(&mut buf, 0)
+        .unwrap();
+    assert_eq!(buf, &contents[NUM_BYTES / 2..]);
+}
+
+#[test]
+fn test_sq_poll() {
+    skip_if_io_uring_unsupported!();
+
+    const NUM_BYTES: usize = 100;
+    let file = TempFile::new().unwrap().into_file();
+    let mut ring = match IoUring::with_sq_poll(NUM_ENTRIES, vec![&file], vec![], None, 10) {
+        Ok(ring) => ring,
+        // Older host kernels only allow privileged processes to poll the submission queue.
+        Err(Error::Setup(e)) if e.kind() == std::io::ErrorKind::PermissionDenied => return,
+        Err(e) => panic!("Unexpected error: {:?}", e),
+    };
+
+    let contents: Vec<u8> = (0..(NUM_BYTES as u8)).collect();
+    unsafe {
+        ring.push(Operation::write(
+            0,
+            contents.as_ptr() as usize,
+            NUM_BYTES as u32,
+            0,
+            71u8,
+        ))
+        .unwrap()
+    };
+    assert_eq!(ring.submit_and_wait_all().unwrap(), 1);
+    let cqe = unsafe { ring.pop::<u8>().unwrap().unwrap() };
+    assert_eq!(cqe.result().unwrap(), NUM_BYTES as u32);
+
+    // Let the kernel thread go to sleep, then make sure it's woken up by new submissions.
+    thread::sleep(Duration::from_millis(50));
+    let mut buf = [0u8; NUM_BYTES];
+    unsafe {
+        ring.push(Operation::read(
+            0,
+            buf.as_mut_ptr() as usize,
+            NUM_BYTES as u32,
+            0,
+            72u8,
+        ))
+        .unwrap()
+    };
+    assert_eq!(ring.submit().unwrap(), 1);
+    assert_eq!(ring.submit_and_wait_all().unwrap(), 0);
+    let cqe = unsafe { ring.pop::<u8>().unwrap().unwrap() };
+    assert_eq!(cqe.result().unwrap(), NUM_BYTES as u32);
+    assert_eq!(buf, &contents[..]);
+}
@@ -4,13 +4,16 @@
 use std::collections::hash_map::Entry::{Occupied, Vacant};
 use std::collections::HashMap;
 use std::fs::{self, File};
-use std::io::{BufRead, BufReader};
+use std::io::{self, BufRead, BufReader};
 use std::path::{Path, PathBuf};
 use std::process;
 
 use regex::Regex;
 
-use crate::{readln_special, writeln_special, Error, Result};
+use crate::cgroup_profile::CpuPinning;
+use crate::mounts::bind_mount;
+use crate::{readln_special, to_cstring, writeln_special, Error, Result};
+use utils::syscall::SyscallReturnCode;
 
 const PROC_MOUNTS: &str = if cfg!(test) {
     "/tmp/firecracker/test/jailer/proc/mounts"
@@ -18,6 +21,20 @@ const PROC_MOUNTS: &str = if cfg!(test) {
     "/proc/mounts"
 };
 
+const PROC_SELF_CGROUP: &str = if cfg!(test) {
+    "/tmp/firecracker/test/jailer/proc/self_cgroup"
+} else {
+    "/proc/self/cgroup"
+};
+
+// Names of the threaded cgroups created for the VMM thread and for each of the vCPU threads.
+const VMM_THREAD_CGROUP: &str = "vmm";
+const VCPU_THREAD_CGROUP_PREFIX: &str = "vcpu";
+
+// Path inside the jail where the cgroup of the microVM is mounted, when its threads are pinned.
+// Firecracker moves each vCPU thread to <THREAD_CGROUPS_JAIL_DIR>/vcpu<index> when it exists.
+const THREAD_CGROUPS_JAIL_DIR: &str = "sys/fs/cgroup";
+
 // Holds information on a cgroup mount point discovered on the system
 struct CgroupMountPoint {
     dir: String,
@@ -99,6 +116,35 @@ impl CgroupBuilder {
         }
     }
 
+    // Creates a cgroupsv2 builder rooted at the cgroup the jailer currently belongs to, instead
+    // of the root of the unified hierarchy. This cgroup must be delegated to the user running the
+    // jailer (e.g with systemd's Delegate=yes), which is what allows unprivileged users to create
+    // cgroups.
+    pub fn new_delegated() -> Result<Self> {
+        let mut b = CgroupBuilder::new(2)?;
+
+        // With cgroupsv2, /proc/self/cgroup contains a single line which looks like this:
+        // 0::/user.slice/user-1000.slice/user@1000.service/app.slice/jailer.scope
+        let f = File::open(PROC_SELF_CGROUP)
+            .map_err(|e| Error::FileOpen(PathBuf::from(PROC_SELF_CGROUP), e))?;
+        let mut delegated_root = None;
+        for l in BufReader::new(f).lines() {
+            let l = l.map_err(|e| Error::ReadLine(PathBuf::from(PROC_SELF_CGROUP), e))?;
+            if let Some(path) = l.strip_prefix("0::") {
+                delegated_root = Some(path.trim_start_matches('/').to_string());
+                break;
+            }
+        }
+        let delegated_root = delegated_root.ok_or_else(|| {
+            Error::CgroupLineNotFound(PROC_SELF_CGROUP.to_string(), "unified".to_string())
+        })?;
+
+        // Ok to unwrap since the builder was created for cgroupsv2.
+        let unified = b.hierarchies.get_mut("unified").unwrap();
+        unified.push(delegated_root);
+        Ok(b)
+    }
+
     // Creates a new cggroup and returns it
     pub fn new_cgroup(
         &mut self,
@@ -130,6 +176,39 @@ impl CgroupBuilder {
         }
     }
 
+    // Creates the threaded cgroups used to pin the threads of the microVM, inside its cgroupsv2
+    // cgroup.
+    pub fn new_thread_cgroups(
+        &self,
+        pinning: &CpuPinning,
+        id: &str,
+        parent_cg: &Path,
+    ) -> Result<ThreadCgroups> {
+        if self.version != 2 {
+            return Err(Error::CgroupInvalidVersion(self.version.to_string()));
+        }
+        let root = self
+            .hierarchies
+            .get("unified")
+            .ok_or_else(|| Error::CgroupHierarchyMissing("unified".to_string()))?;
+        if !CgroupV2::controller_available("cpuset", root) {
+            return Err(Error::CgroupControllerUnavailable("cpuset".to_string()));
+        }
+
+        let mut cpusets = vec![(VMM_THREAD_CGROUP.to_string(), pinning.vmm.clone())];
+        cpusets.extend(pinning.vcpus.iter().enumerate().map(|(index, cpus)| {
+            (
+                format!("{}{}", VCPU_THREAD_CGROUP_PREFIX, index),
+                cpus.clone(),
+            )
+        }));
+        Ok(ThreadCgroups {
+            location: root.join(parent_cg).join(id),
+            root: root.clone(),
+            cpusets,
+        })
+    }
+
     // Returns the path to the root of the hierarchy for the controller specified
     // Cgroups for a controller are arranged in a hierarchy; multiple controllers
     // may share the same hierarchy
@@ -165,11 +244,28 @@ struct CgroupBase {
     location: PathBuf, // microVM cgroup location for the specific controller.
 }
 
+impl CgroupBase {
+    // A cgroup folder can be removed with rmdir() once no process belongs to it, even though it
+    // contains the controller files. The folder may be shared with other cgroups, in which case
+    // it is only removed once.
+    fn remove(&self) -> Result<()> {
+        match fs::remove_dir(&self.location) {
+            Err(err) if err.kind() != io::ErrorKind::NotFound => {
+                Err(Error::RemoveDir(self.location.clone(), err))
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
 pub struct CgroupV1 {
     base: CgroupBase,
     cg_parent_depth: u16, // depth of the nested cgroup hierarchy
 }
-pub struct CgroupV2(CgroupBase);
+pub struct CgroupV2 {
+    base: CgroupBase,
+    root: PathBuf, // root of the hierarchy the jailer is allowed to modify.
+}
 
 pub trait Cgroup {
     // Write the cgroup value into the cgroup property file.
@@ -177,6 +273,9 @@ pub trait Cgroup {
 
     // This function will assign the process associated with the pid to the respective cgroup.
     fn attach_pid(&self) -> Result<()>;
+
+    // Remove the cgroup folder, once all the processes assigned to it have exited.
+    fn remove(&self) -> Result<()>;
 }
 
 // If we call inherit_from_parent_aux(.../A/B/C, file, condition), the following will happen:
@@ -316,14 +415,18 @@ impl Cgroup for CgroupV1 {
 
         Ok(())
     }
+
+    fn remove(&self) -> Result<()> {
+        self.base.remove()
+    }
 }
 
 impl CgroupV2 {
-    // Enables the specified controller along the cgroup nested path.
+    // Enables the specified controller along the cgroup nested path, up to the root.
     // To be able to use a leaf controller within a nested cgroup hierarchy,
     // the controller needs to be enabled by writing to the cgroup.subtree_control
     // of it's parent. This rule applies recursively.
-    fn write_all_subtree_control<P>(path: P, controller: &str) -> Result<()>
+    fn write_all_subtree_control<P>(path: P, root: &Path, controller: &str) -> Result<()>
     where
         P: AsRef<Path>,
     {
@@ -332,14 +435,14 @@ impl CgroupV2 {
             return Ok(());
         }
         let parent = match path.as_ref().parent() {
-            Some(p) => p,
-            None => {
+            Some(p) if path.as_ref() != root => p,
+            _ => {
                 writeln_special(&cg_subtree_ctrl, format!("+{}", &controller))?;
                 return Ok(());
             }
         };
 
-        Self::write_all_subtree_control(&parent, &controller)?;
+        Self::write_all_subtree_control(&parent, root, &controller)?;
         writeln_special(&cg_subtree_ctrl, format!("+{}", &controller))
     }
 
@@ -378,11 +481,14 @@ impl CgroupV2 {
         if CgroupV2::controller_available(controller, unified_path) {
             path.push(parent_cg);
             path.push(id);
-            Ok(CgroupV2(CgroupBase {
-                file,
-                value,
-                location: path,
-            }))
+            Ok(CgroupV2 {
+                base: CgroupBase {
+                    file,
+                    value,
+                    location: path,
+                },
+                root: unified_path.to_path_buf(),
+            })
         } else {
             Err(Error::CgroupControllerUnavailable(controller.to_string()))
         }
@@ -391,32 +497,126 @@ impl CgroupV2 {
 
 impl Cgroup for CgroupV2 {
     fn write_value(&self) -> Result<()> {
-        let location = &mut self.0.location.clone();
-        let controller = get_controller_from_filename(&self.0.file)?;
+        let location = &mut self.base.location.clone();
+        let controller = get_controller_from_filename(&self.base.file)?;
 
         // Create the cgroup directory for the controller.
-        fs::create_dir_all(&self.0.location)
-            .map_err(|e| Error::CreateDir(self.0.location.clone(), e))?;
+        fs::create_dir_all(&self.base.location)
+            .map_err(|e| Error::CreateDir(self.base.location.clone(), e))?;
 
         // Ok to unwrap since the path was just created.
         let parent = location.parent().unwrap();
         // Enable the controller in all parent directories
-        CgroupV2::write_all_subtree_control(&parent, &controller)?;
+        CgroupV2::write_all_subtree_control(&parent, &self.root, &controller)?;
 
-        location.push(&self.0.file);
-        writeln_special(location, &self.0.value)?;
+        location.push(&self.base.file);
+        writeln_special(location, &self.base.value)?;
 
         Ok(())
     }
 
     fn attach_pid(&self) -> Result<()> {
         let pid = process::id();
-        let location = &self.0.location.join("cgroup.procs");
+        // With delegated cgroups, the pid is attached before any value is written.
+        fs::create_dir_all(&self.base.location)
+            .map_err(|e| Error::CreateDir(self.base.location.clone(), e))?;
+        let location = &self.base.location.join("cgroup.procs");
 
         writeln_special(location, pid)?;
 
         Ok(())
     }
+
+    fn remove(&self) -> Result<()> {
+        self.base.remove()
+    }
+}
+
+// Threaded cgroupsv2 subtree of the microVM cgroup, which places the VMM thread and each of the
+// vCPU threads in their own cpuset. Only threaded controllers can be enabled in the subtree, and
+// threads can only be moved inside of it, which is done by Firecracker for the vCPU threads.
+pub struct ThreadCgroups {
+    location: PathBuf,              // microVM cgroup, root of the threaded subtree.
+    root: PathBuf,                  // root of the hierarchy the jailer is allowed to modify.
+    cpusets: Vec<(String, String)>, // threaded cgroups, with the cpus assigned to them.
+}
+
+impl ThreadCgroups {
+    // Assigns the current process to the microVM cgroup. Its threads can then be moved to the
+    // threaded cgroups.
+    pub fn attach_pid(&self) -> Result<()> {
+        fs::create_dir_all(&self.location)
+            .map_err(|e| Error::CreateDir(self.location.clone(), e))?;
+        writeln_special(&self.location.join("cgroup.procs"), process::id())
+    }
+
+    // Creates the threaded cgroups and sets their cpus.
+    pub fn write_values(&self) -> Result<()> {
+        // The threaded cgroups must exist before enabling the cpuset controller in the microVM
+        // cgroup, which may already contain processes.
+        for (name, _) in &self.cpusets {
+            let location = self.location.join(name);
+            fs::create_dir_all(&location).map_err(|e| Error::CreateDir(location.clone(), e))?;
+            writeln_special(&location.join("cgroup.type"), "threaded")?;
+        }
+
+        CgroupV2::write_all_subtree_control(&self.location, &self.root, "cpuset")?;
+
+        for (name, cpus) in &self.cpusets {
+            writeln_special(&self.location.join(name).join("cpuset.cpus"), cpus)?;
+        }
+        Ok(())
+    }
+
+    // Moves the current thread, which execs into Firecracker and runs the VMM, to its threaded
+    // cgroup. The threads it creates afterwards start in the same cgroup.
+    pub fn attach_vmm_thread(&self) -> Result<()> {
+        // Safe because this call cannot fail.
+        let tid = unsafe { libc::syscall(libc::SYS_gettid) };
+        writeln_special(
+            &self.location.join(VMM_THREAD_CGROUP).join("cgroup.threads"),
+            tid,
+        )
+    }
+
+    // Mounts the microVM cgroup inside `chroot_dir`, so that Firecracker can move each vCPU
+    // thread to its cgroup. Moving a thread requires write access to the cgroup.threads file of
+    // the destination, and to the cgroup.procs file of the microVM cgroup, which are the only
+    // files owned by `uid` and `gid`. Must be called from a private mount namespace.
+    pub fn mount(&self, chroot_dir: &Path, uid: u32, gid: u32) -> Result<()> {
+        let files = std::iter::once(self.location.join("cgroup.procs")).chain(
+            self.cpusets
+                .iter()
+                .filter(|(name, _)| name.starts_with(VCPU_THREAD_CGROUP_PREFIX))
+                .map(|(name, _)| self.location.join(name).join("cgroup.threads")),
+        );
+        for file in files {
+            let file_cstr = to_cstring(&file)?;
+            // Safe because we provide valid parameters.
+            SyscallReturnCode(unsafe { libc::chown(file_cstr.as_ptr(), uid, gid) })
+                .into_empty_result()
+                .map_err(|e| Error::ChangeFileOwner(file.clone(), e))?;
+        }
+
+        let target = chroot_dir.join(THREAD_CGROUPS_JAIL_DIR);
+        fs::create_dir_all(&target).map_err(|e| Error::CreateDir(target.clone(), e))?;
+        bind_mount(&self.location, &target, false)
+    }
+
+    // Removes the threaded cgroups, then the microVM cgroup, once all the processes assigned to
+    // them have exited.
+    pub fn remove(&self) -> Result<()> {
+        self.cpusets
+            .iter()
+            .map(|(name, _)| self.location.join(name))
+            .chain(std::iter::once(self.location.clone()))
+            .try_for_each(|location| match fs::remove_dir(&location) {
+                Err(err) if err.kind() != io::ErrorKind::NotFound => {
+                    Err(Error::RemoveDir(location, err))
+                }
+                _ => Ok(()),
+            })
+    }
 }
 
 #[cfg(test)]
@@ -425,7 +625,7 @@ pub mod test_util {
     use std::io::Write;
     use std::path::{Path, PathBuf};
 
-    use super::PROC_MOUNTS;
+    use super::{PROC_MOUNTS, PROC_SELF_CGROUP};
 
     pub struct MockCgroupFs {
         mounts_file: File,
@@ -487,6 +687,27 @@ pub mod test_util {
             Ok(())
         }
 
+        // Populate the mocked proc/self/cgroup file, placing the current process in the
+        // `delegated_root` cgroupv2 subtree, and create the corresponding directory
+        pub fn add_v2_delegation(
+            &mut self,
+            delegated_root: &str,
+        ) -> std::result::Result<(), std::io::Error> {
+            Self::create_file_with_contents(PROC_SELF_CGROUP, &format!("0::/{}", delegated_root))?;
+            let cg_delegated_path = PathBuf::from(format!(
+                "{}/unified/{}",
+                Self::MOCK_SYS_CGROUPS_DIR,
+                delegated_root
+            ));
+            let _ = fs::create_dir_all(&cg_delegated_path)?;
+            Self::create_file_with_contents(
+                cg_delegated_path.join("cgroup.controllers"),
+                "cpu memory pids",
+            )?;
+            Self::create_file_with_contents(cg_delegated_path.join("cgroup.subtree_control"), "")?;
+            Ok(())
+        }
+
         // Populate the mocked proc/mounts file with cgroupv1 entries
         pub fn add_v1_mounts(&mut self) -> std::result::Result<(), std::io::Error> {
             let controllers = vec![
@@ -514,6 +735,7 @@ pub mod test_util {
     impl Drop for MockCgroupFs {
         fn drop(&mut self) {
             let _ = fs::remove_file(PROC_MOUNTS);
+            let _ = fs::remove_file(PROC_SELF_CGROUP);
             let _ = fs::remove_dir_all("/tmp/firecracker/test");
         }
     }
@@ -675,6 +897,77 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_cgroup_builder_delegated() {
+        let mut mock_cgroups = MockCgroupFs::new().unwrap();
+        assert!(!mock_cgroups.add_v2_mounts().is_err());
+
+        // The current cgroup is missing.
+        assert!(matches!(
+            CgroupBuilder::new_delegated(),
+            Err(Error::FileOpen(_, _))
+        ));
+
+        assert!(!mock_cgroups
+            .add_v2_delegation("user.slice/jailer.scope")
+            .is_err());
+        let mut builder = CgroupBuilder::new_delegated().unwrap();
+
+        // Only the controllers delegated to the current cgroup are available.
+        assert!(builder
+            .new_cgroup(
+                "cpuset.mems".to_string(),
+                "1".to_string(),
+                "101",
+                Path::new("fc_test_cgv2"),
+            )
+            .is_err());
+        let cg = builder
+            .new_cgroup(
+                "cpu.max".to_string(),
+                "max".to_string(),
+                "101",
+                Path::new("fc_test_cgv2"),
+            )
+            .unwrap();
+
+        let cg_root = PathBuf::from(format!("{}/unified", MockCgroupFs::MOCK_SYS_CGROUPS_DIR));
+        let cg_delegated_root = cg_root.join("user.slice/jailer.scope");
+        fs::create_dir_all(cg_delegated_root.join("fc_test_cgv2/101")).unwrap();
+        MockCgroupFs::create_file_with_contents(
+            cg_delegated_root.join("fc_test_cgv2/cgroup.subtree_control"),
+            "",
+        )
+        .unwrap();
+
+        // The pid can be attached before writing the values.
+        assert!(!cg.attach_pid().is_err());
+        assert_eq!(
+            read_first_line(cg_delegated_root.join("fc_test_cgv2/101/cgroup.procs")).unwrap(),
+            format!("{}\n", process::id())
+        );
+        assert!(!cg.write_value().is_err());
+        assert_eq!(
+            read_first_line(cg_delegated_root.join("fc_test_cgv2/101/cpu.max")).unwrap(),
+            "max\n"
+        );
+
+        // The controller is enabled up to the delegated root, but not above it.
+        assert!(
+            read_first_line(cg_delegated_root.join("cgroup.subtree_control"))
+                .unwrap()
+                .contains("cpu")
+        );
+        assert!(
+            read_first_line(cg_delegated_root.join("fc_test_cgv2/cgroup.subtree_control"))
+                .unwrap()
+                .contains("cpu")
+        );
+        assert!(!read_first_line(cg_root.join("cgroup.subtree_control"))
+            .unwrap()
+            .contains("cpu"));
+    }
+
     #[test]
     fn test_inherit_from_parent() {
         // 1. If parent file does not exist, return an error.
@@ -738,4 +1031,83 @@ mod tests {
         assert!(result.is_err());
         assert!(format!("{:?}", result).contains("CgroupInvalidFile"));
     }
+
+    #[test]
+    fn test_thread_cgroups() {
+        let mut mock_cgroups = MockCgroupFs::new().unwrap();
+        assert!(!mock_cgroups.add_v1_mounts().is_err());
+        assert!(!mock_cgroups.add_v2_mounts().is_err());
+
+        let pinning = CpuPinning {
+            vmm: "0".to_string(),
+            vcpus: vec!["1-2".to_string(), "3".to_string()],
+        };
+        let builder = CgroupBuilder::new(1).unwrap();
+        assert!(matches!(
+            builder.new_thread_cgroups(&pinning, "101", Path::new("fc_test_cg")),
+            Err(Error::CgroupInvalidVersion(_))
+        ));
+
+        let builder = CgroupBuilder::new(2).unwrap();
+        let thread_cgroups = builder
+            .new_thread_cgroups(&pinning, "101", Path::new("fc_test_cg"))
+            .unwrap();
+
+        // with real cgroups these files are created automatically
+        // since the mock will not do it automatically, we create it here
+        let cg_root = PathBuf::from(format!("{}/unified", MockCgroupFs::MOCK_SYS_CGROUPS_DIR));
+        let location = cg_root.join("fc_test_cg/101");
+        fs::create_dir_all(&location).unwrap();
+        for dir in &[cg_root.join("fc_test_cg"), location.clone()] {
+            MockCgroupFs::create_file_with_contents(dir.join("cgroup.subtree_control"), "")
+                .unwrap();
+        }
+
+        thread_cgroups.attach_pid().unwrap();
+        assert_eq!(
+            read_first_line(location.join("cgroup.procs")).unwrap(),
+            format!("{}\n", process::id())
+        );
+
+        thread_cgroups.write_values().unwrap();
+        for (name, cpus) in &[("vmm", "0"), ("vcpu0", "1-2"), ("vcpu1", "3")] {
+            assert_eq!(
+                read_first_line(location.join(name).join("cgroup.type")).unwrap(),
+                "threaded\n"
+            );
+            assert_eq!(
+                read_first_line(location.join(name).join("cpuset.cpus")).unwrap(),
+                format!("{}\n", cpus)
+            );
+        }
+        // The cpuset controller is enabled in the microVM cgroup and all its parents.
+        for dir in &[
+            cg_root.clone(),
+            cg_root.join("fc_test_cg"),
+            location.clone(),
+        ] {
+            assert!(read_first_line(dir.join("cgroup.subtree_control"))
+                .unwrap()
+                .contains("cpuset"));
+        }
+
+        thread_cgroups.attach_vmm_thread().unwrap();
+        let tid = unsafe { libc::syscall(libc::SYS_gettid) };
+        assert_eq!(
+            read_first_line(location.join("vmm/cgroup.threads")).unwrap(),
+            format!("{}\n", tid)
+        );
+
+        // With real cgroups, the folders can be removed as long as no thread belongs to them.
+        // The mock ones must be empty instead.
+        fs::remove_dir_all(&location).unwrap();
+        for name in &["vmm", "vcpu0", "vcpu1"] {
+            fs::create_dir_all(location.join(name)).unwrap();
+        }
+        thread_cgroups.remove().unwrap();
+        assert!(!location.exists());
+        assert!(cg_root.join("fc_test_cg").exists());
+        // Removing cgroups that are already gone is fine.
+        thread_cgroups.remove().unwrap();
+    }
 }
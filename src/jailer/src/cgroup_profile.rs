@@ -0,0 +1,374 @@
+// Copyright 2022 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+use std::collections::HashSet;
+use std::fs;
+use std::ops::RangeInclusive;
+use std::path::Path;
+
+use serde::Deserialize;
+
+use crate::{Error, Result};
+
+// Bounds enforced by the kernel on the values of cpu.max and cpu.weight.
+const CPU_QUOTA_US_MIN: u64 = 1_000;
+const CPU_PERIOD_US_RANGE: RangeInclusive<u64> = 1_000..=1_000_000;
+const CPU_WEIGHT_RANGE: RangeInclusive<u64> = 1..=10_000;
+// Period used by the kernel when only a quota is written to cpu.max.
+const DEFAULT_CPU_PERIOD_US: u64 = 100_000;
+
+#[derive(Debug, Default, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct CpuLimits {
+    // Run time allowed in each period, in microseconds (cpu.max).
+    quota_us: Option<u64>,
+    // Length of the period, in microseconds (cpu.max).
+    period_us: Option<u64>,
+    // Relative share of CPU time (cpu.weight).
+    weight: Option<u64>,
+}
+
+#[derive(Debug, Default, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct MemoryLimits {
+    // Hard limit in bytes (memory.max).
+    max: Option<u64>,
+    // Throttling limit in bytes (memory.high).
+    high: Option<u64>,
+    // Swap limit in bytes (memory.swap.max).
+    swap_max: Option<u64>,
+}
+
+#[derive(Debug, Default, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct IoLimits {
+    // Block device the limits apply to, as <major>:<minor>.
+    device: String,
+    // Bytes and IO operations per second, for reads and writes (io.max).
+    rbps: Option<u64>,
+    wbps: Option<u64>,
+    riops: Option<u64>,
+    wiops: Option<u64>,
+}
+
+#[derive(Debug, Default, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct PidsLimits {
+    // Maximum number of tasks (pids.max).
+    max: u64,
+}
+
+// CPUs the VMM thread and each of the vCPU threads are pinned to, as cpuset lists (e.g 0-3,6).
+#[derive(Debug, Default, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct CpuPinning {
+    pub vmm: String,
+    pub vcpus: Vec<String>,
+}
+
+// Resources of the microVM cgroup, as specified by the JSON file passed with
+// `--cgroup-profile`.
+#[derive(Debug, Default, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct CgroupProfile {
+    cpu: Option<CpuLimits>,
+    memory: Option<MemoryLimits>,
+    #[serde(default)]
+    io: Vec<IoLimits>,
+    pids: Option<PidsLimits>,
+    cpu_pinning: Option<CpuPinning>,
+}
+
+// Checks that `cpus` is a cpuset list, i.e a comma separated list of CPUs and CPU ranges.
+fn validate_cpu_list(cpus: &str) -> bool {
+    cpus.split(',').all(|range| {
+        let mut bounds = range.splitn(2, '-').map(|cpu| cpu.parse::<u32>().ok());
+        match (bounds.next().flatten(), bounds.next()) {
+            (Some(_), None) => true,
+            (Some(first), Some(Some(last))) => first <= last,
+            _ => false,
+        }
+    })
+}
+
+// Checks that `device` is a block device number, in the <major>:<minor> format.
+fn validate_device(device: &str) -> bool {
+    match device.split_once(':') {
+        Some((major, minor)) => major.parse::<u32>().is_ok() && minor.parse::<u32>().is_ok(),
+        None => false,
+    }
+}
+
+impl CgroupProfile {
+    pub fn from_file(path: &Path) -> Result<Self> {
+        let contents =
+            fs::read_to_string(path).map_err(|e| Error::ReadToString(path.to_path_buf(), e))?;
+        let profile: CgroupProfile = serde_json::from_str(&contents)
+            .map_err(|e| Error::CgroupProfileFormat(path.to_path_buf(), e))?;
+        profile.validate()?;
+        Ok(profile)
+    }
+
+    // Checks the values against the bounds enforced by the kernel, so that invalid profiles are
+    // rejected before any cgroup is written.
+    fn validate(&self) -> Result<()> {
+        let invalid = |msg: String| Err(Error::CgroupProfileInvalid(msg));
+
+        if let Some(ref cpu) = self.cpu {
+            if cpu.quota_us.map_or(false, |quota| quota < CPU_QUOTA_US_MIN) {
+                return invalid(format!(
+                    "cpu.quota_us must be at least {}",
+                    CPU_QUOTA_US_MIN
+                ));
+            }
+            if let Some(period) = cpu.period_us {
+                if !CPU_PERIOD_US_RANGE.contains(&period) {
+                    return invalid(format!(
+                        "cpu.period_us must be between {} and {}",
+                        CPU_PERIOD_US_RANGE.start(),
+                        CPU_PERIOD_US_RANGE.end()
+                    ));
+                }
+            }
+            if let Some(weight) = cpu.weight {
+                if !CPU_WEIGHT_RANGE.contains(&weight) {
+                    return invalid(format!(
+                        "cpu.weight must be between {} and {}",
+                        CPU_WEIGHT_RANGE.start(),
+                        CPU_WEIGHT_RANGE.end()
+                    ));
+                }
+            }
+        }
+
+        if let Some(ref memory) = self.memory {
+            if let (Some(max), Some(high)) = (memory.max, memory.high) {
+                if high > max {
+                    return invalid("memory.high must not be greater than memory.max".to_string());
+                }
+            }
+        }
+
+        let mut devices = HashSet::new();
+        for io in &self.io {
+            if !validate_device(&io.device) {
+                return invalid(format!(
+                    "io device {} must follow the <major>:<minor> format",
+                    io.device
+                ));
+            }
+            if !devices.insert(io.device.as_str()) {
+                return invalid(format!(
+                    "io device {} is specified more than once",
+                    io.device
+                ));
+            }
+            let limits = [io.rbps, io.wbps, io.riops, io.wiops];
+            if limits.iter().all(Option::is_none) {
+                return invalid(format!("io device {} has no limits", io.device));
+            }
+            if limits.contains(&Some(0)) {
+                return invalid(format!("io limits of device {} must not be 0", io.device));
+            }
+        }
+
+        if self.pids.as_ref().map_or(false, |pids| pids.max == 0) {
+            return invalid("pids.max must not be 0".to_string());
+        }
+
+        if let Some(ref pinning) = self.cpu_pinning {
+            if pinning.vcpus.is_empty() {
+                return invalid("cpu_pinning.vcpus must not be empty".to_string());
+            }
+            for cpus in std::iter::once(&pinning.vmm).chain(pinning.vcpus.iter()) {
+                if !validate_cpu_list(cpus) {
+                    return invalid(format!("invalid cpu list in cpu_pinning: {}", cpus));
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    // Returns the cgroup files to write for this profile, along with their values.
+    pub fn cgroup_values(&self) -> Vec<(String, String)> {
+        let mut values = Vec::new();
+
+        if let Some(ref cpu) = self.cpu {
+            match (cpu.quota_us, cpu.period_us) {
+                (Some(quota), period) => values.push((
+                    "cpu.max".to_string(),
+                    format!("{} {}", quota, period.unwrap_or(DEFAULT_CPU_PERIOD_US)),
+                )),
+                (None, Some(period)) => {
+                    values.push(("cpu.max".to_string(), format!("max {}", period)))
+                }
+                (None, None) => (),
+            }
+            if let Some(weight) = cpu.weight {
+                values.push(("cpu.weight".to_string(), weight.to_string()));
+            }
+        }
+
+        if let Some(ref memory) = self.memory {
+            for (file, value) in &[
+                ("memory.max", memory.max),
+                ("memory.high", memory.high),
+                ("memory.swap.max", memory.swap_max),
+            ] {
+                if let Some(value) = value {
+                    values.push((file.to_string(), value.to_string()));
+                }
+            }
+        }
+
+        // io.max takes the limits of a single device per write.
+        for io in &self.io {
+            let limits: String = [
+                ("rbps", io.rbps),
+                ("wbps", io.wbps),
+                ("riops", io.riops),
+                ("wiops", io.wiops),
+            ]
+            .iter()
+            .filter_map(|(key, value)| value.map(|value| format!(" {}={}", key, value)))
+            .collect();
+            values.push(("io.max".to_string(), format!("{}{}", io.device, limits)));
+        }
+
+        if let Some(ref pids) = self.pids {
+            values.push(("pids.max".to_string(), pids.max.to_string()));
+        }
+
+        values
+    }
+
+    pub fn cpu_pinning(&self) -> Option<&CpuPinning> {
+        self.cpu_pinning.as_ref()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use utils::tempfile::TempFile;
+
+    fn profile_from_str(contents: &str) -> Result<CgroupProfile> {
+        let file = TempFile::new().unwrap();
+        fs::write(file.as_path(), contents).unwrap();
+        CgroupProfile::from_file(file.as_path())
+    }
+
+    #[test]
+    fn test_validate_cpu_list() {
+        for cpus in &["0", "0-3", "0,2,4-7", "3-3"] {
+            assert!(validate_cpu_list(cpus), "{}", cpus);
+        }
+        for cpus in &["", "a", "0,", "-1", "0-", "3-1", "0-2-4", "0 - 2"] {
+            assert!(!validate_cpu_list(cpus), "{}", cpus);
+        }
+    }
+
+    #[test]
+    fn test_from_file() {
+        let profile = profile_from_str(
+            r#"{
+                "cpu": { "quota_us": 50000, "weight": 200 },
+                "memory": { "max": 1073741824, "high": 805306368, "swap_max": 0 },
+                "io": [
+                    { "device": "8:0", "rbps": 1048576, "wiops": 100 },
+                    { "device": "253:1", "wbps": 2097152 }
+                ],
+                "pids": { "max": 64 },
+                "cpu_pinning": { "vmm": "0", "vcpus": ["2-3", "4"] }
+            }"#,
+        )
+        .unwrap();
+        assert_eq!(
+            profile.cgroup_values(),
+            vec![
+                ("cpu.max".to_string(), "50000 100000".to_string()),
+                ("cpu.weight".to_string(), "200".to_string()),
+                ("memory.max".to_string(), "1073741824".to_string()),
+                ("memory.high".to_string(), "805306368".to_string()),
+                ("memory.swap.max".to_string(), "0".to_string()),
+                (
+                    "io.max".to_string(),
+                    "8:0 rbps=1048576 wiops=100".to_string()
+                ),
+                ("io.max".to_string(), "253:1 wbps=2097152".to_string()),
+                ("pids.max".to_string(), "64".to_string()),
+            ]
+        );
+        assert_eq!(
+            profile.cpu_pinning(),
+            Some(&CpuPinning {
+                vmm: "0".to_string(),
+                vcpus: vec!["2-3".to_string(), "4".to_string()],
+            })
+        );
+
+        // A period without a quota leaves the bandwidth unlimited.
+        let profile = profile_from_str(r#"{ "cpu": { "period_us": 10000 } }"#).unwrap();
+        assert_eq!(
+            profile.cgroup_values(),
+            vec![("cpu.max".to_string(), "max 10000".to_string())]
+        );
+
+        // An empty profile doesn't write anything.
+        let profile = profile_from_str("{}").unwrap();
+        assert!(profile.cgroup_values().is_empty());
+        assert!(profile.cpu_pinning().is_none());
+
+        assert!(matches!(
+            CgroupProfile::from_file(Path::new("/does/not/exist")),
+            Err(Error::ReadToString(_, _))
+        ));
+        for contents in &[
+            "",
+            r#"{ "cpu": { "quota": 50000 } }"#,
+            r#"{ "memory": { "max": -1 } }"#,
+            r#"{ "pids": {} }"#,
+            r#"{ "cpu_pinning": { "vmm": "0" } }"#,
+            r#"{ "blkio": {} }"#,
+        ] {
+            assert!(
+                matches!(
+                    profile_from_str(contents),
+                    Err(Error::CgroupProfileFormat(_, _))
+                ),
+                "{}",
+                contents
+            );
+        }
+    }
+
+    #[test]
+    fn test_validate() {
+        for contents in &[
+            r#"{ "cpu": { "quota_us": 999 } }"#,
+            r#"{ "cpu": { "period_us": 999 } }"#,
+            r#"{ "cpu": { "period_us": 1000001 } }"#,
+            r#"{ "cpu": { "weight": 0 } }"#,
+            r#"{ "cpu": { "weight": 10001 } }"#,
+            r#"{ "memory": { "max": 1024, "high": 2048 } }"#,
+            r#"{ "io": [{ "device": "sda", "rbps": 1 }] }"#,
+            r#"{ "io": [{ "device": "8:0" }] }"#,
+            r#"{ "io": [{ "device": "8:0", "rbps": 0 }] }"#,
+            r#"{ "io": [{ "device": "8:0", "rbps": 1 }, { "device": "8:0", "wbps": 1 }] }"#,
+            r#"{ "pids": { "max": 0 } }"#,
+            r#"{ "cpu_pinning": { "vmm": "0", "vcpus": [] } }"#,
+            r#"{ "cpu_pinning": { "vmm": "0-", "vcpus": ["1"] } }"#,
+            r#"{ "cpu_pinning": { "vmm": "0", "vcpus": ["1", "3-2"] } }"#,
+        ] {
+            assert!(
+                matches!(
+                    profile_from_str(contents),
+                    Err(Error::CgroupProfileInvalid(_))
+                ),
+                "{}",
+                contents
+            );
+        }
+    }
+}
@@ -13,9 +13,9 @@ const OLD_ROOT_DIR_NAME_NUL_TERMINATED: &[u8] = b"old_root\0";
 const ROOT_DIR_NUL_TERMINATED: &[u8] = b"/\0";
 const CURRENT_DIR_NUL_TERMINATED: &[u8] = b".\0";
 
-// This uses switching to a new mount namespace + pivot_root(), together with the regular chroot,
-// to provide a hardened jail (at least compared to only relying on chroot).
-pub fn chroot(path: &Path) -> Result<()> {
+// Unshares into a new mount namespace, in which mounts done by the jailer are not visible from
+// the host.
+pub fn unshare_mount_ns() -> Result<()> {
     // We unshare into a new mount namespace. The call is safe because we're invoking a C library
     // function with valid parameters.
     SyscallReturnCode(unsafe { libc::unshare(libc::CLONE_NEWNS) })
@@ -26,7 +26,8 @@ pub fn chroot(path: &Path) -> Result<()> {
         CStr::from_bytes_with_nul(ROOT_DIR_NUL_TERMINATED).map_err(Error::FromBytesWithNul)?;
 
     // Recursively change the propagation type of all the mounts in this namespace to SLAVE, so
-    // we can call pivot_root. Safe because we provide valid parameters.
+    // that our mounts don't propagate back to the host and we can call pivot_root. Safe because
+    // we provide valid parameters.
     SyscallReturnCode(unsafe {
         libc::mount(
             null(),
@@ -37,7 +38,16 @@ pub fn chroot(path: &Path) -> Result<()> {
         )
     })
     .into_empty_result()
-    .map_err(Error::MountPropagationSlave)?;
+    .map_err(Error::MountPropagationSlave)
+}
+
+// This uses switching to a new mount namespace + pivot_root(), together with the regular chroot,
+// to provide a hardened jail (at least compared to only relying on chroot).
+pub fn chroot(path: &Path) -> Result<()> {
+    unshare_mount_ns()?;
+
+    let root_dir =
+        CStr::from_bytes_with_nul(ROOT_DIR_NUL_TERMINATED).map_err(Error::FromBytesWithNul)?;
 
     // We need a CString for the following mount call.
     let chroot_dir = to_cstring(path)?;
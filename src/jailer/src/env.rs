@@ -7,11 +7,16 @@ use std::os::unix::fs::PermissionsExt;
 use std::os::unix::io::IntoRawFd;
 use std::os::unix::process::CommandExt;
 use std::path::{Component, Path, PathBuf};
-use std::process::{Command, Stdio};
+use std::process::{self, Command, Stdio};
 
-use crate::cgroup::{Cgroup, CgroupBuilder};
-use crate::chroot::chroot;
+use crate::cgroup::{Cgroup, CgroupBuilder, ThreadCgroups};
+use crate::cgroup_profile::CgroupProfile;
+use crate::chroot::{chroot, unshare_mount_ns};
+use crate::mounts::BindMount;
 use crate::resource_limits::{ResourceLimits, FSIZE_ARG, NO_FILE_ARG};
+use crate::supervisor::{forward_signals, remove_cgroups, remove_jail, wait_child};
+use crate::tap::TapDevice;
+use crate::userns::unshare_user_ns;
 use crate::{Error, Result};
 use std::io;
 use std::io::Write;
@@ -64,6 +69,39 @@ fn dup2(old_fd: libc::c_int, new_fd: libc::c_int) -> Result<()> {
         .map_err(Error::Dup2)
 }
 
+// Opens /dev/null, so that the standard I/O file descriptors can be redirected to it when
+// daemonizing.
+fn open_dev_null() -> Result<libc::c_int> {
+    // Safe because we use a constant null-terminated string and verify the result.
+    SyscallReturnCode(unsafe {
+        libc::open(
+            DEV_NULL_WITH_NUL.as_ptr() as *const libc::c_char,
+            libc::O_RDWR,
+        )
+    })
+    .into_result()
+    .map_err(Error::OpenDevNull)
+}
+
+// Calls setsid() and replaces the standard I/O file descriptors with `dev_null_fd`, which is
+// closed afterwards.
+fn daemonize(dev_null_fd: libc::c_int) -> Result<()> {
+    // Call setsid(). Safe because it's a library function.
+    SyscallReturnCode(unsafe { libc::setsid() })
+        .into_empty_result()
+        .map_err(Error::SetSid)?;
+
+    // Replace the stdio file descriptors with the /dev/null fd.
+    dup2(dev_null_fd, STDIN_FILENO)?;
+    dup2(dev_null_fd, STDOUT_FILENO)?;
+    dup2(dev_null_fd, STDERR_FILENO)?;
+
+    // Safe because we are passing valid parameters, and checking the result.
+    SyscallReturnCode(unsafe { libc::close(dev_null_fd) })
+        .into_empty_result()
+        .map_err(Error::CloseDevNullFd)
+}
+
 // This is a wrapper for the clone system call. When we want to create a new process in a new
 // pid namespace, we will call clone with a NULL stack pointer. We can do this because we will
 // not use the CLONE_VM flag, this will result with the original stack replicated, in a similar
@@ -92,14 +130,20 @@ pub struct Env {
     uid: u32,
     gid: u32,
     netns: Option<String>,
+    new_netns: bool,
+    taps: Vec<TapDevice>,
     daemonize: bool,
     new_pid_ns: bool,
+    user_ns: bool,
+    supervise: bool,
     start_time_us: u64,
     start_time_cpu_us: u64,
     jailer_cpu_time_us: u64,
     extra_args: Vec<String>,
     cgroups: Vec<Box<dyn Cgroup>>,
+    thread_cgroups: Option<ThreadCgroups>,
     resource_limits: ResourceLimits,
+    mounts: Vec<BindMount>,
 }
 
 impl Env {
@@ -159,10 +203,24 @@ impl Env {
 
         let netns = arguments.single_value("netns").cloned();
 
+        let new_netns = arguments.flag_present("new-netns");
+
+        // tap format: <name>[:mtu=<mtu>][:offload=<flag>,...]
+        let mut taps = Vec::new();
+        if let Some(args) = arguments.multiple_values("tap") {
+            for arg in args {
+                taps.push(TapDevice::parse(arg)?);
+            }
+        }
+
         let daemonize = arguments.flag_present("daemonize");
 
         let new_pid_ns = arguments.flag_present("new-pid-ns");
 
+        let user_ns = arguments.flag_present("user-ns");
+
+        let supervise = arguments.flag_present("supervise");
+
         // Optional arguments.
         let mut cgroups: Vec<Box<dyn Cgroup>> = Vec::new();
         let parent_cgroup = match arguments.single_value("parent-cgroup") {
@@ -183,11 +241,27 @@ impl Env {
             .parse::<u8>()
             .map_err(|_| Error::CgroupInvalidVersion(cgroup_ver.to_string()))?;
 
+        // The profile is validated before any cgroup is written.
+        let cgroup_profile = match arguments.single_value("cgroup-profile") {
+            Some(path) if cgroup_ver == 2 => Some(CgroupProfile::from_file(Path::new(path))?),
+            Some(_) => return Err(Error::CgroupProfileVersion),
+            None => None,
+        };
+
         let mut cgroup_builder = None;
+        if arguments.multiple_values("cgroup").is_some() || cgroup_profile.is_some() {
+            // Without root privileges, cgroups can only be created in a delegated subtree.
+            cgroup_builder = Some(match (user_ns, cgroup_ver) {
+                (false, _) => CgroupBuilder::new(cgroup_ver)?,
+                (true, 2) => CgroupBuilder::new_delegated()?,
+                (true, _) => return Err(Error::CgroupV1UserNs),
+            });
+        }
 
         // cgroup format: <cgroup_controller>.<cgroup_property>=<value>,...
         if let Some(cgroups_args) = arguments.multiple_values("cgroup") {
-            let builder = cgroup_builder.get_or_insert(CgroupBuilder::new(cgroup_ver)?);
+            // Ok to unwrap since the builder was created above.
+            let builder = cgroup_builder.as_mut().unwrap();
             for cg in cgroups_args {
                 let aux: Vec<&str> = cg.split('=').collect();
                 if aux.len() != 2 || aux[1].is_empty() {
@@ -210,11 +284,29 @@ impl Env {
             }
         }
 
+        let mut thread_cgroups = None;
+        if let (Some(profile), Some(builder)) = (&cgroup_profile, cgroup_builder.as_mut()) {
+            for (file, value) in profile.cgroup_values() {
+                cgroups.push(builder.new_cgroup(file, value, id, parent_cgroup)?);
+            }
+            if let Some(pinning) = profile.cpu_pinning() {
+                thread_cgroups = Some(builder.new_thread_cgroups(pinning, id, parent_cgroup)?);
+            }
+        }
+
         let mut resource_limits = ResourceLimits::default();
         if let Some(args) = arguments.multiple_values("resource-limit") {
             Env::parse_resource_limits(&mut resource_limits, args)?;
         }
 
+        // mount format: <src>:<dst>[:ro]
+        let mut mounts = Vec::new();
+        if let Some(args) = arguments.multiple_values("mount") {
+            for arg in args {
+                mounts.push(BindMount::parse(arg)?);
+            }
+        }
+
         Ok(Env {
             id: id.to_owned(),
             chroot_dir,
@@ -222,14 +314,20 @@ impl Env {
             uid,
             gid,
             netns,
+            new_netns,
+            taps,
             daemonize,
             new_pid_ns,
+            user_ns,
+            supervise,
             start_time_us,
             start_time_cpu_us,
             jailer_cpu_time_us: 0,
             extra_args: arguments.extra_args(),
             cgroups,
+            thread_cgroups,
             resource_limits,
+            mounts,
         })
     }
 
@@ -283,6 +381,12 @@ impl Env {
                 // Save the PID of the process running the exec file provided
                 // inside <chroot_exec_file>.pid file.
                 self.save_exec_file_pid(child_pid, chroot_exec_file)?;
+                // When supervised, wait for the jailed process instead of exiting, so that the
+                // supervisor doesn't clean up while it's still running.
+                if self.supervise {
+                    forward_signals(child_pid)?;
+                    process::exit(wait_child(child_pid)?);
+                }
                 unsafe { libc::exit(0) }
             }
         }
@@ -338,6 +442,40 @@ impl Env {
             .map_err(|e| Error::ChangeFileOwner(PathBuf::from(dev_path.to_str().unwrap()), e))
     }
 
+    // Device nodes cannot be created in a user namespace, so the host ones are bind mounted
+    // inside the jail instead. Must be called before chrooting.
+    fn bind_mount_dev(&self, dev_path_str: &'static [u8]) -> Result<()> {
+        let dev_path = CStr::from_bytes_with_nul(dev_path_str).map_err(Error::FromBytesWithNul)?;
+        let dev_name = std::str::from_utf8(&dev_path_str[..dev_path_str.len() - 1])
+            .expect("Cannot convert from UTF-8");
+        let jailed_dev_path = self.chroot_dir.join(dev_name.trim_start_matches('/'));
+
+        // Ok to unwrap since the path is inside the chroot dir.
+        let jailed_dev_dir = jailed_dev_path.parent().unwrap();
+        fs::create_dir_all(jailed_dev_dir)
+            .map_err(|e| Error::CreateDir(jailed_dev_dir.to_owned(), e))?;
+        // Create the mount point.
+        OpenOptions::new()
+            .write(true)
+            .create(true)
+            .open(&jailed_dev_path)
+            .map_err(|e| Error::FileOpen(jailed_dev_path.clone(), e))?;
+
+        let jailed_dev_cstr = crate::to_cstring(&jailed_dev_path)?;
+        // Safe because we provide valid parameters.
+        SyscallReturnCode(unsafe {
+            libc::mount(
+                dev_path.as_ptr(),
+                jailed_dev_cstr.as_ptr(),
+                std::ptr::null(),
+                libc::MS_BIND,
+                std::ptr::null(),
+            )
+        })
+        .into_empty_result()
+        .map_err(|e| Error::MountDev(e, dev_name))
+    }
+
     fn setup_jailed_folder(&self, folder: &[u8]) -> Result<()> {
         let folder_cstr = CStr::from_bytes_with_nul(folder).map_err(Error::FromBytesWithNul)?;
 
@@ -398,6 +536,24 @@ impl Env {
             .map_err(Error::CloseNetNsFd)
     }
 
+    fn unshare_netns() -> Result<()> {
+        // The call is safe because we're invoking a C library function with valid parameters.
+        SyscallReturnCode(unsafe { libc::unshare(libc::CLONE_NEWNET) })
+            .into_empty_result()
+            .map_err(Error::UnshareNewNetNs)
+    }
+
+    // Creates a new network namespace if requested, then the tap devices of the microVM in the
+    // current one.
+    fn setup_netns(&self) -> Result<()> {
+        if self.new_netns {
+            Env::unshare_netns()?;
+        }
+        self.taps
+            .iter()
+            .try_for_each(|tap| tap.create(self.uid(), self.gid()))
+    }
+
     fn exec_command(&self, chroot_exec_file: PathBuf) -> io::Error {
         Command::new(chroot_exec_file)
             .args(&["--id", &self.id])
@@ -500,7 +656,43 @@ impl Env {
         Ok(())
     }
 
+    // Waits for the jailed process `child_pid` to exit, then removes its cgroups and jail, and
+    // exits with its exit code.
+    fn supervise(&self, child_pid: libc::pid_t) -> Result<()> {
+        forward_signals(child_pid)?;
+        if self.daemonize {
+            daemonize(open_dev_null()?)?;
+        }
+
+        let exit_code = wait_child(child_pid)?;
+
+        // Remove the jail even if some cgroups are still busy, e.g because the jailed process
+        // left children behind. The threaded cgroups must be removed before the microVM cgroup.
+        let cgroups_result = self
+            .thread_cgroups
+            .iter()
+            .try_for_each(|thread_cgroups| thread_cgroups.remove())
+            .and_then(|_| remove_cgroups(&self.cgroups));
+        // Ok to unwrap since the chroot dir is <chroot_base>/<exec_file_name>/<id>/root.
+        remove_jail(self.chroot_dir.parent().unwrap())?;
+        cgroups_result?;
+
+        process::exit(exit_code)
+    }
+
     pub fn run(mut self) -> Result<()> {
+        // When supervising, fork before anything else, so that only the jailed process is
+        // assigned to the cgroups and enters the namespaces.
+        if self.supervise {
+            // Safe because the jailer is single threaded.
+            let pid = SyscallReturnCode(unsafe { libc::fork() })
+                .into_result()
+                .map_err(Error::Clone)?;
+            if pid != 0 {
+                return self.supervise(pid);
+            }
+        }
+
         let exec_file_name = self.copy_exec_to_chroot()?;
         let chroot_exec_file = PathBuf::from("/").join(&exec_file_name);
 
@@ -509,35 +701,50 @@ impl Env {
             Env::join_netns(path)?;
         }
 
+        // A new network namespace is owned by the user namespace it is created in, so when
+        // running in a user namespace, it is created after unsharing it.
+        if !(self.user_ns && self.new_netns) {
+            self.setup_netns()?;
+        }
+
         // Set limits on resources.
         self.resource_limits.install()?;
 
         // We have to setup cgroups at this point, because we can't do it anymore after chrooting.
         // cgroups are iterated two times as some cgroups may require others (e.g cpuset requires
         // cpuset.mems and cpuset.cpus) to be set before attaching any pid.
+        // In a delegated cgroupsv2 subtree, the pid is attached first instead: controllers can't
+        // be enabled in the delegated root while the jailer still belongs to it.
+        if self.user_ns {
+            for cgroup in &self.cgroups {
+                // it will panic if any cgroup fails to attach
+                cgroup.attach_pid().unwrap();
+            }
+        }
+
         for cgroup in &self.cgroups {
             // it will panic if any cgroup fails to write
             cgroup.write_value().unwrap();
         }
 
-        for cgroup in &self.cgroups {
-            // it will panic if any cgroup fails to attach
-            cgroup.attach_pid().unwrap();
+        if !self.user_ns {
+            for cgroup in &self.cgroups {
+                // it will panic if any cgroup fails to attach
+                cgroup.attach_pid().unwrap();
+            }
+        }
+
+        // The threads of a process can only be moved to threaded cgroups of the cgroup the
+        // process belongs to.
+        if let Some(ref thread_cgroups) = self.thread_cgroups {
+            thread_cgroups.attach_pid()?;
+            thread_cgroups.write_values()?;
+            thread_cgroups.attach_vmm_thread()?;
         }
 
         // If daemonization was requested, open /dev/null before chrooting.
         let dev_null = if self.daemonize {
-            // Safe because we use a constant null-terminated string and verify the result.
-            Some(
-                SyscallReturnCode(unsafe {
-                    libc::open(
-                        DEV_NULL_WITH_NUL.as_ptr() as *const libc::c_char,
-                        libc::O_RDWR,
-                    )
-                })
-                .into_result()
-                .map_err(Error::OpenDevNull)?,
-            )
+            Some(open_dev_null()?)
         } else {
             None
         };
@@ -546,6 +753,36 @@ impl Env {
         #[cfg(target_arch = "aarch64")]
         self.copy_midr_el1_info()?;
 
+        if self.user_ns {
+            // Everything requiring privileges on the host must be done before this point.
+            unshare_user_ns(self.uid(), self.gid())?;
+
+            if self.new_netns {
+                self.setup_netns()?;
+            }
+
+            self.bind_mount_dev(DEV_NET_TUN_WITH_NUL)?;
+            self.bind_mount_dev(DEV_KVM_WITH_NUL)?;
+            let _ = self.bind_mount_dev(DEV_URANDOM_WITH_NUL).map_err(|err| {
+                println!(
+                    "Warning! Could not bind mount /dev/urandom inside jailer: {}.",
+                    err
+                );
+                println!("MMDS version 2 will not be available to use.");
+            });
+        }
+
+        if !self.mounts.is_empty() || self.thread_cgroups.is_some() {
+            // Keep the bind mounts private to the jail.
+            unshare_mount_ns()?;
+            for mount in &self.mounts {
+                mount.mount(self.chroot_dir(), self.uid(), self.gid())?;
+            }
+            if let Some(ref thread_cgroups) = self.thread_cgroups {
+                thread_cgroups.mount(self.chroot_dir(), self.uid(), self.gid())?;
+            }
+        }
+
         // Jail self.
         chroot(self.chroot_dir())?;
 
@@ -555,45 +792,35 @@ impl Env {
             .iter()
             .try_for_each(|f| self.setup_jailed_folder(*f))?;
 
-        // Here we are creating the /dev/kvm and /dev/net/tun devices inside the jailer.
+        // Here we are creating the /dev/kvm and /dev/net/tun devices inside the jailer, unless
+        // they were bind mounted from the host.
         // Following commands can be translated into bash like this:
         // $: mkdir -p $chroot_dir/dev/net
         // $: dev_net_tun_path={$chroot_dir}/"tun"
         // $: mknod $dev_net_tun_path c 10 200
         // www.kernel.org/doc/Documentation/networking/tuntap.txt specifies 10 and 200 as the major
         // and minor for the /dev/net/tun device.
-        self.mknod_and_own_dev(DEV_NET_TUN_WITH_NUL, DEV_NET_TUN_MAJOR, DEV_NET_TUN_MINOR)?;
-        // Do the same for /dev/kvm with (major, minor) = (10, 232).
-        self.mknod_and_own_dev(DEV_KVM_WITH_NUL, DEV_KVM_MAJOR, DEV_KVM_MINOR)?;
-        // And for /dev/urandom with (major, minor) = (1, 9).
-        // If the device is not accessible on the host, output a warning to inform user that MMDS
-        // version 2 will not be available to use.
-        let _ = self
-            .mknod_and_own_dev(DEV_URANDOM_WITH_NUL, DEV_URANDOM_MAJOR, DEV_URANDOM_MINOR)
-            .map_err(|err| {
-                println!(
-                    "Warning! Could not create /dev/urandom device inside jailer: {}.",
-                    err
-                );
-                println!("MMDS version 2 will not be available to use.");
-            });
+        if !self.user_ns {
+            self.mknod_and_own_dev(DEV_NET_TUN_WITH_NUL, DEV_NET_TUN_MAJOR, DEV_NET_TUN_MINOR)?;
+            // Do the same for /dev/kvm with (major, minor) = (10, 232).
+            self.mknod_and_own_dev(DEV_KVM_WITH_NUL, DEV_KVM_MAJOR, DEV_KVM_MINOR)?;
+            // And for /dev/urandom with (major, minor) = (1, 9).
+            // If the device is not accessible on the host, output a warning to inform user that
+            // MMDS version 2 will not be available to use.
+            let _ = self
+                .mknod_and_own_dev(DEV_URANDOM_WITH_NUL, DEV_URANDOM_MAJOR, DEV_URANDOM_MINOR)
+                .map_err(|err| {
+                    println!(
+                        "Warning! Could not create /dev/urandom device inside jailer: {}.",
+                        err
+                    );
+                    println!("MMDS version 2 will not be available to use.");
+                });
+        }
 
         // Daemonize before exec, if so required (when the dev_null variable != None).
         if let Some(fd) = dev_null {
-            // Call setsid(). Safe because it's a library function.
-            SyscallReturnCode(unsafe { libc::setsid() })
-                .into_empty_result()
-                .map_err(Error::SetSid)?;
-
-            // Replace the stdio file descriptors with the /dev/null fd.
-            dup2(fd, STDIN_FILENO)?;
-            dup2(fd, STDOUT_FILENO)?;
-            dup2(fd, STDERR_FILENO)?;
-
-            // Safe because we are passing valid parameters, and checking the result.
-            SyscallReturnCode(unsafe { libc::close(fd) })
-                .into_empty_result()
-                .map_err(Error::CloseDevNullFd)?;
+            daemonize(fd)?;
         }
 
         // If specified, exec the provided binary into a new PID namespace.
@@ -624,10 +851,15 @@ mod tests {
         pub gid: &'a str,
         pub chroot_base: &'a str,
         pub netns: Option<&'a str>,
+        pub new_netns: bool,
+        pub taps: Vec<&'a str>,
         pub daemonize: bool,
         pub new_pid_ns: bool,
+        pub user_ns: bool,
+        pub supervise: bool,
         pub cgroups: Vec<&'a str>,
         pub resource_limits: Vec<&'a str>,
+        pub mounts: Vec<&'a str>,
         pub parent_cgroup: Option<&'a str>,
     }
 
@@ -640,10 +872,15 @@ mod tests {
                 gid: "1002",
                 chroot_base: "/",
                 netns: Some("zzzns"),
+                new_netns: false,
+                taps: vec!["tap0", "tap1:mtu=9000:offload=csum,tso4"],
                 daemonize: true,
                 new_pid_ns: true,
+                user_ns: false,
+                supervise: true,
                 cgroups: vec!["cpu.shares=2", "cpuset.mems=0"],
                 resource_limits: vec!["no-file=1024", "fsize=1048575"],
+                mounts: vec!["/proc/cpuinfo:/cpuinfo:ro", "/proc/self:/run/proc"],
                 parent_cgroup: None,
             }
         }
@@ -679,11 +916,27 @@ mod tests {
             arg_vec.push((*limit).to_string());
         }
 
+        // Append mount arguments
+        for mount in &arg_vals.mounts {
+            arg_vec.push("--mount".to_string());
+            arg_vec.push((*mount).to_string());
+        }
+
         if let Some(s) = arg_vals.netns {
             arg_vec.push("--netns".to_string());
             arg_vec.push(s.to_string());
         }
 
+        if arg_vals.new_netns {
+            arg_vec.push("--new-netns".to_string());
+        }
+
+        // Append tap arguments
+        for tap in &arg_vals.taps {
+            arg_vec.push("--tap".to_string());
+            arg_vec.push((*tap).to_string());
+        }
+
         if arg_vals.daemonize {
             arg_vec.push("--daemonize".to_string());
         }
@@ -692,6 +945,14 @@ mod tests {
             arg_vec.push("--new-pid-ns".to_string());
         }
 
+        if arg_vals.user_ns {
+            arg_vec.push("--user-ns".to_string());
+        }
+
+        if arg_vals.supervise {
+            arg_vec.push("--supervise".to_string());
+        }
+
         if let Some(parent_cg) = arg_vals.parent_cgroup {
             arg_vec.push("--parent-cgroup".to_string());
             arg_vec.push(parent_cg.to_string());
@@ -739,13 +1000,33 @@ mod tests {
         assert_eq!(format!("{}", good_env.uid()), good_arg_vals.uid);
 
         assert_eq!(good_env.netns, good_arg_vals.netns.map(String::from));
+        assert!(!good_env.new_netns);
+        assert_eq!(
+            good_env.taps,
+            good_arg_vals
+                .taps
+                .iter()
+                .map(|arg| TapDevice::parse(arg).unwrap())
+                .collect::<Vec<TapDevice>>()
+        );
         assert!(good_env.daemonize);
         assert!(good_env.new_pid_ns);
+        assert!(good_env.supervise);
+        assert_eq!(
+            good_env.mounts,
+            good_arg_vals
+                .mounts
+                .iter()
+                .map(|arg| BindMount::parse(arg).unwrap())
+                .collect::<Vec<BindMount>>()
+        );
 
         let another_good_arg_vals = ArgVals {
             netns: None,
+            new_netns: true,
             daemonize: false,
             new_pid_ns: false,
+            supervise: false,
             ..good_arg_vals
         };
 
@@ -754,8 +1035,32 @@ mod tests {
         args.parse(&make_args(&another_good_arg_vals)).unwrap();
         let another_good_env = Env::new(&args, 0, 0)
             .expect("This another new environment should be created successfully.");
+        assert!(another_good_env.new_netns);
         assert!(!another_good_env.daemonize);
         assert!(!another_good_env.new_pid_ns);
+        assert!(!another_good_env.user_ns);
+        assert!(!another_good_env.supervise);
+
+        // Without root privileges, cgroups can only be set with cgroupsv2.
+        let user_ns_arg_vals = ArgVals {
+            user_ns: true,
+            ..another_good_arg_vals.clone()
+        };
+        let arg_parser = build_arg_parser();
+        args = arg_parser.arguments().clone();
+        args.parse(&make_args(&user_ns_arg_vals)).unwrap();
+        assert!(matches!(Env::new(&args, 0, 0), Err(Error::CgroupV1UserNs)));
+
+        let user_ns_arg_vals = ArgVals {
+            cgroups: Vec::new(),
+            ..user_ns_arg_vals
+        };
+        let arg_parser = build_arg_parser();
+        args = arg_parser.arguments().clone();
+        args.parse(&make_args(&user_ns_arg_vals)).unwrap();
+        let user_ns_env = Env::new(&args, 0, 0)
+            .expect("This user namespace environment should be created successfully.");
+        assert!(user_ns_env.user_ns);
 
         let base_invalid_arg_vals = ArgVals {
             daemonize: true,
@@ -782,6 +1087,49 @@ mod tests {
         args.parse(&make_args(&invalid_res_limit_arg_vals)).unwrap();
         assert!(Env::new(&args, 0, 0).is_err());
 
+        let invalid_mount_arg_vals = ArgVals {
+            mounts: vec!["/proc/cpuinfo:/cpuinfo:rw"],
+            ..base_invalid_arg_vals.clone()
+        };
+
+        let arg_parser = build_arg_parser();
+        args = arg_parser.arguments().clone();
+        args.parse(&make_args(&invalid_mount_arg_vals)).unwrap();
+        assert!(matches!(Env::new(&args, 0, 0), Err(Error::MountFormat(_))));
+
+        // A new network namespace can't be created while joining an existing one.
+        let invalid_netns_arg_vals = ArgVals {
+            netns: Some("zzzns"),
+            ..base_invalid_arg_vals.clone()
+        };
+
+        let arg_parser = build_arg_parser();
+        args = arg_parser.arguments().clone();
+        assert!(args.parse(&make_args(&invalid_netns_arg_vals)).is_err());
+
+        let invalid_tap_arg_vals = ArgVals {
+            taps: vec!["tap0:mtu=9000:mtu=1500"],
+            ..base_invalid_arg_vals.clone()
+        };
+
+        let arg_parser = build_arg_parser();
+        args = arg_parser.arguments().clone();
+        args.parse(&make_args(&invalid_tap_arg_vals)).unwrap();
+        assert!(matches!(Env::new(&args, 0, 0), Err(Error::TapFormat(_))));
+
+        let invalid_tap_arg_vals = ArgVals {
+            taps: vec!["tap/0"],
+            ..base_invalid_arg_vals.clone()
+        };
+
+        let arg_parser = build_arg_parser();
+        args = arg_parser.arguments().clone();
+        args.parse(&make_args(&invalid_tap_arg_vals)).unwrap();
+        assert!(matches!(
+            Env::new(&args, 0, 0),
+            Err(Error::TapInvalidName(_))
+        ));
+
         let invalid_id_arg_vals = ArgVals {
             id: "/ad./sa12",
             ..base_invalid_arg_vals.clone()
@@ -972,6 +1320,36 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_bind_mount_dev() {
+        use std::os::unix::fs::FileTypeExt;
+
+        let mut mock_cgroups = MockCgroupFs::new().unwrap();
+        assert!(!mock_cgroups.add_v1_mounts().is_err());
+        let mut env = create_env();
+        let chroot_dir = TempDir::new().unwrap();
+        env.chroot_dir = chroot_dir.as_path().to_path_buf();
+
+        // Ensure path buffers without NULL-termination are handled well.
+        assert!(env.bind_mount_dev(b"/dev/null").is_err());
+
+        // The device and its parent directories are created, then the host device is mounted.
+        env.bind_mount_dev(DEV_NULL_WITH_NUL).unwrap();
+        let jailed_dev_null = chroot_dir.as_path().join("dev/null");
+        let metadata = fs::metadata(&jailed_dev_null).unwrap();
+        assert!(metadata.file_type().is_char_device());
+        assert_eq!(
+            metadata.st_rdev(),
+            fs::metadata("/dev/null").unwrap().st_rdev()
+        );
+
+        let jailed_dev_null_cstr = crate::to_cstring(&jailed_dev_null).unwrap();
+        assert_eq!(
+            unsafe { libc::umount2(jailed_dev_null_cstr.as_ptr(), libc::MNT_DETACH) },
+            0
+        );
+    }
+
     #[test]
     fn test_copy_exec_to_chroot() {
         // Create a standard environment.
@@ -994,10 +1372,15 @@ mod tests {
             gid: "1002",
             chroot_base: some_dir_path,
             netns: Some("zzzns"),
+            new_netns: false,
+            taps: Vec::new(),
             daemonize: false,
             new_pid_ns: false,
+            user_ns: false,
+            supervise: false,
             cgroups: Vec::new(),
             resource_limits: Vec::new(),
+            mounts: Vec::new(),
             parent_cgroup: None,
         };
         fs::write(some_file_path, "some_content").unwrap();
@@ -1123,6 +1506,58 @@ mod tests {
         assert!(Env::new(&args, 0, 0).is_ok());
     }
 
+    #[test]
+    fn test_cgroup_profile_parsing() {
+        let mut mock_cgroups = MockCgroupFs::new().unwrap();
+        assert!(!mock_cgroups.add_v1_mounts().is_err());
+        assert!(!mock_cgroups.add_v2_mounts().is_err());
+
+        let profile = TempFile::new().unwrap();
+        fs::write(
+            profile.as_path(),
+            r#"{
+                "cpu": { "weight": 100 },
+                "pids": { "max": 64 },
+                "cpu_pinning": { "vmm": "0", "vcpus": ["1"] }
+            }"#,
+        )
+        .unwrap();
+        let arg_vals = ArgVals {
+            cgroups: Vec::new(),
+            ..ArgVals::new()
+        };
+        let mut arg_vec = make_args(&arg_vals);
+        arg_vec.push("--cgroup-profile".to_string());
+        arg_vec.push(profile.as_path().to_str().unwrap().to_string());
+
+        // Profiles can only be used with cgroupsv2.
+        let arg_parser = build_arg_parser();
+        let mut args = arg_parser.arguments().clone();
+        args.parse(&arg_vec).unwrap();
+        assert!(matches!(
+            Env::new(&args, 0, 0),
+            Err(Error::CgroupProfileVersion)
+        ));
+
+        arg_vec.push("--cgroup-version".to_string());
+        arg_vec.push("2".to_string());
+        let mut args = arg_parser.arguments().clone();
+        args.parse(&arg_vec).unwrap();
+        let env = Env::new(&args, 0, 0).unwrap();
+        // A cgroup is created for each value of the profile.
+        assert_eq!(env.cgroups.len(), 2);
+        assert!(env.thread_cgroups.is_some());
+
+        // Invalid profiles are rejected before any cgroup is created.
+        fs::write(profile.as_path(), r#"{ "pids": { "max": 0 } }"#).unwrap();
+        let mut args = arg_parser.arguments().clone();
+        args.parse(&arg_vec).unwrap();
+        assert!(matches!(
+            Env::new(&args, 0, 0),
+            Err(Error::CgroupProfileInvalid(_))
+        ));
+    }
+
     #[test]
     fn test_parse_resource_limits() {
         let mut resource_limits = ResourceLimits::default();
@@ -1,9 +1,14 @@
 // Copyright 2018 Amazon.com, Inc. or its affiliates. All Rights Reserved.
 // SPDX-License-Identifier: Apache-2.0
 mod cgroup;
+mod cgroup_profile;
 mod chroot;
 mod env;
+mod mounts;
 mod resource_limits;
+mod supervisor;
+mod tap;
+mod userns;
 use std::env as p_env;
 
 use std::ffi::{CString, NulError, OsString};
@@ -32,6 +37,10 @@ pub enum Error {
     CgroupControllerUnavailable(String),
     CgroupInvalidVersion(String),
     CgroupInvalidParentPath(),
+    CgroupV1UserNs,
+    CgroupProfileFormat(PathBuf, serde_json::Error),
+    CgroupProfileInvalid(String),
+    CgroupProfileVersion,
     ChangeFileOwner(PathBuf, io::Error),
     ChdirNewRoot(io::Error),
     Chmod(PathBuf, io::Error),
@@ -40,6 +49,7 @@ pub enum Error {
     CloseDevNullFd(io::Error),
     Copy(PathBuf, PathBuf, io::Error),
     CreateDir(PathBuf, io::Error),
+    CreateSocket(io::Error),
     CStringParsing(NulError),
     Dup2(io::Error),
     Exec(io::Error),
@@ -53,15 +63,22 @@ pub enum Error {
     MkdirOldRoot(io::Error),
     MknodDev(io::Error, &'static str),
     MountBind(io::Error),
+    MountDev(io::Error, &'static str),
+    MountFormat(String),
+    MountInaccessible(PathBuf, u32, u32),
+    MountInvalidDestination(String),
     MountPropagationSlave(io::Error),
+    MountResource(PathBuf, io::Error),
     NotAFile(PathBuf),
     NotADirectory(PathBuf),
     OpenDevNull(io::Error),
+    OpenTun(io::Error),
     OsStringParsing(PathBuf, OsString),
     PivotRoot(io::Error),
     ReadLine(PathBuf, io::Error),
     ReadToString(PathBuf, io::Error),
     RegEx(regex::Error),
+    RemoveDir(PathBuf, io::Error),
     ResLimitArgument(String),
     ResLimitFormat(String),
     ResLimitValue(String, String),
@@ -70,12 +87,22 @@ pub enum Error {
     SetNetNs(io::Error),
     Setrlimit(String),
     SetSid(io::Error),
+    Sigaction(io::Error),
+    TapFormat(String),
+    TapInvalidName(String),
+    TapIoctl(String, &'static str, io::Error),
     Uid(String),
+    Umount(PathBuf, io::Error),
     UmountOldRoot(io::Error),
     UnexpectedListenerFd(i32),
+    UnshareNewNetNs(io::Error),
     UnshareNewNs(io::Error),
+    UnshareNewUserNs(io::Error),
     UnsetCloexec(io::Error),
+    UserNsSync(io::Error),
+    Wait(io::Error),
     Write(PathBuf, io::Error),
+    WriteIdMaps(io::Error),
 }
 
 impl fmt::Display for Error {
@@ -124,6 +151,19 @@ impl fmt::Display for Error {
                     "Parent cgroup path is invalid. Path should not be absolute or contain '..' or '.'",
                 )
             }
+            CgroupV1UserNs => write!(
+                f,
+                "Cgroups can only be set with cgroup version 2 in a user namespace"
+            ),
+            CgroupProfileFormat(ref path, ref err) => write!(
+                f,
+                "{}",
+                format!("Invalid format for cgroup profile {:?}: {}", path, err).replace("\"", "")
+            ),
+            CgroupProfileInvalid(ref msg) => write!(f, "Invalid cgroup profile: {}", msg),
+            CgroupProfileVersion => {
+                write!(f, "Cgroup profiles can only be used with cgroup version 2")
+            }
             ChangeFileOwner(ref path, ref err) => {
                 write!(f, "Failed to change owner for {:?}: {}", path, err)
             }
@@ -141,6 +181,7 @@ impl fmt::Display for Error {
                 "{}",
                 format!("Failed to create directory {:?}: {}", path, err).replace("\"", "")
             ),
+            CreateSocket(ref err) => write!(f, "Failed to create socket: {}", err),
             CStringParsing(_) => write!(f, "Encountered interior \\0 while parsing a string"),
             Dup2(ref err) => write!(f, "Failed to duplicate fd: {}", err),
             Exec(ref err) => write!(f, "Failed to exec into Firecracker: {}", err),
@@ -178,9 +219,37 @@ impl fmt::Display for Error {
             MountBind(ref err) => {
                 write!(f, "Failed to bind mount the jail root directory: {}", err)
             }
+            MountDev(ref err, ref devname) => write!(
+                f,
+                "Failed to bind mount {} inside the jail: {}",
+                devname, err
+            ),
+            MountFormat(ref arg) => write!(f, "Invalid format for bind mount: {}", arg),
+            MountInaccessible(ref path, uid, gid) => write!(
+                f,
+                "{}",
+                format!(
+                    "Cannot bind mount {:?} inside the jail: it is not accessible to uid {} and \
+                     gid {}. Its ownership or permissions must be changed beforehand.",
+                    path, uid, gid
+                )
+                .replace("\"", "")
+            ),
+            MountInvalidDestination(ref arg) => write!(
+                f,
+                "Invalid bind mount destination: {}. It should be inside the jail and should not \
+                 contain '..' or '.'",
+                arg
+            ),
             MountPropagationSlave(ref err) => {
                 write!(f, "Failed to change the propagation type to slave: {}", err)
             }
+            MountResource(ref path, ref err) => write!(
+                f,
+                "{}",
+                format!("Failed to bind mount {:?} inside the jail: {}", path, err)
+                    .replace("\"", "")
+            ),
             NotAFile(ref path) => write!(
                 f,
                 "{}",
@@ -192,6 +261,7 @@ impl fmt::Display for Error {
                 format!("{:?} is not a directory", path).replace("\"", "")
             ),
             OpenDevNull(ref err) => write!(f, "Failed to open /dev/null: {}", err),
+            OpenTun(ref err) => write!(f, "Failed to open /dev/net/tun: {}", err),
             OsStringParsing(ref path, _) => write!(
                 f,
                 "{}",
@@ -209,6 +279,11 @@ impl fmt::Display for Error {
                 format!("Failed to read file {:?} into a string: {}", path, err).replace("\"", "")
             ),
             RegEx(ref err) => write!(f, "Regex failed: {:?}", err),
+            RemoveDir(ref path, ref err) => write!(
+                f,
+                "{}",
+                format!("Failed to remove directory {:?}: {}", path, err).replace("\"", "")
+            ),
             ResLimitArgument(ref arg) => write!(f, "Invalid resource argument: {}", arg,),
             ResLimitFormat(ref arg) => write!(f, "Invalid format for resources limits: {}", arg,),
             ResLimitValue(ref arg, ref err) => {
@@ -219,24 +294,57 @@ impl fmt::Display for Error {
             SetNetNs(ref err) => write!(f, "Failed to join network namespace: netns: {}", err),
             Setrlimit(ref err) => write!(f, "Failed to set limit for resource: {}", err),
             SetSid(ref err) => write!(f, "Failed to daemonize: setsid: {}", err),
+            Sigaction(ref err) => write!(f, "Failed to install signal handler: {}", err),
+            TapFormat(ref arg) => write!(f, "Invalid format for tap device: {}", arg),
+            TapInvalidName(ref name) => write!(
+                f,
+                "Invalid tap device name: {}. It should have at most 15 characters and should not \
+                 contain '/' or whitespaces",
+                name
+            ),
+            TapIoctl(ref name, ref request, ref err) => write!(
+                f,
+                "Failed to configure tap device {}: {}: {}",
+                name, request, err
+            ),
             Uid(ref uid) => write!(f, "Invalid uid: {}", uid),
+            Umount(ref path, ref err) => write!(
+                f,
+                "{}",
+                format!("Failed to unmount {:?}: {}", path, err).replace("\"", "")
+            ),
             UmountOldRoot(ref err) => write!(f, "Failed to unmount the old jail root: {}", err),
             UnexpectedListenerFd(fd) => {
                 write!(f, "Unexpected value for the socket listener fd: {}", fd)
             }
+            UnshareNewNetNs(ref err) => {
+                write!(f, "Failed to unshare into new network namespace: {}", err)
+            }
             UnshareNewNs(ref err) => {
                 write!(f, "Failed to unshare into new mount namespace: {}", err)
             }
+            UnshareNewUserNs(ref err) => {
+                write!(f, "Failed to unshare into new user namespace: {}", err)
+            }
             UnsetCloexec(ref err) => write!(
                 f,
                 "Failed to unset the O_CLOEXEC flag on the socket fd: {}",
                 err
             ),
+            UserNsSync(ref err) => write!(
+                f,
+                "Failed to synchronize with the user namespace helper: {}",
+                err
+            ),
+            Wait(ref err) => write!(f, "Failed to wait for the jailed process: {}", err),
             Write(ref path, ref err) => write!(
                 f,
                 "{}",
                 format!("Failed to write to {:?}: {}", path, err).replace("\"", "")
             ),
+            WriteIdMaps(ref err) => {
+                write!(f, "Failed to write the user namespace id maps: {}", err)
+            }
         }
     }
 }
@@ -282,6 +390,19 @@ pub fn build_arg_parser() -> ArgParser<'static> {
                 .takes_value(true)
                 .help("Path to the network namespace this microVM should join."),
         )
+        .arg(
+            Argument::new("new-netns")
+                .takes_value(false)
+                .forbids(vec!["netns"])
+                .help("Create a new network namespace for this microVM."),
+        )
+        .arg(Argument::new("tap").allow_multiple(true).help(
+            "Tap device to be created by the jailer in the network namespace of this microVM, \
+             and owned by the jailed uid and gid. It must follow this format: \
+             <name>[:mtu=<mtu>][:offload=<flag>,...] (e.g tap0:mtu=9000:offload=csum,tso4), where \
+             the offload flags are csum, tso4, tso6, tso_ecn and ufo. This argument can be used \
+             multiple times to add multiple tap devices.",
+        ))
         .arg(Argument::new("daemonize").takes_value(false).help(
             "Daemonize the jailer before exec, by invoking setsid(), and redirecting \
              the standard I/O file descriptors to /dev/null.",
@@ -311,11 +432,30 @@ pub fn build_arg_parser() -> ArgParser<'static> {
                 .default_value("1")
                 .help("Select the cgroup version used by the jailer."),
         )
+        .arg(Argument::new("cgroup-profile").takes_value(true).help(
+            "Path to a JSON file describing the cgroup resources of this microVM: CPU bandwidth and \
+             weight, memory limits, IO limits per device, maximum number of tasks, and the \
+             cpusets the VMM and vCPU threads are pinned to. Requires cgroup version 2.",
+        ))
         .arg(
             Argument::new("parent-cgroup")
                 .takes_value(true)
                 .help("Parent cgroup in which the cgroup of this microvm will be placed."),
         )
+        .arg(Argument::new("mount").allow_multiple(true).help(
+            "Host path to be bind mounted inside the jail. It must follow this format: \
+             <src>:<dst>[:ro] (e.g /images/rootfs.ext4:/rootfs.ext4:ro), where <dst> is relative \
+             to the jail root. This argument can be used multiple times to add multiple mounts.",
+        ))
+        .arg(Argument::new("supervise").takes_value(false).help(
+            "Keep the jailer running as the parent of the jailed process. Once it exits, its \
+             cgroups and chroot directory are removed, and the jailer exits with its exit code.",
+        ))
+        .arg(Argument::new("user-ns").takes_value(false).help(
+            "Run the jailer without root privileges, in a new user namespace. Device nodes are \
+             bind mounted from the host instead of created, and cgroups are created in the \
+             cgroupsv2 subtree delegated to the current cgroup.",
+        ))
         .arg(
             Argument::new("version")
                 .takes_value(false)
@@ -551,6 +691,32 @@ mod tests {
             "Invalid format for cgroups: cpuset.mems",
         );
 
+        assert_eq!(
+            format!("{}", Error::CgroupV1UserNs),
+            "Cgroups can only be set with cgroup version 2 in a user namespace",
+        );
+        assert_eq!(
+            format!(
+                "{}",
+                Error::CgroupProfileFormat(
+                    PathBuf::from("/profile.json"),
+                    serde_json::from_str::<u64>("foo").unwrap_err()
+                )
+            ),
+            "Invalid format for cgroup profile /profile.json: expected value at line 1 column 1",
+        );
+        assert_eq!(
+            format!(
+                "{}",
+                Error::CgroupProfileInvalid("pids.max must not be 0".to_string())
+            ),
+            "Invalid cgroup profile: pids.max must not be 0",
+        );
+        assert_eq!(
+            format!("{}", Error::CgroupProfileVersion),
+            "Cgroup profiles can only be used with cgroup version 2",
+        );
+
         assert_eq!(
             format!(
                 "{}",
@@ -598,6 +764,10 @@ mod tests {
             ),
             format!("Failed to create directory /foo: {}", err2_str)
         );
+        assert_eq!(
+            format!("{}", Error::CreateSocket(io::Error::from_raw_os_error(42))),
+            "Failed to create socket: No message of desired type (os error 42)",
+        );
         assert_eq!(
             format!(
                 "{}",
@@ -666,10 +836,47 @@ mod tests {
             format!("{}", Error::MountBind(io::Error::from_raw_os_error(42))),
             "Failed to bind mount the jail root directory: No message of desired type (os error 42)",
         );
+        assert_eq!(
+            format!(
+                "{}",
+                Error::MountDev(io::Error::from_raw_os_error(42), "/dev/kvm")
+            ),
+            "Failed to bind mount /dev/kvm inside the jail: No message of desired type (os error 42)",
+        );
+        assert_eq!(
+            format!("{}", Error::MountFormat("foo".to_string())),
+            "Invalid format for bind mount: foo",
+        );
+        assert_eq!(
+            format!(
+                "{}",
+                Error::MountInaccessible(PathBuf::from("/foo"), 123, 456)
+            ),
+            "Cannot bind mount /foo inside the jail: it is not accessible to uid 123 and gid 456. \
+             Its ownership or permissions must be changed beforehand.",
+        );
+        assert_eq!(
+            format!(
+                "{}",
+                Error::MountInvalidDestination("/foo:../bar".to_string())
+            ),
+            "Invalid bind mount destination: /foo:../bar. It should be inside the jail and should \
+             not contain '..' or '.'",
+        );
         assert_eq!(
             format!("{}", Error::MountPropagationSlave(io::Error::from_raw_os_error(42))),
             "Failed to change the propagation type to slave: No message of desired type (os error 42)",
         );
+        assert_eq!(
+            format!(
+                "{}",
+                Error::MountResource(file_path.clone(), io::Error::from_raw_os_error(2))
+            ),
+            format!(
+                "Failed to bind mount /foo/bar inside the jail: {}",
+                err2_str
+            ),
+        );
         assert_eq!(
             format!("{}", Error::NotAFile(file_path.clone())),
             "/foo/bar is not a file",
@@ -682,6 +889,10 @@ mod tests {
             format!("{}", Error::OpenDevNull(io::Error::from_raw_os_error(42))),
             "Failed to open /dev/null: No message of desired type (os error 42)",
         );
+        assert_eq!(
+            format!("{}", Error::OpenTun(io::Error::from_raw_os_error(2))),
+            format!("Failed to open /dev/net/tun: {}", err2_str),
+        );
         assert_eq!(
             format!(
                 "{}",
@@ -711,6 +922,13 @@ mod tests {
             format!("{}", Error::RegEx(err_regex.clone())),
             format!("Regex failed: {:?}", err_regex),
         );
+        assert_eq!(
+            format!(
+                "{}",
+                Error::RemoveDir(file_path.clone(), io::Error::from_raw_os_error(16))
+            ),
+            "Failed to remove directory /foo/bar: Device or resource busy (os error 16)",
+        );
         assert_eq!(
             format!("{}", Error::ResLimitArgument("foo".to_string())),
             "Invalid resource argument: foo",
@@ -746,10 +964,41 @@ mod tests {
             format!("{}", Error::SetSid(io::Error::from_raw_os_error(42))),
             "Failed to daemonize: setsid: No message of desired type (os error 42)",
         );
+        assert_eq!(
+            format!("{}", Error::Sigaction(io::Error::from_raw_os_error(22))),
+            "Failed to install signal handler: Invalid argument (os error 22)",
+        );
+        assert_eq!(
+            format!("{}", Error::TapFormat("tap0:mtu=foo".to_string())),
+            "Invalid format for tap device: tap0:mtu=foo",
+        );
+        assert_eq!(
+            format!("{}", Error::TapInvalidName("tap/0".to_string())),
+            "Invalid tap device name: tap/0. It should have at most 15 characters and should not \
+             contain '/' or whitespaces",
+        );
+        assert_eq!(
+            format!(
+                "{}",
+                Error::TapIoctl(
+                    "tap0".to_string(),
+                    "TUNSETIFF",
+                    io::Error::from_raw_os_error(1)
+                )
+            ),
+            "Failed to configure tap device tap0: TUNSETIFF: Operation not permitted (os error 1)",
+        );
         assert_eq!(
             format!("{}", Error::Uid(id.to_string())),
             "Invalid uid: foobar",
         );
+        assert_eq!(
+            format!(
+                "{}",
+                Error::Umount(file_path.clone(), io::Error::from_raw_os_error(16))
+            ),
+            "Failed to unmount /foo/bar: Device or resource busy (os error 16)",
+        );
         assert_eq!(
             format!("{}", Error::UmountOldRoot(io::Error::from_raw_os_error(42))),
             "Failed to unmount the old jail root: No message of desired type (os error 42)",
@@ -758,15 +1007,39 @@ mod tests {
             format!("{}", Error::UnexpectedListenerFd(42)),
             "Unexpected value for the socket listener fd: 42",
         );
+        assert_eq!(
+            format!(
+                "{}",
+                Error::UnshareNewNetNs(io::Error::from_raw_os_error(42))
+            ),
+            "Failed to unshare into new network namespace: No message of desired type (os error \
+             42)",
+        );
         assert_eq!(
             format!("{}", Error::UnshareNewNs(io::Error::from_raw_os_error(42))),
             "Failed to unshare into new mount namespace: No message of desired type (os error 42)",
         );
+        assert_eq!(
+            format!(
+                "{}",
+                Error::UnshareNewUserNs(io::Error::from_raw_os_error(42))
+            ),
+            "Failed to unshare into new user namespace: No message of desired type (os error 42)",
+        );
         assert_eq!(
             format!("{}", Error::UnsetCloexec(io::Error::from_raw_os_error(42))),
             "Failed to unset the O_CLOEXEC flag on the socket fd: No message of desired type (os \
              error 42)",
         );
+        assert_eq!(
+            format!("{}", Error::UserNsSync(io::Error::from_raw_os_error(42))),
+            "Failed to synchronize with the user namespace helper: No message of desired type (os \
+             error 42)",
+        );
+        assert_eq!(
+            format!("{}", Error::Wait(io::Error::from_raw_os_error(10))),
+            "Failed to wait for the jailed process: No child processes (os error 10)",
+        );
         assert_eq!(
             format!(
                 "{}",
@@ -774,6 +1047,10 @@ mod tests {
             ),
             format!("Failed to write to /foo/bar: {}", err2_str),
         );
+        assert_eq!(
+            format!("{}", Error::WriteIdMaps(io::Error::from_raw_os_error(1))),
+            "Failed to write the user namespace id maps: Operation not permitted (os error 1)",
+        );
     }
 
     #[test]
@@ -0,0 +1,344 @@
+// Copyright 2022 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+use std::ffi::CStr;
+use std::fs::{self, canonicalize, Metadata, OpenOptions};
+use std::io;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Component, Path, PathBuf};
+use std::ptr::null;
+
+use super::{to_cstring, Error, Result};
+use utils::syscall::SyscallReturnCode;
+
+// Suffix marking a bind mount as read-only.
+const READ_ONLY_SUFFIX: &str = "ro";
+
+// Mount flags applied to every bind mount. The VM resources are only ever read or written by
+// Firecracker, so there is no reason to allow executables, setuid binaries or devices in them.
+const DEFAULT_MOUNT_FLAGS: libc::c_ulong = libc::MS_NOSUID | libc::MS_NODEV | libc::MS_NOEXEC;
+
+// Permissions the jailed process needs on the mount sources, as "other" permission bits.
+const ACCESS_READ: u32 = 0o4;
+const ACCESS_WRITE: u32 = 0o2;
+const ACCESS_SEARCH: u32 = 0o1;
+
+// Host path bind mounted inside the jail, as specified by a `--mount src:dst[:ro]` argument.
+#[derive(Debug, PartialEq)]
+pub struct BindMount {
+    // Canonical path of the source on the host.
+    src: PathBuf,
+    // Path of the destination, relative to the jail root.
+    dst: PathBuf,
+    read_only: bool,
+}
+
+impl BindMount {
+    pub fn parse(arg: &str) -> Result<Self> {
+        let parts: Vec<&str> = arg.split(':').collect();
+        let read_only = match parts.as_slice() {
+            [_, _] => false,
+            [_, _, READ_ONLY_SUFFIX] => true,
+            _ => return Err(Error::MountFormat(arg.to_string())),
+        };
+
+        let src = canonicalize(parts[0]).map_err(|e| Error::Canonicalize(parts[0].into(), e))?;
+
+        // The destination is always relative to the jail root, and can't escape it.
+        let dst = Path::new(parts[1]);
+        let dst = dst.strip_prefix("/").unwrap_or(dst);
+        if dst.as_os_str().is_empty()
+            || dst
+                .components()
+                .any(|c| c == Component::CurDir || c == Component::ParentDir)
+        {
+            return Err(Error::MountInvalidDestination(arg.to_string()));
+        }
+
+        Ok(BindMount {
+            src,
+            dst: dst.to_path_buf(),
+            read_only,
+        })
+    }
+
+    // Creates the mount point inside `chroot_dir` and bind mounts the source on it. The source
+    // must already be accessible to `uid` and `gid`: its ownership is left untouched, as host
+    // paths may be shared with other users. Must be called from a private mount namespace.
+    pub fn mount(&self, chroot_dir: &Path, uid: u32, gid: u32) -> Result<()> {
+        self.check_access(uid, gid)?;
+
+        let target = chroot_dir.join(&self.dst);
+        // Ok to unwrap since the destination is not the jail root.
+        let target_dir = target.parent().unwrap();
+        fs::create_dir_all(target_dir).map_err(|e| Error::CreateDir(target_dir.to_owned(), e))?;
+
+        // A previous bind mount may have brought symlinks pointing outside of the jail.
+        let canonical_target_dir =
+            canonicalize(target_dir).map_err(|e| Error::Canonicalize(target_dir.to_owned(), e))?;
+        if !canonical_target_dir.starts_with(chroot_dir) {
+            return Err(Error::MountInvalidDestination(
+                self.dst.to_string_lossy().into_owned(),
+            ));
+        }
+
+        if self.src.is_dir() {
+            fs::create_dir_all(&target).map_err(|e| Error::CreateDir(target.clone(), e))?;
+        } else {
+            OpenOptions::new()
+                .write(true)
+                .create(true)
+                .open(&target)
+                .map_err(|e| Error::FileOpen(target.clone(), e))?;
+        }
+
+        bind_mount(&self.src, &target, self.read_only)
+    }
+
+    // Checks that the jailed process, running as `uid` and `gid`, can read the source, write to
+    // it unless the mount is read-only, and search it if it's a directory.
+    fn check_access(&self, uid: u32, gid: u32) -> Result<()> {
+        let metadata =
+            fs::metadata(&self.src).map_err(|e| Error::MountResource(self.src.clone(), e))?;
+        let mut access = ACCESS_READ;
+        if !self.read_only {
+            access |= ACCESS_WRITE;
+        }
+        if metadata.is_dir() {
+            access |= ACCESS_SEARCH;
+        }
+
+        if has_access(&metadata, uid, gid, access) {
+            Ok(())
+        } else {
+            Err(Error::MountInaccessible(self.src.clone(), uid, gid))
+        }
+    }
+}
+
+// Returns whether a process running as `uid` and `gid` is granted the `access` permissions by
+// the mode of a file. Root is granted all of them.
+fn has_access(metadata: &Metadata, uid: u32, gid: u32, access: u32) -> bool {
+    let mode = metadata.mode();
+    let granted = if uid == 0 {
+        return true;
+    } else if metadata.uid() == uid {
+        mode >> 6
+    } else if metadata.gid() == gid {
+        mode >> 3
+    } else {
+        mode
+    };
+    granted & access == access
+}
+
+// Bind mounts `src` on `target`, with the default mount flags. Must be called from a private
+// mount namespace.
+pub fn bind_mount(src: &Path, target: &Path, read_only: bool) -> Result<()> {
+    let src_cstr = to_cstring(src)?;
+    let target_cstr = to_cstring(target)?;
+
+    // Safe because we provide valid parameters.
+    SyscallReturnCode(unsafe {
+        libc::mount(
+            src_cstr.as_ptr(),
+            target_cstr.as_ptr(),
+            null(),
+            libc::MS_BIND,
+            null(),
+        )
+    })
+    .into_empty_result()
+    .map_err(|e| Error::MountResource(src.to_path_buf(), e))?;
+
+    // The flags of a bind mount can only be changed by remounting it.
+    let mut flags = libc::MS_BIND | libc::MS_REMOUNT | DEFAULT_MOUNT_FLAGS;
+    flags |= locked_flags(&target_cstr).map_err(|e| Error::MountResource(src.to_path_buf(), e))?;
+    if read_only {
+        flags |= libc::MS_RDONLY;
+    }
+    // Safe because we provide valid parameters.
+    SyscallReturnCode(unsafe { libc::mount(null(), target_cstr.as_ptr(), null(), flags, null()) })
+        .into_empty_result()
+        .map_err(|e| Error::MountResource(src.to_path_buf(), e))
+}
+
+// Returns the flags of the underlying mount that must be kept when remounting, as they can't be
+// cleared from a user namespace.
+fn locked_flags(target: &CStr) -> io::Result<libc::c_ulong> {
+    // Safe because the structure only contains integers.
+    let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+    // Safe because we provide valid parameters.
+    SyscallReturnCode(unsafe { libc::statvfs(target.as_ptr(), &mut stat) }).into_empty_result()?;
+
+    Ok([
+        (libc::ST_RDONLY, libc::MS_RDONLY),
+        (libc::ST_NOATIME, libc::MS_NOATIME),
+        (libc::ST_NODIRATIME, libc::MS_NODIRATIME),
+    ]
+    .iter()
+    .filter(|(st_flag, _)| stat.f_flag & st_flag != 0)
+    .fold(0, |flags, (_, ms_flag)| flags | ms_flag))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::PermissionsExt;
+    use utils::tempdir::TempDir;
+    use utils::tempfile::TempFile;
+
+    #[test]
+    fn test_parse() {
+        let src = TempFile::new().unwrap();
+        let src_path = src.as_path().to_str().unwrap();
+
+        assert_eq!(
+            BindMount::parse(&format!("{}:/rootfs.ext4", src_path)).unwrap(),
+            BindMount {
+                src: src.as_path().to_path_buf(),
+                dst: PathBuf::from("rootfs.ext4"),
+                read_only: false,
+            }
+        );
+        assert_eq!(
+            BindMount::parse(&format!("{}:images/vmlinux:ro", src_path)).unwrap(),
+            BindMount {
+                src: src.as_path().to_path_buf(),
+                dst: PathBuf::from("images/vmlinux"),
+                read_only: true,
+            }
+        );
+
+        for arg in &[
+            src_path.to_string(),
+            format!("{}:/vmlinux:rw", src_path),
+            format!("{}:/vmlinux:ro:ro", src_path),
+        ] {
+            assert!(matches!(
+                BindMount::parse(arg),
+                Err(Error::MountFormat(ref s)) if s == arg
+            ));
+        }
+
+        for arg in &[
+            format!("{}:/", src_path),
+            format!("{}:", src_path),
+            format!("{}:../vmlinux", src_path),
+            format!("{}:/images/../../vmlinux", src_path),
+            format!("{}:./vmlinux", src_path),
+        ] {
+            assert!(matches!(
+                BindMount::parse(arg),
+                Err(Error::MountInvalidDestination(ref s)) if s == arg
+            ));
+        }
+
+        assert!(matches!(
+            BindMount::parse("/does/not/exist:/vmlinux"),
+            Err(Error::Canonicalize(_, _))
+        ));
+    }
+
+    #[test]
+    fn test_has_access() {
+        let file = TempFile::new().unwrap();
+        let file_cstr = to_cstring(file.as_path()).unwrap();
+        assert_eq!(unsafe { libc::chown(file_cstr.as_ptr(), 123, 456) }, 0);
+        fs::set_permissions(file.as_path(), fs::Permissions::from_mode(0o640)).unwrap();
+        let metadata = fs::metadata(file.as_path()).unwrap();
+
+        // The owner permissions apply.
+        assert!(has_access(&metadata, 123, 0, ACCESS_READ | ACCESS_WRITE));
+        assert!(!has_access(&metadata, 123, 456, ACCESS_SEARCH));
+        // The group permissions apply.
+        assert!(has_access(&metadata, 124, 456, ACCESS_READ));
+        assert!(!has_access(&metadata, 124, 456, ACCESS_READ | ACCESS_WRITE));
+        // The other permissions apply.
+        assert!(!has_access(&metadata, 124, 457, ACCESS_READ));
+        // Root is granted everything.
+        assert!(has_access(
+            &metadata,
+            0,
+            0,
+            ACCESS_READ | ACCESS_WRITE | ACCESS_SEARCH
+        ));
+    }
+
+    #[test]
+    fn test_mount() {
+        let chroot_dir = TempDir::new().unwrap();
+        let chroot_path = canonicalize(chroot_dir.as_path()).unwrap();
+        let src = TempFile::new().unwrap();
+        let src_path = src.as_path().to_str().unwrap();
+
+        let umount = |path: &Path| {
+            let path_cstr = to_cstring(path).unwrap();
+            assert_eq!(
+                unsafe { libc::umount2(path_cstr.as_ptr(), libc::MNT_DETACH) },
+                0
+            );
+        };
+
+        let set_mode = |path: &Path, mode: u32| {
+            fs::set_permissions(path, fs::Permissions::from_mode(mode)).unwrap();
+        };
+
+        // Sources which are not accessible to the jailed user are rejected.
+        let mount = BindMount::parse(&format!("{}:/images/rootfs.ext4", src_path)).unwrap();
+        set_mode(src.as_path(), 0o600);
+        assert!(matches!(
+            mount.mount(&chroot_path, 123, 456),
+            Err(Error::MountInaccessible(ref path, 123, 456)) if path == src.as_path()
+        ));
+        assert!(!chroot_path.join("images").exists());
+
+        // Writable mounts keep their ownership.
+        let metadata = fs::metadata(src.as_path()).unwrap();
+        let owner = (metadata.uid(), metadata.gid());
+        set_mode(src.as_path(), 0o606);
+        mount.mount(&chroot_path, 123, 456).unwrap();
+        let target = chroot_path.join("images/rootfs.ext4");
+        fs::write(&target, "foo").unwrap();
+        assert_eq!(fs::read_to_string(src.as_path()).unwrap(), "foo");
+        let metadata = fs::metadata(src.as_path()).unwrap();
+        assert_eq!((metadata.uid(), metadata.gid()), owner);
+        umount(&target);
+
+        // Read-only mounts only need to be readable.
+        let mount = BindMount::parse(&format!("{}:/vmlinux:ro", src_path)).unwrap();
+        set_mode(src.as_path(), 0o600);
+        assert!(matches!(
+            mount.mount(&chroot_path, 123, 456),
+            Err(Error::MountInaccessible(_, _, _))
+        ));
+        set_mode(src.as_path(), 0o604);
+
+        // Read-only mounts can't be written to.
+        mount.mount(&chroot_path, 123, 456).unwrap();
+        let target = chroot_path.join("vmlinux");
+        assert_eq!(fs::read_to_string(&target).unwrap(), "foo");
+        assert_eq!(
+            fs::write(&target, "bar").unwrap_err().raw_os_error(),
+            Some(libc::EROFS)
+        );
+        umount(&target);
+
+        // Directories can be mounted too, and mounts can't escape the jail through symlinks.
+        let src_dir = TempDir::new().unwrap();
+        set_mode(src_dir.as_path(), 0o707);
+        std::os::unix::fs::symlink("/tmp", src_dir.as_path().join("link")).unwrap();
+        let mount =
+            BindMount::parse(&format!("{}:/run", src_dir.as_path().to_str().unwrap())).unwrap();
+        mount.mount(&chroot_path, 123, 456).unwrap();
+        let target = chroot_path.join("run");
+        assert!(target.join("link").exists());
+        set_mode(src.as_path(), 0o606);
+        let mount = BindMount::parse(&format!("{}:/run/link/foo", src_path)).unwrap();
+        assert!(matches!(
+            mount.mount(&chroot_path, 123, 456),
+            Err(Error::MountInvalidDestination(_))
+        ));
+        umount(&target);
+    }
+}
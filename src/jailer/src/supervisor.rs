@@ -0,0 +1,284 @@
+// Copyright 2022 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+use std::ffi::OsString;
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader};
+use std::os::unix::ffi::OsStringExt;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicI32, Ordering};
+
+use crate::cgroup::Cgroup;
+use crate::{to_cstring, Error, Result};
+use utils::syscall::SyscallReturnCode;
+
+const PROC_SELF_MOUNTS: &str = "/proc/self/mounts";
+
+// Signals received by the supervisor which are forwarded to the jailed process, so that it can
+// still clean up once the jailed process exits.
+const FORWARDED_SIGNALS: [libc::c_int; 3] = [libc::SIGHUP, libc::SIGINT, libc::SIGTERM];
+
+// Pid of the process the supervisor forwards signals to.
+static CHILD_PID: AtomicI32 = AtomicI32::new(0);
+
+extern "C" fn forward_signal(signum: libc::c_int) {
+    // Safe because kill() is async-signal-safe.
+    unsafe { libc::kill(CHILD_PID.load(Ordering::Relaxed), signum) };
+}
+
+// Forwards the termination signals received by the current process to `pid`.
+pub fn forward_signals(pid: libc::pid_t) -> Result<()> {
+    CHILD_PID.store(pid, Ordering::Relaxed);
+    for signum in FORWARDED_SIGNALS.iter() {
+        // Safe because the handler only uses atomics and kill(), and we check the return value.
+        unsafe {
+            let mut sigact: libc::sigaction = std::mem::zeroed();
+            sigact.sa_flags = libc::SA_RESTART;
+            sigact.sa_sigaction = forward_signal as usize;
+            SyscallReturnCode(libc::sigaction(*signum, &sigact, std::ptr::null_mut()))
+                .into_empty_result()
+                .map_err(Error::Sigaction)?;
+        }
+    }
+    Ok(())
+}
+
+// Converts a wait status to an exit code. A process killed by a signal is reported like shells
+// do, with 128 + the signal number.
+fn exit_code(status: libc::c_int) -> i32 {
+    if libc::WIFSIGNALED(status) {
+        128 + libc::WTERMSIG(status)
+    } else {
+        libc::WEXITSTATUS(status)
+    }
+}
+
+// Waits for the child process `pid` to exit and returns its exit code.
+pub fn wait_child(pid: libc::pid_t) -> Result<i32> {
+    let mut status = 0;
+    loop {
+        // Safe because we're waiting on our own child with a valid status pointer.
+        match SyscallReturnCode(unsafe { libc::waitpid(pid, &mut status, 0) }).into_empty_result() {
+            Ok(()) => return Ok(exit_code(status)),
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+            Err(err) => return Err(Error::Wait(err)),
+        }
+    }
+}
+
+// Removes the cgroups of a microVM, once all its processes have exited. The parent cgroups are
+// left in place, since they are shared with other microVMs.
+pub fn remove_cgroups(cgroups: &[Box<dyn Cgroup>]) -> Result<()> {
+    cgroups.iter().try_for_each(|cgroup| cgroup.remove())
+}
+
+// Decodes the octal escapes (e.g `\040` for a space) used for the fields of /proc/self/mounts.
+fn unescape_mount_field(field: &str) -> PathBuf {
+    let raw = field.as_bytes();
+    let mut bytes = Vec::with_capacity(raw.len());
+    let mut i = 0;
+    while i < raw.len() {
+        let escaped = raw
+            .get(i + 1..i + 4)
+            .filter(|_| raw[i] == b'\\')
+            .and_then(|octal| std::str::from_utf8(octal).ok())
+            .and_then(|octal| u8::from_str_radix(octal, 8).ok());
+        match escaped {
+            Some(byte) => {
+                bytes.push(byte);
+                i += 4;
+            }
+            None => {
+                bytes.push(raw[i]);
+                i += 1;
+            }
+        }
+    }
+    PathBuf::from(OsString::from_vec(bytes))
+}
+
+// Returns the mount points found inside `dir` in the current mount namespace, in the order they
+// were mounted.
+fn mount_points_in(dir: &Path) -> Result<Vec<PathBuf>> {
+    let f = File::open(PROC_SELF_MOUNTS)
+        .map_err(|e| Error::FileOpen(PathBuf::from(PROC_SELF_MOUNTS), e))?;
+
+    let mut mount_points = Vec::new();
+    for l in BufReader::new(f).lines() {
+        let l = l.map_err(|e| Error::ReadLine(PathBuf::from(PROC_SELF_MOUNTS), e))?;
+        // /proc/self/mounts contains lines that look like this:
+        // /dev/sda1 /srv/jailer/firecracker/<id>/root/rootfs ext4 rw,relatime 0 0
+        if let Some(mount_point) = l.split(' ').nth(1).map(unescape_mount_field) {
+            if mount_point.starts_with(dir) {
+                mount_points.push(mount_point);
+            }
+        }
+    }
+    Ok(mount_points)
+}
+
+// Unmounts everything still mounted inside `jail_dir`, then removes it. The mounts done by the
+// jailer live in the mount namespace of the jailed process and are gone with it, so this only
+// handles the ones made from the host, which would otherwise have their contents removed too.
+pub fn remove_jail(jail_dir: &Path) -> Result<()> {
+    // Unmount the nested mount points first.
+    for mount_point in mount_points_in(jail_dir)?.iter().rev() {
+        let mount_point_cstr = to_cstring(mount_point)?;
+        // Safe because we provide valid parameters.
+        SyscallReturnCode(unsafe { libc::umount2(mount_point_cstr.as_ptr(), libc::MNT_DETACH) })
+            .into_empty_result()
+            .map_err(|e| Error::Umount(mount_point.clone(), e))?;
+    }
+
+    match fs::remove_dir_all(jail_dir) {
+        Err(err) if err.kind() != io::ErrorKind::NotFound => {
+            Err(Error::RemoveDir(jail_dir.to_path_buf(), err))
+        }
+        _ => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cgroup::test_util::MockCgroupFs;
+    use crate::cgroup::CgroupBuilder;
+    use utils::tempdir::TempDir;
+
+    #[test]
+    fn test_wait_child() {
+        // Safe because the child only calls async-signal-safe functions.
+        let pid = unsafe { libc::fork() };
+        assert!(pid >= 0);
+        if pid == 0 {
+            unsafe { libc::_exit(3) };
+        }
+        assert_eq!(wait_child(pid).unwrap(), 3);
+
+        let pid = unsafe { libc::fork() };
+        assert!(pid >= 0);
+        if pid == 0 {
+            unsafe {
+                libc::pause();
+                libc::_exit(0);
+            }
+        }
+        // Safe because we're signaling our own child.
+        unsafe { libc::kill(pid, libc::SIGKILL) };
+        assert_eq!(wait_child(pid).unwrap(), 128 + libc::SIGKILL);
+
+        // There is no child left to wait for.
+        assert!(matches!(wait_child(pid), Err(Error::Wait(_))));
+    }
+
+    #[test]
+    fn test_unescape_mount_field() {
+        assert_eq!(
+            unescape_mount_field("/srv/jailer"),
+            Path::new("/srv/jailer")
+        );
+        assert_eq!(
+            unescape_mount_field("/srv/my\\040jail\\134root"),
+            Path::new("/srv/my jail\\root")
+        );
+        // Incomplete or invalid escapes are kept as they are.
+        assert_eq!(unescape_mount_field("/srv/\\098"), Path::new("/srv/\\098"));
+        assert_eq!(unescape_mount_field("/srv/\\04"), Path::new("/srv/\\04"));
+    }
+
+    #[test]
+    fn test_remove_cgroups() {
+        let mut mock_cgroups = MockCgroupFs::new().unwrap();
+        assert!(!mock_cgroups.add_v1_mounts().is_err());
+        assert!(!mock_cgroups.add_v2_mounts().is_err());
+
+        for v in &[1, 2] {
+            let mut builder = CgroupBuilder::new(*v).unwrap();
+            let cgroups: Vec<Box<dyn Cgroup>> = ["cpuset.mems=0", "cpu.max=max", "cpu.weight=1"]
+                .iter()
+                .map(|cg| {
+                    let (file, value) = cg.split_once('=').unwrap();
+                    builder
+                        .new_cgroup(
+                            file.to_string(),
+                            value.to_string(),
+                            "101",
+                            Path::new("fc_test_cg"),
+                        )
+                        .unwrap()
+                })
+                .collect();
+
+            // With real cgroups, the folders can be removed as long as no process belongs to
+            // them. The mock ones must be empty instead.
+            let locations = match v {
+                1 => vec!["cpuset/fc_test_cg/101", "cpu,cpuacct/fc_test_cg/101"],
+                _ => vec!["unified/fc_test_cg/101"],
+            };
+            for location in &locations {
+                fs::create_dir_all(Path::new(MockCgroupFs::MOCK_SYS_CGROUPS_DIR).join(location))
+                    .unwrap();
+            }
+
+            // The folders shared by several cgroups are only removed once.
+            remove_cgroups(&cgroups).unwrap();
+            for location in &locations {
+                let location = Path::new(MockCgroupFs::MOCK_SYS_CGROUPS_DIR).join(location);
+                assert!(!location.exists());
+                // The parent cgroup is kept.
+                assert!(location.parent().unwrap().exists());
+            }
+
+            // Removing cgroups that are already gone is fine.
+            remove_cgroups(&cgroups).unwrap();
+
+            // Folders that can't be removed are reported.
+            let location = Path::new(MockCgroupFs::MOCK_SYS_CGROUPS_DIR).join(locations[0]);
+            fs::create_dir_all(location.join("busy")).unwrap();
+            assert!(matches!(
+                remove_cgroups(&cgroups),
+                Err(Error::RemoveDir(path, _)) if path == location
+            ));
+        }
+    }
+
+    #[test]
+    fn test_remove_jail() {
+        let jail_base = TempDir::new().unwrap();
+        let jail_dir = jail_base.as_path().join("firecracker/101");
+        fs::create_dir_all(jail_dir.join("root/dev/net")).unwrap();
+        fs::write(jail_dir.join("root/firecracker"), "foo").unwrap();
+
+        // Resources mounted inside the jail from the host are unmounted, not removed.
+        let resources = TempDir::new().unwrap();
+        fs::write(resources.as_path().join("rootfs.ext4"), "bar").unwrap();
+        let mount_point = jail_dir.join("root/resources");
+        fs::create_dir(&mount_point).unwrap();
+        let src_cstr = to_cstring(resources.as_path()).unwrap();
+        let mount_point_cstr = to_cstring(&mount_point).unwrap();
+        assert_eq!(
+            unsafe {
+                libc::mount(
+                    src_cstr.as_ptr(),
+                    mount_point_cstr.as_ptr(),
+                    std::ptr::null(),
+                    libc::MS_BIND,
+                    std::ptr::null(),
+                )
+            },
+            0
+        );
+        assert_eq!(mount_points_in(&jail_dir).unwrap(), vec![mount_point]);
+
+        remove_jail(&jail_dir).unwrap();
+        assert!(!jail_dir.exists());
+        assert!(jail_base.as_path().join("firecracker").exists());
+        assert_eq!(
+            fs::read_to_string(resources.as_path().join("rootfs.ext4")).unwrap(),
+            "bar"
+        );
+
+        // Removing a jail that is already gone is fine.
+        remove_jail(&jail_dir).unwrap();
+    }
+}
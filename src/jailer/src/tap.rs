@@ -0,0 +1,326 @@
+// Copyright 2022 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::os::raw::{c_int, c_short, c_uint, c_ulong};
+use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::io::{AsRawFd, FromRawFd};
+
+use net_gen::ifreq;
+use utils::ioctl::{ioctl_with_mut_ref, ioctl_with_val};
+use utils::{ioctl_expr, ioctl_ioc_nr, ioctl_iow_nr};
+
+use crate::{Error, Result};
+
+const DEV_NET_TUN: &str = "/dev/net/tun";
+
+// As defined in the Linux UAPI:
+// https://elixir.bootlin.com/linux/v4.17/source/include/uapi/linux/if.h#L33
+const IFACE_NAME_MAX_LEN: usize = 16;
+
+const TUNTAP: c_uint = 84;
+ioctl_iow_nr!(TUNSETIFF, TUNTAP, 202, c_int);
+ioctl_iow_nr!(TUNSETPERSIST, TUNTAP, 203, c_int);
+ioctl_iow_nr!(TUNSETOWNER, TUNTAP, 204, c_int);
+ioctl_iow_nr!(TUNSETGROUP, TUNTAP, 206, c_int);
+ioctl_iow_nr!(TUNSETOFFLOAD, TUNTAP, 208, c_uint);
+
+// Names of the offload flags which can be enabled on a tap device.
+const OFFLOAD_FLAGS: [(&str, c_uint); 5] = [
+    ("csum", net_gen::TUN_F_CSUM),
+    ("tso4", net_gen::TUN_F_TSO4),
+    ("tso6", net_gen::TUN_F_TSO6),
+    ("tso_ecn", net_gen::TUN_F_TSO_ECN),
+    ("ufo", net_gen::TUN_F_UFO),
+];
+
+// Persistent tap device created by the jailer, as specified by a
+// `--tap <name>[:mtu=<mtu>][:offload=<flag>,...]` argument.
+#[derive(Debug, PartialEq)]
+pub struct TapDevice {
+    name: String,
+    mtu: Option<c_int>,
+    offload: Option<c_uint>,
+}
+
+impl TapDevice {
+    pub fn parse(arg: &str) -> Result<Self> {
+        let mut parts = arg.split(':');
+        // Ok to unwrap since split always yields at least one item.
+        let name = parts.next().unwrap();
+        // The kernel also rejects these names, but with a less helpful error.
+        if name.is_empty()
+            || name.len() >= IFACE_NAME_MAX_LEN
+            || name == "."
+            || name == ".."
+            || name.contains(|c: char| c == '/' || c.is_whitespace())
+        {
+            return Err(Error::TapInvalidName(name.to_string()));
+        }
+
+        let mut tap = TapDevice {
+            name: name.to_string(),
+            mtu: None,
+            offload: None,
+        };
+        for option in parts {
+            match option.split_once('=') {
+                Some(("mtu", value)) if tap.mtu.is_none() => {
+                    tap.mtu = Some(
+                        value
+                            .parse::<c_int>()
+                            .ok()
+                            .filter(|mtu| *mtu > 0)
+                            .ok_or_else(|| Error::TapFormat(arg.to_string()))?,
+                    );
+                }
+                Some(("offload", value)) if tap.offload.is_none() => {
+                    tap.offload = Some(
+                        Self::parse_offload(value)
+                            .ok_or_else(|| Error::TapFormat(arg.to_string()))?,
+                    );
+                }
+                _ => return Err(Error::TapFormat(arg.to_string())),
+            }
+        }
+
+        Ok(tap)
+    }
+
+    // Parses a comma separated list of offload flags. An empty list disables all offloads.
+    fn parse_offload(value: &str) -> Option<c_uint> {
+        if value.is_empty() {
+            return Some(0);
+        }
+        value.split(',').try_fold(0, |flags, name| {
+            OFFLOAD_FLAGS
+                .iter()
+                .find(|(flag_name, _)| *flag_name == name)
+                .map(|(_, flag)| flags | flag)
+        })
+    }
+
+    // Runs an ioctl on `fd`, taking a pointer to an ifreq for this tap device.
+    fn ifreq_ioctl<F: AsRawFd>(
+        &self,
+        fd: &F,
+        request: c_ulong,
+        request_name: &'static str,
+        ifreq: &mut ifreq,
+    ) -> Result<()> {
+        // Since we don't call as_mut on the same union field more than once, this block is safe.
+        let ifrn_name = unsafe { ifreq.ifr_ifrn.ifrn_name.as_mut() };
+        ifrn_name[..self.name.len()].copy_from_slice(self.name.as_bytes());
+
+        // ioctl is safe. Called with a valid fd, and we check the return.
+        if unsafe { ioctl_with_mut_ref(fd, request, ifreq) } < 0 {
+            return Err(Error::TapIoctl(
+                self.name.clone(),
+                request_name,
+                io::Error::last_os_error(),
+            ));
+        }
+        Ok(())
+    }
+
+    // Runs an ioctl on the tap file descriptor `tap`, taking an integer value.
+    fn val_ioctl(
+        &self,
+        tap: &File,
+        request: c_ulong,
+        request_name: &'static str,
+        value: c_ulong,
+    ) -> Result<()> {
+        // ioctl is safe. Called with a valid tap fd, and we check the return.
+        if unsafe { ioctl_with_val(tap, request, value) } < 0 {
+            return Err(Error::TapIoctl(
+                self.name.clone(),
+                request_name,
+                io::Error::last_os_error(),
+            ));
+        }
+        Ok(())
+    }
+
+    // Creates the tap device in the current network namespace, owned by `uid` and `gid`, so that
+    // the jailed process can attach to it without CAP_NET_ADMIN. The device is persistent, which
+    // means that it's only removed along with the network namespace, and is brought up.
+    pub fn create(&self, uid: u32, gid: u32) -> Result<()> {
+        let tap = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .custom_flags(libc::O_CLOEXEC)
+            .open(DEV_NET_TUN)
+            .map_err(Error::OpenTun)?;
+
+        // The flags must match the ones used by Firecracker when it attaches to the device.
+        let mut ifreq = ifreq::default();
+        // Since we don't call as_mut on the same union field more than once, this block is safe.
+        let ifru_flags = unsafe { ifreq.ifr_ifru.ifru_flags.as_mut() };
+        *ifru_flags = (net_gen::IFF_TAP | net_gen::IFF_NO_PI | net_gen::IFF_VNET_HDR) as c_short;
+        self.ifreq_ioctl(&tap, TUNSETIFF(), "TUNSETIFF", &mut ifreq)?;
+
+        if let Some(offload) = self.offload {
+            self.val_ioctl(
+                &tap,
+                TUNSETOFFLOAD(),
+                "TUNSETOFFLOAD",
+                c_ulong::from(offload),
+            )?;
+        }
+        self.val_ioctl(&tap, TUNSETOWNER(), "TUNSETOWNER", c_ulong::from(uid))?;
+        self.val_ioctl(&tap, TUNSETGROUP(), "TUNSETGROUP", c_ulong::from(gid))?;
+        self.val_ioctl(&tap, TUNSETPERSIST(), "TUNSETPERSIST", 1)?;
+
+        // The interface is configured through any socket of the network namespace.
+        // Safe because we're invoking a C library function with valid parameters, and we check
+        // the result.
+        let fd = unsafe { libc::socket(libc::AF_INET, libc::SOCK_DGRAM | libc::SOCK_CLOEXEC, 0) };
+        if fd < 0 {
+            return Err(Error::CreateSocket(io::Error::last_os_error()));
+        }
+        // We just checked that the fd is valid, and nothing else owns it.
+        let socket = unsafe { File::from_raw_fd(fd) };
+
+        if let Some(mtu) = self.mtu {
+            let mut ifreq = ifreq::default();
+            // Since we don't call as_mut on the same union field more than once, this block is
+            // safe.
+            unsafe { *ifreq.ifr_ifru.ifru_mtu.as_mut() = mtu };
+            self.ifreq_ioctl(
+                &socket,
+                c_ulong::from(net_gen::sockios::SIOCSIFMTU),
+                "SIOCSIFMTU",
+                &mut ifreq,
+            )?;
+        }
+
+        let mut ifreq = ifreq::default();
+        self.ifreq_ioctl(
+            &socket,
+            c_ulong::from(net_gen::sockios::SIOCGIFFLAGS),
+            "SIOCGIFFLAGS",
+            &mut ifreq,
+        )?;
+        // Safe because the flags were just filled in by the kernel.
+        unsafe {
+            *ifreq.ifr_ifru.ifru_flags.as_mut() |= net_gen::net_device_flags_IFF_UP as c_short
+        };
+        self.ifreq_ioctl(
+            &socket,
+            c_ulong::from(net_gen::sockios::SIOCSIFFLAGS),
+            "SIOCSIFFLAGS",
+            &mut ifreq,
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(
+            TapDevice::parse("tap0").unwrap(),
+            TapDevice {
+                name: "tap0".to_string(),
+                mtu: None,
+                offload: None,
+            }
+        );
+        assert_eq!(
+            TapDevice::parse("tap0:offload=csum,tso4:mtu=9000").unwrap(),
+            TapDevice {
+                name: "tap0".to_string(),
+                mtu: Some(9000),
+                offload: Some(net_gen::TUN_F_CSUM | net_gen::TUN_F_TSO4),
+            }
+        );
+        // An empty list of offloads disables all of them.
+        assert_eq!(TapDevice::parse("tap0:offload=").unwrap().offload, Some(0));
+
+        // Invalid names.
+        for arg in &[
+            "",
+            ":mtu=1500",
+            "a_very_long_name",
+            ".",
+            "..",
+            "tap/0",
+            "tap 0",
+        ] {
+            assert!(matches!(
+                TapDevice::parse(arg),
+                Err(Error::TapInvalidName(_))
+            ));
+        }
+
+        // Invalid options.
+        for arg in &[
+            "tap0:",
+            "tap0:mtu",
+            "tap0:mtu=0",
+            "tap0:mtu=-1",
+            "tap0:mtu=foo",
+            "tap0:mtu=1500:mtu=1500",
+            "tap0:offload=foo",
+            "tap0:offload=csum,",
+            "tap0:foo=bar",
+        ] {
+            assert!(matches!(
+                TapDevice::parse(arg),
+                Err(Error::TapFormat(ref a)) if a == arg
+            ));
+        }
+    }
+
+    #[test]
+    fn test_create() {
+        // Create the tap devices in a new network namespace, so they don't clash with the host
+        // interfaces, and are removed along with the namespace.
+        assert_eq!(unsafe { libc::unshare(libc::CLONE_NEWNET) }, 0);
+
+        let tap = TapDevice::parse("jailertap0:mtu=1400:offload=csum").unwrap();
+        tap.create(1001, 1002).unwrap();
+
+        // Creating the device again attaches to the existing one.
+        tap.create(1001, 1002).unwrap();
+
+        let socket = unsafe {
+            File::from_raw_fd(libc::socket(
+                libc::AF_INET,
+                libc::SOCK_DGRAM | libc::SOCK_CLOEXEC,
+                0,
+            ))
+        };
+        let mut ifreq = ifreq::default();
+        tap.ifreq_ioctl(
+            &socket,
+            c_ulong::from(net_gen::sockios::SIOCGIFMTU),
+            "SIOCGIFMTU",
+            &mut ifreq,
+        )
+        .unwrap();
+        assert_eq!(unsafe { *ifreq.ifr_ifru.ifru_mtu.as_ref() }, 1400);
+
+        let mut ifreq = ifreq::default();
+        tap.ifreq_ioctl(
+            &socket,
+            c_ulong::from(net_gen::sockios::SIOCGIFFLAGS),
+            "SIOCGIFFLAGS",
+            &mut ifreq,
+        )
+        .unwrap();
+        let flags = unsafe { *ifreq.ifr_ifru.ifru_flags.as_ref() };
+        assert_ne!(flags & net_gen::net_device_flags_IFF_UP as c_short, 0);
+
+        // Invalid configurations are reported by the kernel.
+        let tap = TapDevice::parse("jailertap1:mtu=100000").unwrap();
+        assert!(matches!(
+            tap.create(1001, 1002),
+            Err(Error::TapIoctl(_, "SIOCSIFMTU", _))
+        ));
+    }
+}
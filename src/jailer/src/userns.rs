@@ -0,0 +1,112 @@
+// Copyright 2022 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+use std::fs;
+use std::io;
+
+use crate::{Error, Result};
+use utils::syscall::SyscallReturnCode;
+
+// Builds the contents of an id map, which makes `id` available inside the user namespace.
+// Unprivileged users can only map their own id (`current_id`) on the host, so `id` maps to it.
+// When the jailer is started as root, `id` maps onto itself instead, so that the jailed process
+// never runs as root on the host, and root is mapped too, so the jailer keeps owning the files
+// it created before unsharing.
+fn id_map(id: u32, current_id: u32) -> String {
+    if current_id != 0 {
+        format!("{} {} 1\n", id, current_id)
+    } else if id == 0 {
+        "0 0 1\n".to_string()
+    } else {
+        format!("0 0 1\n{} {} 1\n", id, id)
+    }
+}
+
+// Writes the id maps of the process `pid`. Unprivileged processes must give up setgroups()
+// before they can write a gid map. The supplementary groups of the jailer, like the group
+// owning /dev/kvm, are kept.
+fn write_id_maps(pid: libc::pid_t, uid: u32, gid: u32, euid: u32, egid: u32) -> io::Result<()> {
+    if euid != 0 {
+        fs::write(format!("/proc/{}/setgroups", pid), "deny")?;
+    }
+    fs::write(format!("/proc/{}/uid_map", pid), id_map(uid, euid))?;
+    fs::write(format!("/proc/{}/gid_map", pid), id_map(gid, egid))
+}
+
+// Unshares into a new user namespace, along with a new mount namespace owned by it, and maps
+// the provided uid and gid in the user namespace. The jailer has all capabilities inside the
+// namespaces after this call, and loses them when it execs as `uid`.
+//
+// A process can only map ids other than its own if it has CAP_SETUID in the parent user
+// namespace, which the jailer loses as soon as it unshares. The maps are therefore written by a
+// helper process forked beforehand, which stays in the parent user namespace.
+pub fn unshare_user_ns(uid: u32, gid: u32) -> Result<()> {
+    // Safe because these calls cannot fail.
+    let (pid, euid, egid) = unsafe { (libc::getpid(), libc::geteuid(), libc::getegid()) };
+
+    let mut pipe_fds = [-1; 2];
+    // The call is safe because we're invoking a C library function with valid parameters.
+    SyscallReturnCode(unsafe { libc::pipe2(pipe_fds.as_mut_ptr(), libc::O_CLOEXEC) })
+        .into_empty_result()
+        .map_err(Error::UserNsSync)?;
+    let [read_fd, write_fd] = pipe_fds;
+
+    // Safe because the jailer is single threaded.
+    let helper_pid = SyscallReturnCode(unsafe { libc::fork() })
+        .into_result()
+        .map_err(Error::Clone)?;
+    if helper_pid == 0 {
+        // Wait for the jailer to unshare. It exits without signaling us if it fails to.
+        let mut byte = 0u8;
+        // Safe because we're providing valid fds and a valid buffer.
+        let exit_code = match unsafe {
+            libc::close(write_fd);
+            libc::read(read_fd, (&mut byte as *mut u8).cast(), 1)
+        } {
+            1 => write_id_maps(pid, uid, gid, euid, egid)
+                .map_or_else(|err| err.raw_os_error().unwrap_or(libc::EINVAL), |_| 0),
+            _ => 0,
+        };
+        // Safe because the helper doesn't own any resources that need to be cleaned up.
+        unsafe { libc::_exit(exit_code) }
+    }
+
+    // The call is safe because we're invoking a C library function with valid parameters.
+    SyscallReturnCode(unsafe { libc::unshare(libc::CLONE_NEWUSER | libc::CLONE_NEWNS) })
+        .into_empty_result()
+        .map_err(Error::UnshareNewUserNs)?;
+
+    // Signal the helper that it can write the id maps.
+    // Safe because we're providing a valid fd and a valid buffer.
+    SyscallReturnCode(unsafe { libc::write(write_fd, [1u8].as_ptr().cast(), 1) } as libc::c_int)
+        .into_empty_result()
+        .map_err(Error::UserNsSync)?;
+
+    let mut status = 0;
+    // Safe because we're waiting on our own child with a valid status pointer.
+    SyscallReturnCode(unsafe { libc::waitpid(helper_pid, &mut status, 0) })
+        .into_empty_result()
+        .map_err(Error::UserNsSync)?;
+    match (libc::WIFEXITED(status), libc::WEXITSTATUS(status)) {
+        (true, 0) => Ok(()),
+        (true, errno) => Err(Error::WriteIdMaps(io::Error::from_raw_os_error(errno))),
+        _ => Err(Error::WriteIdMaps(io::Error::from_raw_os_error(
+            libc::ECHILD,
+        ))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_id_map() {
+        // Unprivileged jailer.
+        assert_eq!(id_map(123, 1000), "123 1000 1\n");
+        assert_eq!(id_map(0, 1000), "0 1000 1\n");
+        // Privileged jailer.
+        assert_eq!(id_map(123, 0), "0 0 1\n123 123 1\n");
+        assert_eq!(id_map(0, 0), "0 0 1\n");
+    }
+}
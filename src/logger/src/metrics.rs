@@ -411,6 +411,10 @@ pub struct PutRequestsMetrics {
     pub mmds_count: SharedIncMetric,
     /// Number of failures in creating a new mmds.
     pub mmds_fails: SharedIncMetric,
+    /// Number of PUTs for defining a rate limiter group.
+    pub rate_limiters_count: SharedIncMetric,
+    /// Number of failures in defining a rate limiter group.
+    pub rate_limiters_fails: SharedIncMetric,
     /// Number of PUTs for creating a vsock device.
     pub vsock_count: SharedIncMetric,
     /// Number of failures in creating a vsock device.
@@ -436,6 +440,14 @@ pub struct PatchRequestsMetrics {
     pub mmds_count: SharedIncMetric,
     /// Number of failures in PATCHing an mmds.
     pub mmds_fails: SharedIncMetric,
+    /// Number of tries to PATCH a rate limiter group.
+    pub rate_limiters_count: SharedIncMetric,
+    /// Number of failures in PATCHing a rate limiter group.
+    pub rate_limiters_fails: SharedIncMetric,
+    /// Number of tries to PATCH a vsock device.
+    pub vsock_count: SharedIncMetric,
+    /// Number of failures in PATCHing a vsock device.
+    pub vsock_fails: SharedIncMetric,
 }
 
 /// Metrics related to deprecated user-facing API calls.
@@ -481,6 +493,10 @@ pub struct BlockDeviceMetrics {
     pub invalid_reqs_count: SharedIncMetric,
     /// Number of flushes operation triggered on this block device.
     pub flush_count: SharedIncMetric,
+    /// Number of successful discard operations.
+    pub discard_count: SharedIncMetric,
+    /// Number of successful write zeroes operations.
+    pub write_zeroes_count: SharedIncMetric,
     /// Number of events triggerd on the queue of this block device.
     pub queue_event_count: SharedIncMetric,
     /// Number of events ratelimiter-related.
@@ -499,9 +515,16 @@ pub struct BlockDeviceMetrics {
     pub write_count: SharedIncMetric,
     /// Number of rate limiter throttling events.
     pub rate_limiter_throttled_events: SharedIncMetric,
+    /// Number of rate limiter throttling events which delayed a read request.
+    pub read_rate_limiter_throttled_events: SharedIncMetric,
+    /// Number of rate limiter throttling events which delayed a write request.
+    pub write_rate_limiter_throttled_events: SharedIncMetric,
     /// Number of virtio events throttled because of the IO engine.
     /// This happens when the io_uring submission queue is full.
     pub io_engine_throttled_events: SharedIncMetric,
+    /// Number of requests in flight on each of the up to 16 queues of a block device, as last
+    /// updated by any block device.
+    pub queue_depth: [SharedStoreMetric; 16],
 }
 
 /// Metrics specific to the i8042 device.
@@ -559,6 +582,14 @@ pub struct MmdsMetrics {
     pub connections_created: SharedIncMetric,
     /// The number of connections cleaned up by the MMDS TCP handler.
     pub connections_destroyed: SharedIncMetric,
+    /// The number of session tokens issued by MMDS V2.
+    pub tokens_issued: SharedIncMetric,
+    /// The number of session tokens successfully validated by MMDS V2.
+    pub tokens_validated: SharedIncMetric,
+    /// The number of session tokens rejected by MMDS V2 as invalid or expired.
+    pub tokens_rejected: SharedIncMetric,
+    /// The number of responses replaced with an error for exceeding the maximum response size.
+    pub responses_too_large: SharedIncMetric,
 }
 
 /// Network-related metrics.
@@ -618,6 +649,26 @@ pub struct NetDeviceMetrics {
     pub tx_rate_limiter_throttled: SharedIncMetric,
     /// Number of packets with a spoofed mac, sent by the guest.
     pub tx_spoofed_mac_count: SharedIncMetric,
+    /// Number of TX frames dropped by the egress filter because of a spoofed source MAC.
+    pub tx_filter_spoofed_mac_drops: SharedIncMetric,
+    /// Number of TX frames dropped by the egress filter because of a spoofed source IP.
+    pub tx_filter_spoofed_ip_drops: SharedIncMetric,
+    /// Number of DHCP server frames dropped by the egress filter.
+    pub tx_filter_dhcp_server_drops: SharedIncMetric,
+    /// Number of IPv6 router advertisements dropped by the egress filter.
+    pub tx_filter_router_adv_drops: SharedIncMetric,
+    /// Number of malformed or unsupported TX frames dropped by the egress filter.
+    pub tx_filter_unsupported_drops: SharedIncMetric,
+    /// Number of TCP connections proxied by the user mode networking backend.
+    pub user_tcp_connections: SharedIncMetric,
+    /// Number of host connection attempts which failed in the user mode networking backend.
+    pub user_tcp_connect_fails: SharedIncMetric,
+    /// Number of UDP flows relayed by the user mode networking backend.
+    pub user_udp_flows: SharedIncMetric,
+    /// Number of frames dropped by the user mode networking backend.
+    pub user_dropped_frames: SharedIncMetric,
+    /// Number of failed host socket operations in the user mode networking backend.
+    pub user_socket_fails: SharedIncMetric,
 }
 
 /// Performance metrics related for the moment only to snapshots.
@@ -794,6 +845,16 @@ pub struct VsockDeviceMetrics {
     pub tx_write_fails: SharedIncMetric,
     /// Number of times read() has failed.
     pub rx_read_fails: SharedIncMetric,
+    /// Number of connections refused because of the connection limits.
+    pub conns_rejected: SharedIncMetric,
+    /// Number of events associated with the RX rate limiter.
+    pub rx_rate_limiter_event_count: SharedIncMetric,
+    /// Number of times the RX queue processing was throttled by the rate limiter.
+    pub rx_rate_limiter_throttled: SharedIncMetric,
+    /// Number of events associated with the TX rate limiter.
+    pub tx_rate_limiter_event_count: SharedIncMetric,
+    /// Number of times the TX queue processing was throttled by the rate limiter.
+    pub tx_rate_limiter_throttled: SharedIncMetric,
 }
 
 // The sole purpose of this struct is to produce an UTC timestamp when an instance is serialized.
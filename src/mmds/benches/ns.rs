@@ -0,0 +1,237 @@
+// Copyright 2022 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+//
+// Benchmark testing
+//
+// Measure how fast a guest can fetch metadata through the MMDS network stack:
+//  - a large response, with and without TCP window scaling
+//  - several small responses, with and without HTTP/1.1 pipelining
+
+use std::net::Ipv4Addr;
+use std::num::NonZeroUsize;
+use std::sync::{Arc, Mutex};
+
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+use dumbo::pdu::ethernet::{EthernetFrame, ETHERTYPE_IPV4};
+use dumbo::pdu::ipv4::{IPv4Packet, PROTOCOL_TCP};
+use dumbo::pdu::tcp::{Flags as TcpFlags, TcpSegment};
+use mmds::data_store::Mmds;
+use mmds::ns::{MmdsNetworkStack, MmdsNetworkStackConfig};
+use utils::net::mac::MacAddr;
+
+const GUEST_MAC: &str = "11:11:11:22:22:22";
+const MMDS_MAC: &str = "06:01:23:45:67:01";
+const GUEST_ADDR: Ipv4Addr = Ipv4Addr::new(169, 254, 0, 2);
+const GUEST_PORT: u16 = 40000;
+const MMDS_PORT: u16 = 80;
+const GUEST_MSS: u16 = 1460;
+const GUEST_ISN: u32 = 1000;
+const BLOB_SIZE: usize = 1 << 20;
+const SMALL_REQUESTS: usize = 16;
+
+// A minimal guest side TCP endpoint, which ACKs everything it receives right away.
+struct Guest {
+    seq: u32,
+    ack: u32,
+    window_scale: Option<u8>,
+}
+
+impl Guest {
+    fn write_frame(
+        &self,
+        buf: &mut [u8],
+        mmds_addr: Ipv4Addr,
+        flags: TcpFlags,
+        payload: &[u8],
+    ) -> usize {
+        let (mss_option, window_scale_option) = if flags.intersects(TcpFlags::SYN) {
+            (Some(GUEST_MSS), self.window_scale)
+        } else {
+            (None, None)
+        };
+        let payload = if payload.is_empty() {
+            None
+        } else {
+            Some((payload, payload.len()))
+        };
+
+        let mut eth = EthernetFrame::write_incomplete(
+            buf,
+            MacAddr::parse_str(MMDS_MAC).unwrap(),
+            MacAddr::parse_str(GUEST_MAC).unwrap(),
+            ETHERTYPE_IPV4,
+        )
+        .unwrap();
+        let packet_len = {
+            let mut packet = IPv4Packet::write_header(
+                eth.inner_mut().payload_mut(),
+                PROTOCOL_TCP,
+                GUEST_ADDR,
+                mmds_addr,
+            )
+            .unwrap();
+            let segment_len = TcpSegment::write_incomplete_segment::<[u8]>(
+                packet.inner_mut().payload_mut(),
+                self.seq,
+                self.ack,
+                flags,
+                // 64 KiB, or more once the window scale option is in effect.
+                u16::max_value(),
+                mss_option,
+                window_scale_option,
+                GUEST_MSS,
+                payload,
+            )
+            .unwrap()
+            .finalize(GUEST_PORT, MMDS_PORT, Some((GUEST_ADDR, mmds_addr)))
+            .len();
+            packet.with_payload_len_unchecked(segment_len, true).len()
+        };
+        eth.with_payload_len_unchecked(packet_len).len()
+    }
+
+    fn send(&mut self, ns: &mut MmdsNetworkStack, flags: TcpFlags, payload: &[u8]) {
+        let mut buf = [0u8; 4096];
+        let len = self.write_frame(&mut buf, ns.ipv4_addr(), flags, payload);
+        assert!(ns.detour_frame(&buf[..len]));
+        self.seq = self.seq.wrapping_add(payload.len() as u32);
+    }
+
+    // Reads every frame the MMDS has to send, ACKing them in bursts, until the MMDS goes quiet.
+    // Returns the number of payload bytes received.
+    fn receive_all(&mut self, ns: &mut MmdsNetworkStack) -> usize {
+        let mut buf = vec![0u8; 65536];
+        let mut received = 0;
+        loop {
+            let mut burst = 0;
+            while let Some(len) = ns.write_next_frame(&mut buf) {
+                let eth = EthernetFrame::from_bytes(&buf[..len.get()]).unwrap();
+                let packet = IPv4Packet::from_bytes(eth.payload(), false).unwrap();
+                let segment = TcpSegment::from_bytes(packet.payload(), None).unwrap();
+                let payload_len = segment.payload_len();
+                if segment.flags_after_ns().intersects(TcpFlags::SYN) {
+                    self.ack = segment.sequence_number().wrapping_add(1);
+                } else {
+                    self.ack = segment.sequence_number().wrapping_add(payload_len as u32);
+                }
+                burst += payload_len;
+            }
+            if burst == 0 {
+                return received;
+            }
+            received += burst;
+            self.send(ns, TcpFlags::ACK, &[]);
+        }
+    }
+
+    fn connect(&mut self, ns: &mut MmdsNetworkStack) {
+        self.send(ns, TcpFlags::SYN, &[]);
+        self.seq = self.seq.wrapping_add(1);
+        self.receive_all(ns);
+    }
+
+    fn reset(&mut self, ns: &mut MmdsNetworkStack) {
+        self.send(ns, TcpFlags::RST, &[]);
+    }
+}
+
+fn network_stack() -> MmdsNetworkStack {
+    let mut mmds = Mmds::default();
+    mmds.set_data_store_limit(2 * BLOB_SIZE);
+    let mut data = serde_json::Map::new();
+    data.insert(
+        "blob".to_string(),
+        serde_json::Value::String("x".repeat(BLOB_SIZE)),
+    );
+    for i in 0..SMALL_REQUESTS {
+        data.insert(
+            format!("key{}", i),
+            serde_json::Value::String(i.to_string()),
+        );
+    }
+    mmds.put_data(serde_json::Value::Object(data)).unwrap();
+
+    let config = MmdsNetworkStackConfig {
+        max_response_size: NonZeroUsize::new(2 * BLOB_SIZE),
+        ..Default::default()
+    };
+    MmdsNetworkStack::new_with_config(None, config, Arc::new(Mutex::new(mmds)))
+}
+
+fn get_request(path: &str) -> String {
+    format!("GET http://169.254.169.254/{} HTTP/1.1\r\n\r\n", path)
+}
+
+fn fetch_blob(ns: &mut MmdsNetworkStack, window_scale: Option<u8>) -> usize {
+    let mut guest = Guest {
+        seq: GUEST_ISN,
+        ack: 0,
+        window_scale,
+    };
+    guest.connect(ns);
+    guest.send(ns, TcpFlags::ACK, get_request("blob").as_bytes());
+    let received = guest.receive_all(ns);
+    guest.reset(ns);
+    assert!(received > BLOB_SIZE);
+    received
+}
+
+fn fetch_keys(ns: &mut MmdsNetworkStack, pipelined: bool) -> usize {
+    let mut guest = Guest {
+        seq: GUEST_ISN,
+        ack: 0,
+        window_scale: None,
+    };
+    guest.connect(ns);
+    let mut received = 0;
+    if pipelined {
+        let requests: String = (0..SMALL_REQUESTS)
+            .map(|i| get_request(&format!("key{}", i)))
+            .collect();
+        guest.send(ns, TcpFlags::ACK, requests.as_bytes());
+        received += guest.receive_all(ns);
+    } else {
+        for i in 0..SMALL_REQUESTS {
+            guest.send(
+                ns,
+                TcpFlags::ACK,
+                get_request(&format!("key{}", i)).as_bytes(),
+            );
+            received += guest.receive_all(ns);
+        }
+    }
+    guest.reset(ns);
+    received
+}
+
+pub fn criterion_benchmark(c: &mut Criterion) {
+    let mut ns = network_stack();
+
+    let mut group = c.benchmark_group("mmds_large_response");
+    group.bench_function("no_window_scaling", |b| {
+        b.iter(|| fetch_blob(black_box(&mut ns), None))
+    });
+    group.bench_function("window_scaling", |b| {
+        b.iter(|| fetch_blob(black_box(&mut ns), Some(7)))
+    });
+    group.finish();
+
+    let mut group = c.benchmark_group("mmds_small_responses");
+    group.bench_function("sequential", |b| {
+        b.iter(|| fetch_keys(black_box(&mut ns), false))
+    });
+    group.bench_function("pipelined", |b| {
+        b.iter(|| fetch_keys(black_box(&mut ns), true))
+    });
+    group.finish();
+}
+
+criterion_group! {
+    name = benches;
+    config = Criterion::default().sample_size(10);
+    targets = criterion_benchmark
+}
+
+criterion_main! {
+    benches
+}
@@ -7,12 +7,16 @@ use serde::{Deserialize, Serialize};
 use serde_json::{to_vec, Value};
 use std::fmt;
 use std::fmt::{Display, Formatter};
+use std::net::SocketAddrV4;
 
 /// The Mmds is the Microvm Metadata Service represented as an untyped json.
 pub struct Mmds {
+    // None for the default data store.
+    name: Option<String>,
     data_store: Value,
     // None when MMDS V1 is configured, Some for MMDS V2.
     token_authority: Option<TokenAuthority>,
+    token_binding: TokenBinding,
     is_initialized: bool,
     data_store_limit: usize,
 }
@@ -39,6 +43,31 @@ impl Display for MmdsVersion {
     }
 }
 
+/// Guest endpoint attributes that MMDS session tokens are bound to.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
+pub enum TokenBinding {
+    /// Tokens are accepted from any guest endpoint.
+    None,
+    /// Tokens are accepted only from the source IPv4 address they were issued to.
+    SourceIp,
+    /// Tokens are accepted only from the source IPv4 address and TCP port
+    /// they were issued to.
+    SourceIpAndPort,
+}
+
+impl TokenBinding {
+    /// Returns true if tokens are not bound to the guest endpoint.
+    pub fn is_none(&self) -> bool {
+        *self == TokenBinding::None
+    }
+}
+
+impl Default for TokenBinding {
+    fn default() -> Self {
+        TokenBinding::None
+    }
+}
+
 /// MMDS possible outputs.
 pub enum OutputFormat {
     Json,
@@ -94,8 +123,10 @@ impl fmt::Display for Error {
 impl Default for Mmds {
     fn default() -> Self {
         Mmds {
+            name: None,
             data_store: Value::default(),
             token_authority: None,
+            token_binding: TokenBinding::default(),
             is_initialized: false,
             data_store_limit: MAX_DATA_STORE_SIZE,
         }
@@ -103,6 +134,19 @@ impl Default for Mmds {
 }
 
 impl Mmds {
+    /// Creates an empty data store, identified by `name` among the data stores of the microVM.
+    pub fn new_named(name: String) -> Self {
+        Mmds {
+            name: Some(name),
+            ..Default::default()
+        }
+    }
+
+    /// Returns the name of the data store, or `None` for the default data store.
+    pub fn name(&self) -> Option<&str> {
+        self.name.as_deref()
+    }
+
     /// This method is needed to check if data store is initialized.
     /// When a PATCH request is made on an uninitialized Mmds structure this method
     /// should return a NotFound error.
@@ -148,32 +192,76 @@ impl Mmds {
         }
     }
 
-    /// Checks if the provided token has not expired.
-    pub fn is_valid_token(&self, token: &str) -> Result<bool, TokenError> {
+    /// Sets the guest endpoint attributes that session tokens are bound to.
+    pub fn set_token_binding(&mut self, token_binding: TokenBinding) {
+        self.token_binding = token_binding;
+    }
+
+    /// Returns the guest endpoint attributes that session tokens are bound to.
+    pub fn token_binding(&self) -> TokenBinding {
+        self.token_binding
+    }
+
+    // Identifies `client` according to the configured token binding.
+    fn token_client(&self, client: SocketAddrV4) -> Option<String> {
+        match self.token_binding {
+            TokenBinding::None => None,
+            TokenBinding::SourceIp => Some(client.ip().to_string()),
+            TokenBinding::SourceIpAndPort => Some(client.to_string()),
+        }
+    }
+
+    /// Checks if the provided token has not expired and was issued to `client`,
+    /// if tokens are bound to the guest endpoint.
+    pub fn is_valid_token(&self, token: &str, client: SocketAddrV4) -> Result<bool, TokenError> {
+        let token_client = self.token_client(client);
         self.token_authority
             .as_ref()
             .ok_or(TokenError::InvalidState)
-            .map(|ta| ta.is_valid(token))
+            .map(|ta| ta.is_valid(token, token_client.as_deref()))
     }
 
-    /// Generate a new Mmds token using the token authority.
-    pub fn generate_token(&mut self, ttl_seconds: u32) -> Result<String, TokenError> {
+    /// Generate a new Mmds token for `client` using the token authority.
+    pub fn generate_token(
+        &mut self,
+        ttl_seconds: u32,
+        client: SocketAddrV4,
+    ) -> Result<String, TokenError> {
+        let token_client = self.token_client(client);
         self.token_authority
             .as_mut()
             .ok_or(TokenError::InvalidState)
-            .and_then(|ta| ta.generate_token_secret(ttl_seconds))
+            .and_then(|ta| ta.generate_token_secret(ttl_seconds, token_client.as_deref()))
+    }
+
+    /// Invalidates all the tokens issued so far by rotating the token authority key.
+    /// There are no tokens to revoke when MMDS version 1 is configured.
+    pub fn revoke_tokens(&mut self) -> Result<(), Error> {
+        match self.token_authority.as_mut() {
+            Some(ta) => ta.rotate_key().map_err(Error::TokenAuthority),
+            None => Ok(()),
+        }
     }
 
     pub fn set_data_store_limit(&mut self, data_store_limit: usize) {
         self.data_store_limit = data_store_limit;
     }
 
-    // We do not check data_store size here because a request with a body
-    // bigger than the imposed limit will be stopped by micro_http before
-    // reaching here.
-    pub fn put_data(&mut self, data: Value) {
+    pub fn data_store_limit(&self) -> usize {
+        self.data_store_limit
+    }
+
+    // A request with a body bigger than the API server payload limit is stopped by
+    // micro_http before reaching here, but the data store limit can be lower than that.
+    pub fn put_data(&mut self, data: Value) -> Result<(), Error> {
+        // It is safe to unwrap because our data store keys are all strings and
+        // we are using default serializer which does not return error.
+        if to_vec(&data).unwrap().len() > self.data_store_limit {
+            return Err(Error::DataStoreLimitExceeded);
+        }
         self.data_store = data;
         self.is_initialized = true;
+        Ok(())
     }
 
     pub fn patch_data(&mut self, patch_data: Value) -> Result<(), Error> {
@@ -290,6 +378,11 @@ impl Mmds {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use std::net::Ipv4Addr;
+
+    fn client(port: u16) -> SocketAddrV4 {
+        SocketAddrV4::new(Ipv4Addr::new(169, 254, 0, 1), port)
+    }
 
     impl Mmds {
         pub fn get_data_str(&self) -> String {
@@ -334,7 +427,8 @@ mod tests {
 
         let mut mmds_json = "{\"meta-data\":{\"iam\":\"dummy\"},\"user-data\":\"1522850095\"}";
 
-        mmds.put_data(serde_json::from_str(mmds_json).unwrap());
+        mmds.put_data(serde_json::from_str(mmds_json).unwrap())
+            .unwrap();
         assert!(mmds.check_data_store_initialized().is_ok());
 
         assert_eq!(mmds.get_data_str(), mmds_json);
@@ -365,7 +459,7 @@ mod tests {
             "balance": -24
         }"#;
         let data_store: Value = serde_json::from_str(data).unwrap();
-        mmds.put_data(data_store);
+        mmds.put_data(data_store).unwrap();
 
         // Test invalid path.
         assert_eq!(
@@ -517,7 +611,7 @@ mod tests {
             "age": "43"
         }"#;
         let data_store: Value = serde_json::from_str(data).unwrap();
-        mmds.put_data(data_store);
+        mmds.put_data(data_store).unwrap();
 
         let data = r#"{
             "name": {
@@ -537,7 +631,7 @@ mod tests {
             "age": 43
         }"#;
         let data_store: Value = serde_json::from_str(data).unwrap();
-        mmds.put_data(data_store);
+        mmds.put_data(data_store).unwrap();
 
         let data = r#"{
             "name": {
@@ -576,6 +670,26 @@ mod tests {
         assert_eq!(mmds.get_data_str().len(), 72);
     }
 
+    #[test]
+    fn test_named_data_store() {
+        assert!(Mmds::default().name().is_none());
+
+        let mut mmds = Mmds::new_named("sidecar".to_string());
+        assert_eq!(mmds.name(), Some("sidecar"));
+        assert_eq!(mmds.version(), MmdsVersion::V1);
+
+        mmds.set_data_store_limit(16);
+        let data_store: Value = serde_json::from_str("{\"key\": \"value\"}").unwrap();
+        mmds.put_data(data_store).unwrap();
+
+        let data_store: Value = serde_json::from_str("{\"key\": \"long_value\"}").unwrap();
+        assert_eq!(
+            mmds.put_data(data_store).unwrap_err().to_string(),
+            Error::DataStoreLimitExceeded.to_string()
+        );
+        assert_eq!(mmds.get_data_str(), "{\"key\":\"value\"}");
+    }
+
     #[test]
     fn test_is_valid() {
         let mut mmds = Mmds::default();
@@ -583,11 +697,13 @@ mod tests {
         mmds.set_version(MmdsVersion::V2).unwrap();
         assert_eq!(mmds.version(), MmdsVersion::V2);
 
-        assert!(!mmds.is_valid_token("aaa").unwrap());
+        assert!(!mmds.is_valid_token("aaa", client(1)).unwrap());
 
         mmds.token_authority = None;
         assert_eq!(
-            mmds.is_valid_token("aaa").unwrap_err().to_string(),
+            mmds.is_valid_token("aaa", client(1))
+                .unwrap_err()
+                .to_string(),
             TokenError::InvalidState.to_string()
         )
     }
@@ -599,13 +715,59 @@ mod tests {
         mmds.set_version(MmdsVersion::V2).unwrap();
         assert_eq!(mmds.version(), MmdsVersion::V2);
 
-        let token = mmds.generate_token(1).unwrap();
-        assert!(mmds.is_valid_token(&token).unwrap());
+        let token = mmds.generate_token(1, client(1)).unwrap();
+        assert!(mmds.is_valid_token(&token, client(1)).unwrap());
 
         mmds.token_authority = None;
         assert_eq!(
-            mmds.generate_token(1).err().unwrap().to_string(),
+            mmds.generate_token(1, client(1)).err().unwrap().to_string(),
             TokenError::InvalidState.to_string()
         );
     }
+
+    #[test]
+    fn test_token_binding() {
+        let mut mmds = Mmds::default();
+        mmds.set_version(MmdsVersion::V2).unwrap();
+        assert_eq!(mmds.token_binding(), TokenBinding::None);
+
+        // Unbound tokens are accepted from any guest endpoint.
+        let token = mmds.generate_token(60, client(1)).unwrap();
+        assert!(mmds.is_valid_token(&token, client(2)).unwrap());
+
+        // Tokens bound to the source address are accepted from any port of that address.
+        mmds.set_token_binding(TokenBinding::SourceIp);
+        assert!(!mmds.is_valid_token(&token, client(1)).unwrap());
+        let token = mmds.generate_token(60, client(1)).unwrap();
+        assert!(mmds.is_valid_token(&token, client(2)).unwrap());
+        let other_ip = SocketAddrV4::new(Ipv4Addr::new(169, 254, 0, 2), 1);
+        assert!(!mmds.is_valid_token(&token, other_ip).unwrap());
+
+        // Tokens bound to the source address and port.
+        mmds.set_token_binding(TokenBinding::SourceIpAndPort);
+        let token = mmds.generate_token(60, client(1)).unwrap();
+        assert!(mmds.is_valid_token(&token, client(1)).unwrap());
+        assert!(!mmds.is_valid_token(&token, client(2)).unwrap());
+
+        // Switching versions keeps the token binding.
+        mmds.set_version(MmdsVersion::V1).unwrap();
+        mmds.set_version(MmdsVersion::V2).unwrap();
+        assert_eq!(mmds.token_binding(), TokenBinding::SourceIpAndPort);
+    }
+
+    #[test]
+    fn test_revoke_tokens() {
+        let mut mmds = Mmds::default();
+        // No tokens to revoke for MMDS V1.
+        mmds.revoke_tokens().unwrap();
+
+        mmds.set_version(MmdsVersion::V2).unwrap();
+        let token = mmds.generate_token(60, client(1)).unwrap();
+        assert!(mmds.is_valid_token(&token, client(1)).unwrap());
+
+        mmds.revoke_tokens().unwrap();
+        assert!(!mmds.is_valid_token(&token, client(1)).unwrap());
+        let token = mmds.generate_token(60, client(1)).unwrap();
+        assert!(mmds.is_valid_token(&token, client(1)).unwrap());
+    }
 }
@@ -9,12 +9,15 @@ pub mod token_headers;
 
 use serde_json::{Map, Value};
 use std::fmt;
+use std::net::SocketAddrV4;
+use std::num::NonZeroUsize;
 use std::sync::{Arc, Mutex};
 
 use crate::data_store::{Error as MmdsError, Mmds, MmdsVersion, OutputFormat};
 use crate::token::PATH_TO_TOKEN;
 
 use crate::token_headers::REJECTED_HEADER;
+use logger::{IncMetric, METRICS};
 use micro_http::{
     Body, HttpHeaderError, MediaType, Method, Request, RequestError, Response, StatusCode, Version,
 };
@@ -29,6 +32,7 @@ pub enum Error {
     NoTokenProvided,
     NoTtlProvided,
     ResourceNotFound(String),
+    ResponseTooLarge(usize),
 }
 
 impl fmt::Display for Error {
@@ -50,6 +54,11 @@ impl fmt::Display for Error {
             Error::ResourceNotFound(ref uri) => {
                 write!(f, "{}", format!("Resource not found: {}.", uri))
             }
+            Error::ResponseTooLarge(max_size) => write!(
+                f,
+                "The response exceeds the maximum size of {} bytes.",
+                max_size
+            ),
         }
     }
 }
@@ -111,7 +120,12 @@ fn sanitize_uri(mut uri: String) -> String {
     uri
 }
 
-pub fn convert_to_response(mmds: Arc<Mutex<Mmds>>, request: Request) -> Response {
+/// Builds the response to a `request` received from the guest endpoint `client`.
+pub fn convert_to_response(
+    mmds: Arc<Mutex<Mmds>>,
+    request: Request,
+    client: SocketAddrV4,
+) -> Response {
     let uri = request.uri().get_abs_path();
     if uri.is_empty() {
         return build_response(
@@ -125,10 +139,34 @@ pub fn convert_to_response(mmds: Arc<Mutex<Mmds>>, request: Request) -> Response
 
     match mmds_guard.version() {
         MmdsVersion::V1 => respond_to_request_mmdsv1(&mmds_guard, request),
-        MmdsVersion::V2 => respond_to_request_mmdsv2(&mut mmds_guard, request),
+        MmdsVersion::V2 => respond_to_request_mmdsv2(&mut mmds_guard, request, client),
     }
 }
 
+/// Replaces `response` with a `413 Payload Too Large` error when its body is larger than
+/// `max_size` bytes. Responses are left untouched when no limit is given.
+pub fn limit_response_size(
+    http_version: Version,
+    response: Response,
+    max_size: Option<NonZeroUsize>,
+) -> Response {
+    let max_size = match max_size {
+        Some(max_size) => max_size.get(),
+        None => return response,
+    };
+
+    if response.body().map_or(0, |body| body.len()) <= max_size {
+        return response;
+    }
+
+    METRICS.mmds.responses_too_large.inc();
+    build_response(
+        http_version,
+        StatusCode::PayloadTooLarge,
+        Body::new(Error::ResponseTooLarge(max_size).to_string()),
+    )
+}
+
 fn respond_to_request_mmdsv1(mmds: &Mmds, request: Request) -> Response {
     // Allow only GET requests.
     match request.method() {
@@ -145,7 +183,7 @@ fn respond_to_request_mmdsv1(mmds: &Mmds, request: Request) -> Response {
     }
 }
 
-fn respond_to_request_mmdsv2(mmds: &mut Mmds, request: Request) -> Response {
+fn respond_to_request_mmdsv2(mmds: &mut Mmds, request: Request, client: SocketAddrV4) -> Response {
     // Fetch custom headers from request.
     let token_headers = match TokenHeaders::try_from(request.headers.custom_entries()) {
         Ok(token_headers) => token_headers,
@@ -160,8 +198,8 @@ fn respond_to_request_mmdsv2(mmds: &mut Mmds, request: Request) -> Response {
 
     // Allow only GET and PUT requests.
     match request.method() {
-        Method::Get => respond_to_get_request_checked(mmds, request, token_headers),
-        Method::Put => respond_to_put_request(mmds, request, token_headers),
+        Method::Get => respond_to_get_request_checked(mmds, request, token_headers, client),
+        Method::Put => respond_to_put_request(mmds, request, token_headers, client),
         _ => {
             let mut response = build_response(
                 request.http_version(),
@@ -179,6 +217,7 @@ fn respond_to_get_request_checked(
     mmds: &Mmds,
     request: Request,
     token_headers: TokenHeaders,
+    client: SocketAddrV4,
 ) -> Response {
     // Get MMDS token from custom headers.
     let token = match token_headers.x_metadata_token() {
@@ -194,13 +233,19 @@ fn respond_to_get_request_checked(
     };
 
     // Validate MMDS token.
-    match mmds.is_valid_token(token) {
-        Ok(true) => respond_to_get_request_unchecked(mmds, request),
-        Ok(false) => build_response(
-            request.http_version(),
-            StatusCode::Unauthorized,
-            Body::new(Error::InvalidToken.to_string()),
-        ),
+    match mmds.is_valid_token(token, client) {
+        Ok(true) => {
+            METRICS.mmds.tokens_validated.inc();
+            respond_to_get_request_unchecked(mmds, request)
+        }
+        Ok(false) => {
+            METRICS.mmds.tokens_rejected.inc();
+            build_response(
+                request.http_version(),
+                StatusCode::Unauthorized,
+                Body::new(Error::InvalidToken.to_string()),
+            )
+        }
         Err(_) => unreachable!(),
     }
 }
@@ -246,6 +291,7 @@ fn respond_to_put_request(
     mmds: &mut Mmds,
     request: Request,
     token_headers: TokenHeaders,
+    client: SocketAddrV4,
 ) -> Response {
     // Reject `PUT` requests that contain `X-Forwarded-For` header.
     if request
@@ -291,9 +337,10 @@ fn respond_to_put_request(
     };
 
     // Generate token.
-    let result = mmds.generate_token(ttl_seconds);
+    let result = mmds.generate_token(ttl_seconds, client);
     match result {
         Ok(token) => {
+            METRICS.mmds.tokens_issued.inc();
             let mut response =
                 build_response(request.http_version(), StatusCode::OK, Body::new(token));
             response.set_content_type(MediaType::PlainText);
@@ -310,9 +357,15 @@ fn respond_to_put_request(
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::data_store::TokenBinding;
     use crate::token::{MAX_TOKEN_TTL_SECONDS, MIN_TOKEN_TTL_SECONDS};
+    use std::net::Ipv4Addr;
     use std::time::Duration;
 
+    fn client() -> SocketAddrV4 {
+        SocketAddrV4::new(Ipv4Addr::new(169, 254, 0, 1), 1234)
+    }
+
     fn populate_mmds() -> Arc<Mutex<Mmds>> {
         let data = r#"{
             "name": {
@@ -331,7 +384,8 @@ mod tests {
         let mmds = Arc::new(Mutex::new(Mmds::default()));
         mmds.lock()
             .expect("Poisoned lock")
-            .put_data(serde_json::from_str(data).unwrap());
+            .put_data(serde_json::from_str(data).unwrap())
+            .unwrap();
 
         mmds
     }
@@ -372,6 +426,40 @@ mod tests {
         assert_eq!(sanitize_uri("//aa//bb///cc//d".to_owned()), "/aa/bb/cc/d");
     }
 
+    #[test]
+    fn test_limit_response_size() {
+        let response = || {
+            build_response(
+                Version::Http10,
+                StatusCode::OK,
+                Body::new("0123456789".to_string()),
+            )
+        };
+
+        // No limit, or a limit which is not exceeded.
+        assert_eq!(
+            limit_response_size(Version::Http10, response(), None),
+            response()
+        );
+        assert_eq!(
+            limit_response_size(Version::Http10, response(), NonZeroUsize::new(10)),
+            response()
+        );
+
+        // The body is larger than the limit.
+        let count = METRICS.mmds.responses_too_large.count();
+        let expected = build_response(
+            Version::Http10,
+            StatusCode::PayloadTooLarge,
+            Body::new(Error::ResponseTooLarge(9).to_string()),
+        );
+        assert_eq!(
+            limit_response_size(Version::Http10, response(), NonZeroUsize::new(9)),
+            expected
+        );
+        assert!(METRICS.mmds.responses_too_large.count() > count);
+    }
+
     #[test]
     fn test_respond_to_request_mmdsv1() {
         // Populate MMDS with data.
@@ -394,7 +482,7 @@ mod tests {
         expected_response.set_body(Body::new(
             Error::ResourceNotFound(String::from("/invalid")).to_string(),
         ));
-        let actual_response = convert_to_response(mmds.clone(), request);
+        let actual_response = convert_to_response(mmds.clone(), request, client());
         assert_eq!(actual_response, expected_response);
 
         // Test NotImplemented.
@@ -403,7 +491,7 @@ mod tests {
         let mut expected_response = Response::new(Version::Http11, StatusCode::NotImplemented);
         let body = "Cannot retrieve value. The value has an unsupported type.".to_string();
         expected_response.set_body(Body::new(body));
-        let actual_response = convert_to_response(mmds.clone(), request);
+        let actual_response = convert_to_response(mmds.clone(), request, client());
         assert_eq!(actual_response, expected_response);
 
         // Test not allowed HTTP Method.
@@ -415,7 +503,7 @@ mod tests {
                 Response::new(Version::Http10, StatusCode::MethodNotAllowed);
             expected_response.set_body(Body::new(Error::MethodNotAllowed.to_string()));
             expected_response.allow_method(Method::Get);
-            let actual_response = convert_to_response(mmds.clone(), request);
+            let actual_response = convert_to_response(mmds.clone(), request, client());
             assert_eq!(actual_response, expected_response);
         }
 
@@ -424,7 +512,7 @@ mod tests {
         let request = Request::try_from(request_bytes, None).unwrap();
         let mut expected_response = Response::new(Version::Http10, StatusCode::BadRequest);
         expected_response.set_body(Body::new(Error::InvalidURI.to_string()));
-        let actual_response = convert_to_response(mmds.clone(), request);
+        let actual_response = convert_to_response(mmds.clone(), request, client());
         assert_eq!(actual_response, expected_response);
 
         // Test invalid custom header value is ignored when V1 is configured.
@@ -434,7 +522,7 @@ mod tests {
         let request = Request::try_from(request_bytes, None).unwrap();
         let mut expected_response = Response::new(Version::Http10, StatusCode::OK);
         expected_response.set_body(Body::new("\"John\""));
-        let actual_response = convert_to_response(mmds.clone(), request);
+        let actual_response = convert_to_response(mmds.clone(), request, client());
         assert_eq!(actual_response, expected_response);
 
         // Test Ok path.
@@ -445,7 +533,7 @@ mod tests {
         let mut body = get_json_data().to_string();
         body.retain(|c| !c.is_whitespace());
         expected_response.set_body(Body::new(body));
-        let actual_response = convert_to_response(mmds, request);
+        let actual_response = convert_to_response(mmds, request, client());
         assert_eq!(actual_response, expected_response);
     }
 
@@ -471,7 +559,7 @@ mod tests {
         expected_response.set_body(Body::new(Error::MethodNotAllowed.to_string()));
         expected_response.allow_method(Method::Get);
         expected_response.allow_method(Method::Put);
-        let actual_response = convert_to_response(mmds.clone(), request);
+        let actual_response = convert_to_response(mmds.clone(), request, client());
         assert_eq!(actual_response, expected_response);
 
         // Test invalid value for custom header.
@@ -485,7 +573,7 @@ mod tests {
             Key:X-metadata-token-ttl-seconds; Value:application/json"
                 .to_string(),
         ));
-        let actual_response = convert_to_response(mmds.clone(), request);
+        let actual_response = convert_to_response(mmds.clone(), request, client());
         assert_eq!(actual_response, expected_response);
 
         // Test PUT requests.
@@ -497,7 +585,7 @@ mod tests {
         expected_response.set_body(Body::new(
             "Invalid header. Reason: Unsupported header name. Key: X-Forwarded-For".to_string(),
         ));
-        let actual_response = convert_to_response(mmds.clone(), request);
+        let actual_response = convert_to_response(mmds.clone(), request, client());
         assert_eq!(actual_response, expected_response);
 
         // Test invalid path.
@@ -508,7 +596,7 @@ mod tests {
         expected_response.set_body(Body::new(
             Error::ResourceNotFound(String::from("/token")).to_string(),
         ));
-        let actual_response = convert_to_response(mmds.clone(), request);
+        let actual_response = convert_to_response(mmds.clone(), request, client());
         assert_eq!(actual_response, expected_response);
 
         // Test invalid lifetime values for token.
@@ -527,7 +615,7 @@ mod tests {
                 invalid_value, MIN_TOKEN_TTL_SECONDS, MAX_TOKEN_TTL_SECONDS
             );
             expected_response.set_body(Body::new(error_msg));
-            let actual_response = convert_to_response(mmds.clone(), request);
+            let actual_response = convert_to_response(mmds.clone(), request, client());
             assert_eq!(actual_response, expected_response);
         }
 
@@ -536,14 +624,14 @@ mod tests {
         let request = Request::try_from(request_bytes, None).unwrap();
         let mut expected_response = Response::new(Version::Http10, StatusCode::BadRequest);
         expected_response.set_body(Body::new(Error::NoTtlProvided.to_string()));
-        let actual_response = convert_to_response(mmds.clone(), request);
+        let actual_response = convert_to_response(mmds.clone(), request, client());
         assert_eq!(actual_response, expected_response);
 
         // Test valid PUT.
         let request_bytes = b"PUT http://169.254.169.254/latest/api/token HTTP/1.0\r\n\
                                     X-metadata-token-ttl-seconds: 60\r\n\r\n";
         let request = Request::try_from(request_bytes, None).unwrap();
-        let actual_response = convert_to_response(mmds.clone(), request);
+        let actual_response = convert_to_response(mmds.clone(), request, client());
         assert_eq!(actual_response.status(), StatusCode::OK);
         assert_eq!(actual_response.content_type(), MediaType::PlainText);
 
@@ -560,7 +648,7 @@ mod tests {
         let mut body = get_json_data().to_string();
         body.retain(|c| !c.is_whitespace());
         expected_response.set_body(Body::new(body));
-        let actual_response = convert_to_response(mmds.clone(), request);
+        let actual_response = convert_to_response(mmds.clone(), request, client());
         assert_eq!(actual_response, expected_response);
 
         // Test GET request towards unsupported value type.
@@ -573,7 +661,7 @@ mod tests {
         let mut expected_response = Response::new(Version::Http11, StatusCode::NotImplemented);
         let body = "Cannot retrieve value. The value has an unsupported type.".to_string();
         expected_response.set_body(Body::new(body));
-        let actual_response = convert_to_response(mmds.clone(), request);
+        let actual_response = convert_to_response(mmds.clone(), request, client());
         assert_eq!(actual_response, expected_response);
 
         // Test GET request towards invalid resource.
@@ -587,7 +675,7 @@ mod tests {
         expected_response.set_body(Body::new(
             Error::ResourceNotFound(String::from("/invalid")).to_string(),
         ));
-        let actual_response = convert_to_response(mmds.clone(), request);
+        let actual_response = convert_to_response(mmds.clone(), request, client());
         assert_eq!(actual_response, expected_response);
 
         // Test GET request without token should return Unauthorized status code.
@@ -595,7 +683,7 @@ mod tests {
         let request = Request::try_from(request_bytes, None).unwrap();
         let mut expected_response = Response::new(Version::Http10, StatusCode::Unauthorized);
         expected_response.set_body(Body::new(Error::NoTokenProvided.to_string()));
-        let actual_response = convert_to_response(mmds.clone(), request);
+        let actual_response = convert_to_response(mmds.clone(), request, client());
         assert_eq!(actual_response, expected_response);
 
         // Test GET request with invalid token should return Unauthorized status code.
@@ -604,14 +692,14 @@ mod tests {
         let request = Request::try_from(request_bytes, None).unwrap();
         let mut expected_response = Response::new(Version::Http10, StatusCode::Unauthorized);
         expected_response.set_body(Body::new(Error::InvalidToken.to_string()));
-        let actual_response = convert_to_response(mmds.clone(), request);
+        let actual_response = convert_to_response(mmds.clone(), request, client());
         assert_eq!(actual_response, expected_response);
 
         // Create a new MMDS token that expires in one second.
         let request_bytes = b"PUT http://169.254.169.254/latest/api/token HTTP/1.0\r\n\
                                     X-metadata-token-ttl-seconds: 1\r\n\r\n";
         let request = Request::try_from(request_bytes, None).unwrap();
-        let actual_response = convert_to_response(mmds.clone(), request);
+        let actual_response = convert_to_response(mmds.clone(), request, client());
         assert_eq!(actual_response.status(), StatusCode::OK);
         assert_eq!(actual_response.content_type(), MediaType::PlainText);
 
@@ -629,7 +717,7 @@ mod tests {
             let request = Request::try_from(request_bytes.as_bytes(), None).unwrap();
             let mut expected_response = Response::new(Version::Http10, StatusCode::Unauthorized);
             expected_response.set_body(Body::new(Error::InvalidToken.to_string()));
-            let actual_response = convert_to_response(mmds.clone(), request);
+            let actual_response = convert_to_response(mmds.clone(), request, client());
             assert_eq!(actual_response, expected_response);
 
             // Wait for the second token to expire.
@@ -637,6 +725,55 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_token_binding() {
+        let mmds = populate_mmds();
+        {
+            let mut mmds_guard = mmds.lock().expect("Poisoned lock");
+            mmds_guard.set_version(MmdsVersion::V2).unwrap();
+            mmds_guard.set_token_binding(TokenBinding::SourceIpAndPort);
+        }
+
+        let issued_count = METRICS.mmds.tokens_issued.count();
+        let request_bytes = b"PUT http://169.254.169.254/latest/api/token HTTP/1.0\r\n\
+                                    X-metadata-token-ttl-seconds: 60\r\n\r\n";
+        let request = Request::try_from(request_bytes, None).unwrap();
+        let actual_response = convert_to_response(mmds.clone(), request, client());
+        assert_eq!(actual_response.status(), StatusCode::OK);
+        assert!(METRICS.mmds.tokens_issued.count() > issued_count);
+
+        let token = String::from_utf8(actual_response.body().unwrap().body).unwrap();
+        let request_bytes = format!(
+            "GET http://169.254.169.254/age HTTP/1.0\r\n\
+            Accept: application/json\r\n\
+            X-metadata-token: {}\r\n\r\n",
+            token
+        );
+
+        // The token is accepted from the guest endpoint it was issued to.
+        let validated_count = METRICS.mmds.tokens_validated.count();
+        let request = Request::try_from(request_bytes.as_bytes(), None).unwrap();
+        let actual_response = convert_to_response(mmds.clone(), request, client());
+        assert_eq!(actual_response.status(), StatusCode::OK);
+        assert!(METRICS.mmds.tokens_validated.count() > validated_count);
+
+        // The token is rejected when used from a different source port.
+        let rejected_count = METRICS.mmds.tokens_rejected.count();
+        let other_client = SocketAddrV4::new(*client().ip(), client().port() + 1);
+        let request = Request::try_from(request_bytes.as_bytes(), None).unwrap();
+        let mut expected_response = Response::new(Version::Http10, StatusCode::Unauthorized);
+        expected_response.set_body(Body::new(Error::InvalidToken.to_string()));
+        let actual_response = convert_to_response(mmds.clone(), request, other_client);
+        assert_eq!(actual_response, expected_response);
+        assert!(METRICS.mmds.tokens_rejected.count() > rejected_count);
+
+        // Revoking tokens invalidates the token for the guest endpoint it was issued to as well.
+        mmds.lock().expect("Poisoned lock").revoke_tokens().unwrap();
+        let request = Request::try_from(request_bytes.as_bytes(), None).unwrap();
+        let actual_response = convert_to_response(mmds, request, client());
+        assert_eq!(actual_response, expected_response);
+    }
+
     #[test]
     fn test_json_patch() {
         let mut data = serde_json::json!({
@@ -5,7 +5,7 @@
 #![allow(missing_docs)]
 
 use std::convert::From;
-use std::net::Ipv4Addr;
+use std::net::{Ipv4Addr, SocketAddrV4};
 use std::num::NonZeroUsize;
 use std::result::Result;
 use std::sync::{Arc, Mutex};
@@ -20,7 +20,7 @@ use dumbo::pdu::ethernet::{
 use dumbo::pdu::ipv4::{
     test_speculative_dst_addr, Error as IPv4PacketError, IPv4Packet, PROTOCOL_TCP,
 };
-use dumbo::pdu::tcp::Error as TcpSegmentError;
+use dumbo::pdu::tcp::{Error as TcpSegmentError, TcpSegment};
 use dumbo::pdu::Incomplete;
 use dumbo::tcp::handler::{self, RecvEvent, TcpIPv4Handler, WriteEvent};
 use dumbo::tcp::NextSegmentStatus;
@@ -34,6 +34,36 @@ const DEFAULT_TCP_PORT: u16 = 80;
 const DEFAULT_MAX_CONNECTIONS: usize = 30;
 const DEFAULT_MAX_PENDING_RESETS: usize = 100;
 
+/// The largest accepted value of `MmdsNetworkStackConfig::max_connections`.
+pub const MAX_CONNECTIONS_LIMIT: usize = 1024;
+/// The largest accepted value of `MmdsNetworkStackConfig::max_pending_resets`.
+pub const MAX_PENDING_RESETS_LIMIT: usize = 1024;
+
+/// Tunable parameters of the MMDS network stack.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct MmdsNetworkStackConfig {
+    /// The TCP port the MMDS HTTP server listens on.
+    pub tcp_port: u16,
+    /// The maximum number of concurrent guest connections.
+    pub max_connections: NonZeroUsize,
+    /// The maximum number of RST segments waiting to be sent.
+    pub max_pending_resets: NonZeroUsize,
+    /// Responses with larger bodies are replaced with a `413 Payload Too Large` error.
+    pub max_response_size: Option<NonZeroUsize>,
+}
+
+impl Default for MmdsNetworkStackConfig {
+    fn default() -> Self {
+        // The unwrap()s are safe because the given literals are greater than 0.
+        MmdsNetworkStackConfig {
+            tcp_port: DEFAULT_TCP_PORT,
+            max_connections: NonZeroUsize::new(DEFAULT_MAX_CONNECTIONS).unwrap(),
+            max_pending_resets: NonZeroUsize::new(DEFAULT_MAX_PENDING_RESETS).unwrap(),
+            max_response_size: None,
+        }
+    }
+}
+
 #[cfg_attr(test, derive(Debug, PartialEq))]
 enum WriteArpFrameError {
     NoPendingArpReply,
@@ -70,6 +100,8 @@ pub struct MmdsNetworkStack {
     pending_arp_reply_dest: Option<Ipv4Addr>,
     // This handles MMDS<->guest interaction at the TCP level.
     pub(crate) tcp_handler: TcpIPv4Handler,
+    // Responses with larger bodies are replaced with an error.
+    pub(crate) max_response_size: Option<NonZeroUsize>,
     // Data store reference shared across all MmdsNetworkStack instances.
     pub mmds: Arc<Mutex<Mmds>>,
 }
@@ -94,24 +126,62 @@ impl MmdsNetworkStack {
                 max_connections,
                 max_pending_resets,
             ),
+            max_response_size: None,
             mmds,
         }
     }
 
     pub fn new_with_defaults(mmds_ipv4_addr: Option<Ipv4Addr>, mmds: Arc<Mutex<Mmds>>) -> Self {
+        Self::new_with_config(mmds_ipv4_addr, MmdsNetworkStackConfig::default(), mmds)
+    }
+
+    pub fn new_with_config(
+        mmds_ipv4_addr: Option<Ipv4Addr>,
+        config: MmdsNetworkStackConfig,
+        mmds: Arc<Mutex<Mmds>>,
+    ) -> Self {
         // The unwrap is safe if parse_str() is implemented properly.
         let mac_addr = MacAddr::parse_str(DEFAULT_MAC_ADDR).unwrap();
         let ipv4_addr = mmds_ipv4_addr.unwrap_or_else(|| Ipv4Addr::from(DEFAULT_IPV4_ADDR));
 
-        // The unwrap()s are safe because the given literals are greater than 0.
-        Self::new(
+        let mut ns = Self::new(
             mac_addr,
             ipv4_addr,
-            DEFAULT_TCP_PORT,
-            NonZeroUsize::new(DEFAULT_MAX_CONNECTIONS).unwrap(),
-            NonZeroUsize::new(DEFAULT_MAX_PENDING_RESETS).unwrap(),
+            config.tcp_port,
+            config.max_connections,
+            config.max_pending_resets,
             mmds,
-        )
+        );
+        ns.max_response_size = config.max_response_size;
+        ns
+    }
+
+    pub fn config(&self) -> MmdsNetworkStackConfig {
+        // The unwrap()s are safe because the TCP handler was created from non-zero values.
+        MmdsNetworkStackConfig {
+            tcp_port: self.tcp_handler.local_port(),
+            max_connections: NonZeroUsize::new(self.tcp_handler.max_connections()).unwrap(),
+            max_pending_resets: NonZeroUsize::new(self.tcp_handler.max_pending_resets()).unwrap(),
+            max_response_size: self.max_response_size,
+        }
+    }
+
+    // Updates the tunable parameters of the stack. Changing any of the TCP parameters drops all
+    // the existing connections.
+    pub fn set_config(&mut self, config: MmdsNetworkStackConfig) {
+        let current = self.config();
+        if current.tcp_port != config.tcp_port
+            || current.max_connections != config.max_connections
+            || current.max_pending_resets != config.max_pending_resets
+        {
+            self.tcp_handler = TcpIPv4Handler::new(
+                self.ipv4_addr,
+                config.tcp_port,
+                config.max_connections,
+                config.max_pending_resets,
+            );
+        }
+        self.max_response_size = config.max_response_size;
     }
 
     pub fn set_ipv4_addr(&mut self, ipv4_addr: Ipv4Addr) {
@@ -175,8 +245,20 @@ impl MmdsNetworkStack {
                 // each MmdsNetworkStack routes packets for only one network device.
                 self.remote_mac_addr = eth.src_mac();
                 let mmds_instance = self.mmds.clone();
-                match &mut self.tcp_handler.receive_packet(&ip, move |request| {
-                    super::convert_to_response(mmds_instance, request)
+                // The guest endpoint which sent the segment, used to bind session tokens.
+                // When the segment cannot be parsed, `receive_packet` fails before
+                // building any response, so the port value does not matter.
+                let source_port = TcpSegment::from_bytes(ip.payload(), None)
+                    .map(|segment| segment.source_port())
+                    .unwrap_or(0);
+                let client = SocketAddrV4::new(ip.source_address(), source_port);
+                let max_response_size = self.max_response_size;
+                // Pipelined requests may invoke the callback several times for a single segment.
+                match &mut self.tcp_handler.receive_packet(&ip, |request| {
+                    let http_version = request.http_version();
+                    let response =
+                        super::convert_to_response(mmds_instance.clone(), request, client);
+                    super::limit_response_size(http_version, response, max_response_size)
                 }) {
                     Ok(event) => {
                         METRICS.mmds.rx_count.inc();
@@ -374,6 +456,7 @@ mod tests {
                     flags,
                     10000,
                     None,
+                    None,
                     0,
                     None,
                 )
@@ -540,6 +623,36 @@ mod tests {
         assert_eq!(ns.tcp_handler.local_ipv4_addr(), Ipv4Addr::LOCALHOST);
     }
 
+    #[test]
+    fn test_ns_config() {
+        let ns = MmdsNetworkStack::new_with_defaults(None, Arc::new(Mutex::new(Mmds::default())));
+        assert_eq!(ns.config(), MmdsNetworkStackConfig::default());
+        assert_eq!(ns.config().tcp_port, DEFAULT_TCP_PORT);
+        assert_eq!(ns.config().max_response_size, None);
+
+        let config = MmdsNetworkStackConfig {
+            tcp_port: 8080,
+            max_connections: NonZeroUsize::new(2).unwrap(),
+            max_pending_resets: NonZeroUsize::new(3).unwrap(),
+            max_response_size: NonZeroUsize::new(1024),
+        };
+        let mut ns = MmdsNetworkStack::new_with_config(
+            Some(Ipv4Addr::LOCALHOST),
+            config,
+            Arc::new(Mutex::new(Mmds::default())),
+        );
+        assert_eq!(ns.ipv4_addr, Ipv4Addr::LOCALHOST);
+        assert_eq!(ns.config(), config);
+        assert_eq!(ns.tcp_handler.local_port(), 8080);
+        assert_eq!(ns.tcp_handler.max_connections(), 2);
+        assert_eq!(ns.tcp_handler.max_pending_resets(), 3);
+
+        // Updating the config keeps the IPv4 address of the stack.
+        ns.set_config(MmdsNetworkStackConfig::default());
+        assert_eq!(ns.config(), MmdsNetworkStackConfig::default());
+        assert_eq!(ns.tcp_handler.local_ipv4_addr(), Ipv4Addr::LOCALHOST);
+    }
+
     #[test]
     fn test_default_ipv4_addr() {
         let actual = MmdsNetworkStack::default_ipv4_addr();
@@ -4,14 +4,15 @@
 //! Defines the structures needed for saving/restoring MmdsNetworkStack.
 
 use std::net::Ipv4Addr;
+use std::num::NonZeroUsize;
 use std::sync::{Arc, Mutex};
 
 use snapshot::Persist;
 use utils::net::mac::{MacAddr, MAC_ADDR_LEN};
-use versionize::{VersionMap, Versionize, VersionizeResult};
+use versionize::{VersionMap, Versionize, VersionizeError, VersionizeResult};
 use versionize_derive::Versionize;
 
-use super::ns::MmdsNetworkStack;
+use super::ns::{MmdsNetworkStack, MmdsNetworkStackConfig};
 use crate::Mmds;
 
 /// State of a MmdsNetworkStack.
@@ -23,6 +24,20 @@ pub struct MmdsNetworkStackState {
     tcp_port: u16,
     max_connections: usize,
     max_pending_resets: usize,
+    #[version(start = 2, ser_fn = "max_response_size_serialize")]
+    max_response_size: Option<usize>,
+}
+
+impl MmdsNetworkStackState {
+    fn max_response_size_serialize(&mut self, target_version: u16) -> VersionizeResult<()> {
+        if target_version < 2 && self.max_response_size.is_some() {
+            return Err(VersionizeError::Semantic(
+                "Target version does not support limiting the MMDS response size.".to_owned(),
+            ));
+        }
+
+        Ok(())
+    }
 }
 
 impl Persist<'_> for MmdsNetworkStack {
@@ -40,6 +55,7 @@ impl Persist<'_> for MmdsNetworkStack {
             tcp_port: self.tcp_handler.local_port(),
             max_connections: self.tcp_handler.max_connections(),
             max_pending_resets: self.tcp_handler.max_pending_resets(),
+            max_response_size: self.max_response_size.map(NonZeroUsize::get),
         }
     }
 
@@ -47,14 +63,16 @@ impl Persist<'_> for MmdsNetworkStack {
         mmds: Self::ConstructorArgs,
         state: &Self::State,
     ) -> std::result::Result<Self, Self::Error> {
-        Ok(MmdsNetworkStack::new(
+        let mut ns = MmdsNetworkStack::new(
             MacAddr::from_bytes_unchecked(&state.mac_addr),
             Ipv4Addr::from(state.ipv4_addr),
             state.tcp_port,
-            std::num::NonZeroUsize::new(state.max_connections).unwrap(),
-            std::num::NonZeroUsize::new(state.max_pending_resets).unwrap(),
+            NonZeroUsize::new(state.max_connections).unwrap(),
+            NonZeroUsize::new(state.max_pending_resets).unwrap(),
             mmds,
-        ))
+        );
+        ns.max_response_size = state.max_response_size.and_then(NonZeroUsize::new);
+        Ok(ns)
     }
 }
 
@@ -94,4 +112,36 @@ mod tests {
             ns.tcp_handler.max_pending_resets()
         );
     }
+
+    #[test]
+    fn test_max_response_size_persistence() {
+        let config = MmdsNetworkStackConfig {
+            max_response_size: NonZeroUsize::new(1024),
+            ..Default::default()
+        };
+        let ns =
+            MmdsNetworkStack::new_with_config(None, config, Arc::new(Mutex::new(Mmds::default())));
+
+        let mut mem = vec![0; 4096];
+        let mut version_map = VersionMap::new();
+        version_map
+            .new_version()
+            .set_type_version(MmdsNetworkStackState::type_id(), 2);
+
+        // Older versions can't represent the limit.
+        assert!(ns
+            .save()
+            .serialize(&mut mem.as_mut_slice(), &version_map, 1)
+            .is_err());
+
+        ns.save()
+            .serialize(&mut mem.as_mut_slice(), &version_map, 2)
+            .unwrap();
+        let restored_ns = MmdsNetworkStack::restore(
+            Arc::new(Mutex::new(Mmds::default())),
+            &MmdsNetworkStackState::deserialize(&mut mem.as_slice(), &version_map, 2).unwrap(),
+        )
+        .unwrap();
+        assert_eq!(restored_ns.config(), config);
+    }
 }
@@ -114,14 +114,29 @@ impl TokenAuthority {
         self.aad = format!("microvmid={}", instance_id);
     }
 
+    /// Returns the Additional Authenticated Data for a token bound to `client`.
+    /// Tokens which are not bound to a client only use the microVM ID.
+    fn aad(&self, client: Option<&str>) -> String {
+        match client {
+            Some(client) => format!("{};client={}", self.aad, client),
+            None => self.aad.clone(),
+        }
+    }
+
     /// Generate encoded token string using the token time to live provided.
-    pub fn generate_token_secret(&mut self, ttl_seconds: u32) -> Result<String, Error> {
+    /// When `client` is provided, the token will only be valid for the same `client`.
+    pub fn generate_token_secret(
+        &mut self,
+        ttl_seconds: u32,
+        client: Option<&str>,
+    ) -> Result<String, Error> {
         // Check number of tokens encrypted under the current key. We need to
         // make sure no more than 2^32 tokens are encrypted with the same key.
         // If this number is reached, we need to reinitialize the cipher entity.
         self.check_encryption_count()?;
         // Create token structure containing the encrypted expiry value.
-        let token = self.create_token(ttl_seconds)?;
+        let aad = self.aad(client);
+        let token = self.create_token(ttl_seconds, &aad)?;
         // Encode struct into base64 in order to obtain token string.
         let encoded_token = token.base64_encode()?;
         // Increase the count of encrypted tokens.
@@ -131,7 +146,7 @@ impl TokenAuthority {
     }
 
     /// Create a new Token structure to encrypt.
-    fn create_token(&mut self, ttl_seconds: u32) -> Result<Token, Error> {
+    fn create_token(&mut self, ttl_seconds: u32, aad: &str) -> Result<Token, Error> {
         // Validate token time to live against bounds.
         if !TokenAuthority::check_ttl(ttl_seconds) {
             return Err(Error::InvalidTtlValue(ttl_seconds));
@@ -146,7 +161,7 @@ impl TokenAuthority {
         // Compute expiration time in milliseconds from ttl.
         let expiry = TokenAuthority::compute_expiry(ttl_seconds);
         // Encrypt expiry using the nonce.
-        let (payload, tag) = self.encrypt_expiry(expiry, iv.as_ref())?;
+        let (payload, tag) = self.encrypt_expiry(expiry, iv.as_ref(), aad)?;
 
         Ok(Token::new(iv, payload, tag))
     }
@@ -156,6 +171,7 @@ impl TokenAuthority {
         &self,
         expiry: u64,
         iv: &[u8],
+        aad: &str,
     ) -> Result<([u8; PAYLOAD_LEN], [u8; TAG_LEN]), Error> {
         // Create Nonce object from initialization vector.
         let nonce = Nonce::from_slice(iv);
@@ -164,7 +180,7 @@ impl TokenAuthority {
 
         let tag = self
             .cipher
-            .encrypt_in_place_detached(nonce, self.aad.as_bytes(), &mut expiry_as_bytes)
+            .encrypt_in_place_detached(nonce, aad.as_bytes(), &mut expiry_as_bytes)
             .map_err(|_| Error::TokenEncryption)?;
 
         // Tag must be of size `TAG_LEN`.
@@ -178,8 +194,9 @@ impl TokenAuthority {
 
     /// Attempts to decrypt expiry value within token sequence. Returns false if expiry
     /// cannot be decrypted. If decryption succeeds, returns true if token has not expired
-    /// (i.e. current time is greater than expiry) and false otherwise.
-    pub fn is_valid(&self, encoded_token: &str) -> bool {
+    /// (i.e. current time is greater than expiry) and false otherwise. Tokens bound to a
+    /// client only decrypt successfully when the same `client` is provided.
+    pub fn is_valid(&self, encoded_token: &str, client: Option<&str>) -> bool {
         // Check size of encoded token struct.
         if encoded_token.len() > TOKEN_LENGTH_LIMIT {
             return false;
@@ -192,7 +209,8 @@ impl TokenAuthority {
         };
 
         // Decrypt ttl using AES-GCM block cipher.
-        let expiry = match self.decrypt_expiry(&mut token.payload, &token.tag, &token.iv) {
+        let aad = self.aad(client);
+        let expiry = match self.decrypt_expiry(&mut token.payload, &token.tag, &token.iv, &aad) {
             Ok(expiry) => expiry,
             Err(_) => return false,
         };
@@ -207,6 +225,7 @@ impl TokenAuthority {
         payload: &mut [u8; PAYLOAD_LEN],
         tag: &[u8],
         iv: &[u8],
+        aad: &str,
     ) -> Result<u64, Error> {
         // Create Nonce object from initialization vector.
         let nonce = Nonce::from_slice(iv);
@@ -214,7 +233,7 @@ impl TokenAuthority {
         self.cipher
             .decrypt_in_place_detached(
                 nonce,
-                self.aad.as_bytes(),
+                aad.as_bytes(),
                 payload,
                 aes_gcm::Tag::from_slice(tag),
             )
@@ -252,9 +271,7 @@ impl TokenAuthority {
             // healthy interactions with MMDS. However, if it happens, we expect the
             // customer code to have a retry mechanism in place and regenerate the
             // session token if the previous ones become invalid.
-            self.cipher = TokenAuthority::create_cipher(&mut self.entropy_pool)?;
-            // Reset encrypted tokens count.
-            self.num_encrypted_tokens = 0;
+            self.rotate_key()?;
             warn!(
                 "The limit of tokens generated under current MMDS token authority
                 has been reached. MMDS's token authority entity has been reseeded
@@ -265,6 +282,15 @@ impl TokenAuthority {
         Ok(())
     }
 
+    /// Reinitialize the cipher under a new key. All the tokens generated under the
+    /// previous key are invalidated.
+    pub fn rotate_key(&mut self) -> Result<(), Error> {
+        self.cipher = TokenAuthority::create_cipher(&mut self.entropy_pool)?;
+        // Reset encrypted tokens count.
+        self.num_encrypted_tokens = 0;
+        Ok(())
+    }
+
     /// Validate the token time to live against bounds.
     fn check_ttl(ttl_seconds: u32) -> bool {
         MIN_TOKEN_TTL_SECONDS <= ttl_seconds && ttl_seconds <= MAX_TOKEN_TTL_SECONDS
@@ -357,7 +383,7 @@ mod tests {
 
         // Test invalid time to live value.
         assert_eq!(
-            token_authority.create_token(0).unwrap_err().to_string(),
+            token_authority.create_token(0, "").unwrap_err().to_string(),
             format!(
                 "Invalid time to live value provided for token: 0. \
                 Please provide a value between {} and {}.",
@@ -366,7 +392,7 @@ mod tests {
         );
 
         // Test valid time to live value.
-        let token = token_authority.create_token(1).unwrap();
+        let token = token_authority.create_token(1, "").unwrap();
         assert_eq!(token.iv.len(), IV_LEN);
         assert_eq!(token.payload.len(), PAYLOAD_LEN);
         assert_eq!(token.tag.len(), TAG_LEN);
@@ -397,17 +423,19 @@ mod tests {
         let expiry = TokenAuthority::compute_expiry(10);
 
         // Test valid ciphertext.
-        let (mut payload, mut tag) = token_authority.encrypt_expiry(expiry, &iv).unwrap();
+        token_authority.set_aad("foo");
+        let aad = token_authority.aad(None);
+        let (mut payload, mut tag) = token_authority.encrypt_expiry(expiry, &iv, &aad).unwrap();
         let decrypted_expiry = token_authority
-            .decrypt_expiry(&mut payload, &tag, iv.as_mut())
+            .decrypt_expiry(&mut payload, &tag, iv.as_mut(), &aad)
             .unwrap();
         assert_eq!(expiry, decrypted_expiry);
 
         // Test decrypting expiry under a different AAD than it was encrypted with.
-        token_authority.set_aad("foo");
+        token_authority.set_aad("bar");
         assert_eq!(
             token_authority
-                .decrypt_expiry(&mut payload, &tag, iv.as_mut())
+                .decrypt_expiry(&mut payload, &tag, iv.as_mut(), &token_authority.aad(None))
                 .unwrap_err()
                 .to_string(),
             Error::ExpiryExtraction.to_string()
@@ -417,7 +445,7 @@ mod tests {
         payload[0] = u8::MAX - payload[0];
         assert_eq!(
             token_authority
-                .decrypt_expiry(&mut payload, &tag, iv.as_mut())
+                .decrypt_expiry(&mut payload, &tag, iv.as_mut(), &aad)
                 .unwrap_err()
                 .to_string(),
             Error::ExpiryExtraction.to_string()
@@ -430,7 +458,7 @@ mod tests {
         ciphertext.extend_from_slice(&tag);
         assert_eq!(
             token_authority
-                .decrypt_expiry(&mut payload, &tag, iv.as_mut())
+                .decrypt_expiry(&mut payload, &tag, iv.as_mut(), &aad)
                 .unwrap_err()
                 .to_string(),
             Error::ExpiryExtraction.to_string()
@@ -456,7 +484,7 @@ mod tests {
         // Test time to live value too small.
         assert_eq!(
             token_authority
-                .generate_token_secret(MIN_TOKEN_TTL_SECONDS - 1)
+                .generate_token_secret(MIN_TOKEN_TTL_SECONDS - 1, None)
                 .unwrap_err()
                 .to_string(),
             format!(
@@ -471,7 +499,7 @@ mod tests {
         // Test time to live value too big.
         assert_eq!(
             token_authority
-                .generate_token_secret(MAX_TOKEN_TTL_SECONDS + 1)
+                .generate_token_secret(MAX_TOKEN_TTL_SECONDS + 1, None)
                 .unwrap_err()
                 .to_string(),
             format!(
@@ -484,7 +512,7 @@ mod tests {
         );
 
         // Generate token with lifespan of 60 seconds.
-        let _ = token_authority.generate_token_secret(60).unwrap();
+        let _ = token_authority.generate_token_secret(60, None).unwrap();
         assert_eq!(token_authority.num_encrypted_tokens, 1);
     }
 
@@ -493,11 +521,42 @@ mod tests {
         let mut token_authority = TokenAuthority::new().unwrap();
 
         // Test token with size bigger than expected.
-        assert!(!token_authority.is_valid(str::repeat("a", TOKEN_LENGTH_LIMIT + 1).as_str()));
+        assert!(!token_authority.is_valid(str::repeat("a", TOKEN_LENGTH_LIMIT + 1).as_str(), None));
 
         // Test valid token.
-        let token0 = token_authority.generate_token_secret(1).unwrap();
-        assert!(token_authority.is_valid(&token0));
+        let token0 = token_authority.generate_token_secret(1, None).unwrap();
+        assert!(token_authority.is_valid(&token0, None));
+        // A token which is not bound to a client can be used by any client.
+        assert!(token_authority.is_valid(&token0, Some("169.254.0.1")));
+
+        // Test token bound to a client.
+        let token1 = token_authority
+            .generate_token_secret(1, Some("169.254.0.1"))
+            .unwrap();
+        assert!(token_authority.is_valid(&token1, Some("169.254.0.1")));
+        assert!(!token_authority.is_valid(&token1, Some("169.254.0.2")));
+        assert!(!token_authority.is_valid(&token1, None));
+    }
+
+    #[test]
+    fn test_rotate_key() {
+        let mut token_authority = TokenAuthority::new().unwrap();
+
+        let token0 = token_authority.generate_token_secret(60, None).unwrap();
+        let token1 = token_authority
+            .generate_token_secret(60, Some("169.254.0.1"))
+            .unwrap();
+        assert_eq!(token_authority.num_encrypted_tokens, 2);
+
+        // All the tokens generated under the previous key are invalidated.
+        token_authority.rotate_key().unwrap();
+        assert_eq!(token_authority.num_encrypted_tokens, 0);
+        assert!(!token_authority.is_valid(&token0, None));
+        assert!(!token_authority.is_valid(&token1, Some("169.254.0.1")));
+
+        // New tokens are generated under the new key.
+        let token2 = token_authority.generate_token_secret(60, None).unwrap();
+        assert!(token_authority.is_valid(&token2, None));
     }
 
     #[test]
@@ -505,28 +564,28 @@ mod tests {
         let mut token_authority = TokenAuthority::new().unwrap();
 
         // Generate token with lifespan of 60 seconds.
-        let token0 = token_authority.generate_token_secret(60).unwrap();
-        assert!(token_authority.is_valid(&token0));
+        let token0 = token_authority.generate_token_secret(60, None).unwrap();
+        assert!(token_authority.is_valid(&token0, None));
 
         // Generate token with lifespan of one second.
-        let token1 = token_authority.generate_token_secret(1).unwrap();
+        let token1 = token_authority.generate_token_secret(1, None).unwrap();
         assert_eq!(token_authority.num_encrypted_tokens, 2);
-        assert!(token_authority.is_valid(&token1));
+        assert!(token_authority.is_valid(&token1, None));
         // Wait for `token1` to expire.
         sleep(Duration::new(1, 0));
-        assert!(!token_authority.is_valid(&token1));
+        assert!(!token_authority.is_valid(&token1, None));
         // The first token should still be valid.
-        assert!(token_authority.is_valid(&token0));
+        assert!(token_authority.is_valid(&token0, None));
 
         // Simulate reaching to a count of 2^32 encrypted tokens.
         // The cipher and count should reset at this point and previous
         // tokens should become invalid.
         token_authority.num_encrypted_tokens = u32::MAX;
-        let token2 = token_authority.generate_token_secret(60).unwrap();
+        let token2 = token_authority.generate_token_secret(60, None).unwrap();
         assert_eq!(token_authority.num_encrypted_tokens, 1);
-        assert!(token_authority.is_valid(&token2));
-        assert!(!token_authority.is_valid(&token0));
-        assert!(!token_authority.is_valid(&token1));
+        assert!(token_authority.is_valid(&token2, None));
+        assert!(!token_authority.is_valid(&token0, None));
+        assert!(!token_authority.is_valid(&token1, None));
     }
 
     #[test]
@@ -33,6 +33,21 @@
 //! The granularity for 'wake up' events when the rate limiter is blocked is
 //! currently hardcoded to `100 milliseconds`.
 //!
+//! ## Groups
+//!
+//! Several rate limiters can be attached to a `RateLimiterGroup`, whose token
+//! buckets are shared by all of them, on top of their own buckets. The group has
+//! no timer of its own: a rate limiter which runs out of group budget arms its
+//! own timer, so every throttled user gets woken up on its own FD.
+//!
+//! ## Directions
+//!
+//! On top of the two token buckets above, a rate limiter can have dedicated
+//! bandwidth and ops token buckets for each `IoDirection`. Operations going
+//! through `consume_directional()` are accounted against both the buckets of
+//! their direction and the direction-agnostic ones, which allows e.g. capping
+//! writes tighter than reads.
+//!
 //! ## Limitations
 //!
 //! This rate limiter implementation relies on the *Linux kernel's timerfd* so its
@@ -44,6 +59,7 @@
 //! needs to be called by the user on every event on the rate limiter's `AsRawFd` FD.
 use logger::error;
 use std::os::unix::io::{AsRawFd, RawFd};
+use std::sync::{Arc, Mutex};
 use std::time::{Duration, Instant};
 use std::{fmt, io};
 use timerfd::{ClockId, SetTimeFlags, TimerFd, TimerState};
@@ -152,18 +168,24 @@ impl TokenBucket {
         })
     }
 
-    // Replenishes token bucket based on elapsed time. Should only be called internally by `Self`.
-    fn auto_replenish(&mut self) {
+    // Computes the budget of the bucket once replenished based on elapsed time.
+    fn replenished_budget(&self) -> u64 {
         // Compute time passed since last refill/update.
         let time_delta = self.last_update.elapsed().as_nanos() as u64;
-        self.last_update = Instant::now();
 
         // At each 'time_delta' nanoseconds the bucket should refill with:
         // refill_amount = (time_delta * size) / (complete_refill_time_ms * 1_000_000)
         // `processed_capacity` and `processed_refill_time` are the result of simplifying above
         // fraction formula with their greatest-common-factor.
-        let tokens = (time_delta * self.processed_capacity) / self.processed_refill_time;
-        self.budget = std::cmp::min(self.budget + tokens, self.size);
+        let tokens =
+            time_delta.saturating_mul(self.processed_capacity) / self.processed_refill_time;
+        std::cmp::min(self.budget.saturating_add(tokens), self.size)
+    }
+
+    // Replenishes token bucket based on elapsed time. Should only be called internally by `Self`.
+    fn auto_replenish(&mut self) {
+        self.budget = self.replenished_budget();
+        self.last_update = Instant::now();
     }
 
     /// Attempts to consume `tokens` from the bucket and returns whether the action succeeded.
@@ -244,6 +266,15 @@ impl TokenBucket {
         self.budget
     }
 
+    /// Returns the budget available right now, including the tokens refilled since the bucket
+    /// was last used (one time burst allowance notwithstanding).
+    ///
+    /// Buckets are replenished lazily, so unlike `budget()` this reflects the time elapsed
+    /// since the last operation.
+    pub fn available_budget(&self) -> u64 {
+        self.replenished_budget()
+    }
+
     /// Returns the initially configured one time burst budget.
     pub fn initial_one_time_burst(&self) -> u64 {
         self.initial_one_time_burst
@@ -251,6 +282,7 @@ impl TokenBucket {
 }
 
 /// Enum that describes the type of token used.
+#[derive(Clone, Copy, Debug, PartialEq)]
 pub enum TokenType {
     /// Token type used for bandwidth limiting.
     Bytes,
@@ -258,6 +290,15 @@ pub enum TokenType {
     Ops,
 }
 
+/// Enum that describes the direction of an IO operation.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum IoDirection {
+    /// Data flows from the device to its user.
+    Read,
+    /// Data flows from the user to the device.
+    Write,
+}
+
 /// Enum that describes the type of token bucket update.
 pub enum BucketUpdate {
     /// No Update - same as before.
@@ -268,6 +309,149 @@ pub enum BucketUpdate {
     Update(TokenBucket),
 }
 
+// A pair of bandwidth and ops token buckets, used by rate limiter groups and for the
+// per-direction limits of a rate limiter.
+#[derive(Debug, Default, PartialEq)]
+struct TokenBuckets {
+    bandwidth: Option<TokenBucket>,
+    ops: Option<TokenBucket>,
+}
+
+impl TokenBuckets {
+    fn bucket_mut(&mut self, token_type: TokenType) -> Option<&mut TokenBucket> {
+        match token_type {
+            TokenType::Bytes => self.bandwidth.as_mut(),
+            TokenType::Ops => self.ops.as_mut(),
+        }
+    }
+
+    fn update(&mut self, bytes: BucketUpdate, ops: BucketUpdate) {
+        match bytes {
+            BucketUpdate::Disabled => self.bandwidth = None,
+            BucketUpdate::Update(tb) => self.bandwidth = Some(tb),
+            BucketUpdate::None => (),
+        };
+        match ops {
+            BucketUpdate::Disabled => self.ops = None,
+            BucketUpdate::Update(tb) => self.ops = Some(tb),
+            BucketUpdate::None => (),
+        };
+    }
+}
+
+/// A named set of token buckets, shared by the rate limiters attached to it.
+///
+/// Cloning a group yields a new handle to the same token buckets, so the budget of a group is
+/// consumed by all of its rate limiters, and updates to it are seen by all of them.
+#[derive(Clone)]
+pub struct RateLimiterGroup {
+    name: String,
+    buckets: Arc<Mutex<TokenBuckets>>,
+}
+
+impl PartialEq for RateLimiterGroup {
+    fn eq(&self, other: &RateLimiterGroup) -> bool {
+        Arc::ptr_eq(&self.buckets, &other.buckets)
+    }
+}
+
+impl fmt::Debug for RateLimiterGroup {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let buckets = self.buckets.lock().expect("Poisoned lock");
+        write!(
+            f,
+            "RateLimiterGroup {{ name: {:?}, bandwidth: {:?}, ops: {:?} }}",
+            self.name, buckets.bandwidth, buckets.ops
+        )
+    }
+}
+
+impl RateLimiterGroup {
+    /// Creates a new rate limiter group named `name`, which can limit on both bytes/s and
+    /// ops/s.
+    ///
+    /// The arguments have the same meaning as the ones of `RateLimiter::new()`. Unlike a
+    /// `RateLimiter`, a group has no timer of its own, so its creation cannot fail.
+    pub fn new(
+        name: String,
+        bytes_total_capacity: u64,
+        bytes_one_time_burst: u64,
+        bytes_complete_refill_time_ms: u64,
+        ops_total_capacity: u64,
+        ops_one_time_burst: u64,
+        ops_complete_refill_time_ms: u64,
+    ) -> Self {
+        Self::with_buckets(
+            name,
+            TokenBucket::new(
+                bytes_total_capacity,
+                bytes_one_time_burst,
+                bytes_complete_refill_time_ms,
+            ),
+            TokenBucket::new(
+                ops_total_capacity,
+                ops_one_time_burst,
+                ops_complete_refill_time_ms,
+            ),
+        )
+    }
+
+    fn with_buckets(
+        name: String,
+        bandwidth: Option<TokenBucket>,
+        ops: Option<TokenBucket>,
+    ) -> Self {
+        RateLimiterGroup {
+            name,
+            buckets: Arc::new(Mutex::new(TokenBuckets { bandwidth, ops })),
+        }
+    }
+
+    /// Returns the name of the group.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Updates the parameters of the token buckets of the group.
+    pub fn update_buckets(&self, bytes: BucketUpdate, ops: BucketUpdate) {
+        self.buckets
+            .lock()
+            .expect("Poisoned lock")
+            .update(bytes, ops);
+    }
+
+    /// Returns a snapshot of the bandwidth token bucket of the group.
+    pub fn bandwidth(&self) -> Option<TokenBucket> {
+        self.buckets
+            .lock()
+            .expect("Poisoned lock")
+            .bandwidth
+            .clone()
+    }
+
+    /// Returns a snapshot of the ops token bucket of the group.
+    pub fn ops(&self) -> Option<TokenBucket> {
+        self.buckets.lock().expect("Poisoned lock").ops.clone()
+    }
+
+    // Attempts to consume `tokens` from the bucket of `token_type`. Returns the outcome of the
+    // reduction along with the refill time of the bucket, or `None` if the bucket is disabled.
+    fn reduce(&self, tokens: u64, token_type: TokenType) -> Option<(BucketReduction, u64)> {
+        let mut buckets = self.buckets.lock().expect("Poisoned lock");
+        buckets
+            .bucket_mut(token_type)
+            .map(|bucket| (bucket.reduce(tokens), bucket.refill_time_ms()))
+    }
+
+    // Adds `tokens` to the bucket of `token_type`.
+    fn force_replenish(&self, tokens: u64, token_type: TokenType) {
+        let mut buckets = self.buckets.lock().expect("Poisoned lock");
+        if let Some(bucket) = buckets.bucket_mut(token_type) {
+            bucket.force_replenish(tokens);
+        }
+    }
+}
+
 /// Rate Limiter that works on both bandwidth and ops/s limiting.
 ///
 /// Bandwidth (bytes/s) and ops/s limiting can be used at the same time or individually.
@@ -283,18 +467,34 @@ pub enum BucketUpdate {
 /// RateLimiters will generate events on the FDs provided by their `AsRawFd` trait
 /// implementation. These events are meant to be consumed by the user of this struct.
 /// On each such event, the user must call the `event_handler()` method.
+///
+/// A RateLimiter attached to a `RateLimiterGroup` only lets operations through if both its own
+/// buckets and the ones of the group have enough budget.
+///
+/// The buckets dedicated to an `IoDirection` are only exercised by `consume_directional()`.
 pub struct RateLimiter {
     bandwidth: Option<TokenBucket>,
     ops: Option<TokenBucket>,
+    read: TokenBuckets,
+    write: TokenBuckets,
+    group: Option<RateLimiterGroup>,
 
     timer_fd: TimerFd,
     // Internal flag that quickly determines timer state.
     timer_active: bool,
+    // Time at which the timer was last armed.
+    blocked_since: Instant,
+    // Total time spent blocked, up to the last time the timer expired.
+    throttled_time: Duration,
 }
 
 impl PartialEq for RateLimiter {
     fn eq(&self, other: &RateLimiter) -> bool {
-        self.bandwidth == other.bandwidth && self.ops == other.ops
+        self.bandwidth == other.bandwidth
+            && self.ops == other.ops
+            && self.read == other.read
+            && self.write == other.write
+            && self.group == other.group
     }
 }
 
@@ -302,8 +502,10 @@ impl fmt::Debug for RateLimiter {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         write!(
             f,
-            "RateLimiter {{ bandwidth: {:?}, ops: {:?} }}",
-            self.bandwidth, self.ops
+            "RateLimiter {{ bandwidth: {:?}, ops: {:?}, group: {:?} }}",
+            self.bandwidth,
+            self.ops,
+            self.group.as_ref().map(RateLimiterGroup::name)
         )
     }
 }
@@ -358,85 +560,195 @@ impl RateLimiter {
         Ok(RateLimiter {
             bandwidth: bytes_token_bucket,
             ops: ops_token_bucket,
+            read: TokenBuckets::default(),
+            write: TokenBuckets::default(),
+            group: None,
             timer_fd,
             timer_active: false,
+            blocked_since: Instant::now(),
+            throttled_time: Duration::default(),
         })
     }
 
     // Arm the timer of the rate limiter with the provided `TimerState`.
     fn activate_timer(&mut self, timer_state: TimerState) {
+        if !self.timer_active {
+            self.blocked_since = Instant::now();
+        }
         // Register the timer; don't care about its previous state
         self.timer_fd.set_state(timer_state, SetTimeFlags::Default);
         self.timer_active = true;
     }
 
+    // Handles the outcome of a bucket reduction, arming the timer if needed. Returns whether
+    // the tokens have been consumed.
+    fn handle_reduction(&mut self, reduction: BucketReduction, refill_time: u64) -> bool {
+        match reduction {
+            // When we report budget is over, there will be no further calls here,
+            // register a timer to replenish the bucket and resume processing;
+            // make sure there is only one running timer for this limiter.
+            BucketReduction::Failure => {
+                if !self.timer_active {
+                    self.activate_timer(TIMER_REFILL_STATE);
+                }
+                false
+            }
+            // The operation succeeded and further calls can be made.
+            BucketReduction::Success => true,
+            // The operation succeeded as the tokens have been consumed
+            // but the timer still needs to be armed.
+            BucketReduction::OverConsumption(ratio) => {
+                // The operation "borrowed" a number of tokens `ratio` times
+                // greater than the size of the bucket, and since it takes
+                // `refill_time` milliseconds to fill an empty bucket, in
+                // order to enforce the bandwidth limit we need to prevent
+                // further calls to the rate limiter for
+                // `ratio * refill_time` milliseconds.
+                self.activate_timer(TimerState::Oneshot(Duration::from_millis(
+                    (ratio * refill_time as f64) as u64,
+                )));
+                true
+            }
+        }
+    }
+
     /// Attempts to consume tokens and returns whether that is possible.
     ///
     /// If rate limiting is disabled on provided `token_type`, this function will always succeed.
+    /// If the limiter is attached to a group, the tokens are consumed from the group buckets
+    /// as well.
     pub fn consume(&mut self, tokens: u64, token_type: TokenType) -> bool {
         // If the timer is active, we can't consume tokens from any bucket and the function fails.
         if self.timer_active {
             return false;
         }
 
+        self.consume_shared(tokens, token_type)
+    }
+
+    /// Attempts to consume tokens for an operation going in `direction` and returns whether
+    /// that is possible.
+    ///
+    /// The tokens are consumed from the buckets dedicated to `direction` as well as from the
+    /// ones `consume()` uses. If rate limiting is disabled on all of them for the provided
+    /// `token_type`, this function will always succeed.
+    pub fn consume_directional(
+        &mut self,
+        tokens: u64,
+        token_type: TokenType,
+        direction: IoDirection,
+    ) -> bool {
+        // If the timer is active, we can't consume tokens from any bucket and the function fails.
+        if self.timer_active {
+            return false;
+        }
+
+        let reduction = self
+            .direction_buckets_mut(direction)
+            .bucket_mut(token_type)
+            .map(|bucket| (bucket.reduce(tokens), bucket.refill_time_ms()));
+        if let Some((reduction, refill_time)) = reduction {
+            if !self.handle_reduction(reduction, refill_time) {
+                return false;
+            }
+        }
+
+        if !self.consume_shared(tokens, token_type) {
+            // The operation won't go through, so give the tokens back to the direction bucket.
+            if let Some(bucket) = self.direction_buckets_mut(direction).bucket_mut(token_type) {
+                bucket.force_replenish(tokens);
+            }
+            return false;
+        }
+
+        true
+    }
+
+    // Consumes tokens from the direction-agnostic buckets of the limiter and of its group,
+    // regardless of the timer state.
+    fn consume_shared(&mut self, tokens: u64, token_type: TokenType) -> bool {
         // Identify the required token bucket.
         let token_bucket = match token_type {
             TokenType::Bytes => self.bandwidth.as_mut(),
             TokenType::Ops => self.ops.as_mut(),
         };
         // Try to consume from the token bucket.
+        // If bucket is not present rate limiting is disabled on token type.
         if let Some(bucket) = token_bucket {
             let refill_time = bucket.refill_time_ms();
-            match bucket.reduce(tokens) {
-                // When we report budget is over, there will be no further calls here,
-                // register a timer to replenish the bucket and resume processing;
-                // make sure there is only one running timer for this limiter.
-                BucketReduction::Failure => {
-                    if !self.timer_active {
-                        self.activate_timer(TIMER_REFILL_STATE);
-                    }
-                    false
-                }
-                // The operation succeeded and further calls can be made.
-                BucketReduction::Success => true,
-                // The operation succeeded as the tokens have been consumed
-                // but the timer still needs to be armed.
-                BucketReduction::OverConsumption(ratio) => {
-                    // The operation "borrowed" a number of tokens `ratio` times
-                    // greater than the size of the bucket, and since it takes
-                    // `refill_time` milliseconds to fill an empty bucket, in
-                    // order to enforce the bandwidth limit we need to prevent
-                    // further calls to the rate limiter for
-                    // `ratio * refill_time` milliseconds.
-                    self.activate_timer(TimerState::Oneshot(Duration::from_millis(
-                        (ratio * refill_time as f64) as u64,
-                    )));
-                    true
-                }
+            let reduction = bucket.reduce(tokens);
+            if !self.handle_reduction(reduction, refill_time) {
+                return false;
+            }
+        }
+
+        // The tokens also have to fit in the budget of the group.
+        let group_reduction = self
+            .group
+            .as_ref()
+            .and_then(|group| group.reduce(tokens, token_type));
+        if let Some((reduction, refill_time)) = group_reduction {
+            if !self.handle_reduction(reduction, refill_time) {
+                // The operation won't go through, so give the tokens back to our own bucket.
+                self.replenish_own(tokens, token_type);
+                return false;
             }
-        } else {
-            // If bucket is not present rate limiting is disabled on token type,
-            // consume() will always succeed.
-            true
         }
+
+        true
     }
 
-    /// Adds tokens of `token_type` to their respective bucket.
-    ///
-    /// Can be used to *manually* add tokens to a bucket. Useful for reverting a
-    /// `consume()` if needed.
-    pub fn manual_replenish(&mut self, tokens: u64, token_type: TokenType) {
-        // Identify the required token bucket.
+    // Adds tokens of `token_type` to the bucket of this limiter, leaving the group untouched.
+    fn replenish_own(&mut self, tokens: u64, token_type: TokenType) {
         let token_bucket = match token_type {
             TokenType::Bytes => self.bandwidth.as_mut(),
             TokenType::Ops => self.ops.as_mut(),
         };
-        // Add tokens to the token bucket.
         if let Some(bucket) = token_bucket {
             bucket.force_replenish(tokens);
         }
     }
 
+    /// Adds tokens of `token_type` to their respective bucket.
+    ///
+    /// Can be used to *manually* add tokens to a bucket. Useful for reverting a
+    /// `consume()` if needed.
+    pub fn manual_replenish(&mut self, tokens: u64, token_type: TokenType) {
+        self.replenish_own(tokens, token_type);
+        if let Some(group) = self.group.as_ref() {
+            group.force_replenish(tokens, token_type);
+        }
+    }
+
+    /// Adds tokens of `token_type` to the buckets `consume_directional()` took them from.
+    ///
+    /// Useful for reverting a `consume_directional()` if needed.
+    pub fn manual_replenish_directional(
+        &mut self,
+        tokens: u64,
+        token_type: TokenType,
+        direction: IoDirection,
+    ) {
+        if let Some(bucket) = self.direction_buckets_mut(direction).bucket_mut(token_type) {
+            bucket.force_replenish(tokens);
+        }
+        self.manual_replenish(tokens, token_type);
+    }
+
+    fn direction_buckets(&self, direction: IoDirection) -> &TokenBuckets {
+        match direction {
+            IoDirection::Read => &self.read,
+            IoDirection::Write => &self.write,
+        }
+    }
+
+    fn direction_buckets_mut(&mut self, direction: IoDirection) -> &mut TokenBuckets {
+        match direction {
+            IoDirection::Read => &mut self.read,
+            IoDirection::Write => &mut self.write,
+        }
+    }
+
     /// Returns whether this rate limiter is blocked.
     ///
     /// The limiter 'blocks' when a `consume()` operation fails because there was not enough
@@ -446,6 +758,18 @@ impl RateLimiter {
         self.timer_active
     }
 
+    /// Returns the total time this rate limiter has been blocked for, including the current
+    /// blocked period if any.
+    ///
+    /// The time is accounted since the rate limiter was created, or restored from a snapshot.
+    pub fn throttled_time(&self) -> Duration {
+        if self.timer_active {
+            self.throttled_time + self.blocked_since.elapsed()
+        } else {
+            self.throttled_time
+        }
+    }
+
     /// This function needs to be called every time there is an event on the
     /// FD provided by this object's `AsRawFd` trait implementation.
     ///
@@ -459,6 +783,7 @@ impl RateLimiter {
             )),
             _ => {
                 self.timer_active = false;
+                self.throttled_time += self.blocked_since.elapsed();
                 Ok(())
             }
         }
@@ -488,6 +813,36 @@ impl RateLimiter {
     pub fn ops(&self) -> Option<&TokenBucket> {
         self.ops.as_ref()
     }
+
+    /// Updates the parameters of the token buckets dedicated to `direction`.
+    pub fn update_direction_buckets(
+        &mut self,
+        direction: IoDirection,
+        bytes: BucketUpdate,
+        ops: BucketUpdate,
+    ) {
+        self.direction_buckets_mut(direction).update(bytes, ops);
+    }
+
+    /// Returns an immutable view of the bandwidth token bucket dedicated to `direction`.
+    pub fn direction_bandwidth(&self, direction: IoDirection) -> Option<&TokenBucket> {
+        self.direction_buckets(direction).bandwidth.as_ref()
+    }
+
+    /// Returns an immutable view of the ops token bucket dedicated to `direction`.
+    pub fn direction_ops(&self, direction: IoDirection) -> Option<&TokenBucket> {
+        self.direction_buckets(direction).ops.as_ref()
+    }
+
+    /// Attaches the rate limiter to `group`, or detaches it from its current group if `None`.
+    pub fn set_group(&mut self, group: Option<RateLimiterGroup>) {
+        self.group = group;
+    }
+
+    /// Returns the group the rate limiter is attached to, if any.
+    pub fn group(&self) -> Option<&RateLimiterGroup> {
+        self.group.as_ref()
+    }
 }
 
 impl AsRawFd for RateLimiter {
@@ -710,6 +1065,33 @@ pub(crate) mod tests {
         assert!(l.consume(100, TokenType::Bytes));
     }
 
+    #[test]
+    fn test_rate_limiter_throttled_time() {
+        // rate limiter with limit of 1000 bytes/s
+        let mut l = RateLimiter::new(1000, 0, 1000, 0, 0, 0).unwrap();
+        assert_eq!(l.throttled_time(), Duration::default());
+
+        assert!(l.consume(1000, TokenType::Bytes));
+        assert_eq!(l.get_token_bucket(TokenType::Bytes).unwrap().budget(), 0);
+        assert!(!l.consume(100, TokenType::Bytes));
+        // The time spent blocked so far is accounted while the limiter is still blocked.
+        thread::sleep(Duration::from_millis(REFILL_TIMER_INTERVAL_MS / 2));
+        assert!(l.throttled_time() >= Duration::from_millis(REFILL_TIMER_INTERVAL_MS / 2));
+        thread::sleep(Duration::from_millis(REFILL_TIMER_INTERVAL_MS / 2));
+        assert!(l.event_handler().is_ok());
+
+        // Once unblocked, the throttled time stops increasing.
+        let throttled_time = l.throttled_time();
+        assert!(throttled_time >= Duration::from_millis(REFILL_TIMER_INTERVAL_MS));
+        thread::sleep(Duration::from_millis(10));
+        assert_eq!(l.throttled_time(), throttled_time);
+
+        // The bucket is replenished lazily, but the available budget accounts for the refill.
+        let bucket = l.get_token_bucket(TokenType::Bytes).unwrap();
+        assert!(bucket.budget() < 100);
+        assert!(bucket.available_budget() >= 100);
+    }
+
     #[test]
     fn test_rate_limiter_ops() {
         // rate limiter with limit of 1000 ops/s
@@ -874,10 +1256,136 @@ pub(crate) mod tests {
         assert_eq!(
             format!("{:?}", l),
             format!(
-                "RateLimiter {{ bandwidth: {:?}, ops: {:?} }}",
+                "RateLimiter {{ bandwidth: {:?}, ops: {:?}, group: None }}",
                 l.bandwidth(),
                 l.ops()
             ),
         );
     }
+
+    #[test]
+    fn test_rate_limiter_directions() {
+        // Shared limit of 10 ops, writes capped to 2 ops. The long refill time keeps the
+        // budgets steady across the test.
+        let mut l = RateLimiter::new(0, 0, 0, 10, 0, 100_000).unwrap();
+        l.update_direction_buckets(
+            IoDirection::Write,
+            BucketUpdate::None,
+            BucketUpdate::Update(TokenBucket::new(2, 0, 100_000).unwrap()),
+        );
+        assert!(l.direction_ops(IoDirection::Read).is_none());
+        assert!(l.direction_bandwidth(IoDirection::Write).is_none());
+        assert_eq!(l.direction_ops(IoDirection::Write).unwrap().capacity(), 2);
+
+        // Directional operations consume from the shared buckets as well.
+        assert!(l.consume_directional(1, TokenType::Ops, IoDirection::Write));
+        assert!(l.consume_directional(1, TokenType::Ops, IoDirection::Read));
+        assert_eq!(l.get_token_bucket(TokenType::Ops).unwrap().budget(), 8);
+
+        // Reverting a directional consume gives the tokens back to both buckets.
+        l.manual_replenish_directional(1, TokenType::Ops, IoDirection::Write);
+        assert_eq!(l.get_token_bucket(TokenType::Ops).unwrap().budget(), 9);
+        assert_eq!(l.direction_ops(IoDirection::Write).unwrap().budget(), 2);
+
+        assert!(l.consume_directional(2, TokenType::Ops, IoDirection::Write));
+        assert!(!l.consume_directional(1, TokenType::Ops, IoDirection::Write));
+        assert!(l.is_blocked());
+        // Wait for the timer to expire.
+        thread::sleep(Duration::from_millis(200));
+        l.event_handler().unwrap();
+
+        // Reads are only limited by the shared bucket.
+        assert!(l.consume_directional(7, TokenType::Ops, IoDirection::Read));
+        assert!(!l.consume_directional(1, TokenType::Ops, IoDirection::Read));
+        thread::sleep(Duration::from_millis(200));
+        l.event_handler().unwrap();
+
+        // A shared bucket failure gives the tokens back to the direction bucket.
+        l.update_direction_buckets(
+            IoDirection::Write,
+            BucketUpdate::None,
+            BucketUpdate::Update(TokenBucket::new(100, 0, 100_000).unwrap()),
+        );
+        l.update_buckets(
+            BucketUpdate::None,
+            BucketUpdate::Update(TokenBucket::new(10, 0, 100_000).unwrap()),
+        );
+        assert!(l.consume(8, TokenType::Ops));
+        assert!(!l.consume_directional(5, TokenType::Ops, IoDirection::Write));
+        assert_eq!(l.direction_ops(IoDirection::Write).unwrap().budget(), 100);
+        thread::sleep(Duration::from_millis(200));
+        l.event_handler().unwrap();
+
+        // Plain consume() calls ignore the direction buckets.
+        l.update_buckets(BucketUpdate::None, BucketUpdate::Disabled);
+        assert!(l.consume(1000, TokenType::Ops));
+        assert_eq!(l.direction_ops(IoDirection::Write).unwrap().budget(), 100);
+    }
+
+    #[test]
+    fn test_rate_limiter_group() {
+        let group = RateLimiterGroup::new("grp".to_string(), 0, 0, 0, 2, 0, 1000);
+        assert_eq!(group.name(), "grp");
+        assert!(group.bandwidth().is_none());
+        assert_eq!(group.ops().unwrap().capacity(), 2);
+
+        let mut l1 = RateLimiter::new(0, 0, 0, 10, 0, 1000).unwrap();
+        let mut l2 = RateLimiter::default();
+        l1.set_group(Some(group.clone()));
+        l2.set_group(Some(group.clone()));
+        assert_eq!(l1.group(), Some(&group));
+
+        // The group budget is shared by both limiters.
+        assert!(l1.consume(1, TokenType::Ops));
+        assert!(l2.consume(1, TokenType::Ops));
+        assert!(!l1.consume(1, TokenType::Ops));
+        // The failed consume() got its tokens back from the own bucket of `l1`.
+        assert_eq!(l1.get_token_bucket(TokenType::Ops).unwrap().budget(), 9);
+        // Both limiters get blocked and woken up on their own timer.
+        assert!(l1.is_blocked());
+        assert!(!l2.consume(1, TokenType::Ops));
+        assert!(l2.is_blocked());
+
+        // Manual replenishing also reaches the group.
+        let mut l3 = RateLimiter::default();
+        l3.set_group(Some(group.clone()));
+        l3.manual_replenish(1, TokenType::Ops);
+        assert!(l3.consume(1, TokenType::Ops));
+
+        // Updates to the group are seen by all the limiters.
+        group.update_buckets(BucketUpdate::None, BucketUpdate::Disabled);
+        assert!(group.ops().is_none());
+        assert!(l3.consume(100, TokenType::Ops));
+
+        // Moving to another group applies its limits, detaching removes them.
+        let group = RateLimiterGroup::new("other".to_string(), 0, 0, 0, 1, 0, 1000);
+        l3.set_group(Some(group));
+        assert!(l3.consume(1, TokenType::Ops));
+        assert!(!l3.consume(1, TokenType::Ops));
+        // Wait for the timer to expire.
+        thread::sleep(Duration::from_millis(200));
+        l3.event_handler().unwrap();
+        l3.set_group(None);
+        assert!(l3.group().is_none());
+        assert!(l3.consume(100, TokenType::Ops));
+    }
+
+    #[test]
+    fn test_rate_limiter_group_debug() {
+        let group = RateLimiterGroup::new("grp".to_string(), 1, 2, 3, 4, 5, 6);
+        assert_eq!(
+            format!("{:?}", group),
+            format!(
+                "RateLimiterGroup {{ name: \"grp\", bandwidth: {:?}, ops: {:?} }}",
+                group.bandwidth(),
+                group.ops()
+            ),
+        );
+        // Clones are handles to the same buckets.
+        assert_eq!(group, group.clone());
+        assert_ne!(
+            group,
+            RateLimiterGroup::new("grp".to_string(), 1, 2, 3, 4, 5, 6)
+        );
+    }
 }
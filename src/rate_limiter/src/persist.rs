@@ -5,7 +5,7 @@
 
 use super::*;
 use snapshot::Persist;
-use versionize::{VersionMap, Versionize, VersionizeResult};
+use versionize::{VersionMap, Versionize, VersionizeError, VersionizeResult};
 use versionize_derive::Versionize;
 
 /// State for saving a TokenBucket.
@@ -57,6 +57,54 @@ impl Persist<'_> for TokenBucket {
 pub struct RateLimiterState {
     ops: Option<TokenBucketState>,
     bandwidth: Option<TokenBucketState>,
+    #[version(start = 2, ser_fn = "direction_buckets_serialize")]
+    read_ops: Option<TokenBucketState>,
+    #[version(start = 2)]
+    read_bandwidth: Option<TokenBucketState>,
+    #[version(start = 2)]
+    write_ops: Option<TokenBucketState>,
+    #[version(start = 2)]
+    write_bandwidth: Option<TokenBucketState>,
+}
+
+impl RateLimiterState {
+    fn direction_buckets_serialize(&mut self, target_version: u16) -> VersionizeResult<()> {
+        if target_version < 2
+            && (self.read_ops.is_some()
+                || self.read_bandwidth.is_some()
+                || self.write_ops.is_some()
+                || self.write_bandwidth.is_some())
+        {
+            return Err(VersionizeError::Semantic(
+                "Target version does not support per-direction rate limiter buckets.".to_owned(),
+            ));
+        }
+
+        Ok(())
+    }
+}
+
+fn save_buckets(buckets: &TokenBuckets) -> (Option<TokenBucketState>, Option<TokenBucketState>) {
+    (
+        buckets.ops.as_ref().map(|ops| ops.save()),
+        buckets.bandwidth.as_ref().map(|bw| bw.save()),
+    )
+}
+
+fn restore_buckets(
+    ops: &Option<TokenBucketState>,
+    bandwidth: &Option<TokenBucketState>,
+) -> Result<TokenBuckets, io::Error> {
+    Ok(TokenBuckets {
+        ops: ops
+            .as_ref()
+            .map(|ops| TokenBucket::restore((), ops))
+            .transpose()?,
+        bandwidth: bandwidth
+            .as_ref()
+            .map(|bw| TokenBucket::restore((), bw))
+            .transpose()?,
+    })
 }
 
 impl Persist<'_> for RateLimiter {
@@ -65,9 +113,15 @@ impl Persist<'_> for RateLimiter {
     type Error = io::Error;
 
     fn save(&self) -> Self::State {
+        let (read_ops, read_bandwidth) = save_buckets(&self.read);
+        let (write_ops, write_bandwidth) = save_buckets(&self.write);
         RateLimiterState {
             ops: self.ops.as_ref().map(|ops| ops.save()),
             bandwidth: self.bandwidth.as_ref().map(|bw| bw.save()),
+            read_ops,
+            read_bandwidth,
+            write_ops,
+            write_bandwidth,
         }
     }
 
@@ -83,14 +137,55 @@ impl Persist<'_> for RateLimiter {
             } else {
                 None
             },
+            read: restore_buckets(&state.read_ops, &state.read_bandwidth)?,
+            write: restore_buckets(&state.write_ops, &state.write_bandwidth)?,
+            // Groups are saved separately, the owner of the rate limiter reattaches it.
+            group: None,
             timer_fd: TimerFd::new_custom(ClockId::Monotonic, true, true)?,
             timer_active: false,
+            blocked_since: Instant::now(),
+            throttled_time: Duration::default(),
         };
 
         Ok(rate_limiter)
     }
 }
 
+impl Persist<'_> for RateLimiterGroup {
+    type State = RateLimiterState;
+    /// The name of the group.
+    type ConstructorArgs = String;
+    type Error = io::Error;
+
+    fn save(&self) -> Self::State {
+        let buckets = self.buckets.lock().expect("Poisoned lock");
+        let (ops, bandwidth) = save_buckets(&buckets);
+        RateLimiterState {
+            ops,
+            bandwidth,
+            read_ops: None,
+            read_bandwidth: None,
+            write_ops: None,
+            write_bandwidth: None,
+        }
+    }
+
+    fn restore(name: Self::ConstructorArgs, state: &Self::State) -> Result<Self, Self::Error> {
+        let bandwidth = if let Some(bw) = state.bandwidth.as_ref() {
+            Some(TokenBucket::restore((), bw)?)
+        } else {
+            None
+        };
+        let ops = if let Some(ops) = state.ops.as_ref() {
+            Some(TokenBucket::restore((), ops)?)
+        } else {
+            None
+        };
+
+        Ok(RateLimiterGroup::with_buckets(name, bandwidth, ops))
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -205,4 +300,93 @@ mod tests {
             .unwrap()
             .partial_eq(&restored_rate_limiter.bandwidth().unwrap()));
     }
+
+    #[test]
+    fn test_rate_limiter_direction_persistence() {
+        let refill_time = 100_000;
+        let mut rate_limiter = RateLimiter::new(100, 0, refill_time, 0, 0, 0).unwrap();
+        rate_limiter.update_direction_buckets(
+            IoDirection::Write,
+            BucketUpdate::Update(TokenBucket::new(50, 0, refill_time).unwrap()),
+            BucketUpdate::Update(TokenBucket::new(5, 0, refill_time).unwrap()),
+        );
+        rate_limiter.consume_directional(10, TokenType::Bytes, IoDirection::Write);
+
+        let mut version_map = VersionMap::new();
+        version_map
+            .new_version()
+            .set_type_version(RateLimiterState::type_id(), 2);
+
+        // Per-direction buckets cannot be saved for a version which does not support them.
+        let mut mem = vec![0; 4096];
+        assert!(rate_limiter
+            .save()
+            .serialize(&mut mem.as_mut_slice(), &version_map, 1)
+            .is_err());
+
+        rate_limiter
+            .save()
+            .serialize(&mut mem.as_mut_slice(), &version_map, 2)
+            .unwrap();
+        let restored_rate_limiter = RateLimiter::restore(
+            (),
+            &RateLimiterState::deserialize(&mut mem.as_slice(), &version_map, 2).unwrap(),
+        )
+        .unwrap();
+
+        assert!(restored_rate_limiter
+            .direction_bandwidth(IoDirection::Read)
+            .is_none());
+        assert!(rate_limiter
+            .direction_bandwidth(IoDirection::Write)
+            .unwrap()
+            .partial_eq(
+                restored_rate_limiter
+                    .direction_bandwidth(IoDirection::Write)
+                    .unwrap()
+            ));
+        assert!(rate_limiter
+            .direction_ops(IoDirection::Write)
+            .unwrap()
+            .partial_eq(
+                restored_rate_limiter
+                    .direction_ops(IoDirection::Write)
+                    .unwrap()
+            ));
+    }
+
+    #[test]
+    fn test_rate_limiter_group_persistence() {
+        let refill_time = 100_000;
+        let group = RateLimiterGroup::new("grp".to_string(), 100, 0, refill_time, 0, 0, 0);
+        let mut rate_limiter = RateLimiter::default();
+        rate_limiter.set_group(Some(group.clone()));
+        rate_limiter.consume(10, TokenType::Bytes);
+
+        // The rate limiter is restored detached from its group.
+        let restored_rate_limiter =
+            RateLimiter::restore((), &rate_limiter.save()).expect("Unable to restore rate limiter");
+        assert!(restored_rate_limiter.group().is_none());
+
+        // Test serialization.
+        let mut mem = vec![0; 4096];
+        let version_map = VersionMap::new();
+        group
+            .save()
+            .serialize(&mut mem.as_mut_slice(), &version_map, 1)
+            .unwrap();
+        let restored_group = RateLimiterGroup::restore(
+            "grp".to_string(),
+            &RateLimiterState::deserialize(&mut mem.as_slice(), &version_map, 1).unwrap(),
+        )
+        .unwrap();
+
+        assert_eq!(restored_group.name(), "grp");
+        assert!(restored_group.ops().is_none());
+        assert!(group
+            .bandwidth()
+            .unwrap()
+            .partial_eq(&restored_group.bandwidth().unwrap()));
+        assert_eq!(restored_group.bandwidth().unwrap().budget(), 90);
+    }
 }
@@ -44,6 +44,7 @@ const SECCOMP_RET_KILL_PROCESS: u32 = 0x8000_0000;
 const SECCOMP_RET_LOG: u32 = 0x7ffc_0000;
 const SECCOMP_RET_TRACE: u32 = 0x7ff0_0000;
 const SECCOMP_RET_TRAP: u32 = 0x0003_0000;
+const SECCOMP_RET_USER_NOTIF: u32 = 0x7fc0_0000;
 const SECCOMP_RET_MASK: u32 = 0x0000_ffff;
 
 // Architecture identifier.
@@ -259,6 +260,8 @@ pub(crate) enum SeccompAction {
     Trace(u32),
     /// Sends `SIGSYS` to the calling process.
     Trap,
+    /// Suspends the calling thread and notifies the supervisor holding the filter's listener.
+    UserNotif,
 }
 
 /// Rule that `seccomp` attempts to match for a syscall.
@@ -546,6 +549,7 @@ impl From<SeccompAction> for u32 {
             SeccompAction::Log => SECCOMP_RET_LOG,
             SeccompAction::Trace(x) => SECCOMP_RET_TRACE | (x & SECCOMP_RET_MASK),
             SeccompAction::Trap => SECCOMP_RET_TRAP,
+            SeccompAction::UserNotif => SECCOMP_RET_USER_NOTIF,
         }
     }
 }
@@ -1779,6 +1783,7 @@ mod tests {
         assert_eq!(0x7ffc_0000, u32::from(SeccompAction::Log));
         assert_eq!(0x7ff0_002a, u32::from(SeccompAction::Trace(42)));
         assert_eq!(0x0003_0000, u32::from(SeccompAction::Trap));
+        assert_eq!(0x7fc0_0000, u32::from(SeccompAction::UserNotif));
     }
 
     #[test]
@@ -0,0 +1,929 @@
+// Copyright 2022 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Decompiler for the BPF programs generated by seccompiler-bin.
+//!
+//! The decompiler walks each program the same way seccompiler-bin lays it out (architecture
+//! check, syscall number load, one rule chain per syscall number, default action) and recovers
+//! the rules and argument conditions, in the JSON format accepted by seccompiler-bin.
+//!
+//! The output is canonical: rules are sorted by syscall name and the conditions of a rule by
+//! argument index. Two filters are equivalent if their decompiled forms are equal, which is
+//! what `diff_filters` relies on.
+
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::fmt::{Display, Formatter};
+
+use serde::Serialize;
+
+use crate::common::sock_filter;
+use crate::BpfThreadMap;
+
+// BPF instructions emitted by seccompiler-bin.
+// See /usr/include/linux/filter.h .
+const BPF_LD_W_ABS: u16 = 0x20;
+const BPF_ALU_AND_K: u16 = 0x54;
+const BPF_JMP_JA: u16 = 0x05;
+const BPF_JMP_JEQ_K: u16 = 0x15;
+const BPF_JMP_JGT_K: u16 = 0x25;
+const BPF_JMP_JGE_K: u16 = 0x35;
+const BPF_RET_K: u16 = 0x06;
+
+// Return codes for BPF programs.
+// See /usr/include/linux/seccomp.h .
+const SECCOMP_RET_ACTION_FULL: u32 = 0xffff_0000;
+const SECCOMP_RET_DATA: u32 = 0x0000_ffff;
+const SECCOMP_RET_ALLOW: u32 = 0x7fff_0000;
+const SECCOMP_RET_ERRNO: u32 = 0x0005_0000;
+const SECCOMP_RET_KILL_THREAD: u32 = 0x0000_0000;
+const SECCOMP_RET_KILL_PROCESS: u32 = 0x8000_0000;
+const SECCOMP_RET_LOG: u32 = 0x7ffc_0000;
+const SECCOMP_RET_TRACE: u32 = 0x7ff0_0000;
+const SECCOMP_RET_TRAP: u32 = 0x0003_0000;
+const SECCOMP_RET_USER_NOTIF: u32 = 0x7fc0_0000;
+
+// Architecture identifiers.
+// See /usr/include/linux/audit.h .
+const AUDIT_ARCH_X86_64: u32 = 62 | 0x8000_0000 | 0x4000_0000;
+const AUDIT_ARCH_AARCH64: u32 = 183 | 0x8000_0000 | 0x4000_0000;
+
+// `struct seccomp_data` offsets and sizes of fields in bytes.
+const SECCOMP_DATA_NR_OFFSET: u32 = 0;
+const SECCOMP_DATA_ARCH_OFFSET: u32 = 4;
+const SECCOMP_DATA_ARGS_OFFSET: u32 = 16;
+const SECCOMP_DATA_ARG_SIZE: u32 = 8;
+const SECCOMP_DATA_ARGS_END: u32 = SECCOMP_DATA_ARGS_OFFSET + 6 * SECCOMP_DATA_ARG_SIZE;
+
+/// Decompilation errors.
+#[derive(Debug, PartialEq)]
+pub enum DecompileError {
+    /// Invalid target arch string.
+    InvalidArch(String),
+    /// The program does not check for the target architecture.
+    ArchMismatch,
+    /// Instruction which seccompiler-bin does not generate at that position.
+    UnexpectedInstruction(usize),
+    /// Jump outside of the program.
+    InvalidJump(usize),
+    /// Unknown syscall number.
+    UnknownSyscall(u32),
+    /// Unknown return value.
+    UnknownAction(u32),
+    /// The rules don't share the same action, which the JSON format cannot express.
+    MixedRuleActions,
+}
+
+impl Display for DecompileError {
+    fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
+        use self::DecompileError::*;
+
+        match *self {
+            InvalidArch(ref arch) => write!(f, "Invalid target arch string: {}", arch),
+            ArchMismatch => write!(f, "The filter was not compiled for the target arch."),
+            UnexpectedInstruction(pc) => write!(f, "Unexpected BPF instruction at {}.", pc),
+            InvalidJump(pc) => write!(f, "Invalid BPF jump to {}.", pc),
+            UnknownSyscall(nr) => write!(f, "Unknown syscall number: {}.", nr),
+            UnknownAction(value) => write!(f, "Unknown return value: {:#x}.", value),
+            MixedRuleActions => write!(f, "The filter rules have different actions."),
+        }
+    }
+}
+
+type Result<T> = std::result::Result<T, DecompileError>;
+
+/// Action of a decompiled filter, serialized like the `SeccompAction` of seccompiler-bin.
+#[derive(Clone, Debug, Eq, Ord, PartialEq, PartialOrd, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DecompiledAction {
+    /// Allows syscall.
+    Allow,
+    /// Returns from syscall with specified error number.
+    Errno(u32),
+    /// Kills calling thread.
+    KillThread,
+    /// Kills calling process.
+    KillProcess,
+    /// Same as allow but logs call.
+    Log,
+    /// Notifies tracing process of the caller with respective number.
+    Trace(u32),
+    /// Sends `SIGSYS` to the calling process.
+    Trap,
+    /// Suspends the calling thread and notifies the supervisor holding the filter's listener.
+    UserNotif,
+}
+
+impl DecompiledAction {
+    fn from_ret(value: u32) -> Result<Self> {
+        let data = value & SECCOMP_RET_DATA;
+        match value & SECCOMP_RET_ACTION_FULL {
+            SECCOMP_RET_ALLOW => Ok(DecompiledAction::Allow),
+            SECCOMP_RET_ERRNO => Ok(DecompiledAction::Errno(data)),
+            SECCOMP_RET_KILL_THREAD => Ok(DecompiledAction::KillThread),
+            SECCOMP_RET_KILL_PROCESS => Ok(DecompiledAction::KillProcess),
+            SECCOMP_RET_LOG => Ok(DecompiledAction::Log),
+            SECCOMP_RET_TRACE => Ok(DecompiledAction::Trace(data)),
+            SECCOMP_RET_TRAP => Ok(DecompiledAction::Trap),
+            SECCOMP_RET_USER_NOTIF => Ok(DecompiledAction::UserNotif),
+            _ => Err(DecompileError::UnknownAction(value)),
+        }
+    }
+}
+
+impl Display for DecompiledAction {
+    fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
+        use self::DecompiledAction::*;
+
+        match *self {
+            Allow => write!(f, "allow"),
+            Errno(errno) => write!(f, "errno({})", errno),
+            KillThread => write!(f, "kill_thread"),
+            KillProcess => write!(f, "kill_process"),
+            Log => write!(f, "log"),
+            Trace(value) => write!(f, "trace({})", value),
+            Trap => write!(f, "trap"),
+            UserNotif => write!(f, "user_notif"),
+        }
+    }
+}
+
+/// Length of a decompiled argument value.
+#[derive(Clone, Copy, Debug, Eq, Ord, PartialEq, PartialOrd, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DecompiledCmpArgLen {
+    /// Argument value length is 4 bytes.
+    Dword,
+    /// Argument value length is 8 bytes.
+    Qword,
+}
+
+/// Comparison of a decompiled condition.
+#[derive(Clone, Debug, Eq, Ord, PartialEq, PartialOrd, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DecompiledCmpOp {
+    /// Argument value is equal to the specified value.
+    Eq,
+    /// Argument value is greater than or equal to the specified value.
+    Ge,
+    /// Argument value is greater than specified value.
+    Gt,
+    /// Argument value is less than or equal to the specified value.
+    Le,
+    /// Argument value is less than specified value.
+    Lt,
+    /// Masked argument value is equal to the specified value.
+    MaskedEq(u64),
+    /// Argument value is not equal to the specified value.
+    Ne,
+}
+
+/// Decompiled argument condition.
+#[derive(Clone, Debug, Eq, Ord, PartialEq, PartialOrd, Serialize)]
+pub struct DecompiledCondition {
+    /// Index of the argument that is compared.
+    pub index: u8,
+    /// Length of the argument value that is compared.
+    #[serde(rename = "type")]
+    pub arg_len: DecompiledCmpArgLen,
+    /// Comparison to perform.
+    pub op: DecompiledCmpOp,
+    /// The value the argument is compared with. For `masked_eq`, this is the masked value.
+    pub val: u64,
+}
+
+impl Display for DecompiledCondition {
+    fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
+        let arg_len = match self.arg_len {
+            DecompiledCmpArgLen::Dword => "dword",
+            DecompiledCmpArgLen::Qword => "qword",
+        };
+        write!(f, "args[{}]:{} ", self.index, arg_len)?;
+        match self.op {
+            DecompiledCmpOp::Eq => write!(f, "== {:#x}", self.val),
+            DecompiledCmpOp::Ge => write!(f, ">= {:#x}", self.val),
+            DecompiledCmpOp::Gt => write!(f, "> {:#x}", self.val),
+            DecompiledCmpOp::Le => write!(f, "<= {:#x}", self.val),
+            DecompiledCmpOp::Lt => write!(f, "< {:#x}", self.val),
+            DecompiledCmpOp::MaskedEq(mask) => write!(f, "& {:#x} == {:#x}", mask, self.val),
+            DecompiledCmpOp::Ne => write!(f, "!= {:#x}", self.val),
+        }
+    }
+}
+
+/// Decompiled syscall rule.
+#[derive(Clone, Debug, Eq, Ord, PartialEq, PartialOrd, Serialize)]
+pub struct DecompiledRule {
+    /// Name of the syscall.
+    pub syscall: String,
+    /// Conditions on the syscall arguments, all of which must match.
+    #[serde(rename = "args", skip_serializing_if = "Vec::is_empty")]
+    pub conditions: Vec<DecompiledCondition>,
+}
+
+impl Display for DecompiledRule {
+    fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
+        write!(f, "{}", self.syscall)?;
+        if !self.conditions.is_empty() {
+            let conditions: Vec<String> = self.conditions.iter().map(|c| c.to_string()).collect();
+            write!(f, "({})", conditions.join(", "))?;
+        }
+        Ok(())
+    }
+}
+
+/// Decompiled filter of a thread category.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct DecompiledFilter {
+    /// Action applied if no rule matches.
+    pub default_action: DecompiledAction,
+    /// Action applied if a rule matches.
+    pub filter_action: DecompiledAction,
+    /// The rules, sorted by syscall name and conditions.
+    pub filter: Vec<DecompiledRule>,
+}
+
+/// Semantic difference between two filter files.
+#[derive(Clone, Debug, PartialEq)]
+pub enum FilterChange {
+    /// Thread category only present in the new filters.
+    CategoryAdded(String),
+    /// Thread category only present in the old filters.
+    CategoryRemoved(String),
+    /// The default action of a thread category changed, from the first to the second action.
+    DefaultAction(String, DecompiledAction, DecompiledAction),
+    /// The filter action of a thread category changed, from the first to the second action.
+    FilterAction(String, DecompiledAction, DecompiledAction),
+    /// Rule only present in the new filter of a thread category.
+    RuleAdded(String, DecompiledRule),
+    /// Rule only present in the old filter of a thread category.
+    RuleRemoved(String, DecompiledRule),
+}
+
+impl Display for FilterChange {
+    fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
+        use self::FilterChange::*;
+
+        match *self {
+            CategoryAdded(ref category) => write!(f, "+ {}", category),
+            CategoryRemoved(ref category) => write!(f, "- {}", category),
+            DefaultAction(ref category, ref old, ref new) => {
+                write!(f, "~ {}: default_action {} -> {}", category, old, new)
+            }
+            FilterAction(ref category, ref old, ref new) => {
+                write!(f, "~ {}: filter_action {} -> {}", category, old, new)
+            }
+            RuleAdded(ref category, ref rule) => write!(f, "+ {}: {}", category, rule),
+            RuleRemoved(ref category, ref rule) => write!(f, "- {}: {}", category, rule),
+        }
+    }
+}
+
+// Walks a BPF program laid out by seccompiler-bin.
+struct Decompiler<'a> {
+    program: &'a [sock_filter],
+    syscall_names: &'a HashMap<u32, String>,
+}
+
+impl<'a> Decompiler<'a> {
+    fn insn(&self, pc: usize) -> Result<&'a sock_filter> {
+        self.program.get(pc).ok_or(DecompileError::InvalidJump(pc))
+    }
+
+    // Returns the target of a jump, relative to the instruction at `pc`.
+    fn jump_target(&self, pc: usize, offset: u32) -> Result<usize> {
+        let target = pc + 1 + offset as usize;
+        self.insn(target)?;
+        Ok(target)
+    }
+
+    // Follows the unconditional jumps starting at `pc`. seccompiler-bin inserts them at the
+    // start of each rule and between conditions, when the rules get too long.
+    fn resolve(&self, mut pc: usize) -> Result<usize> {
+        for _ in 0..self.program.len() {
+            let insn = self.insn(pc)?;
+            if insn.code != BPF_JMP_JA {
+                return Ok(pc);
+            }
+            pc = self.jump_target(pc, insn.k)?;
+        }
+        Err(DecompileError::InvalidJump(pc))
+    }
+
+    // Returns the argument index and whether the most significant half is loaded, if the
+    // instruction at `pc` loads a syscall argument.
+    fn arg_load(&self, pc: usize) -> Result<Option<(u8, bool)>> {
+        let insn = self.insn(pc)?;
+        if insn.code != BPF_LD_W_ABS
+            || insn.k < SECCOMP_DATA_ARGS_OFFSET
+            || insn.k >= SECCOMP_DATA_ARGS_END
+            || insn.k % 4 != 0
+        {
+            return Ok(None);
+        }
+        let offset = insn.k - SECCOMP_DATA_ARGS_OFFSET;
+        Ok(Some((
+            (offset / SECCOMP_DATA_ARG_SIZE) as u8,
+            offset % SECCOMP_DATA_ARG_SIZE != 0,
+        )))
+    }
+
+    // Decompiles the comparison of the least significant half of an argument at `pc`.
+    // Returns the condition, the next instruction if the condition matches and the start of the
+    // next rule, where the program jumps if it doesn't.
+    fn dword_condition(&self, pc: usize) -> Result<(DecompiledCondition, usize, usize)> {
+        let index = match self.arg_load(pc)? {
+            Some((index, false)) => index,
+            _ => return Err(DecompileError::UnexpectedInstruction(pc)),
+        };
+        let mut jump_pc = pc + 1;
+        let mask = match self.insn(jump_pc)? {
+            insn if insn.code == BPF_ALU_AND_K => {
+                jump_pc += 1;
+                Some(insn.k)
+            }
+            _ => None,
+        };
+
+        let jump = self.insn(jump_pc)?;
+        let (op, fail_offset) = match (jump.code, jump.jt, jump.jf, mask) {
+            (BPF_JMP_JEQ_K, 0, jf, Some(mask)) if jf != 0 => {
+                (DecompiledCmpOp::MaskedEq(u64::from(mask)), jf)
+            }
+            (BPF_JMP_JEQ_K, 0, jf, None) if jf != 0 => (DecompiledCmpOp::Eq, jf),
+            (BPF_JMP_JEQ_K, jt, 0, None) if jt != 0 => (DecompiledCmpOp::Ne, jt),
+            (BPF_JMP_JGE_K, 0, jf, None) if jf != 0 => (DecompiledCmpOp::Ge, jf),
+            (BPF_JMP_JGE_K, jt, 0, None) if jt != 0 => (DecompiledCmpOp::Lt, jt),
+            (BPF_JMP_JGT_K, 0, jf, None) if jf != 0 => (DecompiledCmpOp::Gt, jf),
+            (BPF_JMP_JGT_K, jt, 0, None) if jt != 0 => (DecompiledCmpOp::Le, jt),
+            _ => return Err(DecompileError::UnexpectedInstruction(jump_pc)),
+        };
+        let fail = self.resolve(self.jump_target(jump_pc, u32::from(fail_offset))?)?;
+
+        Ok((
+            DecompiledCondition {
+                index,
+                arg_len: DecompiledCmpArgLen::Dword,
+                op,
+                val: u64::from(jump.k),
+            },
+            jump_pc + 1,
+            fail,
+        ))
+    }
+
+    // Decompiles the condition starting at `pc`. See `dword_condition` for the return value.
+    fn condition(&self, pc: usize) -> Result<(DecompiledCondition, usize, usize)> {
+        let index = match self.arg_load(pc)? {
+            Some((index, true)) => index,
+            Some((_, false)) => return self.dword_condition(pc),
+            None => return Err(DecompileError::UnexpectedInstruction(pc)),
+        };
+
+        // The most significant half is compared first, with the same operand for every
+        // comparison, then the least significant half determines the operator.
+        let (mut msb, mut mask_msb) = (None, None);
+        let mut lsb_pc = pc + 1;
+        while self.arg_load(lsb_pc)?.is_none() {
+            let insn = self.insn(lsb_pc)?;
+            match insn.code {
+                BPF_ALU_AND_K if mask_msb.is_none() => mask_msb = Some(insn.k),
+                BPF_JMP_JEQ_K | BPF_JMP_JGT_K if msb.map_or(true, |msb| msb == insn.k) => {
+                    msb = Some(insn.k)
+                }
+                _ => return Err(DecompileError::UnexpectedInstruction(lsb_pc)),
+            }
+            lsb_pc += 1;
+        }
+
+        let (mut condition, next, fail) = self.dword_condition(lsb_pc)?;
+        if condition.index != index {
+            return Err(DecompileError::UnexpectedInstruction(lsb_pc));
+        }
+        condition.arg_len = DecompiledCmpArgLen::Qword;
+        condition.val |= u64::from(msb.unwrap_or(0)) << 32;
+        if let DecompiledCmpOp::MaskedEq(ref mut mask) = condition.op {
+            *mask |= u64::from(mask_msb.unwrap_or(0)) << 32;
+        }
+        Ok((condition, next, fail))
+    }
+
+    // Decompiles the rule chain of a syscall, starting at `pc`, which is the first instruction
+    // of its first rule.
+    fn rule_chain(
+        &self,
+        mut pc: usize,
+    ) -> Result<Vec<(Vec<DecompiledCondition>, DecompiledAction)>> {
+        let mut rules = Vec::new();
+        for _ in 0..self.program.len() {
+            let mut conditions = Vec::new();
+            let mut next_rule = None;
+            let mut cond_pc = self.resolve(pc)?;
+            let action = loop {
+                let insn = self.insn(cond_pc)?;
+                if insn.code == BPF_RET_K {
+                    break DecompiledAction::from_ret(insn.k)?;
+                }
+                let (condition, next, fail) = self.condition(cond_pc)?;
+                conditions.push(condition);
+                next_rule.get_or_insert(fail);
+                cond_pc = self.resolve(next)?;
+            };
+            rules.push((conditions, action));
+
+            // A rule without conditions is the only rule of its chain. Otherwise, the chain
+            // ends when failing the conditions leads to the default action.
+            match next_rule {
+                Some(next) if self.insn(next)?.code != BPF_RET_K => pc = next,
+                _ => return Ok(rules),
+            }
+        }
+        Err(DecompileError::InvalidJump(pc))
+    }
+
+    fn filter(&self, audit_arch: u32) -> Result<DecompiledFilter> {
+        // Architecture check.
+        let arch_check = [
+            (BPF_LD_W_ABS, SECCOMP_DATA_ARCH_OFFSET),
+            (BPF_JMP_JEQ_K, audit_arch),
+            (BPF_RET_K, SECCOMP_RET_KILL_PROCESS),
+        ];
+        for (pc, (code, k)) in arch_check.iter().enumerate() {
+            let insn = self.insn(pc)?;
+            if insn.code != *code {
+                return Err(DecompileError::UnexpectedInstruction(pc));
+            }
+            if insn.k != *k {
+                return Err(DecompileError::ArchMismatch);
+            }
+        }
+
+        let insn = self.insn(3)?;
+        if insn.code == BPF_RET_K {
+            let default_action = DecompiledAction::from_ret(insn.k)?;
+            return Ok(DecompiledFilter {
+                filter_action: placeholder_filter_action(&default_action),
+                default_action,
+                filter: vec![],
+            });
+        }
+        if insn.code != BPF_LD_W_ABS || insn.k != SECCOMP_DATA_NR_OFFSET {
+            return Err(DecompileError::UnexpectedInstruction(3));
+        }
+
+        let mut filter_action = None;
+        let mut rules = BTreeSet::new();
+        let mut pc = 4;
+        for _ in 0..self.program.len() {
+            pc = self.resolve(pc)?;
+            let insn = self.insn(pc)?;
+            match (insn.code, insn.jt, insn.jf) {
+                (BPF_RET_K, _, _) => {
+                    let default_action = DecompiledAction::from_ret(insn.k)?;
+                    return Ok(DecompiledFilter {
+                        filter_action: filter_action
+                            .unwrap_or_else(|| placeholder_filter_action(&default_action)),
+                        default_action,
+                        filter: rules.into_iter().collect(),
+                    });
+                }
+                (BPF_JMP_JEQ_K, 0, 1) => {
+                    let syscall = self
+                        .syscall_names
+                        .get(&insn.k)
+                        .ok_or(DecompileError::UnknownSyscall(insn.k))?;
+                    for (mut conditions, action) in self.rule_chain(pc + 1)? {
+                        if *filter_action.get_or_insert_with(|| action.clone()) != action {
+                            return Err(DecompileError::MixedRuleActions);
+                        }
+                        conditions.sort();
+                        rules.insert(DecompiledRule {
+                            syscall: syscall.clone(),
+                            conditions,
+                        });
+                    }
+                    pc += 2;
+                }
+                _ => return Err(DecompileError::UnexpectedInstruction(pc)),
+            }
+        }
+        Err(DecompileError::InvalidJump(pc))
+    }
+}
+
+// A filter without rules doesn't record its filter action, pick one that is valid.
+fn placeholder_filter_action(default_action: &DecompiledAction) -> DecompiledAction {
+    match default_action {
+        DecompiledAction::Allow => DecompiledAction::KillProcess,
+        _ => DecompiledAction::Allow,
+    }
+}
+
+// Returns the audit value and syscall names of the given arch.
+fn arch_info(arch: &str) -> Result<(u32, HashMap<u32, String>)> {
+    let mut table = HashMap::new();
+    let audit_arch = match arch.to_lowercase().as_str() {
+        "x86_64" => {
+            crate::syscall_table_x86_64::make_syscall_table(&mut table);
+            AUDIT_ARCH_X86_64
+        }
+        "aarch64" => {
+            crate::syscall_table_aarch64::make_syscall_table(&mut table);
+            AUDIT_ARCH_AARCH64
+        }
+        _ => return Err(DecompileError::InvalidArch(arch.to_string())),
+    };
+    Ok((
+        audit_arch,
+        table
+            .into_iter()
+            .map(|(name, nr)| (nr as u32, name))
+            .collect(),
+    ))
+}
+
+/// Decompile a BPF program generated by seccompiler-bin for the given target arch
+/// (`x86_64` or `aarch64`).
+pub fn decompile_filter(program: &[sock_filter], arch: &str) -> Result<DecompiledFilter> {
+    let (audit_arch, syscall_names) = arch_info(arch)?;
+    Decompiler {
+        program,
+        syscall_names: &syscall_names,
+    }
+    .filter(audit_arch)
+}
+
+/// Decompile the BPF programs of all the thread categories in `filters`, as deserialized by
+/// `deserialize_binary`.
+pub fn decompile_filters(
+    filters: &BpfThreadMap,
+    arch: &str,
+) -> Result<BTreeMap<String, DecompiledFilter>> {
+    let (audit_arch, syscall_names) = arch_info(arch)?;
+    filters
+        .iter()
+        .map(|(category, program)| {
+            let filter = Decompiler {
+                program,
+                syscall_names: &syscall_names,
+            }
+            .filter(audit_arch)?;
+            Ok((category.clone(), filter))
+        })
+        .collect()
+}
+
+/// Returns the semantic differences between two sets of decompiled filters. The order of the
+/// rules and of their conditions does not matter.
+pub fn diff_filters(
+    old: &BTreeMap<String, DecompiledFilter>,
+    new: &BTreeMap<String, DecompiledFilter>,
+) -> Vec<FilterChange> {
+    let mut changes = Vec::new();
+    for category in old.keys().filter(|category| !new.contains_key(*category)) {
+        changes.push(FilterChange::CategoryRemoved(category.clone()));
+    }
+    for category in new.keys().filter(|category| !old.contains_key(*category)) {
+        changes.push(FilterChange::CategoryAdded(category.clone()));
+    }
+
+    for (category, old_filter) in old.iter() {
+        let new_filter = match new.get(category) {
+            Some(filter) => filter,
+            None => continue,
+        };
+        if old_filter.default_action != new_filter.default_action {
+            changes.push(FilterChange::DefaultAction(
+                category.clone(),
+                old_filter.default_action.clone(),
+                new_filter.default_action.clone(),
+            ));
+        }
+        // The filter action of a filter without rules is made up, so it can't change.
+        if old_filter.filter_action != new_filter.filter_action
+            && !old_filter.filter.is_empty()
+            && !new_filter.filter.is_empty()
+        {
+            changes.push(FilterChange::FilterAction(
+                category.clone(),
+                old_filter.filter_action.clone(),
+                new_filter.filter_action.clone(),
+            ));
+        }
+
+        let old_rules: BTreeSet<&DecompiledRule> = old_filter.filter.iter().collect();
+        let new_rules: BTreeSet<&DecompiledRule> = new_filter.filter.iter().collect();
+        for rule in old_rules.difference(&new_rules) {
+            changes.push(FilterChange::RuleRemoved(category.clone(), (*rule).clone()));
+        }
+        for rule in new_rules.difference(&old_rules) {
+            changes.push(FilterChange::RuleAdded(category.clone(), (*rule).clone()));
+        }
+    }
+    changes
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+
+    // Builds a BPF instruction.
+    fn insn(code: u16, k: u32, jt: u8, jf: u8) -> sock_filter {
+        sock_filter { code, jt, jf, k }
+    }
+
+    fn arch_check() -> Vec<sock_filter> {
+        let audit_arch = if cfg!(target_arch = "aarch64") {
+            AUDIT_ARCH_AARCH64
+        } else {
+            AUDIT_ARCH_X86_64
+        };
+        vec![
+            insn(BPF_LD_W_ABS, SECCOMP_DATA_ARCH_OFFSET, 0, 0),
+            insn(BPF_JMP_JEQ_K, audit_arch, 1, 0),
+            insn(BPF_RET_K, SECCOMP_RET_KILL_PROCESS, 0, 0),
+        ]
+    }
+
+    fn host_arch() -> &'static str {
+        std::env::consts::ARCH
+    }
+
+    fn syscall_nr(name: &str) -> u32 {
+        let (_, names) = arch_info(host_arch()).unwrap();
+        *names.iter().find(|(_, n)| n.as_str() == name).unwrap().0
+    }
+
+    // The program seccompiler-bin generates for:
+    // - `read` without conditions;
+    // - `ioctl` with two rules, the first with a dword `eq` on args[1] and a qword `masked_eq`
+    //   on args[0], the second with a qword `le` on args[2];
+    // with `trap` as default action and `allow` as filter action.
+    fn sample_program() -> Vec<sock_filter> {
+        let mut program = arch_check();
+        program.extend(vec![
+            insn(BPF_LD_W_ABS, SECCOMP_DATA_NR_OFFSET, 0, 0),
+            // read
+            insn(BPF_JMP_JEQ_K, syscall_nr("read"), 0, 1),
+            insn(BPF_JMP_JA, 1, 0, 0),
+            insn(BPF_JMP_JA, 2, 0, 0),
+            insn(BPF_RET_K, SECCOMP_RET_ALLOW, 0, 0),
+            insn(BPF_RET_K, SECCOMP_RET_TRAP, 0, 0),
+            // ioctl
+            insn(BPF_JMP_JEQ_K, syscall_nr("ioctl"), 0, 1),
+            // First rule.
+            insn(BPF_JMP_JA, 1, 0, 0),
+            insn(BPF_JMP_JA, 10, 0, 0),
+            insn(BPF_LD_W_ABS, 20, 0, 0),
+            insn(BPF_ALU_AND_K, 0xff, 0, 0),
+            insn(BPF_JMP_JEQ_K, 0x1, 0, 6),
+            insn(BPF_LD_W_ABS, 16, 0, 0),
+            insn(BPF_ALU_AND_K, 0xf0, 0, 0),
+            insn(BPF_JMP_JEQ_K, 0x20, 0, 3),
+            insn(BPF_LD_W_ABS, 24, 0, 0),
+            insn(BPF_JMP_JEQ_K, 0x5401, 0, 1),
+            insn(BPF_RET_K, SECCOMP_RET_ALLOW, 0, 0),
+            // Second rule.
+            insn(BPF_JMP_JA, 1, 0, 0),
+            insn(BPF_JMP_JA, 7, 0, 0),
+            insn(BPF_LD_W_ABS, 36, 0, 0),
+            insn(BPF_JMP_JGT_K, 0, 4, 0),
+            insn(BPF_JMP_JEQ_K, 0, 0, 2),
+            insn(BPF_LD_W_ABS, 32, 0, 0),
+            insn(BPF_JMP_JGT_K, 7, 1, 0),
+            insn(BPF_RET_K, SECCOMP_RET_ALLOW, 0, 0),
+            insn(BPF_RET_K, SECCOMP_RET_TRAP, 0, 0),
+            // Default action.
+            insn(BPF_RET_K, SECCOMP_RET_TRAP, 0, 0),
+        ]);
+        program
+    }
+
+    #[test]
+    fn test_error_messages() {
+        assert_eq!(
+            DecompileError::InvalidArch("riscv".to_string()).to_string(),
+            "Invalid target arch string: riscv"
+        );
+        assert_eq!(
+            DecompileError::ArchMismatch.to_string(),
+            "The filter was not compiled for the target arch."
+        );
+        assert_eq!(
+            DecompileError::UnexpectedInstruction(3).to_string(),
+            "Unexpected BPF instruction at 3."
+        );
+        assert_eq!(
+            DecompileError::InvalidJump(300).to_string(),
+            "Invalid BPF jump to 300."
+        );
+        assert_eq!(
+            DecompileError::UnknownSyscall(1000).to_string(),
+            "Unknown syscall number: 1000."
+        );
+        assert_eq!(
+            DecompileError::UnknownAction(0x1234_0000).to_string(),
+            "Unknown return value: 0x12340000."
+        );
+        assert_eq!(
+            DecompileError::MixedRuleActions.to_string(),
+            "The filter rules have different actions."
+        );
+    }
+
+    #[test]
+    fn test_action_from_ret() {
+        assert_eq!(
+            DecompiledAction::from_ret(0x0005_002a).unwrap(),
+            DecompiledAction::Errno(42)
+        );
+        assert_eq!(
+            DecompiledAction::from_ret(0x7fc0_0000).unwrap(),
+            DecompiledAction::UserNotif
+        );
+        assert_eq!(DecompiledAction::UserNotif.to_string(), "user_notif");
+        assert!(matches!(
+            DecompiledAction::from_ret(0x1234_0000),
+            Err(DecompileError::UnknownAction(0x1234_0000))
+        ));
+    }
+
+    #[test]
+    fn test_decompile_filter() {
+        let filter = decompile_filter(&sample_program(), host_arch()).unwrap();
+        assert_eq!(filter.default_action, DecompiledAction::Trap);
+        assert_eq!(filter.filter_action, DecompiledAction::Allow);
+        assert_eq!(
+            filter.filter,
+            vec![
+                DecompiledRule {
+                    syscall: "ioctl".to_string(),
+                    conditions: vec![
+                        DecompiledCondition {
+                            index: 0,
+                            arg_len: DecompiledCmpArgLen::Qword,
+                            op: DecompiledCmpOp::MaskedEq(0xff_0000_00f0),
+                            val: 0x1_0000_0020,
+                        },
+                        DecompiledCondition {
+                            index: 1,
+                            arg_len: DecompiledCmpArgLen::Dword,
+                            op: DecompiledCmpOp::Eq,
+                            val: 0x5401,
+                        },
+                    ],
+                },
+                DecompiledRule {
+                    syscall: "ioctl".to_string(),
+                    conditions: vec![DecompiledCondition {
+                        index: 2,
+                        arg_len: DecompiledCmpArgLen::Qword,
+                        op: DecompiledCmpOp::Le,
+                        val: 7,
+                    }],
+                },
+                DecompiledRule {
+                    syscall: "read".to_string(),
+                    conditions: vec![],
+                },
+            ]
+        );
+
+        let json = serde_json::to_value(&filter).unwrap();
+        assert_eq!(json["default_action"], "trap");
+        assert_eq!(
+            json["filter"][0]["args"][0]["op"]["masked_eq"],
+            0xff_0000_00f0u64
+        );
+        assert_eq!(json["filter"][0]["args"][1]["type"], "dword");
+        assert!(json["filter"][2].get("args").is_none());
+
+        // Filter without rules.
+        let mut program = arch_check();
+        program.push(insn(BPF_RET_K, SECCOMP_RET_ERRNO | 1, 0, 0));
+        let filter = decompile_filter(&program, host_arch()).unwrap();
+        assert_eq!(filter.default_action, DecompiledAction::Errno(1));
+        assert_eq!(filter.filter_action, DecompiledAction::Allow);
+        assert!(filter.filter.is_empty());
+    }
+
+    #[test]
+    fn test_decompile_errors() {
+        assert_eq!(
+            decompile_filter(&sample_program(), "riscv").unwrap_err(),
+            DecompileError::InvalidArch("riscv".to_string())
+        );
+        let other_arch = if host_arch() == "x86_64" {
+            "aarch64"
+        } else {
+            "x86_64"
+        };
+        assert_eq!(
+            decompile_filter(&sample_program(), other_arch).unwrap_err(),
+            DecompileError::ArchMismatch
+        );
+
+        // Truncated program.
+        let program = sample_program();
+        assert_eq!(
+            decompile_filter(&program[..10], host_arch()).unwrap_err(),
+            DecompileError::InvalidJump(10)
+        );
+
+        // Unknown return value.
+        let mut program = sample_program();
+        program[7].k = 0x1234_0000;
+        assert_eq!(
+            decompile_filter(&program, host_arch()).unwrap_err(),
+            DecompileError::UnknownAction(0x1234_0000)
+        );
+
+        // Rules with different actions.
+        let mut program = sample_program();
+        program[7].k = SECCOMP_RET_LOG;
+        assert_eq!(
+            decompile_filter(&program, host_arch()).unwrap_err(),
+            DecompileError::MixedRuleActions
+        );
+
+        // Unknown syscall.
+        let mut program = sample_program();
+        program[4].k = 1000;
+        assert_eq!(
+            decompile_filter(&program, host_arch()).unwrap_err(),
+            DecompileError::UnknownSyscall(1000)
+        );
+
+        // Jump outside of the program.
+        let mut program = sample_program();
+        program[5].k = 100;
+        assert_eq!(
+            decompile_filter(&program, host_arch()).unwrap_err(),
+            DecompileError::InvalidJump(106)
+        );
+    }
+
+    #[test]
+    fn test_diff_filters() {
+        let mut map = BpfThreadMap::new();
+        map.insert("vmm".to_string(), Arc::new(sample_program()));
+        map.insert("api".to_string(), Arc::new(sample_program()));
+        let old = decompile_filters(&map, host_arch()).unwrap();
+        assert_eq!(old.len(), 2);
+        assert!(diff_filters(&old, &old).is_empty());
+
+        let mut new = old.clone();
+        new.remove("api");
+        let vcpu = new["vmm"].clone();
+        new.insert("vcpu".to_string(), vcpu);
+        let vmm = new.get_mut("vmm").unwrap();
+        vmm.default_action = DecompiledAction::KillProcess;
+        let read = vmm.filter.pop().unwrap();
+        vmm.filter.insert(
+            0,
+            DecompiledRule {
+                syscall: "close".to_string(),
+                conditions: vec![],
+            },
+        );
+        // Reordering the rules is not a change.
+        vmm.filter.swap(1, 2);
+
+        let changes = diff_filters(&old, &new);
+        assert_eq!(
+            changes,
+            vec![
+                FilterChange::CategoryRemoved("api".to_string()),
+                FilterChange::CategoryAdded("vcpu".to_string()),
+                FilterChange::DefaultAction(
+                    "vmm".to_string(),
+                    DecompiledAction::Trap,
+                    DecompiledAction::KillProcess
+                ),
+                FilterChange::RuleRemoved("vmm".to_string(), read),
+                FilterChange::RuleAdded(
+                    "vmm".to_string(),
+                    DecompiledRule {
+                        syscall: "close".to_string(),
+                        conditions: vec![],
+                    }
+                ),
+            ]
+        );
+        let lines: Vec<String> = changes.iter().map(|c| c.to_string()).collect();
+        assert_eq!(
+            lines,
+            vec![
+                "- api",
+                "+ vcpu",
+                "~ vmm: default_action trap -> kill_process",
+                "- vmm: read",
+                "+ vmm: close",
+            ]
+        );
+        assert_eq!(
+            old["vmm"].filter[0].to_string(),
+            "ioctl(args[0]:qword & 0xff000000f0 == 0x100000020, args[1]:dword == 0x5401)"
+        );
+    }
+}
@@ -0,0 +1,681 @@
+// Copyright 2022 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Seccomp learning mode.
+//!
+//! The learning filters trap every syscall made by the threads they are installed on. The
+//! `SIGSYS` handler records the syscall, along with the arguments that select the operation of
+//! well-known multiplexing syscalls (`ioctl`, `fcntl`, `futex` etc.), and then re-issues the
+//! syscall from an executable stub page which the filters allow. At the end of the run, the
+//! recorded syscalls are turned into a minimal filter, in the JSON format accepted by
+//! seccompiler-bin.
+//!
+//! The `SIGSYS` handler runs on the trapping thread, so it only uses atomics and preallocated
+//! storage. `rt_sigreturn` is always allowed by the learning filters, and always present in
+//! the learned ones, since every signal handler needs it.
+
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::fmt::{Display, Formatter};
+use std::os::raw::{c_int, c_void};
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, AtomicU8, AtomicUsize, Ordering};
+use std::sync::Arc;
+
+use serde::Serialize;
+
+use crate::common::sock_filter;
+#[cfg(target_arch = "aarch64")]
+use crate::syscall_table_aarch64::make_syscall_table;
+#[cfg(target_arch = "x86_64")]
+use crate::syscall_table_x86_64::make_syscall_table;
+use crate::BpfThreadMap;
+
+/// Maximum number of thread categories that can be learned at the same time.
+pub const MAX_CATEGORIES: usize = 8;
+// Syscall numbers above this value are not recorded.
+const MAX_SYSCALLS: usize = 512;
+// Number of distinct (syscall, arguments) tuples that can be recorded per thread category.
+const MAX_RECORDS: usize = 512;
+// Number of syscall arguments.
+const MAX_ARGS: usize = 6;
+
+// Syscalls for which the value of some arguments is recorded, because they select the
+// operation performed by the syscall. These are the arguments the default filters constrain.
+const RECORDED_ARGS: &[(&str, &[usize])] = &[
+    ("accept4", &[3]),
+    ("fcntl", &[1]),
+    ("futex", &[1]),
+    ("ioctl", &[1]),
+    ("madvise", &[2]),
+    ("mmap", &[3]),
+    ("prctl", &[0]),
+    ("rt_sigaction", &[0]),
+    ("socket", &[0, 1, 2]),
+    ("timerfd_settime", &[1]),
+    ("tkill", &[1]),
+];
+
+// BPF instructions and return values used by the learning filters.
+// See /usr/include/linux/filter.h and /usr/include/linux/seccomp.h .
+const BPF_LD_W_ABS: u16 = 0x20;
+const BPF_ALU_AND_K: u16 = 0x54;
+const BPF_JMP_JEQ_K: u16 = 0x15;
+const BPF_RET_K: u16 = 0x06;
+const SECCOMP_RET_ALLOW: u32 = 0x7fff_0000;
+const SECCOMP_RET_TRAP: u32 = 0x0003_0000;
+const SECCOMP_RET_KILL_PROCESS: u32 = 0x8000_0000;
+// Offsets of the `seccomp_data` fields.
+const SECCOMP_DATA_NR_OFFSET: u32 = 0;
+const SECCOMP_DATA_ARCH_OFFSET: u32 = 4;
+const SECCOMP_DATA_IP_LO_OFFSET: u32 = 8;
+const SECCOMP_DATA_IP_HI_OFFSET: u32 = 12;
+// The stub is smaller than 4 KiB and page aligned, so this mask matches any address in it.
+const STUB_ADDR_MASK: u32 = 0xffff_f000;
+
+#[cfg(target_arch = "aarch64")]
+const AUDIT_ARCH: u32 = 0xC000_00B7;
+#[cfg(target_arch = "x86_64")]
+const AUDIT_ARCH: u32 = 0xC000_003E;
+
+// `extern "C" fn(nr, arg0, .., arg5) -> i64` which issues the syscall.
+#[cfg(target_arch = "aarch64")]
+const SYSCALL_STUB: [u32; 9] = [
+    0xAA00_03E8, // mov x8, x0
+    0xAA01_03E0, // mov x0, x1
+    0xAA02_03E1, // mov x1, x2
+    0xAA03_03E2, // mov x2, x3
+    0xAA04_03E3, // mov x3, x4
+    0xAA05_03E4, // mov x4, x5
+    0xAA06_03E5, // mov x5, x6
+    0xD400_0001, // svc #0
+    0xD65F_03C0, // ret
+];
+#[cfg(target_arch = "x86_64")]
+const SYSCALL_STUB: [u8; 28] = [
+    0x48, 0x89, 0xf8, // mov rax, rdi
+    0x48, 0x89, 0xf7, // mov rdi, rsi
+    0x48, 0x89, 0xd6, // mov rsi, rdx
+    0x48, 0x89, 0xca, // mov rdx, rcx
+    0x4d, 0x89, 0xc2, // mov r10, r8
+    0x4d, 0x89, 0xc8, // mov r8, r9
+    0x4c, 0x8b, 0x4c, 0x24, 0x08, // mov r9, [rsp + 8]
+    0x0f, 0x05, // syscall
+    0xc3, // ret
+    0xcc, 0xcc, // int3 padding
+];
+
+type SyscallStub = extern "C" fn(i64, u64, u64, u64, u64, u64, u64) -> i64;
+
+// Location of the general purpose registers in `ucontext_t`, and the registers which hold the
+// syscall arguments and return value.
+#[cfg(target_arch = "aarch64")]
+const UC_REGS_OFFSET: usize = 184;
+#[cfg(target_arch = "aarch64")]
+const SYSCALL_ARG_REGS: [usize; MAX_ARGS] = [0, 1, 2, 3, 4, 5];
+#[cfg(target_arch = "aarch64")]
+const SYSCALL_RET_REG: usize = 0;
+#[cfg(target_arch = "x86_64")]
+const UC_REGS_OFFSET: usize = 40;
+// rdi, rsi, rdx, r10, r8, r9
+#[cfg(target_arch = "x86_64")]
+const SYSCALL_ARG_REGS: [usize; MAX_ARGS] = [8, 9, 12, 2, 0, 1];
+// rax
+#[cfg(target_arch = "x86_64")]
+const SYSCALL_RET_REG: usize = 13;
+
+// Offset of `si_syscall` in `siginfo_t`, in `i32` units, and the `si_code` set by seccomp.
+const SI_OFF_SYSCALL: isize = 6;
+const SYS_SECCOMP_CODE: i32 = 1;
+
+// Record states.
+const RECORD_EMPTY: u32 = 0;
+const RECORD_WRITING: u32 = 1;
+const RECORD_READY: u32 = 2;
+
+/// Learning mode errors.
+#[derive(Debug, PartialEq)]
+pub enum LearningError {
+    /// Too many thread categories.
+    TooManyCategories(usize),
+    /// Error setting up the syscall stub.
+    Stub(i32),
+    /// Error installing the `SIGSYS` handler.
+    SignalHandler(i32),
+}
+
+impl Display for LearningError {
+    fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
+        use self::LearningError::*;
+
+        match *self {
+            TooManyCategories(count) => write!(
+                f,
+                "Cannot learn {} thread categories, the maximum is {}",
+                count, MAX_CATEGORIES
+            ),
+            Stub(ref errno) => write!(
+                f,
+                "Failed to set up the syscall stub, error code: {}",
+                errno
+            ),
+            SignalHandler(ref errno) => write!(
+                f,
+                "Failed to install the SIGSYS handler, error code: {}",
+                errno
+            ),
+        }
+    }
+}
+
+// A recorded syscall, along with the value of its recorded arguments.
+struct Record {
+    state: AtomicU32,
+    nr: AtomicU32,
+    args: [AtomicU32; MAX_ARGS],
+}
+
+impl Record {
+    fn matches(&self, nr: usize, args: &[u32; MAX_ARGS]) -> bool {
+        self.nr.load(Ordering::Relaxed) as usize == nr
+            && self
+                .args
+                .iter()
+                .zip(args.iter())
+                .all(|(arg, value)| arg.load(Ordering::Relaxed) == *value)
+    }
+}
+
+// The syscalls observed for a thread category.
+struct CategoryLog {
+    seen: [AtomicU64; MAX_SYSCALLS / 64],
+    records: [Record; MAX_RECORDS],
+    overflow: AtomicBool,
+}
+
+impl CategoryLog {
+    // Records a syscall. Called from the `SIGSYS` handler, so it must not allocate or lock.
+    fn record(&self, nr: usize, args: &[u64; MAX_ARGS]) {
+        if nr >= MAX_SYSCALLS {
+            return;
+        }
+        self.seen[nr / 64].fetch_or(1 << (nr % 64), Ordering::Relaxed);
+
+        let mask = ARG_MASKS[nr].load(Ordering::Relaxed);
+        if mask == 0 {
+            return;
+        }
+        let mut values = [0u32; MAX_ARGS];
+        for (i, value) in values.iter_mut().enumerate() {
+            if mask & (1 << i) != 0 {
+                *value = args[i] as u32;
+            }
+        }
+
+        let start = record_hash(nr, &values) % MAX_RECORDS;
+        for i in 0..MAX_RECORDS {
+            let record = &self.records[(start + i) % MAX_RECORDS];
+            match record.state.load(Ordering::Acquire) {
+                RECORD_READY if record.matches(nr, &values) => return,
+                RECORD_EMPTY => {
+                    if record
+                        .state
+                        .compare_exchange(
+                            RECORD_EMPTY,
+                            RECORD_WRITING,
+                            Ordering::Acquire,
+                            Ordering::Relaxed,
+                        )
+                        .is_ok()
+                    {
+                        record.nr.store(nr as u32, Ordering::Relaxed);
+                        for (arg, value) in record.args.iter().zip(values.iter()) {
+                            arg.store(*value, Ordering::Relaxed);
+                        }
+                        record.state.store(RECORD_READY, Ordering::Release);
+                        return;
+                    }
+                }
+                // Either a different syscall, or a record being written by another thread.
+                // Waiting for the latter could deadlock if it's the interrupted handler of this
+                // thread, so move on; duplicates are removed when emitting the filter.
+                _ => {}
+            }
+        }
+        self.overflow.store(true, Ordering::Relaxed);
+    }
+
+    // Returns the observed syscalls, along with the distinct values of their recorded arguments.
+    fn syscalls(&self) -> BTreeMap<usize, BTreeSet<[u32; MAX_ARGS]>> {
+        let mut syscalls = BTreeMap::new();
+        for nr in 0..MAX_SYSCALLS {
+            if self.seen[nr / 64].load(Ordering::Relaxed) & (1 << (nr % 64)) != 0 {
+                syscalls.insert(nr, BTreeSet::new());
+            }
+        }
+        for record in self.records.iter() {
+            if record.state.load(Ordering::Acquire) != RECORD_READY {
+                continue;
+            }
+            let nr = record.nr.load(Ordering::Relaxed) as usize;
+            let mut values = [0u32; MAX_ARGS];
+            for (value, arg) in values.iter_mut().zip(record.args.iter()) {
+                *value = arg.load(Ordering::Relaxed);
+            }
+            syscalls
+                .entry(nr)
+                .or_insert_with(BTreeSet::new)
+                .insert(values);
+        }
+        syscalls
+    }
+}
+
+#[allow(clippy::declare_interior_mutable_const)]
+const EMPTY_ARG: AtomicU32 = AtomicU32::new(0);
+#[allow(clippy::declare_interior_mutable_const)]
+const EMPTY_RECORD: Record = Record {
+    state: AtomicU32::new(RECORD_EMPTY),
+    nr: AtomicU32::new(0),
+    args: [EMPTY_ARG; MAX_ARGS],
+};
+#[allow(clippy::declare_interior_mutable_const)]
+const EMPTY_BITMAP: AtomicU64 = AtomicU64::new(0);
+#[allow(clippy::declare_interior_mutable_const)]
+const EMPTY_LOG: CategoryLog = CategoryLog {
+    seen: [EMPTY_BITMAP; MAX_SYSCALLS / 64],
+    records: [EMPTY_RECORD; MAX_RECORDS],
+    overflow: AtomicBool::new(false),
+};
+#[allow(clippy::declare_interior_mutable_const)]
+const NO_ARGS: AtomicU8 = AtomicU8::new(0);
+
+static LOGS: [CategoryLog; MAX_CATEGORIES] = [EMPTY_LOG; MAX_CATEGORIES];
+// Bitmask of the recorded arguments, per syscall number.
+static ARG_MASKS: [AtomicU8; MAX_SYSCALLS] = [NO_ARGS; MAX_SYSCALLS];
+// Address of the syscall stub, 0 if it was not set up yet.
+static STUB_ADDR: AtomicUsize = AtomicUsize::new(0);
+
+// FNV-1a, over the syscall number and the recorded arguments.
+fn record_hash(nr: usize, values: &[u32; MAX_ARGS]) -> usize {
+    let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+    for word in std::iter::once(nr as u32).chain(values.iter().copied()) {
+        hash ^= u64::from(word);
+        hash = hash.wrapping_mul(0x0100_0000_01b3);
+    }
+    hash as usize
+}
+
+fn syscall_table() -> HashMap<String, i64> {
+    let mut map = HashMap::new();
+    make_syscall_table(&mut map);
+    map
+}
+
+// Sets up the executable page the trapped syscalls are re-issued from, and returns its address.
+fn setup_stub() -> Result<usize, LearningError> {
+    let addr = STUB_ADDR.load(Ordering::Acquire);
+    if addr != 0 {
+        return Ok(addr);
+    }
+
+    // Safe because we check the return values and only write within the mapping.
+    unsafe {
+        let page_size = libc::sysconf(libc::_SC_PAGESIZE) as usize;
+        let page = libc::mmap(
+            std::ptr::null_mut(),
+            page_size,
+            libc::PROT_READ | libc::PROT_WRITE,
+            libc::MAP_PRIVATE | libc::MAP_ANONYMOUS,
+            -1,
+            0,
+        );
+        if page == libc::MAP_FAILED {
+            return Err(LearningError::Stub(*libc::__errno_location()));
+        }
+        let code_len = std::mem::size_of_val(&SYSCALL_STUB);
+        std::ptr::copy_nonoverlapping(
+            SYSCALL_STUB.as_ptr().cast::<u8>(),
+            page as *mut u8,
+            code_len,
+        );
+        if libc::mprotect(page, page_size, libc::PROT_READ | libc::PROT_EXEC) != 0 {
+            let errno = *libc::__errno_location();
+            libc::munmap(page, page_size);
+            return Err(LearningError::Stub(errno));
+        }
+        #[cfg(target_arch = "aarch64")]
+        {
+            extern "C" {
+                fn __clear_cache(start: *mut c_void, end: *mut c_void);
+            }
+            __clear_cache(page, (page as *mut u8).add(code_len) as *mut c_void);
+        }
+
+        STUB_ADDR.store(page as usize, Ordering::Release);
+        Ok(page as usize)
+    }
+}
+
+extern "C" fn sigsys_handler(_num: c_int, info: *mut libc::siginfo_t, ucontext: *mut c_void) {
+    // Safe because the kernel passes valid `siginfo_t` and `ucontext_t` pointers, and the
+    // handler is only installed once the stub is set up.
+    unsafe {
+        if (*info).si_code != SYS_SECCOMP_CODE {
+            return;
+        }
+        // The category index is passed through the `SECCOMP_RET_DATA` bits of the return value.
+        let category = (*info).si_errno as usize;
+        let nr = *(info as *const i32).offset(SI_OFF_SYSCALL);
+        let regs = (ucontext as *mut u8).add(UC_REGS_OFFSET) as *mut u64;
+        let mut args = [0u64; MAX_ARGS];
+        for (arg, reg) in args.iter_mut().zip(SYSCALL_ARG_REGS.iter()) {
+            *arg = *regs.add(*reg);
+        }
+
+        if let Some(log) = LOGS.get(category) {
+            log.record(nr as usize, &args);
+        }
+
+        // The syscall was skipped by the kernel, issue it from the stub and pass on the result.
+        let stub: SyscallStub = std::mem::transmute(STUB_ADDR.load(Ordering::Acquire));
+        let ret = stub(
+            i64::from(nr),
+            args[0],
+            args[1],
+            args[2],
+            args[3],
+            args[4],
+            args[5],
+        );
+        *regs.add(SYSCALL_RET_REG) = ret as u64;
+    }
+}
+
+fn install_sigsys_handler() -> Result<(), LearningError> {
+    // Safe because the handler only uses atomics and the syscall stub, and we check the
+    // return value. `SA_NODEFER` allows syscalls made by nested signal handlers to be learned.
+    unsafe {
+        let mut sigact: libc::sigaction = std::mem::zeroed();
+        sigact.sa_flags = libc::SA_SIGINFO | libc::SA_NODEFER;
+        sigact.sa_sigaction = sigsys_handler as usize;
+        if libc::sigaction(libc::SIGSYS, &sigact, std::ptr::null_mut()) != 0 {
+            return Err(LearningError::SignalHandler(*libc::__errno_location()));
+        }
+    }
+    Ok(())
+}
+
+// Builds the learning filter of the category with the given index.
+fn learning_program(index: usize, stub_addr: usize, nr_rt_sigreturn: u32) -> Vec<sock_filter> {
+    let stmt = |code, k| sock_filter {
+        code,
+        jt: 0,
+        jf: 0,
+        k,
+    };
+    let jump = |k, jt, jf| sock_filter {
+        code: BPF_JMP_JEQ_K,
+        jt,
+        jf,
+        k,
+    };
+
+    vec![
+        stmt(BPF_LD_W_ABS, SECCOMP_DATA_ARCH_OFFSET),
+        jump(AUDIT_ARCH, 0, 9),
+        stmt(BPF_LD_W_ABS, SECCOMP_DATA_NR_OFFSET),
+        jump(nr_rt_sigreturn, 6, 0),
+        // Allow the syscalls issued from the stub, trap everything else.
+        stmt(BPF_LD_W_ABS, SECCOMP_DATA_IP_HI_OFFSET),
+        jump((stub_addr as u64 >> 32) as u32, 0, 3),
+        stmt(BPF_LD_W_ABS, SECCOMP_DATA_IP_LO_OFFSET),
+        stmt(BPF_ALU_AND_K, STUB_ADDR_MASK),
+        jump(stub_addr as u32 & STUB_ADDR_MASK, 1, 0),
+        stmt(BPF_RET_K, SECCOMP_RET_TRAP | index as u32),
+        stmt(BPF_RET_K, SECCOMP_RET_ALLOW),
+        stmt(BPF_RET_K, SECCOMP_RET_KILL_PROCESS),
+    ]
+}
+
+/// Sets up the learning mode and returns the learning filters of the given thread categories.
+///
+/// The filters record the syscalls made by the threads they are installed on, and can be
+/// installed with `apply_filter`. This also replaces the process' `SIGSYS` handler.
+pub fn learning_filters(categories: &[&str]) -> Result<BpfThreadMap, LearningError> {
+    if categories.len() > MAX_CATEGORIES {
+        return Err(LearningError::TooManyCategories(categories.len()));
+    }
+
+    let table = syscall_table();
+    for (name, args) in RECORDED_ARGS.iter() {
+        if let Some(&nr) = table.get(*name) {
+            let mask = args.iter().fold(0u8, |mask, arg| mask | 1 << arg);
+            ARG_MASKS[nr as usize].store(mask, Ordering::Relaxed);
+        }
+    }
+    // Every supported architecture has `rt_sigreturn`.
+    let nr_rt_sigreturn = table["rt_sigreturn"] as u32;
+
+    let stub_addr = setup_stub()?;
+    install_sigsys_handler()?;
+
+    Ok(categories
+        .iter()
+        .enumerate()
+        .map(|(index, category)| {
+            (
+                category.to_string(),
+                Arc::new(learning_program(index, stub_addr, nr_rt_sigreturn)),
+            )
+        })
+        .collect())
+}
+
+#[derive(Debug, PartialEq, Serialize)]
+struct LearnedCondition {
+    index: usize,
+    #[serde(rename = "type")]
+    arg_len: &'static str,
+    op: &'static str,
+    val: u32,
+}
+
+#[derive(Debug, PartialEq, Serialize)]
+struct LearnedRule {
+    syscall: String,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    args: Vec<LearnedCondition>,
+}
+
+#[derive(Debug, PartialEq, Serialize)]
+struct LearnedFilter {
+    default_action: &'static str,
+    filter_action: &'static str,
+    filter: Vec<LearnedRule>,
+}
+
+fn learned_filter(log: &CategoryLog, names: &HashMap<usize, &str>) -> LearnedFilter {
+    let overflow = log.overflow.load(Ordering::Relaxed);
+    let mut filter = Vec::new();
+    for (nr, tuples) in log.syscalls() {
+        let name = match names.get(&nr) {
+            Some(name) => name.to_string(),
+            None => continue,
+        };
+        let mask = ARG_MASKS[nr].load(Ordering::Relaxed);
+        // Fall back to allowing any arguments if some values could not be recorded.
+        if mask == 0 || overflow || tuples.is_empty() {
+            filter.push(LearnedRule {
+                syscall: name,
+                args: vec![],
+            });
+            continue;
+        }
+        for values in tuples {
+            let args = (0..MAX_ARGS)
+                .filter(|i| mask & (1 << i) != 0)
+                .map(|index| LearnedCondition {
+                    index,
+                    arg_len: "dword",
+                    op: "eq",
+                    val: values[index],
+                })
+                .collect();
+            filter.push(LearnedRule {
+                syscall: name.clone(),
+                args,
+            });
+        }
+    }
+    if !filter.iter().any(|rule| rule.syscall == "rt_sigreturn") {
+        filter.push(LearnedRule {
+            syscall: "rt_sigreturn".to_string(),
+            args: vec![],
+        });
+    }
+    filter.sort_by(|a, b| a.syscall.cmp(&b.syscall));
+
+    LearnedFilter {
+        default_action: "trap",
+        filter_action: "allow",
+        filter,
+    }
+}
+
+/// Returns the filters learned so far for the given thread categories, in the JSON format
+/// accepted by seccompiler-bin.
+///
+/// The categories must be passed in the same order as to `learning_filters`.
+pub fn learned_filters(categories: &[&str]) -> String {
+    let table = syscall_table();
+    let names: HashMap<usize, &str> = table
+        .iter()
+        .map(|(name, &nr)| (nr as usize, name.as_str()))
+        .collect();
+
+    let filters: BTreeMap<&str, LearnedFilter> = categories
+        .iter()
+        .zip(LOGS.iter())
+        .map(|(category, log)| (*category, learned_filter(log, &names)))
+        .collect();
+    // Serializing plain structs and maps with string keys can't fail.
+    serde_json::to_string_pretty(&filters).unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::apply_filter;
+    use std::thread;
+
+    #[test]
+    fn test_learning_error_display() {
+        assert_eq!(
+            LearningError::TooManyCategories(9).to_string(),
+            "Cannot learn 9 thread categories, the maximum is 8"
+        );
+        assert_eq!(
+            LearningError::Stub(12).to_string(),
+            "Failed to set up the syscall stub, error code: 12"
+        );
+        assert_eq!(
+            LearningError::SignalHandler(22).to_string(),
+            "Failed to install the SIGSYS handler, error code: 22"
+        );
+    }
+
+    #[test]
+    fn test_record() {
+        let table = syscall_table();
+        let nr_ioctl = table["ioctl"] as usize;
+        let nr_read = table["read"] as usize;
+        ARG_MASKS[nr_ioctl].store(1 << 1, Ordering::Relaxed);
+
+        let log = EMPTY_LOG;
+        log.record(nr_read, &[1, 2, 3, 4, 5, 6]);
+        log.record(nr_read, &[7, 8, 9, 10, 11, 12]);
+        log.record(nr_ioctl, &[3, 0xAE80, 0, 0, 0, 0]);
+        log.record(nr_ioctl, &[4, 0xAE80, 1, 0, 0, 0]);
+        log.record(nr_ioctl, &[3, 0x1_0000_AE03, 0, 0, 0, 0]);
+        log.record(MAX_SYSCALLS + 1, &[0; MAX_ARGS]);
+
+        let syscalls = log.syscalls();
+        assert_eq!(syscalls.len(), 2);
+        assert!(syscalls[&nr_read].is_empty());
+        let ioctls: Vec<[u32; MAX_ARGS]> = syscalls[&nr_ioctl].iter().copied().collect();
+        assert_eq!(
+            ioctls,
+            vec![[0, 0xAE03, 0, 0, 0, 0], [0, 0xAE80, 0, 0, 0, 0]]
+        );
+
+        let names: HashMap<usize, &str> = vec![(nr_ioctl, "ioctl"), (nr_read, "read")]
+            .into_iter()
+            .collect();
+        let filter = learned_filter(&log, &names);
+        assert_eq!(filter.default_action, "trap");
+        assert_eq!(filter.filter_action, "allow");
+        let rules: Vec<(&str, Vec<u32>)> = filter
+            .filter
+            .iter()
+            .map(|rule| {
+                (
+                    rule.syscall.as_str(),
+                    rule.args.iter().map(|cond| cond.val).collect(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            rules,
+            vec![
+                ("ioctl", vec![0xAE03]),
+                ("ioctl", vec![0xAE80]),
+                ("read", vec![]),
+                ("rt_sigreturn", vec![]),
+            ]
+        );
+
+        // Once the record table is full, the arguments are no longer constrained.
+        for request in 0..MAX_RECORDS as u64 {
+            log.record(nr_ioctl, &[0, request, 0, 0, 0, 0]);
+        }
+        assert!(log.overflow.load(Ordering::Relaxed));
+        let filter = learned_filter(&log, &names);
+        assert_eq!(filter.filter.len(), 3);
+        assert!(filter.filter[0].args.is_empty());
+    }
+
+    #[test]
+    fn test_learning_filters() {
+        assert_eq!(
+            learning_filters(&["t"; MAX_CATEGORIES + 1]).unwrap_err(),
+            LearningError::TooManyCategories(MAX_CATEGORIES + 1)
+        );
+
+        let categories = ["learn_a", "learn_b"];
+        let mut filters = learning_filters(&categories).unwrap();
+        assert_eq!(filters.len(), 2);
+        let filter = filters.remove("learn_b").unwrap();
+        assert_eq!(filter.len(), 12);
+        assert_eq!(filter[9].k, SECCOMP_RET_TRAP | 1);
+
+        // The syscalls are still carried out while being learned.
+        let pid = thread::spawn(move || {
+            apply_filter(&filter).unwrap();
+            let pid = unsafe { libc::getpid() };
+            let flags = unsafe { libc::fcntl(0, libc::F_GETFD) };
+            assert!(flags >= 0 || unsafe { *libc::__errno_location() } == libc::EBADF);
+            pid
+        })
+        .join()
+        .unwrap();
+        assert_eq!(pid, unsafe { libc::getpid() });
+
+        let json: serde_json::Value = serde_json::from_str(&learned_filters(&categories)).unwrap();
+        assert_eq!(json["learn_a"]["default_action"], "trap");
+        let rules = json["learn_b"]["filter"].as_array().unwrap();
+        assert!(rules.iter().any(|rule| rule["syscall"] == "getpid"));
+        assert!(rules.iter().any(|rule| rule["syscall"] == "fcntl"
+            && rule["args"][0]["index"] == 1
+            && rule["args"][0]["val"] == libc::F_GETFD));
+        assert!(rules.iter().any(|rule| rule["syscall"] == "rt_sigreturn"));
+    }
+}
@@ -6,6 +6,14 @@
 //! conjunction with seccompiler-bin.
 
 mod common;
+mod decompiler;
+mod learning;
+// The syscall tables are generated for seccompiler-bin, and shared with the library.
+#[path = "syscall_table/aarch64.rs"]
+mod syscall_table_aarch64;
+#[path = "syscall_table/x86_64.rs"]
+mod syscall_table_x86_64;
+mod user_notif;
 
 use bincode::Error as BincodeError;
 use bincode::{DefaultOptions, Options};
@@ -17,6 +25,13 @@ use std::sync::Arc;
 
 // Re-export the data types needed for calling the helper functions.
 pub use common::{sock_filter, BpfProgram};
+pub use decompiler::{
+    decompile_filter, decompile_filters, diff_filters, DecompileError, DecompiledAction,
+    DecompiledCmpArgLen, DecompiledCmpOp, DecompiledCondition, DecompiledFilter, DecompiledRule,
+    FilterChange,
+};
+pub use learning::{learned_filters, learning_filters, LearningError, MAX_CATEGORIES};
+pub use user_notif::{connect_user_notif_socket, UserNotifError};
 
 /// Type that associates a thread category to a BPF program.
 pub type BpfThreadMap = HashMap<String, Arc<BpfProgram>>;
@@ -56,6 +71,12 @@ pub enum InstallationError {
     FilterTooLarge,
     /// Error returned by `prctl`.
     Prctl(i32),
+    /// The filter uses `user_notif`, but no user notification socket is connected.
+    MissingUserNotifSocket,
+    /// Error returned by `seccomp`.
+    Seccomp(i32),
+    /// Failed to send the user notification listener.
+    SendListener(i32),
 }
 
 impl Display for InstallationError {
@@ -69,6 +90,16 @@ impl Display for InstallationError {
                 BPF_MAX_LEN
             ),
             Prctl(ref errno) => write!(f, "`prctl` syscall failed with error code: {}", errno),
+            MissingUserNotifSocket => write!(
+                f,
+                "The filter uses `user_notif`, but no user notification socket is connected."
+            ),
+            Seccomp(ref errno) => write!(f, "`seccomp` syscall failed with error code: {}", errno),
+            SendListener(ref errno) => write!(
+                f,
+                "Failed to send the user notification listener, error code: {}",
+                errno
+            ),
         }
     }
 }
@@ -101,6 +132,12 @@ pub fn deserialize_binary<R: Read>(
 }
 
 /// Helper function for installing a BPF filter.
+///
+/// Filters are installed on the calling thread only. If the filter uses the `user_notif` action,
+/// a listener is created for it and sent through the socket connected by
+/// [`connect_user_notif_socket`], so each thread installing such a filter sends its own listener.
+///
+/// [`connect_user_notif_socket`]: fn.connect_user_notif_socket.html
 pub fn apply_filter(bpf_filter: BpfProgramRef) -> std::result::Result<(), InstallationError> {
     // If the program is empty, don't install the filter.
     if bpf_filter.is_empty() {
@@ -125,6 +162,10 @@ pub fn apply_filter(bpf_filter: BpfProgramRef) -> std::result::Result<(), Instal
             len: bpf_filter.len() as u16,
             filter: bpf_filter.as_ptr(),
         };
+        if user_notif::uses_user_notif(bpf_filter) {
+            return user_notif::install_with_listener(&bpf_prog);
+        }
+
         let bpf_prog_ptr = &bpf_prog as *const sock_fprog;
         {
             let rc = libc::prctl(
@@ -0,0 +1,354 @@
+// Copyright 2022 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! seccompiler-decompile is a companion program to seccompiler-bin, which decompiles the
+//! serialized filters produced by seccompiler-bin back into the JSON format, or reports the
+//! semantic differences between two serialized filter files.
+
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::BufReader;
+use std::path::PathBuf;
+use std::{fmt, io, process};
+
+use seccompiler::{
+    decompile_filters, deserialize_binary, diff_filters, DecompileError, DecompiledFilter,
+    DeserializationError,
+};
+use utils::arg_parser::{ArgParser, Argument, Arguments as ArgumentsBag};
+
+const SECCOMPILER_VERSION: &str = env!("FIRECRACKER_VERSION");
+const EXIT_CODE_ERROR: i32 = 1;
+const EXIT_CODE_DIFFERENCES: i32 = 2;
+
+#[derive(Debug)]
+enum Error {
+    Decompile(PathBuf, DecompileError),
+    Deserialization(PathBuf, DeserializationError),
+    FileOpen(PathBuf, io::Error),
+    Json(serde_json::Error),
+    MissingInputFile,
+    MissingTargetArch,
+}
+
+type Result<T> = std::result::Result<T, Error>;
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::Error::*;
+
+        match *self {
+            Decompile(ref path, ref err) => write!(
+                f,
+                "{}",
+                format!("Failed to decompile {:?}: {}", path, err).replace("\"", "")
+            ),
+            Deserialization(ref path, ref err) => write!(
+                f,
+                "{}",
+                format!("Failed to deserialize {:?}: {}", path, err).replace("\"", "")
+            ),
+            FileOpen(ref path, ref err) => write!(
+                f,
+                "{}",
+                format!("Failed to open file {:?}: {}", path, err).replace("\"", "")
+            ),
+            Json(ref err) => write!(f, "Error serializing JSON: {}", err),
+            MissingInputFile => write!(f, "Missing input file."),
+            MissingTargetArch => write!(f, "Missing target arch."),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+struct Arguments {
+    input_file: String,
+    diff_file: Option<String>,
+    output_file: Option<String>,
+    target_arch: String,
+}
+
+fn build_arg_parser() -> ArgParser<'static> {
+    ArgParser::new()
+        .arg(
+            Argument::new("input-file")
+                .required(true)
+                .takes_value(true)
+                .help("File path of the filters compiled by seccompiler-bin."),
+        )
+        .arg(
+            Argument::new("target-arch")
+                .required(true)
+                .takes_value(true)
+                .help("The computer architecture the filters were compiled for. Supported architectures: x86_64, aarch64."),
+        )
+        .arg(
+            Argument::new("output-file")
+                .takes_value(true)
+                .forbids(vec!["diff"])
+                .help("Optional path of the JSON output file. The JSON is printed if missing."),
+        )
+        .arg(
+            Argument::new("diff")
+                .takes_value(true)
+                .help("File path of other compiled filters, to compare the input file with. \
+                Rules added by the other filters are prefixed with '+', removed rules with '-' \
+                and changed actions with '~'. The exit code is 2 if the filters differ."),
+        )
+}
+
+fn get_argument_values(arguments: &ArgumentsBag) -> Result<Arguments> {
+    let target_arch = arguments
+        .single_value("target-arch")
+        .ok_or(Error::MissingTargetArch)?;
+    let input_file = arguments
+        .single_value("input-file")
+        .ok_or(Error::MissingInputFile)?;
+
+    Ok(Arguments {
+        input_file: input_file.to_owned(),
+        diff_file: arguments.single_value("diff").cloned(),
+        output_file: arguments.single_value("output-file").cloned(),
+        target_arch: target_arch.to_owned(),
+    })
+}
+
+fn decompile(path: &str, target_arch: &str) -> Result<BTreeMap<String, DecompiledFilter>> {
+    let file = File::open(path).map_err(|err| Error::FileOpen(PathBuf::from(path), err))?;
+    let filters = deserialize_binary(BufReader::new(file), None)
+        .map_err(|err| Error::Deserialization(PathBuf::from(path), err))?;
+    decompile_filters(&filters, target_arch)
+        .map_err(|err| Error::Decompile(PathBuf::from(path), err))
+}
+
+// Decompiles the input file, or compares it with the diff file. Returns the exit code.
+fn run(args: &Arguments) -> Result<i32> {
+    let filters = decompile(&args.input_file, &args.target_arch)?;
+
+    if let Some(diff_file) = args.diff_file.as_ref() {
+        let other_filters = decompile(diff_file, &args.target_arch)?;
+        let changes = diff_filters(&filters, &other_filters);
+        if changes.is_empty() {
+            println!("The filters are equivalent.");
+            return Ok(0);
+        }
+        for change in changes {
+            println!("{}", change);
+        }
+        return Ok(EXIT_CODE_DIFFERENCES);
+    }
+
+    let json = serde_json::to_string_pretty(&filters).map_err(Error::Json)?;
+    match args.output_file.as_ref() {
+        Some(output_file) => std::fs::write(output_file, json)
+            .map_err(|err| Error::FileOpen(PathBuf::from(output_file), err))?,
+        None => println!("{}", json),
+    }
+    Ok(0)
+}
+
+fn main() {
+    let mut arg_parser = build_arg_parser();
+
+    if let Err(err) = arg_parser.parse_from_cmdline() {
+        eprintln!(
+            "Arguments parsing error: {} \n\n\
+             For more information try --help.",
+            err
+        );
+        process::exit(EXIT_CODE_ERROR);
+    }
+
+    if arg_parser.arguments().flag_present("help") {
+        println!("Seccompiler-decompile v{}\n", SECCOMPILER_VERSION);
+        println!("{}", arg_parser.formatted_help());
+        return;
+    }
+    if arg_parser.arguments().flag_present("version") {
+        println!("Seccompiler-decompile v{}\n", SECCOMPILER_VERSION);
+        return;
+    }
+
+    let args = get_argument_values(arg_parser.arguments()).unwrap_or_else(|err| {
+        eprintln!(
+            "{} \n\n\
+            For more information try --help.",
+            err
+        );
+        process::exit(EXIT_CODE_ERROR);
+    });
+
+    match run(&args) {
+        Ok(exit_code) => process::exit(exit_code),
+        Err(err) => {
+            eprintln!("Seccompiler error: {}", err);
+            process::exit(EXIT_CODE_ERROR);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use seccompiler::{sock_filter, BpfProgram};
+    use std::collections::HashMap;
+    use utils::tempfile::TempFile;
+
+    // Writes serialized filters with a single `trap` default action for each category.
+    fn write_filters(file: &TempFile, categories: &[&str]) {
+        let audit_arch = if cfg!(target_arch = "aarch64") {
+            183 | 0xC000_0000
+        } else {
+            62 | 0xC000_0000
+        };
+        let program: BpfProgram = vec![
+            sock_filter {
+                code: 0x20,
+                jt: 0,
+                jf: 0,
+                k: 4,
+            },
+            sock_filter {
+                code: 0x15,
+                jt: 1,
+                jf: 0,
+                k: audit_arch,
+            },
+            sock_filter {
+                code: 0x06,
+                jt: 0,
+                jf: 0,
+                k: 0x8000_0000,
+            },
+            sock_filter {
+                code: 0x06,
+                jt: 0,
+                jf: 0,
+                k: 0x0003_0000,
+            },
+        ];
+        let filters: HashMap<String, BpfProgram> = categories
+            .iter()
+            .map(|category| (category.to_string(), program.clone()))
+            .collect();
+        bincode::serialize_into(file.as_file(), &filters).unwrap();
+    }
+
+    fn arguments(input_file: &TempFile) -> Arguments {
+        Arguments {
+            input_file: input_file.as_path().to_str().unwrap().to_string(),
+            diff_file: None,
+            output_file: None,
+            target_arch: std::env::consts::ARCH.to_string(),
+        }
+    }
+
+    #[test]
+    fn test_error_messages() {
+        let path = PathBuf::from("/path");
+        assert_eq!(
+            Error::Decompile(path.clone(), DecompileError::ArchMismatch).to_string(),
+            "Failed to decompile /path: The filter was not compiled for the target arch."
+        );
+        assert_eq!(
+            Error::FileOpen(path, io::Error::from_raw_os_error(2)).to_string(),
+            format!(
+                "Failed to open file /path: {}",
+                io::Error::from_raw_os_error(2)
+            )
+        );
+        assert_eq!(Error::MissingInputFile.to_string(), "Missing input file.");
+        assert_eq!(Error::MissingTargetArch.to_string(), "Missing target arch.");
+    }
+
+    #[test]
+    fn test_get_argument_values() {
+        let arg_parser = build_arg_parser();
+        let arguments = &mut arg_parser.arguments().clone();
+        arguments
+            .parse(
+                vec![
+                    "seccompiler-decompile",
+                    "--input-file",
+                    "old.bpf",
+                    "--target-arch",
+                    "x86_64",
+                    "--diff",
+                    "new.bpf",
+                ]
+                .into_iter()
+                .map(String::from)
+                .collect::<Vec<String>>()
+                .as_ref(),
+            )
+            .unwrap();
+        assert_eq!(
+            get_argument_values(arguments).unwrap(),
+            Arguments {
+                input_file: "old.bpf".to_string(),
+                diff_file: Some("new.bpf".to_string()),
+                output_file: None,
+                target_arch: "x86_64".to_string(),
+            }
+        );
+
+        // --output-file and --diff are mutually exclusive.
+        let arguments = &mut arg_parser.arguments().clone();
+        assert!(arguments
+            .parse(
+                vec![
+                    "seccompiler-decompile",
+                    "--input-file",
+                    "old.bpf",
+                    "--target-arch",
+                    "x86_64",
+                    "--diff",
+                    "new.bpf",
+                    "--output-file",
+                    "out.json",
+                ]
+                .into_iter()
+                .map(String::from)
+                .collect::<Vec<String>>()
+                .as_ref(),
+            )
+            .is_err());
+    }
+
+    #[test]
+    fn test_run() {
+        let old_file = TempFile::new().unwrap();
+        write_filters(&old_file, &["vmm", "api"]);
+        let new_file = TempFile::new().unwrap();
+        write_filters(&new_file, &["vmm", "api", "vcpu"]);
+        let out_file = TempFile::new().unwrap();
+
+        // Decompile.
+        let mut args = arguments(&old_file);
+        args.output_file = Some(out_file.as_path().to_str().unwrap().to_string());
+        assert_eq!(run(&args).unwrap(), 0);
+        let json: serde_json::Value =
+            serde_json::from_str(&std::fs::read_to_string(out_file.as_path()).unwrap()).unwrap();
+        assert_eq!(json["vmm"]["default_action"], "trap");
+        assert_eq!(json["api"]["filter"], serde_json::json!([]));
+
+        // Diff.
+        let mut args = arguments(&old_file);
+        args.diff_file = Some(old_file.as_path().to_str().unwrap().to_string());
+        assert_eq!(run(&args).unwrap(), 0);
+        args.diff_file = Some(new_file.as_path().to_str().unwrap().to_string());
+        assert_eq!(run(&args).unwrap(), EXIT_CODE_DIFFERENCES);
+
+        // Invalid files.
+        let empty_file = TempFile::new().unwrap();
+        assert!(matches!(
+            run(&arguments(&empty_file)),
+            Err(Error::Deserialization(_, _))
+        ));
+        let mut args = arguments(&old_file);
+        args.target_arch = "riscv".to_string();
+        assert!(matches!(run(&args), Err(Error::Decompile(_, _))));
+        args.input_file = "/invalid/path".to_string();
+        assert!(matches!(run(&args), Err(Error::FileOpen(_, _))));
+    }
+}
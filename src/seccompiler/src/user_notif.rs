@@ -0,0 +1,355 @@
+// Copyright 2022 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Seccomp user notifications.
+//!
+//! Syscalls matching a `user_notif` rule suspend the calling thread until a supervisor process,
+//! holding the listener file descriptor of the filter, replies to the notification. The kernel
+//! only creates the listener when the filter is installed, so filters using `user_notif` are
+//! installed with `seccomp(SECCOMP_SET_MODE_FILTER, SECCOMP_FILTER_FLAG_NEW_LISTENER)` instead
+//! of `prctl`, and the listener is then handed over to the supervisor through the
+//! `SOCK_SEQPACKET` Unix socket connected by `connect_user_notif_socket`.
+//!
+//! Each message carries a single listener as `SCM_RIGHTS` ancillary data, and the name of the
+//! thread which installed the filter as payload. The listener is closed in this process once
+//! sent, so the filter being installed must allow `sendmsg` and `close`.
+
+use std::fmt::{Display, Formatter};
+use std::mem;
+use std::os::raw::c_void;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::io::RawFd;
+use std::path::Path;
+use std::sync::atomic::{AtomicI32, Ordering};
+
+use crate::{sock_fprog, BpfProgramRef, InstallationError};
+
+// See /usr/include/linux/filter.h and /usr/include/linux/seccomp.h .
+const BPF_RET_K: u16 = 0x06;
+const SECCOMP_RET_ACTION_FULL: u32 = 0xffff_0000;
+const SECCOMP_RET_USER_NOTIF: u32 = 0x7fc0_0000;
+const SECCOMP_SET_MODE_FILTER: libc::c_ulong = 1;
+const SECCOMP_FILTER_FLAG_NEW_LISTENER: libc::c_ulong = 1 << 3;
+// Large enough, and aligned, for a control message holding a single file descriptor.
+const CMSG_BUFFER_LEN: usize = 4;
+
+// Connected socket the listeners are sent to, or -1.
+static USER_NOTIF_SOCKET: AtomicI32 = AtomicI32::new(-1);
+
+/// Errors connecting to the user notification socket.
+#[derive(Debug, PartialEq)]
+pub enum UserNotifError {
+    /// The socket path does not fit in `sockaddr_un`.
+    PathTooLong,
+    /// Error returned by `socket`.
+    Socket(i32),
+    /// Error returned by `connect`.
+    Connect(i32),
+}
+
+impl Display for UserNotifError {
+    fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
+        use self::UserNotifError::*;
+
+        match *self {
+            PathTooLong => write!(f, "The user notification socket path is too long."),
+            Socket(ref errno) => write!(f, "`socket` syscall failed with error code: {}", errno),
+            Connect(ref errno) => write!(
+                f,
+                "Failed to connect to the user notification socket, error code: {}",
+                errno
+            ),
+        }
+    }
+}
+
+fn errno() -> i32 {
+    // Safe because the errno location is valid for the lifetime of the thread.
+    unsafe { *libc::__errno_location() }
+}
+
+/// Connects to the `SOCK_SEQPACKET` Unix socket of the supervisor, which receives the listeners
+/// of the filters using `user_notif`. Must be called before installing such filters. Replaces
+/// the previously connected socket, if any.
+pub fn connect_user_notif_socket(path: &Path) -> Result<(), UserNotifError> {
+    // Safe because `sockaddr_un` is plain old data.
+    let mut addr: libc::sockaddr_un = unsafe { mem::zeroed() };
+    let path = path.as_os_str().as_bytes();
+    // Keep the terminating null byte.
+    if path.len() >= addr.sun_path.len() {
+        return Err(UserNotifError::PathTooLong);
+    }
+    addr.sun_family = libc::AF_UNIX as libc::sa_family_t;
+    for (dst, src) in addr.sun_path.iter_mut().zip(path) {
+        *dst = *src as libc::c_char;
+    }
+
+    // Safe because the arguments are valid and the return value is checked.
+    let socket =
+        unsafe { libc::socket(libc::AF_UNIX, libc::SOCK_SEQPACKET | libc::SOCK_CLOEXEC, 0) };
+    if socket < 0 {
+        return Err(UserNotifError::Socket(errno()));
+    }
+    // Safe because `addr` is a valid, initialized `sockaddr_un` of the given size.
+    let rc = unsafe {
+        libc::connect(
+            socket,
+            &addr as *const libc::sockaddr_un as *const libc::sockaddr,
+            mem::size_of::<libc::sockaddr_un>() as libc::socklen_t,
+        )
+    };
+    if rc < 0 {
+        let err = errno();
+        // Safe because `socket` is a file descriptor we own.
+        unsafe { libc::close(socket) };
+        return Err(UserNotifError::Connect(err));
+    }
+
+    let old_socket = USER_NOTIF_SOCKET.swap(socket, Ordering::SeqCst);
+    if old_socket >= 0 {
+        // Safe because `old_socket` is a file descriptor we own.
+        unsafe { libc::close(old_socket) };
+    }
+    Ok(())
+}
+
+/// Returns true if the program can return `SECCOMP_RET_USER_NOTIF`.
+pub(crate) fn uses_user_notif(bpf_filter: BpfProgramRef) -> bool {
+    bpf_filter.iter().any(|insn| {
+        insn.code == BPF_RET_K && insn.k & SECCOMP_RET_ACTION_FULL == SECCOMP_RET_USER_NOTIF
+    })
+}
+
+// Sends the listener to the supervisor, along with the name of the current thread.
+fn send_listener(socket: RawFd, listener: RawFd) -> Result<(), i32> {
+    let thread = std::thread::current();
+    let name = thread.name().unwrap_or("-");
+    let mut iov = libc::iovec {
+        iov_base: name.as_ptr() as *mut c_void,
+        iov_len: name.len(),
+    };
+    // Use a stack buffer, to avoid allocating right after the filter is installed.
+    let mut cmsg_buffer = [0u64; CMSG_BUFFER_LEN];
+
+    // Safe because `msghdr` is plain old data, and the control message is written within
+    // `cmsg_buffer`, which is large enough and suitably aligned for it.
+    let rc = unsafe {
+        let mut msg: libc::msghdr = mem::zeroed();
+        msg.msg_iov = &mut iov;
+        msg.msg_iovlen = 1;
+        msg.msg_control = cmsg_buffer.as_mut_ptr().cast::<c_void>();
+        msg.msg_controllen = libc::CMSG_SPACE(mem::size_of::<RawFd>() as u32) as _;
+
+        let cmsg = libc::CMSG_FIRSTHDR(&msg);
+        (*cmsg).cmsg_level = libc::SOL_SOCKET;
+        (*cmsg).cmsg_type = libc::SCM_RIGHTS;
+        (*cmsg).cmsg_len = libc::CMSG_LEN(mem::size_of::<RawFd>() as u32) as _;
+        std::ptr::write_unaligned(libc::CMSG_DATA(cmsg).cast::<RawFd>(), listener);
+
+        libc::sendmsg(socket, &msg, libc::MSG_NOSIGNAL)
+    };
+    if rc < 0 {
+        return Err(errno());
+    }
+    Ok(())
+}
+
+/// Installs a filter using `user_notif` on the current thread, and sends its listener to the
+/// supervisor.
+pub(crate) fn install_with_listener(bpf_prog: &sock_fprog) -> Result<(), InstallationError> {
+    let socket = USER_NOTIF_SOCKET.load(Ordering::SeqCst);
+    if socket < 0 {
+        return Err(InstallationError::MissingUserNotifSocket);
+    }
+
+    // Safe because `bpf_prog` points to a valid program and the return value is checked.
+    let listener = unsafe {
+        libc::syscall(
+            libc::SYS_seccomp,
+            SECCOMP_SET_MODE_FILTER,
+            SECCOMP_FILTER_FLAG_NEW_LISTENER,
+            bpf_prog as *const sock_fprog,
+        )
+    };
+    if listener < 0 {
+        return Err(InstallationError::Seccomp(errno()));
+    }
+
+    let listener = listener as RawFd;
+    let result = send_listener(socket, listener).map_err(InstallationError::SendListener);
+    // Safe because `listener` is a file descriptor we own. The supervisor has its own copy.
+    unsafe { libc::close(listener) };
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{apply_filter, sock_filter, BpfProgram};
+    use std::thread;
+    use utils::tempfile::TempFile;
+
+    fn user_notif_program() -> BpfProgram {
+        // Notify the supervisor of `acct` calls, allow everything else.
+        vec![
+            sock_filter {
+                code: 0x20,
+                jt: 0,
+                jf: 0,
+                k: 0,
+            },
+            sock_filter {
+                code: 0x15,
+                jt: 0,
+                jf: 1,
+                k: libc::SYS_acct as u32,
+            },
+            sock_filter {
+                code: BPF_RET_K,
+                jt: 0,
+                jf: 0,
+                k: SECCOMP_RET_USER_NOTIF,
+            },
+            sock_filter {
+                code: BPF_RET_K,
+                jt: 0,
+                jf: 0,
+                k: 0x7fff_0000,
+            },
+        ]
+    }
+
+    // Binds and listens on a `SOCK_SEQPACKET` socket at `path`.
+    fn listen(path: &Path) -> RawFd {
+        let mut addr: libc::sockaddr_un = unsafe { mem::zeroed() };
+        addr.sun_family = libc::AF_UNIX as libc::sa_family_t;
+        for (dst, src) in addr.sun_path.iter_mut().zip(path.as_os_str().as_bytes()) {
+            *dst = *src as libc::c_char;
+        }
+        unsafe {
+            let socket = libc::socket(libc::AF_UNIX, libc::SOCK_SEQPACKET, 0);
+            assert!(socket >= 0);
+            assert_eq!(
+                libc::bind(
+                    socket,
+                    &addr as *const libc::sockaddr_un as *const libc::sockaddr,
+                    mem::size_of::<libc::sockaddr_un>() as libc::socklen_t,
+                ),
+                0
+            );
+            assert_eq!(libc::listen(socket, 1), 0);
+            socket
+        }
+    }
+
+    // Receives a message, returning its payload and the file descriptor it carries.
+    fn receive(socket: RawFd) -> (String, RawFd) {
+        let mut payload = [0u8; 64];
+        let mut iov = libc::iovec {
+            iov_base: payload.as_mut_ptr().cast::<c_void>(),
+            iov_len: payload.len(),
+        };
+        let mut cmsg_buffer = [0u64; CMSG_BUFFER_LEN];
+        unsafe {
+            let mut msg: libc::msghdr = mem::zeroed();
+            msg.msg_iov = &mut iov;
+            msg.msg_iovlen = 1;
+            msg.msg_control = cmsg_buffer.as_mut_ptr().cast::<c_void>();
+            msg.msg_controllen = mem::size_of_val(&cmsg_buffer) as _;
+            let len = libc::recvmsg(socket, &mut msg, 0);
+            assert!(len > 0);
+
+            let cmsg = libc::CMSG_FIRSTHDR(&msg);
+            assert!(!cmsg.is_null());
+            assert_eq!((*cmsg).cmsg_type, libc::SCM_RIGHTS);
+            let fd = std::ptr::read_unaligned(libc::CMSG_DATA(cmsg).cast::<RawFd>());
+            (
+                String::from_utf8(payload[..len as usize].to_vec()).unwrap(),
+                fd,
+            )
+        }
+    }
+
+    #[test]
+    fn test_user_notif_error_display() {
+        assert_eq!(
+            UserNotifError::PathTooLong.to_string(),
+            "The user notification socket path is too long."
+        );
+        assert_eq!(
+            UserNotifError::Socket(22).to_string(),
+            "`socket` syscall failed with error code: 22"
+        );
+        assert_eq!(
+            UserNotifError::Connect(111).to_string(),
+            "Failed to connect to the user notification socket, error code: 111"
+        );
+    }
+
+    #[test]
+    fn test_uses_user_notif() {
+        assert!(uses_user_notif(&user_notif_program()));
+        assert!(!uses_user_notif(&user_notif_program()[3..]));
+        // A load of the same value is not a return.
+        assert!(!uses_user_notif(&[sock_filter {
+            code: 0x20,
+            jt: 0,
+            jf: 0,
+            k: SECCOMP_RET_USER_NOTIF,
+        }]));
+    }
+
+    #[test]
+    fn test_install_with_listener() {
+        // Without a socket, the filter is not installed.
+        thread::spawn(|| {
+            assert_eq!(
+                apply_filter(&user_notif_program()).unwrap_err(),
+                InstallationError::MissingUserNotifSocket
+            );
+            assert_eq!(unsafe { libc::prctl(libc::PR_GET_SECCOMP) }, 0);
+        })
+        .join()
+        .unwrap();
+
+        let long_path = Path::new("/tmp").join("a".repeat(200));
+        assert_eq!(
+            connect_user_notif_socket(&long_path),
+            Err(UserNotifError::PathTooLong)
+        );
+        let socket_path = TempFile::new().unwrap();
+        let socket_path = socket_path.as_path().to_path_buf();
+        assert_eq!(
+            connect_user_notif_socket(&socket_path),
+            Err(UserNotifError::Connect(libc::ECONNREFUSED))
+        );
+
+        std::fs::remove_file(&socket_path).unwrap();
+        let listening_socket = listen(&socket_path);
+        connect_user_notif_socket(&socket_path).unwrap();
+        let supervisor =
+            unsafe { libc::accept(listening_socket, std::ptr::null_mut(), std::ptr::null_mut()) };
+        assert!(supervisor >= 0);
+
+        thread::Builder::new()
+            .name("notif_test".to_string())
+            .spawn(|| {
+                apply_filter(&user_notif_program()).unwrap();
+                assert_eq!(unsafe { libc::prctl(libc::PR_GET_SECCOMP) }, 2);
+            })
+            .unwrap()
+            .join()
+            .unwrap();
+
+        let (thread_name, listener) = receive(supervisor);
+        assert_eq!(thread_name, "notif_test");
+        assert!(listener >= 0);
+
+        unsafe {
+            libc::close(listener);
+            libc::close(supervisor);
+            libc::close(listening_socket);
+        }
+        std::fs::remove_file(&socket_path).unwrap();
+    }
+}
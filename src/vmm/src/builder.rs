@@ -46,7 +46,7 @@ use arch::InitrdConfig;
 use cpuid::common::is_same_model;
 #[cfg(target_arch = "aarch64")]
 use devices::legacy::RTCDevice;
-use devices::virtio::{Balloon, Block, MmioTransport, Net, VirtioDevice, Vsock, VsockUnixBackend};
+use devices::virtio::{Balloon, Block, MmioTransport, Net, VirtioDevice, Vsock, VsockHostBackend};
 use event_manager::{MutEventSubscriber, SubscriberOps};
 use linux_loader::cmdline::Cmdline as LoaderKernelCmdline;
 use linux_loader::loader::KernelLoader;
@@ -303,6 +303,7 @@ fn create_vmm_and_vcpus(
         mmio_device_manager,
         #[cfg(target_arch = "x86_64")]
         pio_device_manager,
+        custom_cpu_template: None,
     };
 
     Ok((vmm, vcpus))
@@ -365,6 +366,7 @@ pub fn build_microvm_for_boot(
         track_dirty_pages,
         vcpu_config.vcpu_count,
     )?;
+    vmm.custom_cpu_template = vcpu_config.custom_cpu_template.clone();
 
     // The boot timer device needs to be the first device attached in order
     // to maintain the same MMIO address referenced in the documentation
@@ -389,8 +391,8 @@ pub fn build_microvm_for_boot(
         vm_resources.net_builder.iter(),
         event_manager,
     )?;
-    if let Some(unix_vsock) = vm_resources.vsock.get() {
-        attach_unixsock_vsock_device(&mut vmm, &mut boot_cmdline, unix_vsock, event_manager)?;
+    if let Some(vsock) = vm_resources.vsock.get() {
+        attach_vsock_device(&mut vmm, &mut boot_cmdline, vsock, event_manager)?;
     }
 
     if let Some(init) = init_params {
@@ -466,6 +468,7 @@ pub fn build_microvm_from_snapshot(
         track_dirty_pages,
         vcpu_count,
     )?;
+    vmm.custom_cpu_template = microvm_state.custom_cpu_template.clone();
 
     #[cfg(target_arch = "x86_64")]
     // Check if we need to scale the TSC.
@@ -525,6 +528,7 @@ pub fn build_microvm_from_snapshot(
             mem_size_mib: Some(mem_size_mib(&guest_memory) as usize),
             smt: Some(false),
             cpu_template: None,
+            cpu_template_path: None,
             track_dirty_pages: Some(track_dirty_pages),
         })
         .map_err(SetVmResources)?;
@@ -950,15 +954,15 @@ fn attach_net_devices<'a>(
     Ok(())
 }
 
-fn attach_unixsock_vsock_device(
+fn attach_vsock_device(
     vmm: &mut Vmm,
     cmdline: &mut LoaderKernelCmdline,
-    unix_vsock: &Arc<Mutex<Vsock<VsockUnixBackend>>>,
+    vsock: &Arc<Mutex<Vsock<VsockHostBackend>>>,
     event_manager: &mut EventManager,
 ) -> std::result::Result<(), StartMicrovmError> {
-    let id = String::from(unix_vsock.lock().expect("Poisoned lock").id());
+    let id = String::from(vsock.lock().expect("Poisoned lock").id());
     // The device mutex mustn't be locked here otherwise it will deadlock.
-    attach_virtio_device(event_manager, vmm, id, unix_vsock.clone(), cmdline)
+    attach_virtio_device(event_manager, vmm, id, vsock.clone(), cmdline)
 }
 
 fn attach_balloon_device(
@@ -993,14 +997,15 @@ pub mod tests {
     use crate::vmm_config::boot_source::DEFAULT_KERNEL_CMDLINE;
     use crate::vmm_config::drive::{BlockBuilder, BlockDeviceConfig, CacheType, FileEngineType};
     use crate::vmm_config::net::{NetBuilder, NetworkInterfaceConfig};
+    use crate::vmm_config::rate_limiter_group::RateLimiterGroupBuilder;
     use crate::vmm_config::vsock::tests::default_config;
     use crate::vmm_config::vsock::{VsockBuilder, VsockDeviceConfig};
     use arch::DeviceType;
     use devices::virtio::vsock::VSOCK_DEV_ID;
     use devices::virtio::{TYPE_BALLOON, TYPE_BLOCK, TYPE_VSOCK};
     use linux_loader::cmdline::Cmdline;
-    use mmds::data_store::{Mmds, MmdsVersion};
-    use mmds::ns::MmdsNetworkStack;
+    use mmds::data_store::Mmds;
+    use mmds::ns::{MmdsNetworkStack, MmdsNetworkStackConfig};
     use utils::tempfile::TempFile;
     use vm_memory::GuestMemory;
 
@@ -1093,6 +1098,7 @@ pub mod tests {
             mmio_device_manager,
             #[cfg(target_arch = "x86_64")]
             pio_device_manager,
+            custom_cpu_template: None,
         }
     }
 
@@ -1120,9 +1126,17 @@ pub mod tests {
                 is_read_only: custom_block_cfg.is_read_only,
                 cache_type: custom_block_cfg.cache_type,
                 rate_limiter: None,
+                rate_limiter_group: None,
                 file_engine_type: FileEngineType::default(),
+                direct_io: false,
+                num_queues: 1,
+                io_threads: false,
+                fixed_buffers: false,
+                sq_poll: false,
             };
-            block_dev_configs.insert(block_device_config).unwrap();
+            block_dev_configs
+                .insert(block_device_config, &Default::default())
+                .unwrap();
         }
 
         attach_block_devices(vmm, cmdline, block_dev_configs.list.iter(), event_manager).unwrap();
@@ -1136,26 +1150,38 @@ pub mod tests {
         net_config: NetworkInterfaceConfig,
     ) {
         let mut net_builder = NetBuilder::new();
-        net_builder.build(net_config).unwrap();
+        net_builder.build(net_config, &Default::default()).unwrap();
 
         let res = attach_net_devices(vmm, cmdline, net_builder.iter(), event_manager);
         assert!(res.is_ok());
     }
 
+    pub(crate) fn insert_net_device_with_rate_limiter_groups(
+        vmm: &mut Vmm,
+        cmdline: &mut Cmdline,
+        event_manager: &mut EventManager,
+        net_config: NetworkInterfaceConfig,
+        rate_limiter_groups: &RateLimiterGroupBuilder,
+    ) {
+        let mut net_builder = NetBuilder::new();
+        net_builder.build(net_config, rate_limiter_groups).unwrap();
+
+        attach_net_devices(vmm, cmdline, net_builder.iter(), event_manager).unwrap();
+    }
+
     pub(crate) fn insert_net_device_with_mmds(
         vmm: &mut Vmm,
         cmdline: &mut Cmdline,
         event_manager: &mut EventManager,
         net_config: NetworkInterfaceConfig,
-        mmds_version: MmdsVersion,
+        mmds: Mmds,
     ) {
         let mut net_builder = NetBuilder::new();
-        net_builder.build(net_config).unwrap();
+        net_builder.build(net_config, &Default::default()).unwrap();
         let net = net_builder.iter().next().unwrap();
-        let mut mmds = Mmds::default();
-        mmds.set_version(mmds_version).unwrap();
         net.lock().unwrap().configure_mmds_network_stack(
             MmdsNetworkStack::default_ipv4_addr(),
+            MmdsNetworkStackConfig::default(),
             Arc::new(Mutex::new(mmds)),
         );
 
@@ -1169,10 +1195,10 @@ pub mod tests {
         vsock_config: VsockDeviceConfig,
     ) {
         let vsock_dev_id = VSOCK_DEV_ID.to_owned();
-        let vsock = VsockBuilder::create_unixsock_vsock(vsock_config).unwrap();
+        let vsock = VsockBuilder::create_vsock(vsock_config).unwrap();
         let vsock = Arc::new(Mutex::new(vsock));
 
-        assert!(attach_unixsock_vsock_device(vmm, cmdline, &vsock, event_manager).is_ok());
+        assert!(attach_vsock_device(vmm, cmdline, &vsock, event_manager).is_ok());
 
         assert!(vmm
             .mmio_device_manager
@@ -1312,6 +1338,10 @@ pub mod tests {
             guest_mac: None,
             rx_rate_limiter: None,
             tx_rate_limiter: None,
+            rx_rate_limiter_group: None,
+            tx_rate_limiter_group: None,
+            egress_filter: None,
+            user_net: None,
         };
 
         let mut cmdline = default_kernel_cmdline();
@@ -1324,7 +1354,9 @@ pub mod tests {
 
         // We can not attach it once more.
         let mut net_builder = NetBuilder::new();
-        assert!(net_builder.build(network_interface).is_err());
+        assert!(net_builder
+            .build(network_interface, &Default::default())
+            .is_err());
     }
 
     #[test]
@@ -22,13 +22,14 @@ use devices::virtio::net::persist::{Error as NetError, NetConstructorArgs, NetSt
 use devices::virtio::net::Net;
 use devices::virtio::persist::{MmioTransportConstructorArgs, MmioTransportState};
 use devices::virtio::vsock::persist::{VsockConstructorArgs, VsockState, VsockUdsConstructorArgs};
-use devices::virtio::vsock::{Vsock, VsockError, VsockUnixBackend, VsockUnixBackendError};
+use devices::virtio::vsock::{Vsock, VsockError, VsockHostBackend, VsockHostBackendError};
 use devices::virtio::{
     MmioTransport, VirtioDevice, TYPE_BALLOON, TYPE_BLOCK, TYPE_NET, TYPE_VSOCK,
 };
 use event_manager::{MutEventSubscriber, SubscriberOps};
 use kvm_ioctls::VmFd;
-use mmds::data_store::MmdsVersion;
+use mmds::data_store::{MmdsVersion, TokenBinding};
+use rate_limiter::{persist::RateLimiterState, RateLimiterGroup};
 use snapshot::Persist;
 use versionize::{VersionMap, Versionize, VersionizeError, VersionizeResult};
 use versionize_derive::Versionize;
@@ -45,8 +46,9 @@ pub enum Error {
     Legacy(crate::Error),
     Net(NetError),
     Vsock(VsockError),
-    VsockUnixBackend(VsockUnixBackendError),
+    VsockBackend(VsockHostBackendError),
     MmdsConfig(MmdsConfigError),
+    RateLimiterGroup(std::io::Error),
 }
 
 #[derive(Clone, Versionize)]
@@ -141,6 +143,65 @@ impl From<MmdsVersion> for MmdsVersionState {
     }
 }
 
+/// Holds the guest endpoint attributes that MMDS session tokens are bound to.
+#[derive(Debug, PartialEq, Versionize, Clone)]
+// NOTICE: Any changes to this structure require a snapshot version bump.
+pub enum MmdsTokenBindingState {
+    None,
+    SourceIp,
+    SourceIpAndPort,
+}
+
+impl From<MmdsTokenBindingState> for TokenBinding {
+    fn from(state: MmdsTokenBindingState) -> Self {
+        match state {
+            MmdsTokenBindingState::None => TokenBinding::None,
+            MmdsTokenBindingState::SourceIp => TokenBinding::SourceIp,
+            MmdsTokenBindingState::SourceIpAndPort => TokenBinding::SourceIpAndPort,
+        }
+    }
+}
+
+impl From<TokenBinding> for MmdsTokenBindingState {
+    fn from(token_binding: TokenBinding) -> Self {
+        match token_binding {
+            TokenBinding::None => MmdsTokenBindingState::None,
+            TokenBinding::SourceIp => MmdsTokenBindingState::SourceIp,
+            TokenBinding::SourceIpAndPort => MmdsTokenBindingState::SourceIpAndPort,
+        }
+    }
+}
+
+/// Holds the configuration of a named MMDS data store.
+#[derive(Clone, Debug, PartialEq, Versionize)]
+// NOTICE: Any changes to this structure require a snapshot version bump.
+pub struct MmdsDataStoreState {
+    /// Data store name.
+    pub name: String,
+    /// Data store version.
+    pub version: MmdsVersionState,
+    /// Data store token binding.
+    pub token_binding: MmdsTokenBindingState,
+    /// Network interfaces served by the data store.
+    pub network_interfaces: Vec<String>,
+}
+
+/// Holds the state of a rate limiter group and the devices attached to it.
+#[derive(Clone, Versionize)]
+// NOTICE: Any changes to this structure require a snapshot version bump.
+pub struct RateLimiterGroupState {
+    /// Group name.
+    pub name: String,
+    /// State of the token buckets of the group.
+    pub buckets: RateLimiterState,
+    /// Block devices attached to the group.
+    pub block_devices: Vec<String>,
+    /// Network interfaces whose RX rate limiter is attached to the group.
+    pub net_rx_devices: Vec<String>,
+    /// Network interfaces whose TX rate limiter is attached to the group.
+    pub net_tx_devices: Vec<String>,
+}
+
 #[derive(Clone, Versionize)]
 /// Holds the device states.
 // NOTICE: Any changes to this structure require a snapshot version bump.
@@ -160,6 +221,15 @@ pub struct DeviceStates {
     /// Mmds version.
     #[version(start = 3, ser_fn = "mmds_version_serialize")]
     pub mmds_version: Option<MmdsVersionState>,
+    /// Mmds token binding.
+    #[version(start = 3, ser_fn = "mmds_token_binding_serialize")]
+    pub mmds_token_binding: Option<MmdsTokenBindingState>,
+    /// Named Mmds data stores.
+    #[version(start = 3, ser_fn = "mmds_data_stores_serialize")]
+    pub mmds_data_stores: Vec<MmdsDataStoreState>,
+    /// Rate limiter groups shared by the devices.
+    #[version(start = 3, ser_fn = "rate_limiter_groups_serialize")]
+    pub rate_limiter_groups: Vec<RateLimiterGroupState>,
 }
 
 /// A type used to extract the concrete Arc<Mutex<T>> for each of the device types when restoring
@@ -168,7 +238,7 @@ pub enum SharedDeviceType {
     SharedBlock(Arc<Mutex<Block>>),
     SharedNetwork(Arc<Mutex<Net>>),
     SharedBalloon(Arc<Mutex<Balloon>>),
-    SharedVsock(Arc<Mutex<Vsock<VsockUnixBackend>>>),
+    SharedVsock(Arc<Mutex<Vsock<VsockHostBackend>>>),
 }
 
 impl DeviceStates {
@@ -192,6 +262,83 @@ impl DeviceStates {
 
         Ok(())
     }
+
+    fn mmds_token_binding_serialize(&mut self, target_version: u16) -> VersionizeResult<()> {
+        if target_version < 3
+            && self
+                .mmds_token_binding
+                .as_ref()
+                .map_or(false, |binding| *binding != MmdsTokenBindingState::None)
+        {
+            return Err(VersionizeError::Semantic(
+                "Target version does not support binding MMDS tokens.".to_owned(),
+            ));
+        }
+
+        Ok(())
+    }
+
+    fn mmds_data_stores_serialize(&mut self, target_version: u16) -> VersionizeResult<()> {
+        if target_version < 3 && !self.mmds_data_stores.is_empty() {
+            return Err(VersionizeError::Semantic(
+                "Target version does not support named MMDS data stores.".to_owned(),
+            ));
+        }
+
+        Ok(())
+    }
+
+    fn rate_limiter_groups_serialize(&mut self, target_version: u16) -> VersionizeResult<()> {
+        if target_version < 3 && !self.rate_limiter_groups.is_empty() {
+            return Err(VersionizeError::Semantic(
+                "Target version does not support rate limiter groups.".to_owned(),
+            ));
+        }
+
+        Ok(())
+    }
+
+    // Returns the state of `group`, adding it to the saved groups if needed.
+    fn rate_limiter_group_state(&mut self, group: &RateLimiterGroup) -> &mut RateLimiterGroupState {
+        match self
+            .rate_limiter_groups
+            .iter()
+            .position(|state| state.name == group.name())
+        {
+            Some(index) => &mut self.rate_limiter_groups[index],
+            None => {
+                self.rate_limiter_groups.push(RateLimiterGroupState {
+                    name: group.name().to_string(),
+                    buckets: group.save(),
+                    block_devices: Vec::new(),
+                    net_rx_devices: Vec::new(),
+                    net_tx_devices: Vec::new(),
+                });
+                self.rate_limiter_groups.last_mut().unwrap()
+            }
+        }
+    }
+
+    // Returns the name of the rate limiter group which has `device_id` in the device list
+    // selected by `devices`, if any.
+    fn rate_limiter_group_name<F>(&self, device_id: &str, devices: F) -> Option<&str>
+    where
+        F: Fn(&RateLimiterGroupState) -> &Vec<String>,
+    {
+        self.rate_limiter_groups
+            .iter()
+            .find(|group| devices(group).iter().any(|id| id == device_id))
+            .map(|group| group.name.as_str())
+    }
+
+    // Returns the name of the data store serving the network interface with `device_id`,
+    // or None if it's served by the default data store.
+    fn mmds_data_store_name(&self, device_id: &str) -> Option<&str> {
+        self.mmds_data_stores
+            .iter()
+            .find(|store| store.network_interfaces.iter().any(|id| id == device_id))
+            .map(|store| store.name.as_str())
+    }
 }
 
 pub struct MMIODevManagerConstructorArgs<'a> {
@@ -217,6 +364,9 @@ impl<'a> Persist<'a> for MMIODeviceManager {
             #[cfg(target_arch = "aarch64")]
             legacy_devices: Vec::new(),
             mmds_version: None,
+            mmds_token_binding: None,
+            mmds_data_stores: Vec::new(),
+            rate_limiter_groups: Vec::new(),
         };
         let _: Result<(), ()> = self.for_each_device(|devtype, devid, devinfo, bus_dev| {
             if *devtype == arch::DeviceType::BootTimer {
@@ -262,6 +412,12 @@ impl<'a> Persist<'a> for MMIODeviceManager {
                 TYPE_BLOCK => {
                     let block = locked_device.as_mut_any().downcast_mut::<Block>().unwrap();
                     block.prepare_save();
+                    if let Some(group) = block.rate_limiter().group() {
+                        states
+                            .rate_limiter_group_state(group)
+                            .block_devices
+                            .push(devid.clone());
+                    }
                     states.block_devices.push(ConnectedBlockState {
                         device_id: devid.clone(),
                         device_state: block.save(),
@@ -271,11 +427,42 @@ impl<'a> Persist<'a> for MMIODeviceManager {
                 }
                 TYPE_NET => {
                     let net = locked_device.as_any().downcast_ref::<Net>().unwrap();
-                    if let (Some(mmds_ns), None) =
-                        (net.mmds_ns.as_ref(), states.mmds_version.as_ref())
-                    {
-                        states.mmds_version =
-                            Some(mmds_ns.mmds.lock().expect("Poisoned lock").version().into());
+                    if let Some(group) = net.rx_rate_limiter().group() {
+                        states
+                            .rate_limiter_group_state(group)
+                            .net_rx_devices
+                            .push(devid.clone());
+                    }
+                    if let Some(group) = net.tx_rate_limiter().group() {
+                        states
+                            .rate_limiter_group_state(group)
+                            .net_tx_devices
+                            .push(devid.clone());
+                    }
+                    if let Some(mmds_ns) = net.mmds_ns.as_ref() {
+                        let mmds = mmds_ns.mmds.lock().expect("Poisoned lock");
+                        match mmds.name() {
+                            Some(name) => {
+                                match states
+                                    .mmds_data_stores
+                                    .iter_mut()
+                                    .find(|store| store.name == name)
+                                {
+                                    Some(store) => store.network_interfaces.push(devid.clone()),
+                                    None => states.mmds_data_stores.push(MmdsDataStoreState {
+                                        name: name.to_string(),
+                                        version: mmds.version().into(),
+                                        token_binding: mmds.token_binding().into(),
+                                        network_interfaces: vec![devid.clone()],
+                                    }),
+                                }
+                            }
+                            None if states.mmds_version.is_none() => {
+                                states.mmds_version = Some(mmds.version().into());
+                                states.mmds_token_binding = Some(mmds.token_binding().into());
+                            }
+                            None => (),
+                        }
                     }
 
                     states.net_devices.push(ConnectedNetState {
@@ -288,8 +475,8 @@ impl<'a> Persist<'a> for MMIODeviceManager {
                 TYPE_VSOCK => {
                     let vsock = locked_device
                         .as_mut_any()
-                        // Currently, VsockUnixBackend is the only implementation of VsockBackend.
-                        .downcast_mut::<Vsock<VsockUnixBackend>>()
+                        // Vsock devices are always created with a `VsockHostBackend`.
+                        .downcast_mut::<Vsock<VsockHostBackend>>()
                         .unwrap();
 
                     let vsock_state = VsockState {
@@ -402,10 +589,32 @@ impl<'a> Persist<'a> for MMIODeviceManager {
             )?;
         }
 
+        // Recreate the rate limiter groups before the devices attached to them.
+        for group_state in &state.rate_limiter_groups {
+            let group = RateLimiterGroup::restore(group_state.name.clone(), &group_state.buckets)
+                .map_err(Error::RateLimiterGroup)?;
+            constructor_args
+                .vm_resources
+                .rate_limiter_groups
+                .add_group(group);
+        }
+
         for block_state in &state.block_devices {
+            let rate_limiter_group = state
+                .rate_limiter_group_name(&block_state.device_id, |group| &group.block_devices)
+                .and_then(|name| {
+                    constructor_args
+                        .vm_resources
+                        .rate_limiter_groups
+                        .get(name)
+                        .ok()
+                });
             let device = Arc::new(Mutex::new(
                 Block::restore(
-                    BlockConstructorArgs { mem: mem.clone() },
+                    BlockConstructorArgs {
+                        mem: mem.clone(),
+                        rate_limiter_group,
+                    },
                     &block_state.device_state,
                 )
                 .map_err(Error::Block)?,
@@ -432,28 +641,77 @@ impl<'a> Persist<'a> for MMIODeviceManager {
                 .vm_resources
                 .set_mmds_version(mmds_version.clone().into(), constructor_args.instance_id)
                 .map_err(Error::MmdsConfig)?;
-        } else if state
-            .net_devices
-            .iter()
-            .any(|dev| dev.device_state.mmds_ns.is_some())
-        {
-            // If there's at least one network device having an mmds_ns, it means
-            // that we are restoring from a version that did not persist the `MmdsVersionState`.
-            // Init with the default.
+            if let Some(token_binding) = &state.mmds_token_binding {
+                constructor_args
+                    .vm_resources
+                    .locked_mmds_or_default()
+                    .set_token_binding(token_binding.clone().into());
+            }
+        } else if state.net_devices.iter().any(|dev| {
+            dev.device_state.mmds_ns.is_some()
+                && state.mmds_data_store_name(&dev.device_id).is_none()
+        }) {
+            // If there's at least one network device having an mmds_ns served by the default
+            // data store, it means that we are restoring from a version that did not persist
+            // the `MmdsVersionState`. Init with the default.
             constructor_args.vm_resources.mmds_or_default();
         }
 
+        for store in &state.mmds_data_stores {
+            constructor_args
+                .vm_resources
+                .set_mmds_data_store_version(
+                    &store.name,
+                    store.version.clone().into(),
+                    constructor_args.instance_id,
+                )
+                .map_err(Error::MmdsConfig)?;
+            constructor_args
+                .vm_resources
+                .locked_mmds_data_store_or_default(&store.name)
+                .set_token_binding(store.token_binding.clone().into());
+        }
+
         for net_state in &state.net_devices {
+            let mmds = match state.mmds_data_store_name(&net_state.device_id) {
+                Some(name) => Some(
+                    constructor_args
+                        .vm_resources
+                        .mmds_data_store_or_default(name)
+                        .clone(),
+                ),
+                None => constructor_args
+                    .vm_resources
+                    .mmds
+                    .as_ref()
+                    // Clone the Arc reference.
+                    .cloned(),
+            };
+            let rx_rate_limiter_group = state
+                .rate_limiter_group_name(&net_state.device_id, |group| &group.net_rx_devices)
+                .and_then(|name| {
+                    constructor_args
+                        .vm_resources
+                        .rate_limiter_groups
+                        .get(name)
+                        .ok()
+                });
+            let tx_rate_limiter_group = state
+                .rate_limiter_group_name(&net_state.device_id, |group| &group.net_tx_devices)
+                .and_then(|name| {
+                    constructor_args
+                        .vm_resources
+                        .rate_limiter_groups
+                        .get(name)
+                        .ok()
+                });
             let device = Arc::new(Mutex::new(
                 Net::restore(
                     NetConstructorArgs {
                         mem: mem.clone(),
-                        mmds: constructor_args
-                            .vm_resources
-                            .mmds
-                            .as_ref()
-                            // Clone the Arc reference.
-                            .cloned(),
+                        mmds,
+                        rx_rate_limiter_group,
+                        tx_rate_limiter_group,
                     },
                     &net_state.device_state,
                 )
@@ -479,8 +737,8 @@ impl<'a> Persist<'a> for MMIODeviceManager {
             let ctor_args = VsockUdsConstructorArgs {
                 cid: vsock_state.device_state.frontend.cid,
             };
-            let backend = VsockUnixBackend::restore(ctor_args, &vsock_state.device_state.backend)
-                .map_err(Error::VsockUnixBackend)?;
+            let backend = VsockHostBackend::restore(ctor_args, &vsock_state.device_state.backend)
+                .map_err(Error::VsockBackend)?;
             let device = Arc::new(Mutex::new(
                 Vsock::restore(
                     VsockConstructorArgs {
@@ -518,8 +776,11 @@ mod tests {
     use crate::resources::VmmConfig;
     use crate::vmm_config::balloon::BalloonDeviceConfig;
     use crate::vmm_config::net::NetworkInterfaceConfig;
+    use crate::vmm_config::rate_limiter_group::{RateLimiterGroupBuilder, RateLimiterGroupConfig};
     use crate::vmm_config::vsock::VsockDeviceConfig;
+    use crate::vmm_config::TokenBucketConfig;
     use devices::virtio::block::CacheType;
+    use mmds::data_store::Mmds;
     use utils::tempfile::TempFile;
 
     impl PartialEq for ConnectedBalloonState {
@@ -681,13 +942,19 @@ mod tests {
                 guest_mac: None,
                 rx_rate_limiter: None,
                 tx_rate_limiter: None,
+                rx_rate_limiter_group: None,
+                tx_rate_limiter_group: None,
+                egress_filter: None,
+                user_net: None,
             };
+            let mut mmds = Mmds::default();
+            mmds.set_version(MmdsVersion::V2).unwrap();
             insert_net_device_with_mmds(
                 &mut vmm,
                 &mut cmdline,
                 &mut event_manager,
                 network_interface,
-                MmdsVersion::V2,
+                mmds,
             );
             // Add a vsock device.
             let vsock_dev_id = "vsock";
@@ -695,6 +962,11 @@ mod tests {
                 vsock_id: Some(vsock_dev_id.to_string()),
                 guest_cid: 3,
                 uds_path: tmp_sock_file.as_path().to_str().unwrap().to_string(),
+                tcp: None,
+                rx_rate_limiter: None,
+                tx_rate_limiter: None,
+                max_connections: None,
+                allowed_ports: None,
             };
             insert_vsock_device(&mut vmm, &mut cmdline, &mut event_manager, vsock_config);
 
@@ -772,7 +1044,11 @@ mod tests {
       "is_read_only": true,
       "cache_type": "Unsafe",
       "rate_limiter": null,
-      "io_engine": "Sync"
+      "rate_limiter_group": null,
+      "io_engine": "Sync",
+      "direct_io": false,
+      "num_queues": 1,
+      "io_threads": false
     }}
   ],
   "boot-source": {{
@@ -829,6 +1105,11 @@ mod tests {
             MmdsVersion::V2
         );
         assert_eq!(device_states.mmds_version.unwrap(), MmdsVersion::V2.into());
+        assert_eq!(
+            device_states.mmds_token_binding.unwrap(),
+            TokenBinding::None.into()
+        );
+        assert!(device_states.mmds_data_stores.is_empty());
 
         assert_eq!(restored_dev_manager, original_mmio_device_manager);
         assert_eq!(
@@ -836,4 +1117,188 @@ mod tests {
             serde_json::to_string_pretty(&VmmConfig::from(&*vm_resources)).unwrap()
         );
     }
+
+    #[test]
+    fn test_mmds_data_store_persistence() {
+        let mut buf = vec![0; 16384];
+        let mut version_map = VersionMap::new();
+        version_map
+            .new_version()
+            .set_type_version(DeviceStates::type_id(), 2);
+        version_map
+            .new_version()
+            .set_type_version(DeviceStates::type_id(), 3);
+
+        // Set up a vmm with a net device served by a named data store.
+        {
+            let mut event_manager = EventManager::new().expect("Unable to create EventManager");
+            let mut vmm = default_vmm();
+            let mut cmdline = default_kernel_cmdline();
+            let network_interface = NetworkInterfaceConfig {
+                iface_id: String::from("sidecar_if"),
+                host_dev_name: String::from("sidecarhost"),
+                guest_mac: None,
+                rx_rate_limiter: None,
+                tx_rate_limiter: None,
+                rx_rate_limiter_group: None,
+                tx_rate_limiter_group: None,
+                egress_filter: None,
+                user_net: None,
+            };
+            let mut mmds = Mmds::new_named(String::from("sidecar"));
+            mmds.set_version(MmdsVersion::V2).unwrap();
+            mmds.set_token_binding(TokenBinding::SourceIp);
+            insert_net_device_with_mmds(
+                &mut vmm,
+                &mut cmdline,
+                &mut event_manager,
+                network_interface,
+                mmds,
+            );
+
+            let states = vmm.mmio_device_manager.save();
+            assert!(states.mmds_version.is_none());
+            assert_eq!(
+                states.mmds_data_stores,
+                vec![MmdsDataStoreState {
+                    name: String::from("sidecar"),
+                    version: MmdsVersionState::V2,
+                    token_binding: MmdsTokenBindingState::SourceIp,
+                    network_interfaces: vec![String::from("sidecar_if")],
+                }]
+            );
+
+            assert_eq!(
+                states.serialize(&mut buf.as_mut_slice(), &version_map, 2),
+                Err(VersionizeError::Semantic(
+                    "Target version does not support named MMDS data stores.".to_string()
+                ))
+            );
+            states
+                .serialize(&mut buf.as_mut_slice(), &version_map, 3)
+                .unwrap();
+        }
+
+        let mut event_manager = EventManager::new().expect("Unable to create EventManager");
+        let vmm = default_vmm();
+        let device_states: DeviceStates =
+            DeviceStates::deserialize(&mut buf.as_slice(), &version_map, 3).unwrap();
+        assert_eq!(device_states.mmds_data_stores.len(), 1);
+        let vm_resources = &mut VmResources::default();
+        let restore_args = MMIODevManagerConstructorArgs {
+            mem: vmm.guest_memory().clone(),
+            vm: vmm.vm.fd(),
+            event_manager: &mut event_manager,
+            for_each_restored_device: VmResources::update_from_restored_device,
+            vm_resources,
+            instance_id: "microvm-id",
+        };
+        MMIODeviceManager::restore(restore_args, &device_states).unwrap();
+
+        // The default data store is not initialised, since no interface uses it.
+        assert!(vm_resources.mmds.is_none());
+        let sidecar_mmds = vm_resources.mmds_data_store_or_default("sidecar").clone();
+        assert_eq!(sidecar_mmds.lock().unwrap().version(), MmdsVersion::V2);
+        assert_eq!(
+            sidecar_mmds.lock().unwrap().token_binding(),
+            TokenBinding::SourceIp
+        );
+        let net = vm_resources.net_builder.iter().next().unwrap();
+        assert!(Arc::ptr_eq(
+            &net.lock().unwrap().mmds_ns().unwrap().mmds,
+            &sidecar_mmds
+        ));
+    }
+
+    #[test]
+    fn test_rate_limiter_group_persistence() {
+        let mut buf = vec![0; 16384];
+        let mut version_map = VersionMap::new();
+        version_map
+            .new_version()
+            .set_type_version(DeviceStates::type_id(), 2);
+        version_map
+            .new_version()
+            .set_type_version(DeviceStates::type_id(), 3);
+
+        // Set up a vmm with a net device whose TX rate limiter is attached to a group.
+        {
+            let mut event_manager = EventManager::new().expect("Unable to create EventManager");
+            let mut vmm = default_vmm();
+            let mut cmdline = default_kernel_cmdline();
+            let mut groups = RateLimiterGroupBuilder::default();
+            groups
+                .insert(RateLimiterGroupConfig {
+                    group_id: String::from("egress"),
+                    bandwidth: Some(TokenBucketConfig {
+                        size: 1000,
+                        one_time_burst: None,
+                        refill_time: 100,
+                    }),
+                    ops: None,
+                })
+                .unwrap();
+            let network_interface = NetworkInterfaceConfig {
+                iface_id: String::from("netif"),
+                host_dev_name: String::from("hostgrp"),
+                guest_mac: None,
+                rx_rate_limiter: None,
+                tx_rate_limiter: None,
+                rx_rate_limiter_group: None,
+                tx_rate_limiter_group: Some(String::from("egress")),
+                egress_filter: None,
+                user_net: None,
+            };
+            insert_net_device_with_rate_limiter_groups(
+                &mut vmm,
+                &mut cmdline,
+                &mut event_manager,
+                network_interface,
+                &groups,
+            );
+
+            let states = vmm.mmio_device_manager.save();
+            assert_eq!(states.rate_limiter_groups.len(), 1);
+            assert_eq!(states.rate_limiter_groups[0].name, "egress");
+            assert!(states.rate_limiter_groups[0].block_devices.is_empty());
+            assert!(states.rate_limiter_groups[0].net_rx_devices.is_empty());
+            assert_eq!(
+                states.rate_limiter_groups[0].net_tx_devices,
+                vec![String::from("netif")]
+            );
+
+            assert_eq!(
+                states.serialize(&mut buf.as_mut_slice(), &version_map, 2),
+                Err(VersionizeError::Semantic(
+                    "Target version does not support rate limiter groups.".to_string()
+                ))
+            );
+            states
+                .serialize(&mut buf.as_mut_slice(), &version_map, 3)
+                .unwrap();
+        }
+
+        let mut event_manager = EventManager::new().expect("Unable to create EventManager");
+        let vmm = default_vmm();
+        let device_states: DeviceStates =
+            DeviceStates::deserialize(&mut buf.as_slice(), &version_map, 3).unwrap();
+        let vm_resources = &mut VmResources::default();
+        let restore_args = MMIODevManagerConstructorArgs {
+            mem: vmm.guest_memory().clone(),
+            vm: vmm.vm.fd(),
+            event_manager: &mut event_manager,
+            for_each_restored_device: VmResources::update_from_restored_device,
+            vm_resources,
+            instance_id: "microvm-id",
+        };
+        MMIODeviceManager::restore(restore_args, &device_states).unwrap();
+
+        // The group is recreated and the device is attached to it again.
+        let group = vm_resources.rate_limiter_groups.get("egress").unwrap();
+        assert_eq!(group.bandwidth().unwrap().capacity(), 1000);
+        let net = vm_resources.net_builder.iter().next().unwrap();
+        let net = net.lock().unwrap();
+        assert!(net.rx_rate_limiter().group().is_none());
+        assert_eq!(net.tx_rate_limiter().group(), Some(&group));
+    }
 }
@@ -45,7 +45,9 @@ use crate::device_manager::legacy::PortIODeviceManager;
 use crate::device_manager::mmio::MMIODeviceManager;
 use crate::memory_snapshot::SnapshotMemory;
 use crate::persist::{MicrovmState, MicrovmStateError, VmInfo};
+use crate::vmm_config::cpu_template::CustomCpuTemplate;
 use crate::vmm_config::instance_info::{InstanceInfo, VmState};
+use crate::vmm_config::RateLimiterUpdate;
 use crate::vstate::vcpu::VcpuState;
 use crate::vstate::{
     vcpu::{Vcpu, VcpuEvent, VcpuHandle, VcpuResponse},
@@ -55,13 +57,13 @@ use arch::DeviceType;
 use devices::legacy::serial::{IER_RDA_BIT, IER_RDA_OFFSET};
 use devices::virtio::balloon::Error as BalloonError;
 use devices::virtio::{
-    Balloon, BalloonConfig, BalloonStats, Block, MmioTransport, Net, BALLOON_DEV_ID, TYPE_BALLOON,
-    TYPE_BLOCK, TYPE_NET,
+    Balloon, BalloonConfig, BalloonStats, Block, MmioTransport, Net, Vsock, VsockHostBackend,
+    BALLOON_DEV_ID, TYPE_BALLOON, TYPE_BLOCK, TYPE_NET, TYPE_VSOCK, VSOCK_DEV_ID,
 };
 use devices::BusDevice;
 use event_manager::{EventManager as BaseEventManager, EventOps, Events, MutEventSubscriber};
 use logger::{error, info, warn, LoggerError, MetricsError, METRICS};
-use rate_limiter::BucketUpdate;
+use rate_limiter::{BucketUpdate, IoDirection};
 use seccompiler::BpfProgram;
 use snapshot::Persist;
 use utils::epoll::EventSet;
@@ -256,6 +258,9 @@ pub struct Vmm {
     mmio_device_manager: MMIODeviceManager,
     #[cfg(target_arch = "x86_64")]
     pio_device_manager: PortIODeviceManager,
+
+    // The user-defined CPU template the guest was configured with, saved in snapshots.
+    custom_cpu_template: Option<CustomCpuTemplate>,
 }
 
 impl Vmm {
@@ -445,6 +450,7 @@ impl Vmm {
             vm_state,
             vcpu_states,
             device_states,
+            custom_cpu_template: self.custom_cpu_template.clone(),
         })
     }
 
@@ -561,12 +567,26 @@ impl Vmm {
     pub fn update_block_rate_limiter(
         &mut self,
         drive_id: &str,
-        rl_bytes: BucketUpdate,
-        rl_ops: BucketUpdate,
+        update: RateLimiterUpdate,
     ) -> Result<()> {
         self.mmio_device_manager
             .with_virtio_device_with_id(TYPE_BLOCK, drive_id, |block: &mut Block| {
-                block.update_rate_limiter(rl_bytes, rl_ops);
+                if block.io_threads() {
+                    return Err(
+                        "IO threads are not supported on rate limited block devices".to_string()
+                    );
+                }
+                block.update_rate_limiter(update.bandwidth, update.ops);
+                block.update_direction_rate_limiter(
+                    IoDirection::Read,
+                    update.read_bandwidth,
+                    update.read_ops,
+                );
+                block.update_direction_rate_limiter(
+                    IoDirection::Write,
+                    update.write_bandwidth,
+                    update.write_ops,
+                );
                 Ok(())
             })
             .map_err(Error::DeviceManager)
@@ -589,6 +609,26 @@ impl Vmm {
             .map_err(Error::DeviceManager)
     }
 
+    /// Updates the rate limiter parameters for the vsock device.
+    pub fn update_vsock_rate_limiters(
+        &mut self,
+        rx_bytes: BucketUpdate,
+        rx_ops: BucketUpdate,
+        tx_bytes: BucketUpdate,
+        tx_ops: BucketUpdate,
+    ) -> Result<()> {
+        self.mmio_device_manager
+            .with_virtio_device_with_id(
+                TYPE_VSOCK,
+                VSOCK_DEV_ID,
+                |vsock: &mut Vsock<VsockHostBackend>| {
+                    vsock.patch_rate_limiters(rx_bytes, rx_ops, tx_bytes, tx_ops);
+                    Ok(())
+                },
+            )
+            .map_err(Error::DeviceManager)
+    }
+
     /// Returns a reference to the balloon device if present.
     pub fn balloon_config(&self) -> std::result::Result<BalloonConfig, BalloonError> {
         if let Some(busdev) = self.get_bus_device(DeviceType::Virtio(TYPE_BALLOON), BALLOON_DEV_ID)
@@ -27,11 +27,17 @@ use crate::{Error as VmmError, EventManager, Vmm};
 use cpuid::common::{get_vendor_id_from_cpuid, get_vendor_id_from_host};
 
 use crate::resources::VmResources;
+#[cfg(target_arch = "x86_64")]
+use crate::vmm_config::cpu_template::CPUID_FEATURE_REGISTERS;
+use crate::vmm_config::cpu_template::{CpuidRegister, CustomCpuTemplate};
 use crate::vmm_config::instance_info::InstanceInfo;
 #[cfg(target_arch = "aarch64")]
 use arch::regs::{get_manufacturer_id_from_host, get_manufacturer_id_from_state};
-use logger::{error, info};
+#[cfg(target_arch = "x86_64")]
+use kvm_bindings::CpuId;
+use logger::{error, info, warn};
 use seccompiler::BpfThreadMap;
+use serde::Serialize;
 use snapshot::Snapshot;
 use versionize::{VersionMap, Versionize, VersionizeResult};
 use versionize_derive::Versionize;
@@ -41,6 +47,19 @@ use vm_memory::GuestMemoryMmap;
 #[cfg(target_arch = "x86_64")]
 const FC_V0_23_MAX_DEVICES: u32 = 11;
 
+#[cfg(target_arch = "x86_64")]
+/// CPUID bits which are not checked against the host when looking for missing CPU features,
+/// either because Firecracker exposes them regardless of the host support, or because they
+/// reflect the guest OS state rather than a CPU feature.
+const CPUID_IGNORED_BITS: &[(u32, u32, CpuidRegister, u32)] = &[
+    // TSC deadline timer (bit 24), OSXSAVE (bit 27) and hypervisor present (bit 31).
+    (0x1, 0x0, CpuidRegister::Ecx, 0x8900_0000),
+    // OSPKE (bit 4).
+    (0x7, 0x0, CpuidRegister::Ecx, 1 << 4),
+    // Topology extensions (bit 22), always exposed on AMD.
+    (0x8000_0001, 0x0, CpuidRegister::Ecx, 1 << 22),
+];
+
 /// Holds information related to the VM that is not part of VmState.
 #[derive(Debug, PartialEq, Versionize)]
 // NOTICE: Any changes to this structure require a snapshot version bump.
@@ -63,6 +82,76 @@ pub struct MicrovmState {
     pub vcpu_states: Vec<VcpuState>,
     /// Device states.
     pub device_states: DeviceStates,
+    /// The user-defined CPU template the guest was configured with.
+    #[version(start = 2, ser_fn = "custom_cpu_template_serialize")]
+    pub custom_cpu_template: Option<CustomCpuTemplate>,
+}
+
+impl MicrovmState {
+    fn custom_cpu_template_serialize(&mut self, target_version: u16) -> VersionizeResult<()> {
+        if target_version < 2 && self.custom_cpu_template.is_some() {
+            warn!(
+                "Target version does not support persisting the custom CPU template. It will not \
+                be validated against the host when restoring."
+            );
+        }
+
+        Ok(())
+    }
+}
+
+/// CPUID feature bits the snapshotted guest was using and the host does not support.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct MissingCpuidFeatures {
+    /// The CPUID leaf.
+    pub leaf: u32,
+    /// The CPUID subleaf.
+    pub subleaf: u32,
+    /// The register holding the feature bits.
+    pub register: CpuidRegister,
+    /// The unsupported feature bits.
+    pub bits: u32,
+}
+
+/// Outcome of comparing the CPU features saved in a snapshot with the ones supported by the host.
+#[derive(Clone, Debug, Default, PartialEq, Serialize)]
+pub struct CpuCompatibilityReport {
+    /// CPUID feature bits, aggregated over all vCPUs, which the host does not support.
+    pub missing_cpuid_features: Vec<MissingCpuidFeatures>,
+    /// Saved MSRs which KVM cannot restore on the host.
+    pub missing_msrs: Vec<u32>,
+}
+
+impl CpuCompatibilityReport {
+    /// Returns `true` if the host supports all the CPU features of the snapshot.
+    pub fn is_compatible(&self) -> bool {
+        self.missing_cpuid_features.is_empty() && self.missing_msrs.is_empty()
+    }
+}
+
+impl Display for CpuCompatibilityReport {
+    fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
+        if self.is_compatible() {
+            return write!(f, "The host supports all the CPU features of the snapshot.");
+        }
+
+        write!(
+            f,
+            "The host does not support the following CPU features of the snapshot:"
+        )?;
+        for feature in self.missing_cpuid_features.iter() {
+            write!(
+                f,
+                "\n  CPUID leaf {:#x}, subleaf {:#x}, {}: {:#010x}",
+                feature.leaf, feature.subleaf, feature.register, feature.bits
+            )?;
+        }
+        for msr in self.missing_msrs.iter() {
+            write!(f, "\n  MSR {:#x}", msr)?;
+        }
+
+        Ok(())
+    }
 }
 
 /// Errors related to saving and restoring Microvm state.
@@ -193,6 +282,10 @@ pub enum LoadSnapshotError {
     SnapshotBackingFile(&'static str, io::Error),
     /// Snapshot cpu vendor differs than host cpu vendor.
     CpuVendorCheck(String),
+    /// The host does not support the snapshot custom CPU template.
+    CpuTemplateCheck(String),
+    /// Failed to compare the snapshot CPU features with the host ones.
+    CpuFeaturesCheck(String),
     /// Snapshot failed sanity checks.
     InvalidSnapshot(String),
 }
@@ -218,6 +311,8 @@ impl Display for LoadSnapshotError {
                 action, err
             ),
             CpuVendorCheck(err) => write!(f, "CPU vendor check failed: {}", err),
+            CpuTemplateCheck(err) => write!(f, "CPU template check failed: {}", err),
+            CpuFeaturesCheck(err) => write!(f, "CPU features check failed: {}", err),
             InvalidSnapshot(err) => write!(f, "Snapshot sanity check failed: {}", err),
         }
     }
@@ -373,6 +468,123 @@ pub fn validate_cpu_vendor(
     Ok(())
 }
 
+/// Validates that the host supports the custom CPU template the snapshotted guest was
+/// configured with.
+#[cfg(target_arch = "x86_64")]
+pub fn validate_cpu_template(
+    microvm_state: &MicrovmState,
+) -> std::result::Result<(), LoadSnapshotError> {
+    let template = match microvm_state.custom_cpu_template.as_ref() {
+        Some(template) => template,
+        None => return Ok(()),
+    };
+
+    let kvm = kvm_ioctls::Kvm::new()
+        .map_err(|err| LoadSnapshotError::CpuTemplateCheck(format!("Cannot open KVM: {}", err)))?;
+    let supported_cpuid = kvm
+        .get_supported_cpuid(kvm_bindings::KVM_MAX_CPUID_ENTRIES)
+        .map_err(|err| {
+            LoadSnapshotError::CpuTemplateCheck(format!("Cannot get the supported CPUID: {}", err))
+        })?;
+    let supported_msrs = kvm.get_msr_index_list().map_err(|err| {
+        LoadSnapshotError::CpuTemplateCheck(format!("Cannot get the supported MSRs: {}", err))
+    })?;
+
+    template
+        .check_host_support(&supported_cpuid, supported_msrs.as_slice())
+        .map_err(|err| {
+            let error_string = err.to_string();
+            error!("{}", error_string);
+            LoadSnapshotError::CpuTemplateCheck(error_string)
+        })
+}
+
+/// Compares the CPUID and the MSRs saved for each vCPU with the ones KVM supports on the host.
+#[cfg(target_arch = "x86_64")]
+pub fn check_cpu_compatibility(
+    microvm_state: &MicrovmState,
+) -> std::result::Result<CpuCompatibilityReport, LoadSnapshotError> {
+    let kvm = kvm_ioctls::Kvm::new()
+        .map_err(|err| LoadSnapshotError::CpuFeaturesCheck(format!("Cannot open KVM: {}", err)))?;
+    let supported_cpuid = kvm
+        .get_supported_cpuid(kvm_bindings::KVM_MAX_CPUID_ENTRIES)
+        .map_err(|err| {
+            LoadSnapshotError::CpuFeaturesCheck(format!("Cannot get the supported CPUID: {}", err))
+        })?;
+    let supported_msrs = arch::x86_64::msr::supported_guest_msrs(&kvm).map_err(|err| {
+        LoadSnapshotError::CpuFeaturesCheck(format!("Cannot get the supported MSRs: {:?}", err))
+    })?;
+
+    Ok(cpu_compatibility_report(
+        &microvm_state.vcpu_states,
+        &supported_cpuid,
+        supported_msrs.as_slice(),
+    ))
+}
+
+/// The aarch64 vCPU state does not describe the CPU features, so there is nothing to compare
+/// beyond the manufacturer ID, which is checked by `snapshot_state_sanity_check`.
+#[cfg(target_arch = "aarch64")]
+pub fn check_cpu_compatibility(
+    _microvm_state: &MicrovmState,
+) -> std::result::Result<CpuCompatibilityReport, LoadSnapshotError> {
+    Ok(CpuCompatibilityReport::default())
+}
+
+#[cfg(target_arch = "x86_64")]
+fn cpu_compatibility_report(
+    vcpu_states: &[VcpuState],
+    supported_cpuid: &CpuId,
+    supported_msrs: &[u32],
+) -> CpuCompatibilityReport {
+    let mut report = CpuCompatibilityReport::default();
+
+    for &(leaf, subleaf, register) in CPUID_FEATURE_REGISTERS.iter() {
+        // A leaf which KVM does not report at all supports no feature.
+        let supported = cpuid_register(supported_cpuid, leaf, subleaf, register).unwrap_or(0);
+        let ignored = CPUID_IGNORED_BITS
+            .iter()
+            .filter(|entry| (entry.0, entry.1, entry.2) == (leaf, subleaf, register))
+            .fold(0, |bits, entry| bits | entry.3);
+        let bits = vcpu_states
+            .iter()
+            .filter_map(|state| cpuid_register(&state.cpuid, leaf, subleaf, register))
+            .fold(0, |bits, saved| bits | (saved & !supported & !ignored));
+        if bits != 0 {
+            report.missing_cpuid_features.push(MissingCpuidFeatures {
+                leaf,
+                subleaf,
+                register,
+                bits,
+            });
+        }
+    }
+
+    report.missing_msrs = vcpu_states
+        .iter()
+        .flat_map(|state| state.msr_indices())
+        .filter(|index| !supported_msrs.contains(index))
+        .collect();
+    report.missing_msrs.sort_unstable();
+    report.missing_msrs.dedup();
+
+    report
+}
+
+#[cfg(target_arch = "x86_64")]
+fn cpuid_register(cpuid: &CpuId, leaf: u32, subleaf: u32, register: CpuidRegister) -> Option<u32> {
+    cpuid
+        .as_slice()
+        .iter()
+        .find(|entry| entry.function == leaf && entry.index == subleaf)
+        .map(|entry| match register {
+            CpuidRegister::Eax => entry.eax,
+            CpuidRegister::Ebx => entry.ebx,
+            CpuidRegister::Ecx => entry.ecx,
+            CpuidRegister::Edx => entry.edx,
+        })
+}
+
 /// Validate that Snapshot Manufacturer ID matches
 /// the one from the Host
 ///
@@ -427,6 +639,8 @@ pub fn snapshot_state_sanity_check(
 
     #[cfg(target_arch = "x86_64")]
     validate_cpu_vendor(&microvm_state)?;
+    #[cfg(target_arch = "x86_64")]
+    validate_cpu_template(&microvm_state)?;
     #[cfg(target_arch = "aarch64")]
     validate_cpu_manufacturer_id(&microvm_state)?;
 
@@ -467,6 +681,17 @@ pub fn restore_from_snapshot(
     .map_err(BuildMicroVm)
 }
 
+/// Checks whether the host can run the microVM saved in a snapshot state file, without
+/// restoring it.
+pub fn check_snapshot_compatibility(
+    snapshot_path: &Path,
+    version_map: VersionMap,
+) -> std::result::Result<CpuCompatibilityReport, LoadSnapshotError> {
+    let microvm_state = snapshot_state_from_file(snapshot_path, version_map)?;
+    snapshot_state_sanity_check(&microvm_state)?;
+    check_cpu_compatibility(&microvm_state)
+}
+
 fn snapshot_state_from_file(
     snapshot_path: &Path,
     version_map: VersionMap,
@@ -568,6 +793,10 @@ mod tests {
             guest_mac: None,
             rx_rate_limiter: None,
             tx_rate_limiter: None,
+            rx_rate_limiter_group: None,
+            tx_rate_limiter_group: None,
+            egress_filter: None,
+            user_net: None,
         };
         insert_net_device(
             &mut vmm,
@@ -611,6 +840,7 @@ mod tests {
             vm_state: vmm.vm.save_state(&mpidrs).unwrap(),
             #[cfg(target_arch = "x86_64")]
             vm_state: vmm.vm.save_state().unwrap(),
+            custom_cpu_template: Some(CustomCpuTemplate::default()),
         };
 
         let mut buf = vec![0; 10000];
@@ -634,7 +864,186 @@ mod tests {
         assert_eq!(
             restored_microvm_state.device_states,
             microvm_state.device_states
+        );
+        // The custom CPU template is only saved starting with the second version.
+        assert!(restored_microvm_state.custom_cpu_template.is_none());
+
+        version_map
+            .new_version()
+            .set_type_version(MicrovmState::type_id(), 2);
+        microvm_state
+            .serialize(&mut buf.as_mut_slice(), &version_map, 3)
+            .unwrap();
+        let restored_microvm_state =
+            MicrovmState::deserialize(&mut buf.as_slice(), &version_map, 3).unwrap();
+        assert_eq!(
+            restored_microvm_state.custom_cpu_template,
+            microvm_state.custom_cpu_template
+        );
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    #[test]
+    fn test_validate_cpu_template() {
+        use crate::vmm_config::cpu_template::{CpuidLeafModifier, MsrModifier};
+
+        let vmm = default_vmm_with_devices();
+        let mut microvm_state = MicrovmState {
+            device_states: vmm.mmio_device_manager.save(),
+            memory_state: vmm.guest_memory().describe(),
+            vcpu_states: vec![VcpuState::default()],
+            vm_info: VmInfo { mem_size_mib: 1u64 },
+            vm_state: vmm.vm.save_state().unwrap(),
+            custom_cpu_template: None,
+        };
+        validate_cpu_template(&microvm_state).unwrap();
+
+        microvm_state.custom_cpu_template = Some(CustomCpuTemplate::default());
+        validate_cpu_template(&microvm_state).unwrap();
+
+        microvm_state.custom_cpu_template = Some(CustomCpuTemplate {
+            cpuid_modifiers: vec![CpuidLeafModifier {
+                leaf: 0x4fff_ffff,
+                subleaf: 0,
+                modifiers: vec![],
+            }],
+            msr_modifiers: vec![],
+        });
+        assert!(matches!(
+            validate_cpu_template(&microvm_state),
+            Err(LoadSnapshotError::CpuTemplateCheck(_))
+        ));
+
+        microvm_state.custom_cpu_template = Some(CustomCpuTemplate {
+            cpuid_modifiers: vec![],
+            msr_modifiers: vec![MsrModifier {
+                addr: 0xdead,
+                bitmap: Default::default(),
+            }],
+        });
+        assert_eq!(
+            validate_cpu_template(&microvm_state)
+                .unwrap_err()
+                .to_string(),
+            "CPU template check failed: MSR 0xdead is not supported by the host."
+        );
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    #[test]
+    fn test_cpu_compatibility_report() {
+        use kvm_bindings::kvm_cpuid_entry2;
+
+        let entry = |function, ecx, edx| kvm_cpuid_entry2 {
+            function,
+            ecx,
+            edx,
+            ..Default::default()
+        };
+        let supported_cpuid =
+            CpuId::from_entries(&[entry(0x1, 0x1, 0x1), entry(0x7, 0x0, 0x0)]).unwrap();
+
+        // Only supported and ignored bits are set. The default state saves MSR 0x0.
+        let mut state = VcpuState::default();
+        state.cpuid = CpuId::from_entries(&[entry(0x1, 0x8900_0001, 0x1)]).unwrap();
+        let report = cpu_compatibility_report(&[state.clone()], &supported_cpuid, &[0x0]);
+        assert!(report.is_compatible());
+        assert_eq!(
+            report.to_string(),
+            "The host supports all the CPU features of the snapshot."
+        );
+
+        // Missing bits are aggregated over all vCPUs.
+        let mut other_state = state.clone();
+        other_state.cpuid = CpuId::from_entries(&[
+            entry(0x1, 0x3, 0x1),
+            entry(0x7, 0x12, 0x0),
+            entry(0x8000_0001, 0x0, 0x10),
+        ])
+        .unwrap();
+        let report = cpu_compatibility_report(&[state, other_state], &supported_cpuid, &[]);
+        assert!(!report.is_compatible());
+        assert_eq!(
+            report,
+            CpuCompatibilityReport {
+                missing_cpuid_features: vec![
+                    MissingCpuidFeatures {
+                        leaf: 0x1,
+                        subleaf: 0x0,
+                        register: CpuidRegister::Ecx,
+                        bits: 0x2,
+                    },
+                    MissingCpuidFeatures {
+                        leaf: 0x7,
+                        subleaf: 0x0,
+                        register: CpuidRegister::Ecx,
+                        bits: 0x2,
+                    },
+                    MissingCpuidFeatures {
+                        leaf: 0x8000_0001,
+                        subleaf: 0x0,
+                        register: CpuidRegister::Edx,
+                        bits: 0x10,
+                    },
+                ],
+                missing_msrs: vec![0x0],
+            }
+        );
+        assert_eq!(
+            report.to_string(),
+            "The host does not support the following CPU features of the snapshot:\n  \
+             CPUID leaf 0x1, subleaf 0x0, ecx: 0x00000002\n  \
+             CPUID leaf 0x7, subleaf 0x0, ecx: 0x00000002\n  \
+             CPUID leaf 0x80000001, subleaf 0x0, edx: 0x00000010\n  \
+             MSR 0x0"
+        );
+        assert_eq!(
+            serde_json::to_string(&report).unwrap(),
+            "{\"missing_cpuid_features\":[\
+             {\"leaf\":1,\"subleaf\":0,\"register\":\"ecx\",\"bits\":2},\
+             {\"leaf\":7,\"subleaf\":0,\"register\":\"ecx\",\"bits\":2},\
+             {\"leaf\":2147483649,\"subleaf\":0,\"register\":\"edx\",\"bits\":16}],\
+             \"missing_msrs\":[0]}"
+        );
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    #[test]
+    fn test_check_cpu_compatibility() {
+        use crate::vmm_config::machine_config::CpuFeaturesTemplate;
+        use crate::vstate::vcpu::{KvmVcpu, VcpuConfig};
+        use crate::vstate::vm::tests::setup_vm;
+        use vm_memory::GuestAddress;
+
+        // A vCPU configured on this host only uses features the host supports.
+        let (vm, vm_mem) = setup_vm(0x10000);
+        vm.setup_irqchip().unwrap();
+        let mut vcpu = KvmVcpu::new(0, &vm).unwrap();
+        let vcpu_config = VcpuConfig {
+            vcpu_count: 1,
+            smt: false,
+            cpu_template: CpuFeaturesTemplate::None,
+            custom_cpu_template: None,
+        };
+        vcpu.configure(
+            &vm_mem,
+            GuestAddress(0),
+            &vcpu_config,
+            vm.supported_cpuid().clone(),
         )
+        .unwrap();
+
+        let vmm = default_vmm_with_devices();
+        let microvm_state = MicrovmState {
+            device_states: vmm.mmio_device_manager.save(),
+            memory_state: vmm.guest_memory().describe(),
+            vcpu_states: vec![vcpu.save_state().unwrap()],
+            vm_info: VmInfo { mem_size_mib: 1u64 },
+            vm_state: vmm.vm.save_state().unwrap(),
+            custom_cpu_template: None,
+        };
+        let report = check_cpu_compatibility(&microvm_state).unwrap();
+        assert!(report.is_compatible(), "{}", report);
     }
 
     #[test]
@@ -738,6 +1147,9 @@ mod tests {
 
         let err = CpuVendorCheck(String::new());
         let _ = format!("{}{:?}", err, err);
+
+        let err = CpuFeaturesCheck(String::new());
+        let _ = format!("{}{:?}", err, err);
     }
 
     #[test]
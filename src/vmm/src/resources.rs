@@ -3,23 +3,28 @@
 
 use crate::vmm_config::balloon::*;
 use crate::vmm_config::boot_source::{BootConfig, BootSourceConfig, BootSourceConfigError};
+use crate::vmm_config::cpu_template::CustomCpuTemplate;
 use crate::vmm_config::drive::*;
 use crate::vmm_config::instance_info::InstanceInfo;
 use crate::vmm_config::logger::{init_logger, LoggerConfig, LoggerConfigError};
 use crate::vmm_config::machine_config::{VmConfig, VmConfigError, VmUpdateConfig};
 use crate::vmm_config::metrics::{init_metrics, MetricsConfig, MetricsConfigError};
-use crate::vmm_config::mmds::{MmdsConfig, MmdsConfigError};
+use crate::vmm_config::mmds::{validate_data_store_name, MmdsConfig, MmdsConfigError};
 use crate::vmm_config::net::*;
+use crate::vmm_config::rate_limiter_group::*;
 use crate::vmm_config::vsock::*;
 use crate::vstate::vcpu::VcpuConfig;
 use logger::info;
-use mmds::ns::MmdsNetworkStack;
+use mmds::ns::{MmdsNetworkStack, MmdsNetworkStackConfig};
+use mmds::MAX_DATA_STORE_SIZE;
 use utils::net::ipv4addr::is_link_local_valid;
 
 use crate::device_manager::persist::SharedDeviceType;
-use mmds::data_store::{Mmds, MmdsVersion};
+use mmds::data_store::{self, Mmds, MmdsVersion};
 use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
 use std::convert::From;
+use std::num::NonZeroUsize;
 use std::sync::{Arc, Mutex, MutexGuard};
 
 type Result<E> = std::result::Result<(), E>;
@@ -43,6 +48,8 @@ pub enum Error {
     MmdsConfig(MmdsConfigError),
     /// Net device configuration error.
     NetDevice(NetworkInterfaceError),
+    /// Rate limiter group configuration error.
+    RateLimiterGroup(RateLimiterGroupError),
     /// microVM vCpus or memory configuration error.
     VmConfig(VmConfigError),
     /// Vsock device configuration error.
@@ -60,6 +67,7 @@ impl std::fmt::Display for Error {
             Error::Metrics(e) => write!(f, "Metrics error: {}", e),
             Error::MmdsConfig(e) => write!(f, "MMDS config error: {}", e),
             Error::NetDevice(e) => write!(f, "Network device error: {}", e),
+            Error::RateLimiterGroup(e) => write!(f, "Rate limiter group error: {}", e),
             Error::VmConfig(e) => write!(f, "VM config error: {}", e),
             Error::VsockDevice(e) => write!(f, "Vsock device error: {}", e),
         }
@@ -83,8 +91,20 @@ pub struct VmmConfig {
     metrics: Option<MetricsConfig>,
     #[serde(rename = "mmds-config")]
     mmds_config: Option<MmdsConfig>,
+    #[serde(
+        rename = "mmds-data-stores",
+        default,
+        skip_serializing_if = "Vec::is_empty"
+    )]
+    mmds_data_stores: Vec<MmdsConfig>,
     #[serde(rename = "network-interfaces", default)]
     net_devices: Vec<NetworkInterfaceConfig>,
+    #[serde(
+        rename = "rate-limiters",
+        default,
+        skip_serializing_if = "Vec::is_empty"
+    )]
+    rate_limiter_groups: Vec<RateLimiterGroupConfig>,
     #[serde(rename = "vsock")]
     vsock_device: Option<VsockDeviceConfig>,
 }
@@ -95,6 +115,8 @@ pub struct VmmConfig {
 pub struct VmResources {
     /// The vCpu and memory configuration for this microVM.
     vm_config: VmConfig,
+    /// The user-defined CPU template loaded from `vm_config.cpu_template_path`.
+    custom_cpu_template: Option<CustomCpuTemplate>,
     /// The boot configuration for this microVM.
     boot_config: Option<BootConfig>,
     /// The block devices.
@@ -109,6 +131,11 @@ pub struct VmResources {
     // This is initialised on demand (if ever used), so that we don't allocate it unless it's
     // actually used.
     pub mmds: Option<Arc<Mutex<Mmds>>>,
+    /// The named Mmds data stores, which can be served to network interfaces instead of the
+    /// default one.
+    pub mmds_data_stores: BTreeMap<String, Arc<Mutex<Mmds>>>,
+    /// The rate limiter groups shared by the devices.
+    pub rate_limiter_groups: RateLimiterGroupBuilder,
     /// Whether or not to load boot timer device.
     pub boot_timer: bool,
 }
@@ -144,6 +171,13 @@ impl VmResources {
             .set_boot_source(vmm_config.boot_source)
             .map_err(Error::BootSource)?;
 
+        // The groups need to be defined before the devices referencing them.
+        for group_config in vmm_config.rate_limiter_groups.into_iter() {
+            resources
+                .set_rate_limiter_group(group_config)
+                .map_err(Error::RateLimiterGroup)?;
+        }
+
         for drive_config in vmm_config.block_devices.into_iter() {
             resources
                 .set_block_device(drive_config)
@@ -177,9 +211,13 @@ impl VmResources {
 
         // Init the data store from file, if present.
         if let Some(data) = metadata_json {
-            resources.locked_mmds_or_default().put_data(
-                serde_json::from_str(&data).expect("MMDS error: metadata provided not valid json"),
-            );
+            resources
+                .locked_mmds_or_default()
+                .put_data(
+                    serde_json::from_str(&data)
+                        .expect("MMDS error: metadata provided not valid json"),
+                )
+                .expect("MMDS error: metadata provided exceeds the data store limit");
             info!("Successfully added metadata to mmds from file");
         }
 
@@ -189,6 +227,12 @@ impl VmResources {
                 .map_err(Error::MmdsConfig)?;
         }
 
+        for mmds_config in vmm_config.mmds_data_stores.into_iter() {
+            resources
+                .set_mmds_config(mmds_config, &instance_info.id)
+                .map_err(Error::MmdsConfig)?;
+        }
+
         Ok(resources)
     }
 
@@ -204,6 +248,35 @@ impl VmResources {
         mmds.lock().expect("Poisoned lock")
     }
 
+    /// If not initialised, create the named mmds data store with the default config.
+    pub fn mmds_data_store_or_default(&mut self, name: &str) -> &Arc<Mutex<Mmds>> {
+        self.mmds_data_stores
+            .entry(name.to_string())
+            .or_insert_with(|| Arc::new(Mutex::new(Mmds::new_named(name.to_string()))))
+    }
+
+    /// If not initialised, create the named mmds data store with the default config.
+    pub fn locked_mmds_data_store_or_default(&mut self, name: &str) -> MutexGuard<'_, Mmds> {
+        let mmds = self.mmds_data_store_or_default(name);
+        mmds.lock().expect("Poisoned lock")
+    }
+
+    /// Returns the named mmds data store, if it was initialised.
+    pub fn locked_mmds_data_store(&self, name: &str) -> Option<MutexGuard<'_, Mmds>> {
+        self.mmds_data_stores
+            .get(name)
+            .map(|mmds| mmds.lock().expect("Poisoned lock"))
+    }
+
+    // Returns the named data store, or the default one if `name` is None. The data store
+    // is initialised with the default config if needed.
+    fn data_store_or_default(&mut self, name: Option<&str>) -> Arc<Mutex<Mmds>> {
+        match name {
+            Some(name) => self.mmds_data_store_or_default(name).clone(),
+            None => self.mmds_or_default().clone(),
+        }
+    }
+
     /// Updates the resources from a restored device (used for configuring resources when
     /// restoring from a snapshot).
     pub fn update_from_restored_device(&mut self, device: SharedDeviceType) {
@@ -234,6 +307,7 @@ impl VmResources {
             vcpu_count: self.vm_config().vcpu_count,
             smt: self.vm_config().smt,
             cpu_template: self.vm_config().cpu_template,
+            custom_cpu_template: self.custom_cpu_template.clone(),
         }
     }
 
@@ -254,6 +328,16 @@ impl VmResources {
 
     /// Update the machine configuration of the microVM.
     pub fn update_vm_config(&mut self, machine_config: &VmUpdateConfig) -> Result<VmConfigError> {
+        // Load the custom CPU template upfront, so that an invalid one leaves the
+        // configuration untouched.
+        let custom_cpu_template = match machine_config.cpu_template_path {
+            Some(ref path) => Some(
+                CustomCpuTemplate::from_file(path)
+                    .map_err(|err| VmConfigError::InvalidCpuTemplate(err.to_string()))?,
+            ),
+            None => None,
+        };
+
         let vcpu_count = machine_config
             .vcpu_count
             .unwrap_or(self.vm_config.vcpu_count);
@@ -300,6 +384,10 @@ impl VmResources {
         if let Some(cpu_template) = machine_config.cpu_template {
             self.vm_config.cpu_template = cpu_template;
         }
+        if custom_cpu_template.is_some() {
+            self.vm_config.cpu_template_path = machine_config.cpu_template_path.clone();
+            self.custom_cpu_template = custom_cpu_template;
+        }
 
         // Update dirty page tracking
         if let Some(track_dirty_pages) = machine_config.track_dirty_pages {
@@ -309,35 +397,80 @@ impl VmResources {
         Ok(())
     }
 
-    // Repopulate the MmdsConfig based on information from the data store
-    // and the associated net devices.
+    // Repopulate the MmdsConfig of the default data store.
     fn mmds_config(&self) -> Option<MmdsConfig> {
         // If the data store is not initialised, we can be sure that the user did not configure
         // mmds.
         let mmds = self.mmds.as_ref()?;
+        self.data_store_config(None, mmds)
+    }
 
+    // Repopulate the MmdsConfig of each named data store which serves network interfaces.
+    fn mmds_data_store_configs(&self) -> Vec<MmdsConfig> {
+        self.mmds_data_stores
+            .iter()
+            .filter_map(|(name, mmds)| self.data_store_config(Some(name), mmds))
+            .collect()
+    }
+
+    // Repopulate the MmdsConfig based on information from the data store
+    // and the associated net devices.
+    fn data_store_config(&self, name: Option<&str>, mmds: &Arc<Mutex<Mmds>>) -> Option<MmdsConfig> {
         let mut mmds_config = None;
         let net_devs_with_mmds: Vec<_> = self
             .net_builder
             .iter()
-            .filter(|net| net.lock().expect("Poisoned lock").mmds_ns().is_some())
+            .filter(|net| {
+                // Interfaces served by other data stores are not part of this config.
+                net.lock()
+                    .expect("Poisoned lock")
+                    .mmds_ns()
+                    .map_or(false, |mmds_ns| Arc::ptr_eq(&mmds_ns.mmds, mmds))
+            })
             .collect();
 
         if !net_devs_with_mmds.is_empty() {
+            let mmds_guard = mmds.lock().expect("Poisoned lock");
             let mut inner_mmds_config = MmdsConfig {
-                version: mmds.lock().expect("Poisoned lock").version(),
+                version: mmds_guard.version(),
                 network_interfaces: vec![],
                 ipv4_address: None,
+                data_store: name.map(str::to_string),
+                // The limit of the default data store follows the command line arguments.
+                data_store_limit: name
+                    .map(|_| mmds_guard.data_store_limit())
+                    .filter(|limit| *limit != MAX_DATA_STORE_SIZE),
+                token_binding: mmds_guard.token_binding(),
+                tcp_port: None,
+                max_connections: None,
+                max_pending_resets: None,
+                max_response_size: None,
             };
 
             for net_dev in net_devs_with_mmds {
                 let net = net_dev.lock().unwrap();
                 inner_mmds_config.network_interfaces.push(net.id().clone());
-                // Only need to get one ip address, as they will all be equal.
+                // Only need to get one ip address and network stack config, as they will all
+                // be equal.
                 if inner_mmds_config.ipv4_address.is_none() {
                     // Safe to unwrap the mmds_ns as the filter() explicitly checks for
                     // its existence.
-                    inner_mmds_config.ipv4_address = Some(net.mmds_ns().unwrap().ipv4_addr());
+                    let mmds_ns = net.mmds_ns().unwrap();
+                    inner_mmds_config.ipv4_address = Some(mmds_ns.ipv4_addr());
+
+                    // Only export the parameters which differ from the defaults.
+                    let default = MmdsNetworkStackConfig::default();
+                    let config = mmds_ns.config();
+                    inner_mmds_config.tcp_port =
+                        Some(config.tcp_port).filter(|port| *port != default.tcp_port);
+                    inner_mmds_config.max_connections = Some(config.max_connections)
+                        .filter(|max| *max != default.max_connections)
+                        .map(NonZeroUsize::get);
+                    inner_mmds_config.max_pending_resets = Some(config.max_pending_resets)
+                        .filter(|max| *max != default.max_pending_resets)
+                        .map(NonZeroUsize::get);
+                    inner_mmds_config.max_response_size =
+                        config.max_response_size.map(NonZeroUsize::get);
                 }
             }
 
@@ -362,6 +495,16 @@ impl VmResources {
         if config.amount_mib as usize > self.vm_config.mem_size_mib {
             return Err(BalloonConfigError::TooManyPagesRequested);
         }
+        // The balloon couldn't reclaim the guest memory pinned by the block devices.
+        if let Some(block) = self
+            .block
+            .list
+            .iter()
+            .map(|block| block.lock().expect("Poisoned lock"))
+            .find(|block| block.fixed_buffers())
+        {
+            return Err(BalloonConfigError::FixedBuffersDrive(block.id().clone()));
+        }
 
         self.balloon.set(config)
     }
@@ -382,7 +525,11 @@ impl VmResources {
         &mut self,
         block_device_config: BlockDeviceConfig,
     ) -> Result<DriveError> {
-        self.block.insert(block_device_config)
+        if block_device_config.fixed_buffers && self.balloon.get().is_some() {
+            return Err(DriveError::FixedBuffersWithBalloon);
+        }
+        self.block
+            .insert(block_device_config, &self.rate_limiter_groups)
     }
 
     /// Builds a network device to be attached when the VM starts.
@@ -390,10 +537,43 @@ impl VmResources {
         &mut self,
         body: NetworkInterfaceConfig,
     ) -> Result<NetworkInterfaceError> {
-        let _ = self.net_builder.build(body)?;
+        let _ = self.net_builder.build(body, &self.rate_limiter_groups)?;
         Ok(())
     }
 
+    /// Returns the live configuration and rate limiter state of a block device.
+    pub fn block_device_info(
+        &self,
+        drive_id: &str,
+    ) -> std::result::Result<BlockDeviceInfo, DriveError> {
+        self.block.info(drive_id)
+    }
+
+    /// Returns the live configuration and rate limiters state of a network interface.
+    pub fn net_device_info(
+        &self,
+        iface_id: &str,
+    ) -> std::result::Result<NetworkInterfaceInfo, NetworkInterfaceError> {
+        self.net_builder.info(iface_id)
+    }
+
+    /// Defines a rate limiter group, or redefines the limits of an existing one.
+    pub fn set_rate_limiter_group(
+        &mut self,
+        config: RateLimiterGroupConfig,
+    ) -> Result<RateLimiterGroupError> {
+        self.rate_limiter_groups.insert(config)
+    }
+
+    /// Updates the limits of an existing rate limiter group. The devices referencing the group
+    /// see the new limits right away.
+    pub fn update_rate_limiter_group(
+        &self,
+        config: RateLimiterGroupUpdateConfig,
+    ) -> Result<RateLimiterGroupError> {
+        self.rate_limiter_groups.update(config)
+    }
+
     /// Sets a vsock device to be attached when the VM starts.
     pub fn set_vsock_device(&mut self, config: VsockDeviceConfig) -> Result<VsockConfigError> {
         self.vsock.insert(config)
@@ -405,8 +585,29 @@ impl VmResources {
         config: MmdsConfig,
         instance_id: &str,
     ) -> Result<MmdsConfigError> {
+        if let Some(name) = config.data_store() {
+            validate_data_store_name(name)?;
+        }
         self.set_mmds_network_stack_config(&config)?;
-        self.set_mmds_version(config.version, instance_id)?;
+        match config.data_store() {
+            Some(name) => self.set_mmds_data_store_version(name, config.version, instance_id)?,
+            None => self.set_mmds_version(config.version, instance_id)?,
+        }
+        let mmds = self.data_store_or_default(config.data_store());
+        let mut mmds_guard = mmds.lock().expect("Poisoned lock");
+        if let Some(limit) = config.data_store_limit {
+            mmds_guard.set_data_store_limit(limit);
+        }
+        mmds_guard.set_token_binding(config.token_binding());
+
+        Ok(())
+    }
+
+    /// Invalidates the MMDS session tokens issued by all the data stores.
+    pub fn revoke_mmds_tokens(&self) -> std::result::Result<(), data_store::Error> {
+        for mmds in self.mmds.iter().chain(self.mmds_data_stores.values()) {
+            mmds.lock().expect("Poisoned lock").revoke_tokens()?;
+        }
 
         Ok(())
     }
@@ -418,10 +619,28 @@ impl VmResources {
         instance_id: &str,
     ) -> Result<MmdsConfigError> {
         let mut mmds_guard = self.locked_mmds_or_default();
-        mmds_guard
-            .set_version(version)
+        Self::set_data_store_version(&mut mmds_guard, version, instance_id)
+    }
+
+    /// Updates the version of a named MMDS data store.
+    pub fn set_mmds_data_store_version(
+        &mut self,
+        name: &str,
+        version: MmdsVersion,
+        instance_id: &str,
+    ) -> Result<MmdsConfigError> {
+        let mut mmds_guard = self.locked_mmds_data_store_or_default(name);
+        Self::set_data_store_version(&mut mmds_guard, version, instance_id)
+    }
+
+    fn set_data_store_version(
+        mmds: &mut Mmds,
+        version: MmdsVersion,
+        instance_id: &str,
+    ) -> Result<MmdsConfigError> {
+        mmds.set_version(version)
             .map_err(|e| MmdsConfigError::MmdsVersion(version, e))?;
-        mmds_guard.set_aad(instance_id);
+        mmds.set_aad(instance_id);
 
         Ok(())
     }
@@ -435,6 +654,7 @@ impl VmResources {
             None => Ok(MmdsNetworkStack::default_ipv4_addr()),
             _ => Err(MmdsConfigError::InvalidIpv4Addr),
         }?;
+        let network_stack_config = config.network_stack_config()?;
 
         let network_interfaces = config.network_interfaces();
         // Ensure that at least one network ID is specified.
@@ -452,17 +672,24 @@ impl VmResources {
             return Err(MmdsConfigError::InvalidNetworkInterfaceId);
         }
 
-        // Safe to unwrap because we've just made sure that it's initialised.
-        let mmds = self.mmds_or_default().clone();
+        let mmds = self.data_store_or_default(config.data_store());
 
         // Create `MmdsNetworkStack` and configure the IPv4 address for
         // existing built network devices whose names are defined in the
-        // network interface ID list.
+        // network interface ID list. Interfaces served by other data stores
+        // are left untouched.
         for net_device in self.net_builder.iter_mut() {
             let mut net_device_lock = net_device.lock().expect("Poisoned lock");
             if network_interfaces.contains(net_device_lock.id()) {
-                net_device_lock.configure_mmds_network_stack(ipv4_addr, mmds.clone());
-            } else {
+                net_device_lock.configure_mmds_network_stack(
+                    ipv4_addr,
+                    network_stack_config,
+                    mmds.clone(),
+                );
+            } else if net_device_lock
+                .mmds_ns()
+                .map_or(false, |mmds_ns| Arc::ptr_eq(&mmds_ns.mmds, &mmds))
+            {
                 net_device_lock.disable_mmds_network_stack();
             }
         }
@@ -487,7 +714,9 @@ impl From<&VmResources> for VmmConfig {
             machine_config: Some(resources.vm_config.clone()),
             metrics: None,
             mmds_config: resources.mmds_config(),
+            mmds_data_stores: resources.mmds_data_store_configs(),
             net_devices: resources.net_builder.configs(),
+            rate_limiter_groups: resources.rate_limiter_groups.configs(),
             vsock_device: resources.vsock.config(),
         }
     }
@@ -505,10 +734,11 @@ mod tests {
     use crate::vmm_config::machine_config::{CpuFeaturesTemplate, VmConfig, VmConfigError};
     use crate::vmm_config::net::{NetBuilder, NetworkInterfaceConfig};
     use crate::vmm_config::vsock::tests::default_config;
-    use crate::vmm_config::RateLimiterConfig;
+    use crate::vmm_config::{RateLimiterConfig, TokenBucketConfig};
     use crate::vstate::vcpu::VcpuConfig;
     use devices::virtio::vsock::{VsockError, VSOCK_DEV_ID};
     use logger::{LevelFilter, LOGGER};
+    use mmds::data_store::TokenBinding;
     use serde_json::{Map, Value};
     use utils::net::mac::MacAddr;
     use utils::tempfile::TempFile;
@@ -526,12 +756,18 @@ mod tests {
             guest_mac: Some(MacAddr::parse_str("01:23:45:67:89:0a").unwrap()),
             rx_rate_limiter: Some(RateLimiterConfig::default()),
             tx_rate_limiter: Some(RateLimiterConfig::default()),
+            rx_rate_limiter_group: None,
+            tx_rate_limiter_group: None,
+            egress_filter: None,
+            user_net: None,
         }
     }
 
     fn default_net_builder() -> NetBuilder {
         let mut net_builder = NetBuilder::new();
-        net_builder.build(default_net_cfg()).unwrap();
+        net_builder
+            .build(default_net_cfg(), &Default::default())
+            .unwrap();
 
         net_builder
     }
@@ -547,7 +783,13 @@ mod tests {
                 cache_type: CacheType::Unsafe,
                 is_read_only: false,
                 rate_limiter: Some(RateLimiterConfig::default()),
+                rate_limiter_group: None,
                 file_engine_type: FileEngineType::default(),
+                direct_io: false,
+                num_queues: 1,
+                io_threads: false,
+                fixed_buffers: false,
+                sq_poll: false,
             },
             tmp_file,
         )
@@ -556,7 +798,7 @@ mod tests {
     fn default_blocks() -> BlockBuilder {
         let mut blocks = BlockBuilder::new();
         let (cfg, _file) = default_block_cfg();
-        blocks.insert(cfg).unwrap();
+        blocks.insert(cfg, &Default::default()).unwrap();
         blocks
     }
 
@@ -575,12 +817,15 @@ mod tests {
     fn default_vm_resources() -> VmResources {
         VmResources {
             vm_config: VmConfig::default(),
+            custom_cpu_template: None,
             boot_config: Some(default_boot_cfg()),
             block: default_blocks(),
             vsock: Default::default(),
             balloon: Default::default(),
             net_builder: default_net_builder(),
             mmds: None,
+            mmds_data_stores: BTreeMap::new(),
+            rate_limiter_groups: RateLimiterGroupBuilder::default(),
             boot_timer: false,
         }
     }
@@ -901,12 +1146,19 @@ mod tests {
                         "kernel_image_path": "{}",
                         "boot_args": "console=ttyS0 reboot=k panic=1 pci=off"
                     }},
+                    "rate-limiters": [
+                        {{
+                            "group_id": "disks",
+                            "bandwidth": {{ "size": 1048576, "refill_time": 1000 }}
+                        }}
+                    ],
                     "drives": [
                         {{
                             "drive_id": "rootfs",
                             "path_on_host": "{}",
                             "is_root_device": true,
-                            "is_read_only": false
+                            "is_read_only": false,
+                            "rate_limiter_group": "disks"
                         }}
                     ],
                     "network-interfaces": [
@@ -1169,6 +1421,70 @@ mod tests {
             let vmm_config: VmmConfig = (&resources).into();
             assert_eq!(initial_vmm_config, vmm_config);
         }
+
+        // Interfaces served by named MMDS data stores.
+        {
+            let kernel_file = TempFile::new().unwrap();
+            let rootfs_file = TempFile::new().unwrap();
+            let json = format!(
+                r#"{{
+                    "balloon": {{
+                        "amount_mib": 0,
+                        "deflate_on_oom": false,
+                        "stats_polling_interval_s": 0
+                    }},
+                    "boot-source": {{
+                        "kernel_image_path": "{}",
+                        "boot_args": "console=ttyS0 reboot=k panic=1 pci=off"
+                    }},
+                    "drives": [
+                        {{
+                            "drive_id": "rootfs",
+                            "path_on_host": "{}",
+                            "is_root_device": true,
+                            "is_read_only": false
+                        }}
+                    ],
+                    "network-interfaces": [
+                        {{
+                            "iface_id": "netif1",
+                            "host_dev_name": "hostname9"
+                        }},
+                        {{
+                            "iface_id": "netif2",
+                            "host_dev_name": "hostname10"
+                        }}
+                    ],
+                    "machine-config": {{
+                        "vcpu_count": 2,
+                        "mem_size_mib": 1024,
+                        "smt": false
+                    }},
+                    "mmds-config": {{
+                        "network_interfaces": ["netif1"],
+                        "ipv4_address": "169.254.1.1"
+                    }},
+                    "mmds-data-stores": [
+                        {{
+                            "version": "V2",
+                            "network_interfaces": ["netif2"],
+                            "ipv4_address": "169.254.1.1",
+                            "data_store": "sidecar",
+                            "data_store_limit": 4096
+                        }}
+                    ]
+            }}"#,
+                kernel_file.as_path().to_str().unwrap(),
+                rootfs_file.as_path().to_str().unwrap(),
+            );
+            let resources =
+                VmResources::from_json(json.as_str(), &InstanceInfo::default(), None, None)
+                    .unwrap();
+
+            let initial_vmm_config = serde_json::from_slice::<VmmConfig>(json.as_bytes()).unwrap();
+            let vmm_config: VmmConfig = (&resources).into();
+            assert_eq!(initial_vmm_config, vmm_config);
+        }
     }
 
     #[test]
@@ -1178,6 +1494,7 @@ mod tests {
             vcpu_count: vm_resources.vm_config().vcpu_count,
             smt: vm_resources.vm_config().smt,
             cpu_template: vm_resources.vm_config().cpu_template,
+            custom_cpu_template: None,
         };
 
         let vcpu_config = vm_resources.vcpu_config();
@@ -1200,6 +1517,7 @@ mod tests {
             mem_size_mib: Some(512),
             smt: Some(true),
             cpu_template: Some(CpuFeaturesTemplate::T2),
+            cpu_template_path: None,
             track_dirty_pages: Some(false),
         };
 
@@ -1253,16 +1571,62 @@ mod tests {
         assert!(vm_resources.update_vm_config(&aux_vm_config).is_ok());
     }
 
+    #[test]
+    fn test_update_vm_config_cpu_template() {
+        let mut vm_resources = default_vm_resources();
+        let template_file = TempFile::new().unwrap();
+        let template_path = template_file.as_path().to_str().unwrap().to_string();
+        let mut aux_vm_config = VmUpdateConfig {
+            vcpu_count: Some(2),
+            mem_size_mib: None,
+            smt: None,
+            cpu_template: None,
+            cpu_template_path: Some(template_path.clone()),
+            track_dirty_pages: None,
+        };
+
+        // An invalid template leaves the configuration untouched.
+        std::fs::write(template_file.as_path(), r#"{"msrs": []}"#).unwrap();
+        assert!(matches!(
+            vm_resources.update_vm_config(&aux_vm_config),
+            Err(VmConfigError::InvalidCpuTemplate(_))
+        ));
+        assert_eq!(vm_resources.vm_config(), &VmConfig::default());
+        assert!(vm_resources.vcpu_config().custom_cpu_template.is_none());
+
+        std::fs::write(
+            template_file.as_path(),
+            r#"{"msr_modifiers": [{"addr": "0x10a", "bitmap": "0b1"}]}"#,
+        )
+        .unwrap();
+        vm_resources.update_vm_config(&aux_vm_config).unwrap();
+        assert_eq!(vm_resources.vm_config().vcpu_count, 2);
+        assert_eq!(
+            vm_resources.vm_config().cpu_template_path,
+            Some(template_path)
+        );
+        let template = vm_resources.vcpu_config().custom_cpu_template.unwrap();
+        assert_eq!(template.msr_modifiers[0].addr, 0x10a);
+
+        // Updates which don't reference a template keep the current one.
+        aux_vm_config.cpu_template_path = None;
+        vm_resources.update_vm_config(&aux_vm_config).unwrap();
+        assert!(vm_resources.vcpu_config().custom_cpu_template.is_some());
+    }
+
     #[test]
     fn test_set_balloon_device() {
         let mut vm_resources = VmResources {
             vm_config: VmConfig::default(),
+            custom_cpu_template: None,
             boot_config: Some(default_boot_cfg()),
             block: default_blocks(),
             vsock: Default::default(),
             balloon: BalloonBuilder::new(),
             net_builder: default_net_builder(),
             mmds: None,
+            mmds_data_stores: BTreeMap::new(),
+            rate_limiter_groups: RateLimiterGroupBuilder::default(),
             boot_timer: false,
         };
         let mut new_balloon_cfg = BalloonDeviceConfig {
@@ -1288,12 +1652,15 @@ mod tests {
 
         vm_resources = VmResources {
             vm_config: VmConfig::default(),
+            custom_cpu_template: None,
             boot_config: Some(default_boot_cfg()),
             block: default_blocks(),
             vsock: Default::default(),
             balloon: BalloonBuilder::new(),
             net_builder: default_net_builder(),
             mmds: None,
+            mmds_data_stores: BTreeMap::new(),
+            rate_limiter_groups: RateLimiterGroupBuilder::default(),
             boot_timer: false,
         };
         new_balloon_cfg.amount_mib = 256;
@@ -1364,6 +1731,42 @@ mod tests {
         assert_eq!(vm_resources.block.list.len(), 2);
     }
 
+    #[test]
+    fn test_fixed_buffers_with_balloon() {
+        let balloon_cfg = BalloonDeviceConfig {
+            amount_mib: 100,
+            deflate_on_oom: false,
+            stats_polling_interval_s: 0,
+        };
+        let (mut block_cfg, _file) = default_block_cfg();
+        block_cfg.drive_id = "block2".to_string();
+        block_cfg.rate_limiter = None;
+        block_cfg.file_engine_type = FileEngineType::Async;
+        block_cfg.fixed_buffers = true;
+
+        // Drives with fixed buffers can't be added along with a balloon.
+        let mut vm_resources = default_vm_resources();
+        vm_resources
+            .set_balloon_device(balloon_cfg.clone())
+            .unwrap();
+        assert!(matches!(
+            vm_resources.set_block_device(block_cfg.clone()),
+            Err(DriveError::FixedBuffersWithBalloon)
+        ));
+
+        // Nor can a balloon be added along with drives with fixed buffers.
+        if !FileEngineType::Async.is_supported().unwrap() {
+            return;
+        }
+        let mut vm_resources = default_vm_resources();
+        vm_resources.set_block_device(block_cfg).unwrap();
+        assert!(matches!(
+            vm_resources.set_balloon_device(balloon_cfg),
+            Err(BalloonConfigError::FixedBuffersDrive(drive_id)) if drive_id == "block2"
+        ));
+        assert!(vm_resources.balloon.get().is_none());
+    }
+
     #[test]
     fn test_set_vsock_device() {
         let mut vm_resources = default_vm_resources();
@@ -1391,6 +1794,260 @@ mod tests {
         assert_eq!(vm_resources.net_builder.len(), 2);
     }
 
+    #[test]
+    fn test_set_rate_limiter_group() {
+        let mut vm_resources = default_vm_resources();
+        let (mut block_cfg, _file) = default_block_cfg();
+        block_cfg.rate_limiter_group = Some("disks".to_string());
+        assert!(matches!(
+            vm_resources.set_block_device(block_cfg).unwrap_err(),
+            DriveError::RateLimiterGroup(RateLimiterGroupError::GroupNotFound(_))
+        ));
+
+        vm_resources
+            .set_rate_limiter_group(RateLimiterGroupConfig {
+                group_id: "disks".to_string(),
+                bandwidth: None,
+                ops: None,
+            })
+            .unwrap();
+        let (mut block_cfg, _file) = default_block_cfg();
+        block_cfg.rate_limiter_group = Some("disks".to_string());
+        vm_resources.set_block_device(block_cfg).unwrap();
+        let mut net_cfg = default_net_cfg();
+        net_cfg.tx_rate_limiter_group = Some("disks".to_string());
+        vm_resources.build_net_device(net_cfg).unwrap();
+
+        vm_resources
+            .update_rate_limiter_group(RateLimiterGroupUpdateConfig {
+                group_id: "disks".to_string(),
+                bandwidth: None,
+                ops: Some(TokenBucketConfig {
+                    size: 10,
+                    one_time_burst: None,
+                    refill_time: 100,
+                }),
+            })
+            .unwrap();
+        // The devices share the updated buckets of the group.
+        let block = vm_resources.block.list[0].lock().unwrap();
+        let group = block.rate_limiter().group().unwrap();
+        assert_eq!(group.ops().unwrap().capacity(), 10);
+        let net = vm_resources.net_builder.iter().next().unwrap();
+        assert_eq!(net.lock().unwrap().tx_rate_limiter().group(), Some(group));
+
+        let vmm_config = VmmConfig::from(&vm_resources);
+        assert_eq!(vmm_config.rate_limiter_groups.len(), 1);
+        assert_eq!(
+            vmm_config.block_devices[0].rate_limiter_group.as_deref(),
+            Some("disks")
+        );
+    }
+
+    #[test]
+    fn test_set_mmds_data_store() {
+        let mut vm_resources = default_vm_resources();
+        let mut sidecar_net_cfg = default_net_cfg();
+        sidecar_net_cfg.iface_id = "sidecar_if".to_string();
+        sidecar_net_cfg.guest_mac = Some(MacAddr::parse_str("01:23:45:67:89:0d").unwrap());
+        sidecar_net_cfg.host_dev_name = "dummy_path3".to_string();
+        vm_resources.build_net_device(sidecar_net_cfg).unwrap();
+
+        let mut mmds_config = MmdsConfig {
+            version: MmdsVersion::V1,
+            network_interfaces: vec!["net_if1".to_string()],
+            ipv4_address: None,
+            data_store: None,
+            data_store_limit: None,
+            token_binding: TokenBinding::None,
+            tcp_port: None,
+            max_connections: None,
+            max_pending_resets: None,
+            max_response_size: None,
+        };
+        vm_resources
+            .set_mmds_config(mmds_config.clone(), "instance")
+            .unwrap();
+
+        mmds_config.version = MmdsVersion::V2;
+        mmds_config.network_interfaces = vec!["sidecar_if".to_string()];
+        mmds_config.data_store = Some("sidecar".to_string());
+        mmds_config.data_store_limit = Some(16);
+        vm_resources
+            .set_mmds_config(mmds_config.clone(), "instance")
+            .unwrap();
+
+        // Each interface is served by its own data store.
+        let default_mmds = vm_resources.mmds.clone().unwrap();
+        let sidecar_mmds = vm_resources.mmds_data_store_or_default("sidecar").clone();
+        for net in vm_resources.net_builder.iter() {
+            let net = net.lock().unwrap();
+            let expected = if net.id() == "net_if1" {
+                &default_mmds
+            } else {
+                &sidecar_mmds
+            };
+            assert!(Arc::ptr_eq(&net.mmds_ns().unwrap().mmds, expected));
+        }
+        assert_eq!(default_mmds.lock().unwrap().version(), MmdsVersion::V1);
+        assert_eq!(sidecar_mmds.lock().unwrap().version(), MmdsVersion::V2);
+        assert_eq!(sidecar_mmds.lock().unwrap().name(), Some("sidecar"));
+
+        // The data store limit only applies to the named data store.
+        let data: serde_json::Value = serde_json::from_str(r#"{"key": "long_value"}"#).unwrap();
+        assert!(vm_resources
+            .locked_mmds_data_store("sidecar")
+            .unwrap()
+            .put_data(data.clone())
+            .is_err());
+        vm_resources
+            .locked_mmds_or_default()
+            .put_data(data)
+            .unwrap();
+
+        // Only the interfaces served by the default data store are part of the exported config.
+        let exported_config = vm_resources.mmds_config().unwrap();
+        assert_eq!(exported_config.network_interfaces, vec!["net_if1"]);
+
+        // The named data stores are exported separately, along with their settings.
+        let vmm_config = VmmConfig::from(&vm_resources);
+        let mut expected_config = mmds_config.clone();
+        expected_config.ipv4_address = Some(MmdsNetworkStack::default_ipv4_addr());
+        assert_eq!(vmm_config.mmds_data_stores, vec![expected_config]);
+
+        mmds_config.data_store = Some("config".to_string());
+        assert!(matches!(
+            vm_resources.set_mmds_config(mmds_config.clone(), "instance"),
+            Err(MmdsConfigError::InvalidDataStoreName(_))
+        ));
+        mmds_config.data_store = Some("tokens".to_string());
+        assert!(matches!(
+            vm_resources.set_mmds_config(mmds_config, "instance"),
+            Err(MmdsConfigError::InvalidDataStoreName(_))
+        ));
+    }
+
+    #[test]
+    fn test_mmds_token_binding() {
+        let mut vm_resources = default_vm_resources();
+        // Revoking tokens when MMDS is not configured is a no-op.
+        vm_resources.revoke_mmds_tokens().unwrap();
+
+        let mmds_config = MmdsConfig {
+            version: MmdsVersion::V2,
+            network_interfaces: vec!["net_if1".to_string()],
+            ipv4_address: None,
+            data_store: None,
+            data_store_limit: None,
+            token_binding: TokenBinding::SourceIp,
+            tcp_port: None,
+            max_connections: None,
+            max_pending_resets: None,
+            max_response_size: None,
+        };
+        vm_resources
+            .set_mmds_config(mmds_config, "instance")
+            .unwrap();
+        assert_eq!(
+            vm_resources.locked_mmds_or_default().token_binding(),
+            TokenBinding::SourceIp
+        );
+        assert_eq!(
+            vm_resources.mmds_config().unwrap().token_binding,
+            TokenBinding::SourceIp
+        );
+
+        vm_resources.revoke_mmds_tokens().unwrap();
+    }
+
+    #[test]
+    fn test_mmds_network_stack_config() {
+        let mut vm_resources = default_vm_resources();
+        let mut mmds_config = MmdsConfig {
+            version: MmdsVersion::V1,
+            network_interfaces: vec!["net_if1".to_string()],
+            ipv4_address: None,
+            data_store: None,
+            data_store_limit: None,
+            token_binding: TokenBinding::None,
+            tcp_port: Some(0),
+            max_connections: None,
+            max_pending_resets: None,
+            max_response_size: None,
+        };
+
+        // Zero values are rejected.
+        assert_eq!(
+            vm_resources
+                .set_mmds_config(mmds_config.clone(), "instance")
+                .unwrap_err()
+                .to_string(),
+            MmdsConfigError::InvalidNetworkStackParam("tcp_port").to_string()
+        );
+        mmds_config.tcp_port = None;
+        mmds_config.max_response_size = Some(0);
+        assert_eq!(
+            vm_resources
+                .set_mmds_config(mmds_config.clone(), "instance")
+                .unwrap_err()
+                .to_string(),
+            MmdsConfigError::InvalidNetworkStackParam("max_response_size").to_string()
+        );
+        mmds_config.max_response_size = None;
+
+        // So are the values too large to be allocated for.
+        mmds_config.max_connections = Some(usize::MAX);
+        assert_eq!(
+            vm_resources
+                .set_mmds_config(mmds_config.clone(), "instance")
+                .unwrap_err()
+                .to_string(),
+            MmdsConfigError::InvalidNetworkStackParam("max_connections").to_string()
+        );
+        mmds_config.max_connections = None;
+        mmds_config.max_pending_resets = Some(mmds::ns::MAX_PENDING_RESETS_LIMIT + 1);
+        assert_eq!(
+            vm_resources
+                .set_mmds_config(mmds_config.clone(), "instance")
+                .unwrap_err()
+                .to_string(),
+            MmdsConfigError::InvalidNetworkStackParam("max_pending_resets").to_string()
+        );
+        mmds_config.max_pending_resets = None;
+
+        // The defaults are not exported.
+        vm_resources
+            .set_mmds_config(mmds_config.clone(), "instance")
+            .unwrap();
+        let exported = vm_resources.mmds_config().unwrap();
+        assert_eq!(exported.tcp_port, None);
+        assert_eq!(exported.max_connections, None);
+        assert_eq!(exported.max_pending_resets, None);
+        assert_eq!(exported.max_response_size, None);
+
+        // Custom values are applied to the network stack, and exported.
+        mmds_config.tcp_port = Some(8080);
+        mmds_config.max_connections = Some(64);
+        mmds_config.max_pending_resets = Some(128);
+        mmds_config.max_response_size = Some(1 << 20);
+        vm_resources
+            .set_mmds_config(mmds_config, "instance")
+            .unwrap();
+        {
+            let net = vm_resources.net_builder.iter().next().unwrap();
+            let config = net.lock().unwrap().mmds_ns().unwrap().config();
+            assert_eq!(config.tcp_port, 8080);
+            assert_eq!(config.max_connections.get(), 64);
+            assert_eq!(config.max_pending_resets.get(), 128);
+            assert_eq!(config.max_response_size, NonZeroUsize::new(1 << 20));
+        }
+        let exported = vm_resources.mmds_config().unwrap();
+        assert_eq!(exported.tcp_port, Some(8080));
+        assert_eq!(exported.max_connections, Some(64));
+        assert_eq!(exported.max_pending_resets, Some(128));
+        assert_eq!(exported.max_response_size, Some(1 << 20));
+    }
+
     #[test]
     fn test_error_display() {
         assert_eq!(
@@ -9,10 +9,10 @@ use std::sync::{Arc, Mutex, MutexGuard};
 use super::Error as VmmError;
 #[cfg(not(test))]
 use super::{
-    builder::build_microvm_for_boot, persist::create_snapshot, persist::restore_from_snapshot,
-    resources::VmResources, Vmm,
+    builder::build_microvm_for_boot, persist::check_snapshot_compatibility,
+    persist::create_snapshot, persist::restore_from_snapshot, resources::VmResources, Vmm,
 };
-use crate::persist::{CreateSnapshotError, LoadSnapshotError};
+use crate::persist::{CpuCompatibilityReport, CreateSnapshotError, LoadSnapshotError};
 use crate::resources::VmmConfig;
 use crate::version_map::VERSION_MAP;
 use crate::vmm_config::balloon::{
@@ -20,18 +20,24 @@ use crate::vmm_config::balloon::{
     BalloonUpdateStatsConfig,
 };
 use crate::vmm_config::boot_source::{BootSourceConfig, BootSourceConfigError};
-use crate::vmm_config::drive::{BlockDeviceConfig, BlockDeviceUpdateConfig, DriveError};
+use crate::vmm_config::drive::{
+    BlockDeviceConfig, BlockDeviceInfo, BlockDeviceUpdateConfig, DriveError,
+};
 use crate::vmm_config::instance_info::InstanceInfo;
 use crate::vmm_config::logger::{LoggerConfig, LoggerConfigError};
 use crate::vmm_config::machine_config::{VmConfig, VmConfigError, VmUpdateConfig};
 use crate::vmm_config::metrics::{MetricsConfig, MetricsConfigError};
-use crate::vmm_config::mmds::{MmdsConfig, MmdsConfigError};
+use crate::vmm_config::mmds::{validate_data_store_name, MmdsConfig, MmdsConfigError};
 use crate::vmm_config::net::{
-    NetworkInterfaceConfig, NetworkInterfaceError, NetworkInterfaceUpdateConfig,
+    NetworkInterfaceConfig, NetworkInterfaceError, NetworkInterfaceInfo,
+    NetworkInterfaceUpdateConfig,
+};
+use crate::vmm_config::rate_limiter_group::{
+    RateLimiterGroupConfig, RateLimiterGroupError, RateLimiterGroupUpdateConfig,
 };
 use crate::vmm_config::snapshot::{CreateSnapshotParams, LoadSnapshotParams, SnapshotType};
-use crate::vmm_config::vsock::{VsockConfigError, VsockDeviceConfig};
-use crate::vmm_config::{self, RateLimiterUpdate};
+use crate::vmm_config::vsock::{VsockConfigError, VsockDeviceConfig, VsockDeviceUpdateConfig};
+use crate::vmm_config::{self, RateLimiterConfig, RateLimiterUpdate};
 use crate::{builder::StartMicrovmError, warn, EventManager};
 use crate::{ExitCode, FC_EXIT_CODE_BAD_CONFIGURATION};
 use logger::{info, update_metric_with_elapsed_time, METRICS};
@@ -39,8 +45,8 @@ use mmds::data_store::{self, Mmds};
 use seccompiler::BpfThreadMap;
 #[cfg(test)]
 use tests::{
-    build_microvm_for_boot, create_snapshot, restore_from_snapshot, MockVmRes as VmResources,
-    MockVmm as Vmm,
+    build_microvm_for_boot, check_snapshot_compatibility, create_snapshot, restore_from_snapshot,
+    MockVmRes as VmResources, MockVmm as Vmm,
 };
 
 /// This enum represents the public interface of the VMM. Each action contains various
@@ -63,10 +69,17 @@ pub enum VmmAction {
     GetBalloonConfig,
     /// Get the ballon device latest statistics.
     GetBalloonStats,
+    /// Get the live configuration and rate limiter state of the block device with the given id.
+    GetBlockDevice(String),
     /// Get complete microVM configuration in JSON format.
     GetFullVmConfig,
     /// Get MMDS contents.
     GetMMDS,
+    /// Get the contents of the named MMDS data store.
+    GetMMDSDataStore(String),
+    /// Get the live configuration and rate limiters state of the network interface with the
+    /// given id.
+    GetNetworkInterface(String),
     /// Get the machine configuration of the microVM.
     GetVmMachineConfig,
     /// Get microVM instance information.
@@ -88,18 +101,28 @@ pub enum VmmAction {
     LoadSnapshot(LoadSnapshotParams),
     /// Partial update of the MMDS contents.
     PatchMMDS(Value),
+    /// Partial update of the contents of the named MMDS data store.
+    PatchMMDSDataStore(String, Value),
     /// Pause the guest, by pausing the microVM VCPUs.
     Pause,
     /// Repopulate the MMDS contents.
     PutMMDS(Value),
+    /// Repopulate the contents of the named MMDS data store, creating it if it doesn't exist.
+    PutMMDSDataStore(String, Value),
     /// Resume the guest, by resuming the microVM VCPUs.
     Resume,
+    /// Invalidate all the MMDS session tokens issued so far.
+    RevokeMMDSTokens,
     /// Set the balloon device or update the one that already exists using the
     /// `BalloonDeviceConfig` as input. This action can only be called before the microVM
     /// has booted.
     SetBalloonDevice(BalloonDeviceConfig),
     /// Set the MMDS configuration.
     SetMmdsConfiguration(MmdsConfig),
+    /// Define a rate limiter group or redefine the one that already exists using the
+    /// `RateLimiterGroupConfig` as input. This action can only be called before the microVM has
+    /// booted.
+    SetRateLimiterGroup(RateLimiterGroupConfig),
     /// Set the vsock device or update the one that already exists using the
     /// `VsockDeviceConfig` as input. This action can only be called before the microVM has
     /// booted.
@@ -119,9 +142,14 @@ pub enum VmmAction {
     /// Update a network interface, after microVM start. Currently, the only updatable properties
     /// are the RX and TX rate limiters.
     UpdateNetworkInterface(NetworkInterfaceUpdateConfig),
+    /// Update the token buckets of a rate limiter group, after microVM start.
+    UpdateRateLimiterGroup(RateLimiterGroupUpdateConfig),
     /// Update the microVM configuration (memory & vcpu) using `VmUpdateConfig` as input. This
     /// action can only be called before the microVM has booted.
     UpdateVmConfiguration(VmUpdateConfig),
+    /// Update the vsock device, after microVM start. Currently, the only updatable properties
+    /// are the RX and TX rate limiters.
+    UpdateVsockDevice(VsockDeviceUpdateConfig),
 }
 
 /// Wrapper for all errors associated with VMM actions.
@@ -162,6 +190,9 @@ pub enum VmmActionError {
     OperationNotSupportedPostBoot,
     /// The requested operation is not supported before starting the microVM.
     OperationNotSupportedPreBoot,
+    /// One of the actions `SetRateLimiterGroup` or `UpdateRateLimiterGroup` failed because of
+    /// bad user input.
+    RateLimiterGroup(RateLimiterGroupError),
     /// The action `StartMicroVm` failed because of an internal error.
     StartMicrovm(StartMicrovmError),
     /// The action `SetVsockDevice` failed because of bad user input.
@@ -202,6 +233,7 @@ impl Display for VmmActionError {
                     "The requested operation is not supported before starting the microVM."
                         .to_string()
                 }
+                RateLimiterGroup(err) => err.to_string(),
                 StartMicrovm(err) => err.to_string(),
                 // The action `SetVsockDevice` failed because of bad user input.
                 VsockConfig(err) => err.to_string(),
@@ -218,6 +250,8 @@ pub enum VmmData {
     BalloonConfig(BalloonDeviceConfig),
     /// The latest balloon device statistics.
     BalloonStats(BalloonStats),
+    /// The live configuration and rate limiter state of a block device.
+    BlockDevice(BlockDeviceInfo),
     /// No data is sent on the channel.
     Empty,
     /// The complete microVM configuration in JSON format.
@@ -226,10 +260,14 @@ pub enum VmmData {
     MachineConfiguration(VmConfig),
     /// Mmds contents.
     MmdsValue(serde_json::Value),
+    /// The live configuration and rate limiters state of a network interface.
+    NetworkInterface(NetworkInterfaceInfo),
     /// The microVM instance information.
     InstanceInformation(InstanceInfo),
     /// The microVM version.
     VmmVersion(String),
+    /// The result of checking a snapshot against the host CPU.
+    SnapshotCompatibility(CpuCompatibilityReport),
 }
 
 /// Shorthand result type for external VMM commands.
@@ -240,6 +278,9 @@ pub type ActionResult = result::Result<VmmData, VmmActionError>;
 /// store with the defaults if it's not already initialised.
 trait MmdsRequestHandler {
     fn mmds(&mut self) -> MutexGuard<'_, Mmds>;
+    fn mmds_data_store(&mut self, name: &str) -> Option<MutexGuard<'_, Mmds>>;
+    fn mmds_data_store_or_default(&mut self, name: &str) -> MutexGuard<'_, Mmds>;
+    fn revoke_mmds_tokens(&mut self) -> ActionResult;
 
     fn get_mmds(&mut self) -> ActionResult {
         Ok(VmmData::MmdsValue(self.mmds().data_store_value()))
@@ -249,17 +290,45 @@ trait MmdsRequestHandler {
         self.mmds()
             .patch_data(value)
             .map(|()| VmmData::Empty)
-            .map_err(|e| match e {
-                data_store::Error::DataStoreLimitExceeded => {
-                    VmmActionError::MmdsLimitExceeded(data_store::Error::DataStoreLimitExceeded)
-                }
-                _ => VmmActionError::Mmds(e),
-            })
+            .map_err(mmds_update_error)
     }
 
     fn put_mmds(&mut self, value: serde_json::Value) -> ActionResult {
-        self.mmds().put_data(value);
-        Ok(VmmData::Empty)
+        self.mmds()
+            .put_data(value)
+            .map(|()| VmmData::Empty)
+            .map_err(mmds_update_error)
+    }
+
+    fn get_mmds_data_store(&mut self, name: &str) -> ActionResult {
+        self.mmds_data_store(name)
+            .map(|mmds| VmmData::MmdsValue(mmds.data_store_value()))
+            .ok_or(VmmActionError::Mmds(data_store::Error::NotFound))
+    }
+
+    fn patch_mmds_data_store(&mut self, name: &str, value: serde_json::Value) -> ActionResult {
+        self.mmds_data_store(name)
+            .ok_or(VmmActionError::Mmds(data_store::Error::NotFound))?
+            .patch_data(value)
+            .map(|()| VmmData::Empty)
+            .map_err(mmds_update_error)
+    }
+
+    fn put_mmds_data_store(&mut self, name: &str, value: serde_json::Value) -> ActionResult {
+        validate_data_store_name(name).map_err(VmmActionError::MmdsConfig)?;
+        self.mmds_data_store_or_default(name)
+            .put_data(value)
+            .map(|()| VmmData::Empty)
+            .map_err(mmds_update_error)
+    }
+}
+
+fn mmds_update_error(err: data_store::Error) -> VmmActionError {
+    match err {
+        data_store::Error::DataStoreLimitExceeded => {
+            VmmActionError::MmdsLimitExceeded(data_store::Error::DataStoreLimitExceeded)
+        }
+        _ => VmmActionError::Mmds(err),
     }
 }
 
@@ -282,6 +351,21 @@ impl MmdsRequestHandler for PrebootApiController<'_> {
     fn mmds(&mut self) -> MutexGuard<'_, Mmds> {
         self.vm_resources.locked_mmds_or_default()
     }
+
+    fn mmds_data_store(&mut self, name: &str) -> Option<MutexGuard<'_, Mmds>> {
+        self.vm_resources.locked_mmds_data_store(name)
+    }
+
+    fn mmds_data_store_or_default(&mut self, name: &str) -> MutexGuard<'_, Mmds> {
+        self.vm_resources.locked_mmds_data_store_or_default(name)
+    }
+
+    fn revoke_mmds_tokens(&mut self) -> ActionResult {
+        self.vm_resources
+            .revoke_mmds_tokens()
+            .map(|()| VmmData::Empty)
+            .map_err(VmmActionError::Mmds)
+    }
 }
 
 impl<'a> PrebootApiController<'a> {
@@ -340,9 +424,13 @@ impl<'a> PrebootApiController<'a> {
 
         // Init the data store from file, if present.
         if let Some(data) = metadata_json {
-            vm_resources.locked_mmds_or_default().put_data(
-                serde_json::from_str(&data).expect("MMDS error: metadata provided not valid json"),
-            );
+            vm_resources
+                .locked_mmds_or_default()
+                .put_data(
+                    serde_json::from_str(&data)
+                        .expect("MMDS error: metadata provided not valid json"),
+                )
+                .expect("MMDS error: metadata provided exceeds the data store limit");
             info!("Successfully added metadata to mmds from file");
         }
 
@@ -386,11 +474,14 @@ impl<'a> PrebootApiController<'a> {
                 .map(|()| VmmData::Empty)
                 .map_err(VmmActionError::Metrics),
             GetBalloonConfig => self.balloon_config(),
+            GetBlockDevice(drive_id) => self.block_device_info(&drive_id),
             GetFullVmConfig => {
                 warn!("If the VM was restored from snapshot, boot-source, machine-config.smt, and machine-config.cpu_template will all be empty.");
                 Ok(VmmData::FullVmConfig((&*self.vm_resources).into()))
             }
             GetMMDS => self.get_mmds(),
+            GetMMDSDataStore(name) => self.get_mmds_data_store(&name),
+            GetNetworkInterface(iface_id) => self.net_device_info(&iface_id),
             GetVmMachineConfig => Ok(VmmData::MachineConfiguration(
                 self.vm_resources.vm_config().clone(),
             )),
@@ -400,10 +491,14 @@ impl<'a> PrebootApiController<'a> {
             InsertNetworkDevice(config) => self.insert_net_device(config),
             LoadSnapshot(config) => self.load_snapshot(&config),
             PatchMMDS(value) => self.patch_mmds(value),
+            PatchMMDSDataStore(name, value) => self.patch_mmds_data_store(&name, value),
             PutMMDS(value) => self.put_mmds(value),
+            PutMMDSDataStore(name, value) => self.put_mmds_data_store(&name, value),
+            RevokeMMDSTokens => self.revoke_mmds_tokens(),
             SetBalloonDevice(config) => self.set_balloon_device(config),
             SetVsockDevice(config) => self.set_vsock_device(config),
             SetMmdsConfiguration(config) => self.set_mmds_config(config),
+            SetRateLimiterGroup(config) => self.set_rate_limiter_group(config),
             StartMicroVm => self.start_microvm(),
             UpdateVmConfiguration(config) => self.update_vm_config(config),
             // Operations not allowed pre-boot.
@@ -415,7 +510,9 @@ impl<'a> PrebootApiController<'a> {
             | UpdateBalloon(_)
             | UpdateBalloonStatistics(_)
             | UpdateBlockDevice(_)
-            | UpdateNetworkInterface(_) => Err(VmmActionError::OperationNotSupportedPreBoot),
+            | UpdateNetworkInterface(_)
+            | UpdateRateLimiterGroup(_)
+            | UpdateVsockDevice(_) => Err(VmmActionError::OperationNotSupportedPreBoot),
             #[cfg(target_arch = "x86_64")]
             SendCtrlAltDel => Err(VmmActionError::OperationNotSupportedPreBoot),
         }
@@ -429,6 +526,20 @@ impl<'a> PrebootApiController<'a> {
             .map_err(VmmActionError::BalloonConfig)
     }
 
+    fn block_device_info(&self, drive_id: &str) -> ActionResult {
+        self.vm_resources
+            .block_device_info(drive_id)
+            .map(VmmData::BlockDevice)
+            .map_err(VmmActionError::DriveConfig)
+    }
+
+    fn net_device_info(&self, iface_id: &str) -> ActionResult {
+        self.vm_resources
+            .net_device_info(iface_id)
+            .map(VmmData::NetworkInterface)
+            .map_err(VmmActionError::NetworkConfig)
+    }
+
     fn insert_block_device(&mut self, cfg: BlockDeviceConfig) -> ActionResult {
         self.boot_path = true;
         self.vm_resources
@@ -445,6 +556,14 @@ impl<'a> PrebootApiController<'a> {
             .map_err(VmmActionError::NetworkConfig)
     }
 
+    fn set_rate_limiter_group(&mut self, cfg: RateLimiterGroupConfig) -> ActionResult {
+        self.boot_path = true;
+        self.vm_resources
+            .set_rate_limiter_group(cfg)
+            .map(|()| VmmData::Empty)
+            .map_err(VmmActionError::RateLimiterGroup)
+    }
+
     fn set_balloon_device(&mut self, cfg: BalloonDeviceConfig) -> ActionResult {
         self.boot_path = true;
         self.vm_resources
@@ -512,6 +631,13 @@ impl<'a> PrebootApiController<'a> {
             return Err(err);
         }
 
+        if load_params.dry_run {
+            // Nothing is restored, so the process can keep serving requests.
+            return check_snapshot_compatibility(&load_params.snapshot_path, VERSION_MAP.clone())
+                .map(VmmData::SnapshotCompatibility)
+                .map_err(VmmActionError::LoadSnapshot);
+        }
+
         if load_params.enable_diff_snapshots {
             self.vm_resources.set_track_dirty_pages(true);
         }
@@ -561,6 +687,21 @@ impl MmdsRequestHandler for RuntimeApiController {
     fn mmds(&mut self) -> MutexGuard<'_, Mmds> {
         self.vm_resources.locked_mmds_or_default()
     }
+
+    fn mmds_data_store(&mut self, name: &str) -> Option<MutexGuard<'_, Mmds>> {
+        self.vm_resources.locked_mmds_data_store(name)
+    }
+
+    fn mmds_data_store_or_default(&mut self, name: &str) -> MutexGuard<'_, Mmds> {
+        self.vm_resources.locked_mmds_data_store_or_default(name)
+    }
+
+    fn revoke_mmds_tokens(&mut self) -> ActionResult {
+        self.vm_resources
+            .revoke_mmds_tokens()
+            .map(|()| VmmData::Empty)
+            .map_err(VmmActionError::Mmds)
+    }
 }
 
 impl RuntimeApiController {
@@ -585,8 +726,11 @@ impl RuntimeApiController {
                 .latest_balloon_stats()
                 .map(VmmData::BalloonStats)
                 .map_err(|e| VmmActionError::BalloonConfig(BalloonConfigError::from(e))),
+            GetBlockDevice(drive_id) => self.block_device_info(&drive_id),
             GetFullVmConfig => Ok(VmmData::FullVmConfig((&self.vm_resources).into())),
             GetMMDS => self.get_mmds(),
+            GetMMDSDataStore(name) => self.get_mmds_data_store(&name),
+            GetNetworkInterface(iface_id) => self.net_device_info(&iface_id),
             GetVmMachineConfig => Ok(VmmData::MachineConfiguration(
                 self.vm_resources.vm_config().clone(),
             )),
@@ -597,8 +741,11 @@ impl RuntimeApiController {
                 self.vmm.lock().expect("Poisoned lock").version(),
             )),
             PatchMMDS(value) => self.patch_mmds(value),
+            PatchMMDSDataStore(name, value) => self.patch_mmds_data_store(&name, value),
             Pause => self.pause(),
             PutMMDS(value) => self.put_mmds(value),
+            PutMMDSDataStore(name, value) => self.put_mmds_data_store(&name, value),
+            RevokeMMDSTokens => self.revoke_mmds_tokens(),
             Resume => self.resume(),
             #[cfg(target_arch = "x86_64")]
             SendCtrlAltDel => self.send_ctrl_alt_del(),
@@ -618,6 +765,8 @@ impl RuntimeApiController {
                 .map_err(|e| VmmActionError::BalloonConfig(BalloonConfigError::from(e))),
             UpdateBlockDevice(new_cfg) => self.update_block_device(new_cfg),
             UpdateNetworkInterface(netif_update) => self.update_net_rate_limiters(netif_update),
+            UpdateRateLimiterGroup(group_update) => self.update_rate_limiter_group(group_update),
+            UpdateVsockDevice(vsock_update) => self.update_vsock_rate_limiters(vsock_update),
 
             // Operations not allowed post-boot.
             ConfigureBootSource(_)
@@ -629,6 +778,7 @@ impl RuntimeApiController {
             | SetBalloonDevice(_)
             | SetVsockDevice(_)
             | SetMmdsConfiguration(_)
+            | SetRateLimiterGroup(_)
             | StartMicroVm
             | UpdateVmConfiguration(_) => Err(VmmActionError::OperationNotSupportedPostBoot),
         }
@@ -753,8 +903,7 @@ impl RuntimeApiController {
         if new_cfg.rate_limiter.is_some() {
             vmm.update_block_rate_limiter(
                 &new_cfg.drive_id,
-                RateLimiterUpdate::from(new_cfg.rate_limiter).bandwidth,
-                RateLimiterUpdate::from(new_cfg.rate_limiter).ops,
+                RateLimiterUpdate::from(new_cfg.rate_limiter),
             )
             .map(|()| VmmData::Empty)
             .map_err(DriveError::DeviceUpdate)
@@ -765,6 +914,16 @@ impl RuntimeApiController {
 
     /// Updates configuration for an emulated net device as described in `new_cfg`.
     fn update_net_rate_limiters(&mut self, new_cfg: NetworkInterfaceUpdateConfig) -> ActionResult {
+        if new_cfg
+            .rx_rate_limiter
+            .iter()
+            .chain(new_cfg.tx_rate_limiter.iter())
+            .any(RateLimiterConfig::has_direction_buckets)
+        {
+            return Err(VmmActionError::NetworkConfig(
+                NetworkInterfaceError::DirectionalRateLimiter,
+            ));
+        }
         self.vmm
             .lock()
             .expect("Poisoned lock")
@@ -779,6 +938,55 @@ impl RuntimeApiController {
             .map_err(NetworkInterfaceError::DeviceUpdate)
             .map_err(VmmActionError::NetworkConfig)
     }
+
+    /// Updates the token buckets of a rate limiter group as described in `new_cfg`. The group
+    /// is shared with the devices referencing it, so they pick up the new limits right away.
+    fn update_rate_limiter_group(&mut self, new_cfg: RateLimiterGroupUpdateConfig) -> ActionResult {
+        self.vm_resources
+            .update_rate_limiter_group(new_cfg)
+            .map(|()| VmmData::Empty)
+            .map_err(VmmActionError::RateLimiterGroup)
+    }
+
+    fn block_device_info(&self, drive_id: &str) -> ActionResult {
+        self.vm_resources
+            .block_device_info(drive_id)
+            .map(VmmData::BlockDevice)
+            .map_err(VmmActionError::DriveConfig)
+    }
+
+    fn net_device_info(&self, iface_id: &str) -> ActionResult {
+        self.vm_resources
+            .net_device_info(iface_id)
+            .map(VmmData::NetworkInterface)
+            .map_err(VmmActionError::NetworkConfig)
+    }
+
+    /// Updates configuration for the vsock device as described in `new_cfg`.
+    fn update_vsock_rate_limiters(&mut self, new_cfg: VsockDeviceUpdateConfig) -> ActionResult {
+        if new_cfg
+            .rx_rate_limiter
+            .iter()
+            .chain(new_cfg.tx_rate_limiter.iter())
+            .any(RateLimiterConfig::has_direction_buckets)
+        {
+            return Err(VmmActionError::VsockConfig(
+                VsockConfigError::DirectionalRateLimiter,
+            ));
+        }
+        self.vmm
+            .lock()
+            .expect("Poisoned lock")
+            .update_vsock_rate_limiters(
+                RateLimiterUpdate::from(new_cfg.rx_rate_limiter).bandwidth,
+                RateLimiterUpdate::from(new_cfg.rx_rate_limiter).ops,
+                RateLimiterUpdate::from(new_cfg.tx_rate_limiter).bandwidth,
+                RateLimiterUpdate::from(new_cfg.tx_rate_limiter).ops,
+            )
+            .map(|()| VmmData::Empty)
+            .map_err(VsockConfigError::DeviceUpdate)
+            .map_err(VmmActionError::VsockConfig)
+    }
 }
 
 #[cfg(test)]
@@ -788,11 +996,13 @@ mod tests {
     use crate::vmm_config::drive::{CacheType, FileEngineType};
     use crate::vmm_config::logger::LoggerLevel;
     use crate::vmm_config::vsock::VsockBuilder;
+    use crate::vmm_config::RateLimiterStatus;
     use devices::virtio::balloon::{BalloonConfig, Error as BalloonError};
     use devices::virtio::VsockError;
     use seccompiler::BpfThreadMap;
 
-    use mmds::data_store::MmdsVersion;
+    use mmds::data_store::{MmdsVersion, TokenBinding};
+    use std::collections::HashMap;
     use std::path::PathBuf;
 
     impl PartialEq for VmmActionError {
@@ -817,6 +1027,7 @@ mod tests {
                     | (NotSupported(_), NotSupported(_))
                     | (OperationNotSupportedPostBoot, OperationNotSupportedPostBoot)
                     | (OperationNotSupportedPreBoot, OperationNotSupportedPreBoot)
+                    | (RateLimiterGroup(_), RateLimiterGroup(_))
                     | (StartMicrovm(_), StartMicrovm(_))
                     | (VsockConfig(_), VsockConfig(_))
             )
@@ -835,7 +1046,10 @@ mod tests {
         block_set: bool,
         vsock_set: bool,
         net_set: bool,
+        rate_limiter_group_set: bool,
+        rate_limiter_group_updated: bool,
         pub mmds: Option<Arc<Mutex<Mmds>>>,
+        pub mmds_data_stores: HashMap<String, Arc<Mutex<Mmds>>>,
         pub boot_timer: bool,
         // when `true`, all self methods are forced to fail
         pub force_errors: bool,
@@ -874,6 +1088,7 @@ mod tests {
             self.vm_config.mem_size_mib = machine_config.mem_size_mib.unwrap();
             self.vm_config.smt = machine_config.smt.unwrap();
             self.vm_config.cpu_template = machine_config.cpu_template.unwrap();
+            self.vm_config.cpu_template_path = machine_config.cpu_template_path.clone();
             self.vm_config.track_dirty_pages = machine_config.track_dirty_pages.unwrap();
 
             Ok(())
@@ -922,6 +1137,77 @@ mod tests {
             Ok(())
         }
 
+        pub fn block_device_info(&self, drive_id: &str) -> Result<BlockDeviceInfo, DriveError> {
+            if self.force_errors {
+                return Err(DriveError::DeviceNotFound(drive_id.to_string()));
+            }
+            Ok(BlockDeviceInfo {
+                config: BlockDeviceConfig {
+                    path_on_host: String::new(),
+                    is_root_device: false,
+                    partuuid: None,
+                    cache_type: CacheType::Unsafe,
+                    is_read_only: false,
+                    drive_id: drive_id.to_string(),
+                    rate_limiter: None,
+                    rate_limiter_group: None,
+                    file_engine_type: FileEngineType::default(),
+                    direct_io: false,
+                    num_queues: 1,
+                    io_threads: false,
+                    fixed_buffers: false,
+                    sq_poll: false,
+                },
+                rate_limiter_status: RateLimiterStatus::default(),
+            })
+        }
+
+        pub fn net_device_info(
+            &self,
+            iface_id: &str,
+        ) -> Result<NetworkInterfaceInfo, NetworkInterfaceError> {
+            if self.force_errors {
+                return Err(NetworkInterfaceError::DeviceNotFound(iface_id.to_string()));
+            }
+            Ok(NetworkInterfaceInfo {
+                config: NetworkInterfaceConfig {
+                    iface_id: iface_id.to_string(),
+                    host_dev_name: String::new(),
+                    guest_mac: None,
+                    rx_rate_limiter: None,
+                    tx_rate_limiter: None,
+                    rx_rate_limiter_group: None,
+                    tx_rate_limiter_group: None,
+                    egress_filter: None,
+                    user_net: None,
+                },
+                rx_rate_limiter_status: RateLimiterStatus::default(),
+                tx_rate_limiter_status: RateLimiterStatus::default(),
+            })
+        }
+
+        pub fn set_rate_limiter_group(
+            &mut self,
+            _: RateLimiterGroupConfig,
+        ) -> Result<(), RateLimiterGroupError> {
+            if self.force_errors {
+                return Err(RateLimiterGroupError::InvalidGroupId(String::new()));
+            }
+            self.rate_limiter_group_set = true;
+            Ok(())
+        }
+
+        pub fn update_rate_limiter_group(
+            &mut self,
+            config: RateLimiterGroupUpdateConfig,
+        ) -> Result<(), RateLimiterGroupError> {
+            if self.force_errors {
+                return Err(RateLimiterGroupError::GroupNotFound(config.group_id));
+            }
+            self.rate_limiter_group_updated = true;
+            Ok(())
+        }
+
         pub fn set_vsock_device(&mut self, _: VsockDeviceConfig) -> Result<(), VsockConfigError> {
             if self.force_errors {
                 return Err(VsockConfigError::CreateVsockDevice(
@@ -958,6 +1244,28 @@ mod tests {
             let mmds = self.mmds_or_default();
             mmds.lock().expect("Poisoned lock")
         }
+
+        /// If not initialised, create the named mmds data store with the default config.
+        pub fn locked_mmds_data_store_or_default(&mut self, name: &str) -> MutexGuard<'_, Mmds> {
+            self.mmds_data_stores
+                .entry(name.to_string())
+                .or_insert_with(|| Arc::new(Mutex::new(Mmds::new_named(name.to_string()))))
+                .lock()
+                .expect("Poisoned lock")
+        }
+
+        pub fn locked_mmds_data_store(&self, name: &str) -> Option<MutexGuard<'_, Mmds>> {
+            self.mmds_data_stores
+                .get(name)
+                .map(|mmds| mmds.lock().expect("Poisoned lock"))
+        }
+
+        pub fn revoke_mmds_tokens(&self) -> Result<(), data_store::Error> {
+            for mmds in self.mmds.iter().chain(self.mmds_data_stores.values()) {
+                mmds.lock().expect("Poisoned lock").revoke_tokens()?;
+            }
+            Ok(())
+        }
     }
 
     impl From<&MockVmRes> for VmmConfig {
@@ -979,6 +1287,7 @@ mod tests {
         pub update_balloon_stats_config_called: bool,
         pub update_block_device_path_called: bool,
         pub update_net_rate_limiters_called: bool,
+        pub update_vsock_rate_limiters_called: bool,
         // when `true`, all self methods are forced to fail
         pub force_errors: bool,
     }
@@ -1056,8 +1365,7 @@ mod tests {
         pub fn update_block_rate_limiter(
             &mut self,
             _: &str,
-            _: rate_limiter::BucketUpdate,
-            _: rate_limiter::BucketUpdate,
+            _: RateLimiterUpdate,
         ) -> Result<(), VmmError> {
             Ok(())
         }
@@ -1079,6 +1387,22 @@ mod tests {
             Ok(())
         }
 
+        pub fn update_vsock_rate_limiters(
+            &mut self,
+            _: rate_limiter::BucketUpdate,
+            _: rate_limiter::BucketUpdate,
+            _: rate_limiter::BucketUpdate,
+            _: rate_limiter::BucketUpdate,
+        ) -> Result<(), VmmError> {
+            if self.force_errors {
+                return Err(VmmError::DeviceManager(
+                    crate::device_manager::mmio::Error::IncorrectDeviceType,
+                ));
+            }
+            self.update_vsock_rate_limiters_called = true;
+            Ok(())
+        }
+
         pub fn instance_info(&self) -> InstanceInfo {
             InstanceInfo::default()
         }
@@ -1109,6 +1433,13 @@ mod tests {
         Ok(())
     }
 
+    pub fn check_snapshot_compatibility(
+        _: &std::path::Path,
+        _: versionize::VersionMap,
+    ) -> Result<CpuCompatibilityReport, LoadSnapshotError> {
+        Ok(CpuCompatibilityReport::default())
+    }
+
     // Need to redefine this since the non-test one uses real Vmm
     // instead of our mocks.
     pub fn restore_from_snapshot(
@@ -1217,6 +1548,33 @@ mod tests {
         });
     }
 
+    #[test]
+    fn test_preboot_get_device_info() {
+        let req = VmmAction::GetBlockDevice("rootfs".to_string());
+        check_preboot_request(req, |result, _| match result {
+            Ok(VmmData::BlockDevice(info)) => assert_eq!(info.config.drive_id, "rootfs"),
+            _ => panic!("Unexpected result: {:?}", result),
+        });
+        let req = VmmAction::GetBlockDevice("rootfs".to_string());
+        check_preboot_request_err(
+            req,
+            VmmActionError::DriveConfig(DriveError::DeviceNotFound("rootfs".to_string())),
+        );
+
+        let req = VmmAction::GetNetworkInterface("eth0".to_string());
+        check_preboot_request(req, |result, _| match result {
+            Ok(VmmData::NetworkInterface(info)) => assert_eq!(info.config.iface_id, "eth0"),
+            _ => panic!("Unexpected result: {:?}", result),
+        });
+        let req = VmmAction::GetNetworkInterface("eth0".to_string());
+        check_preboot_request_err(
+            req,
+            VmmActionError::NetworkConfig(NetworkInterfaceError::DeviceNotFound(
+                "eth0".to_string(),
+            )),
+        );
+    }
+
     #[test]
     fn test_preboot_set_vm_config() {
         let req = VmmAction::UpdateVmConfiguration(VmUpdateConfig::from(VmConfig::default()));
@@ -1258,7 +1616,13 @@ mod tests {
             is_read_only: false,
             drive_id: String::new(),
             rate_limiter: None,
+            rate_limiter_group: None,
             file_engine_type: FileEngineType::default(),
+            direct_io: false,
+            num_queues: 1,
+            io_threads: false,
+            fixed_buffers: false,
+            sq_poll: false,
         });
         check_preboot_request(req, |result, vm_res| {
             assert_eq!(result, Ok(VmmData::Empty));
@@ -1273,7 +1637,13 @@ mod tests {
             is_read_only: false,
             drive_id: String::new(),
             rate_limiter: None,
+            rate_limiter_group: None,
             file_engine_type: FileEngineType::default(),
+            direct_io: false,
+            num_queues: 1,
+            io_threads: false,
+            fixed_buffers: false,
+            sq_poll: false,
         });
         check_preboot_request_err(
             req,
@@ -1289,6 +1659,10 @@ mod tests {
             guest_mac: None,
             rx_rate_limiter: None,
             tx_rate_limiter: None,
+            rx_rate_limiter_group: None,
+            tx_rate_limiter_group: None,
+            egress_filter: None,
+            user_net: None,
         });
         check_preboot_request(req, |result, vm_res| {
             assert_eq!(result, Ok(VmmData::Empty));
@@ -1301,6 +1675,10 @@ mod tests {
             guest_mac: None,
             rx_rate_limiter: None,
             tx_rate_limiter: None,
+            rx_rate_limiter_group: None,
+            tx_rate_limiter_group: None,
+            egress_filter: None,
+            user_net: None,
         });
         check_preboot_request_err(
             req,
@@ -1316,6 +1694,11 @@ mod tests {
             vsock_id: Some(String::new()),
             guest_cid: 0,
             uds_path: String::new(),
+            tcp: None,
+            rx_rate_limiter: None,
+            tx_rate_limiter: None,
+            max_connections: None,
+            allowed_ports: None,
         });
         check_preboot_request(req, |result, vm_res| {
             assert_eq!(result, Ok(VmmData::Empty));
@@ -1326,6 +1709,11 @@ mod tests {
             vsock_id: Some(String::new()),
             guest_cid: 0,
             uds_path: String::new(),
+            tcp: None,
+            rx_rate_limiter: None,
+            tx_rate_limiter: None,
+            max_connections: None,
+            allowed_ports: None,
         });
         check_preboot_request_err(
             req,
@@ -1335,12 +1723,42 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_preboot_set_rate_limiter_group() {
+        let req = VmmAction::SetRateLimiterGroup(RateLimiterGroupConfig {
+            group_id: String::from("disks"),
+            bandwidth: None,
+            ops: None,
+        });
+        check_preboot_request(req, |result, vm_res| {
+            assert_eq!(result, Ok(VmmData::Empty));
+            assert!(vm_res.rate_limiter_group_set)
+        });
+
+        let req = VmmAction::SetRateLimiterGroup(RateLimiterGroupConfig {
+            group_id: String::new(),
+            bandwidth: None,
+            ops: None,
+        });
+        check_preboot_request_err(
+            req,
+            VmmActionError::RateLimiterGroup(RateLimiterGroupError::InvalidGroupId(String::new())),
+        );
+    }
+
     #[test]
     fn test_preboot_set_mmds_config() {
         let req = VmmAction::SetMmdsConfiguration(MmdsConfig {
             ipv4_address: None,
             version: MmdsVersion::V2,
             network_interfaces: Vec::new(),
+            data_store: None,
+            data_store_limit: None,
+            token_binding: TokenBinding::None,
+            tcp_port: None,
+            max_connections: None,
+            max_pending_resets: None,
+            max_response_size: None,
         });
         check_preboot_request(req, |result, vm_res| {
             assert_eq!(result, Ok(VmmData::Empty));
@@ -1354,6 +1772,13 @@ mod tests {
             ipv4_address: None,
             version: MmdsVersion::default(),
             network_interfaces: Vec::new(),
+            data_store: None,
+            data_store_limit: None,
+            token_binding: TokenBinding::None,
+            tcp_port: None,
+            max_connections: None,
+            max_pending_resets: None,
+            max_response_size: None,
         });
         check_preboot_request_err(
             req,
@@ -1507,6 +1932,102 @@ mod tests {
         });
     }
 
+    #[test]
+    fn test_preboot_mmds_data_store() {
+        let mut vm_resources = MockVmRes::default();
+        let mut evmgr = EventManager::new().unwrap();
+        let seccomp_filters = BpfThreadMap::new();
+        let mut preboot = default_preboot(&mut vm_resources, &mut evmgr, &seccomp_filters);
+
+        // The data store is created on the first PUT request.
+        assert_eq!(
+            preboot.handle_preboot_request(VmmAction::GetMMDSDataStore("sidecar".to_string())),
+            Err(VmmActionError::Mmds(data_store::Error::NotFound))
+        );
+        assert_eq!(
+            preboot.handle_preboot_request(VmmAction::PatchMMDSDataStore(
+                "sidecar".to_string(),
+                Value::Null
+            )),
+            Err(VmmActionError::Mmds(data_store::Error::NotFound))
+        );
+        let data = serde_json::from_str(r#"{"key1": "value1", "key2": "val2"}"#).unwrap();
+        assert_eq!(
+            preboot
+                .handle_preboot_request(VmmAction::PutMMDSDataStore("sidecar".to_string(), data)),
+            Ok(VmmData::Empty)
+        );
+        let patch = serde_json::from_str(r#"{"key1": null, "key2": "value2"}"#).unwrap();
+        assert_eq!(
+            preboot.handle_preboot_request(VmmAction::PatchMMDSDataStore(
+                "sidecar".to_string(),
+                patch
+            )),
+            Ok(VmmData::Empty)
+        );
+        assert_eq!(
+            preboot.handle_preboot_request(VmmAction::GetMMDSDataStore("sidecar".to_string())),
+            Ok(VmmData::MmdsValue(
+                serde_json::from_str(r#"{"key2": "value2"}"#).unwrap()
+            ))
+        );
+
+        // The default data store is not affected.
+        assert_eq!(
+            preboot.handle_preboot_request(VmmAction::GetMMDS),
+            Ok(VmmData::MmdsValue(Value::Null))
+        );
+
+        // `config` is reserved for the MMDS configuration.
+        assert_eq!(
+            preboot.handle_preboot_request(VmmAction::PutMMDSDataStore(
+                "config".to_string(),
+                Value::Null
+            )),
+            Err(VmmActionError::MmdsConfig(
+                MmdsConfigError::InvalidDataStoreName("config".to_string())
+            ))
+        );
+    }
+
+    #[test]
+    fn test_runtime_mmds_data_store() {
+        let vmm = Arc::new(Mutex::new(MockVmm::default()));
+        let mut runtime = RuntimeApiController::new(MockVmRes::default(), vmm);
+
+        assert_eq!(
+            runtime.handle_request(VmmAction::PutMMDSDataStore(
+                "sidecar".to_string(),
+                Value::String("string".to_string())
+            )),
+            Ok(VmmData::Empty)
+        );
+        assert_eq!(
+            runtime.handle_request(VmmAction::GetMMDSDataStore("sidecar".to_string())),
+            Ok(VmmData::MmdsValue(Value::String("string".to_string())))
+        );
+        assert_eq!(
+            runtime.handle_request(VmmAction::GetMMDSDataStore("other".to_string())),
+            Err(VmmActionError::Mmds(data_store::Error::NotFound))
+        );
+    }
+
+    #[test]
+    fn test_preboot_revoke_mmds_tokens() {
+        check_preboot_request(VmmAction::RevokeMMDSTokens, |result, _| {
+            assert_eq!(result, Ok(VmmData::Empty));
+        });
+    }
+
+    #[test]
+    fn test_runtime_revoke_mmds_tokens() {
+        let mmds = Arc::new(Mutex::new(Mmds::default()));
+        mmds.lock().unwrap().set_version(MmdsVersion::V2).unwrap();
+        check_runtime_request_with_mmds(VmmAction::RevokeMMDSTokens, mmds, |result, _| {
+            assert_eq!(result, Ok(VmmData::Empty));
+        });
+    }
+
     #[test]
     fn test_preboot_load_snapshot() {
         let mut vm_resources = MockVmRes::default();
@@ -1514,12 +2035,30 @@ mod tests {
         let seccomp_filters = BpfThreadMap::new();
         let mut preboot = default_preboot(&mut vm_resources, &mut evmgr, &seccomp_filters);
 
+        // Dry run.
+        let req = VmmAction::LoadSnapshot(LoadSnapshotParams {
+            snapshot_path: PathBuf::new(),
+            mem_file_path: PathBuf::new(),
+            enable_diff_snapshots: false,
+            resume_vm: false,
+            dry_run: true,
+        });
+        // Request should return the compatibility report without building a vmm.
+        assert_eq!(
+            preboot.handle_preboot_request(req),
+            Ok(VmmData::SnapshotCompatibility(
+                CpuCompatibilityReport::default()
+            ))
+        );
+        assert!(preboot.built_vmm.is_none());
+
         // Without resume.
         let req = VmmAction::LoadSnapshot(LoadSnapshotParams {
             snapshot_path: PathBuf::new(),
             mem_file_path: PathBuf::new(),
             enable_diff_snapshots: false,
             resume_vm: false,
+            dry_run: false,
         });
         // Request should succeed.
         preboot.handle_preboot_request(req).unwrap();
@@ -1533,6 +2072,7 @@ mod tests {
             mem_file_path: PathBuf::new(),
             enable_diff_snapshots: false,
             resume_vm: true,
+            dry_run: false,
         });
         // Request should succeed.
         preboot.handle_preboot_request(req).unwrap();
@@ -1583,6 +2123,17 @@ mod tests {
             }),
             VmmActionError::OperationNotSupportedPreBoot,
         );
+        check_preboot_request_err(
+            VmmAction::UpdateVsockDevice(VsockDeviceUpdateConfig {
+                rx_rate_limiter: None,
+                tx_rate_limiter: None,
+            }),
+            VmmActionError::OperationNotSupportedPreBoot,
+        );
+        check_preboot_request_err(
+            VmmAction::UpdateRateLimiterGroup(RateLimiterGroupUpdateConfig::default()),
+            VmmActionError::OperationNotSupportedPreBoot,
+        );
         check_preboot_request_err(
             VmmAction::CreateSnapshot(CreateSnapshotParams {
                 snapshot_type: SnapshotType::Full,
@@ -1857,6 +2408,110 @@ mod tests {
                 VmmError::DeviceManager(crate::device_manager::mmio::Error::IncorrectDeviceType),
             )),
         );
+
+        // Per-direction buckets are reserved to block devices.
+        let req = VmmAction::UpdateNetworkInterface(NetworkInterfaceUpdateConfig {
+            iface_id: String::new(),
+            rx_rate_limiter: Some(RateLimiterConfig {
+                read_ops: Some(vmm_config::TokenBucketConfig::default()),
+                ..Default::default()
+            }),
+            tx_rate_limiter: None,
+        });
+        check_runtime_request(req, |result, vmm| {
+            match result {
+                Err(VmmActionError::NetworkConfig(
+                    NetworkInterfaceError::DirectionalRateLimiter,
+                )) => (),
+                _ => panic!("Unexpected result."),
+            }
+            assert!(!vmm.update_net_rate_limiters_called)
+        });
+    }
+
+    #[test]
+    fn test_runtime_update_vsock_rate_limiters() {
+        let req = VmmAction::UpdateVsockDevice(VsockDeviceUpdateConfig {
+            rx_rate_limiter: None,
+            tx_rate_limiter: None,
+        });
+        check_runtime_request(req, |result, vmm| {
+            assert_eq!(result, Ok(VmmData::Empty));
+            assert!(vmm.update_vsock_rate_limiters_called)
+        });
+
+        let req = VmmAction::UpdateVsockDevice(VsockDeviceUpdateConfig {
+            rx_rate_limiter: None,
+            tx_rate_limiter: None,
+        });
+        check_runtime_request_err(
+            req,
+            VmmActionError::VsockConfig(VsockConfigError::DeviceUpdate(VmmError::DeviceManager(
+                crate::device_manager::mmio::Error::IncorrectDeviceType,
+            ))),
+        );
+    }
+
+    #[test]
+    fn test_runtime_update_rate_limiter_group() {
+        let vmm = Arc::new(Mutex::new(MockVmm::default()));
+        let mut runtime = RuntimeApiController::new(MockVmRes::default(), vmm.clone());
+        let req = VmmAction::UpdateRateLimiterGroup(RateLimiterGroupUpdateConfig {
+            group_id: String::from("disks"),
+            bandwidth: None,
+            ops: None,
+        });
+        assert_eq!(runtime.handle_request(req), Ok(VmmData::Empty));
+        assert!(runtime.vm_resources.rate_limiter_group_updated);
+
+        let vm_res = MockVmRes {
+            force_errors: true,
+            ..Default::default()
+        };
+        let mut runtime = RuntimeApiController::new(vm_res, vmm);
+        let req = VmmAction::UpdateRateLimiterGroup(RateLimiterGroupUpdateConfig {
+            group_id: String::from("disks"),
+            bandwidth: None,
+            ops: None,
+        });
+        assert_eq!(
+            runtime.handle_request(req),
+            Err(VmmActionError::RateLimiterGroup(
+                RateLimiterGroupError::GroupNotFound(String::from("disks"))
+            ))
+        );
+    }
+
+    #[test]
+    fn test_runtime_get_device_info() {
+        let vmm = Arc::new(Mutex::new(MockVmm::default()));
+        let mut runtime = RuntimeApiController::new(MockVmRes::default(), vmm.clone());
+        match runtime.handle_request(VmmAction::GetBlockDevice("rootfs".to_string())) {
+            Ok(VmmData::BlockDevice(info)) => assert_eq!(info.config.drive_id, "rootfs"),
+            result => panic!("Unexpected result: {:?}", result),
+        }
+        match runtime.handle_request(VmmAction::GetNetworkInterface("eth0".to_string())) {
+            Ok(VmmData::NetworkInterface(info)) => assert_eq!(info.config.iface_id, "eth0"),
+            result => panic!("Unexpected result: {:?}", result),
+        }
+
+        let vm_res = MockVmRes {
+            force_errors: true,
+            ..Default::default()
+        };
+        let mut runtime = RuntimeApiController::new(vm_res, vmm);
+        assert_eq!(
+            runtime.handle_request(VmmAction::GetBlockDevice("rootfs".to_string())),
+            Err(VmmActionError::DriveConfig(DriveError::DeviceNotFound(
+                "rootfs".to_string()
+            )))
+        );
+        assert_eq!(
+            runtime.handle_request(VmmAction::GetNetworkInterface("eth0".to_string())),
+            Err(VmmActionError::NetworkConfig(
+                NetworkInterfaceError::DeviceNotFound("eth0".to_string())
+            ))
+        );
     }
 
     #[test]
@@ -1889,7 +2544,13 @@ mod tests {
                 is_read_only: false,
                 drive_id: String::new(),
                 rate_limiter: None,
+                rate_limiter_group: None,
                 file_engine_type: FileEngineType::default(),
+                direct_io: false,
+                num_queues: 1,
+                io_threads: false,
+                fixed_buffers: false,
+                sq_poll: false,
             }),
             VmmActionError::OperationNotSupportedPostBoot,
         );
@@ -1900,6 +2561,10 @@ mod tests {
                 guest_mac: None,
                 rx_rate_limiter: None,
                 tx_rate_limiter: None,
+                rx_rate_limiter_group: None,
+                tx_rate_limiter_group: None,
+                egress_filter: None,
+                user_net: None,
             }),
             VmmActionError::OperationNotSupportedPostBoot,
         );
@@ -1908,6 +2573,11 @@ mod tests {
                 vsock_id: Some(String::new()),
                 guest_cid: 0,
                 uds_path: String::new(),
+                tcp: None,
+                rx_rate_limiter: None,
+                tx_rate_limiter: None,
+                max_connections: None,
+                allowed_ports: None,
             }),
             VmmActionError::OperationNotSupportedPostBoot,
         );
@@ -1915,11 +2585,24 @@ mod tests {
             VmmAction::SetBalloonDevice(BalloonDeviceConfig::default()),
             VmmActionError::OperationNotSupportedPostBoot,
         );
+        check_runtime_request_err(
+            VmmAction::SetRateLimiterGroup(RateLimiterGroupConfig {
+                group_id: String::new(),
+                bandwidth: None,
+                ops: None,
+            }),
+            VmmActionError::OperationNotSupportedPostBoot,
+        );
         check_runtime_request_err(
             VmmAction::SetVsockDevice(VsockDeviceConfig {
                 vsock_id: Some(String::new()),
                 guest_cid: 0,
                 uds_path: String::new(),
+                tcp: None,
+                rx_rate_limiter: None,
+                tx_rate_limiter: None,
+                max_connections: None,
+                allowed_ports: None,
             }),
             VmmActionError::OperationNotSupportedPostBoot,
         );
@@ -1928,6 +2611,13 @@ mod tests {
                 ipv4_address: None,
                 version: MmdsVersion::default(),
                 network_interfaces: Vec::new(),
+                data_store: None,
+                data_store_limit: None,
+                token_binding: TokenBinding::None,
+                tcp_port: None,
+                max_connections: None,
+                max_pending_resets: None,
+                max_response_size: None,
             }),
             VmmActionError::OperationNotSupportedPostBoot,
         );
@@ -1941,6 +2631,7 @@ mod tests {
                 mem_file_path: PathBuf::new(),
                 enable_diff_snapshots: false,
                 resume_vm: false,
+                dry_run: false,
             }),
             VmmActionError::OperationNotSupportedPostBoot,
         );
@@ -1960,6 +2651,7 @@ mod tests {
             mem_file_path: PathBuf::new(),
             enable_diff_snapshots: false,
             resume_vm: false,
+            dry_run: false,
         });
         let err = preboot.handle_preboot_request(req);
         assert_eq!(
@@ -1984,7 +2676,13 @@ mod tests {
             is_read_only: false,
             drive_id: String::new(),
             rate_limiter: None,
+            rate_limiter_group: None,
             file_engine_type: FileEngineType::default(),
+            direct_io: false,
+            num_queues: 1,
+            io_threads: false,
+            fixed_buffers: false,
+            sq_poll: false,
         });
         verify_load_snap_disallowed_after_boot_resources(req, "InsertBlockDevice");
 
@@ -1994,16 +2692,32 @@ mod tests {
             guest_mac: None,
             rx_rate_limiter: None,
             tx_rate_limiter: None,
+            rx_rate_limiter_group: None,
+            tx_rate_limiter_group: None,
+            egress_filter: None,
+            user_net: None,
         });
         verify_load_snap_disallowed_after_boot_resources(req, "InsertNetworkDevice");
 
         let req = VmmAction::SetBalloonDevice(BalloonDeviceConfig::default());
         verify_load_snap_disallowed_after_boot_resources(req, "SetBalloonDevice");
 
+        let req = VmmAction::SetRateLimiterGroup(RateLimiterGroupConfig {
+            group_id: String::from("disks"),
+            bandwidth: None,
+            ops: None,
+        });
+        verify_load_snap_disallowed_after_boot_resources(req, "SetRateLimiterGroup");
+
         let req = VmmAction::SetVsockDevice(VsockDeviceConfig {
             vsock_id: Some(String::new()),
             guest_cid: 0,
             uds_path: String::new(),
+            tcp: None,
+            rx_rate_limiter: None,
+            tx_rate_limiter: None,
+            max_connections: None,
+            allowed_ports: None,
         });
         verify_load_snap_disallowed_after_boot_resources(req, "SetVsockDevice");
 
@@ -2014,6 +2728,13 @@ mod tests {
             ipv4_address: None,
             version: MmdsVersion::default(),
             network_interfaces: Vec::new(),
+            data_store: None,
+            data_store_limit: None,
+            token_binding: TokenBinding::None,
+            tcp_port: None,
+            max_connections: None,
+            max_pending_resets: None,
+            max_response_size: None,
         });
         verify_load_snap_disallowed_after_boot_resources(req, "SetMmdsConfiguration");
     }
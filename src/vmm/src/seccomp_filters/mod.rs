@@ -1,10 +1,14 @@
 // Copyright 2018 Amazon.com, Inc. or its affiliates. All Rights Reserved.
 // SPDX-License-Identifier: Apache-2.0
-use seccompiler::{deserialize_binary, BpfThreadMap, DeserializationError, InstallationError};
+use seccompiler::{
+    deserialize_binary, learned_filters, learning_filters, BpfThreadMap, DeserializationError,
+    InstallationError, LearningError,
+};
 
 use std::fmt;
 use std::fs::File;
 use std::io::{BufReader, Read};
+use std::path::Path;
 use std::sync::Arc;
 
 const THREAD_CATEGORIES: [&str; 3] = ["vmm", "api", "vcpu"];
@@ -30,6 +34,10 @@ pub enum FilterError {
     Install(InstallationError),
     /// File open error.
     FileOpen(std::io::Error),
+    /// Learning mode error.
+    Learning(LearningError),
+    /// Error writing the learned filters.
+    LearnedFiltersWrite(std::io::Error),
 }
 
 impl fmt::Display for FilterError {
@@ -49,6 +57,10 @@ impl fmt::Display for FilterError {
             }
             Install(ref err) => write!(f, "Filter installation error: {}", err),
             FileOpen(ref err) => write!(f, "Filter file open error: {}", err),
+            Learning(ref err) => write!(f, "Filter learning error: {}", err),
+            LearnedFiltersWrite(ref err) => {
+                write!(f, "Failed to write the learned filters: {}", err)
+            }
         }
     }
 }
@@ -61,6 +73,8 @@ pub enum SeccompConfig {
     Advanced,
     /// Custom, user-provided filters.
     Custom(Box<dyn std::io::Read>),
+    /// Learning filters, which record the syscalls made by each thread category.
+    Learning,
 }
 
 impl SeccompConfig {
@@ -68,9 +82,12 @@ impl SeccompConfig {
     pub fn from_args(
         no_seccomp: bool,
         seccomp_filter: Option<&String>,
+        seccomp_learn: bool,
     ) -> Result<Self, FilterError> {
         if no_seccomp {
             Ok(SeccompConfig::None)
+        } else if seccomp_learn {
+            Ok(SeccompConfig::Learning)
         } else {
             match seccomp_filter {
                 Some(path) => Ok(SeccompConfig::Custom(Box::new(
@@ -88,9 +105,19 @@ pub fn get_filters(config: SeccompConfig) -> Result<BpfThreadMap, FilterError> {
         SeccompConfig::None => Ok(get_empty_filters()),
         SeccompConfig::Advanced => get_default_filters(),
         SeccompConfig::Custom(reader) => get_custom_filters(reader),
+        SeccompConfig::Learning => {
+            learning_filters(&THREAD_CATEGORIES).map_err(FilterError::Learning)
+        }
     }
 }
 
+/// Write the filters learned so far to `path`, in the JSON format accepted by seccompiler-bin.
+/// Only meaningful when the filters were retrieved with `SeccompConfig::Learning`.
+pub fn save_learned_filters(path: &Path) -> Result<(), FilterError> {
+    std::fs::write(path, learned_filters(&THREAD_CATEGORIES))
+        .map_err(FilterError::LearnedFiltersWrite)
+}
+
 /// Retrieve the default filters containing the syscall rules required by `Firecracker`
 /// to function. The binary file is generated via the `build.rs` script of this crate.
 fn get_default_filters() -> Result<BpfThreadMap, FilterError> {
@@ -171,6 +198,23 @@ mod tests {
         assert!(get_filters(SeccompConfig::Custom(Box::new(file))).is_err());
     }
 
+    #[test]
+    fn test_save_learned_filters() {
+        let file = TempFile::new().unwrap();
+        save_learned_filters(file.as_path()).unwrap();
+        let json: serde_json::Value =
+            serde_json::from_str(&std::fs::read_to_string(file.as_path()).unwrap()).unwrap();
+        for category in THREAD_CATEGORIES.iter() {
+            assert_eq!(json[*category]["default_action"], "trap");
+            assert_eq!(json[*category]["filter_action"], "allow");
+        }
+
+        assert!(matches!(
+            save_learned_filters(Path::new("/invalid/path")),
+            Err(FilterError::LearnedFiltersWrite(_))
+        ));
+    }
+
     #[test]
     fn test_filter_thread_categories() {
         // correct categories
@@ -209,24 +253,29 @@ mod tests {
     #[test]
     fn test_seccomp_config() {
         assert!(matches!(
-            SeccompConfig::from_args(true, None),
+            SeccompConfig::from_args(true, None, false),
             Ok(SeccompConfig::None)
         ));
 
         assert!(matches!(
-            SeccompConfig::from_args(false, Some(&"/dev/null".to_string())),
+            SeccompConfig::from_args(false, Some(&"/dev/null".to_string()), false),
             Ok(SeccompConfig::Custom(_))
         ));
 
         assert!(matches!(
-            SeccompConfig::from_args(false, Some(&"invalid_path".to_string())),
+            SeccompConfig::from_args(false, Some(&"invalid_path".to_string()), false),
             Err(FilterError::FileOpen(_))
         ));
 
         // test the default case, no parametes -> default advanced.
         assert!(matches!(
-            SeccompConfig::from_args(false, None),
+            SeccompConfig::from_args(false, None, false),
             Ok(SeccompConfig::Advanced)
         ));
+
+        assert!(matches!(
+            SeccompConfig::from_args(false, None, true),
+            Ok(SeccompConfig::Learning)
+        ));
     }
 }
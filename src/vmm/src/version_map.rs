@@ -6,10 +6,15 @@
 use std::collections::HashMap;
 
 use crate::device_manager::persist::DeviceStates;
+use crate::persist::MicrovmState;
 #[cfg(target_arch = "x86_64")]
 use crate::vstate::vcpu::VcpuState;
-use devices::virtio::block::persist::BlockState;
+use devices::virtio::block::persist::{BlockState, FileEngineTypeState};
+use devices::virtio::net::persist::NetState;
+use devices::virtio::vsock::persist::{VsockBackendState, VsockFrontendState, VsockUdsState};
 use devices::virtio::QueueState;
+use mmds::persist::MmdsNetworkStackState;
+use rate_limiter::persist::RateLimiterState;
 
 use lazy_static::lazy_static;
 use versionize::VersionMap;
@@ -48,6 +53,15 @@ lazy_static! {
 
         // v1.1 state change mappings.
         version_map.new_version().set_type_version(DeviceStates::type_id(), 3);
+        version_map.set_type_version(NetState::type_id(), 2);
+        version_map.set_type_version(MmdsNetworkStackState::type_id(), 2);
+        version_map.set_type_version(MicrovmState::type_id(), 2);
+        version_map.set_type_version(VsockBackendState::type_id(), 2);
+        version_map.set_type_version(VsockFrontendState::type_id(), 2);
+        version_map.set_type_version(VsockUdsState::type_id(), 2);
+        version_map.set_type_version(RateLimiterState::type_id(), 2);
+        version_map.set_type_version(BlockState::type_id(), 4);
+        version_map.set_type_version(FileEngineTypeState::type_id(), 2);
 
         version_map
     };
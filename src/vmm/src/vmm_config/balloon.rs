@@ -24,6 +24,8 @@ pub enum BalloonConfigError {
     InvalidStatsUpdate,
     /// Amount of pages requested is too large.
     TooManyPagesRequested,
+    /// A drive registers the guest memory, which pins it on the host.
+    FixedBuffersDrive(String),
     /// The user polled the statistics of a balloon device that
     /// does not have the statistics enabled.
     StatsNotFound,
@@ -44,6 +46,11 @@ impl fmt::Display for BalloonConfigError {
             ),
             InvalidStatsUpdate => write!(f, "Cannot enable/disable the statistics after boot."),
             TooManyPagesRequested => write!(f, "Amount of pages requested is too large."),
+            FixedBuffersDrive(drive_id) => write!(
+                f,
+                "Drive {} uses fixed buffers, which the balloon cannot reclaim.",
+                drive_id
+            ),
             StatsNotFound => write!(f, "Statistics for the balloon device are not enabled"),
             CreateFailure(e) => write!(f, "Error creating the balloon device: {:?}", e),
             UpdateFailure(e) => write!(
@@ -250,6 +257,9 @@ pub(crate) mod tests {
         let err = TooManyPagesRequested;
         let _ = format!("{}{:?}", err, err);
 
+        let err = FixedBuffersDrive("root".to_string());
+        let _ = format!("{}{:?}", err, err);
+
         let err = StatsNotFound;
         let _ = format!("{}{:?}", err, err);
     }
@@ -0,0 +1,541 @@
+// Copyright 2022 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+use std::collections::HashSet;
+use std::fmt;
+use std::fs::File;
+use std::io;
+use std::path::Path;
+
+#[cfg(target_arch = "x86_64")]
+use kvm_bindings::CpuId;
+use serde::{de, Deserialize, Deserializer, Serialize};
+use versionize::{VersionMap, Versionize, VersionizeResult};
+use versionize_derive::Versionize;
+
+#[cfg(target_arch = "x86_64")]
+/// CPUID registers which hold feature bits that the host must support for a template to be
+/// applicable. Other registers (e.g. family/model/stepping) are not checked against the host.
+pub(crate) const CPUID_FEATURE_REGISTERS: &[(u32, u32, CpuidRegister)] = &[
+    (0x1, 0x0, CpuidRegister::Ecx),
+    (0x1, 0x0, CpuidRegister::Edx),
+    (0x7, 0x0, CpuidRegister::Ebx),
+    (0x7, 0x0, CpuidRegister::Ecx),
+    (0x7, 0x0, CpuidRegister::Edx),
+    (0xd, 0x1, CpuidRegister::Eax),
+    (0x8000_0001, 0x0, CpuidRegister::Ecx),
+    (0x8000_0001, 0x0, CpuidRegister::Edx),
+];
+
+/// Errors associated with custom CPU templates.
+#[derive(Debug)]
+pub enum CpuTemplateError {
+    /// A bitmap modifying a CPUID register covers more than 32 bits.
+    CpuidBitmapTooWide(u32, u32, CpuidRegister),
+    /// A CPUID leaf modified by the template is not present.
+    CpuidLeafNotFound(u32, u32),
+    /// The same CPUID register is modified more than once.
+    DuplicateCpuidModifier(u32, u32, CpuidRegister),
+    /// The same MSR is modified more than once.
+    DuplicateMsrModifier(u32),
+    /// Failed to open the template file.
+    OpenFile(io::Error),
+    /// Failed to parse the template document.
+    Parse(serde_json::Error),
+    /// The template sets CPUID feature bits which are not supported by the host.
+    UnsupportedCpuidBits(u32, u32, CpuidRegister, u32),
+    /// The template modifies an MSR which is not supported by the host.
+    UnsupportedMsr(u32),
+}
+
+impl fmt::Display for CpuTemplateError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::CpuTemplateError::*;
+        match self {
+            CpuidBitmapTooWide(leaf, subleaf, register) => write!(
+                f,
+                "The bitmap for CPUID leaf {:#x}, subleaf {:#x}, register {} is wider than 32 \
+                 bits.",
+                leaf, subleaf, register
+            ),
+            CpuidLeafNotFound(leaf, subleaf) => write!(
+                f,
+                "CPUID leaf {:#x}, subleaf {:#x} is not available.",
+                leaf, subleaf
+            ),
+            DuplicateCpuidModifier(leaf, subleaf, register) => write!(
+                f,
+                "CPUID leaf {:#x}, subleaf {:#x}, register {} is modified more than once.",
+                leaf, subleaf, register
+            ),
+            DuplicateMsrModifier(addr) => write!(f, "MSR {:#x} is modified more than once.", addr),
+            OpenFile(err) => write!(f, "Cannot open the CPU template file: {}", err),
+            Parse(err) => write!(f, "Cannot parse the CPU template: {}", err),
+            UnsupportedCpuidBits(leaf, subleaf, register, bits) => write!(
+                f,
+                "CPUID leaf {:#x}, subleaf {:#x}, register {} bits {:#x} are not supported by the \
+                 host.",
+                leaf, subleaf, register, bits
+            ),
+            UnsupportedMsr(addr) => write!(f, "MSR {:#x} is not supported by the host.", addr),
+        }
+    }
+}
+
+type Result<T> = std::result::Result<T, CpuTemplateError>;
+
+/// A CPUID register.
+#[derive(Clone, Copy, Debug, Deserialize, Eq, Hash, PartialEq, Serialize, Versionize)]
+#[serde(rename_all = "lowercase")]
+pub enum CpuidRegister {
+    /// EAX register.
+    Eax,
+    /// EBX register.
+    Ebx,
+    /// ECX register.
+    Ecx,
+    /// EDX register.
+    Edx,
+}
+
+impl fmt::Display for CpuidRegister {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CpuidRegister::Eax => write!(f, "eax"),
+            CpuidRegister::Ebx => write!(f, "ebx"),
+            CpuidRegister::Ecx => write!(f, "ecx"),
+            CpuidRegister::Edx => write!(f, "edx"),
+        }
+    }
+}
+
+/// Bit-level modifier of a register value.
+///
+/// It is deserialized from a string of `0`, `1` and `x` characters, most significant bit
+/// first, optionally prefixed by `0b` and grouped with `_`. An `x` leaves the corresponding bit
+/// unchanged, as do the bits above the length of the string.
+#[derive(Clone, Debug, Default, PartialEq, Versionize)]
+pub struct RegisterValueFilter {
+    /// Mask of the bits that are overwritten.
+    pub filter: u64,
+    /// Value of the overwritten bits.
+    pub value: u64,
+}
+
+impl RegisterValueFilter {
+    /// Returns `register` with the filtered bits replaced.
+    pub fn apply(&self, register: u64) -> u64 {
+        (register & !self.filter) | self.value
+    }
+}
+
+impl<'de> Deserialize<'de> for RegisterValueFilter {
+    fn deserialize<D>(deserializer: D) -> std::result::Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let bitmap = String::deserialize(deserializer)?;
+        let bits = bitmap
+            .strip_prefix("0b")
+            .unwrap_or(&bitmap)
+            .chars()
+            .filter(|c| *c != '_')
+            .collect::<Vec<char>>();
+        if bits.is_empty() || bits.len() > 64 {
+            return Err(de::Error::invalid_length(
+                bits.len(),
+                &"a bitmap of 1 to 64 bits",
+            ));
+        }
+
+        let mut filter = RegisterValueFilter::default();
+        for (pos, bit) in bits.iter().rev().enumerate() {
+            match bit {
+                '0' => filter.filter |= 1 << pos,
+                '1' => {
+                    filter.filter |= 1 << pos;
+                    filter.value |= 1 << pos;
+                }
+                'x' => {}
+                _ => {
+                    return Err(de::Error::invalid_value(
+                        de::Unexpected::Char(*bit),
+                        &"one of `0`, `1` or `x`",
+                    ))
+                }
+            }
+        }
+        Ok(filter)
+    }
+}
+
+/// Modifier of a single CPUID register.
+#[derive(Clone, Debug, Deserialize, PartialEq, Versionize)]
+#[serde(deny_unknown_fields)]
+pub struct CpuidRegisterModifier {
+    /// The modified register.
+    pub register: CpuidRegister,
+    /// The bits written to the register.
+    pub bitmap: RegisterValueFilter,
+}
+
+/// Modifiers of the registers of a CPUID leaf.
+#[derive(Clone, Debug, Deserialize, PartialEq, Versionize)]
+#[serde(deny_unknown_fields)]
+pub struct CpuidLeafModifier {
+    /// The CPUID leaf (function).
+    #[serde(deserialize_with = "deserialize_u32")]
+    pub leaf: u32,
+    /// The CPUID subleaf (index).
+    #[serde(default, deserialize_with = "deserialize_u32")]
+    pub subleaf: u32,
+    /// The register modifiers.
+    pub modifiers: Vec<CpuidRegisterModifier>,
+}
+
+/// Modifier of a Model Specific Register.
+#[derive(Clone, Debug, Deserialize, PartialEq, Versionize)]
+#[serde(deny_unknown_fields)]
+pub struct MsrModifier {
+    /// The MSR address.
+    #[serde(deserialize_with = "deserialize_u32")]
+    pub addr: u32,
+    /// The bits written to the MSR.
+    pub bitmap: RegisterValueFilter,
+}
+
+/// A user-defined CPU template, applied on top of the default CPUID and MSR configuration.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Versionize)]
+#[serde(deny_unknown_fields)]
+// NOTICE: Any changes to this structure require a snapshot version bump.
+pub struct CustomCpuTemplate {
+    /// CPUID modifiers.
+    #[serde(default)]
+    pub cpuid_modifiers: Vec<CpuidLeafModifier>,
+    /// MSR modifiers.
+    #[serde(default)]
+    pub msr_modifiers: Vec<MsrModifier>,
+}
+
+impl CustomCpuTemplate {
+    /// Loads and validates a template from the JSON document at `path`.
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let file = File::open(path).map_err(CpuTemplateError::OpenFile)?;
+        let template: CustomCpuTemplate =
+            serde_json::from_reader(file).map_err(CpuTemplateError::Parse)?;
+        template.validate()?;
+        Ok(template)
+    }
+
+    /// Checks that every register is modified at most once and that the CPUID bitmaps fit
+    /// the 32-bit registers.
+    pub fn validate(&self) -> Result<()> {
+        let mut cpuid_registers = HashSet::new();
+        for leaf_modifier in self.cpuid_modifiers.iter() {
+            for modifier in leaf_modifier.modifiers.iter() {
+                let key = (leaf_modifier.leaf, leaf_modifier.subleaf, modifier.register);
+                if !cpuid_registers.insert(key) {
+                    return Err(CpuTemplateError::DuplicateCpuidModifier(
+                        key.0, key.1, key.2,
+                    ));
+                }
+                if modifier.bitmap.filter > u64::from(u32::max_value()) {
+                    return Err(CpuTemplateError::CpuidBitmapTooWide(key.0, key.1, key.2));
+                }
+            }
+        }
+
+        let mut msrs = HashSet::new();
+        for modifier in self.msr_modifiers.iter() {
+            if !msrs.insert(modifier.addr) {
+                return Err(CpuTemplateError::DuplicateMsrModifier(modifier.addr));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Applies the CPUID modifiers to `cpuid`.
+    #[cfg(target_arch = "x86_64")]
+    pub fn apply_to_cpuid(&self, cpuid: &mut CpuId) -> Result<()> {
+        for leaf_modifier in self.cpuid_modifiers.iter() {
+            let entry = cpuid
+                .as_mut_slice()
+                .iter_mut()
+                .find(|entry| {
+                    entry.function == leaf_modifier.leaf && entry.index == leaf_modifier.subleaf
+                })
+                .ok_or(CpuTemplateError::CpuidLeafNotFound(
+                    leaf_modifier.leaf,
+                    leaf_modifier.subleaf,
+                ))?;
+            for modifier in leaf_modifier.modifiers.iter() {
+                let register = match modifier.register {
+                    CpuidRegister::Eax => &mut entry.eax,
+                    CpuidRegister::Ebx => &mut entry.ebx,
+                    CpuidRegister::Ecx => &mut entry.ecx,
+                    CpuidRegister::Edx => &mut entry.edx,
+                };
+                *register = modifier.bitmap.apply(u64::from(*register)) as u32;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Checks that the host can run a guest configured with this template, given the CPUID
+    /// supported by KVM and the MSRs KVM can save and restore.
+    #[cfg(target_arch = "x86_64")]
+    pub fn check_host_support(
+        &self,
+        supported_cpuid: &CpuId,
+        supported_msrs: &[u32],
+    ) -> Result<()> {
+        for leaf_modifier in self.cpuid_modifiers.iter() {
+            let (leaf, subleaf) = (leaf_modifier.leaf, leaf_modifier.subleaf);
+            let entry = supported_cpuid
+                .as_slice()
+                .iter()
+                .find(|entry| entry.function == leaf && entry.index == subleaf)
+                .ok_or(CpuTemplateError::CpuidLeafNotFound(leaf, subleaf))?;
+            for modifier in leaf_modifier.modifiers.iter() {
+                if !CPUID_FEATURE_REGISTERS.contains(&(leaf, subleaf, modifier.register)) {
+                    continue;
+                }
+                let supported = match modifier.register {
+                    CpuidRegister::Eax => entry.eax,
+                    CpuidRegister::Ebx => entry.ebx,
+                    CpuidRegister::Ecx => entry.ecx,
+                    CpuidRegister::Edx => entry.edx,
+                };
+                let unsupported = modifier.bitmap.value as u32 & !supported;
+                if unsupported != 0 {
+                    return Err(CpuTemplateError::UnsupportedCpuidBits(
+                        leaf,
+                        subleaf,
+                        modifier.register,
+                        unsupported,
+                    ));
+                }
+            }
+        }
+
+        for modifier in self.msr_modifiers.iter() {
+            if !supported_msrs.contains(&modifier.addr) {
+                return Err(CpuTemplateError::UnsupportedMsr(modifier.addr));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Deserializes a `u32` given either as a JSON number or as a hexadecimal string (e.g. "0x7").
+fn deserialize_u32<'de, D>(d: D) -> std::result::Result<u32, D::Error>
+where
+    D: de::Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Number {
+        Int(u32),
+        Str(String),
+    }
+
+    match Number::deserialize(d)? {
+        Number::Int(val) => Ok(val),
+        Number::Str(s) => {
+            let digits = s
+                .strip_prefix("0x")
+                .or_else(|| s.strip_prefix("0X"))
+                .ok_or_else(|| {
+                    de::Error::invalid_value(de::Unexpected::Str(&s), &"a hexadecimal number")
+                })?;
+            u32::from_str_radix(digits, 16).map_err(|_| {
+                de::Error::invalid_value(de::Unexpected::Str(&s), &"a hexadecimal number")
+            })
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use utils::tempfile::TempFile;
+
+    const TEMPLATE: &str = r#"{
+        "cpuid_modifiers": [
+            {
+                "leaf": "0x1",
+                "subleaf": "0x0",
+                "modifiers": [
+                    { "register": "ecx", "bitmap": "0bx0xx_xxxx_xxxx_xxxx_xxxx_xxxx_xxxx_xxx1" }
+                ]
+            },
+            {
+                "leaf": 7,
+                "modifiers": [
+                    { "register": "ebx", "bitmap": "0b0" },
+                    { "register": "edx", "bitmap": "1x" }
+                ]
+            }
+        ],
+        "msr_modifiers": [
+            { "addr": "0x10a", "bitmap": "0b1xxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxx0" }
+        ]
+    }"#;
+
+    #[test]
+    fn test_deserialize_template() {
+        let template: CustomCpuTemplate = serde_json::from_str(TEMPLATE).unwrap();
+        template.validate().unwrap();
+
+        assert_eq!(template.cpuid_modifiers.len(), 2);
+        let leaf = &template.cpuid_modifiers[0];
+        assert_eq!((leaf.leaf, leaf.subleaf), (0x1, 0x0));
+        assert_eq!(leaf.modifiers[0].register, CpuidRegister::Ecx);
+        assert_eq!(
+            leaf.modifiers[0].bitmap,
+            RegisterValueFilter {
+                filter: 0x4000_0001,
+                value: 0x1,
+            }
+        );
+        let leaf = &template.cpuid_modifiers[1];
+        assert_eq!((leaf.leaf, leaf.subleaf), (0x7, 0x0));
+        assert_eq!(
+            leaf.modifiers[1].bitmap,
+            RegisterValueFilter {
+                filter: 0x2,
+                value: 0x2,
+            }
+        );
+
+        let msr = &template.msr_modifiers[0];
+        assert_eq!(msr.addr, 0x10a);
+        assert_eq!(msr.bitmap.filter, 0x8000_0000_0000_0001);
+        assert_eq!(msr.bitmap.value, 0x8000_0000_0000_0000);
+        assert_eq!(msr.bitmap.apply(0x3), 0x8000_0000_0000_0002);
+
+        // Invalid bitmaps.
+        for bitmap in &["", "0b", "0b012", "x".repeat(65).as_str()] {
+            let json = format!(r#"{{"addr": 1, "bitmap": "{}"}}"#, bitmap);
+            assert!(serde_json::from_str::<MsrModifier>(&json).is_err());
+        }
+        // Invalid addresses.
+        for addr in &["\"10a\"", "\"0xzz\"", "-1"] {
+            let json = format!(r#"{{"addr": {}, "bitmap": "1"}}"#, addr);
+            assert!(serde_json::from_str::<MsrModifier>(&json).is_err());
+        }
+        // Unknown fields.
+        assert!(serde_json::from_str::<CustomCpuTemplate>(r#"{"msrs": []}"#).is_err());
+    }
+
+    #[test]
+    fn test_validate_template() {
+        let mut template: CustomCpuTemplate = serde_json::from_str(TEMPLATE).unwrap();
+        template
+            .msr_modifiers
+            .push(template.msr_modifiers[0].clone());
+        assert_eq!(
+            template.validate().unwrap_err().to_string(),
+            "MSR 0x10a is modified more than once."
+        );
+
+        let mut template: CustomCpuTemplate = serde_json::from_str(TEMPLATE).unwrap();
+        let modifier = template.cpuid_modifiers[0].modifiers[0].clone();
+        template.cpuid_modifiers[0].modifiers.push(modifier);
+        assert_eq!(
+            template.validate().unwrap_err().to_string(),
+            "CPUID leaf 0x1, subleaf 0x0, register ecx is modified more than once."
+        );
+
+        let mut template: CustomCpuTemplate = serde_json::from_str(TEMPLATE).unwrap();
+        template.cpuid_modifiers[0].modifiers[0].bitmap.filter = 1 << 32;
+        assert_eq!(
+            template.validate().unwrap_err().to_string(),
+            "The bitmap for CPUID leaf 0x1, subleaf 0x0, register ecx is wider than 32 bits."
+        );
+    }
+
+    #[test]
+    fn test_template_from_file() {
+        let file = TempFile::new().unwrap();
+        assert!(matches!(
+            CustomCpuTemplate::from_file(file.as_path()),
+            Err(CpuTemplateError::Parse(_))
+        ));
+
+        std::fs::write(file.as_path(), TEMPLATE).unwrap();
+        let template = CustomCpuTemplate::from_file(file.as_path()).unwrap();
+        assert_eq!(template.msr_modifiers.len(), 1);
+
+        assert!(matches!(
+            CustomCpuTemplate::from_file("/invalid/path"),
+            Err(CpuTemplateError::OpenFile(_))
+        ));
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    #[test]
+    fn test_apply_template() {
+        use kvm_bindings::kvm_cpuid_entry2;
+
+        let template: CustomCpuTemplate = serde_json::from_str(TEMPLATE).unwrap();
+        let mut cpuid = CpuId::from_entries(&[
+            kvm_cpuid_entry2 {
+                function: 0x1,
+                ecx: 0x4000_0001,
+                ..Default::default()
+            },
+            kvm_cpuid_entry2 {
+                function: 0x7,
+                ebx: 0xffff_ffff,
+                edx: 0x1,
+                ..Default::default()
+            },
+        ])
+        .unwrap();
+
+        // The host supports the bits the template sets, except for leaf 0x7 edx bit 1.
+        assert_eq!(
+            template
+                .check_host_support(&cpuid, &[0x10a])
+                .unwrap_err()
+                .to_string(),
+            "CPUID leaf 0x7, subleaf 0x0, register edx bits 0x2 are not supported by the host."
+        );
+        cpuid.as_mut_slice()[1].edx = 0x2;
+        assert_eq!(
+            template
+                .check_host_support(&cpuid, &[])
+                .unwrap_err()
+                .to_string(),
+            "MSR 0x10a is not supported by the host."
+        );
+        // Leaf 0x1 ecx bit 0 is a feature bit.
+        cpuid.as_mut_slice()[0].ecx = 0x0;
+        assert!(template.check_host_support(&cpuid, &[0x10a]).is_err());
+        cpuid.as_mut_slice()[0].ecx = 0x1;
+        template.check_host_support(&cpuid, &[0x10a]).unwrap();
+
+        cpuid.as_mut_slice()[0].ecx = 0x4000_0001;
+        cpuid.as_mut_slice()[1].edx = 0x1;
+        template.apply_to_cpuid(&mut cpuid).unwrap();
+        let entries = cpuid.as_slice();
+        assert_eq!(entries[0].ecx, 0x1);
+        assert_eq!(entries[1].ebx, 0xffff_fffe);
+        assert_eq!(entries[1].edx, 0x3);
+
+        let mut cpuid = CpuId::from_entries(&[kvm_cpuid_entry2 {
+            function: 0x1,
+            ..Default::default()
+        }])
+        .unwrap();
+        assert_eq!(
+            template.apply_to_cpuid(&mut cpuid).unwrap_err().to_string(),
+            "CPUID leaf 0x7, subleaf 0x0 is not available."
+        );
+    }
+}
@@ -9,9 +9,10 @@ use std::path::PathBuf;
 use std::result;
 use std::sync::{Arc, Mutex};
 
-use super::RateLimiterConfig;
+use super::rate_limiter_group::{RateLimiterGroupBuilder, RateLimiterGroupError};
+use super::{RateLimiterConfig, RateLimiterStatus};
 use crate::Error as VmmError;
-use devices::virtio::block::Error as BlockError;
+use devices::virtio::block::{Error as BlockError, DEFAULT_NUM_QUEUES};
 use devices::virtio::Block;
 
 pub use devices::virtio::block::device::FileEngineType;
@@ -32,12 +33,25 @@ pub enum DriveError {
     CreateBlockDevice(BlockError),
     /// Failed to create a `RateLimiter` object.
     CreateRateLimiter(io::Error),
+    /// No drive with the given ID exists.
+    DeviceNotFound(String),
     /// Error during drive update (patch).
     DeviceUpdate(VmmError),
+    /// NBD exports aren't backed by a local file, which could be opened with `O_DIRECT`.
+    DirectIoWithNbd,
+    /// Registered guest memory is pinned, so it can't be reclaimed by the balloon.
+    FixedBuffersWithBalloon,
+    /// Only the `Async` engine can register the guest memory, or have its submission queue
+    /// polled.
+    IoUringOptionWithoutAsync,
+    /// IO threads can't be used along with rate limiting.
+    IoThreadsWithRateLimiter,
     /// The block device path is invalid.
     InvalidBlockDevicePath(String),
     /// Cannot open block device due to invalid permissions or path.
     OpenBlockDevice(io::Error),
+    /// The rate limiter group referenced by the drive is not valid.
+    RateLimiterGroup(RateLimiterGroupError),
     /// A root block device was already added.
     RootBlockDeviceAlreadyAdded,
 }
@@ -49,13 +63,28 @@ impl Display for DriveError {
             CreateBlockDevice(e) => write!(f, "Unable to create the block device {:?}", e),
             BlockDeviceUpdateFailed(e) => write!(f, "The update operation failed: {}", e),
             CreateRateLimiter(e) => write!(f, "Cannot create RateLimiter: {}", e),
+            DeviceNotFound(id) => write!(f, "Drive {} does not exist", id),
             DeviceUpdate(e) => write!(f, "Error during drive update (patch): {}", e),
+            DirectIoWithNbd => write!(f, "Direct IO is not supported on NBD block devices"),
+            FixedBuffersWithBalloon => write!(
+                f,
+                "Fixed buffers are not supported along with a balloon device"
+            ),
+            IoUringOptionWithoutAsync => write!(
+                f,
+                "Fixed buffers and submission queue polling require the Async io engine"
+            ),
+            IoThreadsWithRateLimiter => write!(
+                f,
+                "IO threads are not supported on rate limited block devices"
+            ),
             InvalidBlockDevicePath(path) => write!(f, "Invalid block device path: {}", path),
             OpenBlockDevice(e) => write!(
                 f,
                 "Cannot open block device. Invalid permission/path: {}",
                 e
             ),
+            RateLimiterGroup(e) => write!(f, "Invalid rate limiter group: {}", e),
             RootBlockDeviceAlreadyAdded => write!(f, "A root block device already exists!"),
         }
     }
@@ -85,10 +114,40 @@ pub struct BlockDeviceConfig {
     pub cache_type: CacheType,
     /// Rate Limiter for I/O operations.
     pub rate_limiter: Option<RateLimiterConfig>,
+    /// Rate limiter group whose limits are shared with other devices.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rate_limiter_group: Option<String>,
     /// The type of IO engine used by the device.
     #[serde(default)]
     #[serde(rename = "io_engine")]
     pub file_engine_type: FileEngineType,
+    /// If set to true, the drive is opened with `O_DIRECT`, bypassing the host page cache.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub direct_io: bool,
+    /// The number of request queues exposed to the guest.
+    #[serde(
+        default = "default_num_queues",
+        skip_serializing_if = "is_default_num_queues"
+    )]
+    pub num_queues: u16,
+    /// If set to true, each queue is processed on a dedicated thread.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub io_threads: bool,
+    /// If set to true, the guest memory is registered with the `Async` engine, which pins it on
+    /// the host.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub fixed_buffers: bool,
+    /// If set to true, the submission queues of the `Async` engine are polled by kernel threads.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub sq_poll: bool,
+}
+
+fn default_num_queues() -> u16 {
+    DEFAULT_NUM_QUEUES
+}
+
+fn is_default_num_queues(num_queues: &u16) -> bool {
+    *num_queues == DEFAULT_NUM_QUEUES
 }
 
 impl From<&Block> for BlockDeviceConfig {
@@ -102,7 +161,35 @@ impl From<&Block> for BlockDeviceConfig {
             is_read_only: block.is_read_only(),
             cache_type: block.cache_type(),
             rate_limiter: rl.into_option(),
+            rate_limiter_group: block
+                .rate_limiter()
+                .group()
+                .map(|group| group.name().to_string()),
             file_engine_type: block.file_engine_type(),
+            direct_io: block.direct_io(),
+            num_queues: block.num_queues(),
+            io_threads: block.io_threads(),
+            fixed_buffers: block.fixed_buffers(),
+            sq_poll: block.sq_poll(),
+        }
+    }
+}
+
+/// The live configuration and rate limiter state of a block device.
+#[derive(Debug, PartialEq, Serialize)]
+pub struct BlockDeviceInfo {
+    /// Current configuration of the device.
+    #[serde(flatten)]
+    pub config: BlockDeviceConfig,
+    /// Current state of the rate limiter of the device.
+    pub rate_limiter_status: RateLimiterStatus,
+}
+
+impl From<&Block> for BlockDeviceInfo {
+    fn from(block: &Block) -> Self {
+        BlockDeviceInfo {
+            config: BlockDeviceConfig::from(block),
+            rate_limiter_status: RateLimiterStatus::from(block.rate_limiter()),
         }
     }
 }
@@ -168,7 +255,11 @@ impl BlockBuilder {
     /// Inserts a `Block` in the block devices list using the specified configuration.
     /// If a block with the same id already exists, it will overwrite it.
     /// Inserting a secondary root block device will fail.
-    pub fn insert(&mut self, config: BlockDeviceConfig) -> Result<()> {
+    pub fn insert(
+        &mut self,
+        config: BlockDeviceConfig,
+        rate_limiter_groups: &RateLimiterGroupBuilder,
+    ) -> Result<()> {
         let is_root_device = config.is_root_device;
         let position = self.get_index_of_drive_id(&config.drive_id);
         let has_root_block = self.has_root_device();
@@ -179,7 +270,7 @@ impl BlockBuilder {
             return Err(DriveError::RootBlockDeviceAlreadyAdded);
         }
 
-        let block_dev = Arc::new(Mutex::new(Self::create_block(config)?));
+        let block_dev = Arc::new(Mutex::new(Self::create_block(config, rate_limiter_groups)?));
         // If the id of the drive already exists in the list, the operation is update/overwrite.
         match position {
             // New block device.
@@ -205,7 +296,27 @@ impl BlockBuilder {
     }
 
     /// Creates a Block device from a BlockDeviceConfig.
-    pub fn create_block(block_device_config: BlockDeviceConfig) -> Result<Block> {
+    pub fn create_block(
+        block_device_config: BlockDeviceConfig,
+        rate_limiter_groups: &RateLimiterGroupBuilder,
+    ) -> Result<Block> {
+        if block_device_config.io_threads
+            && (block_device_config.rate_limiter.is_some()
+                || block_device_config.rate_limiter_group.is_some())
+        {
+            return Err(DriveError::IoThreadsWithRateLimiter);
+        }
+        if block_device_config.direct_io
+            && block_device_config.file_engine_type == FileEngineType::Nbd
+        {
+            return Err(DriveError::DirectIoWithNbd);
+        }
+        if (block_device_config.fixed_buffers || block_device_config.sq_poll)
+            && block_device_config.file_engine_type != FileEngineType::Async
+        {
+            return Err(DriveError::IoUringOptionWithoutAsync);
+        }
+
         // check if the path exists
         let path_on_host = PathBuf::from(&block_device_config.path_on_host);
         if !path_on_host.exists() {
@@ -220,6 +331,11 @@ impl BlockBuilder {
             .map(super::RateLimiterConfig::try_into)
             .transpose()
             .map_err(DriveError::CreateRateLimiter)?;
+        let rate_limiter_group = rate_limiter_groups
+            .resolve(block_device_config.rate_limiter_group.as_deref())
+            .map_err(DriveError::RateLimiterGroup)?;
+        let mut rate_limiter = rate_limiter.unwrap_or_default();
+        rate_limiter.set_group(rate_limiter_group);
 
         // Create and return the Block device
         devices::virtio::Block::new(
@@ -229,8 +345,13 @@ impl BlockBuilder {
             block_device_config.path_on_host,
             block_device_config.is_read_only,
             block_device_config.is_root_device,
-            rate_limiter.unwrap_or_default(),
+            rate_limiter,
             block_device_config.file_engine_type,
+            block_device_config.direct_io,
+            block_device_config.num_queues,
+            block_device_config.io_threads,
+            block_device_config.fixed_buffers,
+            block_device_config.sq_poll,
         )
         .map_err(DriveError::CreateBlockDevice)
     }
@@ -243,11 +364,22 @@ impl BlockBuilder {
         }
         ret
     }
+
+    /// Returns the live configuration and rate limiter state of the device with `drive_id`.
+    pub fn info(&self, drive_id: &str) -> Result<BlockDeviceInfo> {
+        self.list
+            .iter()
+            .map(|block| block.lock().expect("Poisoned lock"))
+            .find(|block| block.id() == drive_id)
+            .map(|block| BlockDeviceInfo::from(block.deref()))
+            .ok_or_else(|| DriveError::DeviceNotFound(drive_id.to_string()))
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::vmm_config::rate_limiter_group::RateLimiterGroupConfig;
     use rate_limiter::RateLimiter;
     use utils::tempfile::TempFile;
 
@@ -269,7 +401,13 @@ mod tests {
                 is_read_only: self.is_read_only,
                 drive_id: self.drive_id.clone(),
                 rate_limiter: None,
-                file_engine_type: FileEngineType::default(),
+                rate_limiter_group: self.rate_limiter_group.clone(),
+                file_engine_type: self.file_engine_type,
+                direct_io: self.direct_io,
+                num_queues: self.num_queues,
+                io_threads: self.io_threads,
+                fixed_buffers: self.fixed_buffers,
+                sq_poll: self.sq_poll,
             }
         }
     }
@@ -293,11 +431,19 @@ mod tests {
             is_read_only: false,
             drive_id: dummy_id.clone(),
             rate_limiter: None,
+            rate_limiter_group: None,
             file_engine_type: FileEngineType::default(),
+            direct_io: false,
+            num_queues: 1,
+            io_threads: false,
+            fixed_buffers: false,
+            sq_poll: false,
         };
 
         let mut block_devs = BlockBuilder::new();
-        assert!(block_devs.insert(dummy_block_device.clone()).is_ok());
+        assert!(block_devs
+            .insert(dummy_block_device.clone(), &Default::default())
+            .is_ok());
 
         assert!(!block_devs.has_root_device());
         assert_eq!(block_devs.list.len(), 1);
@@ -324,11 +470,19 @@ mod tests {
             is_read_only: true,
             drive_id: String::from("1"),
             rate_limiter: None,
+            rate_limiter_group: None,
             file_engine_type: FileEngineType::default(),
+            direct_io: false,
+            num_queues: 1,
+            io_threads: false,
+            fixed_buffers: false,
+            sq_poll: false,
         };
 
         let mut block_devs = BlockBuilder::new();
-        assert!(block_devs.insert(dummy_block_device.clone()).is_ok());
+        assert!(block_devs
+            .insert(dummy_block_device.clone(), &Default::default())
+            .is_ok());
 
         assert!(block_devs.has_root_device());
         assert_eq!(block_devs.list.len(), 1);
@@ -352,7 +506,13 @@ mod tests {
             is_read_only: false,
             drive_id: String::from("1"),
             rate_limiter: None,
+            rate_limiter_group: None,
             file_engine_type: FileEngineType::default(),
+            direct_io: false,
+            num_queues: 1,
+            io_threads: false,
+            fixed_buffers: false,
+            sq_poll: false,
         };
 
         let dummy_file_2 = TempFile::new().unwrap();
@@ -365,13 +525,23 @@ mod tests {
             is_read_only: false,
             drive_id: String::from("2"),
             rate_limiter: None,
+            rate_limiter_group: None,
             file_engine_type: FileEngineType::default(),
+            direct_io: false,
+            num_queues: 1,
+            io_threads: false,
+            fixed_buffers: false,
+            sq_poll: false,
         };
 
         let mut block_devs = BlockBuilder::new();
-        assert!(block_devs.insert(root_block_device_1).is_ok());
+        assert!(block_devs
+            .insert(root_block_device_1, &Default::default())
+            .is_ok());
         assert_eq!(
-            block_devs.insert(root_block_device_2).unwrap_err(),
+            block_devs
+                .insert(root_block_device_2, &Default::default())
+                .unwrap_err(),
             DriveError::RootBlockDeviceAlreadyAdded
         );
     }
@@ -389,7 +559,13 @@ mod tests {
             is_read_only: false,
             drive_id: String::from("1"),
             rate_limiter: None,
+            rate_limiter_group: None,
             file_engine_type: FileEngineType::default(),
+            direct_io: false,
+            num_queues: 1,
+            io_threads: false,
+            fixed_buffers: false,
+            sq_poll: false,
         };
 
         let dummy_file_2 = TempFile::new().unwrap();
@@ -402,7 +578,13 @@ mod tests {
             is_read_only: false,
             drive_id: String::from("2"),
             rate_limiter: None,
+            rate_limiter_group: None,
             file_engine_type: FileEngineType::default(),
+            direct_io: false,
+            num_queues: 1,
+            io_threads: false,
+            fixed_buffers: false,
+            sq_poll: false,
         };
 
         let dummy_file_3 = TempFile::new().unwrap();
@@ -415,13 +597,25 @@ mod tests {
             is_read_only: false,
             drive_id: String::from("3"),
             rate_limiter: None,
+            rate_limiter_group: None,
             file_engine_type: FileEngineType::default(),
+            direct_io: false,
+            num_queues: 1,
+            io_threads: false,
+            fixed_buffers: false,
+            sq_poll: false,
         };
 
         let mut block_devs = BlockBuilder::new();
-        assert!(block_devs.insert(dummy_block_dev_2.clone()).is_ok());
-        assert!(block_devs.insert(dummy_block_dev_3.clone()).is_ok());
-        assert!(block_devs.insert(root_block_device.clone()).is_ok());
+        assert!(block_devs
+            .insert(dummy_block_dev_2.clone(), &Default::default())
+            .is_ok());
+        assert!(block_devs
+            .insert(dummy_block_dev_3.clone(), &Default::default())
+            .is_ok());
+        assert!(block_devs
+            .insert(root_block_device.clone(), &Default::default())
+            .is_ok());
 
         assert_eq!(block_devs.list.len(), 3);
 
@@ -453,7 +647,13 @@ mod tests {
             is_read_only: false,
             drive_id: String::from("1"),
             rate_limiter: None,
+            rate_limiter_group: None,
             file_engine_type: FileEngineType::default(),
+            direct_io: false,
+            num_queues: 1,
+            io_threads: false,
+            fixed_buffers: false,
+            sq_poll: false,
         };
 
         let dummy_file_2 = TempFile::new().unwrap();
@@ -466,7 +666,13 @@ mod tests {
             is_read_only: false,
             drive_id: String::from("2"),
             rate_limiter: None,
+            rate_limiter_group: None,
             file_engine_type: FileEngineType::default(),
+            direct_io: false,
+            num_queues: 1,
+            io_threads: false,
+            fixed_buffers: false,
+            sq_poll: false,
         };
 
         let dummy_file_3 = TempFile::new().unwrap();
@@ -479,13 +685,25 @@ mod tests {
             is_read_only: false,
             drive_id: String::from("3"),
             rate_limiter: None,
+            rate_limiter_group: None,
             file_engine_type: FileEngineType::default(),
+            direct_io: false,
+            num_queues: 1,
+            io_threads: false,
+            fixed_buffers: false,
+            sq_poll: false,
         };
 
         let mut block_devs = BlockBuilder::new();
-        assert!(block_devs.insert(dummy_block_dev_2.clone()).is_ok());
-        assert!(block_devs.insert(dummy_block_dev_3.clone()).is_ok());
-        assert!(block_devs.insert(root_block_device.clone()).is_ok());
+        assert!(block_devs
+            .insert(dummy_block_dev_2.clone(), &Default::default())
+            .is_ok());
+        assert!(block_devs
+            .insert(dummy_block_dev_3.clone(), &Default::default())
+            .is_ok());
+        assert!(block_devs
+            .insert(root_block_device.clone(), &Default::default())
+            .is_ok());
 
         assert_eq!(block_devs.list.len(), 3);
 
@@ -518,7 +736,13 @@ mod tests {
             is_read_only: false,
             drive_id: String::from("1"),
             rate_limiter: None,
+            rate_limiter_group: None,
             file_engine_type: FileEngineType::default(),
+            direct_io: false,
+            num_queues: 1,
+            io_threads: false,
+            fixed_buffers: false,
+            sq_poll: false,
         };
 
         let dummy_file_2 = TempFile::new().unwrap();
@@ -531,14 +755,24 @@ mod tests {
             is_read_only: false,
             drive_id: String::from("2"),
             rate_limiter: None,
+            rate_limiter_group: None,
             file_engine_type: FileEngineType::default(),
+            direct_io: false,
+            num_queues: 1,
+            io_threads: false,
+            fixed_buffers: false,
+            sq_poll: false,
         };
 
         let mut block_devs = BlockBuilder::new();
 
         // Add 2 block devices.
-        assert!(block_devs.insert(root_block_device).is_ok());
-        assert!(block_devs.insert(dummy_block_device_2.clone()).is_ok());
+        assert!(block_devs
+            .insert(root_block_device, &Default::default())
+            .is_ok());
+        assert!(block_devs
+            .insert(dummy_block_device_2.clone(), &Default::default())
+            .is_ok());
 
         // Get index zero.
         assert_eq!(
@@ -558,7 +792,9 @@ mod tests {
             .is_some());
         // Update OK.
         dummy_block_device_2.is_read_only = true;
-        assert!(block_devs.insert(dummy_block_device_2.clone()).is_ok());
+        assert!(block_devs
+            .insert(dummy_block_device_2.clone(), &Default::default())
+            .is_ok());
 
         let index = block_devs
             .get_index_of_drive_id(&dummy_block_device_2.drive_id)
@@ -570,7 +806,7 @@ mod tests {
         let dummy_path_3 = String::from("test_update_3");
         dummy_block_device_2.path_on_host = dummy_path_3.clone();
         assert_eq!(
-            block_devs.insert(dummy_block_device_2.clone()),
+            block_devs.insert(dummy_block_device_2.clone(), &Default::default()),
             Err(DriveError::InvalidBlockDevicePath(dummy_path_3))
         );
 
@@ -578,7 +814,7 @@ mod tests {
         dummy_block_device_2.path_on_host = dummy_path_2.clone();
         dummy_block_device_2.is_root_device = true;
         assert_eq!(
-            block_devs.insert(dummy_block_device_2),
+            block_devs.insert(dummy_block_device_2, &Default::default()),
             Err(DriveError::RootBlockDeviceAlreadyAdded)
         );
 
@@ -590,7 +826,13 @@ mod tests {
             is_read_only: false,
             drive_id: String::from("1"),
             rate_limiter: None,
+            rate_limiter_group: None,
             file_engine_type: FileEngineType::default(),
+            direct_io: false,
+            num_queues: 1,
+            io_threads: false,
+            fixed_buffers: false,
+            sq_poll: false,
         };
         // Switch roots and add a PARTUUID for the new one.
         let mut root_block_device_old = root_block_device;
@@ -603,11 +845,21 @@ mod tests {
             is_read_only: false,
             drive_id: String::from("2"),
             rate_limiter: None,
+            rate_limiter_group: None,
             file_engine_type: FileEngineType::default(),
+            direct_io: false,
+            num_queues: 1,
+            io_threads: false,
+            fixed_buffers: false,
+            sq_poll: false,
         };
-        assert!(block_devs.insert(root_block_device_old).is_ok());
+        assert!(block_devs
+            .insert(root_block_device_old, &Default::default())
+            .is_ok());
         let root_block_id = root_block_device_new.drive_id.clone();
-        assert!(block_devs.insert(root_block_device_new).is_ok());
+        assert!(block_devs
+            .insert(root_block_device_new, &Default::default())
+            .is_ok());
         assert!(block_devs.has_root_device());
         // Verify it's been moved to the first position.
         assert_eq!(block_devs.list[0].lock().unwrap().id(), &root_block_id);
@@ -617,7 +869,7 @@ mod tests {
     fn test_block_config() {
         let dummy_file = TempFile::new().unwrap();
 
-        let dummy_block_device = BlockDeviceConfig {
+        let mut dummy_block_device = BlockDeviceConfig {
             path_on_host: dummy_file.as_path().to_str().unwrap().to_string(),
             is_root_device: true,
             partuuid: None,
@@ -625,15 +877,45 @@ mod tests {
             is_read_only: true,
             drive_id: String::from("1"),
             rate_limiter: None,
+            rate_limiter_group: None,
             file_engine_type: FileEngineType::default(),
+            direct_io: false,
+            num_queues: 1,
+            io_threads: false,
+            fixed_buffers: false,
+            sq_poll: false,
         };
 
         let mut block_devs = BlockBuilder::new();
-        assert!(block_devs.insert(dummy_block_device.clone()).is_ok());
+        assert!(block_devs
+            .insert(dummy_block_device.clone(), &Default::default())
+            .is_ok());
 
         let configs = block_devs.configs();
         assert_eq!(configs.len(), 1);
         assert_eq!(configs.first().unwrap(), &dummy_block_device);
+
+        let info = block_devs.info("1").unwrap();
+        assert_eq!(info.config, dummy_block_device);
+        assert_eq!(info.rate_limiter_status, RateLimiterStatus::default());
+        assert_eq!(
+            block_devs.info("2").unwrap_err().to_string(),
+            DriveError::DeviceNotFound("2".to_string()).to_string()
+        );
+
+        // Direct IO and the queue settings are only part of the exported config when they
+        // differ from the defaults.
+        let json = serde_json::to_value(&dummy_block_device).unwrap();
+        assert!(json.get("direct_io").is_none());
+        assert!(json.get("num_queues").is_none());
+        assert!(json.get("io_threads").is_none());
+        dummy_block_device.direct_io = true;
+        dummy_block_device.num_queues = 4;
+        dummy_block_device.io_threads = true;
+        let json = serde_json::to_value(&dummy_block_device).unwrap();
+        assert_eq!(json["direct_io"], true);
+        assert_eq!(json["num_queues"], 4);
+        assert_eq!(json["io_threads"], true);
     }
 
     #[test]
@@ -650,6 +932,11 @@ mod tests {
             true,
             RateLimiter::default(),
             FileEngineType::default(),
+            false,
+            1,
+            false,
+            false,
+            false,
         )
         .unwrap();
 
@@ -667,4 +954,225 @@ mod tests {
             block_id
         )
     }
+    #[test]
+    fn test_rate_limiter_group() {
+        let dummy_file = TempFile::new().unwrap();
+        let mut dummy_block_device = BlockDeviceConfig {
+            path_on_host: dummy_file.as_path().to_str().unwrap().to_string(),
+            is_root_device: false,
+            partuuid: None,
+            cache_type: CacheType::Unsafe,
+            is_read_only: false,
+            drive_id: String::from("1"),
+            rate_limiter: None,
+            rate_limiter_group: Some("disks".to_string()),
+            file_engine_type: FileEngineType::default(),
+            direct_io: false,
+            num_queues: 1,
+            io_threads: false,
+            fixed_buffers: false,
+            sq_poll: false,
+        };
+
+        let mut groups = RateLimiterGroupBuilder::default();
+        let mut block_devs = BlockBuilder::new();
+        assert_eq!(
+            block_devs
+                .insert(dummy_block_device.clone(), &groups)
+                .unwrap_err(),
+            DriveError::RateLimiterGroup(RateLimiterGroupError::GroupNotFound("disks".to_string()))
+        );
+
+        groups
+            .insert(RateLimiterGroupConfig {
+                group_id: "disks".to_string(),
+                bandwidth: None,
+                ops: None,
+            })
+            .unwrap();
+        assert!(block_devs
+            .insert(dummy_block_device.clone(), &groups)
+            .is_ok());
+        assert_eq!(
+            block_devs.list[0]
+                .lock()
+                .unwrap()
+                .rate_limiter()
+                .group()
+                .unwrap(),
+            &groups.get("disks").unwrap()
+        );
+        assert_eq!(block_devs.configs(), vec![dummy_block_device.clone()]);
+        let json = serde_json::to_value(&dummy_block_device).unwrap();
+        assert_eq!(json["rate_limiter_group"], "disks");
+
+        // The group is left out of the exported config when not set.
+        dummy_block_device.rate_limiter_group = None;
+        let json = serde_json::to_value(&dummy_block_device).unwrap();
+        assert!(json.get("rate_limiter_group").is_none());
+        assert!(block_devs
+            .insert(dummy_block_device.clone(), &groups)
+            .is_ok());
+        assert!(block_devs.list[0]
+            .lock()
+            .unwrap()
+            .rate_limiter()
+            .group()
+            .is_none());
+    }
+
+    #[test]
+    fn test_io_threads() {
+        let dummy_file = TempFile::new().unwrap();
+        let mut dummy_block_device = BlockDeviceConfig {
+            path_on_host: dummy_file.as_path().to_str().unwrap().to_string(),
+            is_root_device: false,
+            partuuid: None,
+            cache_type: CacheType::Unsafe,
+            is_read_only: false,
+            drive_id: String::from("1"),
+            rate_limiter: Some(RateLimiterConfig::default()),
+            rate_limiter_group: None,
+            file_engine_type: FileEngineType::default(),
+            direct_io: false,
+            num_queues: 4,
+            io_threads: true,
+            fixed_buffers: false,
+            sq_poll: false,
+        };
+
+        // IO threads can't be used along with rate limiting.
+        let mut groups = RateLimiterGroupBuilder::default();
+        let mut block_devs = BlockBuilder::new();
+        assert_eq!(
+            block_devs
+                .insert(dummy_block_device.clone(), &groups)
+                .unwrap_err(),
+            DriveError::IoThreadsWithRateLimiter
+        );
+        groups
+            .insert(RateLimiterGroupConfig {
+                group_id: "disks".to_string(),
+                bandwidth: None,
+                ops: None,
+            })
+            .unwrap();
+        dummy_block_device.rate_limiter = None;
+        dummy_block_device.rate_limiter_group = Some("disks".to_string());
+        assert_eq!(
+            block_devs
+                .insert(dummy_block_device.clone(), &groups)
+                .unwrap_err(),
+            DriveError::IoThreadsWithRateLimiter
+        );
+
+        dummy_block_device.rate_limiter_group = None;
+        assert!(block_devs
+            .insert(dummy_block_device.clone(), &groups)
+            .is_ok());
+        assert_eq!(block_devs.list[0].lock().unwrap().num_queues(), 4);
+        assert!(block_devs.list[0].lock().unwrap().io_threads());
+        assert_eq!(block_devs.configs(), vec![dummy_block_device.clone()]);
+
+        // The number of queues is validated by the device.
+        dummy_block_device.num_queues = 0;
+        assert!(matches!(
+            block_devs.insert(dummy_block_device, &groups),
+            Err(DriveError::CreateBlockDevice(BlockError::InvalidNumQueues(
+                0
+            )))
+        ));
+    }
+
+    #[test]
+    fn test_nbd() {
+        let dummy_file = TempFile::new().unwrap();
+        let mut dummy_block_device = BlockDeviceConfig {
+            path_on_host: dummy_file.as_path().to_str().unwrap().to_string(),
+            is_root_device: false,
+            partuuid: None,
+            cache_type: CacheType::Unsafe,
+            is_read_only: false,
+            drive_id: String::from("1"),
+            rate_limiter: None,
+            rate_limiter_group: None,
+            file_engine_type: FileEngineType::Nbd,
+            direct_io: true,
+            num_queues: 1,
+            io_threads: false,
+            fixed_buffers: false,
+            sq_poll: false,
+        };
+
+        // NBD exports can't be opened with `O_DIRECT`.
+        let groups = RateLimiterGroupBuilder::default();
+        let mut block_devs = BlockBuilder::new();
+        assert_eq!(
+            block_devs
+                .insert(dummy_block_device.clone(), &groups)
+                .unwrap_err(),
+            DriveError::DirectIoWithNbd
+        );
+
+        // The path must lead to the socket of an NBD server.
+        dummy_block_device.direct_io = false;
+        assert!(matches!(
+            block_devs.insert(dummy_block_device, &groups),
+            Err(DriveError::CreateBlockDevice(BlockError::FileEngine(_)))
+        ));
+        assert!(block_devs.list.is_empty());
+    }
+
+    #[test]
+    fn test_io_uring_options() {
+        let dummy_file = TempFile::new().unwrap();
+        let mut dummy_block_device = BlockDeviceConfig {
+            path_on_host: dummy_file.as_path().to_str().unwrap().to_string(),
+            is_root_device: false,
+            partuuid: None,
+            cache_type: CacheType::Unsafe,
+            is_read_only: false,
+            drive_id: String::from("1"),
+            rate_limiter: None,
+            rate_limiter_group: None,
+            file_engine_type: FileEngineType::Sync,
+            direct_io: false,
+            num_queues: 1,
+            io_threads: false,
+            fixed_buffers: true,
+            sq_poll: false,
+        };
+
+        // Only the `Async` engine uses io_uring.
+        let groups = RateLimiterGroupBuilder::default();
+        let mut block_devs = BlockBuilder::new();
+        assert_eq!(
+            block_devs
+                .insert(dummy_block_device.clone(), &groups)
+                .unwrap_err(),
+            DriveError::IoUringOptionWithoutAsync
+        );
+        dummy_block_device.fixed_buffers = false;
+        dummy_block_device.sq_poll = true;
+        assert_eq!(
+            block_devs
+                .insert(dummy_block_device.clone(), &groups)
+                .unwrap_err(),
+            DriveError::IoUringOptionWithoutAsync
+        );
+
+        if !FileEngineType::Async.is_supported().unwrap() {
+            return;
+        }
+        dummy_block_device.file_engine_type = FileEngineType::Async;
+        dummy_block_device.fixed_buffers = true;
+        block_devs
+            .insert(dummy_block_device.clone(), &groups)
+            .unwrap();
+        let block = block_devs.list[0].lock().unwrap();
+        assert!(block.fixed_buffers());
+        assert!(block.sq_poll());
+        drop(block);
+        assert_eq!(block_devs.configs(), vec![dummy_block_device]);
+    }
 }
@@ -15,6 +15,8 @@ pub const MAX_SUPPORTED_VCPUS: u8 = 32;
 pub enum VmConfigError {
     /// The memory size is smaller than the target size set in the balloon device configuration.
     IncompatibleBalloonSize,
+    /// The custom CPU template could not be loaded.
+    InvalidCpuTemplate(String),
     /// The memory size is invalid. The memory can only be an unsigned integer.
     InvalidMemorySize,
     /// The vcpu count is invalid. When SMT is enabled, the `cpu_count` must be either
@@ -34,6 +36,7 @@ impl fmt::Display for VmConfigError {
                 "The memory size (MiB) is smaller than the previously \
                  set balloon device target size.",
             ),
+            InvalidCpuTemplate(ref err) => write!(f, "Invalid CPU template: {}", err),
             InvalidMemorySize => write!(f, "The memory size (MiB) is invalid.",),
             InvalidVcpuCount => write!(
                 f,
@@ -69,6 +72,13 @@ pub struct VmConfig {
         skip_serializing_if = "CpuFeaturesTemplate::is_none"
     )]
     pub cpu_template: CpuFeaturesTemplate,
+    /// Path to a user-defined CPU template, applied on top of `cpu_template`.
+    #[serde(
+        default,
+        deserialize_with = "deserialize_cpu_template",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub cpu_template_path: Option<String>,
     /// Enables or disables dirty page tracking. Enabling allows incremental snapshots.
     #[serde(default)]
     pub track_dirty_pages: bool,
@@ -81,6 +91,7 @@ impl Default for VmConfig {
             mem_size_mib: DEFAULT_MEM_SIZE_MIB,
             smt: false,
             cpu_template: CpuFeaturesTemplate::None,
+            cpu_template_path: None,
             track_dirty_pages: false,
         }
     }
@@ -91,8 +102,14 @@ impl fmt::Display for VmConfig {
         write!(
             f,
             "{{ \"vcpu_count\": {:?}, \"mem_size_mib\": {:?}, \"smt\": {:?}, \
-             \"cpu_template\": {:?}, \"track_dirty_pages\": {:?} }}",
-            self.vcpu_count, self.mem_size_mib, self.smt, self.cpu_template, self.track_dirty_pages
+             \"cpu_template\": {:?}, \"cpu_template_path\": {:?}, \
+             \"track_dirty_pages\": {:?} }}",
+            self.vcpu_count,
+            self.mem_size_mib,
+            self.smt,
+            self.cpu_template,
+            self.cpu_template_path,
+            self.track_dirty_pages
         )
     }
 }
@@ -129,6 +146,13 @@ pub struct VmUpdateConfig {
         deserialize_with = "deserialize_cpu_template"
     )]
     pub cpu_template: Option<CpuFeaturesTemplate>,
+    /// Path to a user-defined CPU template, applied on top of `cpu_template`.
+    #[serde(
+        default,
+        skip_serializing_if = "Option::is_none",
+        deserialize_with = "deserialize_cpu_template"
+    )]
+    pub cpu_template_path: Option<String>,
     /// Enables or disables dirty page tracking. Enabling allows incremental snapshots.
     #[serde(skip_serializing_if = "Option::is_none")]
     pub track_dirty_pages: Option<bool>,
@@ -142,6 +166,7 @@ impl VmUpdateConfig {
         if self.vcpu_count.is_none()
             && self.mem_size_mib.is_none()
             && self.cpu_template.is_none()
+            && self.cpu_template_path.is_none()
             && self.smt.is_none()
             && self.track_dirty_pages.is_none()
         {
@@ -159,6 +184,7 @@ impl From<VmConfig> for VmUpdateConfig {
             mem_size_mib: Some(cfg.mem_size_mib),
             smt: Some(cfg.smt),
             cpu_template: Some(cfg.cpu_template),
+            cpu_template_path: cfg.cpu_template_path,
             track_dirty_pages: Some(cfg.track_dirty_pages),
         }
     }
@@ -214,8 +240,8 @@ where
     Ok(val)
 }
 
-/// Deserialization function for the `cpu_template` field in `VmConfig` and `VmUpdateConfig`.
-/// This is called only when `cpu_template` is present in the JSON configuration.
+/// Deserialization function for the `cpu_template` and `cpu_template_path` fields in `VmConfig`
+/// and `VmUpdateConfig`. This is called only when the field is present in the JSON configuration.
 fn deserialize_cpu_template<'de, D, T>(_d: D) -> std::result::Result<T, D::Error>
 where
     D: de::Deserializer<'de>,
@@ -286,5 +312,12 @@ mod tests {
 
         let expected_str = "The memory size (MiB) is invalid.";
         assert_eq!(VmConfigError::InvalidMemorySize.to_string(), expected_str);
+
+        let expected_str = "Invalid CPU template: MSR 0x10a is modified more than once.";
+        assert_eq!(
+            VmConfigError::InvalidCpuTemplate("MSR 0x10a is modified more than once.".to_string())
+                .to_string(),
+            expected_str
+        );
     }
 }
@@ -2,10 +2,12 @@
 // SPDX-License-Identifier: Apache-2.0
 
 use mmds::data_store;
-use mmds::data_store::MmdsVersion;
+use mmds::data_store::{MmdsVersion, TokenBinding};
+use mmds::ns::{MmdsNetworkStackConfig, MAX_CONNECTIONS_LIMIT, MAX_PENDING_RESETS_LIMIT};
 use serde::{Deserialize, Serialize};
 use std::fmt::{Display, Formatter, Result};
 use std::net::Ipv4Addr;
+use std::num::NonZeroUsize;
 
 /// Keeps the MMDS configuration.
 #[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
@@ -18,6 +20,28 @@ pub struct MmdsConfig {
     pub network_interfaces: Vec<String>,
     /// MMDS IPv4 configured address.
     pub ipv4_address: Option<Ipv4Addr>,
+    /// Name of the data store served to the network interfaces. The default data store is used
+    /// when this is not specified.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub data_store: Option<String>,
+    /// Maximum size, in bytes, of the data store contents.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub data_store_limit: Option<usize>,
+    /// Guest endpoint attributes that MMDS V2 session tokens are bound to.
+    #[serde(default, skip_serializing_if = "TokenBinding::is_none")]
+    pub token_binding: TokenBinding,
+    /// TCP port the MMDS HTTP server listens on.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tcp_port: Option<u16>,
+    /// Maximum number of concurrent guest connections, for each network interface.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_connections: Option<usize>,
+    /// Maximum number of TCP resets waiting to be sent, for each network interface.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_pending_resets: Option<usize>,
+    /// Maximum size, in bytes, of a response body.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_response_size: Option<usize>,
 }
 
 impl MmdsConfig {
@@ -36,6 +60,82 @@ impl MmdsConfig {
     pub fn ipv4_addr(&self) -> Option<Ipv4Addr> {
         self.ipv4_address
     }
+
+    /// Returns the name of the data store if one was configured.
+    /// Otherwise returns None, which stands for the default data store.
+    pub fn data_store(&self) -> Option<&str> {
+        self.data_store.as_deref()
+    }
+
+    /// Returns the guest endpoint attributes that session tokens are bound to.
+    pub fn token_binding(&self) -> TokenBinding {
+        self.token_binding
+    }
+
+    /// Returns the MMDS network stack parameters, using the defaults for
+    /// the ones which were not configured.
+    pub fn network_stack_config(
+        &self,
+    ) -> std::result::Result<MmdsNetworkStackConfig, MmdsConfigError> {
+        let default = MmdsNetworkStackConfig::default();
+        if self.tcp_port == Some(0) {
+            return Err(MmdsConfigError::InvalidNetworkStackParam("tcp_port"));
+        }
+
+        Ok(MmdsNetworkStackConfig {
+            tcp_port: self.tcp_port.unwrap_or(default.tcp_port),
+            max_connections: bounded(
+                self.max_connections,
+                MAX_CONNECTIONS_LIMIT,
+                "max_connections",
+            )?
+            .unwrap_or(default.max_connections),
+            max_pending_resets: bounded(
+                self.max_pending_resets,
+                MAX_PENDING_RESETS_LIMIT,
+                "max_pending_resets",
+            )?
+            .unwrap_or(default.max_pending_resets),
+            max_response_size: non_zero(self.max_response_size, "max_response_size")?,
+        })
+    }
+}
+
+// Rejects zero values for the MMDS network stack parameter `name`.
+fn non_zero(
+    value: Option<usize>,
+    name: &'static str,
+) -> std::result::Result<Option<NonZeroUsize>, MmdsConfigError> {
+    match value {
+        Some(value) => NonZeroUsize::new(value)
+            .map(Some)
+            .ok_or(MmdsConfigError::InvalidNetworkStackParam(name)),
+        None => Ok(None),
+    }
+}
+
+// Rejects zero values, and the ones above `max`, for the MMDS network stack parameter `name`.
+fn bounded(
+    value: Option<usize>,
+    max: usize,
+    name: &'static str,
+) -> std::result::Result<Option<NonZeroUsize>, MmdsConfigError> {
+    match value {
+        Some(value) if value > max => Err(MmdsConfigError::InvalidNetworkStackParam(name)),
+        value => non_zero(value, name),
+    }
+}
+
+/// Checks that `name` can be used to identify a named MMDS data store.
+///
+/// Names follow the same rules as device IDs, and `config` and `tokens` are reserved
+/// for the `/mmds/config` and `/mmds/tokens` API resources.
+pub fn validate_data_store_name(name: &str) -> std::result::Result<(), MmdsConfigError> {
+    let is_valid_id = !name.is_empty() && name.chars().all(|c| c == '_' || c.is_alphanumeric());
+    if !is_valid_id || name == "config" || name == "tokens" {
+        return Err(MmdsConfigError::InvalidDataStoreName(name.to_string()));
+    }
+    Ok(())
 }
 
 /// MMDS configuration related errors.
@@ -43,8 +143,12 @@ impl MmdsConfig {
 pub enum MmdsConfigError {
     /// The network interfaces list provided is empty.
     EmptyNetworkIfaceList,
+    /// The provided data store name is not valid.
+    InvalidDataStoreName(String),
     /// The provided IPv4 address is not link-local valid.
     InvalidIpv4Addr,
+    /// The provided MMDS network stack parameter is not valid.
+    InvalidNetworkStackParam(&'static str),
     /// The network interfaces list provided contains IDs that
     /// does not correspond to any existing network interface.
     InvalidNetworkInterfaceId,
@@ -62,9 +166,25 @@ impl Display for MmdsConfigError {
                     forwarding MMDS requests is empty."
                 )
             }
+            MmdsConfigError::InvalidDataStoreName(name) => {
+                write!(
+                    f,
+                    "Invalid MMDS data store name: {}. Names must be non-empty, contain only \
+                    alphanumeric characters and underscores, and be different from `config` \
+                    and `tokens`.",
+                    name
+                )
+            }
             MmdsConfigError::InvalidIpv4Addr => {
                 write!(f, "The MMDS IPv4 address is not link local.")
             }
+            MmdsConfigError::InvalidNetworkStackParam(name) => {
+                write!(
+                    f,
+                    "The MMDS `{}` value is out of the supported range.",
+                    name
+                )
+            }
             MmdsConfigError::InvalidNetworkInterfaceId => {
                 write!(
                     f,
@@ -10,12 +10,14 @@ use std::path::Path;
 use libc::O_NONBLOCK;
 use serde::{Deserialize, Serialize};
 
-use rate_limiter::{BucketUpdate, RateLimiter, TokenBucket};
+use rate_limiter::{BucketUpdate, IoDirection, RateLimiter, TokenBucket};
 
 /// Wrapper for configuring the balloon device.
 pub mod balloon;
 /// Wrapper for configuring the microVM boot source.
 pub mod boot_source;
+/// Wrapper for user-defined CPU templates.
+pub mod cpu_template;
 /// Wrapper for configuring the block devices.
 pub mod drive;
 /// Wrapper over the microVM general information attached to the microVM.
@@ -30,6 +32,8 @@ pub mod metrics;
 pub mod mmds;
 /// Wrapper for configuring the network devices attached to the microVM.
 pub mod net;
+/// Wrapper for configuring the rate limiter groups shared by devices.
+pub mod rate_limiter_group;
 /// Wrapper for configuring microVM snapshots and the microVM state.
 pub mod snapshot;
 /// Wrapper for configuring the vsock devices attached to the microVM.
@@ -79,6 +83,16 @@ pub struct RateLimiterConfig {
     pub bandwidth: Option<TokenBucketConfig>,
     /// Data used to initialize the RateLimiter::ops bucket.
     pub ops: Option<TokenBucketConfig>,
+    /// Data used to initialize the bandwidth bucket dedicated to reads. Only used by block
+    /// devices.
+    pub read_bandwidth: Option<TokenBucketConfig>,
+    /// Data used to initialize the ops bucket dedicated to reads. Only used by block devices.
+    pub read_ops: Option<TokenBucketConfig>,
+    /// Data used to initialize the bandwidth bucket dedicated to writes. Only used by block
+    /// devices.
+    pub write_bandwidth: Option<TokenBucketConfig>,
+    /// Data used to initialize the ops bucket dedicated to writes. Only used by block devices.
+    pub write_ops: Option<TokenBucketConfig>,
 }
 
 /// A public-facing, stateless structure, specifying RateLimiter properties updates.
@@ -87,6 +101,14 @@ pub struct RateLimiterUpdate {
     pub bandwidth: BucketUpdate,
     /// Possible update to the RateLimiter::ops bucket.
     pub ops: BucketUpdate,
+    /// Possible update to the bandwidth bucket dedicated to reads.
+    pub read_bandwidth: BucketUpdate,
+    /// Possible update to the ops bucket dedicated to reads.
+    pub read_ops: BucketUpdate,
+    /// Possible update to the bandwidth bucket dedicated to writes.
+    pub write_bandwidth: BucketUpdate,
+    /// Possible update to the ops bucket dedicated to writes.
+    pub write_ops: BucketUpdate,
 }
 
 fn get_bucket_update(tb_cfg: &Option<TokenBucketConfig>) -> BucketUpdate {
@@ -114,12 +136,20 @@ impl From<Option<RateLimiterConfig>> for RateLimiterUpdate {
             RateLimiterUpdate {
                 bandwidth: get_bucket_update(&cfg.bandwidth),
                 ops: get_bucket_update(&cfg.ops),
+                read_bandwidth: get_bucket_update(&cfg.read_bandwidth),
+                read_ops: get_bucket_update(&cfg.read_ops),
+                write_bandwidth: get_bucket_update(&cfg.write_bandwidth),
+                write_ops: get_bucket_update(&cfg.write_ops),
             }
         } else {
             // No update to the rate-limiter.
             RateLimiterUpdate {
                 bandwidth: BucketUpdate::None,
                 ops: BucketUpdate::None,
+                read_bandwidth: BucketUpdate::None,
+                read_ops: BucketUpdate::None,
+                write_bandwidth: BucketUpdate::None,
+                write_ops: BucketUpdate::None,
             }
         }
     }
@@ -131,14 +161,25 @@ impl TryInto<RateLimiter> for RateLimiterConfig {
     fn try_into(self) -> std::result::Result<RateLimiter, Self::Error> {
         let bw = self.bandwidth.unwrap_or_default();
         let ops = self.ops.unwrap_or_default();
-        RateLimiter::new(
+        let mut rate_limiter = RateLimiter::new(
             bw.size,
             bw.one_time_burst.unwrap_or(0),
             bw.refill_time,
             ops.size,
             ops.one_time_burst.unwrap_or(0),
             ops.refill_time,
-        )
+        )?;
+        rate_limiter.update_direction_buckets(
+            IoDirection::Read,
+            get_bucket_update(&self.read_bandwidth),
+            get_bucket_update(&self.read_ops),
+        );
+        rate_limiter.update_direction_buckets(
+            IoDirection::Write,
+            get_bucket_update(&self.write_bandwidth),
+            get_bucket_update(&self.write_ops),
+        );
+        Ok(rate_limiter)
     }
 }
 
@@ -147,6 +188,18 @@ impl From<&RateLimiter> for RateLimiterConfig {
         RateLimiterConfig {
             bandwidth: rl.bandwidth().map(TokenBucketConfig::from),
             ops: rl.ops().map(TokenBucketConfig::from),
+            read_bandwidth: rl
+                .direction_bandwidth(IoDirection::Read)
+                .map(TokenBucketConfig::from),
+            read_ops: rl
+                .direction_ops(IoDirection::Read)
+                .map(TokenBucketConfig::from),
+            write_bandwidth: rl
+                .direction_bandwidth(IoDirection::Write)
+                .map(TokenBucketConfig::from),
+            write_ops: rl
+                .direction_ops(IoDirection::Write)
+                .map(TokenBucketConfig::from),
         }
     }
 }
@@ -154,12 +207,88 @@ impl From<&RateLimiter> for RateLimiterConfig {
 impl RateLimiterConfig {
     // Option<T> already implements From<T> so we have to use a custom one.
     fn into_option(self) -> Option<RateLimiterConfig> {
-        if self.bandwidth.is_some() || self.ops.is_some() {
+        if self.bandwidth.is_some() || self.ops.is_some() || self.has_direction_buckets() {
             Some(self)
         } else {
             None
         }
     }
+
+    /// Whether per-direction buckets are configured. Only block devices support them.
+    pub(crate) fn has_direction_buckets(&self) -> bool {
+        self.read_bandwidth.is_some()
+            || self.read_ops.is_some()
+            || self.write_bandwidth.is_some()
+            || self.write_ops.is_some()
+    }
+}
+
+/// A public-facing, stateless structure, holding the live state of a TokenBucket.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize)]
+pub struct TokenBucketStatus {
+    /// Tokens currently available in the bucket, including the ones refilled since its last use.
+    pub budget: u64,
+    /// Remaining initial burst tokens.
+    pub one_time_burst: u64,
+}
+
+impl From<&TokenBucket> for TokenBucketStatus {
+    fn from(tb: &TokenBucket) -> Self {
+        TokenBucketStatus {
+            budget: tb.available_budget(),
+            one_time_burst: tb.one_time_burst(),
+        }
+    }
+}
+
+/// A public-facing, stateless structure, holding the live state of a RateLimiter.
+#[derive(Clone, Debug, Default, PartialEq, Serialize)]
+pub struct RateLimiterStatus {
+    /// State of the RateLimiter::bandwidth bucket.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub bandwidth: Option<TokenBucketStatus>,
+    /// State of the RateLimiter::ops bucket.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ops: Option<TokenBucketStatus>,
+    /// State of the bandwidth bucket dedicated to reads.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub read_bandwidth: Option<TokenBucketStatus>,
+    /// State of the ops bucket dedicated to reads.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub read_ops: Option<TokenBucketStatus>,
+    /// State of the bandwidth bucket dedicated to writes.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub write_bandwidth: Option<TokenBucketStatus>,
+    /// State of the ops bucket dedicated to writes.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub write_ops: Option<TokenBucketStatus>,
+    /// Whether the refill timer is armed, i.e. the device is currently throttled.
+    pub blocked: bool,
+    /// Total time the device has been throttled for, in milliseconds.
+    pub throttled_time_ms: u64,
+}
+
+impl From<&RateLimiter> for RateLimiterStatus {
+    fn from(rl: &RateLimiter) -> Self {
+        RateLimiterStatus {
+            bandwidth: rl.bandwidth().map(TokenBucketStatus::from),
+            ops: rl.ops().map(TokenBucketStatus::from),
+            read_bandwidth: rl
+                .direction_bandwidth(IoDirection::Read)
+                .map(TokenBucketStatus::from),
+            read_ops: rl
+                .direction_ops(IoDirection::Read)
+                .map(TokenBucketStatus::from),
+            write_bandwidth: rl
+                .direction_bandwidth(IoDirection::Write)
+                .map(TokenBucketStatus::from),
+            write_ops: rl
+                .direction_ops(IoDirection::Write)
+                .map(TokenBucketStatus::from),
+            blocked: rl.is_blocked(),
+            throttled_time_ms: rl.throttled_time().as_millis() as u64,
+        }
+    }
 }
 
 type Result<T> = std::result::Result<T, std::io::Error>;
@@ -203,6 +332,12 @@ mod tests {
                 one_time_burst: None,
                 refill_time: REFILL_TIME * 2,
             }),
+            write_ops: Some(TokenBucketConfig {
+                size: SIZE / 2,
+                one_time_burst: None,
+                refill_time: REFILL_TIME,
+            }),
+            ..Default::default()
         };
         let rl: RateLimiter = rlconf.try_into().unwrap();
         assert_eq!(rl.bandwidth().unwrap().capacity(), SIZE);
@@ -211,6 +346,13 @@ mod tests {
         assert_eq!(rl.ops().unwrap().capacity(), SIZE * 2);
         assert_eq!(rl.ops().unwrap().one_time_burst(), 0);
         assert_eq!(rl.ops().unwrap().refill_time_ms(), REFILL_TIME * 2);
+        assert!(rl.direction_bandwidth(IoDirection::Write).is_none());
+        assert!(rl.direction_ops(IoDirection::Read).is_none());
+        assert_eq!(
+            rl.direction_ops(IoDirection::Write).unwrap().capacity(),
+            SIZE / 2
+        );
+        assert_eq!(RateLimiterConfig::from(&rl), rlconf);
     }
 
     #[test]
@@ -226,7 +368,7 @@ mod tests {
 
         let rl_conf = RateLimiterConfig {
             bandwidth: Some(bw_tb_cfg),
-            ops: None,
+            ..Default::default()
         };
         let rl: RateLimiter = rl_conf.try_into().unwrap();
         let generated_rl_conf = RateLimiterConfig::from(&rl);
@@ -234,6 +376,42 @@ mod tests {
         assert_eq!(generated_rl_conf.into_option(), Some(rl_conf));
     }
 
+    #[test]
+    fn test_rate_limiter_status() {
+        let mut rl = RateLimiter::new(SIZE, ONE_TIME_BURST, REFILL_TIME, 0, 0, 0).unwrap();
+        let status = RateLimiterStatus::from(&rl);
+        assert_eq!(
+            status.bandwidth,
+            Some(TokenBucketStatus {
+                budget: SIZE,
+                one_time_burst: ONE_TIME_BURST,
+            })
+        );
+        assert!(status.ops.is_none());
+        assert!(!status.blocked);
+        assert_eq!(status.throttled_time_ms, 0);
+        assert_eq!(
+            serde_json::to_value(&status).unwrap(),
+            serde_json::json!({
+                "bandwidth": {
+                    "budget": SIZE,
+                    "one_time_burst": ONE_TIME_BURST
+                },
+                "blocked": false,
+                "throttled_time_ms": 0
+            })
+        );
+
+        // Use up the burst and the budget.
+        assert!(rl.consume(SIZE + ONE_TIME_BURST, rate_limiter::TokenType::Bytes));
+        assert!(!rl.consume(SIZE, rate_limiter::TokenType::Bytes));
+        let status = RateLimiterStatus::from(&rl);
+        let bandwidth = status.bandwidth.unwrap();
+        assert_eq!(bandwidth.one_time_burst, 0);
+        assert!(bandwidth.budget < SIZE);
+        assert!(status.blocked);
+    }
+
     #[test]
     fn test_fifo_line_writer() {
         let log_file_temp =
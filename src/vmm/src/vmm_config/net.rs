@@ -3,13 +3,16 @@
 
 use std::convert::TryInto;
 use std::fmt;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
 use std::result;
 use std::sync::{Arc, Mutex};
 
-use super::RateLimiterConfig;
+use super::rate_limiter_group::{RateLimiterGroupBuilder, RateLimiterGroupError};
+use super::{RateLimiterConfig, RateLimiterStatus};
 use crate::Error as VmmError;
+use devices::virtio::net::user::{DEFAULT_GATEWAY_IP, DEFAULT_GUEST_IP};
 use devices::virtio::net::TapError;
-use devices::virtio::Net;
+use devices::virtio::{EgressFilter, Ipv4Network, Net, PortForward, UserNetConfig};
 use utils::net::mac::MacAddr;
 
 use serde::{Deserialize, Serialize};
@@ -22,7 +25,8 @@ use std::ops::Deref;
 pub struct NetworkInterfaceConfig {
     /// ID of the guest network interface.
     pub iface_id: String,
-    /// Host level path for the guest network interface.
+    /// Host level path for the guest network interface. Must be empty when `user_net` is set.
+    #[serde(default, skip_serializing_if = "String::is_empty")]
     pub host_dev_name: String,
     /// Guest MAC address.
     pub guest_mac: Option<MacAddr>,
@@ -30,6 +34,18 @@ pub struct NetworkInterfaceConfig {
     pub rx_rate_limiter: Option<RateLimiterConfig>,
     /// Rate Limiter for transmitted packages.
     pub tx_rate_limiter: Option<RateLimiterConfig>,
+    /// Rate limiter group whose limits are shared with other devices, for received packages.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rx_rate_limiter_group: Option<String>,
+    /// Rate limiter group whose limits are shared with other devices, for transmitted packages.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tx_rate_limiter_group: Option<String>,
+    /// Anti-spoofing filter for the frames sent by the guest.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub egress_filter: Option<EgressFilterConfig>,
+    /// User mode networking, used instead of a host tap device.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub user_net: Option<UserNetworkConfig>,
 }
 
 impl From<&Net> for NetworkInterfaceConfig {
@@ -42,6 +58,162 @@ impl From<&Net> for NetworkInterfaceConfig {
             guest_mac: net.guest_mac().copied(),
             rx_rate_limiter: rx_rl.into_option(),
             tx_rate_limiter: tx_rl.into_option(),
+            rx_rate_limiter_group: net
+                .rx_rate_limiter()
+                .group()
+                .map(|group| group.name().to_string()),
+            tx_rate_limiter_group: net
+                .tx_rate_limiter()
+                .group()
+                .map(|group| group.name().to_string()),
+            egress_filter: net.egress_filter().map(EgressFilterConfig::from),
+            user_net: net.user_net_config().map(UserNetworkConfig::from),
+        }
+    }
+}
+
+/// The live configuration and rate limiters state of a network interface.
+#[derive(Debug, PartialEq, Serialize)]
+pub struct NetworkInterfaceInfo {
+    /// Current configuration of the interface.
+    #[serde(flatten)]
+    pub config: NetworkInterfaceConfig,
+    /// Current state of the rate limiter for received packages.
+    pub rx_rate_limiter_status: RateLimiterStatus,
+    /// Current state of the rate limiter for transmitted packages.
+    pub tx_rate_limiter_status: RateLimiterStatus,
+}
+
+impl From<&Net> for NetworkInterfaceInfo {
+    fn from(net: &Net) -> Self {
+        NetworkInterfaceInfo {
+            config: NetworkInterfaceConfig::from(net),
+            rx_rate_limiter_status: RateLimiterStatus::from(net.rx_rate_limiter()),
+            tx_rate_limiter_status: RateLimiterStatus::from(net.tx_rate_limiter()),
+        }
+    }
+}
+
+/// Configuration of the anti-spoofing filter applied to the frames sent by the guest.
+///
+/// When enabled, frames are only forwarded to the host if their source MAC is the guest MAC of
+/// the interface and their source IPv4/IPv6 address is one of `allowed_ips`. DHCP server frames
+/// and IPv6 router advertisements are always dropped.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct EgressFilterConfig {
+    /// Source IP addresses the guest is allowed to use.
+    #[serde(default)]
+    pub allowed_ips: Vec<IpAddr>,
+}
+
+impl From<&EgressFilter> for EgressFilterConfig {
+    fn from(filter: &EgressFilter) -> Self {
+        EgressFilterConfig {
+            allowed_ips: filter.allowed_ips().to_vec(),
+        }
+    }
+}
+
+/// Forwards the TCP connections accepted on a host address to a guest port.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct PortForwardConfig {
+    /// Host address (IP and port) to listen on.
+    pub host_addr: SocketAddr,
+    /// Guest port which receives the forwarded connections.
+    pub guest_port: u16,
+}
+
+/// A range of IPv4 addresses, in CIDR notation.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct Ipv4NetworkConfig {
+    /// Network address.
+    pub ip: Ipv4Addr,
+    /// Length of the network prefix, in bits.
+    pub prefix_len: u8,
+}
+
+fn default_gateway_ip() -> Ipv4Addr {
+    DEFAULT_GATEWAY_IP
+}
+
+fn default_guest_ip() -> Ipv4Addr {
+    DEFAULT_GUEST_IP
+}
+
+/// Configuration of the user mode networking backend.
+///
+/// The guest is connected to a virtual gateway, and its TCP/UDP traffic is re-originated from
+/// the Firecracker process. Traffic sent to the gateway address reaches the host loopback
+/// interface, on the allowed ports only. The host addresses, private and link-local networks
+/// are only reachable when listed in the allowed destinations.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct UserNetworkConfig {
+    /// IPv4 address of the virtual gateway.
+    #[serde(default = "default_gateway_ip")]
+    pub gateway_ip: Ipv4Addr,
+    /// IPv4 address the guest is expected to use.
+    #[serde(default = "default_guest_ip")]
+    pub guest_ip: Ipv4Addr,
+    /// Host TCP ports forwarded to the guest.
+    #[serde(default)]
+    pub port_forwards: Vec<PortForwardConfig>,
+    /// Host loopback ports the guest can reach through the gateway address.
+    #[serde(default)]
+    pub host_loopback_ports: Vec<u16>,
+    /// Networks the guest can reach in addition to the public addresses.
+    #[serde(default)]
+    pub allowed_destinations: Vec<Ipv4NetworkConfig>,
+}
+
+impl From<&UserNetConfig> for UserNetworkConfig {
+    fn from(config: &UserNetConfig) -> Self {
+        UserNetworkConfig {
+            gateway_ip: config.gateway_ip,
+            guest_ip: config.guest_ip,
+            port_forwards: config
+                .port_forwards
+                .iter()
+                .map(|forward| PortForwardConfig {
+                    host_addr: forward.host_addr,
+                    guest_port: forward.guest_port,
+                })
+                .collect(),
+            host_loopback_ports: config.host_loopback_ports.clone(),
+            allowed_destinations: config
+                .allowed_destinations
+                .iter()
+                .map(|network| Ipv4NetworkConfig {
+                    ip: network.ip,
+                    prefix_len: network.prefix_len,
+                })
+                .collect(),
+        }
+    }
+}
+
+impl From<UserNetworkConfig> for UserNetConfig {
+    fn from(config: UserNetworkConfig) -> Self {
+        UserNetConfig {
+            gateway_ip: config.gateway_ip,
+            guest_ip: config.guest_ip,
+            port_forwards: config
+                .port_forwards
+                .into_iter()
+                .map(|forward| PortForward {
+                    host_addr: forward.host_addr,
+                    guest_port: forward.guest_port,
+                })
+                .collect(),
+            host_loopback_ports: config.host_loopback_ports,
+            allowed_destinations: config
+                .allowed_destinations
+                .into_iter()
+                .map(|network| Ipv4Network::new(network.ip, network.prefix_len))
+                .collect(),
         }
     }
 }
@@ -70,10 +242,22 @@ pub enum NetworkInterfaceError {
     CreateRateLimiter(std::io::Error),
     /// The MAC address is already in use.
     GuestMacAddressInUse(String),
+    /// No network interface with the given ID exists.
+    DeviceNotFound(String),
     /// Error during interface update (patch).
     DeviceUpdate(VmmError),
+    /// Per-direction rate limiter buckets were specified.
+    DirectionalRateLimiter,
+    /// The egress filter requires a guest MAC address.
+    EgressFilterWithoutGuestMac,
+    /// Both a host device and user mode networking were specified.
+    HostDevNameWithUserNet,
+    /// Neither a host device nor user mode networking were specified.
+    MissingBackend,
     /// Cannot open/create tap device.
     OpenTap(TapError),
+    /// A rate limiter group referenced by the interface is not valid.
+    RateLimiterGroup(RateLimiterGroupError),
 }
 
 impl fmt::Display for NetworkInterfaceError {
@@ -87,7 +271,24 @@ impl fmt::Display for NetworkInterfaceError {
                 "{}",
                 format!("The guest MAC address {} is already in use.", mac_addr)
             ),
+            DeviceNotFound(id) => write!(f, "Network interface {} does not exist", id),
             DeviceUpdate(e) => write!(f, "Error during interface update (patch): {}", e),
+            DirectionalRateLimiter => write!(
+                f,
+                "Per-direction rate limiter buckets are only supported by block devices."
+            ),
+            EgressFilterWithoutGuestMac => write!(
+                f,
+                "The egress filter cannot be enabled without specifying a guest MAC address."
+            ),
+            HostDevNameWithUserNet => write!(
+                f,
+                "A host device name cannot be specified when user mode networking is enabled."
+            ),
+            MissingBackend => write!(
+                f,
+                "Either a host device name or the user mode networking configuration is required."
+            ),
             OpenTap(e) => {
                 // We are propagating the Tap Error. This error can contain
                 // imbricated quotes which would result in an invalid json.
@@ -101,6 +302,7 @@ impl fmt::Display for NetworkInterfaceError {
                     tap_err
                 )
             }
+            RateLimiterGroup(e) => write!(f, "Invalid rate limiter group: {}", e),
         }
     }
 }
@@ -139,7 +341,11 @@ impl NetBuilder {
 
     /// Builds a network device based on a network interface config. Keeps a device reference
     /// in the builder's internal list.
-    pub fn build(&mut self, netif_config: NetworkInterfaceConfig) -> Result<Arc<Mutex<Net>>> {
+    pub fn build(
+        &mut self,
+        netif_config: NetworkInterfaceConfig,
+        rate_limiter_groups: &RateLimiterGroupBuilder,
+    ) -> Result<Arc<Mutex<Net>>> {
         let mac_conflict = |net: &Arc<Mutex<Net>>| {
             let net = net.lock().expect("Poisoned lock");
             // Check if another net dev has same MAC.
@@ -166,14 +372,35 @@ impl NetBuilder {
         }
 
         // Add new device.
-        let net = Arc::new(Mutex::new(Self::create_net(netif_config)?));
+        let net = Arc::new(Mutex::new(Self::create_net(
+            netif_config,
+            rate_limiter_groups,
+        )?));
         self.net_devices.push(net.clone());
 
         Ok(net)
     }
 
     /// Creates a Net device from a NetworkInterfaceConfig.
-    pub fn create_net(cfg: NetworkInterfaceConfig) -> Result<Net> {
+    pub fn create_net(
+        cfg: NetworkInterfaceConfig,
+        rate_limiter_groups: &RateLimiterGroupBuilder,
+    ) -> Result<Net> {
+        let egress_filter = match (cfg.egress_filter, cfg.guest_mac) {
+            (Some(filter_cfg), Some(guest_mac)) => {
+                Some(EgressFilter::new(guest_mac, filter_cfg.allowed_ips))
+            }
+            (Some(_), None) => return Err(NetworkInterfaceError::EgressFilterWithoutGuestMac),
+            (None, _) => None,
+        };
+        if cfg
+            .rx_rate_limiter
+            .iter()
+            .chain(cfg.tx_rate_limiter.iter())
+            .any(RateLimiterConfig::has_direction_buckets)
+        {
+            return Err(NetworkInterfaceError::DirectionalRateLimiter);
+        }
         let rx_rate_limiter = cfg
             .rx_rate_limiter
             .map(super::RateLimiterConfig::try_into)
@@ -184,16 +411,42 @@ impl NetBuilder {
             .map(super::RateLimiterConfig::try_into)
             .transpose()
             .map_err(NetworkInterfaceError::CreateRateLimiter)?;
+        let mut rx_rate_limiter = rx_rate_limiter.unwrap_or_default();
+        rx_rate_limiter.set_group(
+            rate_limiter_groups
+                .resolve(cfg.rx_rate_limiter_group.as_deref())
+                .map_err(NetworkInterfaceError::RateLimiterGroup)?,
+        );
+        let mut tx_rate_limiter = tx_rate_limiter.unwrap_or_default();
+        tx_rate_limiter.set_group(
+            rate_limiter_groups
+                .resolve(cfg.tx_rate_limiter_group.as_deref())
+                .map_err(NetworkInterfaceError::RateLimiterGroup)?,
+        );
 
         // Create and return the Net device
-        devices::virtio::net::Net::new_with_tap(
-            cfg.iface_id,
-            cfg.host_dev_name.clone(),
-            cfg.guest_mac.as_ref(),
-            rx_rate_limiter.unwrap_or_default(),
-            tx_rate_limiter.unwrap_or_default(),
-        )
-        .map_err(NetworkInterfaceError::CreateNetworkDevice)
+        let mut net = match (cfg.user_net, cfg.host_dev_name.is_empty()) {
+            (Some(_), false) => return Err(NetworkInterfaceError::HostDevNameWithUserNet),
+            (Some(user_net_cfg), true) => devices::virtio::net::Net::new_with_user_net(
+                cfg.iface_id,
+                user_net_cfg.into(),
+                cfg.guest_mac.as_ref(),
+                rx_rate_limiter,
+                tx_rate_limiter,
+            ),
+            (None, false) => devices::virtio::net::Net::new_with_tap(
+                cfg.iface_id,
+                cfg.host_dev_name.clone(),
+                cfg.guest_mac.as_ref(),
+                rx_rate_limiter,
+                tx_rate_limiter,
+            ),
+            (None, true) => return Err(NetworkInterfaceError::MissingBackend),
+        }
+        .map_err(NetworkInterfaceError::CreateNetworkDevice)?;
+        net.set_egress_filter(egress_filter);
+
+        Ok(net)
     }
 
     /// Returns a vec with the structures used to configure the net devices.
@@ -204,6 +457,16 @@ impl NetBuilder {
         }
         ret
     }
+
+    /// Returns the live configuration and rate limiters state of the interface with `iface_id`.
+    pub fn info(&self, iface_id: &str) -> Result<NetworkInterfaceInfo> {
+        self.net_devices
+            .iter()
+            .map(|net| net.lock().expect("Poisoned lock"))
+            .find(|net| net.id() == iface_id)
+            .map(|net| NetworkInterfaceInfo::from(net.deref()))
+            .ok_or_else(|| NetworkInterfaceError::DeviceNotFound(iface_id.to_string()))
+    }
 }
 
 #[cfg(test)]
@@ -212,6 +475,7 @@ mod tests {
     use std::str;
 
     use super::*;
+    use crate::vmm_config::TokenBucketConfig;
 
     impl NetBuilder {
         pub fn len(&self) -> usize {
@@ -230,6 +494,10 @@ mod tests {
             guest_mac: Some(MacAddr::parse_str(mac).unwrap()),
             rx_rate_limiter: RateLimiterConfig::default().into_option(),
             tx_rate_limiter: RateLimiterConfig::default().into_option(),
+            rx_rate_limiter_group: None,
+            tx_rate_limiter_group: None,
+            egress_filter: None,
+            user_net: None,
         }
     }
 
@@ -241,6 +509,10 @@ mod tests {
                 guest_mac: self.guest_mac,
                 rx_rate_limiter: None,
                 tx_rate_limiter: None,
+                rx_rate_limiter_group: self.rx_rate_limiter_group.clone(),
+                tx_rate_limiter_group: self.tx_rate_limiter_group.clone(),
+                egress_filter: self.egress_filter.clone(),
+                user_net: self.user_net.clone(),
             }
         }
     }
@@ -255,20 +527,20 @@ mod tests {
 
         // Test create.
         let netif_1 = create_netif(id_1, host_dev_name_1, guest_mac_1);
-        assert!(net_builder.build(netif_1).is_ok());
+        assert!(net_builder.build(netif_1, &Default::default()).is_ok());
         assert_eq!(net_builder.net_devices.len(), 1);
 
         // Test update mac address (this test does not modify the tap).
         guest_mac_1 = "01:23:45:67:89:0b";
         let netif_1 = create_netif(id_1, host_dev_name_1, guest_mac_1);
 
-        assert!(net_builder.build(netif_1).is_ok());
+        assert!(net_builder.build(netif_1, &Default::default()).is_ok());
         assert_eq!(net_builder.net_devices.len(), 1);
 
         // Test update host_dev_name (the tap will be updated).
         host_dev_name_1 = "dev2";
         let netif_1 = create_netif(id_1, host_dev_name_1, guest_mac_1);
-        assert!(net_builder.build(netif_1).is_ok());
+        assert!(net_builder.build(netif_1, &Default::default()).is_ok());
         assert_eq!(net_builder.net_devices.len(), 1);
     }
 
@@ -282,7 +554,7 @@ mod tests {
 
         // Adding the first valid network config.
         let netif_1 = create_netif(id_1, host_dev_name_1, guest_mac_1);
-        assert!(net_builder.build(netif_1).is_ok());
+        assert!(net_builder.build(netif_1, &Default::default()).is_ok());
 
         // Error Cases for CREATE
         // Error Case: Add new network config with the same mac as netif_1.
@@ -296,7 +568,11 @@ mod tests {
             guest_mac_1.to_string()
         );
         assert_eq!(
-            net_builder.build(netif_2).err().unwrap().to_string(),
+            net_builder
+                .build(netif_2, &Default::default())
+                .err()
+                .unwrap()
+                .to_string(),
             expected_error
         );
         assert_eq!(net_builder.net_devices.len(), 1);
@@ -304,7 +580,11 @@ mod tests {
         // Error Case: Add new network config with the same dev_host_name as netif_1.
         let netif_2 = create_netif(id_2, host_dev_name_1, guest_mac_2);
         assert_eq!(
-            net_builder.build(netif_2).err().unwrap().to_string(),
+            net_builder
+                .build(netif_2, &Default::default())
+                .err()
+                .unwrap()
+                .to_string(),
             NetworkInterfaceError::CreateNetworkDevice(devices::virtio::net::Error::TapOpen(
                 TapError::IoctlError(std::io::Error::from_raw_os_error(16))
             ))
@@ -314,7 +594,7 @@ mod tests {
 
         // Adding the second valid network config.
         let netif_2 = create_netif(id_2, host_dev_name_2, guest_mac_2);
-        assert!(net_builder.build(netif_2).is_ok());
+        assert!(net_builder.build(netif_2, &Default::default()).is_ok());
 
         // Error Cases for UPDATE
         // Error Case: Update netif_2 mac using the same mac as netif_1.
@@ -324,19 +604,46 @@ mod tests {
             guest_mac_1.to_string()
         );
         assert_eq!(
-            net_builder.build(netif_2).err().unwrap().to_string(),
+            net_builder
+                .build(netif_2, &Default::default())
+                .err()
+                .unwrap()
+                .to_string(),
             expected_error
         );
 
         // Error Case: Update netif_2 dev_host_name using the same dev_host_name as netif_1.
         let netif_2 = create_netif(id_2, host_dev_name_1, guest_mac_2);
         assert_eq!(
-            net_builder.build(netif_2).err().unwrap().to_string(),
+            net_builder
+                .build(netif_2, &Default::default())
+                .err()
+                .unwrap()
+                .to_string(),
             NetworkInterfaceError::CreateNetworkDevice(devices::virtio::net::Error::TapOpen(
                 TapError::IoctlError(std::io::Error::from_raw_os_error(16))
             ))
             .to_string()
         );
+
+        // Error Case: Per-direction rate limiter buckets.
+        let mut netif_3 = create_netif("id_3", "dev5", "01:23:45:67:89:0e");
+        netif_3.rx_rate_limiter = Some(RateLimiterConfig {
+            read_ops: Some(TokenBucketConfig {
+                size: 10,
+                one_time_burst: None,
+                refill_time: 100,
+            }),
+            ..Default::default()
+        });
+        assert_eq!(
+            net_builder
+                .build(netif_3, &Default::default())
+                .err()
+                .unwrap()
+                .to_string(),
+            NetworkInterfaceError::DirectionalRateLimiter.to_string()
+        );
     }
 
     #[test]
@@ -353,6 +660,21 @@ mod tests {
             NetworkInterfaceError::DeviceUpdate(VmmError::VcpuExit),
             NetworkInterfaceError::DeviceUpdate(VmmError::VcpuExit)
         );
+        let _ = format!(
+            "{}{:?}",
+            NetworkInterfaceError::EgressFilterWithoutGuestMac,
+            NetworkInterfaceError::EgressFilterWithoutGuestMac
+        );
+        let _ = format!(
+            "{}{:?}",
+            NetworkInterfaceError::HostDevNameWithUserNet,
+            NetworkInterfaceError::HostDevNameWithUserNet
+        );
+        let _ = format!(
+            "{}{:?}",
+            NetworkInterfaceError::MissingBackend,
+            NetworkInterfaceError::MissingBackend
+        );
         let _ = format!(
             "{}{:?}",
             NetworkInterfaceError::OpenTap(TapError::InvalidIfname),
@@ -373,12 +695,110 @@ mod tests {
         );
 
         let mut net_builder = NetBuilder::new();
-        assert!(net_builder.build(net_if_cfg.clone()).is_ok());
+        assert!(net_builder
+            .build(net_if_cfg.clone(), &Default::default())
+            .is_ok());
         assert_eq!(net_builder.net_devices.len(), 1);
 
         let configs = net_builder.configs();
         assert_eq!(configs.len(), 1);
         assert_eq!(configs.first().unwrap(), &net_if_cfg);
+        // The unset optional fields are left out of the exported config.
+        let json = serde_json::to_value(configs.first().unwrap()).unwrap();
+        assert!(json.get("rx_rate_limiter_group").is_none());
+        assert!(json.get("tx_rate_limiter_group").is_none());
+        assert!(json.get("egress_filter").is_none());
+        assert!(json.get("user_net").is_none());
+
+        let info = net_builder.info(net_id).unwrap();
+        assert_eq!(info.config, net_if_cfg);
+        assert_eq!(info.rx_rate_limiter_status, RateLimiterStatus::default());
+        assert_eq!(info.tx_rate_limiter_status, RateLimiterStatus::default());
+        assert_eq!(
+            net_builder.info("other").unwrap_err().to_string(),
+            NetworkInterfaceError::DeviceNotFound("other".to_string()).to_string()
+        );
+    }
+
+    #[test]
+    fn test_egress_filter_config() {
+        let mut net_builder = NetBuilder::new();
+        let guest_ip: IpAddr = "10.0.0.2".parse().unwrap();
+
+        // The filter needs a guest MAC to enforce.
+        let mut netif = create_netif("id_egress", "dev_egress", "01:23:45:67:89:0c");
+        netif.guest_mac = None;
+        netif.egress_filter = Some(EgressFilterConfig {
+            allowed_ips: vec![guest_ip],
+        });
+        assert_eq!(
+            net_builder
+                .build(netif, &Default::default())
+                .err()
+                .unwrap()
+                .to_string(),
+            NetworkInterfaceError::EgressFilterWithoutGuestMac.to_string()
+        );
+        assert!(net_builder.is_empty());
+
+        let mut netif = create_netif("id_egress", "dev_egress", "01:23:45:67:89:0c");
+        netif.egress_filter = Some(EgressFilterConfig {
+            allowed_ips: vec![guest_ip],
+        });
+        let net = net_builder
+            .build(netif.clone(), &Default::default())
+            .unwrap();
+        {
+            let net = net.lock().unwrap();
+            let filter = net.egress_filter().unwrap();
+            assert_eq!(filter.guest_mac(), netif.guest_mac.as_ref().unwrap());
+            assert_eq!(filter.allowed_ips(), &[guest_ip]);
+        }
+        assert_eq!(net_builder.configs().first().unwrap(), &netif);
+    }
+
+    #[test]
+    fn test_user_net_config() {
+        let mut net_builder = NetBuilder::new();
+
+        // A backend is required.
+        let mut netif = create_netif("id_user", "", "01:23:45:67:89:0d");
+        assert_eq!(
+            net_builder
+                .build(netif.clone(), &Default::default())
+                .err()
+                .unwrap()
+                .to_string(),
+            NetworkInterfaceError::MissingBackend.to_string()
+        );
+
+        // The tap and the user mode backends are mutually exclusive.
+        let user_net: UserNetworkConfig = serde_json::from_str(
+            r#"{"port_forwards": [{"host_addr": "127.0.0.1:0", "guest_port": 22}]}"#,
+        )
+        .unwrap();
+        assert_eq!(user_net.gateway_ip, DEFAULT_GATEWAY_IP);
+        assert_eq!(user_net.guest_ip, DEFAULT_GUEST_IP);
+        assert!(user_net.host_loopback_ports.is_empty());
+        assert!(user_net.allowed_destinations.is_empty());
+        netif.user_net = Some(user_net);
+        netif.host_dev_name = String::from("dev_user");
+        assert_eq!(
+            net_builder
+                .build(netif.clone(), &Default::default())
+                .err()
+                .unwrap()
+                .to_string(),
+            NetworkInterfaceError::HostDevNameWithUserNet.to_string()
+        );
+        assert!(net_builder.is_empty());
+
+        netif.host_dev_name = String::new();
+        let net = net_builder
+            .build(netif.clone(), &Default::default())
+            .unwrap();
+        assert_eq!(net.lock().unwrap().iface_name(), "");
+        assert_eq!(net_builder.configs().first().unwrap(), &netif);
     }
 
     #[test]
@@ -0,0 +1,221 @@
+// Copyright 2022 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+use std::collections::BTreeMap;
+use std::fmt::{Display, Formatter};
+use std::result;
+
+use rate_limiter::{BucketUpdate, RateLimiterGroup};
+use serde::{Deserialize, Serialize};
+
+use super::{get_bucket_update, TokenBucketConfig};
+
+type Result<T> = result::Result<T, RateLimiterGroupError>;
+
+/// Errors associated with the operations allowed on a rate limiter group.
+#[derive(Debug, PartialEq)]
+pub enum RateLimiterGroupError {
+    /// The group ID is not valid.
+    InvalidGroupId(String),
+    /// No group with the given ID exists.
+    GroupNotFound(String),
+}
+
+impl Display for RateLimiterGroupError {
+    fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
+        use self::RateLimiterGroupError::*;
+        match self {
+            InvalidGroupId(id) => write!(f, "Invalid rate limiter group ID: {:?}", id),
+            GroupNotFound(id) => write!(f, "Rate limiter group {} does not exist", id),
+        }
+    }
+}
+
+/// Use this structure to define a rate limiter group, whose token buckets are shared by all
+/// the devices referencing it.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct RateLimiterGroupConfig {
+    /// Unique identifier of the group.
+    pub group_id: String,
+    /// Data used to initialize the bandwidth bucket of the group.
+    pub bandwidth: Option<TokenBucketConfig>,
+    /// Data used to initialize the ops bucket of the group.
+    pub ops: Option<TokenBucketConfig>,
+}
+
+impl From<&RateLimiterGroup> for RateLimiterGroupConfig {
+    fn from(group: &RateLimiterGroup) -> Self {
+        RateLimiterGroupConfig {
+            group_id: group.name().to_string(),
+            bandwidth: group.bandwidth().as_ref().map(TokenBucketConfig::from),
+            ops: group.ops().as_ref().map(TokenBucketConfig::from),
+        }
+    }
+}
+
+/// Only provided fields will be updated. I.e. if any optional fields
+/// are missing, they will not be updated.
+#[derive(Debug, Default, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct RateLimiterGroupUpdateConfig {
+    /// The group ID, as provided by the user at creation time.
+    pub group_id: String,
+    /// New bandwidth bucket of the group.
+    pub bandwidth: Option<TokenBucketConfig>,
+    /// New ops bucket of the group.
+    pub ops: Option<TokenBucketConfig>,
+}
+
+/// Wrapper for the collection that holds all the rate limiter groups.
+#[derive(Default)]
+pub struct RateLimiterGroupBuilder {
+    groups: BTreeMap<String, RateLimiterGroup>,
+}
+
+impl RateLimiterGroupBuilder {
+    /// Defines a rate limiter group using the specified configuration.
+    /// If a group with the same id already exists, its buckets are replaced, so that the
+    /// devices already referencing it see the new limits.
+    pub fn insert(&mut self, config: RateLimiterGroupConfig) -> Result<()> {
+        if config.group_id.is_empty() {
+            return Err(RateLimiterGroupError::InvalidGroupId(config.group_id));
+        }
+
+        // A missing bucket disables the respective limit.
+        let bandwidth = match get_bucket_update(&config.bandwidth) {
+            BucketUpdate::None => BucketUpdate::Disabled,
+            update => update,
+        };
+        let ops = match get_bucket_update(&config.ops) {
+            BucketUpdate::None => BucketUpdate::Disabled,
+            update => update,
+        };
+        self.groups
+            .entry(config.group_id.clone())
+            .or_insert_with(|| RateLimiterGroup::new(config.group_id, 0, 0, 0, 0, 0, 0))
+            .update_buckets(bandwidth, ops);
+
+        Ok(())
+    }
+
+    /// Updates the buckets of an existing group. Only the provided buckets are updated.
+    pub fn update(&self, config: RateLimiterGroupUpdateConfig) -> Result<()> {
+        let group = self.get(&config.group_id)?;
+        group.update_buckets(
+            get_bucket_update(&config.bandwidth),
+            get_bucket_update(&config.ops),
+        );
+
+        Ok(())
+    }
+
+    /// Returns a handle to the group with the specified id.
+    pub fn get(&self, group_id: &str) -> Result<RateLimiterGroup> {
+        self.groups
+            .get(group_id)
+            .cloned()
+            .ok_or_else(|| RateLimiterGroupError::GroupNotFound(group_id.to_string()))
+    }
+
+    /// Returns a handle to the group with the specified id, or `None` if no id is given.
+    pub fn resolve(&self, group_id: Option<&str>) -> Result<Option<RateLimiterGroup>> {
+        group_id.map(|id| self.get(id)).transpose()
+    }
+
+    /// Inserts an existing group.
+    pub fn add_group(&mut self, group: RateLimiterGroup) {
+        self.groups.insert(group.name().to_string(), group);
+    }
+
+    /// Returns a vec with the structures used to configure the groups.
+    pub fn configs(&self) -> Vec<RateLimiterGroupConfig> {
+        self.groups
+            .values()
+            .map(RateLimiterGroupConfig::from)
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn bucket(size: u64) -> Option<TokenBucketConfig> {
+        Some(TokenBucketConfig {
+            size,
+            one_time_burst: None,
+            refill_time: 1000,
+        })
+    }
+
+    #[test]
+    fn test_rate_limiter_groups() {
+        let mut groups = RateLimiterGroupBuilder::default();
+        assert!(groups.configs().is_empty());
+        assert_eq!(
+            groups.get("disks").unwrap_err(),
+            RateLimiterGroupError::GroupNotFound("disks".to_string())
+        );
+        assert!(groups.resolve(None).unwrap().is_none());
+
+        let config = RateLimiterGroupConfig {
+            group_id: "disks".to_string(),
+            bandwidth: bucket(1000),
+            ops: None,
+        };
+        groups.insert(config.clone()).unwrap();
+        assert_eq!(groups.configs(), vec![config]);
+        let group = groups.resolve(Some("disks")).unwrap().unwrap();
+        assert_eq!(group.bandwidth().unwrap().capacity(), 1000);
+
+        // Updates only touch the provided buckets.
+        groups
+            .update(RateLimiterGroupUpdateConfig {
+                group_id: "disks".to_string(),
+                bandwidth: None,
+                ops: bucket(10),
+            })
+            .unwrap();
+        assert_eq!(group.bandwidth().unwrap().capacity(), 1000);
+        assert_eq!(group.ops().unwrap().capacity(), 10);
+        assert_eq!(
+            groups
+                .update(RateLimiterGroupUpdateConfig {
+                    group_id: "nets".to_string(),
+                    ..Default::default()
+                })
+                .unwrap_err(),
+            RateLimiterGroupError::GroupNotFound("nets".to_string())
+        );
+
+        // Redefining a group keeps the existing handles working and disables missing buckets.
+        groups
+            .insert(RateLimiterGroupConfig {
+                group_id: "disks".to_string(),
+                bandwidth: bucket(2000),
+                ops: None,
+            })
+            .unwrap();
+        assert_eq!(group.bandwidth().unwrap().capacity(), 2000);
+        assert!(group.ops().is_none());
+        assert_eq!(groups.get("disks").unwrap(), group);
+
+        assert_eq!(
+            groups
+                .insert(RateLimiterGroupConfig {
+                    group_id: String::new(),
+                    bandwidth: None,
+                    ops: None,
+                })
+                .unwrap_err()
+                .to_string(),
+            "Invalid rate limiter group ID: \"\""
+        );
+
+        let restored = RateLimiterGroup::new("nets".to_string(), 0, 0, 0, 5, 0, 100);
+        groups.add_group(restored.clone());
+        assert_eq!(groups.get("nets").unwrap(), restored);
+        assert_eq!(groups.configs().len(), 2);
+    }
+}
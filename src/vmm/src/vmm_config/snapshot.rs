@@ -56,6 +56,10 @@ pub struct LoadSnapshotParams {
     /// is successful.
     #[serde(default)]
     pub resume_vm: bool,
+    /// When set to true, the snapshot is only checked for compatibility with
+    /// the host CPU and no microVM is restored.
+    #[serde(default)]
+    pub dry_run: bool,
 }
 
 /// The microVM state options.
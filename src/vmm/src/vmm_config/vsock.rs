@@ -1,23 +1,41 @@
 // Copyright 2018 Amazon.com, Inc. or its affiliates. All Rights Reserved.
 // SPDX-License-Identifier: Apache-2.0
 
-use std::convert::TryFrom;
+use std::convert::{TryFrom, TryInto};
 use std::fmt;
+use std::net::SocketAddr;
 use std::sync::{Arc, Mutex};
 
-use devices::virtio::{Vsock, VsockError, VsockUnixBackend, VsockUnixBackendError};
+use super::RateLimiterConfig;
+use crate::Error as VmmError;
+use devices::virtio::{
+    Vsock, VsockConnLimits, VsockError, VsockHostBackend, VsockHostBackendError, VsockTcpConfig,
+    VsockTcpForward, VsockUnixBackendError, VSOCK_MAX_CONNECTIONS,
+};
 
 use serde::{Deserialize, Serialize};
 
-type MutexVsockUnix = Arc<Mutex<Vsock<VsockUnixBackend>>>;
+type MutexVsock = Arc<Mutex<Vsock<VsockHostBackend>>>;
 
 /// Errors associated with `NetworkInterfaceConfig`.
 #[derive(Debug)]
 pub enum VsockConfigError {
     /// Failed to create the backend for the vsock device.
-    CreateVsockBackend(VsockUnixBackendError),
+    CreateVsockBackend(VsockHostBackendError),
     /// Failed to create the vsock device.
     CreateVsockDevice(VsockError),
+    /// Failed to create a `RateLimiter` object.
+    CreateRateLimiter(std::io::Error),
+    /// Error during device update (patch).
+    DeviceUpdate(VmmError),
+    /// Per-direction rate limiter buckets were specified.
+    DirectionalRateLimiter,
+    /// The connection limit is out of range.
+    InvalidConnectionLimit(usize),
+    /// Neither a unix socket path nor a TCP port map were specified.
+    MissingBackend,
+    /// Both a unix socket path and a TCP port map were specified.
+    UdsPathWithTcp,
 }
 
 impl fmt::Display for VsockConfigError {
@@ -28,6 +46,25 @@ impl fmt::Display for VsockConfigError {
                 write!(f, "Cannot create backend for vsock device: {:?}", e)
             }
             CreateVsockDevice(ref e) => write!(f, "Cannot create vsock device: {:?}", e),
+            CreateRateLimiter(ref e) => write!(f, "Cannot create RateLimiter: {}", e),
+            DeviceUpdate(ref e) => write!(f, "Error during vsock device update (patch): {}", e),
+            DirectionalRateLimiter => write!(
+                f,
+                "Per-direction rate limiter buckets are only supported by block devices."
+            ),
+            InvalidConnectionLimit(limit) => write!(
+                f,
+                "Invalid vsock connection limit {}: it must be between 1 and {}.",
+                limit, VSOCK_MAX_CONNECTIONS
+            ),
+            MissingBackend => write!(
+                f,
+                "Either a unix socket path or a TCP port map is required for the vsock device."
+            ),
+            UdsPathWithTcp => write!(
+                f,
+                "A unix socket path cannot be specified when the TCP port map is used."
+            ),
         }
     }
 }
@@ -45,90 +82,227 @@ pub struct VsockDeviceConfig {
     pub vsock_id: Option<String>,
     /// A 32-bit Context Identifier (CID) used to identify the guest.
     pub guest_cid: u32,
-    /// Path to local unix socket.
+    /// Path to local unix socket. Must be empty when `tcp` is set.
+    #[serde(default, skip_serializing_if = "String::is_empty")]
     pub uds_path: String,
+    /// Port map used to forward connections to and from host TCP endpoints, instead of the
+    /// unix socket.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tcp: Option<VsockTcpDeviceConfig>,
+    /// Rate limiter for the packets received by the guest.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rx_rate_limiter: Option<RateLimiterConfig>,
+    /// Rate limiter for the packets transmitted by the guest.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tx_rate_limiter: Option<RateLimiterConfig>,
+    /// Maximum number of established connections. Defaults to `VSOCK_MAX_CONNECTIONS`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_connections: Option<usize>,
+    /// Ports the guest is allowed to connect to. All ports are allowed if not set.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub allowed_ports: Option<Vec<u32>>,
 }
 
-struct VsockAndUnixPath {
-    vsock: MutexVsockUnix,
-    uds_path: String,
+/// The data fed into a vsock device update request. Currently, only the RX and TX rate limiters
+/// can be updated.
+#[derive(Debug, Deserialize, PartialEq, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct VsockDeviceUpdateConfig {
+    /// New RX rate limiter config. Only provided data will be updated. I.e. if any optional data
+    /// is missing, it will not be nullified, but left unchanged.
+    pub rx_rate_limiter: Option<RateLimiterConfig>,
+    /// New TX rate limiter config. Only provided data will be updated. I.e. if any optional data
+    /// is missing, it will not be nullified, but left unchanged.
+    pub tx_rate_limiter: Option<RateLimiterConfig>,
+}
+
+/// Associates a guest vsock port with a host TCP address.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct VsockTcpForwardConfig {
+    /// Guest vsock port.
+    pub guest_port: u32,
+    /// Host address (IP and port).
+    pub host_addr: SocketAddr,
+}
+
+/// Port map of the vsock TCP backend.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct VsockTcpDeviceConfig {
+    /// Guest-initiated connections to `guest_port` are forwarded to `host_addr`.
+    #[serde(default)]
+    pub connect: Vec<VsockTcpForwardConfig>,
+    /// Connections accepted on `host_addr` are forwarded to `guest_port`.
+    #[serde(default)]
+    pub listen: Vec<VsockTcpForwardConfig>,
 }
 
-impl From<&VsockAndUnixPath> for VsockDeviceConfig {
-    fn from(vsock: &VsockAndUnixPath) -> Self {
-        let vsock_lock = vsock.vsock.lock().unwrap();
+impl From<&VsockTcpConfig> for VsockTcpDeviceConfig {
+    fn from(config: &VsockTcpConfig) -> Self {
+        let to_config = |forwards: &Vec<VsockTcpForward>| {
+            forwards
+                .iter()
+                .map(|forward| VsockTcpForwardConfig {
+                    guest_port: forward.guest_port,
+                    host_addr: forward.host_addr,
+                })
+                .collect()
+        };
+        VsockTcpDeviceConfig {
+            connect: to_config(&config.connect),
+            listen: to_config(&config.listen),
+        }
+    }
+}
+
+impl From<VsockTcpDeviceConfig> for VsockTcpConfig {
+    fn from(config: VsockTcpDeviceConfig) -> Self {
+        let to_forwards = |forwards: Vec<VsockTcpForwardConfig>| {
+            forwards
+                .into_iter()
+                .map(|forward| VsockTcpForward {
+                    guest_port: forward.guest_port,
+                    host_addr: forward.host_addr,
+                })
+                .collect()
+        };
+        VsockTcpConfig {
+            connect: to_forwards(config.connect),
+            listen: to_forwards(config.listen),
+        }
+    }
+}
+
+impl From<&Vsock<VsockHostBackend>> for VsockDeviceConfig {
+    fn from(vsock: &Vsock<VsockHostBackend>) -> Self {
+        let rx_rl: RateLimiterConfig = vsock.rx_rate_limiter().into();
+        let tx_rl: RateLimiterConfig = vsock.tx_rate_limiter().into();
+        let conn_limits = vsock.backend().conn_limits();
         VsockDeviceConfig {
             vsock_id: None,
-            guest_cid: u32::try_from(vsock_lock.cid()).unwrap(),
-            uds_path: vsock.uds_path.clone(),
+            guest_cid: u32::try_from(vsock.cid()).unwrap(),
+            uds_path: vsock
+                .backend()
+                .host_sock_path()
+                .map(str::to_owned)
+                .unwrap_or_default(),
+            tcp: vsock.backend().tcp_config().map(VsockTcpDeviceConfig::from),
+            rx_rate_limiter: rx_rl.into_option(),
+            tx_rate_limiter: tx_rl.into_option(),
+            max_connections: Some(conn_limits.max_connections)
+                .filter(|&max| max != VSOCK_MAX_CONNECTIONS),
+            allowed_ports: conn_limits.allowed_ports.clone(),
         }
     }
 }
 
-/// A builder of Vsock with Unix backend from 'VsockDeviceConfig'.
+/// A builder of Vsock from 'VsockDeviceConfig'.
 #[derive(Default)]
 pub struct VsockBuilder {
-    inner: Option<VsockAndUnixPath>,
+    inner: Option<MutexVsock>,
 }
 
 impl VsockBuilder {
-    /// Creates an empty Vsock with Unix backend Store.
+    /// Creates an empty Vsock Store.
     pub fn new() -> Self {
         Self { inner: None }
     }
 
     /// Inserts an existing vsock device.
-    pub fn set_device(&mut self, device: Arc<Mutex<Vsock<VsockUnixBackend>>>) {
-        self.inner = Some(VsockAndUnixPath {
-            uds_path: device
-                .lock()
-                .expect("Poisoned lock")
-                .backend()
-                .host_sock_path()
-                .to_owned(),
-            vsock: device.clone(),
-        });
+    pub fn set_device(&mut self, device: MutexVsock) {
+        self.inner = Some(device);
     }
 
-    /// Inserts a Unix backend Vsock in the store.
+    /// Inserts a Vsock in the store.
     /// If an entry already exists, it will overwrite it.
     pub fn insert(&mut self, cfg: VsockDeviceConfig) -> Result<()> {
-        // Make sure to drop the old one and remove the socket before creating a new one.
+        // Make sure to drop the old one and remove its socket before creating a new one.
         if let Some(existing) = self.inner.take() {
-            std::fs::remove_file(existing.uds_path)
-                .map_err(VsockUnixBackendError::UnixBind)
-                .map_err(VsockConfigError::CreateVsockBackend)?;
+            let uds_path = existing
+                .lock()
+                .expect("Poisoned lock")
+                .backend()
+                .host_sock_path()
+                .map(str::to_owned);
+            drop(existing);
+            if let Some(uds_path) = uds_path {
+                std::fs::remove_file(uds_path)
+                    .map_err(VsockUnixBackendError::UnixBind)
+                    .map_err(VsockHostBackendError::Unix)
+                    .map_err(VsockConfigError::CreateVsockBackend)?;
+            }
         }
-        self.inner = Some(VsockAndUnixPath {
-            uds_path: cfg.uds_path.clone(),
-            vsock: Arc::new(Mutex::new(Self::create_unixsock_vsock(cfg)?)),
-        });
+        self.inner = Some(Arc::new(Mutex::new(Self::create_vsock(cfg)?)));
         Ok(())
     }
 
     /// Provides a reference to the Vsock if present.
-    pub fn get(&self) -> Option<&MutexVsockUnix> {
-        self.inner.as_ref().map(|pair| &pair.vsock)
+    pub fn get(&self) -> Option<&MutexVsock> {
+        self.inner.as_ref()
     }
 
     /// Creates a Vsock device from a VsockDeviceConfig.
-    pub fn create_unixsock_vsock(cfg: VsockDeviceConfig) -> Result<Vsock<VsockUnixBackend>> {
-        let backend = VsockUnixBackend::new(u64::from(cfg.guest_cid), cfg.uds_path)
-            .map_err(VsockConfigError::CreateVsockBackend)?;
+    pub fn create_vsock(cfg: VsockDeviceConfig) -> Result<Vsock<VsockHostBackend>> {
+        let cid = u64::from(cfg.guest_cid);
+        let max_connections = cfg.max_connections.unwrap_or(VSOCK_MAX_CONNECTIONS);
+        if max_connections == 0 || max_connections > VSOCK_MAX_CONNECTIONS {
+            return Err(VsockConfigError::InvalidConnectionLimit(max_connections));
+        }
+        let conn_limits = VsockConnLimits {
+            max_connections,
+            allowed_ports: cfg.allowed_ports,
+        };
+        if cfg
+            .rx_rate_limiter
+            .iter()
+            .chain(cfg.tx_rate_limiter.iter())
+            .any(RateLimiterConfig::has_direction_buckets)
+        {
+            return Err(VsockConfigError::DirectionalRateLimiter);
+        }
+        let rx_rate_limiter = cfg
+            .rx_rate_limiter
+            .map(RateLimiterConfig::try_into)
+            .transpose()
+            .map_err(VsockConfigError::CreateRateLimiter)?;
+        let tx_rate_limiter = cfg
+            .tx_rate_limiter
+            .map(RateLimiterConfig::try_into)
+            .transpose()
+            .map_err(VsockConfigError::CreateRateLimiter)?;
+
+        let backend = match (cfg.tcp, cfg.uds_path.is_empty()) {
+            (Some(_), false) => return Err(VsockConfigError::UdsPathWithTcp),
+            (Some(tcp_cfg), true) => VsockHostBackend::new_tcp(cid, tcp_cfg.into(), conn_limits),
+            (None, false) => VsockHostBackend::new_unix(cid, cfg.uds_path, conn_limits),
+            (None, true) => return Err(VsockConfigError::MissingBackend),
+        }
+        .map_err(VsockConfigError::CreateVsockBackend)?;
 
-        Vsock::new(u64::from(cfg.guest_cid), backend).map_err(VsockConfigError::CreateVsockDevice)
+        Vsock::new(
+            cid,
+            backend,
+            rx_rate_limiter.unwrap_or_default(),
+            tx_rate_limiter.unwrap_or_default(),
+        )
+        .map_err(VsockConfigError::CreateVsockDevice)
     }
 
     /// Returns the structure used to configure the vsock device.
     pub fn config(&self) -> Option<VsockDeviceConfig> {
-        self.inner.as_ref().map(VsockDeviceConfig::from)
+        self.inner
+            .as_ref()
+            .map(|vsock| VsockDeviceConfig::from(&*vsock.lock().expect("Poisoned lock")))
     }
 }
 
 #[cfg(test)]
 pub(crate) mod tests {
     use super::*;
+    use crate::vmm_config::TokenBucketConfig;
     use devices::virtio::vsock::VSOCK_DEV_ID;
+    use rate_limiter::RateLimiter;
     use utils::tempfile::TempFile;
 
     pub(crate) fn default_config(tmp_sock_file: &TempFile) -> VsockDeviceConfig {
@@ -136,6 +310,11 @@ pub(crate) mod tests {
             vsock_id: None,
             guest_cid: 3,
             uds_path: tmp_sock_file.as_path().to_str().unwrap().to_string(),
+            tcp: None,
+            rx_rate_limiter: None,
+            tx_rate_limiter: None,
+            max_connections: None,
+            allowed_ports: None,
         }
     }
 
@@ -144,7 +323,50 @@ pub(crate) mod tests {
         let mut tmp_sock_file = TempFile::new().unwrap();
         tmp_sock_file.remove().unwrap();
         let vsock_config = default_config(&tmp_sock_file);
-        VsockBuilder::create_unixsock_vsock(vsock_config).unwrap();
+        VsockBuilder::create_vsock(vsock_config).unwrap();
+    }
+
+    #[test]
+    fn test_vsock_create_tcp() {
+        let tcp_config = VsockTcpDeviceConfig {
+            connect: vec![VsockTcpForwardConfig {
+                guest_port: 1026,
+                host_addr: "127.0.0.1:8080".parse().unwrap(),
+            }],
+            listen: vec![VsockTcpForwardConfig {
+                guest_port: 1025,
+                host_addr: "127.0.0.1:0".parse().unwrap(),
+            }],
+        };
+        let mut vsock_config = VsockDeviceConfig {
+            vsock_id: None,
+            guest_cid: 3,
+            uds_path: String::new(),
+            tcp: Some(tcp_config),
+            rx_rate_limiter: None,
+            tx_rate_limiter: None,
+            max_connections: None,
+            allowed_ports: None,
+        };
+
+        let mut vsock_builder = VsockBuilder::new();
+        vsock_builder.insert(vsock_config.clone()).unwrap();
+        assert_eq!(vsock_builder.config().unwrap(), vsock_config);
+        // The listening sockets are closed when the device is replaced.
+        vsock_builder.insert(vsock_config.clone()).unwrap();
+
+        vsock_config.uds_path = "/tmp/vsock".to_string();
+        match VsockBuilder::create_vsock(vsock_config.clone()) {
+            Err(VsockConfigError::UdsPathWithTcp) => (),
+            _ => panic!("Unexpected result."),
+        }
+
+        vsock_config.uds_path = String::new();
+        vsock_config.tcp = None;
+        match VsockBuilder::create_vsock(vsock_config) {
+            Err(VsockConfigError::MissingBackend) => (),
+            _ => panic!("Unexpected result."),
+        }
     }
 
     #[test]
@@ -165,6 +387,57 @@ pub(crate) mod tests {
         assert_eq!(vsock.lock().unwrap().cid(), new_cid as u64);
     }
 
+    #[test]
+    fn test_vsock_limits() {
+        let mut tmp_sock_file = TempFile::new().unwrap();
+        tmp_sock_file.remove().unwrap();
+        let mut vsock_config = default_config(&tmp_sock_file);
+        vsock_config.rx_rate_limiter = Some(RateLimiterConfig {
+            bandwidth: Some(TokenBucketConfig {
+                size: 1000,
+                one_time_burst: None,
+                refill_time: 100,
+            }),
+            ..Default::default()
+        });
+        vsock_config.max_connections = Some(16);
+        vsock_config.allowed_ports = Some(vec![1024, 1026]);
+
+        let mut vsock_builder = VsockBuilder::new();
+        vsock_builder.insert(vsock_config.clone()).unwrap();
+        assert_eq!(vsock_builder.config().unwrap(), vsock_config);
+        {
+            let vsock = vsock_builder.get().unwrap().lock().unwrap();
+            assert!(vsock.rx_rate_limiter().bandwidth().is_some());
+            assert!(vsock.tx_rate_limiter().bandwidth().is_none());
+            assert_eq!(vsock.backend().conn_limits().max_connections, 16);
+        }
+
+        // The connection limit must be between 1 and `VSOCK_MAX_CONNECTIONS`.
+        for &limit in [0, VSOCK_MAX_CONNECTIONS + 1].iter() {
+            vsock_config.max_connections = Some(limit);
+            match VsockBuilder::create_vsock(vsock_config.clone()) {
+                Err(VsockConfigError::InvalidConnectionLimit(l)) => assert_eq!(l, limit),
+                _ => panic!("Unexpected result."),
+            }
+        }
+
+        // Per-direction buckets are reserved to block devices.
+        vsock_config.max_connections = None;
+        vsock_config.tx_rate_limiter = Some(RateLimiterConfig {
+            write_ops: Some(TokenBucketConfig {
+                size: 10,
+                one_time_burst: None,
+                refill_time: 100,
+            }),
+            ..Default::default()
+        });
+        match VsockBuilder::create_vsock(vsock_config) {
+            Err(VsockConfigError::DirectionalRateLimiter) => (),
+            _ => panic!("Unexpected result."),
+        }
+    }
+
     #[test]
     fn test_vsock_config() {
         let mut vsock_builder = VsockBuilder::new();
@@ -182,11 +455,26 @@ pub(crate) mod tests {
     fn test_error_messages() {
         use super::VsockConfigError::*;
         use std::io;
-        let err = CreateVsockBackend(devices::virtio::VsockUnixBackendError::EpollAdd(
-            io::Error::from_raw_os_error(0),
+        let err = CreateVsockBackend(VsockHostBackendError::Unix(
+            VsockUnixBackendError::EpollAdd(io::Error::from_raw_os_error(0)),
         ));
         let _ = format!("{}{:?}", err, err);
 
+        let err = MissingBackend;
+        let _ = format!("{}{:?}", err, err);
+
+        let err = UdsPathWithTcp;
+        let _ = format!("{}{:?}", err, err);
+
+        let err = CreateRateLimiter(io::Error::from_raw_os_error(0));
+        let _ = format!("{}{:?}", err, err);
+
+        let err = InvalidConnectionLimit(0);
+        let _ = format!("{}{:?}", err, err);
+
+        let err = DeviceUpdate(VmmError::VcpuExit);
+        let _ = format!("{}{:?}", err, err);
+
         let err = CreateVsockDevice(devices::virtio::VsockError::EventFd(
             io::Error::from_raw_os_error(0),
         ));
@@ -200,15 +488,21 @@ pub(crate) mod tests {
         tmp_sock_file.remove().unwrap();
         let vsock = Vsock::new(
             0,
-            VsockUnixBackend::new(1, tmp_sock_file.as_path().to_str().unwrap().to_string())
-                .unwrap(),
+            VsockHostBackend::new_unix(
+                1,
+                tmp_sock_file.as_path().to_str().unwrap().to_string(),
+                VsockConnLimits::default(),
+            )
+            .unwrap(),
+            RateLimiter::default(),
+            RateLimiter::default(),
         )
         .unwrap();
 
         vsock_builder.set_device(Arc::new(Mutex::new(vsock)));
         assert!(vsock_builder.inner.is_some());
         assert_eq!(
-            vsock_builder.inner.unwrap().uds_path,
+            vsock_builder.config().unwrap().uds_path,
             tmp_sock_file.as_path().to_str().unwrap().to_string()
         )
     }
@@ -12,19 +12,22 @@ use std::sync::{Arc, Barrier};
 use std::{
     cell::Cell,
     fmt::{Display, Formatter},
-    io, result,
+    fs, io,
+    path::Path,
+    result,
     sync::atomic::{fence, Ordering},
     sync::mpsc::{channel, Receiver, Sender, TryRecvError},
     thread,
 };
 
 use crate::{
-    vmm_config::machine_config::CpuFeaturesTemplate, vstate::vm::Vm, FC_EXIT_CODE_GENERIC_ERROR,
-    FC_EXIT_CODE_OK,
+    vmm_config::{cpu_template::CustomCpuTemplate, machine_config::CpuFeaturesTemplate},
+    vstate::vm::Vm,
+    FC_EXIT_CODE_GENERIC_ERROR, FC_EXIT_CODE_OK,
 };
 use kvm_bindings::{KVM_SYSTEM_EVENT_RESET, KVM_SYSTEM_EVENT_SHUTDOWN};
 use kvm_ioctls::VcpuExit;
-use logger::{error, info, IncMetric, METRICS};
+use logger::{error, info, warn, IncMetric, METRICS};
 use seccompiler::{BpfProgram, BpfProgramRef};
 use utils::{
     errno,
@@ -47,6 +50,10 @@ pub(crate) use x86_64::{Error as VcpuError, *};
 /// Signal number (SIGRTMIN) used to kick Vcpus.
 pub(crate) const VCPU_RTSIG_OFFSET: i32 = 0;
 
+/// Directory where the jailer mounts the cgroup of the microVM when pinning its threads. Each
+/// vCPU thread moves itself to the `vcpu<index>` threaded cgroup, if present.
+const VCPU_CGROUPS_DIR: &str = "/sys/fs/cgroup";
+
 /// Errors associated with the wrappers over KVM ioctls.
 #[derive(Debug)]
 pub enum Error {
@@ -93,6 +100,8 @@ pub struct VcpuConfig {
     pub smt: bool,
     /// CPUID template to use.
     pub cpu_template: CpuFeaturesTemplate,
+    /// User-defined CPU template, applied on top of `cpu_template`.
+    pub custom_cpu_template: Option<CustomCpuTemplate>,
 }
 
 // Using this for easier explicit type-casting to help IDEs interpret the code.
@@ -244,6 +253,7 @@ impl Vcpu {
             .name(format!("fc_vcpu {}", self.kvm_vcpu.index))
             .spawn(move || {
                 let filter = &*seccomp_filter;
+                self.join_cgroup();
                 self.init_thread_local_data()
                     .expect("Cannot cleanly initialize vcpu TLS.");
                 // Synchronization to make sure thread local data is initialized.
@@ -259,6 +269,26 @@ impl Vcpu {
         ))
     }
 
+    /// Moves the current thread to the cgroup reserved for this vCPU by the jailer, if any.
+    /// Must be called before loading the seccomp filters.
+    fn join_cgroup(&self) {
+        let threads_file = Path::new(VCPU_CGROUPS_DIR)
+            .join(format!("vcpu{}", self.kvm_vcpu.index))
+            .join("cgroup.threads");
+        if !threads_file.exists() {
+            return;
+        }
+
+        // Safe because this call cannot fail.
+        let tid = unsafe { libc::syscall(libc::SYS_gettid) };
+        if let Err(err) = fs::write(&threads_file, tid.to_string()) {
+            warn!(
+                "Failed to move vCPU {} to its cgroup: {}",
+                self.kvm_vcpu.index, err
+            );
+        }
+    }
+
     /// Main loop of the vCPU thread.
     ///
     /// Runs the vCPU in KVM context in a loop. Handles KVM_EXITs then goes back in.
@@ -913,6 +943,7 @@ mod tests {
                 vcpu_count: 1,
                 smt: false,
                 cpu_template: CpuFeaturesTemplate::None,
+                custom_cpu_template: None,
             };
             vcpu.kvm_vcpu
                 .configure(
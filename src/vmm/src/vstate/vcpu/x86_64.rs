@@ -10,6 +10,7 @@ use std::{
     result,
 };
 
+use crate::vmm_config::cpu_template::{CpuTemplateError, CustomCpuTemplate};
 use crate::vmm_config::machine_config::CpuFeaturesTemplate;
 use crate::vstate::{
     vcpu::{VcpuConfig, VcpuEmulation},
@@ -37,6 +38,8 @@ const TSC_KHZ_TOL: f64 = 250.0 / 1_000_000.0;
 pub enum Error {
     /// A call to cpuid instruction failed.
     CpuId(cpuid::Error),
+    /// Failed to apply the custom CPU template.
+    CpuTemplate(CpuTemplateError),
     /// A FamStructWrapper operation has failed.
     FamError(utils::fam::Error),
     /// Error configuring the floating point related registers
@@ -105,6 +108,7 @@ impl Display for Error {
 
         match self {
             CpuId(e) => write!(f, "Cpuid error: {:?}", e),
+            CpuTemplate(e) => write!(f, "Cannot apply the custom CPU template: {}", e),
             LocalIntConfiguration(e) => write!(
                 f,
                 "Cannot set the local interruption due to bad configuration: {:?}",
@@ -219,10 +223,18 @@ impl KvmVcpu {
             }
             CpuFeaturesTemplate::None => {}
         }
+        if let Some(template) = vcpu_config.custom_cpu_template.as_ref() {
+            template
+                .apply_to_cpuid(&mut cpuid)
+                .map_err(Error::CpuTemplate)?;
+        }
 
         self.fd.set_cpuid2(&cpuid).map_err(Error::VcpuSetCpuid)?;
 
         arch::x86_64::msr::setup_msrs(&self.fd).map_err(Error::MSRSConfiguration)?;
+        if let Some(template) = vcpu_config.custom_cpu_template.as_ref() {
+            self.apply_msr_modifiers(template)?;
+        }
         arch::x86_64::regs::setup_regs(&self.fd, kernel_start_addr.raw_value() as u64)
             .map_err(Error::REGSConfiguration)?;
         arch::x86_64::regs::setup_fpu(&self.fd).map_err(Error::FPUConfiguration)?;
@@ -231,6 +243,46 @@ impl KvmVcpu {
         Ok(())
     }
 
+    // Overwrites the MSRs modified by `template`, keeping the bits it leaves unchanged.
+    fn apply_msr_modifiers(&self, template: &CustomCpuTemplate) -> Result<()> {
+        if template.msr_modifiers.is_empty() {
+            return Ok(());
+        }
+
+        let mut msrs = Msrs::new(template.msr_modifiers.len()).map_err(Error::FamError)?;
+        for (entry, modifier) in msrs
+            .as_mut_slice()
+            .iter_mut()
+            .zip(template.msr_modifiers.iter())
+        {
+            entry.index = modifier.addr;
+        }
+        // Both ioctls stop at the first MSR they cannot handle.
+        let unsupported_msr = |msrs: &Msrs, count: usize| {
+            Error::CpuTemplate(CpuTemplateError::UnsupportedMsr(
+                msrs.as_slice()[count].index,
+            ))
+        };
+
+        let count = self.fd.get_msrs(&mut msrs).map_err(Error::VcpuGetMsrs)?;
+        if count != template.msr_modifiers.len() {
+            return Err(unsupported_msr(&msrs, count));
+        }
+        for (entry, modifier) in msrs
+            .as_mut_slice()
+            .iter_mut()
+            .zip(template.msr_modifiers.iter())
+        {
+            entry.data = modifier.bitmap.apply(entry.data);
+        }
+        let count = self.fd.set_msrs(&msrs).map_err(Error::VcpuSetMsrs)?;
+        if count != template.msr_modifiers.len() {
+            return Err(unsupported_msr(&msrs, count));
+        }
+
+        Ok(())
+    }
+
     /// Sets a Port Mapped IO bus for this vcpu.
     pub fn set_pio_bus(&mut self, pio_bus: devices::Bus) {
         self.pio_bus = Some(pio_bus);
@@ -459,6 +511,11 @@ impl VcpuState {
 
         Ok(())
     }
+
+    /// Returns the indices of the MSRs saved in this state.
+    pub fn msr_indices(&self) -> Vec<u32> {
+        self.msrs.as_slice().iter().map(|msr| msr.index).collect()
+    }
 }
 
 #[cfg(test)]
@@ -505,6 +562,7 @@ mod tests {
             vcpu_count: 1,
             smt: false,
             cpu_template: CpuFeaturesTemplate::None,
+            custom_cpu_template: None,
         };
 
         assert!(vcpu
@@ -546,6 +604,84 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_configure_vcpu_custom_template() {
+        let (vm, vcpu, vm_mem) = setup_vcpu(0x10000);
+        let template: CustomCpuTemplate = serde_json::from_str(
+            r#"{
+                "cpuid_modifiers": [
+                    { "leaf": "0x1", "modifiers": [{ "register": "ecx", "bitmap": "0b1x" }] }
+                ],
+                "msr_modifiers": [{ "addr": "0x1a0", "bitmap": "0b1" }]
+            }"#,
+        )
+        .unwrap();
+        let mut vcpu_config = VcpuConfig {
+            vcpu_count: 1,
+            smt: false,
+            cpu_template: CpuFeaturesTemplate::None,
+            custom_cpu_template: Some(template.clone()),
+        };
+        vcpu.configure(
+            &vm_mem,
+            GuestAddress(0),
+            &vcpu_config,
+            vm.supported_cpuid().clone(),
+        )
+        .unwrap();
+
+        let cpuid = vcpu
+            .fd
+            .get_cpuid2(kvm_bindings::KVM_MAX_CPUID_ENTRIES)
+            .unwrap();
+        let leaf_0x1 = cpuid
+            .as_slice()
+            .iter()
+            .find(|entry| entry.function == 0x1)
+            .unwrap();
+        assert_eq!(leaf_0x1.ecx & 0x2, 0x2);
+        let mut msrs = Msrs::from_entries(&[kvm_bindings::kvm_msr_entry {
+            index: 0x1a0,
+            ..Default::default()
+        }])
+        .unwrap();
+        vcpu.fd.get_msrs(&mut msrs).unwrap();
+        assert_eq!(msrs.as_slice()[0].data & 0x1, 0x1);
+
+        // MSRs unknown to KVM are rejected.
+        let mut invalid_template = template.clone();
+        invalid_template.msr_modifiers[0].addr = 0xdead;
+        vcpu_config.custom_cpu_template = Some(invalid_template);
+        assert_eq!(
+            vcpu.configure(
+                &vm_mem,
+                GuestAddress(0),
+                &vcpu_config,
+                vm.supported_cpuid().clone(),
+            )
+            .unwrap_err()
+            .to_string(),
+            "Cannot apply the custom CPU template: MSR 0xdead is not supported by the host."
+        );
+
+        // So are CPUID leaves the guest doesn't have.
+        let mut invalid_template = template;
+        invalid_template.cpuid_modifiers[0].leaf = 0x4fff_ffff;
+        vcpu_config.custom_cpu_template = Some(invalid_template);
+        assert_eq!(
+            vcpu.configure(
+                &vm_mem,
+                GuestAddress(0),
+                &vcpu_config,
+                vm.supported_cpuid().clone(),
+            )
+            .unwrap_err()
+            .to_string(),
+            "Cannot apply the custom CPU template: CPUID leaf 0x4fffffff, subleaf 0x0 is not \
+             available."
+        );
+    }
+
     #[test]
     fn test_vcpu_cpuid_restore() {
         let (_vm, vcpu, _) = setup_vcpu(0x1000);
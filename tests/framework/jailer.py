@@ -30,13 +30,19 @@ class JailerContext:
     gid = None
     chroot_base = None
     netns = None
+    new_netns = None
+    taps = None
     daemonize = None
     new_pid_ns = None
+    user_ns = None
+    supervise = None
     extra_args = None
     api_socket_name = None
     cgroups = None
     resource_limits = None
+    mounts = None
     cgroup_ver = None
+    cgroup_profile = None
     parent_cgroup = None
 
     def __init__(
@@ -47,11 +53,17 @@ class JailerContext:
             gid=1234,
             chroot_base=DEFAULT_CHROOT_PATH,
             netns=None,
+            new_netns=False,
+            taps=None,
             daemonize=True,
             new_pid_ns=False,
+            user_ns=False,
+            supervise=False,
             cgroups=None,
             resource_limits=None,
+            mounts=None,
             cgroup_ver=None,
+            cgroup_profile=None,
             parent_cgroup=None,
             **extra_args
     ):
@@ -67,13 +79,19 @@ class JailerContext:
         self.gid = gid
         self.chroot_base = chroot_base
         self.netns = netns if netns is not None else jailer_id
+        self.new_netns = new_netns
+        self.taps = taps
         self.daemonize = daemonize
         self.new_pid_ns = new_pid_ns
+        self.user_ns = user_ns
+        self.supervise = supervise
         self.extra_args = extra_args
         self.api_socket_name = DEFAULT_USOCKET_NAME
         self.cgroups = cgroups
         self.resource_limits = resource_limits
+        self.mounts = mounts
         self.cgroup_ver = cgroup_ver
+        self.cgroup_profile = cgroup_profile
         self.parent_cgroup = parent_cgroup
         self.ramfs_subdir_name = 'ramfs'
         self._ramfs_path = None
@@ -110,10 +128,19 @@ class JailerContext:
             )
         if self.netns is not None:
             jailer_param_list.extend(['--netns', str(self.netns_file_path())])
+        if self.new_netns:
+            jailer_param_list.append('--new-netns')
+        if self.taps is not None:
+            for tap in self.taps:
+                jailer_param_list.extend(['--tap', str(tap)])
         if self.daemonize:
             jailer_param_list.append('--daemonize')
         if self.new_pid_ns:
             jailer_param_list.append('--new-pid-ns')
+        if self.user_ns:
+            jailer_param_list.append('--user-ns')
+        if self.supervise:
+            jailer_param_list.append('--supervise')
         if self.parent_cgroup:
             jailer_param_list.extend(
                 ['--parent-cgroup', str(self.parent_cgroup)]
@@ -125,9 +152,16 @@ class JailerContext:
         if self.cgroups is not None:
             for cgroup in self.cgroups:
                 jailer_param_list.extend(['--cgroup', str(cgroup)])
+        if self.cgroup_profile is not None:
+            jailer_param_list.extend(
+                ['--cgroup-profile', str(self.cgroup_profile)]
+            )
         if self.resource_limits is not None:
             for limit in self.resource_limits:
                 jailer_param_list.extend(['--resource-limit', str(limit)])
+        if self.mounts is not None:
+            for mount in self.mounts:
+                jailer_param_list.extend(['--mount', str(mount)])
         # applying necessary extra args if needed
         if len(self.extra_args) > 0:
             jailer_param_list.append('--')
@@ -264,7 +264,8 @@ class Drive():
             is_read_only=None,
             rate_limiter=None,
             cache_type=None,
-            io_engine=None):
+            io_engine=None,
+            num_queues=None):
         """Compose the json associated to this type of API request."""
         datax = {}
 
@@ -292,6 +293,9 @@ class Drive():
         if io_engine is not None:
             datax['io_engine'] = io_engine
 
+        if num_queues is not None:
+            datax['num_queues'] = num_queues
+
         return datax
 
 
@@ -463,7 +467,8 @@ class SnapshotLoad():
         )
 
     @staticmethod
-    def create_json(mem_file_path, snapshot_path, diff=False, resume=False):
+    def create_json(mem_file_path, snapshot_path, diff=False, resume=False,
+                    dry_run=False):
         """Compose the json associated to this type of API request."""
         datax = {
             'mem_file_path': mem_file_path,
@@ -473,6 +478,8 @@ class SnapshotLoad():
             datax['enable_diff_snapshots'] = True
         if resume:
             datax['resume_vm'] = True
+        if dry_run:
+            datax['dry_run'] = True
         return datax
 
 
@@ -517,6 +524,14 @@ class SnapshotHelper():
 
         return response
 
+    def check(self, mem_file_path, snapshot_path):
+        """Check a snapshot against the host CPU, without loading it."""
+        return self._load.put(
+            mem_file_path=mem_file_path,
+            snapshot_path=snapshot_path,
+            dry_run=True
+        )
+
 
 class Metrics:
     """Facility for setting up the metrics system and sending API requests."""
@@ -613,6 +628,7 @@ class MachineConfigure():
             mem_size_mib=None,
             smt=None,
             cpu_template=None,
+            cpu_template_path=None,
             track_dirty_pages=None):
         """Compose the json associated to this type of API request."""
         datax = {}
@@ -630,6 +646,9 @@ class MachineConfigure():
         if cpu_template is not None:
             datax['cpu_template'] = cpu_template
 
+        if cpu_template_path is not None:
+            datax['cpu_template_path'] = cpu_template_path
+
         if track_dirty_pages is not None:
             datax['track_dirty_pages'] = track_dirty_pages
 
@@ -663,6 +682,12 @@ class MMDS():
             json=args['json']
         )
 
+    def revoke_tokens(self):
+        """Send a request to revoke all the MMDS session tokens."""
+        return self._api_session.delete(
+            "{}".format(self._mmds_cfg_url + "/tokens")
+        )
+
     def patch(self, **args):
         """Update the details of some MMDS request."""
         return self._api_session.patch(
@@ -715,6 +740,8 @@ class Network():
             guest_mac=None,
             rx_rate_limiter=None,
             tx_rate_limiter=None,
+            egress_filter=None,
+            user_net=None,
             allow_mmds_requests=None):
         """Create the json for the net specific API request."""
         datax = {
@@ -733,6 +760,12 @@ class Network():
         if rx_rate_limiter is not None:
             datax['rx_rate_limiter'] = rx_rate_limiter
 
+        if egress_filter is not None:
+            datax['egress_filter'] = egress_filter
+
+        if user_net is not None:
+            datax['user_net'] = user_net
+
         # Keep this for interacting with older FC versions in snapshot tests.
         if allow_mmds_requests is not None:
             datax['allow_mmds_requests'] = allow_mmds_requests
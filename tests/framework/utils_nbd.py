@@ -0,0 +1,164 @@
+# Copyright 2022 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+# SPDX-License-Identifier: Apache-2.0
+"""Helper functions for testing NBD backed drives."""
+
+import os
+import struct
+from socket import socket, AF_UNIX, SOCK_STREAM, SHUT_RDWR
+from threading import Thread
+
+NBD_MAGIC = 0x4e42444d41474943
+NBD_OPTS_MAGIC = 0x49484156454f5054
+NBD_REP_MAGIC = 0x0003e889045565a9
+NBD_REQUEST_MAGIC = 0x25609513
+NBD_SIMPLE_REPLY_MAGIC = 0x67446698
+
+NBD_FLAG_FIXED_NEWSTYLE = 1 << 0
+NBD_FLAG_NO_ZEROES = 1 << 1
+NBD_OPT_GO = 7
+NBD_REP_ACK = 1
+NBD_REP_INFO = 3
+NBD_REP_ERR_UNSUP = (1 << 31) | 1
+NBD_INFO_EXPORT = 0
+
+# NBD_FLAG_HAS_FLAGS | NBD_FLAG_SEND_FLUSH | NBD_FLAG_SEND_TRIM |
+# NBD_FLAG_SEND_WRITE_ZEROES | NBD_FLAG_CAN_MULTI_CONN
+EXPORT_FLAGS = (1 << 0) | (1 << 2) | (1 << 5) | (1 << 6) | (1 << 8)
+
+NBD_CMD_READ = 0
+NBD_CMD_WRITE = 1
+NBD_CMD_DISC = 2
+NBD_CMD_FLUSH = 3
+NBD_CMD_TRIM = 4
+NBD_CMD_WRITE_ZEROES = 6
+
+EINVAL = 22
+ZEROES_CHUNK_LEN = 1024 * 1024
+
+
+def _recv_exact(conn, length):
+    buf = b''
+    while len(buf) < length:
+        data = conn.recv(length - len(buf))
+        if not data:
+            raise EOFError("NBD client went away")
+        buf += data
+    return buf
+
+
+class NbdServer(Thread):
+    """A minimal NBD server, exporting a file on a Unix socket.
+
+    The server speaks the fixed newstyle protocol, only sends simple replies,
+    and serves each connection from its own thread. The export advertises
+    multi-connection consistency, so it can back drives with several queues.
+    """
+
+    def __init__(self, path, backing_file):
+        """."""
+        super().__init__(daemon=True)
+        self.path = path
+        self.backing_file = backing_file
+        self.size = os.path.getsize(backing_file)
+        self.sock = socket(AF_UNIX, SOCK_STREAM)
+        self.sock.bind(path)
+        self.sock.listen(16)
+        self.connections = 0
+        self.error = None
+
+    def run(self):
+        """Accept connections until the server exits."""
+        while True:
+            try:
+                conn, _ = self.sock.accept()
+            except OSError:
+                # The listening socket was shut down.
+                return
+            self.connections += 1
+            Thread(target=self._serve, args=(conn,), daemon=True).start()
+
+    def exit(self):
+        """Stop accepting connections, and wait for the server to exit."""
+        self.sock.shutdown(SHUT_RDWR)
+        self.join()
+        self.sock.close()
+        os.remove(self.path)
+
+    def _serve(self, conn):
+        try:
+            with conn, open(self.backing_file, 'r+b', buffering=0) as disk:
+                self._handshake(conn)
+                self._transmission(conn, disk)
+        except (ConnectionError, EOFError):
+            # The client may go away at any point.
+            pass
+        # pylint: disable=broad-except
+        except Exception as err:
+            self.error = err
+
+    @staticmethod
+    def _option_reply(conn, option, reply, data=b''):
+        conn.sendall(
+            struct.pack('>QIII', NBD_REP_MAGIC, option, reply, len(data)) +
+            data
+        )
+
+    def _handshake(self, conn):
+        conn.sendall(struct.pack(
+            '>QQH', NBD_MAGIC, NBD_OPTS_MAGIC,
+            NBD_FLAG_FIXED_NEWSTYLE | NBD_FLAG_NO_ZEROES
+        ))
+        # Client flags.
+        _recv_exact(conn, 4)
+        while True:
+            magic, option, length = struct.unpack(
+                '>QII', _recv_exact(conn, 16)
+            )
+            assert magic == NBD_OPTS_MAGIC
+            _recv_exact(conn, length)
+            if option == NBD_OPT_GO:
+                info = struct.pack(
+                    '>HQH', NBD_INFO_EXPORT, self.size, EXPORT_FLAGS
+                )
+                self._option_reply(conn, option, NBD_REP_INFO, info)
+                self._option_reply(conn, option, NBD_REP_ACK)
+                return
+            # Structured replies, amongst others, aren't supported.
+            self._option_reply(conn, option, NBD_REP_ERR_UNSUP)
+
+    def _transmission(self, conn, disk):
+        while True:
+            magic, _, cmd, cookie, offset, length = struct.unpack(
+                '>IHHQQI', _recv_exact(conn, 28)
+            )
+            assert magic == NBD_REQUEST_MAGIC
+            if cmd == NBD_CMD_DISC:
+                return
+            data = _recv_exact(conn, length) if cmd == NBD_CMD_WRITE else b''
+
+            errno, payload = 0, b''
+            if offset + length > self.size:
+                errno = EINVAL
+            elif cmd == NBD_CMD_READ:
+                disk.seek(offset)
+                payload = disk.read(length)
+            elif cmd == NBD_CMD_WRITE:
+                disk.seek(offset)
+                disk.write(data)
+            elif cmd == NBD_CMD_WRITE_ZEROES:
+                disk.seek(offset)
+                while length > 0:
+                    chunk_len = min(length, ZEROES_CHUNK_LEN)
+                    disk.write(bytes(chunk_len))
+                    length -= chunk_len
+            elif cmd == NBD_CMD_FLUSH:
+                os.fsync(disk.fileno())
+            elif cmd == NBD_CMD_TRIM:
+                # Trimming is only advisory, and is skipped.
+                pass
+            else:
+                errno = EINVAL
+            conn.sendall(
+                struct.pack('>IIQ', NBD_SIMPLE_REPLY_MAGIC, errno, cookie) +
+                payload
+            )
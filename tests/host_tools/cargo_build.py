@@ -133,6 +133,33 @@ def run_seccompiler_bin(bpf_path,
     assert rc == 0
 
 
+def run_seccompiler_decompile(bpf_path, diff_path=None):
+    """
+    Run seccompiler-decompile.
+
+    :param bpf_path: path to the compiled filters
+    :param diff_path: optional path to other compiled filters to compare with
+    :return: tuple of (return code, stdout)
+    """
+    cargo_target = '{}-unknown-linux-musl'.format(platform.machine())
+
+    cmd = 'cargo run -p seccompiler --bin seccompiler-decompile\
+        --target-dir {} --target {} --\
+        --input-file {} --target-arch {}'.format(
+        defs.SECCOMPILER_TARGET_DIR,
+        cargo_target,
+        bpf_path,
+        platform.machine()
+    )
+
+    if diff_path is not None:
+        cmd += ' --diff {}'.format(diff_path)
+
+    rc, stdout, _ = utils.run_cmd(cmd, ignore_return_code=True)
+
+    return rc, stdout
+
+
 def run_rebase_snap_bin(base_snap,
                         diff_snap):
     """
@@ -2,6 +2,7 @@
 # SPDX-License-Identifier: Apache-2.0
 """Tests that ensure the correctness of the command line parameters."""
 
+import json
 import logging
 import platform
 
@@ -76,3 +77,33 @@ def test_describe_snapshot_all_versions(bin_cloner_path):
         assert code == 0
         assert stderr == ''
         assert target_version in stdout
+
+
+def test_check_snapshot(bin_cloner_path):
+    """
+    Test `--check-snapshot` on a snapshot created on the same host.
+
+    @type: functional
+    """
+    builder = MicrovmBuilder(bin_cloner_path)
+    vm_instance = builder.build_vm_nano()
+    vm = vm_instance.vm
+    vm.start()
+
+    snapshot = SnapshotBuilder(vm).create([vm_instance.disks[0].local_path()],
+                                          vm_instance.ssh_key,
+                                          snapshot_type=SnapshotType.FULL)
+    vm.kill()
+
+    fc_binary, _ = get_firecracker_binaries()
+    cmd = [fc_binary, "--check-snapshot", snapshot.vmstate]
+    code, stdout, _ = run_cmd(cmd)
+    assert code == 0
+    assert "The host supports all the CPU features of the snapshot." in stdout
+
+    code, stdout, _ = run_cmd(cmd + ["--json-report"])
+    assert code == 0
+    assert json.loads(stdout) == {
+        "missing_cpuid_features": [],
+        "missing_msrs": []
+    }
@@ -2,6 +2,8 @@
 # SPDX-License-Identifier: Apache-2.0
 """Tests for the CPU topology emulation feature."""
 
+import json
+import os
 import platform
 import re
 import pytest
@@ -183,6 +185,62 @@ def test_cpu_template(test_microvm_with_api, network_config, cpu_template):
     check_enabled_features(test_microvm, cpu_template)
 
 
+@pytest.mark.skipif(
+    PLATFORM != "x86_64",
+    reason="Custom CPU templates are only supported on x86_64."
+)
+def test_custom_cpu_template(test_microvm_with_api, network_config):
+    """
+    Test that a user-defined CPU template is validated and applied.
+
+    @type: functional
+    """
+    test_microvm = test_microvm_with_api
+    test_microvm.spawn()
+    test_microvm.basic_config(vcpu_count=1)
+
+    template_path = os.path.join(test_microvm.path, 'cpu_template.json')
+
+    # Registers can only be modified once per template.
+    duplicate_msr = {"addr": "0x1a0", "bitmap": "0b1"}
+    with open(template_path, 'w', encoding='utf-8') as template_file:
+        json.dump({"msr_modifiers": [duplicate_msr, duplicate_msr]},
+                  template_file)
+    response = test_microvm.machine_cfg.patch(
+        cpu_template_path=test_microvm.create_jailed_resource(template_path)
+    )
+    assert test_microvm.api_session.is_status_bad_request(
+        response.status_code)
+    assert "is modified more than once" in response.text
+
+    # Hide the SSE4.2 feature (leaf 0x1, ecx bit 20) from the guest.
+    template = {
+        "cpuid_modifiers": [{
+            "leaf": "0x1",
+            "subleaf": "0x0",
+            "modifiers": [{
+                "register": "ecx",
+                "bitmap": "0bxxxx_xxxx_xxx0_xxxx_xxxx_xxxx_xxxx_xxxx"
+            }]
+        }]
+    }
+    with open(template_path, 'w', encoding='utf-8') as template_file:
+        json.dump(template, template_file)
+    response = test_microvm.machine_cfg.patch(
+        cpu_template_path=test_microvm.create_jailed_resource(template_path)
+    )
+    assert test_microvm.api_session.is_status_no_content(response.status_code)
+
+    _tap, _, _ = test_microvm.ssh_network_config(network_config, '1')
+    test_microvm.start()
+
+    ssh_connection = net_tools.SSHConnection(test_microvm.ssh_config)
+    guest_cmd = "cat /proc/cpuinfo | grep 'flags' | head -1"
+    _, stdout, stderr = ssh_connection.execute_command(guest_cmd)
+    assert stderr.read() == ''
+    assert "sse4_2" not in stdout.readline().rstrip().split(' ')
+
+
 def check_masked_features(test_microvm, cpu_template):
     """Verify the masked features of the given template."""
     common_masked_features_lscpu = ["dtes64", "monitor", "ds_cpl", "tm2",
@@ -6,6 +6,7 @@ import os
 import platform
 
 from framework import utils
+from framework.utils_nbd import NbdServer
 
 import host_tools.drive as drive_tools
 import host_tools.network as net_tools  # pylint: disable=import-error
@@ -606,6 +607,96 @@ def test_patch_drive_limiter(test_microvm_with_api, network_config):
     check_iops_limit(ssh_connection, 4096, 10000, 0.7, 1.3)
 
 
+def test_nbd_drive(test_microvm_with_api, network_config):
+    """
+    Test a drive backed by an NBD server, under the default seccomp filters.
+
+    The drive is updated after boot, which connects to a new server, and the
+    microVM is snapshotted, which drains the requests sent to the server.
+
+    @type: functional
+    """
+    test_microvm = test_microvm_with_api
+    test_microvm.spawn()
+
+    # Set up the microVM with 2 vCPUs, 256 MiB of RAM, 1 network iface, a root
+    # file system with the rw permission, and a scratch drive served over NBD
+    # with one queue per vCPU.
+    test_microvm.basic_config()
+
+    _tap, _, _ = test_microvm.ssh_network_config(network_config, '1')
+
+    fs1 = drive_tools.FilesystemFile(
+        os.path.join(test_microvm.fsfiles, 'scratch'), size=128
+    )
+    server1 = NbdServer(
+        os.path.join(test_microvm.fsfiles, 'scratch.sock'), fs1.path
+    )
+    server1.start()
+    response = test_microvm.drive.put(
+        drive_id='scratch',
+        path_on_host=test_microvm.create_jailed_resource(server1.path),
+        is_root_device=False,
+        is_read_only=False,
+        io_engine='Nbd',
+        num_queues=2
+    )
+    assert test_microvm.api_session.is_status_no_content(response.status_code)
+    assert server1.connections == 2
+
+    test_microvm.start()
+
+    ssh_connection = net_tools.SSHConnection(test_microvm.ssh_config)
+    _check_block_size(ssh_connection, '/dev/vdb', fs1.size())
+
+    # Connecting to another server after boot is allowed by the filters.
+    fs2 = drive_tools.FilesystemFile(
+        os.path.join(test_microvm.fsfiles, 'otherscratch'), size=256
+    )
+    server2 = NbdServer(
+        os.path.join(test_microvm.fsfiles, 'otherscratch.sock'), fs2.path
+    )
+    server2.start()
+    response = test_microvm.drive.patch(
+        drive_id='scratch',
+        path_on_host=test_microvm.create_jailed_resource(server2.path)
+    )
+    assert test_microvm.api_session.is_status_no_content(response.status_code)
+    assert server2.connections == 2
+    _check_block_size(ssh_connection, '/dev/vdb', fs2.size())
+
+    # Write to the drive, and keep some requests in flight while snapshotting.
+    exit_code, _, _ = ssh_connection.execute_command(
+        "dd if=/dev/urandom of=/tmp/data bs=1M count=4 && "
+        "dd if=/tmp/data of=/dev/vdb bs=1M oflag=direct"
+    )
+    assert exit_code == 0
+    exit_code, _, _ = ssh_connection.execute_command(
+        "dd if=/dev/vdb of=/dev/null bs=4k count=10000 iflag=direct "
+        "> /dev/null 2>&1 &"
+    )
+    assert exit_code == 0
+
+    test_microvm.pause_to_snapshot(
+        mem_file_path="/vm.mem",
+        snapshot_path="/vm.vmstate"
+    )
+    response = test_microvm.vm.patch(state='Resumed')
+    assert test_microvm.api_session.is_status_no_content(response.status_code)
+
+    # The drive still works, and holds the data written by the guest.
+    _, stdout, stderr = ssh_connection.execute_command(
+        "cmp -n 4194304 /tmp/data /dev/vdb && echo same"
+    )
+    assert stderr.read() == ''
+    assert stdout.read().strip() == 'same'
+
+    server1.exit()
+    server2.exit()
+    assert server1.error is None
+    assert server2.error is None
+
+
 def _check_block_size(ssh_connection, dev_path, size):
     _, stdout, stderr = ssh_connection.execute_command(
         'blockdev --getsize64 {}'.format(dev_path)
@@ -981,3 +981,60 @@ def test_mmds_v2_negative(test_microvm_with_api, network_config):
     # Check `GET` request fails when expired token is provided.
     _run_guest_cmd(ssh_connection, generate_mmds_get_request(
         DEFAULT_IPV4, token=token), "MMDS token not valid.")
+
+
+def test_mmds_v2_token_revocation(test_microvm_with_api, network_config):
+    """
+    Test that revoking MMDS session tokens invalidates them.
+
+    @type: functional
+    """
+    test_microvm = test_microvm_with_api
+    test_microvm.spawn()
+
+    # Attach network device.
+    _tap = test_microvm.ssh_network_config(network_config, '1')
+    # Configure MMDS version.
+    configure_mmds(test_microvm, version='V2', iface_ids=['1'])
+
+    data_store = {
+        'latest': {
+            'meta-data': {
+                'ami-id': 'ami-12345678'
+            }
+        }
+    }
+    _populate_data_store(test_microvm, data_store)
+
+    test_microvm.basic_config(vcpu_count=1)
+    test_microvm.start()
+    ssh_connection = net_tools.SSHConnection(test_microvm.ssh_config)
+
+    _run_guest_cmd(ssh_connection, f'ip route add {DEFAULT_IPV4} dev eth0', '')
+
+    token = generate_mmds_session_token(
+        ssh_connection,
+        ipv4_address=DEFAULT_IPV4,
+        token_ttl=60
+    )
+    pre = generate_mmds_get_request(DEFAULT_IPV4, token=token, app_json=False)
+    _run_guest_cmd(ssh_connection, pre + 'latest/meta-data/ami-id',
+                   'ami-12345678')
+
+    # Revoke all the session tokens.
+    response = test_microvm.mmds.revoke_tokens()
+    assert test_microvm.api_session.is_status_no_content(response.status_code)
+
+    # The previously issued token is no longer accepted.
+    _run_guest_cmd(ssh_connection, pre + 'latest/meta-data/ami-id',
+                   'MMDS token not valid.')
+
+    # A newly issued token is accepted.
+    token = generate_mmds_session_token(
+        ssh_connection,
+        ipv4_address=DEFAULT_IPV4,
+        token_ttl=60
+    )
+    pre = generate_mmds_get_request(DEFAULT_IPV4, token=token, app_json=False)
+    _run_guest_cmd(ssh_connection, pre + 'latest/meta-data/ami-id',
+                   'ami-12345678')
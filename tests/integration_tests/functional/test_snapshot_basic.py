@@ -375,6 +375,48 @@ def test_load_snapshot_failure_handling(test_microvm_with_api):
     wait_process_termination(vm.jailer_clone_pid)
 
 
+def test_load_snapshot_dry_run(bin_cloner_path, test_microvm_with_api):
+    """
+    Test checking a snapshot against the host CPU before loading it.
+
+    @type: functional
+    """
+    vm_builder = MicrovmBuilder(bin_cloner_path)
+    vm_instance = vm_builder.build_vm_nano()
+    basevm = vm_instance.vm
+    basevm.start()
+
+    disks = [vm_instance.disks[0].local_path()]
+    snapshot = SnapshotBuilder(basevm).create(disks,
+                                              vm_instance.ssh_key,
+                                              SnapshotType.FULL)
+    basevm.kill()
+
+    vm = test_microvm_with_api
+    vm.spawn()
+    jailed_mem = vm.create_jailed_resource(snapshot.mem)
+    jailed_vmstate = vm.create_jailed_resource(snapshot.vmstate)
+    for disk in snapshot.disks:
+        vm.create_jailed_resource(disk)
+
+    # The snapshot was created on this host, so it uses no missing feature.
+    response = vm.snapshot.check(mem_file_path=jailed_mem,
+                                 snapshot_path=jailed_vmstate)
+    assert vm.api_session.is_status_ok(response.status_code)
+    assert response.json() == {
+        'missing_cpuid_features': [],
+        'missing_msrs': []
+    }
+
+    # Nothing was restored, so the snapshot can still be loaded.
+    response = vm.snapshot.load(mem_file_path=jailed_mem,
+                                snapshot_path=jailed_vmstate,
+                                resume=True)
+    assert vm.api_session.is_status_no_content(response.status_code)
+
+    vm.kill()
+
+
 def test_cmp_full_and_first_diff_mem(network_config,
                                      bin_cloner_path):
     """
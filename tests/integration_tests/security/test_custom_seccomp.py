@@ -2,9 +2,11 @@
 # SPDX-License-Identifier: Apache-2.0
 """Tests that the --seccomp-filter parameter works as expected."""
 
+import array
 import os
 import platform
 import json
+import socket
 import tempfile
 import time
 import psutil
@@ -242,3 +244,133 @@ def test_invalid_bpf(test_microvm_with_api, vm_config_file):
 
     # assert that the process was killed
     assert not psutil.pid_exists(test_microvm.jailer_clone_pid)
+
+
+@pytest.mark.skipif(
+    platform.machine() != "x86_64",
+    reason="The guest is shut down through SendCtrlAltDel."
+)
+def test_learn_filter(test_microvm_with_api):
+    """
+    Test --seccomp-learn, then run a microVM with the learned filters.
+
+    @type: security
+    """
+    test_microvm = test_microvm_with_api
+
+    # Create the output file in advance, so that the jailed process can
+    # write it.
+    learned_path = os.path.join(test_microvm.path, 'learned.json')
+    open(learned_path, 'w', encoding='utf-8').close()
+    test_microvm.jailer.extra_args.update({
+        "seccomp-learn": test_microvm.create_jailed_resource(learned_path)
+    })
+    test_microvm.spawn()
+    test_microvm.basic_config()
+    test_microvm.start()
+
+    # Wait for the guest to boot, then shut it down so that Firecracker
+    # exits gracefully and writes the learned filters.
+    time.sleep(2)
+    response = test_microvm.actions.put(action_type='SendCtrlAltDel')
+    assert test_microvm.api_session.is_status_no_content(response.status_code)
+    utils.wait_process_termination(test_microvm.jailer_clone_pid)
+
+    with open(learned_path, encoding='utf-8') as learned_file:
+        learned = json.load(learned_file)
+
+    assert set(learned.keys()) == {"vmm", "api", "vcpu"}
+    vcpu_syscalls = [rule["syscall"] for rule in learned["vcpu"]["filter"]]
+    assert "ioctl" in vcpu_syscalls
+    assert "rt_sigreturn" in vcpu_syscalls
+    for rule in learned["vcpu"]["filter"]:
+        if rule["syscall"] == "ioctl":
+            assert rule["args"][0]["index"] == 1
+
+    # The learned filters must compile.
+    bpf_path = os.path.join(test_microvm.path, 'learned.bpf')
+    run_seccompiler_bin(bpf_path=bpf_path, json_path=learned_path)
+    assert os.path.getsize(bpf_path) > 0
+
+
+def _receive_listener(supervisor):
+    """Receive a listener sent by Firecracker, with the thread name."""
+    fds = array.array("i")
+    msg, ancdata, _, _ = supervisor.recvmsg(
+        64, socket.CMSG_SPACE(fds.itemsize))
+    for level, kind, data in ancdata:
+        if level == socket.SOL_SOCKET and kind == socket.SCM_RIGHTS:
+            fds.frombytes(data[:fds.itemsize])
+    assert len(fds) == 1
+    return msg.decode("utf-8"), fds[0]
+
+
+def test_user_notif_filter(test_microvm_with_api):
+    """
+    Test --seccomp-notif-socket with filters using `user_notif`.
+
+    @type: security
+    """
+    test_microvm = test_microvm_with_api
+
+    # Firecracker never calls `acct`, so the microVM runs normally, but each
+    # filter still gets a listener.
+    _custom_filter_setup(test_microvm, """{
+        "Vmm": {
+            "default_action": "allow",
+            "filter_action": "user_notif",
+            "filter": [
+                {
+                    "syscall": "acct"
+                }
+            ]
+        },
+        "Api": {
+            "default_action": "allow",
+            "filter_action": "user_notif",
+            "filter": [
+                {
+                    "syscall": "acct"
+                }
+            ]
+        },
+        "Vcpu": {
+            "default_action": "allow",
+            "filter_action": "user_notif",
+            "filter": [
+                {
+                    "syscall": "acct"
+                }
+            ]
+        }
+    }""".encode("utf-8"))
+
+    # The supervisor listens inside the jail, before Firecracker starts.
+    os.makedirs(test_microvm.chroot(), exist_ok=True)
+    socket_path = os.path.join(test_microvm.chroot(), "notif.socket")
+    listening_socket = socket.socket(socket.AF_UNIX, socket.SOCK_SEQPACKET)
+    listening_socket.bind(socket_path)
+    os.chmod(socket_path, 0o777)
+    listening_socket.listen(1)
+    test_microvm.jailer.extra_args.update(
+        {"seccomp-notif-socket": "notif.socket"})
+
+    test_microvm.spawn()
+    supervisor, _ = listening_socket.accept()
+    supervisor.settimeout(10)
+
+    test_microvm.basic_config(vcpu_count=2)
+    test_microvm.start()
+
+    thread_names = []
+    for _ in range(4):
+        thread_name, listener = _receive_listener(supervisor)
+        thread_names.append(thread_name)
+        os.close(listener)
+    assert sorted(thread_names) == \
+        ["fc_api", "fc_vcpu 0", "fc_vcpu 1", "main"]
+
+    utils.assert_seccomp_level(test_microvm.jailer_clone_pid, "2")
+
+    supervisor.close()
+    listening_socket.close()
@@ -1,9 +1,12 @@
 # Copyright 2018 Amazon.com, Inc. or its affiliates. All Rights Reserved.
 # SPDX-License-Identifier: Apache-2.0
 """Tests that verify the jailer's behavior."""
+import errno
 import http.client as http_client
+import json
 import os
 import resource
+import signal
 import stat
 import subprocess
 import time
@@ -18,6 +21,7 @@ import urllib3
 from framework.builder import SnapshotBuilder
 from framework.defs import FC_BINARY_NAME
 from framework.jailer import JailerContext
+from framework import utils
 import host_tools.cargo_build as build_tools
 
 
@@ -324,6 +328,68 @@ def test_cgroups(test_microvm_with_initrd, sys_setup_cgroups):
         )
 
 
+def test_cgroup_profile(test_microvm_with_ssh, sys_setup_cgroups, tmp_path):
+    """
+    Test that the jailer applies a cgroup profile and pins the threads.
+
+    @type: security
+    """
+    # pylint: disable=redefined-outer-name
+    if sys_setup_cgroups != 2:
+        pytest.skip("cgroup profiles require cgroup v2")
+
+    test_microvm = test_microvm_with_ssh
+    cpus = get_cpus(0).split(',')[0].split('-')[0]
+    profile = {
+        "cpu": {"quota_us": 50000, "weight": 200},
+        "memory": {"max": 1073741824},
+        "pids": {"max": 128},
+        "cpu_pinning": {"vmm": cpus, "vcpus": [cpus, cpus]},
+    }
+    profile_path = tmp_path / "profile.json"
+    profile_path.write_text(json.dumps(profile))
+    test_microvm.jailer.cgroup_ver = 2
+    test_microvm.jailer.cgroup_profile = profile_path
+
+    test_microvm.spawn()
+    test_microvm.basic_config(vcpu_count=2)
+    test_microvm.start()
+
+    jail_cgroup = '/sys/fs/cgroup/{}/{}'.format(
+        FC_BINARY_NAME, test_microvm.jailer.jailer_id)
+    for file_name, value in [
+        ("cpu.max", "50000 100000"),
+        ("cpu.weight", "200"),
+        ("memory.max", "1073741824"),
+        ("pids.max", "128"),
+    ]:
+        with open(os.path.join(jail_cgroup, file_name),
+                  encoding='utf-8') as file:
+            assert file.read().strip() == value
+
+    # The VMM thread and each vCPU thread have their own threaded cgroup.
+    fc_pid = int(test_microvm.jailer_clone_pid)
+    threads = {}
+    for tid in os.listdir("/proc/{}/task".format(fc_pid)):
+        with open("/proc/{}/task/{}/comm".format(fc_pid, tid),
+                  encoding='utf-8') as file:
+            comm = file.read().strip()
+        with open("/proc/{}/task/{}/cgroup".format(fc_pid, tid),
+                  encoding='utf-8') as file:
+            threads[comm] = os.path.basename(file.read().strip())
+    assert threads["firecracker"] == "vmm"
+    assert threads["fc_api"] == "vmm"
+    assert threads["fc_vcpu 0"] == "vcpu0"
+    assert threads["fc_vcpu 1"] == "vcpu1"
+    for name in ["vmm", "vcpu0", "vcpu1"]:
+        with open(os.path.join(jail_cgroup, name, "cgroup.type"),
+                  encoding='utf-8') as file:
+            assert file.read().strip() == "threaded"
+        with open(os.path.join(jail_cgroup, name, "cpuset.cpus"),
+                  encoding='utf-8') as file:
+            assert file.read().strip() == cpus
+
+
 def test_cgroups_custom_parent(test_microvm_with_initrd, sys_setup_cgroups):
     """
     Test cgroups when a custom parent cgroup is used.
@@ -634,3 +700,182 @@ def test_new_pid_namespace(test_microvm_with_api):
     assert len(nstgid_list) == 2
     assert int(nstgid_list[1]) == 1
     assert int(nstgid_list[0]) == fc_pid
+
+
+def test_user_namespace(test_microvm_with_ssh):
+    """
+    Test that Firecracker is spawned in a new user namespace if requested.
+
+    @type: security
+    """
+    test_microvm = test_microvm_with_ssh
+
+    test_microvm.jailer.user_ns = True
+
+    test_microvm.spawn()
+    test_microvm.basic_config()
+    test_microvm.start()
+
+    fc_pid = int(test_microvm.jailer_clone_pid)
+    uid = test_microvm.jailer.uid
+    gid = test_microvm.jailer.gid
+
+    # Check that Firecracker runs in a user namespace nested in ours.
+    assert os.readlink("/proc/{}/ns/user".format(fc_pid)) != \
+        os.readlink("/proc/self/ns/user")
+
+    # The jailer runs as root, so the jailed uid and gid are mapped onto
+    # themselves and Firecracker doesn't run as root on the host.
+    with open("/proc/{}/uid_map".format(fc_pid), encoding='utf-8') as file:
+        uid_map = [line.split() for line in file.readlines()]
+    assert uid_map == [["0", "0", "1"], [str(uid), str(uid), "1"]]
+    with open("/proc/{}/gid_map".format(fc_pid), encoding='utf-8') as file:
+        gid_map = [line.split() for line in file.readlines()]
+    assert gid_map == [["0", "0", "1"], [str(gid), str(gid), "1"]]
+    assert psutil.Process(fc_pid).uids().effective == uid
+
+    # The devices are bind mounted from the host instead of being created,
+    # so only empty mount points are visible from outside the jail.
+    jailed_kvm = os.stat(
+        os.path.join(test_microvm.jailer.chroot_path(), "dev/kvm"))
+    assert stat.S_ISREG(jailed_kvm.st_mode)
+    with open("/proc/{}/mountinfo".format(fc_pid), encoding='utf-8') as file:
+        mountinfo = file.read()
+    assert " /dev/kvm " in mountinfo
+    assert " /dev/net/tun " in mountinfo
+
+
+def test_bind_mounts(test_microvm_with_api, tmp_path):
+    """
+    Test that the jailer bind mounts the requested host paths in the jail.
+
+    @type: security
+    """
+    test_microvm = test_microvm_with_api
+
+    # The sources must be accessible to the jailed user beforehand.
+    resources = tmp_path / "resources"
+    resources.mkdir()
+    os.chown(resources, test_microvm.jailer.uid, test_microvm.jailer.gid)
+    read_only_file = tmp_path / "read_only"
+    read_only_file.write_text("foo")
+    read_only_file.chmod(0o644)
+    test_microvm.jailer.mounts = [
+        "{}:/resources".format(resources),
+        "{}:/images/read_only:ro".format(read_only_file),
+    ]
+
+    test_microvm.spawn()
+
+    fc_pid = int(test_microvm.jailer_clone_pid)
+    jail_root = "/proc/{}/root".format(fc_pid)
+
+    # Writable mounts can be written to.
+    with open(os.path.join(jail_root, "resources/bar"), "w",
+              encoding='utf-8') as file:
+        file.write("bar")
+    assert (resources / "bar").read_text() == "bar"
+
+    # The ownership of the sources is left untouched.
+    assert os.stat(read_only_file).st_uid == os.getuid()
+
+    # Read-only mounts can't be written to.
+    with open(os.path.join(jail_root, "images/read_only"),
+              encoding='utf-8') as file:
+        assert file.read() == "foo"
+    with pytest.raises(OSError) as err:
+        with open(os.path.join(jail_root, "images/read_only"), "w",
+                  encoding='utf-8'):
+            pass
+    assert err.value.errno == errno.EROFS
+
+    # All the mounts are private to the jail, and use restrictive flags.
+    with open("/proc/{}/mountinfo".format(fc_pid), encoding='utf-8') as file:
+        mounts = {line.split()[4]: line.split()[5].split(",")
+                  for line in file.readlines()}
+    assert {"nosuid", "nodev", "noexec", "rw"} <= set(mounts["/resources"])
+    assert {"nosuid", "nodev", "noexec", "ro"} <= \
+        set(mounts["/images/read_only"])
+    with open("/proc/self/mountinfo", encoding='utf-8') as file:
+        assert test_microvm.jailer.chroot_path() not in file.read()
+
+
+def test_new_netns_taps(test_microvm_with_api):
+    """
+    Test that the jailer creates the requested taps in a new netns.
+
+    @type: security
+    """
+    test_microvm = test_microvm_with_api
+    test_microvm.jailer.netns = None
+    test_microvm.jailer.new_netns = True
+    test_microvm.jailer.taps = ["tap0", "tap1:mtu=1400:offload=csum,tso4"]
+
+    test_microvm.spawn()
+
+    fc_pid = int(test_microvm.jailer_clone_pid)
+    netns_path = "/proc/{}/ns/net".format(fc_pid)
+    assert os.readlink(netns_path) != os.readlink("/proc/self/ns/net")
+
+    # The taps are only visible in the network namespace of Firecracker.
+    _, stdout, _ = utils.run_cmd("ip link show")
+    assert "tap1" not in stdout
+
+    nsenter = "nsenter --net={} ".format(netns_path)
+    _, stdout, _ = utils.run_cmd(nsenter + "ip -o link show tap1")
+    assert "mtu 1400" in stdout
+    assert "UP" in stdout.split("<")[1].split(">")[0].split(",")
+
+    # The taps are persistent and owned by the jailed user.
+    uid = test_microvm.jailer.uid
+    gid = test_microvm.jailer.gid
+    _, stdout, _ = utils.run_cmd(nsenter + "ip tuntap show")
+    taps = dict(line.split(": ", 1) for line in stdout.strip().splitlines())
+    assert sorted(taps) == ["tap0", "tap1"]
+    for flags in taps.values():
+        flags = flags.split()
+        assert "persist" in flags
+        assert "vnet_hdr" in flags
+        assert flags[flags.index("user") + 1] == str(uid)
+        assert flags[flags.index("group") + 1] == str(gid)
+
+
+def test_supervise(test_microvm_with_api, sys_setup_cgroups):
+    """
+    Test that the supervising jailer cleans up after Firecracker exits.
+
+    @type: security
+    """
+    # pylint: disable=redefined-outer-name
+    test_microvm = test_microvm_with_api
+    test_microvm.jailer.new_pid_ns = True
+    test_microvm.jailer.supervise = True
+    test_microvm.jailer.cgroup_ver = sys_setup_cgroups
+    test_microvm.jailer.cgroups = [
+        'cpuset.mems=0',
+        'cpuset.cpus={}'.format(get_cpus(0))
+    ]
+
+    test_microvm.spawn()
+
+    if test_microvm.jailer.cgroup_ver == 1:
+        cgroup_dir = '/sys/fs/cgroup/cpuset/{}/{}'.format(
+            FC_BINARY_NAME, test_microvm.jailer.jailer_id)
+    else:
+        cgroup_dir = '/sys/fs/cgroup/{}/{}'.format(
+            FC_BINARY_NAME, test_microvm.jailer.jailer_id)
+    jail_dir = test_microvm.jailer.chroot_base_with_id()
+    assert os.path.isdir(cgroup_dir)
+    assert os.path.isdir(jail_dir)
+
+    # The supervisor outlives Firecracker, and exits with its exit code.
+    os.kill(test_microvm.pid_in_new_ns, signal.SIGKILL)
+    _, status = os.waitpid(test_microvm.jailer_clone_pid, 0)
+    assert os.WIFEXITED(status)
+    assert os.WEXITSTATUS(status) == 128 + signal.SIGKILL
+
+    # The cgroup and the jail of the microVM are gone, but not their parents.
+    assert not os.path.exists(cgroup_dir)
+    assert os.path.isdir(os.path.dirname(cgroup_dir))
+    assert not os.path.exists(jail_dir)
+    assert os.path.isdir(os.path.dirname(jail_dir))
@@ -7,7 +7,8 @@ import os
 import tempfile
 import platform
 
-from host_tools.cargo_build import run_seccompiler_bin
+from host_tools.cargo_build import run_seccompiler_bin, \
+    run_seccompiler_decompile
 from framework import utils
 
 
@@ -311,3 +312,48 @@ def test_seccomp_rust_panic(bin_seccomp_paths):
             "filters.".format(code, thread)
 
     os.unlink(bpf_path)
+
+
+def test_seccomp_decompile():
+    """
+    Test seccompiler-decompile on the default Firecracker filters.
+
+    Decompile the compiled filters, recompile the resulting JSON and check
+    that the two compiled filters are semantically equivalent.
+
+    @type: security
+    """
+    fc_filters_path = "../resources/seccomp/{}-unknown-linux-musl.json".format(
+        platform.machine()
+    )
+    with open(fc_filters_path, "r", encoding='utf-8') as fc_filters:
+        filter_threads = sorted(json_lib.loads(fc_filters.read()))
+
+    bpf_temp = tempfile.NamedTemporaryFile(delete=False)
+    run_seccompiler_bin(bpf_path=bpf_temp.name,
+                        json_path=fc_filters_path)
+
+    rc, stdout = run_seccompiler_decompile(bpf_temp.name)
+    assert rc == 0
+    decompiled = json_lib.loads(stdout)
+    assert sorted(decompiled) == filter_threads
+
+    # Recompile the decompiled filters and compare them with the original.
+    recompiled_bpf_path = _run_seccompiler_bin(json_lib.dumps(decompiled))
+    rc, stdout = run_seccompiler_decompile(bpf_temp.name,
+                                           diff_path=recompiled_bpf_path)
+    assert rc == 0, stdout
+    assert "The filters are equivalent." in stdout
+
+    # Dropping a rule is reported as a difference.
+    vmm_rules = decompiled["vmm"]["filter"]
+    removed_syscall = vmm_rules.pop()["syscall"]
+    modified_bpf_path = _run_seccompiler_bin(json_lib.dumps(decompiled))
+    rc, stdout = run_seccompiler_decompile(bpf_temp.name,
+                                           diff_path=modified_bpf_path)
+    assert rc == 2
+    assert "- vmm: {}".format(removed_syscall) in stdout
+
+    os.unlink(bpf_temp.name)
+    os.unlink(recompiled_bpf_path)
+    os.unlink(modified_bpf_path)